@@ -0,0 +1,109 @@
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use vaulty::admin::AddressInfo;
+use vaulty::api::ErrorResponse;
+
+/// Request timeout, in seconds
+const TIMEOUT: u64 = 30;
+
+/// Client for the admin API of a Vaulty server
+pub struct Client {
+    url: String,
+    user: String,
+    pass: String,
+    http: reqwest::blocking::Client,
+}
+
+impl Client {
+    pub fn new(url: &str, user: &str, pass: &str) -> Self {
+        let http = reqwest::blocking::Client::builder()
+            .timeout(std::time::Duration::from_secs(TIMEOUT))
+            .build()
+            .unwrap_or_default();
+
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            user: user.to_string(),
+            pass: pass.to_string(),
+            http,
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.http
+            .request(method, &format!("{}{}", self.url, path))
+            .basic_auth(&self.user, Some(&self.pass))
+    }
+
+    /// Send a request, turning error responses into the message and code the
+    /// server gave for them
+    fn send(&self, req: RequestBuilder) -> Result<Response, String> {
+        let resp = req
+            .send()
+            .map_err(|e| format!("Failed to reach {}: {}", self.url, e))?;
+        let status = resp.status();
+
+        if status.is_success() {
+            return Ok(resp);
+        }
+
+        if status == StatusCode::NOT_FOUND {
+            return Err("Not found".to_string());
+        }
+
+        let message = resp
+            .json::<ErrorResponse>()
+            .map(|r| format!("{} ({})", r.message, r.code))
+            .unwrap_or_else(|_| status.to_string());
+
+        Err(message)
+    }
+
+    fn json<T: DeserializeOwned>(&self, req: RequestBuilder) -> Result<T, String> {
+        self.send(req)?
+            .json()
+            .map_err(|e| format!("Unexpected response from {}: {}", self.url, e))
+    }
+
+    pub fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, String> {
+        self.json(self.request(Method::GET, path).query(query))
+    }
+
+    pub fn post<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<T, String> {
+        self.json(self.request(Method::POST, path).json(body))
+    }
+
+    pub fn put<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+        body: &impl Serialize,
+    ) -> Result<T, String> {
+        self.json(self.request(Method::PUT, path).query(query).json(body))
+    }
+
+    pub fn delete<T: DeserializeOwned>(&self, path: &str) -> Result<T, String> {
+        self.json(self.request(Method::DELETE, path))
+    }
+
+    /// Look up an address by name, as the admin API works with address IDs
+    pub fn find_address(&self, address: &str) -> Result<AddressInfo, String> {
+        let addresses: Vec<AddressInfo> = self.get("/admin/addresses", &[])?;
+
+        addresses
+            .into_iter()
+            .find(|a| a.address.eq_ignore_ascii_case(address))
+            .ok_or_else(|| format!("No such address: {}", address))
+    }
+}
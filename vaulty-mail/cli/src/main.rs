@@ -0,0 +1,533 @@
+use std::io;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use structopt::clap::Shell;
+use structopt::StructOpt;
+
+use vaulty::admin::{AddressInfo, EmailStatus, LogEntry, Quotas};
+
+mod client;
+mod profile;
+mod table;
+
+use client::Client;
+
+/// How often `logs tail --follow` checks for new messages, in seconds
+const FOLLOW_INTERVAL: u64 = 2;
+
+/// Server used if neither the options nor the profile set one
+const DEFAULT_URL: &str = "http://127.0.0.1:7777";
+
+/// How results are printed
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Output {
+    /// Aligned columns, for people
+    Table,
+    /// The API response as is, for scripts
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Output::Table),
+            "json" => Ok(Output::Json),
+            _ => Err(format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "vaulty",
+    about = "Manage a Vaulty server through its admin API."
+)]
+struct Opt {
+    /// Saved server and credentials to use (see `profile::Profile`). Options
+    /// and environment variables take precedence over the profile.
+    #[structopt(long, env = "VAULTY_PROFILE")]
+    profile: Option<String>,
+
+    /// Base URL of the Vaulty server [default: http://127.0.0.1:7777]
+    #[structopt(long, env = "VAULTY_URL")]
+    url: Option<String>,
+
+    /// API user, as set up in the web admin
+    #[structopt(short, long, env = "VAULTY_USER")]
+    user: Option<String>,
+
+    #[structopt(short, long, env = "VAULTY_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// One of table or json
+    #[structopt(short, long, default_value = "table")]
+    output: Output,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Show and manage addresses
+    Address(AddressCommand),
+    /// Manage the senders whitelisted by an address
+    Whitelist(WhitelistCommand),
+    /// Show messages logged while handling email
+    Logs(LogsCommand),
+    /// Look up emails
+    Email(EmailCommand),
+    /// Show how often attachments were submitted again, and why
+    Retries,
+    /// Print a completion script for a shell (bash, fish, zsh, powershell,
+    /// or elvish)
+    Completions {
+        #[structopt(possible_values = &Shell::variants())]
+        shell: Shell,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum AddressCommand {
+    /// List addresses
+    List {
+        /// Only list the addresses of this user ID
+        #[structopt(long)]
+        user: Option<i32>,
+    },
+    /// Show an address
+    Show { address: String },
+    /// Add an address for a user
+    Add {
+        address: String,
+
+        /// ID of the user that owns the address
+        #[structopt(long)]
+        user: i32,
+
+        /// One of dropbox, gdrive, or s3. Inherited from the domain of the
+        /// address if not set.
+        #[structopt(long)]
+        storage_backend: Option<String>,
+
+        #[structopt(long)]
+        storage_token: String,
+
+        /// Folder that files are stored under
+        #[structopt(long)]
+        storage_path: String,
+
+        /// Add the address without accepting email for it yet
+        #[structopt(long)]
+        inactive: bool,
+    },
+    /// Manage the quotas of an address
+    Quota(QuotaCommand),
+}
+
+#[derive(Debug, StructOpt)]
+enum QuotaCommand {
+    /// Set quotas of an address; quotas that are not given are left as they
+    /// are
+    Set {
+        address: String,
+
+        /// Emails per quota period
+        #[structopt(long)]
+        emails: Option<i32>,
+
+        /// Largest email accepted, in bytes
+        #[structopt(long)]
+        max_email_size: Option<i32>,
+
+        /// Bytes stored per quota period
+        #[structopt(long)]
+        storage: Option<i64>,
+
+        /// Show what would change, without changing it
+        #[structopt(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum WhitelistCommand {
+    /// List the whitelisted senders of an address
+    List { address: String },
+    /// Whitelist a sender, a domain (e.g., "*@example.com"), a regex between
+    /// slashes, or all senders ("*")
+    Add { address: String, sender: String },
+    /// Remove a sender from the whitelist
+    Remove { address: String, sender: String },
+}
+
+#[derive(Debug, StructOpt)]
+enum LogsCommand {
+    /// Show the most recent messages
+    Tail {
+        /// Only show messages about the emails of this address
+        #[structopt(long)]
+        address: Option<String>,
+
+        /// Number of messages to show
+        #[structopt(short = "n", long, default_value = "20")]
+        lines: i64,
+
+        /// Keep showing new messages as they are logged
+        #[structopt(short, long)]
+        follow: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum EmailCommand {
+    /// Show the status of an email and its attachments
+    Status { id: String },
+}
+
+/// Print a result as JSON, or as a table with `print_table`
+fn show<T: Serialize + ?Sized>(output: Output, value: &T, print_table: impl FnOnce(&T)) {
+    match output {
+        Output::Table => print_table(value),
+        Output::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(value).unwrap_or_default()
+        ),
+    }
+}
+
+fn or_dash<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_string(), |v| v.to_string())
+}
+
+fn print_addresses(addresses: &[AddressInfo]) {
+    let rows: Vec<Vec<String>> = addresses
+        .iter()
+        .map(|a| {
+            vec![
+                a.id.to_string(),
+                a.address.clone(),
+                or_dash(a.user_id),
+                if a.is_active { "active" } else { "inactive" }.to_string(),
+                format!("{}/{}", a.num_received, or_dash(a.quotas.email_quota)),
+                format!("{}/{}", a.storage_used, or_dash(a.quotas.storage_quota)),
+            ]
+        })
+        .collect();
+
+    table::print(
+        &["ID", "ADDRESS", "USER", "STATUS", "EMAILS", "STORAGE"],
+        &rows,
+    );
+}
+
+fn print_whitelist(whitelist: &[String]) {
+    if whitelist.is_empty() {
+        println!("No whitelisted senders");
+    }
+
+    for sender in whitelist {
+        println!("{}", sender);
+    }
+}
+
+fn print_logs(logs: &[LogEntry]) {
+    for log in logs {
+        println!(
+            "{} {:<7} {} {}",
+            log.creation_time.to_rfc3339(),
+            log.level.to_uppercase(),
+            or_dash(log.mail_id),
+            log.msg
+        );
+    }
+}
+
+/// Print retry stats (see `/monitor/retries`)
+fn print_retries(stats: &Value) {
+    println!(
+        "{} submission(s), {} retried ({:.1}%)",
+        stats["submissions"],
+        stats["retries"],
+        stats["retry_rate"].as_f64().unwrap_or_default() * 100.0
+    );
+
+    let rows: Vec<Vec<String>> = stats["by_kind"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(kind, count)| vec![kind.clone(), count.to_string()])
+        .collect();
+
+    table::print(&["KIND", "RETRIES"], &rows);
+}
+
+/// Print the outcome of a dry run (see `vaulty::admin::DryRun`)
+fn print_dry_run(dry_run: &Value) {
+    for section in &["address", "settings"] {
+        for change in dry_run[section].as_array().into_iter().flatten() {
+            println!(
+                "{}: {} -> {}",
+                change["field"].as_str().unwrap_or_default(),
+                change["before"],
+                change["after"]
+            );
+        }
+    }
+
+    for warning in dry_run["warnings"].as_array().into_iter().flatten() {
+        println!("warning: {}", warning.as_str().unwrap_or_default());
+    }
+}
+
+fn print_email(email: &EmailStatus) {
+    let status = if email.status { "ok" } else { "failed" };
+
+    println!("Email {} to {}: {}", email.id, email.address, status);
+    if let Some(msg) = &email.error_msg {
+        println!("  Error: {}", msg);
+    }
+    if let Some(message_id) = &email.message_id {
+        println!("  Message-ID: {}", message_id);
+    }
+    println!("  Received: {}", email.creation_time.to_rfc3339());
+    println!("  Updated: {}", email.last_update_time.to_rfc3339());
+    println!(
+        "  Attachments: {} of {} submitted",
+        email.attachments.len(),
+        email.num_attachments
+    );
+
+    if email.attachments.is_empty() {
+        return;
+    }
+
+    let rows: Vec<Vec<String>> = email
+        .attachments
+        .iter()
+        .map(|attachment| {
+            let status = if !attachment.status {
+                "failed"
+            } else if attachment.is_duplicate {
+                "duplicate"
+            } else if attachment.storage_path.is_none() {
+                "dropped"
+            } else {
+                "stored"
+            };
+
+            vec![
+                attachment.index.to_string(),
+                attachment
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| "(no name)".to_string()),
+                attachment.size.to_string(),
+                status.to_string(),
+                attachment
+                    .error_msg
+                    .clone()
+                    .or_else(|| attachment.storage_path.clone())
+                    .unwrap_or_default(),
+            ]
+        })
+        .collect();
+
+    println!();
+    table::print(&["#", "NAME", "BYTES", "STATUS", "PATH/ERROR"], &rows);
+}
+
+fn address(client: &Client, output: Output, command: AddressCommand) -> Result<(), String> {
+    match command {
+        AddressCommand::List { user } => {
+            let query: Vec<_> = user
+                .map(|id| ("user", id.to_string()))
+                .into_iter()
+                .collect();
+            let addresses: Vec<AddressInfo> = client.get("/admin/addresses", &query)?;
+
+            show(output, &addresses[..], print_addresses);
+        }
+        AddressCommand::Show { address } => {
+            // All fields are shown, which do not fit in a table
+            let address = client.find_address(&address)?;
+            show(Output::Json, &address, |_| ());
+        }
+        AddressCommand::Add {
+            address,
+            user,
+            storage_backend,
+            storage_token,
+            storage_path,
+            inactive,
+        } => {
+            let body = json!({
+                "address": address,
+                "user_id": user,
+                "is_active": !inactive,
+                "storage_backend": storage_backend,
+                "storage_token": storage_token,
+                "storage_path": storage_path,
+            });
+            let address: AddressInfo = client.post("/admin/addresses", &body)?;
+
+            show(output, &[address][..], print_addresses);
+        }
+        AddressCommand::Quota(QuotaCommand::Set {
+            address,
+            emails,
+            max_email_size,
+            storage,
+            dry_run,
+        }) => {
+            // Quotas are replaced as a whole
+            let current = client.find_address(&address)?;
+            let quotas = Quotas {
+                email_quota: emails.or(current.quotas.email_quota),
+                max_email_size: max_email_size.or(current.quotas.max_email_size),
+                storage_quota: storage.or(current.quotas.storage_quota),
+            };
+            let path = format!("/admin/addresses/{}/quotas", current.id);
+
+            if dry_run {
+                let query = [("dry_run", "true".to_string())];
+                let dry_run: Value = client.put(&path, &query, &quotas)?;
+                show(output, &dry_run, print_dry_run);
+            } else {
+                let address: AddressInfo = client.put(&path, &[], &quotas)?;
+                show(output, &[address][..], print_addresses);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn whitelist(client: &Client, output: Output, command: WhitelistCommand) -> Result<(), String> {
+    let path = |address: &str| -> Result<String, String> {
+        let address = client.find_address(address)?;
+        Ok(format!("/admin/addresses/{}/whitelist", address.id))
+    };
+
+    let whitelist: Vec<String> = match command {
+        WhitelistCommand::List { address } => client.get(&path(&address)?, &[])?,
+        WhitelistCommand::Add { address, sender } => {
+            client.post(&path(&address)?, &json!({ "sender": sender }))?
+        }
+        WhitelistCommand::Remove { address, sender } => {
+            let sender = vaulty::admin::encode_path_segment(&sender);
+            client.delete(&format!("{}/{}", path(&address)?, sender))?
+        }
+    };
+
+    show(output, &whitelist[..], print_whitelist);
+
+    Ok(())
+}
+
+fn logs(client: &Client, output: Output, command: LogsCommand) -> Result<(), String> {
+    let LogsCommand::Tail {
+        address,
+        lines,
+        follow,
+    } = command;
+
+    let mut query = vec![("limit", lines.to_string())];
+    if let Some(address) = &address {
+        query.push(("address", address.clone()));
+    }
+
+    let logs: Vec<LogEntry> = client.get("/admin/logs", &query)?;
+    show(output, &logs[..], print_logs);
+
+    if !follow {
+        return Ok(());
+    }
+
+    let mut last = logs.last().map_or(0, |l| l.id);
+
+    loop {
+        thread::sleep(Duration::from_secs(FOLLOW_INTERVAL));
+
+        let mut query = query.clone();
+        query.push(("after", last.to_string()));
+
+        let logs: Vec<LogEntry> = client.get("/admin/logs", &query)?;
+        show(output, &logs[..], print_logs);
+
+        last = logs.last().map_or(last, |l| l.id);
+    }
+}
+
+fn email(client: &Client, output: Output, command: EmailCommand) -> Result<(), String> {
+    let EmailCommand::Status { id } = command;
+
+    let email: EmailStatus = client.get(&format!("/admin/emails/{}", id), &[])?;
+    show(output, &email, print_email);
+
+    Ok(())
+}
+
+fn retries(client: &Client, output: Output) -> Result<(), String> {
+    let stats: Value = client.get("/monitor/retries", &[])?;
+    show(output, &stats, print_retries);
+
+    Ok(())
+}
+
+/// Connect to the server given by the options, or else by the profile
+fn connect(opt: &Opt) -> Result<Client, String> {
+    let profile = profile::load(opt.profile.as_deref())?;
+
+    let url = opt
+        .url
+        .clone()
+        .or(profile.url)
+        .unwrap_or_else(|| DEFAULT_URL.to_string());
+    let user = opt
+        .user
+        .clone()
+        .or(profile.user)
+        .ok_or("No API user given: use --user, VAULTY_USER, or a profile")?;
+    let password = opt
+        .password
+        .clone()
+        .or(profile.password)
+        .ok_or("No API password given: use --password, VAULTY_PASSWORD, or a profile")?;
+
+    Ok(Client::new(&url, &user, &password))
+}
+
+fn run(opt: Opt) -> Result<(), String> {
+    if let Command::Completions { shell } = opt.command {
+        Opt::clap().gen_completions_to("vaulty", shell, &mut io::stdout());
+        return Ok(());
+    }
+
+    let client = connect(&opt)?;
+    let output = opt.output;
+
+    match opt.command {
+        Command::Address(command) => address(&client, output, command),
+        Command::Whitelist(command) => whitelist(&client, output, command),
+        Command::Logs(command) => logs(&client, output, command),
+        Command::Email(command) => email(&client, output, command),
+        Command::Retries => retries(&client, output),
+        Command::Completions { .. } => Ok(()),
+    }
+}
+
+fn main() {
+    let result = run(Opt::from_args());
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+/// Profile used if none is selected
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Server and credentials to use, saved under a name in the profiles file
+///
+/// ```toml
+/// [default]
+/// url = "https://mail.vaulty.net"
+/// user = "admin"
+/// password = "secret"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct Profile {
+    pub url: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Path of the profiles file: `$VAULTY_PROFILES`, or `vaulty/profiles.toml`
+/// under `$XDG_CONFIG_HOME` (`~/.config` if not set)
+pub fn path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("VAULTY_PROFILES") {
+        return Some(path.into());
+    }
+
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_dir.join("vaulty").join("profiles.toml"))
+}
+
+fn parse(contents: &str, name: &str) -> Result<Option<Profile>, String> {
+    let mut profiles: HashMap<String, Profile> =
+        toml::from_str(contents).map_err(|e| format!("Invalid profiles file: {}", e))?;
+
+    Ok(profiles.remove(name))
+}
+
+/// Load a profile by name
+///
+/// The default profile is optional, so it is empty if it was not saved.
+pub fn load(name: Option<&str>) -> Result<Profile, String> {
+    let (name, is_default) = match name {
+        Some(name) => (name, false),
+        None => (DEFAULT_PROFILE, true),
+    };
+
+    let path = path();
+    let contents = path.as_ref().and_then(|path| fs::read_to_string(path).ok());
+
+    match contents.as_deref().map(|c| parse(c, name)).transpose()? {
+        Some(Some(profile)) => Ok(profile),
+        _ if is_default => Ok(Profile::default()),
+        _ => Err(format!(
+            "No profile named {} in {}",
+            name,
+            path.map_or_else(
+                || "the profiles file".to_string(),
+                |p| p.display().to_string()
+            )
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_profiles() {
+        let contents = r#"
+            [default]
+            url = "http://127.0.0.1:7777"
+            user = "admin"
+
+            [prod]
+            url = "https://mail.vaulty.net"
+            user = "ops"
+            password = "secret"
+        "#;
+
+        let prod = parse(contents, "prod").unwrap().unwrap();
+        assert_eq!(prod.url.as_deref(), Some("https://mail.vaulty.net"));
+        assert_eq!(prod.password.as_deref(), Some("secret"));
+
+        let default = parse(contents, "default").unwrap().unwrap();
+        assert!(default.password.is_none());
+
+        assert!(parse(contents, "staging").unwrap().is_none());
+        assert!(parse("[prod]\nurl = 1", "prod").is_err());
+    }
+}
@@ -0,0 +1,59 @@
+fn line<'a>(cells: impl Iterator<Item = &'a str>, widths: &[usize]) -> String {
+    let cells: Vec<String> = cells
+        .zip(widths)
+        .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+        .collect();
+
+    cells.join("  ").trim_end().to_string()
+}
+
+/// Render rows as a table with aligned columns, under a header
+pub fn render(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut lines = vec![line(header.iter().copied(), &widths)];
+    lines.extend(
+        rows.iter()
+            .map(|row| line(row.iter().map(String::as_str), &widths)),
+    );
+
+    lines.join("\n")
+}
+
+pub fn print(header: &[&str], rows: &[Vec<String>]) {
+    println!("{}", render(header, rows));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_columns() {
+        let rows = vec![
+            vec![
+                "1".to_string(),
+                "foo@vaulty.net".to_string(),
+                "".to_string(),
+            ],
+            vec![
+                "12".to_string(),
+                "b@vaulty.net".to_string(),
+                "x".to_string(),
+            ],
+        ];
+
+        assert_eq!(
+            render(&["ID", "ADDRESS", "NOTE"], &rows),
+            "ID  ADDRESS         NOTE\n\
+             1   foo@vaulty.net\n\
+             12  b@vaulty.net    x"
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use std::env;
+use std::fs;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+// Request timeout, in seconds
+const DEFAULT_TIMEOUT: u64 = 15;
+
+// Port the server listens on by default, used for the legacy env config
+const DEFAULT_SERVER_PORT: u16 = 7777;
+
+/// Filter config, loaded from a TOML file
+///
+/// ```toml
+/// timeout = 15
+///
+/// [[servers]]
+/// url = "https://vaulty-1.example.com:7777"
+/// domains = ["example.com", "*.example.com"]
+/// user = "relay-1"
+/// pass = "secret"
+///
+/// # Tried if the first server is unreachable
+/// [[servers]]
+/// url = "https://vaulty-2.example.com:7777"
+/// domains = ["example.com"]
+/// priority = 1
+/// user = "relay-1"
+/// pass = "secret"
+/// ca_cert = "/etc/vaulty/ca.pem"
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    /// Default request timeout, in seconds
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+
+    /// Reply to the sender when an email is processed successfully, for all
+    /// addresses. Per address replies are sent by the server.
+    #[serde(default)]
+    pub reply_on_success: bool,
+
+    pub servers: Vec<Server>,
+}
+
+/// An upstream Vaulty server
+#[derive(Clone, Debug, Deserialize)]
+pub struct Server {
+    /// Base URL of the server (e.g., "http://127.0.0.1:7777")
+    pub url: String,
+
+    /// Recipient domains handled by this server, either exact or a wildcard
+    /// subdomain (e.g., "*.example.com"). Servers with no domains handle
+    /// all domains not listed elsewhere.
+    #[serde(default)]
+    pub domains: Vec<String>,
+
+    /// Servers for the same domain are tried in increasing priority order,
+    /// then in file order
+    #[serde(default)]
+    pub priority: u32,
+
+    /// HTTP basic auth credentials
+    pub user: String,
+    pub pass: String,
+
+    /// Request and connection timeouts, in seconds
+    pub timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+
+    /// PEM CA certificate to trust, in addition to the system roots
+    pub ca_cert: Option<String>,
+
+    /// PKCS#12 client certificate and its password, for mutual TLS
+    pub identity: Option<String>,
+    pub identity_password: Option<String>,
+
+    /// Skip certificate verification; for testing only
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+impl Config {
+    /// Load the config file at `path`
+    ///
+    /// If the file does not exist, a single server is configured from the
+    /// VAULTY_SERVER_ADDR, VAULTY_USER, and VAULTY_PASS environment
+    /// variables, as before the config file existed.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::from_env(),
+            Err(e) => return Err(format!("Failed to read config {}: {}", path, e)),
+        };
+
+        let config: Self =
+            toml::from_str(&contents).map_err(|e| format!("Invalid config {}: {}", path, e))?;
+
+        if config.servers.is_empty() {
+            return Err(format!("No servers in config {}", path));
+        }
+
+        Ok(config)
+    }
+
+    fn from_env() -> Result<Self, String> {
+        let addr = env::var("VAULTY_SERVER_ADDR").unwrap_or_else(|_| "127.0.0.1".to_string());
+        let user = env::var("VAULTY_USER").map_err(|_| "No auth username found!".to_string())?;
+        let pass = env::var("VAULTY_PASS").map_err(|_| "No auth password found!".to_string())?;
+
+        Ok(Self {
+            timeout: DEFAULT_TIMEOUT,
+            reply_on_success: env::var("VAULTY_REPLY_SUCCESS").is_ok(),
+            servers: vec![Server {
+                url: format!("http://{}:{}", addr, DEFAULT_SERVER_PORT),
+                domains: Vec::new(),
+                priority: 0,
+                user,
+                pass,
+                timeout: None,
+                connect_timeout: None,
+                ca_cert: None,
+                identity: None,
+                identity_password: None,
+                accept_invalid_certs: false,
+            }],
+        })
+    }
+
+    /// Servers to try for a recipient, in order
+    pub fn servers_for(&self, recipient: &str) -> Vec<&Server> {
+        let domain = recipient
+            .rsplit('@')
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+
+        let mut servers: Vec<&Server> = self
+            .servers
+            .iter()
+            .filter(|s| s.domains.iter().any(|d| domain_matches(d, &domain)))
+            .collect();
+
+        if servers.is_empty() {
+            servers = self
+                .servers
+                .iter()
+                .filter(|s| s.domains.is_empty())
+                .collect();
+        }
+
+        // Stable, so file order is kept within a priority
+        servers.sort_by_key(|s| s.priority);
+        servers
+    }
+
+    /// Build an HTTP client for a server
+    pub fn client(&self, server: &Server) -> Result<reqwest::blocking::Client, String> {
+        let mut builder = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(server.timeout.unwrap_or(self.timeout)))
+            .danger_accept_invalid_certs(server.accept_invalid_certs);
+
+        if let Some(connect_timeout) = server.connect_timeout {
+            builder = builder.connect_timeout(Duration::from_secs(connect_timeout));
+        }
+
+        if let Some(path) = &server.ca_cert {
+            let pem = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            let cert = reqwest::Certificate::from_pem(&pem)
+                .map_err(|e| format!("Invalid CA certificate {}: {}", path, e))?;
+            builder = builder.add_root_certificate(cert);
+        }
+
+        if let Some(path) = &server.identity {
+            let der = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            let password = server.identity_password.as_deref().unwrap_or("");
+            let identity = reqwest::Identity::from_pkcs12_der(&der, password)
+                .map_err(|e| format!("Invalid client certificate {}: {}", path, e))?;
+            builder = builder.identity(identity);
+        }
+
+        builder.build().map_err(|e| e.to_string())
+    }
+}
+
+impl Server {
+    /// Full URL of an endpoint on this server
+    pub fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.url.trim_end_matches('/'), path)
+    }
+}
+
+fn domain_matches(pattern: &str, domain: &str) -> bool {
+    let pattern = pattern.trim().to_ascii_lowercase();
+
+    if pattern.starts_with("*.") {
+        domain.ends_with(&pattern[1..])
+    } else {
+        pattern == domain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_by_domain() {
+        let config: Config = toml::from_str(
+            r#"
+            [[servers]]
+            url = "http://default:7777/"
+            user = "u"
+            pass = "p"
+
+            [[servers]]
+            url = "http://b-backup"
+            domains = ["b.com"]
+            priority = 1
+            user = "u"
+            pass = "p"
+
+            [[servers]]
+            url = "http://b"
+            domains = ["B.com", "*.c.com"]
+            user = "u"
+            pass = "p"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.timeout, DEFAULT_TIMEOUT);
+
+        let urls = |recipient| {
+            config
+                .servers_for(recipient)
+                .iter()
+                .map(|s| s.url.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(urls("test@b.com"), vec!["http://b", "http://b-backup"]);
+        assert_eq!(urls("test@mail.c.com"), vec!["http://b"]);
+        assert_eq!(urls("test@c.com"), vec!["http://default:7777/"]);
+        assert_eq!(urls("test@a.com"), vec!["http://default:7777/"]);
+
+        assert_eq!(
+            config.servers[0].endpoint("/postfix/email"),
+            "http://default:7777/postfix/email"
+        );
+    }
+}
@@ -2,9 +2,22 @@
 pub enum Error {
     Server(vaulty::api::ServerResult),
     Temporary,
+    /// The server could not be reached, or did not accept the email
+    Unreachable,
     Unexpected,
 }
 
+impl Error {
+    /// Map a failed request, telling apart servers that cannot be reached
+    pub fn from_request(err: reqwest::Error) -> Self {
+        if err.is_connect() {
+            Self::Unreachable
+        } else {
+            Self::Temporary
+        }
+    }
+}
+
 impl std::error::Error for Error {}
 
 impl std::fmt::Display for Error {
@@ -19,6 +32,7 @@ impl std::fmt::Display for Error {
                 }
             }
             Error::Temporary => write!(f, "Mail processing failed temporarily."),
+            Error::Unreachable => write!(f, "Vaulty server is unavailable."),
             Error::Unexpected => write!(
                 f,
                 "An unexpected error occurred while processing this email.\n\n
@@ -1,28 +1,18 @@
-use std::env;
 use std::io::Read;
 use std::time::Duration;
 
-use lazy_static::lazy_static;
-
 use reqwest::StatusCode;
 
 use structopt::StructOpt;
 
+mod config;
 mod error;
 mod reply;
 
+use config::{Config, Server};
 use error::Error;
 
-use vaulty::api::ServerResult;
-
-// TODO: Can we make this more flexible?
-lazy_static! {
-    static ref VAULTY_USER: String = env::var("VAULTY_USER").expect("No auth username found!");
-    static ref VAULTY_PASS: String = env::var("VAULTY_PASS").expect("No auth username found!");
-}
-
-// Request timeout, in seconds
-const REQUEST_TIMEOUT: u64 = 15;
+use vaulty::api::{DirectUpload, ServerResult, UploadUrl};
 
 // Postfix filter error codes
 // Postfix will re-queue delivery of the email to this filter
@@ -30,6 +20,10 @@ const REQUEST_TIMEOUT: u64 = 15;
 const UNAVAILABLE: i32 = 69;
 const TEMPFAIL: i32 = 75;
 
+// Timeout for uploads straight to the storage backend, in seconds
+// These are only used for large attachments
+const DIRECT_UPLOAD_TIMEOUT: u64 = 10 * 60;
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "vaulty-filter",
@@ -41,10 +35,18 @@ struct Opt {
 
     #[structopt(short, long)]
     recipients: Vec<String>,
+
+    /// Message size declared by the sending client (Postfix ${size})
+    #[structopt(short = "z", long)]
+    size: Option<usize>,
+
+    /// Path to the filter config file
+    #[structopt(short, long, default_value = "/etc/vaulty/filter.toml")]
+    config: String,
 }
 
 fn send_attachment(
-    remote_addr: &str,
+    server: &Server,
     client: &reqwest::blocking::Client,
     email: &vaulty::email::Email,
     attachment: vaulty::email::Attachment,
@@ -57,7 +59,7 @@ fn send_attachment(
     // Body just contains the attachment
     // All metadata passed along as headers
     let req = client
-        .post(&format!("http://{}:7777/postfix/attachment", remote_addr))
+        .post(&server.endpoint("/postfix/attachment"))
         .header(reqwest::header::CONTENT_TYPE, attachment.get_mime())
         .header(reqwest::header::CONTENT_LENGTH, attachment.get_size())
         .header(vaulty::constants::VAULTY_EMAIL_ID, &email.uuid.to_string())
@@ -69,7 +71,11 @@ fn send_attachment(
             vaulty::constants::VAULTY_ATTACHMENT_INDEX,
             attachment.get_index(),
         )
-        .basic_auth(VAULTY_USER.as_str(), Some(VAULTY_PASS.as_str()))
+        .header(
+            vaulty::constants::VAULTY_ATTACHMENT_SHA256,
+            vaulty::email::content_hash(attachment.get_data()),
+        )
+        .basic_auth(&server.user, Some(&server.pass))
         .body(attachment.get_data_owned());
 
     let resp = req.send();
@@ -82,6 +88,13 @@ fn send_attachment(
     }
 
     let resp = resp.unwrap();
+
+    // Server asked us to retry later (e.g., storage token was refreshed), or
+    // the storage backend failed
+    if is_deferred(resp.status()) {
+        return Err(Error::Temporary);
+    }
+
     let result = resp.json::<ServerResult>()?;
 
     log::debug!("{:?}", result);
@@ -89,17 +102,239 @@ fn send_attachment(
     Ok(result)
 }
 
+/// Upload a large attachment straight to the storage backend, then confirm
+/// it with the server
+///
+/// Returns `None` if the attachment should be sent to the server as usual
+/// instead (e.g., the server did not issue an upload URL).
+fn send_direct(
+    server: &Server,
+    client: &reqwest::blocking::Client,
+    email: &vaulty::email::Email,
+    attachment: &vaulty::email::Attachment,
+) -> Result<Option<ServerResult>, Error> {
+    let upload = DirectUpload {
+        mail_id: email.uuid.to_string(),
+        index: attachment.get_index(),
+        name: attachment.get_name().clone(),
+        content_type: attachment.get_mime().clone(),
+        size: attachment.get_size(),
+        sha256: vaulty::email::content_hash(attachment.get_data()),
+    };
+
+    let resp = client
+        .post(&server.endpoint("/postfix/attachment/url"))
+        .basic_auth(&server.user, Some(&server.pass))
+        .json(&upload)
+        .send()?
+        .error_for_status()?;
+
+    let upload_url = resp.json::<UploadUrl>()?;
+    let url = match upload_url.url {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+
+    log::debug!(
+        "Uploading attachment {} of email {} directly",
+        upload.index,
+        upload.mail_id
+    );
+
+    // Uploads can take much longer than requests to the server
+    let storage_client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(DIRECT_UPLOAD_TIMEOUT))
+        .build()?;
+
+    let mut req = storage_client.put(&url);
+    for (k, v) in &upload_url.headers {
+        req = req.header(k.as_str(), v.as_str());
+    }
+
+    // The attachment can still be sent through the server
+    let resp = req.body(attachment.get_data().clone()).send();
+    if let Err(e) = resp.and_then(|r| r.error_for_status()) {
+        log::warn!(
+            "Direct upload of attachment {} of email {} failed: {}",
+            upload.index,
+            upload.mail_id,
+            e
+        );
+        return Ok(None);
+    }
+
+    let resp = client
+        .post(&server.endpoint("/postfix/attachment/confirm"))
+        .basic_auth(&server.user, Some(&server.pass))
+        .json(&upload)
+        .send()?;
+
+    // Server asked us to retry later
+    if is_deferred(resp.status()) {
+        return Err(Error::Temporary);
+    }
+
+    let result = resp.json::<ServerResult>()?;
+
+    log::debug!("{:?}", result);
+
+    Ok(Some(result))
+}
+
+/// Check the size of this email before it is read, so that the server can
+/// reject oversized emails up front
+fn check_size(
+    server: &Server,
+    client: &reqwest::blocking::Client,
+    recipients: &[String],
+    size: usize,
+) -> Result<(), Error> {
+    let estimate = vaulty::api::SizeEstimate {
+        recipients: recipients.to_vec(),
+        size,
+    };
+
+    let resp = client
+        .post(&server.endpoint("/postfix/size"))
+        .basic_auth(&server.user, Some(&server.pass))
+        .json(&estimate)
+        .send()
+        .map_err(Error::from_request)?;
+
+    if is_rejected(resp.status()) {
+        let result = resp.json::<ServerResult>()?;
+        log::debug!("{:?}", result);
+        return Err(Error::Server(result));
+    }
+
+    Ok(())
+}
+
+/// Returns true if the server rejected the email for a reason that is
+/// described by its result (see `reply::reply_error`)
+///
+/// * `UNPROCESSABLE_ENTITY`: the email is invalid (e.g., unknown recipient)
+/// * `FORBIDDEN`: the sender is not allowed to mail this address
+/// * `TOO_MANY_REQUESTS`: the address is over its quota or rate limit
+fn is_rejected(status: StatusCode) -> bool {
+    status == StatusCode::UNPROCESSABLE_ENTITY
+        || status == StatusCode::FORBIDDEN
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Returns true if the server asked for the email to be retried later
+fn is_deferred(status: StatusCode) -> bool {
+    status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::BAD_GATEWAY
+}
+
+/// Send the raw message, exactly as received, for .eml archival
+fn send_raw(
+    server: &Server,
+    client: &reqwest::blocking::Client,
+    email: &vaulty::email::Email,
+    raw: &[u8],
+) -> Result<ServerResult, Error> {
+    log::debug!("Sending raw message for email: {}", email.uuid);
+
+    let req = client
+        .post(&server.endpoint("/postfix/raw"))
+        .header(reqwest::header::CONTENT_TYPE, "message/rfc822")
+        .header(reqwest::header::CONTENT_LENGTH, raw.len())
+        .header(vaulty::constants::VAULTY_EMAIL_ID, &email.uuid.to_string())
+        .basic_auth(&server.user, Some(&server.pass))
+        .body(raw.to_vec());
+
+    let resp = req.send();
+    if let Err(e) = resp {
+        if e.is_timeout() {
+            log::error!("Request to server timed out...: {}", e);
+        }
+
+        return Err(Error::Temporary);
+    }
+
+    let resp = resp.unwrap();
+
+    // Server asked us to retry later (e.g., storage token was refreshed), or
+    // the storage backend failed
+    if is_deferred(resp.status()) {
+        return Err(Error::Temporary);
+    }
+
+    let result = resp.json::<ServerResult>()?;
+
+    log::debug!("{:?}", result);
+
+    Ok(result)
+}
+
+/// Send the whole message, for addresses that bundle attachments
+///
+/// The server parses the message again, and stores it and its attachments
+/// in one go. The envelope is sent along, as it is not part of the message.
+fn send_message(
+    server: &Server,
+    client: &reqwest::blocking::Client,
+    email: &vaulty::email::Email,
+    raw: &[u8],
+) -> Result<ServerResult, Error> {
+    log::debug!("Sending whole message for email: {}", email.uuid);
+
+    let req = client
+        .post(&server.endpoint("/postfix/message"))
+        .header(reqwest::header::CONTENT_TYPE, "message/rfc822")
+        .header(reqwest::header::CONTENT_LENGTH, raw.len())
+        .header(vaulty::constants::VAULTY_EMAIL_ID, &email.uuid.to_string())
+        .header(vaulty::constants::VAULTY_SENDER, &email.sender)
+        .header(vaulty::constants::VAULTY_RECIPIENTS, &email.recipients.join(","))
+        .basic_auth(&server.user, Some(&server.pass))
+        .body(raw.to_vec());
+
+    let resp = req.send();
+    if let Err(e) = resp {
+        if e.is_timeout() {
+            log::error!("Request to server timed out...: {}", e);
+        }
+
+        return Err(Error::Temporary);
+    }
+
+    let resp = resp.unwrap();
+    let status = resp.status();
+
+    if is_deferred(status) {
+        return Err(Error::Temporary);
+    }
+
+    let result = resp.json::<ServerResult>()?;
+
+    log::debug!("{:?}", result);
+
+    if is_rejected(status) {
+        return Err(Error::Server(result));
+    } else if !status.is_success() {
+        return Err(Error::Unexpected);
+    }
+
+    Ok(result)
+}
+
 /// Transmit this email to the Vaulty processing server
-fn process(remote_addr: &str, mail: &mut vaulty::email::Email) -> Result<ServerResult, Error> {
-    let client = reqwest::blocking::Client::builder()
-        .timeout(Duration::from_secs(REQUEST_TIMEOUT))
-        .build()
-        .unwrap();
+///
+/// Fails with `Error::Unreachable` if the server did not accept the email,
+/// so that it can be sent to another server instead.
+fn process(
+    server: &Server,
+    client: &reqwest::blocking::Client,
+    mail: &mut vaulty::email::Email,
+    raw: &[u8],
+) -> Result<ServerResult, Error> {
     let email = serde_json::to_string(&mail)?;
 
     let req = client
-        .post(&format!("http://{}:7777/postfix/email", remote_addr))
-        .basic_auth(VAULTY_USER.as_str(), Some(VAULTY_PASS.as_str()))
+        .post(&server.endpoint("/postfix/email"))
+        .header(vaulty::constants::VAULTY_EMAIL_ID, &mail.uuid.to_string())
+        .basic_auth(&server.user, Some(&server.pass))
         .body(reqwest::blocking::Body::from(email));
 
     let resp = req.send();
@@ -108,7 +343,7 @@ fn process(remote_addr: &str, mail: &mut vaulty::email::Email) -> Result<ServerR
             log::error!("Request to server timed out...: {}", e.to_string());
         }
 
-        return Err(Error::Temporary);
+        return Err(Error::from_request(e));
     }
 
     let resp = resp.unwrap();
@@ -119,11 +354,19 @@ fn process(remote_addr: &str, mail: &mut vaulty::email::Email) -> Result<ServerR
     let mut result = resp.json::<ServerResult>()?;
 
     if !is_success {
-        // TODO: Handle all possible error codes
-        if status == StatusCode::UNPROCESSABLE_ENTITY {
-            // Reject the email gracefully
+        if is_rejected(status) {
+            // Reject or defer the email gracefully
             log::debug!("{:?}", result);
             return Err(Error::Server(result));
+        } else if status == StatusCode::SERVICE_UNAVAILABLE {
+            // Try another server, or let Postfix retry the email later
+            log::debug!("{:?}", result);
+            return Err(Error::Unreachable);
+        } else if status == StatusCode::BAD_GATEWAY {
+            // The storage backend failed; other servers share it, so let
+            // Postfix retry the email later
+            log::debug!("{:?}", result);
+            return Err(Error::Temporary);
         } else {
             // Unexpected server error
             log::debug!(
@@ -135,14 +378,49 @@ fn process(remote_addr: &str, mail: &mut vaulty::email::Email) -> Result<ServerR
         }
     }
 
-    let attachments = mail.attachments.take();
+    // The address bundles attachments, which takes the whole message
+    if result.send_message.unwrap_or(false) {
+        return send_message(server, client, mail, raw);
+    }
+
+    // A retried email that was already accepted keeps its original ID, so
+    // that its remaining attachments are sent against it
+    if let Some(Ok(uuid)) = result.mail_id.as_ref().map(|id| id.parse()) {
+        mail.uuid = uuid;
+    }
+
+    // The raw message is archived before any attachments are sent
+    if result.archive_eml.unwrap_or(false) {
+        send_raw(server, client, mail, raw)?;
+    }
+
+    // The server expects no attachments if it ignored the email
+    let attachments = mail
+        .attachments
+        .take()
+        .filter(|_| result.num_attachments != Some(0));
+
+    // Large attachments may be uploaded straight to the storage backend
+    let direct_upload_min_size = result.direct_upload_min_size;
 
     // Send each attachment one at a time
     if let Some(attachments) = attachments {
         let num_attachments = attachments.len();
 
         for (i, a) in attachments.into_iter().enumerate() {
-            match send_attachment(&remote_addr, &client, &mail, a) {
+            let sent = match direct_upload_min_size {
+                Some(min_size) if a.get_size() >= min_size => {
+                    send_direct(server, client, mail, &a)?
+                }
+                _ => None,
+            };
+
+            let sent = match sent {
+                Some(r) => Ok(r),
+                None => send_attachment(server, client, mail, a),
+            };
+
+            match sent {
                 Err(e) => return Err(e),
                 Ok(r) => {
                     if i == num_attachments - 1 {
@@ -157,18 +435,75 @@ fn process(remote_addr: &str, mail: &mut vaulty::email::Email) -> Result<ServerR
     Ok(result)
 }
 
-fn main() {
-    let remote_addr = env::var("VAULTY_SERVER_ADDR")
-                                 .unwrap_or("127.0.0.1".to_string());
+/// Run `f` against each server in turn, until one is reachable
+fn with_failover<T>(
+    config: &Config,
+    servers: &[&Server],
+    mut f: impl FnMut(&Server, &reqwest::blocking::Client) -> Result<T, Error>,
+) -> Result<T, Error> {
+    for server in servers {
+        let client = match config.client(server) {
+            Ok(client) => client,
+            Err(e) => {
+                log::error!("Failed to set up client for {}: {}", server.url, e);
+                continue;
+            }
+        };
 
-    let reply_on_success = env::var("VAULTY_REPLY_SUCCESS").is_ok();
+        match f(server, &client) {
+            Err(Error::Unreachable) => {
+                log::warn!("Vaulty server {} is unavailable", server.url);
+            }
+            result => return result,
+        }
+    }
+
+    Err(Error::Unreachable)
+}
 
+fn main() {
     // Init logger
     env_logger::builder().format_timestamp_micros().init();
 
     // Parse input arguments
     let opt = Opt::from_args();
 
+    let config = match Config::load(&opt.config) {
+        Ok(config) => config,
+        Err(e) => {
+            // Keep the email queued until the config is fixed
+            log::error!("{}", e);
+            std::process::exit(TEMPFAIL);
+        }
+    };
+
+    // Servers are picked based on the first recipient
+    let servers = config.servers_for(opt.recipients.first().map_or("", |r| r.as_str()));
+    if servers.is_empty() {
+        log::error!("No Vaulty server configured for {:?}", opt.recipients);
+        std::process::exit(TEMPFAIL);
+    }
+
+    // The email is sent once, so all recipients must be handled by the same
+    // servers. Otherwise, Postfix should be set to deliver one recipient at a
+    // time (vaulty_filter_destination_recipient_limit = 1).
+    let same_servers = |other: Vec<&Server>| {
+        other.len() == servers.len()
+            && other.iter().zip(&servers).all(|(a, b)| std::ptr::eq(*a, *b))
+    };
+    if let Some(r) = opt
+        .recipients
+        .iter()
+        .find(|r| !same_servers(config.servers_for(r)))
+    {
+        log::error!(
+            "Recipient {} is not handled by the same servers as {:?}; deliver one recipient at a time",
+            r,
+            opt.recipients.first()
+        );
+        std::process::exit(TEMPFAIL);
+    }
+
     // If this is a delivery status notification (DSN), just ignore it
     // See: Postfix pipe null_sender argument
     if opt.sender == "" {
@@ -176,9 +511,25 @@ fn main() {
         std::process::exit(0);
     }
 
+    // Reject oversized emails before reading them, based on the size declared
+    // by the client. Other failures are not fatal, as the size is checked
+    // again once the email is sent.
+    if let Some(size) = opt.size {
+        let result = with_failover(&config, &servers, |server, client| {
+            check_size(server, client, &opt.recipients, size)
+        });
+
+        match result {
+            Err(e @ Error::Server(_)) => std::process::exit(reply::reply_error(e)),
+            Err(e) => log::warn!("Failed to check email size: {}", e),
+            Ok(()) => (),
+        }
+    }
+
     // Get message body from stdin
-    let mut email_content = String::new();
-    if let Err(_) = std::io::stdin().read_to_string(&mut email_content) {
+    // The raw bytes are kept as-is so that they can be archived exactly
+    let mut email_content = Vec::new();
+    if std::io::stdin().read_to_end(&mut email_content).is_err() {
         // Message body is invalid for some reason - exit cleanly with a message
         // NOTE(aksiksi): When providing DSN status code to Postfix, the code
         // must end with either a space or EOF.
@@ -188,7 +539,7 @@ fn main() {
     }
 
     // Try to parse this email
-    let result = vaulty::email::Email::from_mime(email_content.as_bytes());
+    let result = vaulty::email::Email::from_mime(&email_content);
     if let Err(_) = result {
         println!("5.6.0 Failed to parse mail body");
         std::process::exit(UNAVAILABLE);
@@ -201,10 +552,16 @@ fn main() {
 
     // Process this email
     // If an error is encountered, we send a reply to the user
-    std::process::exit(match process(&remote_addr, &mut mail) {
+    let result = with_failover(&config, &servers, |server, client| {
+        process(server, client, &mut mail, &email_content)
+    });
+
+    std::process::exit(match result {
         Err(e) => reply::reply_error(e),
         Ok(r) => {
-            if reply_on_success {
+            // Replies can be enabled for all addresses here. Per address
+            // replies are sent by the server.
+            if config.reply_on_success {
                 reply::reply_success(&mail, r)
             } else {
                 0
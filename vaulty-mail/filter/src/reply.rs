@@ -6,6 +6,12 @@ use vaulty::api::ServerResult;
 use crate::error::Error;
 
 pub fn reply(mail: &vaulty::email::Email, body: String) {
+    if let Some(kind) = mail.auto_generated {
+        // Replying could start a mail loop
+        log::info!("Not replying to {} mail", kind.description());
+        return;
+    }
+
     if mail.message_id.is_none() {
         // We cannot reply to a message with no Message-ID!
         log::error!("Mail has no Message-ID!");
@@ -33,6 +39,9 @@ pub fn reply(mail: &vaulty::email::Email, body: String) {
         .subject(format!("Re: {}", subject))
         .in_reply_to(message_id.clone())
         .references(message_id.clone())
+        // Loop guards, see `vaulty::email::AutoGenerated`
+        .header(("Auto-Submitted", "auto-replied"))
+        .header((vaulty::email::LOOP_HEADER, mail.uuid.to_string()))
         // TODO: Add `message_id` call once Lettre creates a new release
         .text(body)
         .build()
@@ -62,7 +71,7 @@ pub fn reply(mail: &vaulty::email::Email, body: String) {
 pub fn reply_error(err: Error) -> i32 {
     // SMTP status code
     let status_code = match &err {
-        Error::Temporary => {
+        Error::Temporary | Error::Unreachable => {
             // If this was unexpected server error (e.g., timeout), tell Postfix
             // to retry delivery of this email to the filter.
             // Do not inform the user about this.
@@ -76,7 +85,10 @@ pub fn reply_error(err: Error) -> i32 {
             Some(err) => match err {
                 vaulty::Error::InvalidRecipient => Some("5.1.1"),
                 vaulty::Error::QuotaExceeded(_) => Some("5.2.3"),
+                vaulty::Error::InvalidSender(_) => Some("5.1.7"),
                 vaulty::Error::SenderNotWhitelisted { .. } => Some("5.7.1"),
+                vaulty::Error::AutoGenerated { .. } => Some("5.7.1"),
+                vaulty::Error::RateLimited { .. } => Some("4.7.1"),
                 vaulty::Error::TokenExpired | vaulty::Error::Unauthorized => Some("5.7.8"),
                 _ => Some("5.2.0"),
             },
@@ -86,7 +98,13 @@ pub fn reply_error(err: Error) -> i32 {
 
     if let Some(code) = status_code {
         println!("{} {}", code, err.to_string());
-        super::UNAVAILABLE
+
+        // Postfix retries the email later on a 4.X.X status
+        if code.starts_with('4') {
+            super::TEMPFAIL
+        } else {
+            super::UNAVAILABLE
+        }
     } else {
         // If we're here, this email was successful?
         log::warn!("Successful email, but marked as failed?");
@@ -0,0 +1,3 @@
+-- Daily usage and volume queries filter emails by address and time
+CREATE INDEX IF NOT EXISTS vaulty_mail_address_creation_time_idx
+    ON vaulty_mail (address_id, creation_time)
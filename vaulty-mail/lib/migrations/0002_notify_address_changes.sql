@@ -0,0 +1,21 @@
+-- Notify server instances when an address (or the defaults of its domain)
+-- changes, so that they drop their copies of it. The payload is the value of
+-- the column named by the first trigger argument, prefixed with the second.
+-- Usage counters change with every email and are not worth a notification.
+CREATE OR REPLACE FUNCTION vaulty_notify_address_changed() RETURNS trigger AS $$
+DECLARE
+    old_row JSONB := CASE WHEN TG_OP = 'INSERT' THEN NULL ELSE to_jsonb(OLD) END;
+    new_row JSONB := CASE WHEN TG_OP = 'DELETE' THEN NULL ELSE to_jsonb(NEW) END;
+BEGIN
+    IF old_row - 'num_received' - 'storage_used' = new_row - 'num_received' - 'storage_used' THEN
+        RETURN NULL;
+    END IF;
+
+    PERFORM pg_notify(
+        'vaulty_address_changed',
+        TG_ARGV[1] || (COALESCE(new_row, old_row) ->> TG_ARGV[0])
+    );
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql
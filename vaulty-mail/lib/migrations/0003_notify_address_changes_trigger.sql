@@ -0,0 +1,4 @@
+-- See 0002_notify_address_changes.sql
+CREATE TRIGGER vaulty_addresses_notify_changed
+    AFTER INSERT OR UPDATE OR DELETE ON vaulty_addresses
+    FOR EACH ROW EXECUTE PROCEDURE vaulty_notify_address_changed('address', '')
@@ -0,0 +1,4 @@
+-- See 0002_notify_address_changes.sql. Domains are notified as "@domain".
+CREATE TRIGGER vaulty_domains_notify_changed
+    AFTER INSERT OR UPDATE OR DELETE ON vaulty_domains
+    FOR EACH ROW EXECUTE PROCEDURE vaulty_notify_address_changed('domain', '@')
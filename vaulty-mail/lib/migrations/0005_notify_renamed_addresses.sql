@@ -0,0 +1,25 @@
+-- Replaces the function of 0002_notify_address_changes.sql, so that renamed
+-- addresses (and domains) are notified under both their old and new names:
+-- copies are kept under the old name.
+CREATE OR REPLACE FUNCTION vaulty_notify_address_changed() RETURNS trigger AS $$
+DECLARE
+    old_row JSONB := CASE WHEN TG_OP = 'INSERT' THEN NULL ELSE to_jsonb(OLD) END;
+    new_row JSONB := CASE WHEN TG_OP = 'DELETE' THEN NULL ELSE to_jsonb(NEW) END;
+    old_name TEXT := old_row ->> TG_ARGV[0];
+    new_name TEXT := new_row ->> TG_ARGV[0];
+BEGIN
+    IF old_row - 'num_received' - 'storage_used' = new_row - 'num_received' - 'storage_used' THEN
+        RETURN NULL;
+    END IF;
+
+    IF new_name IS NOT NULL THEN
+        PERFORM pg_notify('vaulty_address_changed', TG_ARGV[1] || new_name);
+    END IF;
+
+    IF old_name IS DISTINCT FROM new_name AND old_name IS NOT NULL THEN
+        PERFORM pg_notify('vaulty_address_changed', TG_ARGV[1] || old_name);
+    END IF;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql
@@ -0,0 +1,636 @@
+//! Users and addresses, as managed through the admin API
+//!
+//! Storage tokens can be set, but are never shown.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::db::Address;
+use crate::filename::{self, Template};
+use crate::settings::{Settings, SettingsLayer};
+use crate::storage::{self, Backend};
+use crate::Error;
+
+/// Names of the storage backends, as stored in the DB
+const BACKENDS: &[&str] = &["dropbox", "gdrive", "s3"];
+
+/// Longest username the web app accepts
+const MAX_USERNAME_LEN: usize = 150;
+
+/// Longest address
+const MAX_ADDRESS_LEN: usize = 512;
+
+/// Number of log messages returned if no limit is given
+const DEFAULT_LOG_LIMIT: i64 = 50;
+
+/// Most log messages returned at once
+const MAX_LOG_LIMIT: i64 = 1000;
+
+fn default_active() -> bool {
+    true
+}
+
+fn invalid(msg: String) -> Error {
+    Error::InvalidRequest(msg)
+}
+
+/// Check that an address looks like one
+pub fn validate_address(address: &str) -> Result<(), Error> {
+    let valid = match address.rfind('@') {
+        Some(i) => i > 0 && i + 1 < address.len() && !address.contains(char::is_whitespace),
+        None => false,
+    };
+
+    if !valid || address.len() > MAX_ADDRESS_LEN {
+        return Err(invalid(format!("{} is not a valid address", address)));
+    }
+
+    Ok(())
+}
+
+/// Check that a whitelist entry is an address, a wildcard, or a valid regex
+/// (see `whitelist::Entry`)
+pub fn validate_whitelist_entry(entry: &str) -> Result<(), Error> {
+    crate::whitelist::Entry::parse(entry).map(|_| ())
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+    pub is_active: bool,
+    pub is_subscribed: bool,
+    pub date_joined: DateTime<Utc>,
+}
+
+/// User to create
+///
+/// Users are created without a usable password: they set one in the web
+/// app (e.g., with a password reset).
+#[derive(Debug, Deserialize)]
+pub struct NewUser {
+    pub username: String,
+    #[serde(default)]
+    pub email: String,
+    #[serde(default = "default_active")]
+    pub is_active: bool,
+    #[serde(default)]
+    pub is_subscribed: bool,
+}
+
+impl NewUser {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.username.is_empty() || self.username.len() > MAX_USERNAME_LEN {
+            return Err(invalid(format!(
+                "Usernames must be 1 to {} characters long",
+                MAX_USERNAME_LEN
+            )));
+        }
+
+        if !self.email.is_empty() {
+            validate_address(&self.email)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Changes to a user; fields that are not set are left as they are
+#[derive(Debug, Default, Deserialize)]
+pub struct UserUpdate {
+    pub email: Option<String>,
+    pub is_active: Option<bool>,
+    pub is_subscribed: Option<bool>,
+}
+
+impl UserUpdate {
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.email.as_deref() {
+            Some(email) if !email.is_empty() => validate_address(email),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddressInfo {
+    pub id: i32,
+    pub address: String,
+    pub user_id: Option<i32>,
+    pub is_active: bool,
+    #[serde(flatten)]
+    pub quotas: Quotas,
+    pub num_received: i32,
+    pub storage_used: i64,
+    pub last_renewal_time: DateTime<Utc>,
+    pub storage_backend: Option<String>,
+    pub storage_path: String,
+    pub storage_token_expiry: Option<DateTime<Utc>>,
+    pub is_whitelist_enabled: bool,
+    pub whitelist: Vec<String>,
+    pub filename_template: Option<String>,
+    pub bundle_template: Option<String>,
+    pub creation_time: DateTime<Utc>,
+}
+
+/// Address to create
+#[derive(Debug, Deserialize)]
+pub struct NewAddress {
+    pub address: String,
+    pub user_id: i32,
+    #[serde(default = "default_active")]
+    pub is_active: bool,
+    #[serde(flatten)]
+    pub quotas: Quotas,
+    #[serde(flatten)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub is_whitelist_enabled: bool,
+    #[serde(default)]
+    pub whitelist: Vec<String>,
+}
+
+impl NewAddress {
+    pub fn validate(&self) -> Result<(), Error> {
+        validate_address(&self.address)?;
+        self.quotas.validate()?;
+        self.storage.validate()?;
+
+        for sender in &self.whitelist {
+            validate_whitelist_entry(sender)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Changes to an address; fields that are not set are left as they are
+///
+/// Quotas and storage are set on their own (see `Quotas` and `Storage`).
+#[derive(Debug, Default, Deserialize)]
+pub struct AddressUpdate {
+    pub user_id: Option<i32>,
+    pub is_active: Option<bool>,
+    pub is_whitelist_enabled: Option<bool>,
+
+    /// Templates for the names of files and zip archives; an empty template
+    /// is cleared
+    pub filename_template: Option<String>,
+    pub bundle_template: Option<String>,
+}
+
+impl AddressUpdate {
+    /// Apply the changes to an address, as the DB would
+    pub fn apply(&self, address: &mut AddressInfo) {
+        let template = |update: &Option<String>, current: &mut Option<String>| {
+            if let Some(template) = update {
+                *current = Some(template.clone()).filter(|t| !t.is_empty());
+            }
+        };
+
+        address.user_id = self.user_id.or(address.user_id);
+        address.is_active = self.is_active.unwrap_or(address.is_active);
+        address.is_whitelist_enabled = self
+            .is_whitelist_enabled
+            .unwrap_or(address.is_whitelist_enabled);
+        template(&self.filename_template, &mut address.filename_template);
+        template(&self.bundle_template, &mut address.bundle_template);
+    }
+}
+
+/// Quotas of an address
+///
+/// Quotas that are not set are inherited from the domain of the address,
+/// or the deployment defaults.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Quotas {
+    pub email_quota: Option<i32>,
+    pub max_email_size: Option<i32>,
+    pub storage_quota: Option<i64>,
+}
+
+impl Quotas {
+    pub fn validate(&self) -> Result<(), Error> {
+        let negative = self.email_quota.map_or(false, |q| q < 0)
+            || self.max_email_size.map_or(false, |q| q < 0)
+            || self.storage_quota.map_or(false, |q| q < 0);
+
+        if negative {
+            return Err(invalid("Quotas cannot be negative".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Storage credentials of an address
+#[derive(Debug, Deserialize)]
+pub struct Storage {
+    /// Inherited from the domain or deployment defaults if not set
+    pub storage_backend: Option<String>,
+    pub storage_token: String,
+    pub storage_refresh_token: Option<String>,
+    pub storage_token_expiry: Option<DateTime<Utc>>,
+    pub storage_path: String,
+}
+
+impl Storage {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(backend) = &self.storage_backend {
+            if !BACKENDS.contains(&backend.as_str()) {
+                return Err(invalid(format!(
+                    "Unknown storage backend {}; expected one of {}",
+                    backend,
+                    BACKENDS.join(", ")
+                )));
+            }
+        }
+
+        if self.storage_token.is_empty() {
+            return Err(invalid("A storage token is required".to_string()));
+        }
+
+        if self.storage_path.is_empty() {
+            return Err(invalid("A storage path is required".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Apply the storage settings to an address, as the DB would
+    pub fn apply(&self, address: &mut AddressInfo) {
+        address.storage_backend = self.storage_backend.clone();
+        address.storage_path = self.storage_path.clone();
+        address.storage_token_expiry = self.storage_token_expiry;
+    }
+}
+
+/// Sender to add to the whitelist of an address; may be a wildcard or regex
+/// (see `whitelist::Entry`)
+#[derive(Debug, Deserialize)]
+pub struct WhitelistEntry {
+    pub sender: String,
+}
+
+/// Encode a whitelist entry as a URL path segment
+///
+/// Regex entries may contain slashes and other characters that cannot be
+/// sent in a path as they are.
+pub fn encode_path_segment(entry: &str) -> String {
+    percent_encoding::utf8_percent_encode(entry, percent_encoding::NON_ALPHANUMERIC).to_string()
+}
+
+/// Decode a whitelist entry sent as a URL path segment
+pub fn decode_path_segment(segment: &str) -> String {
+    percent_encoding::percent_decode_str(segment)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// Number of log messages to return for a requested limit
+pub fn log_limit(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_LOG_LIMIT).max(1).min(MAX_LOG_LIMIT)
+}
+
+/// A message logged to the DB while handling email
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LogEntry {
+    pub id: i32,
+    pub mail_id: Option<Uuid>,
+    pub level: String,
+    pub msg: String,
+    pub creation_time: DateTime<Utc>,
+}
+
+/// Where an email is at, along with each of its attachments
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EmailStatus {
+    pub id: Uuid,
+    pub address: String,
+    pub message_id: Option<String>,
+    pub num_attachments: i32,
+    pub total_size: i32,
+    pub status: bool,
+    pub error_msg: Option<String>,
+    pub attachments: Vec<AttachmentStatus>,
+    pub last_update_time: DateTime<Utc>,
+    pub creation_time: DateTime<Utc>,
+}
+
+/// An attachment of an email that was stored, or failed to be
+///
+/// Attachments that were not submitted yet are not listed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AttachmentStatus {
+    pub index: i32,
+    pub name: Option<String>,
+    pub size: i32,
+    pub mime_type: Option<String>,
+    pub storage_path: Option<String>,
+    pub is_duplicate: bool,
+    pub status: bool,
+    pub error_msg: Option<String>,
+    pub creation_time: DateTime<Utc>,
+}
+
+/// A field that a change would affect
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Value,
+    pub after: Value,
+}
+
+/// Fields that differ between two values, as serialized, in order of name
+pub fn diff<T: Serialize>(before: &T, after: &T) -> Vec<FieldChange> {
+    let (before, after) = match (serde_json::to_value(before), serde_json::to_value(after)) {
+        (Ok(Value::Object(before)), Ok(Value::Object(after))) => (before, after),
+        _ => return Vec::new(),
+    };
+
+    after
+        .into_iter()
+        .filter_map(|(field, after)| {
+            let before = before.get(&field).cloned().unwrap_or(Value::Null);
+
+            if before == after {
+                None
+            } else {
+                Some(FieldChange {
+                    field,
+                    before,
+                    after,
+                })
+            }
+        })
+        .collect()
+}
+
+/// Outcome of a change to an address that was not made
+#[derive(Debug, Serialize)]
+pub struct DryRun {
+    /// Fields of the address that would change
+    pub address: Vec<FieldChange>,
+
+    /// Effective settings that would change, once resolved against the
+    /// domain and deployment defaults
+    pub settings: Vec<FieldChange>,
+
+    /// Likely problems with the address after the change (e.g., a storage
+    /// token that is not valid for its backend)
+    pub warnings: Vec<String>,
+}
+
+/// Preview a change to an address
+///
+/// `before` and `after` are the address as shown before and after the
+/// change, and `address` is the address before the change, with its
+/// settings resolved against `defaults`. `storage` is set if the change
+/// replaces the storage credentials.
+pub fn preview(
+    before: &AddressInfo,
+    after: &AddressInfo,
+    address: &Address,
+    defaults: &Settings,
+    storage: Option<&Storage>,
+    now: DateTime<Utc>,
+) -> DryRun {
+    // Quotas and storage backend are the only settings shown as fields
+    let overrides = SettingsLayer {
+        email_quota: after.quotas.email_quota,
+        max_email_size: after.quotas.max_email_size,
+        storage_quota: after.quotas.storage_quota,
+        storage_backend: after.storage_backend.clone().map(Backend::from),
+        ..address.address_settings.clone()
+    };
+    let settings = Settings::resolve(defaults, &[&address.domain_settings, &overrides]);
+
+    let (token, refresh_token) = match storage {
+        Some(storage) => (
+            storage.storage_token.as_str(),
+            storage.storage_refresh_token.as_deref(),
+        ),
+        None => (
+            address.storage_token.as_str(),
+            address.storage_refresh_token.as_deref(),
+        ),
+    };
+
+    DryRun {
+        address: diff(before, after),
+        settings: diff(&address.settings, &settings),
+        warnings: warnings(after, &settings, token, refresh_token, now),
+    }
+}
+
+/// Likely problems with an address, given its effective settings and
+/// storage tokens
+fn warnings(
+    address: &AddressInfo,
+    settings: &Settings,
+    token: &str,
+    refresh_token: Option<&str>,
+    now: DateTime<Utc>,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    if !address.is_active {
+        warnings.push("The address is inactive, so all email to it is rejected".to_string());
+    }
+
+    if let Backend::S3 = settings.storage_backend {
+        if let Err(e) = serde_json::from_str::<storage::s3::Config>(token) {
+            warnings.push(format!("The storage token is not valid for S3: {}", e));
+        }
+    }
+
+    match address.storage_token_expiry {
+        Some(expiry) if expiry <= now && refresh_token.is_none() => warnings.push(format!(
+            "The storage token expired at {}, and there is no refresh token to renew it",
+            expiry.to_rfc3339()
+        )),
+        _ => (),
+    }
+
+    let templates = [
+        ("filename_template", &address.filename_template),
+        ("bundle_template", &address.bundle_template),
+    ];
+
+    for (field, template) in templates.iter() {
+        let template = match template {
+            Some(template) => Template::new(template),
+            None => continue,
+        };
+
+        for variable in template.unknown_variables() {
+            warnings.push(format!(
+                "{} references unknown variable {{{}}}, which is kept as-is; known variables are {}",
+                field,
+                variable,
+                filename::VARIABLES.join(", ")
+            ));
+        }
+    }
+
+    if address.num_received >= settings.email_quota {
+        warnings.push(format!(
+            "The email quota of {} is already used up for this period ({} received)",
+            settings.email_quota, address.num_received
+        ));
+    }
+
+    if address.storage_used >= settings.storage_quota {
+        warnings.push(format!(
+            "The storage quota of {} bytes is already used up for this period ({} bytes used)",
+            settings.storage_quota, address.storage_used
+        ));
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_requests() {
+        assert_eq!(log_limit(None), DEFAULT_LOG_LIMIT);
+        assert_eq!(log_limit(Some(0)), 1);
+        assert_eq!(log_limit(Some(1_000_000)), MAX_LOG_LIMIT);
+
+        assert!(validate_address("a@b.com").is_ok());
+        assert!(validate_address("\"a@b\"@b.com").is_ok());
+        assert!(validate_address("a.com").is_err());
+        assert!(validate_address("@b.com").is_err());
+        assert!(validate_address("a@").is_err());
+        assert!(validate_address("a b@b.com").is_err());
+
+        assert!(validate_whitelist_entry("*@b.com").is_ok());
+        assert!(validate_whitelist_entry("/(unclosed/").is_err());
+
+        let entry = r"/(bob|carol)\+bills@b\.com/";
+        assert_eq!(decode_path_segment(&encode_path_segment(entry)), entry);
+        assert!(!encode_path_segment(entry).contains('/'));
+
+        let user: NewUser = serde_json::from_str(r#"{"username": "assil"}"#).unwrap();
+        assert!(user.validate().is_ok());
+        assert!(user.is_active);
+        assert!(!user.is_subscribed);
+
+        let user: NewUser =
+            serde_json::from_str(r#"{"username": "", "email": "a@b.com"}"#).unwrap();
+        assert!(user.validate().is_err());
+
+        let address: NewAddress = serde_json::from_str(
+            r#"{
+                "address": "a@vaulty.net",
+                "user_id": 1,
+                "storage_quota": 1000000,
+                "storage_backend": "s3",
+                "storage_token": "token",
+                "storage_path": "/vaulty",
+                "whitelist": ["b@b.com"]
+            }"#,
+        )
+        .unwrap();
+        assert!(address.validate().is_ok());
+        assert_eq!(address.quotas.storage_quota, Some(1_000_000));
+        assert_eq!(address.quotas.email_quota, None);
+        assert!(!address.is_whitelist_enabled);
+
+        let storage: Storage = serde_json::from_str(
+            r#"{"storage_backend": "ftp", "storage_token": "t", "storage_path": "/"}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            storage.validate(),
+            Err(Error::InvalidRequest(msg)) if msg.starts_with("Unknown storage backend ftp")
+        ));
+
+        let quotas = Quotas {
+            email_quota: Some(-1),
+            ..Default::default()
+        };
+        assert!(quotas.validate().is_err());
+    }
+
+    fn address_info() -> AddressInfo {
+        let now = Utc::now();
+
+        AddressInfo {
+            id: 1,
+            address: "a@vaulty.net".to_string(),
+            user_id: Some(1),
+            is_active: true,
+            quotas: Default::default(),
+            num_received: 0,
+            storage_used: 0,
+            last_renewal_time: now,
+            storage_backend: None,
+            storage_path: "/vaulty".to_string(),
+            storage_token_expiry: None,
+            is_whitelist_enabled: false,
+            whitelist: vec![],
+            filename_template: None,
+            bundle_template: None,
+            creation_time: now,
+        }
+    }
+
+    #[test]
+    fn dry_run_changes() {
+        let before = address_info();
+        let mut after = before.clone();
+
+        let update = AddressUpdate {
+            is_active: Some(false),
+            filename_template: Some("{date}/{sendr}-{name}".to_string()),
+            ..Default::default()
+        };
+        update.apply(&mut after);
+        after.quotas.email_quota = Some(10);
+
+        let changes = diff(&before, &after);
+        let fields: Vec<&str> = changes.iter().map(|c| c.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            vec!["email_quota", "filename_template", "is_active"]
+        );
+        assert_eq!(changes[0].before, Value::Null);
+        assert_eq!(changes[0].after, Value::from(10));
+
+        // Clearing a template
+        let update = AddressUpdate {
+            filename_template: Some("".to_string()),
+            ..Default::default()
+        };
+        update.apply(&mut after);
+        assert_eq!(after.filename_template, None);
+
+        let mut settings = Settings::from_config(&Default::default());
+        settings.storage_backend = Backend::S3;
+        after.is_active = false;
+        after.num_received = 10;
+        after.bundle_template = Some("{date}/{sendr}".to_string());
+        after.storage_token_expiry = Some(Utc::now() - chrono::Duration::hours(1));
+        settings.email_quota = 10;
+        settings.storage_quota = 1000;
+
+        let warnings = warnings(&after, &settings, "token", None, Utc::now());
+        assert_eq!(warnings.len(), 5, "{:?}", warnings);
+        assert!(warnings[0].starts_with("The address is inactive"));
+        assert!(warnings[1].starts_with("The storage token is not valid for S3"));
+        assert!(warnings[2].starts_with("The storage token expired"));
+        assert!(warnings[3].starts_with("bundle_template references unknown variable {sendr}"));
+        assert!(warnings[4].starts_with("The email quota of 10 is already used up"));
+
+        // Expired tokens are fine if they can be refreshed
+        let warnings = super::warnings(&after, &settings, "token", Some("refresh"), Utc::now());
+        assert_eq!(warnings.len(), 4);
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+
+use crate::config::Config;
+
+/// Email received by an address over a single day
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Volume {
+    pub num_emails: i64,
+
+    /// Total size of the emails, in bytes
+    pub total_size: i64,
+}
+
+/// A part of `Volume` that is checked for spikes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Metric {
+    Emails,
+    Bytes,
+}
+
+impl Metric {
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Emails => "emails received",
+            Self::Bytes => "bytes stored",
+        }
+    }
+
+    fn value(self, volume: &Volume) -> i64 {
+        match self {
+            Self::Emails => volume.num_emails,
+            Self::Bytes => volume.total_size,
+        }
+    }
+}
+
+/// An unusual spike in the volume of an address
+#[derive(Clone, Debug, PartialEq)]
+pub struct Anomaly {
+    pub metric: Metric,
+    pub today: i64,
+
+    /// Daily average over the previous days
+    pub average: f64,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} today, against a daily average of {:.1}",
+            self.today,
+            self.metric.description(),
+            self.average
+        )
+    }
+}
+
+/// How far above its average the volume of an address has to go to be
+/// flagged
+#[derive(Clone, Debug)]
+pub struct Thresholds {
+    /// Number of previous days the average is taken over
+    pub window_days: i64,
+
+    /// Multiple of the average that today's volume has to exceed
+    pub factor: f64,
+
+    /// Spikes below these are ignored, so that quiet addresses are not
+    /// flagged over a handful of emails
+    pub min_emails: i64,
+    pub min_bytes: i64,
+}
+
+impl Thresholds {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            window_days: config.anomaly_window_days,
+            factor: config.anomaly_factor,
+            min_emails: config.anomaly_min_emails,
+            min_bytes: config.anomaly_min_bytes,
+        }
+    }
+
+    fn min(&self, metric: Metric) -> i64 {
+        match metric {
+            Metric::Emails => self.min_emails,
+            Metric::Bytes => self.min_bytes,
+        }
+    }
+}
+
+/// Compare the volume of an address today against its rolling average over
+/// the previous days
+///
+/// `history` holds one entry per previous day, where days without email are
+/// zero. Nothing is flagged without any history.
+pub fn detect(history: &[Volume], today: &Volume, thresholds: &Thresholds) -> Vec<Anomaly> {
+    if history.is_empty() {
+        return Vec::new();
+    }
+
+    [Metric::Emails, Metric::Bytes]
+        .iter()
+        .filter_map(|&metric| {
+            let total: i64 = history.iter().map(|v| metric.value(v)).sum();
+            let average = total as f64 / history.len() as f64;
+            let value = metric.value(today);
+
+            if value >= thresholds.min(metric) && value as f64 > average * thresholds.factor {
+                Some(Anomaly {
+                    metric,
+                    today: value,
+                    average,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: i64 = 1024 * 1024;
+
+    #[test]
+    fn detect_spikes() {
+        let thresholds = Thresholds {
+            window_days: 7,
+            factor: 5.0,
+            min_emails: 50,
+            min_bytes: 100 * MB,
+        };
+
+        let day = Volume {
+            num_emails: 10,
+            total_size: 20 * MB,
+        };
+        let history = vec![day; 7];
+
+        // A normal day
+        assert!(detect(&history, &day, &thresholds).is_empty());
+
+        // Runaway report generator
+        let today = Volume {
+            num_emails: 500,
+            total_size: 150 * MB,
+        };
+        let anomalies = detect(&history, &today, &thresholds);
+
+        assert_eq!(anomalies.len(), 2);
+        assert_eq!(anomalies[0].metric, Metric::Emails);
+        assert_eq!(anomalies[0].today, 500);
+        assert_eq!(anomalies[0].average, 10.0);
+        assert_eq!(anomalies[1].metric, Metric::Bytes);
+
+        // A few large attachments, but not far above average
+        let today = Volume {
+            num_emails: 40,
+            total_size: 90 * MB,
+        };
+        assert!(detect(&history, &today, &thresholds).is_empty());
+
+        // Quiet address: a spike, but below the minimum
+        let quiet = vec![
+            Volume {
+                num_emails: 1,
+                total_size: MB,
+            };
+            7
+        ];
+        let today = Volume {
+            num_emails: 20,
+            total_size: 20 * MB,
+        };
+        assert!(detect(&quiet, &today, &thresholds).is_empty());
+
+        // No baseline yet
+        assert!(detect(&[], &today, &thresholds).is_empty());
+    }
+}
@@ -12,7 +12,117 @@ use serde::{Deserialize, Serialize};
 pub struct ServerResult {
     pub success: bool,
     pub message: Option<String>,
+    /// UUID the email is stored under. This is the UUID of the original
+    /// email if the email was sent again (e.g., on a Postfix retry), and
+    /// must be used for its attachments.
+    pub mail_id: Option<String>,
     pub storage_backend: Option<crate::storage::Backend>,
     pub num_attachments: Option<i32>,
+    /// Send the raw message to the server for .eml archival
+    pub archive_eml: Option<bool>,
+    /// Send the whole message to /postfix/message instead of the email and
+    /// its attachments. Set for addresses that bundle attachments into a
+    /// zip archive, which needs all of them at once. The email was not
+    /// accepted yet.
+    pub send_message: Option<bool>,
+    /// Attachments at least this large can be uploaded straight to the
+    /// storage backend (see `DirectUpload`)
+    pub direct_upload_min_size: Option<usize>,
+    /// Set if the attachment was received and is being stored in the
+    /// background (see `settings::DeliveryMode`)
+    pub queued: Option<bool>,
     pub error: Option<crate::Error>,
 }
+
+/// JSON body of every error response from the Vaulty server
+///
+/// Clients should act on `code` rather than `message`, as messages may be
+/// localized (see `i18n`). The body also parses as a `ServerResult`, for
+/// clients that only read the error from it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    /// Always false
+    pub success: bool,
+    /// See `Error::code`
+    pub code: String,
+    pub message: String,
+    /// UUID of the email the request was for, if any
+    pub email_uuid: Option<String>,
+    pub error: Option<crate::Error>,
+}
+
+impl ErrorResponse {
+    pub fn new(error: &crate::Error) -> Self {
+        Self {
+            success: false,
+            code: error.code().to_string(),
+            message: error.to_string(),
+            email_uuid: None,
+            error: Some(error.clone()),
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> Self {
+        self.message = message;
+        self
+    }
+
+    pub fn with_email_uuid(mut self, email_uuid: Option<String>) -> Self {
+        self.email_uuid = email_uuid;
+        self
+    }
+}
+
+/// Size of an email, sent by the client before the email itself so that
+/// oversized emails can be rejected up front
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SizeEstimate {
+    pub recipients: Vec<String>,
+    /// Size declared by the sending client (SMTP SIZE), or the size of the
+    /// raw message, in bytes
+    pub size: usize,
+}
+
+/// Attachment uploaded by the client straight to the storage backend,
+/// instead of through the server
+///
+/// The client first asks for an upload URL, uploads the attachment to it,
+/// and then sends the same request again to confirm the upload.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DirectUpload {
+    pub mail_id: String,
+    pub index: u16,
+    pub name: String,
+    pub content_type: String,
+    pub size: usize,
+    /// Hex SHA-256 of the attachment
+    pub sha256: String,
+}
+
+/// Where to upload an attachment to, in reply to a `DirectUpload`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UploadUrl {
+    /// Not set if the attachment must be sent to the server as usual
+    /// (e.g., it is dropped by a rule, or would exceed the address quota)
+    pub url: Option<String>,
+    /// Headers that must be sent as is with the PUT request
+    pub headers: Vec<(String, String)>,
+    pub message: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_response_is_server_result() {
+        let resp = ErrorResponse::new(&crate::Error::InvalidRecipient)
+            .with_email_uuid(Some("6d2b1c3e".to_string()));
+        let json = serde_json::to_string(&resp).unwrap();
+
+        let result: ServerResult = serde_json::from_str(&json).unwrap();
+        assert!(!result.success);
+        assert_eq!(result.message, Some(resp.message));
+        assert!(matches!(result.error, Some(crate::Error::InvalidRecipient)));
+    }
+}
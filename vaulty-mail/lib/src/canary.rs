@@ -0,0 +1,123 @@
+use lettre::SendableEmail;
+use lettre_email::Email;
+
+use crate::Error;
+
+/// Name of the file attached to canary emails
+pub const CANARY_ATTACHMENT: &str = "vaulty-canary.txt";
+
+/// Name of the file attached to test emails
+pub const TEST_ATTACHMENT: &str = "vaulty-test.txt";
+
+/// Folder test emails are stored in, under the storage path of the address
+pub const TEST_FOLDER: &str = "Vaulty Test";
+
+/// Message ID of the canary email with the given ID
+///
+/// Stored emails are looked up by this to check that the canary made it
+/// through.
+pub fn message_id(id: &uuid::Uuid) -> String {
+    format!("canary-{}@vaulty", id)
+}
+
+/// Build a synthetic email to send to a canary address
+///
+/// The email is sent from the canary address to itself, so that it is not
+/// mistaken for one of our replies, and has a single small attachment so
+/// that it exercises the full pipeline through to storage.
+pub fn build(address: &str, id: &uuid::Uuid) -> Result<SendableEmail, Error> {
+    let content = format!("Vaulty canary {}\n", id);
+
+    let email: SendableEmail = Email::builder()
+        .to(address)
+        .from(address)
+        .subject(format!("Vaulty canary {}", id))
+        .text("This is an automated Vaulty self-test.")
+        .attachment(content.as_bytes(), CANARY_ATTACHMENT, &mime::TEXT_PLAIN)
+        .and_then(|builder| builder.build())
+        .map_err(|e| Error::Generic(format!("Failed to build canary: {}", e)))?
+        .into();
+
+    // lettre always adds a Message-ID of its own, so it is swapped for ours
+    let envelope = email.envelope().clone();
+    let generated = format!("<{}.lettre@localhost>", email.message_id());
+    let raw = email
+        .message_to_string()
+        .map_err(|e| Error::Generic(format!("Failed to build canary: {}", e)))?
+        .replacen(&generated, &format!("<{}>", message_id(id)), 1);
+
+    Ok(SendableEmail::new(
+        envelope,
+        message_id(id),
+        raw.into_bytes(),
+    ))
+}
+
+/// Build a test email for an address, to check its storage connection
+///
+/// Unlike the canary, the test email does not go through SMTP: it is handed
+/// straight to the server, from the address to itself. It is marked as a
+/// test so that it is recorded and stored apart from real email.
+pub fn test_email(address: &str, id: &uuid::Uuid) -> Result<crate::email::Email, Error> {
+    let content = format!("Vaulty test {}\n", id);
+
+    let email: SendableEmail = Email::builder()
+        .to(address)
+        .from(address)
+        .subject("Vaulty test email")
+        .header(("Message-ID", format!("<test-{}@vaulty>", id)))
+        .text("This is a test email sent by Vaulty to check your storage connection.")
+        .attachment(content.as_bytes(), TEST_ATTACHMENT, &mime::TEXT_PLAIN)
+        .and_then(|builder| builder.build())
+        .map_err(|e| Error::Generic(format!("Failed to build test email: {}", e)))?
+        .into();
+
+    let raw = email
+        .message_to_string()
+        .map_err(|e| Error::Generic(format!("Failed to build test email: {}", e)))?;
+
+    let mut email = crate::email::Email::from_mime(raw.as_bytes())
+        .map_err(|e| Error::Generic(format!("Failed to parse test email: {}", e)))?
+        .with_sender(address.to_string())
+        .with_recipients(vec![address.to_string()]);
+    email.is_test = true;
+
+    Ok(email)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canary_is_parsed() {
+        let id = uuid::Uuid::from_u128(42);
+        let raw = build("canary@vaulty.net", &id)
+            .unwrap()
+            .message_to_string()
+            .unwrap();
+
+        let mut email = crate::email::Email::from_mime(raw.as_bytes()).unwrap();
+
+        assert_eq!(email.message_id, Some(message_id(&id)));
+        assert!(email.auto_generated.is_none());
+
+        let attachments = email.attachments.take().unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].get_name(), CANARY_ATTACHMENT);
+    }
+
+    #[test]
+    fn test_email_is_marked() {
+        let id = uuid::Uuid::from_u128(42);
+        let mut email = test_email("test1@vaulty.net", &id).unwrap();
+
+        assert!(email.is_test);
+        assert_eq!(email.sender, "test1@vaulty.net");
+        assert_eq!(email.recipients, vec!["test1@vaulty.net".to_string()]);
+
+        let attachments = email.attachments.take().unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].get_name(), TEST_ATTACHMENT);
+    }
+}
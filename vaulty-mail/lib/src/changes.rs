@@ -0,0 +1,183 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::Error;
+
+/// Default number of changes returned per page of the change feed
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Changes are only served once they are this old, in seconds
+///
+/// Change IDs are assigned on insert, but concurrent inserts may commit out
+/// of order. Holding back recent changes keeps a client that is caught up
+/// from moving its cursor past a change that has not committed yet.
+pub const SETTLE_DELAY: i64 = 5;
+
+/// State transition of an email or attachment
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// The email was accepted, and its attachments are on the way
+    EmailCreated,
+    /// The email and all of its attachments were stored
+    EmailStored,
+    EmailFailed,
+    AttachmentStored,
+    AttachmentFailed,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::EmailCreated => "email_created",
+            Self::EmailStored => "email_stored",
+            Self::EmailFailed => "email_failed",
+            Self::AttachmentStored => "attachment_stored",
+            Self::AttachmentFailed => "attachment_failed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Self::EmailCreated,
+            Self::EmailStored,
+            Self::EmailFailed,
+            Self::AttachmentStored,
+            Self::AttachmentFailed,
+        ]
+        .iter()
+        .copied()
+        .find(|k| k.name() == name)
+    }
+}
+
+/// A single entry in the change feed
+#[derive(Clone, Debug, Serialize)]
+pub struct Change {
+    pub kind: Kind,
+    pub mail_id: Uuid,
+    pub address: String,
+
+    /// Index of the attachment, for attachment changes
+    pub index: Option<i32>,
+
+    pub error_msg: Option<String>,
+    pub time: DateTime<Utc>,
+
+    /// Position of this change in the feed
+    #[serde(skip)]
+    pub cursor: Cursor,
+}
+
+/// Position in the change feed
+///
+/// Cursors are opaque to clients: a client passes back the cursor of the
+/// last page it got to get the changes that came after it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor(pub i64);
+
+impl Cursor {
+    pub fn encode(self) -> String {
+        hex::encode(self.0.to_be_bytes())
+    }
+
+    pub fn decode(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidRequest(format!("Invalid change feed cursor: {}", s));
+
+        let bytes = hex::decode(s).map_err(|_| invalid())?;
+
+        if bytes.len() != 8 {
+            return Err(invalid());
+        }
+
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&bytes);
+
+        Ok(Self(i64::from_be_bytes(buf)))
+    }
+}
+
+/// A page of the change feed
+#[derive(Clone, Debug, Serialize)]
+pub struct Page {
+    pub changes: Vec<Change>,
+
+    /// Cursor to pass to get the next page
+    ///
+    /// This is the cursor that was passed in if there are no new changes.
+    pub cursor: String,
+
+    /// True if more changes are available right away
+    pub has_more: bool,
+}
+
+impl Page {
+    /// Build a page from the changes after `since`
+    ///
+    /// `changes` must hold up to `limit + 1` changes, so that it is known
+    /// whether there are more.
+    pub fn new(mut changes: Vec<Change>, since: Cursor, limit: usize) -> Self {
+        let has_more = changes.len() > limit;
+        changes.truncate(limit);
+
+        let cursor = changes.last().map_or(since, |c| c.cursor);
+
+        Self {
+            changes,
+            cursor: cursor.encode(),
+            has_more,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(id: i64) -> Change {
+        Change {
+            kind: Kind::EmailCreated,
+            mail_id: Uuid::nil(),
+            address: "test@vaulty.net".to_string(),
+            index: None,
+            error_msg: None,
+            time: Utc::now(),
+            cursor: Cursor(id),
+        }
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        for &id in &[0, 1, 42, i64::MAX] {
+            let cursor = Cursor(id);
+            assert_eq!(Cursor::decode(&cursor.encode()).unwrap(), cursor);
+        }
+
+        assert!(Cursor::decode("").is_err());
+        assert!(Cursor::decode("zz").is_err());
+        assert!(Cursor::decode("0102").is_err());
+    }
+
+    #[test]
+    fn pages() {
+        let page = Page::new(vec![change(3), change(4), change(5)], Cursor(2), 2);
+        assert_eq!(page.changes.len(), 2);
+        assert_eq!(page.cursor, Cursor(4).encode());
+        assert!(page.has_more);
+
+        let page = Page::new(vec![change(5)], Cursor(4), 2);
+        assert_eq!(page.cursor, Cursor(5).encode());
+        assert!(!page.has_more);
+
+        // The cursor stays put until there are new changes
+        let page = Page::new(Vec::new(), Cursor(5), 2);
+        assert_eq!(page.cursor, Cursor(5).encode());
+        assert!(!page.has_more);
+
+        let json = serde_json::to_value(change(1)).unwrap();
+        assert_eq!(json["kind"], "email_created");
+        assert!(json.get("cursor").is_none());
+    }
+}
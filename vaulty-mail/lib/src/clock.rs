@@ -0,0 +1,69 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+
+/// Source of the current time.
+///
+/// Anything that needs "now" (e.g., DB timestamps, quota windows) should go
+/// through a `Clock` so that tests can freeze and advance time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// Wall clock time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that is frozen at a given time and only moves when told to
+#[derive(Debug)]
+pub struct FixedClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl FixedClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += duration;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_clock_advance() {
+        let start = "2020-02-09T19:38:12Z".parse::<DateTime<Utc>>().unwrap();
+        let clock = FixedClock::new(start);
+
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::days(1));
+        assert_eq!(clock.now(), start + Duration::days(1));
+
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
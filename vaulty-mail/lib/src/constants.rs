@@ -1,3 +1,12 @@
 pub const VAULTY_EMAIL_ID: &str = "Vaulty-Email-ID";
 pub const VAULTY_ATTACHMENT_NAME: &str = "Vaulty-Attachment-Name";
 pub const VAULTY_ATTACHMENT_INDEX: &str = "Vaulty-Attachment-Index";
+
+/// Envelope of an email whose whole message is sent to the server (see
+/// `ServerResult::send_message`). Recipients are comma-separated.
+pub const VAULTY_SENDER: &str = "Vaulty-Sender";
+pub const VAULTY_RECIPIENTS: &str = "Vaulty-Recipients";
+
+/// Hex SHA-256 of an attachment, used to detect attachments that were
+/// already stored for an email
+pub const VAULTY_ATTACHMENT_SHA256: &str = "Vaulty-Attachment-SHA256";
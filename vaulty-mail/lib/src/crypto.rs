@@ -0,0 +1,376 @@
+use std::fmt;
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use bytes::{Bytes, BytesMut};
+use futures::stream::{self, Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::pipeline::Data;
+use crate::Error;
+
+/// Encrypted files start with this
+pub const MAGIC: &[u8] = b"VAULTYE1";
+
+/// Secrets encrypted with `seal` start with this
+const SEALED_PREFIX: &str = "sealed:v1:";
+
+const KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+const HEADER_SIZE: usize = MAGIC.len() + SALT_SIZE;
+
+/// Files are encrypted in segments of this size, so that they can be
+/// encrypted as they are uploaded
+const SEGMENT_SIZE: usize = 64 * 1024;
+
+/// AES-256 key of an address, or the master key that address keys are
+/// encrypted with in the DB
+#[derive(Clone)]
+pub struct Key([u8; KEY_SIZE]);
+
+impl Key {
+    pub fn generate() -> Self {
+        Self(rand::random())
+    }
+
+    pub fn from_hex(key: &str) -> Result<Self, Error> {
+        let invalid = |e: String| Error::Generic(format!("Invalid encryption key: {}", e));
+
+        let bytes = hex::decode(key.trim()).map_err(|e| invalid(e.to_string()))?;
+        if bytes.len() != KEY_SIZE {
+            return Err(invalid(format!("expected {} bytes", KEY_SIZE)));
+        }
+
+        let mut key = [0; KEY_SIZE];
+        key.copy_from_slice(&bytes);
+
+        Ok(Self(key))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    /// Encrypt this key with the master key, for storage in the DB
+    pub fn wrap(&self, master: &Key) -> String {
+        hex::encode(master.encrypt_message(&self.0))
+    }
+
+    /// Decrypt a key stored in the DB with the master key
+    pub fn unwrap(master: &Key, wrapped: &str) -> Result<Self, Error> {
+        let invalid = || Error::Generic("Encryption key cannot be decrypted".to_string());
+
+        let wrapped = hex::decode(wrapped.trim()).map_err(|_| invalid())?;
+        if wrapped.len() != NONCE_SIZE + KEY_SIZE + TAG_SIZE {
+            return Err(invalid());
+        }
+
+        let key = master.decrypt_message(&wrapped).ok_or_else(invalid)?;
+
+        let mut out = [0; KEY_SIZE];
+        out.copy_from_slice(&key);
+
+        Ok(Self(out))
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(GenericArray::from_slice(&self.0))
+    }
+
+    /// Encrypt a short message with a random nonce, which is prepended to it
+    fn encrypt_message(&self, message: &[u8]) -> Vec<u8> {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let encrypted = self
+            .cipher()
+            .encrypt(GenericArray::from_slice(&nonce), message)
+            .expect("Messages fit in a single segment");
+
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&encrypted);
+        out
+    }
+
+    fn decrypt_message(&self, message: &[u8]) -> Option<Vec<u8>> {
+        if message.len() < NONCE_SIZE + TAG_SIZE {
+            return None;
+        }
+
+        let (nonce, encrypted) = message.split_at(NONCE_SIZE);
+        self.cipher()
+            .decrypt(GenericArray::from_slice(nonce), encrypted)
+            .ok()
+    }
+
+    /// Cipher for a single file, so that nonces are never reused across
+    /// files
+    fn file_cipher(&self, salt: &[u8]) -> Aes256Gcm {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.0).expect("HMAC accepts keys of any size");
+        mac.input(salt);
+
+        Aes256Gcm::new(GenericArray::from_slice(mac.result().code().as_slice()))
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Encrypt a secret (e.g., a storage token) for storage in the DB
+pub fn seal(key: &Key, secret: &str) -> String {
+    format!(
+        "{}{}",
+        SEALED_PREFIX,
+        hex::encode(key.encrypt_message(secret.as_bytes()))
+    )
+}
+
+/// Returns true if a stored secret was encrypted with `seal`
+pub fn is_sealed(stored: &str) -> bool {
+    stored.starts_with(SEALED_PREFIX)
+}
+
+/// Decrypt a secret stored with `seal`
+///
+/// Secrets stored before encryption was enabled are returned as they are,
+/// so that they keep working until they are encrypted.
+pub fn open(key: Option<&Key>, stored: &str) -> Result<String, Error> {
+    if !is_sealed(stored) {
+        return Ok(stored.to_string());
+    }
+
+    let key = key.ok_or_else(|| {
+        Error::Generic("Secret is encrypted, but no key is configured to decrypt it".to_string())
+    })?;
+
+    hex::decode(&stored[SEALED_PREFIX.len()..])
+        .ok()
+        .and_then(|sealed| key.decrypt_message(&sealed))
+        .and_then(|secret| String::from_utf8(secret).ok())
+        .ok_or_else(|| {
+            Error::Generic(
+                "Secret cannot be decrypted: it is corrupt, or was encrypted with another key"
+                    .to_string(),
+            )
+        })
+}
+
+/// Nonce of a segment of a file
+///
+/// The last segment is marked as such, so that a file cut short at a
+/// segment boundary does not decrypt.
+fn nonce(counter: u64, is_last: bool) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[3..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = is_last as u8;
+    nonce
+}
+
+/// Returns true if the data looks like an encrypted file
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+struct Encryptor {
+    cipher: Aes256Gcm,
+    data: Data,
+    buf: BytesMut,
+    counter: u64,
+    done: bool,
+}
+
+impl Encryptor {
+    async fn next_segment(&mut self) -> Option<Result<Bytes, Error>> {
+        if self.done {
+            return None;
+        }
+
+        // More than a segment is buffered before one is sealed, so that the
+        // last segment is known when it is sealed
+        while self.buf.len() <= SEGMENT_SIZE {
+            match self.data.next().await {
+                Some(Ok(chunk)) => self.buf.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                None => {
+                    self.done = true;
+                    let segment = self.buf.split_to(self.buf.len());
+                    return Some(self.seal(&segment, true));
+                }
+            }
+        }
+
+        let segment = self.buf.split_to(SEGMENT_SIZE);
+        Some(self.seal(&segment, false))
+    }
+
+    fn seal(&mut self, segment: &[u8], is_last: bool) -> Result<Bytes, Error> {
+        let nonce = nonce(self.counter, is_last);
+        self.counter += 1;
+
+        self.cipher
+            .encrypt(GenericArray::from_slice(&nonce), segment)
+            .map(Bytes::from)
+            .map_err(|_| Error::Generic("Failed to encrypt file".to_string()))
+    }
+}
+
+/// Encrypt a file as it is streamed
+///
+/// Each file is encrypted with its own key, derived from the key of the
+/// address and a random salt stored in the file header. The content is
+/// split into segments, each sealed with AES-256-GCM, so files can be
+/// decrypted without trusting any part of them (see `decrypt`).
+pub fn encrypt(
+    key: &Key,
+    data: impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static,
+) -> impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static {
+    let salt: [u8; SALT_SIZE] = rand::random();
+
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&salt);
+
+    let encryptor = Encryptor {
+        cipher: key.file_cipher(&salt),
+        data: Box::pin(data),
+        buf: BytesMut::new(),
+        counter: 0,
+        done: false,
+    };
+
+    let segments = stream::unfold(encryptor, |mut encryptor| async move {
+        encryptor
+            .next_segment()
+            .await
+            .map(|segment| (segment, encryptor))
+    });
+
+    stream::iter(vec![Ok(Bytes::from(header))]).chain(segments)
+}
+
+/// Decrypt a file encrypted by `encrypt`
+pub fn decrypt(key: &Key, data: &[u8]) -> Result<Vec<u8>, Error> {
+    if !is_encrypted(data) || data.len() < HEADER_SIZE {
+        return Err(Error::Generic("Not an encrypted Vaulty file".to_string()));
+    }
+
+    let cipher = key.file_cipher(&data[MAGIC.len()..HEADER_SIZE]);
+
+    let mut out = Vec::with_capacity(data.len());
+    let mut rest = &data[HEADER_SIZE..];
+    let mut counter = 0;
+
+    loop {
+        let is_last = rest.len() <= SEGMENT_SIZE + TAG_SIZE;
+        let (segment, next) = rest.split_at(rest.len().min(SEGMENT_SIZE + TAG_SIZE));
+
+        let plain = cipher
+            .decrypt(GenericArray::from_slice(&nonce(counter, is_last)), segment)
+            .map_err(|_| {
+                Error::Generic(
+                    "File is corrupt or truncated, or was encrypted with another key".to_string(),
+                )
+            })?;
+        out.extend_from_slice(&plain);
+
+        if is_last {
+            return Ok(out);
+        }
+
+        rest = next;
+        counter += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::stream::TryStreamExt;
+
+    async fn encrypted(key: &Key, data: &[u8]) -> Vec<u8> {
+        // Chunks do not line up with segments
+        let chunks: Vec<Result<Bytes, Error>> = data
+            .chunks(10_000)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+
+        encrypt(key, stream::iter(chunks))
+            .try_fold(Vec::new(), |mut buf, chunk| async move {
+                buf.extend_from_slice(&chunk);
+                Ok(buf)
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn encrypt_files() {
+        let key = Key::generate();
+
+        for &size in &[0, 1, SEGMENT_SIZE, 2 * SEGMENT_SIZE + 1] {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let file = encrypted(&key, &data).await;
+
+            assert!(is_encrypted(&file));
+            assert_eq!(decrypt(&key, &file).unwrap(), data);
+
+            // Files are cut short, tampered with, or decrypted with the
+            // wrong key
+            assert!(decrypt(&key, &file[..file.len() - 1]).is_err());
+            if size > SEGMENT_SIZE {
+                assert!(decrypt(&key, &file[..HEADER_SIZE + SEGMENT_SIZE + TAG_SIZE]).is_err());
+            }
+
+            let mut tampered = file.clone();
+            tampered[HEADER_SIZE] ^= 1;
+            assert!(decrypt(&key, &tampered).is_err());
+
+            assert!(decrypt(&Key::generate(), &file).is_err());
+        }
+
+        // The same content encrypts differently each time
+        assert_ne!(encrypted(&key, b"a").await, encrypted(&key, b"a").await);
+    }
+
+    #[test]
+    fn wrap_keys() {
+        let master = Key::generate();
+        let key = Key::generate();
+
+        let wrapped = key.wrap(&master);
+        assert_eq!(Key::unwrap(&master, &wrapped).unwrap().0, key.0);
+        assert!(Key::unwrap(&Key::generate(), &wrapped).is_err());
+        assert!(Key::unwrap(&master, "00").is_err());
+
+        assert_eq!(Key::from_hex(&key.to_hex()).unwrap().0, key.0);
+        assert!(Key::from_hex("abcd").is_err());
+    }
+
+    #[test]
+    fn seal_secrets() {
+        let key = Key::generate();
+
+        let sealed = seal(&key, "sl.token");
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains("sl.token"));
+        assert_eq!(open(Some(&key), &sealed).unwrap(), "sl.token");
+        assert_ne!(seal(&key, "sl.token"), sealed);
+
+        // Secrets stored before encryption was enabled
+        assert_eq!(open(Some(&key), "sl.token").unwrap(), "sl.token");
+        assert_eq!(open(None, "sl.token").unwrap(), "sl.token");
+
+        assert!(open(None, &sealed).is_err());
+        assert!(open(Some(&Key::generate()), &sealed).is_err());
+        assert!(open(Some(&key), "sealed:v1:00").is_err());
+    }
+}
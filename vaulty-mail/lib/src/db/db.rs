@@ -1,11 +1,35 @@
-use crate::email::Email;
+use std::collections::HashMap;
+use std::sync::Arc;
 
-use chrono::{DateTime, Utc};
+use crate::email::{Email, Importance};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::Row;
 
+use crate::admin::{
+    AddressInfo, AddressUpdate, AttachmentStatus, EmailStatus, LogEntry, NewAddress, NewUser,
+    Quotas, Storage, User, UserUpdate,
+};
+use crate::anomaly::Volume;
+use crate::changes;
+use crate::clock::{Clock, SystemClock};
+use crate::crypto;
+use crate::directive::Directives;
+use crate::export;
+use crate::faults;
+use crate::manifest;
+use crate::notify::Webhook;
+use crate::rules::{Priority, PriorityRule, Route, Rule};
+use crate::settings::{AutoGeneratedPolicy, DeliveryMode, Organization, Settings, SettingsLayer};
+use crate::stats;
 use crate::storage;
+use crate::whitelist::Whitelist;
 use crate::Error;
 
+use super::retry::RetryPolicy;
+
 pub enum LogLevel {
     Debug,
     Info,
@@ -25,55 +49,325 @@ impl From<i32> for LogLevel {
     }
 }
 
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warning => "warning",
+            LogLevel::Error => "error",
+        }
+    }
+}
+
 #[allow(dead_code)]
 const USER_TABLE: &str = "vaulty_users";
 const ADDRESS_TABLE: &str = "vaulty_addresses";
+const DOMAIN_TABLE: &str = "vaulty_domains";
 const MAIL_TABLE: &str = "vaulty_mail";
 const ATTACHMENT_TABLE: &str = "vaulty_attachments";
 const LOG_TABLE: &str = "vaulty_logs";
+const SAMPLE_TABLE: &str = "vaulty_samples";
+const RULE_TABLE: &str = "vaulty_attachment_rules";
+const PRIORITY_RULE_TABLE: &str = "vaulty_priority_rules";
+const STORAGE_ROUTE_TABLE: &str = "vaulty_storage_routes";
+const WEBHOOK_TABLE: &str = "vaulty_webhooks";
+const CHANGE_TABLE: &str = "vaulty_changes";
+const API_USER_TABLE: &str = "vaulty_api_users";
+const INDEXED_FILE_TABLE: &str = "vaulty_indexed_files";
+
+/// Access tokens are refreshed this long before they expire
+const TOKEN_EXPIRY_MARGIN_MINS: i64 = 5;
+
+/// Number of indexed files inserted per query, which keeps the number of
+/// parameters (4 per file) well under the Postgres limit of 65535
+const INDEX_INSERT_CHUNK_SIZE: usize = 1000;
+
+/// Check that an email of the given size fits in the max email size and
+/// storage quota of an address, given its current storage use
+fn check_size(
+    address: &str,
+    settings: &Settings,
+    storage_used: i64,
+    size: usize,
+) -> Option<String> {
+    if size as i64 > settings.max_email_size as i64 {
+        Some(format!(
+            "This email is larger than allowed for {}: the maximum email size is {} MB.",
+            address,
+            (settings.max_email_size / 1_000_000),
+        ))
+    } else if (storage_used + size as i64) > settings.storage_quota {
+        Some(format!(
+            "Address {} has hit its storage quota of {} MB for this period.",
+            address,
+            (settings.storage_quota / 1_000_000)
+        ))
+    } else {
+        None
+    }
+}
+
+/// Query for addresses along with the defaults of their domain, with the
+/// given filter (e.g., a WHERE clause)
+fn address_query(filter: &str) -> String {
+    format!(
+        "SELECT a.*,
+            array_to_string(a.pipeline_stages, ',') AS pipeline,
+            d.email_quota AS domain_email_quota,
+            d.storage_quota AS domain_storage_quota,
+            d.max_email_size AS domain_max_email_size,
+            d.storage_backend AS domain_storage_backend,
+            d.reply_on_success AS domain_reply_on_success,
+            d.reply_on_rejection AS domain_reply_on_rejection,
+            d.skip_unchanged AS domain_skip_unchanged,
+            d.dedup_attachments AS domain_dedup_attachments,
+            d.transliterate_filenames AS domain_transliterate_filenames,
+            d.store_body AS domain_store_body,
+            d.archive_eml AS domain_archive_eml,
+            d.archive_links AS domain_archive_links,
+            d.body_pdf AS domain_body_pdf,
+            d.sign_manifests AS domain_sign_manifests,
+            d.auto_generated_policy AS domain_auto_generated_policy,
+            d.delivery_mode AS domain_delivery_mode
+        FROM {} a
+        LEFT JOIN {} d ON d.domain = split_part(a.address, '@', 2)
+        {}",
+        ADDRESS_TABLE, DOMAIN_TABLE, filter
+    )
+}
+
+/// Columns of a user, as shown by the admin API
+const USER_COLUMNS: &str = "id, username, email, is_active, is_subscribed, date_joined";
+
+/// Columns of an address, as shown by the admin API
+const ADDRESS_INFO_COLUMNS: &str = "id, address, user_id, is_active, email_quota,
+    max_email_size, storage_quota, num_received, storage_used, last_renewal_time,
+    storage_backend, storage_path, storage_token_expiry, is_whitelist_enabled,
+    array_to_string(whitelist, ',') AS whitelist, filename_template, bundle_template,
+    creation_time";
+
+fn split_list(list: Option<String>) -> Vec<String> {
+    list.map(|s| {
+        s.split(',')
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn user_from_row(row: &sqlx::postgres::PgRow) -> User {
+    User {
+        id: row.get("id"),
+        username: row.get("username"),
+        email: row.get("email"),
+        is_active: row.get("is_active"),
+        is_subscribed: row.get("is_subscribed"),
+        date_joined: row.get("date_joined"),
+    }
+}
+
+fn address_info_from_row(row: &sqlx::postgres::PgRow) -> AddressInfo {
+    AddressInfo {
+        id: row.get("id"),
+        address: row.get("address"),
+        user_id: row.get("user_id"),
+        is_active: row.get("is_active"),
+        quotas: Quotas {
+            email_quota: row.get("email_quota"),
+            max_email_size: row.get("max_email_size"),
+            storage_quota: row.get("storage_quota"),
+        },
+        num_received: row.get("num_received"),
+        storage_used: row.get("storage_used"),
+        last_renewal_time: row.get("last_renewal_time"),
+        storage_backend: row.get("storage_backend"),
+        storage_path: row.get("storage_path"),
+        storage_token_expiry: row.get("storage_token_expiry"),
+        is_whitelist_enabled: row.get("is_whitelist_enabled"),
+        whitelist: split_list(row.get("whitelist")),
+        filename_template: row.get("filename_template"),
+        bundle_template: row.get("bundle_template"),
+        creation_time: row.get("creation_time"),
+    }
+}
+
+fn default_active() -> bool {
+    true
+}
 
 /// Single address row in DB
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Address {
     pub address: String,
     pub user_id: i32,
-    pub email_quota: i32,
+
+    /// Deactivated addresses are kept in the DB, but no longer receive
+    /// email
+    #[serde(default = "default_active")]
+    pub is_active: bool,
+
     pub num_received: i32,
-    pub max_email_size: i32,
-    pub storage_quota: i64,
     pub storage_used: i64,
     pub storage_token: String,
-    pub storage_backend: storage::Backend,
     pub storage_path: String,
     pub last_renewal_time: DateTime<Utc>,
+
+    /// OAuth2 refresh token and access token expiry, if the backend uses
+    /// short-lived access tokens
+    pub storage_refresh_token: Option<String>,
+    pub storage_token_expiry: Option<DateTime<Utc>>,
+
+    /// Dropbox Business namespace (e.g., team space) and team member to
+    /// upload as, if not the token owner's personal space
+    pub dropbox_namespace_id: Option<String>,
+    pub dropbox_team_member_id: Option<String>,
+
+    /// Templates of replies to senders, if not the defaults. See
+    /// `reply::Mailer`.
+    pub reply_success_template: Option<String>,
+    pub reply_rejection_template: Option<String>,
+
+    /// Linked files matching this pattern are fetched and stored when
+    /// archiving links. See `links::matches`.
+    pub link_archive_pattern: Option<String>,
+
+    /// Names of the stages that attachments go through before they are
+    /// stored, in order. See `pipeline::builtin`.
+    #[serde(default)]
+    pub pipeline: Vec<String>,
+
+    /// Template for the names attachments are stored under, if not their
+    /// original names. See `filename::Template`.
+    #[serde(default)]
+    pub filename_template: Option<String>,
+
+    /// Subfolders uploads are organized into (e.g., by date)
+    #[serde(default)]
+    pub organization: Organization,
+
+    /// Store the attachments of each email as a single zip archive. See
+    /// `EmailHandler::bundle`.
+    #[serde(default)]
+    pub bundle_attachments: bool,
+
+    /// Template for the names of zip archives, if not the email date and
+    /// subject. See `EmailHandler::with_bundle_template`.
+    #[serde(default)]
+    pub bundle_template: Option<String>,
+
+    /// Encrypt files before they are uploaded, with the key of the address.
+    /// See `crypto`.
+    #[serde(default)]
+    pub encrypt_files: bool,
+
+    /// Key of the address, encrypted with the master key, once created
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+
+    /// Storage folder whose existing files were last indexed, if any. See
+    /// `Client::index_files`.
+    #[serde(default)]
+    pub indexed_storage_path: Option<String>,
+
+    /// Effective settings for this address, resolved from the deployment,
+    /// domain, and address layers
+    pub settings: Settings,
+
+    /// Raw domain and address layers, kept to explain the resolved settings
+    pub domain_settings: SettingsLayer,
+    pub address_settings: SettingsLayer,
 }
 
 impl Address {
     const TABLE_NAME: &'static str = ADDRESS_TABLE;
 
-    /// Validates sender address by checking that it is in the list of
-    /// whitelisted senders for this recipient.
-    pub async fn validate_sender(
-        &self,
-        email: &Email,
-        db_client: &mut Client<'_>,
-    ) -> Result<bool, Error> {
-        let sender = &email.sender;
-        let recipient = &self.address;
+    /// End of the current quota period for this address
+    pub fn quota_period_end(&self, period: Duration) -> DateTime<Utc> {
+        self.last_renewal_time + period
+    }
+
+    /// Returns true if the storage access token has expired or is about to
+    ///
+    /// Tokens with no known expiry are assumed to be valid.
+    pub fn is_token_expired(&self, clock: &dyn Clock) -> bool {
+        self.storage_token_expiry
+            .map(|expiry| clock.now() + Duration::minutes(TOKEN_EXPIRY_MARGIN_MINS) >= expiry)
+            .unwrap_or(false)
+    }
+
+    /// Returns true if the files already in the storage folder of this
+    /// address were not indexed yet, e.g., as it was just pointed at the
+    /// folder
+    ///
+    /// Only Dropbox folders are indexed.
+    pub fn needs_index(&self) -> bool {
+        matches!(self.settings.storage_backend, storage::Backend::Dropbox)
+            && self.indexed_storage_path.as_ref() != Some(&self.storage_path)
+    }
+
+    /// Check that an email of the given size fits in the max email size and
+    /// remaining storage quota of this address
+    ///
+    /// Returns the reason the email does not fit, if any.
+    pub fn check_size(&self, size: usize) -> Option<String> {
+        check_size(&self.address, &self.settings, self.storage_used, size)
+    }
+
+    /// Returns true if the current quota period has elapsed and the address
+    /// quota is due for renewal
+    pub fn is_renewal_due(&self, clock: &dyn Clock, period: Duration) -> bool {
+        clock.now() >= self.quota_period_end(period)
+    }
 
+    /// Load the whitelist of this address, and whether it is enabled
+    async fn load_whitelist(&self, db_client: &mut Client<'_>) -> Result<(bool, Whitelist), Error> {
         let query = format!(
-            "SELECT is_active FROM {} WHERE ($1 = ANY (whitelist) OR is_whitelist_enabled = false)
-            AND address = $2",
+            "SELECT is_whitelist_enabled, array_to_string(whitelist, ',') AS whitelist
+            FROM {} WHERE address = $1",
             Self::TABLE_NAME
         );
 
         let row = sqlx::query(&query)
-            .bind(sender)
-            .bind(recipient)
+            .bind(&self.address)
             .fetch_optional(db_client.db)
             .await?;
 
-        if row.is_none() {
+        Ok(match row {
+            Some(row) => (
+                row.get("is_whitelist_enabled"),
+                Whitelist::new(&split_list(row.get("whitelist"))),
+            ),
+            None => (true, Whitelist::default()),
+        })
+    }
+
+    /// Check that a sender can send email to this address, i.e., that the
+    /// sender matches an entry of the whitelist or the whitelist is disabled
+    ///
+    /// Unlike `validate_sender`, rejected senders are not logged.
+    pub async fn allows_sender(
+        &self,
+        sender: &str,
+        db_client: &mut Client<'_>,
+    ) -> Result<bool, Error> {
+        let (is_enabled, whitelist) = self.load_whitelist(db_client).await?;
+
+        Ok(!is_enabled || whitelist.allows(sender))
+    }
+
+    /// Validates sender address by checking that it matches the whitelist of
+    /// this recipient (see `whitelist::Entry`).
+    pub async fn validate_sender(
+        &self,
+        email: &Email,
+        db_client: &mut Client<'_>,
+    ) -> Result<bool, Error> {
+        let sender = &email.sender;
+        let recipient = &self.address;
+
+        if !self.allows_sender(sender, db_client).await? {
             let msg = format!(
                 "Rejecting email {} (Message-ID: {}): sender {} is not on {} whitelist",
                 &email.uuid,
@@ -93,6 +387,48 @@ impl Address {
         }
     }
 
+    /// Check that the sender of an email is explicitly on the whitelist of
+    /// this address, i.e., that it matches an exact entry
+    ///
+    /// Unlike `validate_sender`, senders that only match a wildcard or regex
+    /// entry are not on the whitelist, and neither is any sender if the
+    /// whitelist has no exact entries.
+    pub async fn is_whitelisted(
+        &self,
+        email: &Email,
+        db_client: &mut Client<'_>,
+    ) -> Result<bool, Error> {
+        let (_, whitelist) = self.load_whitelist(db_client).await?;
+
+        Ok(whitelist.lists(&email.sender))
+    }
+
+    /// Store the encryption key of this address, encrypted with the master
+    /// key, unless it already has one
+    ///
+    /// Returns the key the address ends up with, which is the existing key
+    /// if another request stored one first.
+    pub async fn set_encryption_key(
+        &self,
+        wrapped: &str,
+        db_client: &mut Client<'_>,
+    ) -> Result<String, Error> {
+        let query = format!(
+            "UPDATE {} SET encryption_key = COALESCE(encryption_key, $2)
+            WHERE address = $1
+            RETURNING encryption_key",
+            Self::TABLE_NAME
+        );
+
+        let row = sqlx::query(&query)
+            .bind(&self.address)
+            .bind(wrapped)
+            .fetch_one(db_client.db)
+            .await?;
+
+        Ok(row.get("encryption_key"))
+    }
+
     /// Update address storage use for this address
     pub async fn update_storage_used(
         &self,
@@ -120,65 +456,328 @@ impl Address {
             )
         };
 
-        let _num_rows = sqlx::query(&query)
-            .bind(&self.address)
-            .execute(db_client.db)
-            .await?;
+        // Only retried if the update was not applied, as it is not idempotent
+        let mut attempt = 1;
+        loop {
+            let result = sqlx::query(&query)
+                .bind(&self.address)
+                .execute(&mut *db_client.db)
+                .await
+                .map_err(Error::from);
 
-        Ok(())
+            if let Some(result) = db_client.retry.check(result, &mut attempt, false).await {
+                return result.map(|_num_rows| ());
+            }
+        }
     }
 }
 
 /// Abstraction over sqlx DB client for Vaulty DB
 pub struct Client<'a> {
     pub db: &'a mut sqlx::PgPool,
+    clock: Arc<dyn Clock>,
+
+    /// Key that storage tokens are encrypted with, if any
+    token_key: Option<crypto::Key>,
+
+    /// Retries of calls on the mail path
+    retry: RetryPolicy,
 }
 
 impl<'a> Client<'a> {
     pub fn new(db: &'a mut sqlx::PgPool) -> Self {
-        Client { db }
+        Client {
+            db,
+            clock: Arc::new(SystemClock),
+            token_key: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Use the given clock for all timestamps written to the DB
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Encrypt storage tokens with the given key as they are written, and
+    /// decrypt them as they are read (see `crypto::seal`)
+    ///
+    /// Tokens stored in plaintext are still read as they are, until they
+    /// are encrypted with `encrypt_storage_tokens`.
+    pub fn with_token_key(self, token_key: Option<crypto::Key>) -> Self {
+        Self { token_key, ..self }
+    }
+
+    /// Retry calls on the mail path that fail for transient reasons with the
+    /// given policy (see `RetryPolicy`)
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
+    fn seal_token(&self, token: &str) -> String {
+        match &self.token_key {
+            Some(key) => crypto::seal(key, token),
+            None => token.to_string(),
+        }
+    }
+
+    fn open_token(&self, stored: &str) -> Result<String, Error> {
+        crypto::open(self.token_key.as_ref(), stored)
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
     }
 
     /// Convert a list of recipient emails into address info.
     ///
     /// This function will only return info for the **first** valid recipient
     /// email in the provided list.
-    pub async fn get_address(&mut self, recipients: &Vec<&str>) -> Result<Option<Address>, Error> {
-        // Build a SQL list of values to check against
-        // NOTE: This may need to be sanitizied
-        let address_list = recipients
+    ///
+    /// Address settings are resolved against the given deployment defaults
+    /// and the defaults for the address domain, if any.
+    pub async fn get_address(
+        &mut self,
+        recipients: &[&str],
+        defaults: &Settings,
+    ) -> Result<Option<Address>, Error> {
+        let mut attempt = 1;
+        loop {
+            let result = self.try_get_address(recipients, defaults).await;
+            if let Some(result) = self.retry.check(result, &mut attempt, true).await {
+                return result;
+            }
+        }
+    }
+
+    async fn try_get_address(
+        &mut self,
+        recipients: &[&str],
+        defaults: &Settings,
+    ) -> Result<Option<Address>, Error> {
+        faults::inject(faults::Target::Db).await?;
+
+        if recipients.is_empty() {
+            return Ok(None);
+        }
+
+        // Recipients are bound as parameters, never formatted into the query,
+        // and the first one that is a valid address is picked. Domain
+        // defaults are joined in based on the address domain.
+        let params: Vec<String> = (1..=recipients.len()).map(|i| format!("${}", i)).collect();
+        let order: Vec<String> = params
             .iter()
-            .map(|r| format!("'{}'", r))
-            .collect::<Vec<String>>()
-            .join(", ");
-
-        let query = format!(
-            "SELECT * FROM {} WHERE address IN ({})",
-            ADDRESS_TABLE, &address_list
-        );
-
-        let row = sqlx::query(&query).fetch_optional(self.db).await?;
-
-        if let Some(data) = row {
-            let address = Address {
-                address: data.get("address"),
-                user_id: data.get("user_id"),
-                email_quota: data.get("email_quota"),
-                num_received: data.get("num_received"),
-                max_email_size: data.get("max_email_size"),
-                storage_quota: data.get("storage_quota"),
-                storage_used: data.get("storage_used"),
-                storage_token: data.get("storage_token"),
-                storage_backend: data.get::<String, &str>("storage_backend").into(),
-                storage_path: data.get("storage_path"),
-                last_renewal_time: data.get("last_renewal_time"),
-            };
+            .enumerate()
+            .map(|(i, p)| format!("WHEN {} THEN {}", p, i))
+            .collect();
+        let query = address_query(&format!(
+            "WHERE a.address IN ({})
+            ORDER BY CASE a.address {} END
+            LIMIT 1",
+            params.join(", "),
+            order.join(" ")
+        ));
 
-            Ok(Some(address))
-        } else {
-            // If no rows returned, none of the recipients are valid
-            Ok(None)
+        let mut query = sqlx::query(&query);
+        for recipient in recipients {
+            query = query.bind(*recipient);
+        }
+
+        let row = query.fetch_optional(self.db).await?;
+
+        // If no rows returned, none of the recipients are valid
+        row.map(|data| self.address_from_row(&data, defaults))
+            .transpose()
+    }
+
+    /// Get an address by its ID, with its settings resolved like
+    /// `get_address`
+    pub async fn get_address_by_id(
+        &mut self,
+        id: i32,
+        defaults: &Settings,
+    ) -> Result<Option<Address>, Error> {
+        let query = address_query("WHERE a.id = $1");
+        let row = sqlx::query(&query).bind(id).fetch_optional(self.db).await?;
+
+        row.map(|data| self.address_from_row(&data, defaults))
+            .transpose()
+    }
+
+    fn address_from_row(
+        &self,
+        data: &sqlx::postgres::PgRow,
+        defaults: &Settings,
+    ) -> Result<Address, Error> {
+        let domain_settings = SettingsLayer {
+            email_quota: data.get("domain_email_quota"),
+            storage_quota: data.get("domain_storage_quota"),
+            max_email_size: data.get("domain_max_email_size"),
+            storage_backend: data
+                .get::<Option<String>, &str>("domain_storage_backend")
+                .map(storage::Backend::from),
+            reply_on_success: data.get("domain_reply_on_success"),
+            reply_on_rejection: data.get("domain_reply_on_rejection"),
+            skip_unchanged: data.get("domain_skip_unchanged"),
+            dedup_attachments: data.get("domain_dedup_attachments"),
+            transliterate_filenames: data.get("domain_transliterate_filenames"),
+            store_body: data.get("domain_store_body"),
+            archive_eml: data.get("domain_archive_eml"),
+            archive_links: data.get("domain_archive_links"),
+            body_pdf: data.get("domain_body_pdf"),
+            sign_manifests: data.get("domain_sign_manifests"),
+            auto_generated_policy: data
+                .get::<Option<String>, &str>("domain_auto_generated_policy")
+                .map(AutoGeneratedPolicy::from),
+            delivery_mode: data
+                .get::<Option<String>, &str>("domain_delivery_mode")
+                .map(DeliveryMode::from),
+        };
+
+        let address_settings = SettingsLayer {
+            email_quota: data.get("email_quota"),
+            storage_quota: data.get("storage_quota"),
+            max_email_size: data.get("max_email_size"),
+            storage_backend: data
+                .get::<Option<String>, &str>("storage_backend")
+                .map(storage::Backend::from),
+            reply_on_success: data.get("reply_on_success"),
+            reply_on_rejection: data.get("reply_on_rejection"),
+            skip_unchanged: data.get("skip_unchanged"),
+            dedup_attachments: data.get("dedup_attachments"),
+            transliterate_filenames: data.get("transliterate_filenames"),
+            store_body: data.get("store_body"),
+            archive_eml: data.get("archive_eml"),
+            archive_links: data.get("archive_links"),
+            body_pdf: data.get("body_pdf"),
+            sign_manifests: data.get("sign_manifests"),
+            auto_generated_policy: data
+                .get::<Option<String>, &str>("auto_generated_policy")
+                .map(AutoGeneratedPolicy::from),
+            delivery_mode: data
+                .get::<Option<String>, &str>("delivery_mode")
+                .map(DeliveryMode::from),
+        };
+
+        let settings = Settings::resolve(defaults, &[&domain_settings, &address_settings]);
+
+        let storage_token = self.open_token(&data.get::<String, &str>("storage_token"))?;
+        let storage_refresh_token = data
+            .get::<Option<String>, &str>("storage_refresh_token")
+            .map(|token| self.open_token(&token))
+            .transpose()?;
+
+        let address = Address {
+            address: data.get("address"),
+            user_id: data.get("user_id"),
+            is_active: data.get("is_active"),
+            num_received: data.get("num_received"),
+            storage_used: data.get("storage_used"),
+            storage_token,
+            storage_path: data.get("storage_path"),
+            last_renewal_time: data.get("last_renewal_time"),
+            storage_refresh_token,
+            storage_token_expiry: data.get("storage_token_expiry"),
+            dropbox_namespace_id: data.get("dropbox_namespace_id"),
+            dropbox_team_member_id: data.get("dropbox_team_member_id"),
+            reply_success_template: data.get("reply_success_template"),
+            reply_rejection_template: data.get("reply_rejection_template"),
+            link_archive_pattern: data.get("link_archive_pattern"),
+            pipeline: split_list(data.get("pipeline")),
+            filename_template: data.get("filename_template"),
+            organization: data
+                .get::<Option<String>, &str>("organization")
+                .map(Organization::from)
+                .unwrap_or_default(),
+            bundle_attachments: data
+                .get::<Option<bool>, &str>("bundle_attachments")
+                .unwrap_or(false),
+            bundle_template: data.get("bundle_template"),
+            encrypt_files: data
+                .get::<Option<bool>, &str>("encrypt_files")
+                .unwrap_or(false),
+            encryption_key: data.get("encryption_key"),
+            indexed_storage_path: data.get("indexed_storage_path"),
+            settings,
+            domain_settings,
+            address_settings,
+        };
+
+        Ok(address)
+    }
+
+    /// Persist a refreshed storage access token for an address
+    pub async fn update_storage_token(
+        &mut self,
+        address: &str,
+        token: &str,
+        expiry: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let query = format!(
+            "
+            UPDATE {}
+            SET storage_token = $1, storage_token_expiry = $2
+            WHERE address = $3",
+            ADDRESS_TABLE
+        );
+
+        let _num_rows = sqlx::query(&query)
+            .bind(self.seal_token(token))
+            .bind(expiry)
+            .bind(address)
+            .execute(self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Encrypt the storage tokens of addresses and storage routes that are
+    /// still stored in plaintext (e.g., once a token key is first set)
+    ///
+    /// A token is left alone if it changed in the meantime. Returns the
+    /// number of tokens that were encrypted.
+    pub async fn encrypt_storage_tokens(&mut self) -> Result<u64, Error> {
+        let key = self.token_key.clone().ok_or_else(|| {
+            Error::Generic("No key is configured to encrypt storage tokens".to_string())
+        })?;
+
+        let columns = [
+            (ADDRESS_TABLE, "storage_token"),
+            (ADDRESS_TABLE, "storage_refresh_token"),
+            (STORAGE_ROUTE_TABLE, "storage_token"),
+        ];
+        let mut num_encrypted = 0;
+
+        for (table, column) in columns.iter() {
+            let query = format!(
+                "SELECT id, {1} AS token FROM {0} WHERE {1} IS NOT NULL AND {1} != ''",
+                table, column
+            );
+            let rows = sqlx::query(&query).fetch_all(self.db).await?;
+
+            let update = format!(
+                "UPDATE {0} SET {1} = $1 WHERE id = $2 AND {1} = $3",
+                table, column
+            );
+
+            for row in rows {
+                let id: i32 = row.get("id");
+                let token: String = row.get("token");
+
+                if crypto::is_sealed(&token) {
+                    continue;
+                }
+
+                num_encrypted += sqlx::query(&update)
+                    .bind(crypto::seal(&key, &token))
+                    .bind(id)
+                    .bind(&token)
+                    .execute(self.db)
+                    .await?;
+            }
         }
+
+        Ok(num_encrypted)
     }
 
     /// Log a message to the logs table
@@ -196,7 +795,7 @@ impl<'a> Client<'a> {
             LOG_TABLE
         );
 
-        let creation_time: DateTime<Utc> = Utc::now();
+        let creation_time: DateTime<Utc> = self.clock.now();
 
         let num_rows = sqlx::query(&query)
             .bind(mail_id)
@@ -214,13 +813,15 @@ impl<'a> Client<'a> {
     /// Insert an email into DB
     /// Status and error message must be updated later
     pub async fn insert_email(&mut self, email: &Email) -> Result<(), Error> {
+        faults::inject(faults::Target::Db).await?;
+
         let mail_id = &email.uuid;
 
         // Recipient list will have been filtered down at this point
         let recipient = &email.recipients[0];
 
         let total_size = email.size;
-        let creation_time: DateTime<Utc> = Utc::now();
+        let creation_time: DateTime<Utc> = self.clock.now();
         let last_update_time = creation_time.clone();
 
         let query = format!("
@@ -243,68 +844,2193 @@ impl<'a> Client<'a> {
             .execute(self.db)
             .await?;
 
+        self.insert_change(changes::Kind::EmailCreated, mail_id, None, None)
+            .await;
+
         Ok(())
     }
 
-    /// Update email status (success or failure)
-    /// We do not really care if this operation fails (best-effort)
-    pub async fn update_email(&mut self, email: &Email, status: bool, msg: Option<&str>) {
+    /// Accept an email for an address
+    ///
+    /// The email is inserted, checked against the address quotas, and
+    /// counted against the address in a single transaction. The address row
+    /// is locked for the duration so that concurrent emails for the same
+    /// address see each other's usage.
+    ///
+    /// If a quota would be exceeded, the email is stored as failed and
+    /// `Error::QuotaExceeded` is returned.
+    ///
+    /// Returns false if an email with the same ID was already accepted, in
+    /// which case nothing is counted. An email that previously failed is
+    /// accepted again.
+    ///
+    /// The transaction is run again if Postgres rolls it back (e.g., on a
+    /// serialization failure).
+    pub async fn accept_email(&mut self, email: &Email, address: &Address) -> Result<bool, Error> {
+        let mut attempt = 1;
+        loop {
+            let result = self.try_accept_email(email, address).await;
+            if let Some(result) = self.retry.check(result, &mut attempt, false).await {
+                return result;
+            }
+        }
+    }
+
+    async fn try_accept_email(&mut self, email: &Email, address: &Address) -> Result<bool, Error> {
+        faults::inject(faults::Target::Db).await?;
+
         let mail_id = &email.uuid;
+        let settings = &address.settings;
+        let creation_time: DateTime<Utc> = email.received_time.unwrap_or_else(|| self.clock.now());
+        let last_update_time = creation_time;
+
+        let mut tx = self.db.begin().await?;
 
         let query = format!(
             "
-            UPDATE {}
-            SET status = $1, error_msg = $2
-            WHERE mail_id = $3",
+            SELECT id, user_id, num_received, storage_used FROM {}
+            WHERE address = $1
+            FOR UPDATE",
+            ADDRESS_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(&address.address)
+            .fetch_one(&mut tx)
+            .await?;
+
+        let address_id: i32 = row.get("id");
+        let user_id: i32 = row.get("user_id");
+        let num_received: i32 = row.get("num_received");
+        let storage_used: i64 = row.get("storage_used");
+
+        // Verify that address quota is not exceeded with this email
+        // Quota is checked again on every attachment
+        let size_error = check_size(&address.address, settings, storage_used, email.size);
+        let error_msg = if size_error.is_some() {
+            size_error
+        } else if (num_received + 1) > settings.email_quota {
+            Some(format!(
+                "Address {} has hit its quota of {} emails for this period.",
+                address.address, settings.email_quota,
+            ))
+        } else {
+            None
+        };
+
+        let query = format!("
+            INSERT INTO {0} (user_id, address_id, id, num_attachments, total_size, message_id, importance, is_priority, priority_folder, is_test, is_bundle, directive_folder, directive_notify, submitted_by, status, error_msg, last_update_time, creation_time) VALUES
+            ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)
+            ON CONFLICT (id) DO UPDATE SET
+                status = EXCLUDED.status,
+                error_msg = EXCLUDED.error_msg,
+                last_update_time = EXCLUDED.last_update_time
+            WHERE {0}.status = false",
             MAIL_TABLE
         );
 
+        let priority = email.priority.as_ref();
+        let directives = email.directives.as_ref();
+
         let num_rows = sqlx::query(&query)
-            .bind(status)
-            .bind(msg)
+            .bind(user_id)
+            .bind(address_id)
             .bind(mail_id)
-            .execute(self.db)
-            .await;
+            .bind(email.num_attachments as i32)
+            .bind(email.size as i32)
+            .bind(email.message_id.as_ref())
+            .bind(email.importance.map(Importance::as_str))
+            .bind(priority.map_or(false, |p| p.notify))
+            .bind(priority.and_then(|p| p.folder.as_ref()))
+            .bind(email.is_test)
+            .bind(email.is_bundle)
+            .bind(directives.and_then(|d| d.folder.as_ref()))
+            .bind(directives.and_then(|d| d.notify))
+            .bind(email.submitted_by.as_ref())
+            .bind(error_msg.is_none())
+            .bind(error_msg.as_deref().unwrap_or(""))
+            .bind(last_update_time)
+            .bind(creation_time)
+            .execute(&mut tx)
+            .await?;
 
-        if let Err(e) = num_rows {
-            log::error!("Failed to update email: {}", e.to_string());
+        if num_rows == 0 {
+            tx.rollback().await?;
+            return Ok(false);
         }
-    }
-    /// Insert an attachment into DB
-    /// Status and error message must be updated later
-    pub async fn insert_attachment(
-        &mut self,
-        email: &Email,
-        index: u16,
-        size: usize,
-        status: bool,
-        error_msg: Option<&str>,
-    ) {
-        let mail_id = &email.uuid;
 
-        let creation_time: DateTime<Utc> = Utc::now();
+        if let Some(msg) = error_msg {
+            tx.commit().await?;
+
+            self.insert_change(changes::Kind::EmailCreated, mail_id, None, None)
+                .await;
+            self.insert_change(changes::Kind::EmailFailed, mail_id, None, Some(&msg))
+                .await;
+
+            return Err(Error::QuotaExceeded(msg));
+        }
 
+        // Count the email body against the address storage
         let query = format!(
             "
-            INSERT INTO {0} (mail_id, index, size, status, error_msg, creation_time) VALUES
-             $1, $2, $3, $4, $5, $6, $7)",
-            ATTACHMENT_TABLE
+            UPDATE {}
+            SET storage_used = storage_used + $1, num_received = num_received + 1
+            WHERE id = $2",
+            ADDRESS_TABLE
         );
 
-        let error_msg = error_msg.unwrap_or("");
+        sqlx::query(&query)
+            .bind(email.body.len() as i64)
+            .bind(address_id)
+            .execute(&mut tx)
+            .await?;
 
-        let num_rows = sqlx::query(&query)
-            .bind(mail_id)
-            .bind(index as i32)
-            .bind(size as i32)
-            .bind(status)
-            .bind(error_msg)
-            .bind(creation_time)
-            .execute(self.db)
+        tx.commit().await?;
+
+        self.insert_change(changes::Kind::EmailCreated, mail_id, None, None)
             .await;
 
-        if let Err(e) = num_rows {
-            log::error!("Failed to insert attachment: {}", e.to_string());
+        Ok(true)
+    }
+
+    /// Find an email sent to an address that was already accepted, either
+    /// with the given ID or with the given Message-ID
+    ///
+    /// An email with the same ID is preferred.
+    pub async fn find_accepted_email(
+        &mut self,
+        mail_id: &uuid::Uuid,
+        message_id: Option<&str>,
+        address: &str,
+    ) -> Result<Option<uuid::Uuid>, Error> {
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .try_find_accepted_email(mail_id, message_id, address)
+                .await;
+            if let Some(result) = self.retry.check(result, &mut attempt, true).await {
+                return result;
+            }
         }
     }
+
+    async fn try_find_accepted_email(
+        &mut self,
+        mail_id: &uuid::Uuid,
+        message_id: Option<&str>,
+        address: &str,
+    ) -> Result<Option<uuid::Uuid>, Error> {
+        faults::inject(faults::Target::Db).await?;
+
+        let query = format!(
+            "
+            SELECT m.id FROM {} m
+            JOIN {} a ON a.id = m.address_id
+            WHERE a.address = $1 AND m.status = true
+                AND (m.id = $2 OR ($3::text IS NOT NULL AND m.message_id = $3))
+            ORDER BY m.id = $2 DESC, m.creation_time
+            LIMIT 1",
+            MAIL_TABLE, ADDRESS_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(address)
+            .bind(mail_id)
+            .bind(message_id)
+            .fetch_optional(self.db)
+            .await?;
+
+        Ok(row.map(|r| r.get("id")))
+    }
+
+    /// Look up an email that has already been inserted, along with the
+    /// indices of its successfully processed attachments
+    ///
+    /// Only what is stored in the DB can be recovered: the sender, subject,
+    /// and body are left empty. Emails that have already failed are not
+    /// returned.
+    pub async fn get_email(
+        &mut self,
+        mail_id: &uuid::Uuid,
+    ) -> Result<Option<(Email, Vec<u16>)>, Error> {
+        let mut attempt = 1;
+        loop {
+            let result = self.try_get_email(mail_id).await;
+            if let Some(result) = self.retry.check(result, &mut attempt, true).await {
+                return result;
+            }
+        }
+    }
+
+    async fn try_get_email(
+        &mut self,
+        mail_id: &uuid::Uuid,
+    ) -> Result<Option<(Email, Vec<u16>)>, Error> {
+        faults::inject(faults::Target::Db).await?;
+
+        let query = format!(
+            "
+            SELECT m.num_attachments, m.total_size, m.message_id, m.importance,
+                m.is_priority, m.priority_folder, m.is_test, m.is_bundle,
+                m.directive_folder, m.directive_notify, m.submitted_by, m.creation_time,
+                a.address
+            FROM {} m
+            JOIN {} a ON a.id = m.address_id
+            WHERE m.id = $1 AND m.status = true",
+            MAIL_TABLE, ADDRESS_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(mail_id)
+            .fetch_optional(self.db)
+            .await?;
+
+        let data = match row {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        let is_priority: bool = data.get("is_priority");
+        let priority_folder: Option<String> = data.get("priority_folder");
+        let priority = if is_priority || priority_folder.is_some() {
+            Some(Priority {
+                folder: priority_folder,
+                notify: is_priority,
+            })
+        } else {
+            None
+        };
+
+        let directive_folder: Option<String> = data.get("directive_folder");
+        let directive_notify: Option<bool> = data.get("directive_notify");
+        let directives = if directive_folder.is_some() || directive_notify.is_some() {
+            Some(Directives {
+                folder: directive_folder,
+                notify: directive_notify,
+                // Only used when the email is received, after which it is
+                // stored as a bundle (`is_bundle`)
+                zip: None,
+            })
+        } else {
+            None
+        };
+
+        let email = Email {
+            uuid: *mail_id,
+            recipients: vec![data.get("address")],
+            num_attachments: data.get::<i32, &str>("num_attachments") as u16,
+            size: data.get::<i32, &str>("total_size") as usize,
+            message_id: data.get("message_id"),
+            importance: data
+                .get::<Option<String>, &str>("importance")
+                .map(Importance::from),
+            priority,
+            is_test: data.get("is_test"),
+            is_bundle: data.get("is_bundle"),
+            directives,
+            submitted_by: data.get("submitted_by"),
+            received_time: Some(data.get("creation_time")),
+            ..Default::default()
+        };
+
+        let query = format!(
+            "SELECT index FROM {} WHERE mail_id = $1 AND status = true",
+            ATTACHMENT_TABLE
+        );
+
+        let processed = sqlx::query(&query)
+            .bind(mail_id)
+            .fetch_all(self.db)
+            .await?
+            .iter()
+            .map(|r| r.get::<i32, &str>("index") as u16)
+            .collect();
+
+        Ok(Some((email, processed)))
+    }
+
+    /// Check if an email with the given message ID was accepted and has at
+    /// least one attachment stored
+    pub async fn is_stored(&mut self, message_id: &str) -> Result<bool, Error> {
+        let query = format!(
+            "
+            SELECT COUNT(*) AS num_stored
+            FROM {} m
+            JOIN {} a ON a.mail_id = m.id
+            WHERE m.message_id = $1 AND m.status = true AND a.status = true",
+            MAIL_TABLE, ATTACHMENT_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(message_id)
+            .fetch_one(self.db)
+            .await?;
+
+        Ok(row.get::<i64, &str>("num_stored") > 0)
+    }
+
+    /// Update email status (success or failure)
+    /// We do not really care if this operation fails (best-effort)
+    pub async fn update_email(&mut self, email: &Email, status: bool, msg: Option<&str>) {
+        let mail_id = &email.uuid;
+
+        let query = format!(
+            "
+            UPDATE {}
+            SET status = $1, error_msg = $2
+            WHERE id = $3",
+            MAIL_TABLE
+        );
+
+        let num_rows = sqlx::query(&query)
+            .bind(status)
+            .bind(msg)
+            .bind(mail_id)
+            .execute(self.db)
+            .await;
+
+        if let Err(e) = num_rows {
+            log::error!("Failed to update email: {}", e.to_string());
+        }
+
+        let kind = if status {
+            changes::Kind::EmailStored
+        } else {
+            changes::Kind::EmailFailed
+        };
+        self.insert_change(kind, mail_id, None, msg).await;
+    }
+
+    /// Get the password hash of an active API user
+    pub async fn get_api_user_password(&mut self, username: &str) -> Result<Option<String>, Error> {
+        let query = format!(
+            "SELECT password FROM {} WHERE username = $1 AND is_active = true",
+            API_USER_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(username)
+            .fetch_optional(self.db)
+            .await?;
+
+        Ok(row.map(|r| r.get("password")))
+    }
+
+    /// Look up the hash of the SMTP submission password of an active address
+    ///
+    /// Returns None if the address does not exist, or has no password.
+    pub async fn get_submission_password(
+        &mut self,
+        address: &str,
+    ) -> Result<Option<String>, Error> {
+        let query = format!(
+            "SELECT submission_password FROM {} WHERE address = $1 AND is_active = true",
+            ADDRESS_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(address)
+            .fetch_optional(self.db)
+            .await?;
+
+        Ok(row
+            .map(|r| r.get::<String, &str>("submission_password"))
+            .filter(|hash| !hash.is_empty()))
+    }
+
+    /// Record an email or attachment state transition in the change feed
+    /// We do not really care if this operation fails (best-effort)
+    pub async fn insert_change(
+        &mut self,
+        kind: changes::Kind,
+        mail_id: &uuid::Uuid,
+        index: Option<u16>,
+        error_msg: Option<&str>,
+    ) {
+        let query = format!(
+            "
+            INSERT INTO {0}
+            (mail_id, kind, index, error_msg, creation_time) VALUES
+            ($1, $2, $3, $4, $5)",
+            CHANGE_TABLE
+        );
+
+        let creation_time: DateTime<Utc> = self.clock.now();
+
+        let num_rows = sqlx::query(&query)
+            .bind(mail_id)
+            .bind(kind.name())
+            .bind(index.map(|i| i as i32))
+            .bind(error_msg.filter(|m| !m.is_empty()))
+            .bind(creation_time)
+            .execute(self.db)
+            .await;
+
+        if let Err(e) = num_rows {
+            log::error!("Failed to insert change: {}", e);
+        }
+    }
+
+    /// Get up to `limit` changes after the given cursor, oldest first
+    ///
+    /// Changes newer than `changes::SETTLE_DELAY` are left out.
+    pub async fn get_changes(
+        &mut self,
+        after: changes::Cursor,
+        address: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<changes::Change>, Error> {
+        let address_clause = if address.is_some() {
+            "AND a.address = $4"
+        } else {
+            ""
+        };
+
+        let query = format!(
+            "
+            SELECT c.id, c.kind, c.mail_id, a.address, c.index, c.error_msg, c.creation_time
+            FROM {} c
+            JOIN {} m ON m.id = c.mail_id
+            JOIN {} a ON a.id = m.address_id
+            WHERE c.id > $1 AND c.creation_time < $2 {}
+            ORDER BY c.id
+            LIMIT $3",
+            CHANGE_TABLE, MAIL_TABLE, ADDRESS_TABLE, address_clause
+        );
+
+        let settled = self.clock.now() - Duration::seconds(changes::SETTLE_DELAY);
+
+        let mut q = sqlx::query(&query)
+            .bind(after.0)
+            .bind(settled)
+            .bind(limit as i64);
+
+        if let Some(address) = address {
+            q = q.bind(address);
+        }
+
+        let rows = q.fetch_all(self.db).await?;
+
+        let changes = rows
+            .iter()
+            .filter_map(|r| {
+                let kind: String = r.get("kind");
+
+                Some(changes::Change {
+                    kind: changes::Kind::from_name(&kind)?,
+                    mail_id: r.get("mail_id"),
+                    address: r.get("address"),
+                    index: r.get("index"),
+                    error_msg: r.get("error_msg"),
+                    time: r.get("creation_time"),
+                    cursor: changes::Cursor(r.get("id")),
+                })
+            })
+            .collect();
+
+        Ok(changes)
+    }
+    /// Insert an attachment into DB
+    ///
+    /// Each attachment of an email has a single row: a retried attachment
+    /// replaces the row of its previous attempt.
+    ///
+    /// `storage_path` is where the attachment is (or was to be) stored, and
+    /// is not set for dropped attachments. `content_hash` is only known if
+    /// the attachment was fully read (i.e., it was not dropped and its
+    /// upload did not fail). Attachments skipped because their content was
+    /// already stored are recorded with `is_duplicate` set.
+    pub async fn insert_attachment(
+        &mut self,
+        email: &Email,
+        index: u16,
+        name: &str,
+        size: usize,
+        mime_type: &str,
+        storage_path: Option<&str>,
+        content_hash: Option<&str>,
+        is_duplicate: bool,
+        status: bool,
+        error_msg: Option<&str>,
+    ) {
+        let mail_id = &email.uuid;
+
+        let creation_time: DateTime<Utc> = self.clock.now();
+
+        let query = format!(
+            "
+            INSERT INTO {0}
+            (mail_id, index, name, size, mime_type, storage_path, content_hash, is_duplicate, status, error_msg, creation_time) VALUES
+            ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (mail_id, index) DO UPDATE SET
+                name = EXCLUDED.name,
+                size = EXCLUDED.size,
+                mime_type = EXCLUDED.mime_type,
+                storage_path = EXCLUDED.storage_path,
+                content_hash = EXCLUDED.content_hash,
+                is_duplicate = EXCLUDED.is_duplicate,
+                status = EXCLUDED.status,
+                error_msg = EXCLUDED.error_msg,
+                creation_time = EXCLUDED.creation_time",
+            ATTACHMENT_TABLE
+        );
+
+        let error_msg = error_msg.unwrap_or("");
+
+        let num_rows = sqlx::query(&query)
+            .bind(mail_id)
+            .bind(index as i32)
+            .bind(name)
+            .bind(size as i32)
+            .bind(mime_type)
+            .bind(storage_path)
+            .bind(content_hash)
+            .bind(is_duplicate)
+            .bind(status)
+            .bind(error_msg)
+            .bind(creation_time)
+            .execute(self.db)
+            .await;
+
+        if let Err(e) = num_rows {
+            log::error!("Failed to insert attachment: {}", e.to_string());
+            return;
+        }
+
+        let kind = if status {
+            changes::Kind::AttachmentStored
+        } else {
+            changes::Kind::AttachmentFailed
+        };
+        self.insert_change(kind, mail_id, Some(index), Some(error_msg))
+            .await;
+    }
+
+    /// Update the status of an attachment that is already in DB (e.g., if a
+    /// stored attachment could not be accounted for)
+    ///
+    /// Like `insert_attachment`, this is best-effort.
+    pub async fn update_attachment_status(
+        &mut self,
+        mail_id: &uuid::Uuid,
+        index: u16,
+        status: bool,
+        error_msg: Option<&str>,
+    ) {
+        let query = format!(
+            "UPDATE {} SET status = $1, error_msg = $2 WHERE mail_id = $3 AND index = $4",
+            ATTACHMENT_TABLE
+        );
+
+        let error_msg = error_msg.unwrap_or("");
+
+        let num_rows = sqlx::query(&query)
+            .bind(status)
+            .bind(error_msg)
+            .bind(mail_id)
+            .bind(index as i32)
+            .execute(self.db)
+            .await;
+
+        match num_rows {
+            Ok(0) => {
+                log::warn!("Attachment {} of {} not found", index, mail_id);
+                return;
+            }
+            Ok(_) => (),
+            Err(e) => {
+                log::error!("Failed to update attachment: {}", e);
+                return;
+            }
+        }
+
+        let kind = if status {
+            changes::Kind::AttachmentStored
+        } else {
+            changes::Kind::AttachmentFailed
+        };
+        self.insert_change(kind, mail_id, Some(index), Some(error_msg))
+            .await;
+    }
+
+    /// Update the storage path of an attachment that is already in DB (e.g.,
+    /// if the storage backend renamed it when its upload was committed)
+    ///
+    /// Like `insert_attachment`, this is best-effort.
+    pub async fn update_attachment_path(
+        &mut self,
+        mail_id: &uuid::Uuid,
+        index: u16,
+        storage_path: &str,
+    ) {
+        let query = format!(
+            "UPDATE {} SET storage_path = $1 WHERE mail_id = $2 AND index = $3",
+            ATTACHMENT_TABLE
+        );
+
+        let result = sqlx::query(&query)
+            .bind(storage_path)
+            .bind(mail_id)
+            .bind(index as i32)
+            .execute(self.db)
+            .await;
+
+        if let Err(e) = result {
+            log::error!("Failed to update attachment: {}", e);
+        }
+    }
+
+    /// Status of an attachment of an email, or `None` if it has not been
+    /// recorded yet
+    pub async fn get_attachment_status(
+        &mut self,
+        mail_id: &uuid::Uuid,
+        index: u16,
+    ) -> Result<Option<bool>, Error> {
+        let mut attempt = 1;
+        loop {
+            let result = self.try_get_attachment_status(mail_id, index).await;
+            if let Some(result) = self.retry.check(result, &mut attempt, true).await {
+                return result;
+            }
+        }
+    }
+
+    async fn try_get_attachment_status(
+        &mut self,
+        mail_id: &uuid::Uuid,
+        index: u16,
+    ) -> Result<Option<bool>, Error> {
+        let query = format!(
+            "SELECT status FROM {} WHERE mail_id = $1 AND index = $2",
+            ATTACHMENT_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(mail_id)
+            .bind(index as i32)
+            .fetch_optional(self.db)
+            .await?;
+
+        Ok(row.map(|r| r.get::<bool, &str>("status")))
+    }
+
+    /// Check if an attachment with the given name and content hash was
+    /// already stored for an email
+    pub async fn is_attachment_stored(
+        &mut self,
+        mail_id: &uuid::Uuid,
+        name: &str,
+        content_hash: &str,
+    ) -> Result<bool, Error> {
+        let query = format!(
+            "
+            SELECT COUNT(*) AS num_stored FROM {}
+            WHERE mail_id = $1 AND name = $2 AND content_hash = $3 AND status = true",
+            ATTACHMENT_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(mail_id)
+            .bind(name)
+            .bind(content_hash)
+            .fetch_one(self.db)
+            .await?;
+
+        Ok(row.get::<i64, &str>("num_stored") > 0)
+    }
+
+    /// Content hash of the last attachment with the given name that was
+    /// successfully stored for an address, if any
+    pub async fn get_last_attachment_hash(
+        &mut self,
+        address: &str,
+        name: &str,
+    ) -> Result<Option<String>, Error> {
+        let query = format!(
+            "
+            SELECT at.content_hash FROM {} at
+            JOIN {} m ON m.id = at.mail_id
+            JOIN {} a ON a.id = m.address_id
+            WHERE a.address = $1 AND at.name = $2 AND at.status = true
+                AND at.content_hash IS NOT NULL
+            ORDER BY at.creation_time DESC
+            LIMIT 1",
+            ATTACHMENT_TABLE, MAIL_TABLE, ADDRESS_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(address)
+            .bind(name)
+            .fetch_optional(self.db)
+            .await?;
+
+        Ok(row.map(|r| r.get("content_hash")))
+    }
+
+    /// Replace the index of the files already in the storage folder of an
+    /// address with the given files, and mark the folder as indexed
+    ///
+    /// The paths of indexed files are never overwritten (see
+    /// `get_indexed_paths`). They are not checked for duplicates, as the
+    /// owner may have removed them since.
+    pub async fn index_files(
+        &mut self,
+        address: &Address,
+        files: &[storage::dropbox::client::ListedFile],
+    ) -> Result<(), Error> {
+        let mut tx = self.db.begin().await?;
+
+        let query = format!(
+            "
+            UPDATE {}
+            SET indexed_storage_path = $2
+            WHERE address = $1 AND is_active = true
+            RETURNING id",
+            ADDRESS_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(&address.address)
+            .bind(&address.storage_path)
+            .fetch_optional(&mut tx)
+            .await?;
+
+        let address_id: i32 = match row {
+            Some(row) => row.get("id"),
+            None => {
+                tx.rollback().await?;
+                return Ok(());
+            }
+        };
+
+        let query = format!("DELETE FROM {} WHERE address_id = $1", INDEXED_FILE_TABLE);
+        sqlx::query(&query)
+            .bind(address_id)
+            .execute(&mut tx)
+            .await?;
+
+        // Files are inserted in chunks of rows, as Postgres caps the number
+        // of parameters of a query
+        let now = self.clock.now();
+
+        for chunk in files.chunks(INDEX_INSERT_CHUNK_SIZE) {
+            let rows: Vec<String> = (0..chunk.len())
+                .map(|i| {
+                    let p = 3 + i * 4;
+                    format!("($1, ${}, ${}, ${}, ${}, $2)", p, p + 1, p + 2, p + 3)
+                })
+                .collect();
+
+            let query = format!(
+                "
+                INSERT INTO {} (address_id, path, name, size, content_hash, creation_time)
+                VALUES {}",
+                INDEXED_FILE_TABLE,
+                rows.join(", ")
+            );
+
+            let mut query = sqlx::query(&query).bind(address_id).bind(now);
+            for file in chunk {
+                query = query
+                    .bind(file.path.as_str())
+                    .bind(file.name.as_str())
+                    .bind(file.size as i64)
+                    .bind(file.content_hash.as_str());
+            }
+
+            query.execute(&mut tx).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Paths of the files indexed in the storage folder of an address
+    pub async fn get_indexed_paths(&mut self, address: &str) -> Result<Vec<String>, Error> {
+        let query = format!(
+            "
+            SELECT f.path FROM {} f
+            JOIN {} a ON a.id = f.address_id
+            WHERE a.address = $1 AND a.is_active = true",
+            INDEXED_FILE_TABLE, ADDRESS_TABLE
+        );
+
+        let rows = sqlx::query(&query).bind(address).fetch_all(self.db).await?;
+
+        Ok(rows.iter().map(|r| r.get("path")).collect())
+    }
+
+    /// Storage path of an attachment with the given content hash that was
+    /// stored for an address, if any
+    ///
+    /// Attachments that were themselves skipped as duplicates are ignored,
+    /// so the path returned is always that of a stored file. It is the path
+    /// the file was actually stored at, which differs from its name if the
+    /// storage backend renamed it.
+    pub async fn find_attachment_by_hash(
+        &mut self,
+        address: &str,
+        content_hash: &str,
+    ) -> Result<Option<String>, Error> {
+        let query = format!(
+            "
+            SELECT at.storage_path FROM {} at
+            JOIN {} m ON m.id = at.mail_id
+            JOIN {} a ON a.id = m.address_id
+            WHERE a.address = $1 AND at.content_hash = $2 AND at.status = true
+                AND at.is_duplicate = false AND at.storage_path IS NOT NULL
+            ORDER BY at.creation_time DESC
+            LIMIT 1",
+            ATTACHMENT_TABLE, MAIL_TABLE, ADDRESS_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(address)
+            .bind(content_hash)
+            .fetch_optional(self.db)
+            .await?;
+
+        Ok(row.map(|r| r.get("storage_path")))
+    }
+
+    /// Store a sample of a rejected email for abuse review
+    ///
+    /// Only a hash of the body and its first `sample_size` bytes are stored.
+    /// Like logging, this is best-effort.
+    pub async fn insert_sample(&mut self, email: &Email, reason: &str, sample_size: usize) {
+        let content_hash = hex::encode(Sha256::digest(email.body.as_bytes()).as_slice());
+
+        // Truncate on a char boundary
+        let mut end = sample_size.min(email.body.len());
+        while !email.body.is_char_boundary(end) {
+            end -= 1;
+        }
+        let sample = &email.body[..end];
+
+        let creation_time: DateTime<Utc> = self.clock.now();
+
+        let query = format!(
+            "
+            INSERT INTO {0}
+            (content_hash, sample, sender, recipient, subject, reason, creation_time) VALUES
+            ($1, $2, $3, $4, $5, $6, $7)",
+            SAMPLE_TABLE
+        );
+
+        let num_rows = sqlx::query(&query)
+            .bind(content_hash)
+            .bind(sample)
+            .bind(&email.sender)
+            .bind(email.recipients.join(", "))
+            .bind(email.subject.as_ref())
+            .bind(reason)
+            .bind(creation_time)
+            .execute(self.db)
+            .await;
+
+        if let Err(e) = num_rows {
+            log::error!("Failed to insert sample: {}", e);
+        }
+    }
+
+    /// Get the attachment filtering rules for an address
+    pub async fn get_attachment_rules(&mut self, address: &str) -> Result<Vec<Rule>, Error> {
+        faults::inject(faults::Target::Db).await?;
+
+        let query = format!(
+            "
+            SELECT r.action, r.extension, r.mime_type, r.larger_than
+            FROM {} r
+            JOIN {} a ON a.id = r.address_id
+            WHERE a.address = $1",
+            RULE_TABLE, ADDRESS_TABLE
+        );
+
+        let rows = sqlx::query(&query).bind(address).fetch_all(self.db).await?;
+
+        let rules = rows
+            .iter()
+            .map(|r| Rule {
+                action: r.get::<String, &str>("action").into(),
+                extension: r.get("extension"),
+                mime_type: r.get("mime_type"),
+                larger_than: r.get("larger_than"),
+            })
+            .collect();
+
+        Ok(rules)
+    }
+
+    /// Get the priority rules for an address, in the order they are checked
+    pub async fn get_priority_rules(&mut self, address: &str) -> Result<Vec<PriorityRule>, Error> {
+        faults::inject(faults::Target::Db).await?;
+
+        let query = format!(
+            "
+            SELECT r.importance, r.subject_tag, r.folder, r.notify
+            FROM {} r
+            JOIN {} a ON a.id = r.address_id
+            WHERE a.address = $1
+            ORDER BY r.id",
+            PRIORITY_RULE_TABLE, ADDRESS_TABLE
+        );
+
+        let rows = sqlx::query(&query).bind(address).fetch_all(self.db).await?;
+
+        let rules = rows
+            .iter()
+            .map(|r| PriorityRule {
+                importance: r
+                    .get::<Option<String>, &str>("importance")
+                    .map(Importance::from),
+                subject_tag: r.get("subject_tag"),
+                priority: Priority {
+                    folder: r.get("folder"),
+                    notify: r.get("notify"),
+                },
+            })
+            .collect();
+
+        Ok(rules)
+    }
+
+    /// Get the storage routes for an address, in the order they are checked
+    pub async fn get_storage_routes(&mut self, address: &str) -> Result<Vec<Route>, Error> {
+        faults::inject(faults::Target::Db).await?;
+
+        let query = format!(
+            "
+            SELECT r.extension, r.mime_type, r.larger_than, r.storage_backend,
+                r.storage_token, r.storage_path
+            FROM {} r
+            JOIN {} a ON a.id = r.address_id
+            WHERE a.address = $1
+            ORDER BY r.id",
+            STORAGE_ROUTE_TABLE, ADDRESS_TABLE
+        );
+
+        let rows = sqlx::query(&query).bind(address).fetch_all(self.db).await?;
+
+        rows.iter()
+            .map(|r| {
+                Ok(Route {
+                    extension: r.get("extension"),
+                    mime_type: r.get("mime_type"),
+                    larger_than: r.get("larger_than"),
+                    storage_backend: r.get::<String, &str>("storage_backend").into(),
+                    storage_token: self.open_token(&r.get::<String, &str>("storage_token"))?,
+                    storage_path: r.get("storage_path"),
+                })
+            })
+            .collect()
+    }
+
+    /// Get the names of the attachments stored for an email
+    pub async fn get_stored_attachments(
+        &mut self,
+        mail_id: &uuid::Uuid,
+    ) -> Result<Vec<String>, Error> {
+        let query = format!(
+            "SELECT name FROM {} WHERE mail_id = $1 AND status = true ORDER BY index",
+            ATTACHMENT_TABLE
+        );
+
+        let rows = sqlx::query(&query).bind(mail_id).fetch_all(self.db).await?;
+
+        Ok(rows.iter().filter_map(|r| r.get("name")).collect())
+    }
+
+    /// Files stored for an email, for its manifest
+    ///
+    /// Dropped and failed attachments are not included. Duplicates point to
+    /// the stored copy.
+    pub async fn get_manifest_files(
+        &mut self,
+        mail_id: &uuid::Uuid,
+    ) -> Result<Vec<manifest::File>, Error> {
+        let query = format!(
+            "SELECT storage_path, mime_type, size, content_hash FROM {}
+            WHERE mail_id = $1 AND status = true
+                AND storage_path IS NOT NULL AND content_hash IS NOT NULL
+            ORDER BY index",
+            ATTACHMENT_TABLE
+        );
+
+        let rows = sqlx::query(&query).bind(mail_id).fetch_all(self.db).await?;
+
+        Ok(rows
+            .iter()
+            .map(|r| manifest::File {
+                path: r.get("storage_path"),
+                mime_type: r.get("mime_type"),
+                size: r.get::<i32, &str>("size") as u64,
+                sha256: r.get("content_hash"),
+            })
+            .collect())
+    }
+
+    /// Compute the distribution of email sizes, attachment counts, and
+    /// attachment sizes for the emails received since the given time
+    pub async fn get_stats(&mut self, since: DateTime<Utc>) -> Result<stats::Stats, Error> {
+        let email_size = self
+            .get_distributions(
+                MAIL_TABLE,
+                "total_size",
+                None,
+                since,
+                stats::SIZE_BUCKETS,
+                1,
+            )
+            .await?;
+        let attachment_count = self
+            .get_distributions(
+                MAIL_TABLE,
+                "num_attachments",
+                None,
+                since,
+                stats::ATTACHMENT_COUNT_BUCKETS,
+                1,
+            )
+            .await?;
+        let attachment_size = self
+            .get_distributions(
+                ATTACHMENT_TABLE,
+                "size",
+                None,
+                since,
+                stats::SIZE_BUCKETS,
+                1,
+            )
+            .await?;
+
+        // Parameters (e.g., "; charset=utf-8") are left out
+        let content_types = self
+            .get_distributions(
+                ATTACHMENT_TABLE,
+                "size",
+                Some("lower(split_part(COALESCE(mime_type, 'unknown'), ';', 1))"),
+                since,
+                stats::SIZE_BUCKETS,
+                stats::MAX_CONTENT_TYPES,
+            )
+            .await?;
+
+        let first = |d: Vec<(String, stats::Distribution)>, bounds: &[i64]| {
+            d.into_iter()
+                .next()
+                .map_or_else(|| stats::Distribution::empty(bounds), |(_, d)| d)
+        };
+
+        Ok(stats::Stats {
+            since,
+            email_size: first(email_size, stats::SIZE_BUCKETS),
+            attachment_count: first(attachment_count, stats::ATTACHMENT_COUNT_BUCKETS),
+            attachment_size: first(attachment_size, stats::SIZE_BUCKETS),
+            content_types: content_types
+                .into_iter()
+                .map(|(content_type, size)| stats::ContentType { content_type, size })
+                .collect(),
+            computed_at: self.clock.now(),
+        })
+    }
+
+    /// Emails accepted for an address on each day since the given time
+    ///
+    /// Days with no email are left out.
+    pub async fn get_daily_usage(
+        &mut self,
+        address: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<stats::DailyUsage>, Error> {
+        let query = format!(
+            "
+            SELECT date_trunc('day', m.creation_time AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' AS day,
+                COUNT(*) AS emails, COALESCE(SUM(m.total_size), 0)::bigint AS bytes
+            FROM {} m
+            JOIN {} a ON a.id = m.address_id
+            WHERE a.address = $1 AND m.creation_time >= $2 AND m.status = true
+            GROUP BY day
+            ORDER BY day",
+            MAIL_TABLE, ADDRESS_TABLE
+        );
+
+        let rows = sqlx::query(&query)
+            .bind(address)
+            .bind(since)
+            .fetch_all(self.db)
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|r| stats::DailyUsage {
+                day: r.get("day"),
+                emails: r.get("emails"),
+                bytes: r.get("bytes"),
+            })
+            .collect())
+    }
+
+    /// Distribution of a column over the rows created since the given time,
+    /// optionally grouped by an expression
+    ///
+    /// Returns up to `limit` groups, largest first. The table, column, and
+    /// group are never user input.
+    async fn get_distributions(
+        &mut self,
+        table: &str,
+        column: &str,
+        group: Option<&str>,
+        since: DateTime<Utc>,
+        bounds: &[i64],
+        limit: usize,
+    ) -> Result<Vec<(String, stats::Distribution)>, Error> {
+        let group = group.unwrap_or("''::text");
+        let value = format!("{}::bigint", column);
+
+        let query = format!(
+            "
+            SELECT {0} AS grp, COUNT(*) AS count,
+                percentile_disc(0.5) WITHIN GROUP (ORDER BY {1}) AS p50,
+                percentile_disc(0.9) WITHIN GROUP (ORDER BY {1}) AS p90,
+                percentile_disc(0.99) WITHIN GROUP (ORDER BY {1}) AS p99,
+                MAX({1}) AS max
+            FROM {2}
+            WHERE creation_time >= $1
+            GROUP BY grp
+            ORDER BY count DESC, grp
+            LIMIT $2",
+            group, value, table
+        );
+
+        let rows = sqlx::query(&query)
+            .bind(since)
+            .bind(limit as i64)
+            .fetch_all(self.db)
+            .await?;
+
+        // Bounds are bound one by one, and gathered into the array that
+        // width_bucket expects
+        let params: Vec<String> = (0..bounds.len()).map(|i| format!("${}", i + 2)).collect();
+
+        let query = format!(
+            "
+            SELECT {0} AS grp, width_bucket({1}, ARRAY[{3}]::bigint[]) AS bucket,
+                COUNT(*) AS count
+            FROM {2}
+            WHERE creation_time >= $1
+            GROUP BY grp, bucket",
+            group,
+            value,
+            table,
+            params.join(", ")
+        );
+
+        let mut query = sqlx::query(&query).bind(since);
+        for bound in bounds {
+            query = query.bind(*bound);
+        }
+
+        let bucket_rows = query.fetch_all(self.db).await?;
+
+        let distributions = rows
+            .iter()
+            .map(|r| {
+                let grp: String = r.get("grp");
+
+                let counts: Vec<(i32, i64)> = bucket_rows
+                    .iter()
+                    .filter(|b| b.get::<String, _>("grp") == grp)
+                    .map(|b| (b.get("bucket"), b.get("count")))
+                    .collect();
+
+                let distribution = stats::Distribution {
+                    count: r.get("count"),
+                    p50: r.get("p50"),
+                    p90: r.get("p90"),
+                    p99: r.get("p99"),
+                    max: r.get("max"),
+                    buckets: stats::buckets(bounds, &counts),
+                };
+
+                (grp, distribution)
+            })
+            .collect();
+
+        Ok(distributions)
+    }
+
+    /// Get a batch of emails or attachments created in `[start, end)`, for
+    /// export
+    ///
+    /// Rows are ordered by creation time. Pass the cursor of the last row of
+    /// a batch to get the next one.
+    pub async fn get_export_rows(
+        &mut self,
+        table: export::Table,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        after: Option<&export::Cursor>,
+        limit: usize,
+    ) -> Result<Vec<(export::Row, export::Cursor)>, Error> {
+        let (columns, from, alias) = match table {
+            export::Table::Emails => (
+                "m.id AS mail_id, m.message_id, m.num_attachments, m.total_size, \
+                 NULL::integer AS index, NULL::varchar AS name, NULL::integer AS size, \
+                 NULL::boolean AS is_duplicate",
+                format!(
+                    "{} m JOIN {} a ON a.id = m.address_id",
+                    MAIL_TABLE, ADDRESS_TABLE
+                ),
+                "m",
+            ),
+            export::Table::Attachments => (
+                "t.mail_id, m.message_id, NULL::integer AS num_attachments, \
+                 NULL::integer AS total_size, t.index, t.name, t.size, t.is_duplicate",
+                format!(
+                    "{} t JOIN {} m ON m.id = t.mail_id JOIN {} a ON a.id = m.address_id",
+                    ATTACHMENT_TABLE, MAIL_TABLE, ADDRESS_TABLE
+                ),
+                "t",
+            ),
+        };
+
+        // Rows are ordered by ID within the same creation time, so that
+        // batches never skip or repeat a row
+        let after_clause = if after.is_some() {
+            format!("AND ({0}.creation_time, {0}.id::text) > ($4, $5)", alias)
+        } else {
+            String::new()
+        };
+
+        let query = format!(
+            "
+            SELECT {1}, a.address, {0}.status, {0}.error_msg, {0}.creation_time,
+                {0}.id::text AS cursor_id
+            FROM {2}
+            WHERE {0}.creation_time >= $1 AND {0}.creation_time < $2 {3}
+            ORDER BY {0}.creation_time, {0}.id::text
+            LIMIT $3",
+            alias, columns, from, after_clause
+        );
+
+        let mut q = sqlx::query(&query).bind(start).bind(end).bind(limit as i64);
+
+        if let Some(cursor) = after {
+            q = q.bind(cursor.creation_time).bind(cursor.id.clone());
+        }
+
+        let rows = q.fetch_all(self.db).await?;
+
+        let rows = rows
+            .iter()
+            .map(|r| {
+                let row = export::Row {
+                    mail_id: r.get("mail_id"),
+                    address: r.get("address"),
+                    message_id: r.get("message_id"),
+                    num_attachments: r.get("num_attachments"),
+                    total_size: r.get("total_size"),
+                    index: r.get("index"),
+                    name: r.get("name"),
+                    size: r.get("size"),
+                    is_duplicate: r.get("is_duplicate"),
+                    status: r.get("status"),
+                    error_msg: r.get("error_msg"),
+                    creation_time: r.get("creation_time"),
+                };
+
+                let cursor = export::Cursor {
+                    creation_time: row.creation_time,
+                    id: r.get("cursor_id"),
+                };
+
+                (row, cursor)
+            })
+            .collect();
+
+        Ok(rows)
+    }
+
+    /// Get the webhooks configured for an address
+    pub async fn get_webhooks(&mut self, address: &str) -> Result<Vec<Webhook>, Error> {
+        let query = format!(
+            "
+            SELECT w.url, w.format, w.on_received, w.on_success, w.on_rejection,
+                w.on_attachment, w.on_attachment_received, w.on_alert, w.secret,
+                w.template
+            FROM {} w
+            JOIN {} a ON a.id = w.address_id
+            WHERE a.address = $1",
+            WEBHOOK_TABLE, ADDRESS_TABLE
+        );
+
+        let rows = sqlx::query(&query).bind(address).fetch_all(self.db).await?;
+
+        let webhooks = rows
+            .iter()
+            .map(|r| Webhook {
+                url: r.get("url"),
+                format: r.get::<String, &str>("format").into(),
+                on_received: r.get("on_received"),
+                on_success: r.get("on_success"),
+                on_rejection: r.get("on_rejection"),
+                on_attachment: r.get("on_attachment"),
+                on_attachment_received: r.get("on_attachment_received"),
+                on_alert: r.get("on_alert"),
+                secret: r.get("secret"),
+                template: r.get("template"),
+            })
+            .collect();
+
+        Ok(webhooks)
+    }
+
+    /// Email received per day by each address, over the previous `days`
+    /// days and today, oldest first
+    ///
+    /// Only addresses that received email today are returned, as only they
+    /// can spike. Test emails are not counted.
+    pub async fn get_daily_volumes(
+        &mut self,
+        days: i64,
+    ) -> Result<HashMap<String, Vec<Volume>>, Error> {
+        let today = self.clock.now().date().and_hms(0, 0, 0);
+        let since = today - Duration::days(days);
+
+        let query = format!(
+            "
+            SELECT a.address, date_trunc('day', m.creation_time) AS day,
+                COUNT(*) AS num_emails, COALESCE(SUM(m.total_size), 0)::BIGINT AS total_size
+            FROM {0} m
+            JOIN {1} a ON a.id = m.address_id
+            WHERE m.creation_time >= $1 AND m.is_test = false
+                AND m.address_id IN (
+                    SELECT address_id FROM {0} WHERE creation_time >= $2
+                )
+            GROUP BY a.address, day",
+            MAIL_TABLE, ADDRESS_TABLE
+        );
+
+        let rows = sqlx::query(&query)
+            .bind(since)
+            .bind(today)
+            .fetch_all(self.db)
+            .await?;
+
+        let mut volumes: HashMap<String, Vec<Volume>> = HashMap::new();
+
+        for r in &rows {
+            let day: DateTime<Utc> = r.get("day");
+            let offset = (day - since).num_days();
+
+            if offset < 0 || offset > days {
+                continue;
+            }
+
+            let daily = volumes
+                .entry(r.get("address"))
+                .or_insert_with(|| vec![Volume::default(); days as usize + 1]);
+
+            daily[offset as usize] = Volume {
+                num_emails: r.get("num_emails"),
+                total_size: r.get("total_size"),
+            };
+        }
+
+        Ok(volumes)
+    }
+
+    /// Reset the email and storage counts of every address whose quota
+    /// period has elapsed, starting a new period
+    ///
+    /// Returns the renewed addresses.
+    pub async fn renew_quotas(&mut self, period: Duration) -> Result<Vec<String>, Error> {
+        let now = self.clock.now();
+
+        let query = format!(
+            "
+            UPDATE {}
+            SET num_received = 0, storage_used = 0, last_renewal_time = $1
+            WHERE last_renewal_time <= $2
+            RETURNING address",
+            ADDRESS_TABLE
+        );
+
+        let rows = sqlx::query(&query)
+            .bind(now)
+            .bind(now - period)
+            .fetch_all(self.db)
+            .await?;
+
+        Ok(rows.iter().map(|r| r.get("address")).collect())
+    }
+
+    /// Delete abuse samples older than the retention period
+    pub async fn prune_samples(&mut self, retention_days: i64) {
+        let cutoff = self.clock.now() - Duration::days(retention_days);
+
+        let query = format!("DELETE FROM {} WHERE creation_time < $1", SAMPLE_TABLE);
+
+        let num_rows = sqlx::query(&query).bind(cutoff).execute(self.db).await;
+
+        if let Err(e) = num_rows {
+            log::error!("Failed to prune samples: {}", e);
+        }
+    }
+
+    pub async fn list_users(&mut self) -> Result<Vec<User>, Error> {
+        let query = format!("SELECT {} FROM {} ORDER BY id", USER_COLUMNS, USER_TABLE);
+        let rows = sqlx::query(&query).fetch_all(self.db).await?;
+
+        Ok(rows.iter().map(user_from_row).collect())
+    }
+
+    pub async fn get_user(&mut self, id: i32) -> Result<Option<User>, Error> {
+        let query = format!("SELECT {} FROM {} WHERE id = $1", USER_COLUMNS, USER_TABLE);
+        let row = sqlx::query(&query).bind(id).fetch_optional(self.db).await?;
+
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    /// Create a user without a usable password (see `NewUser`)
+    pub async fn create_user(&mut self, user: &NewUser) -> Result<User, Error> {
+        let query = format!("SELECT 1 FROM {} WHERE username = $1", USER_TABLE);
+        let existing = sqlx::query(&query)
+            .bind(&user.username)
+            .fetch_optional(self.db)
+            .await?;
+
+        if existing.is_some() {
+            return Err(Error::InvalidRequest(format!(
+                "User {} already exists",
+                user.username
+            )));
+        }
+
+        // Passwords starting with "!" never match in Django
+        let now = self.clock.now();
+        let query = format!(
+            "
+            INSERT INTO {}
+                (username, email, password, is_active, is_subscribed, is_superuser,
+                 is_staff, first_name, last_name, date_joined, last_update_time)
+            VALUES ($1, $2, '!', $3, $4, false, false, '', '', $5, $5)
+            RETURNING {}",
+            USER_TABLE, USER_COLUMNS
+        );
+
+        let row = sqlx::query(&query)
+            .bind(&user.username)
+            .bind(&user.email)
+            .bind(user.is_active)
+            .bind(user.is_subscribed)
+            .bind(now)
+            .fetch_one(self.db)
+            .await?;
+
+        Ok(user_from_row(&row))
+    }
+
+    /// Returns None if the user does not exist
+    pub async fn update_user(
+        &mut self,
+        id: i32,
+        update: &UserUpdate,
+    ) -> Result<Option<User>, Error> {
+        let query = format!(
+            "
+            UPDATE {}
+            SET email = COALESCE($1, email),
+                is_active = COALESCE($2, is_active),
+                is_subscribed = COALESCE($3, is_subscribed),
+                last_update_time = $4
+            WHERE id = $5
+            RETURNING {}",
+            USER_TABLE, USER_COLUMNS
+        );
+
+        let row = sqlx::query(&query)
+            .bind(update.email.as_deref())
+            .bind(update.is_active)
+            .bind(update.is_subscribed)
+            .bind(self.clock.now())
+            .bind(id)
+            .fetch_optional(self.db)
+            .await?;
+
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    /// Delete a user that has no addresses or emails left
+    ///
+    /// Users with emails should be deactivated instead, so that their
+    /// history is kept. Returns false if the user does not exist.
+    pub async fn delete_user(&mut self, id: i32) -> Result<bool, Error> {
+        let query = format!(
+            "SELECT
+                EXISTS (SELECT 1 FROM {} WHERE user_id = $1) AS has_addresses,
+                EXISTS (SELECT 1 FROM {} WHERE user_id = $1) AS has_mail",
+            ADDRESS_TABLE, MAIL_TABLE
+        );
+        let row = sqlx::query(&query).bind(id).fetch_one(self.db).await?;
+
+        if row.get::<bool, &str>("has_addresses") || row.get::<bool, &str>("has_mail") {
+            return Err(Error::InvalidRequest(format!(
+                "User {} still has addresses or emails; deactivate it instead",
+                id
+            )));
+        }
+
+        let query = format!("DELETE FROM {} WHERE id = $1", USER_TABLE);
+        let num_rows = sqlx::query(&query).bind(id).execute(self.db).await?;
+
+        Ok(num_rows > 0)
+    }
+
+    /// List all addresses, or only those of a user
+    pub async fn list_addresses(
+        &mut self,
+        user_id: Option<i32>,
+    ) -> Result<Vec<AddressInfo>, Error> {
+        let query = format!(
+            "SELECT {} FROM {} WHERE $1::INTEGER IS NULL OR user_id = $1 ORDER BY id",
+            ADDRESS_INFO_COLUMNS, ADDRESS_TABLE
+        );
+        let rows = sqlx::query(&query).bind(user_id).fetch_all(self.db).await?;
+
+        Ok(rows.iter().map(address_info_from_row).collect())
+    }
+
+    pub async fn get_address_info(&mut self, id: i32) -> Result<Option<AddressInfo>, Error> {
+        let query = format!(
+            "SELECT {} FROM {} WHERE id = $1",
+            ADDRESS_INFO_COLUMNS, ADDRESS_TABLE
+        );
+        let row = sqlx::query(&query).bind(id).fetch_optional(self.db).await?;
+
+        Ok(row.as_ref().map(address_info_from_row))
+    }
+
+    /// Create an address, with a new quota period starting now
+    ///
+    /// Only one address with the same name can be active at a time.
+    pub async fn create_address(&mut self, address: &NewAddress) -> Result<AddressInfo, Error> {
+        if self.get_user(address.user_id).await?.is_none() {
+            return Err(Error::InvalidRequest(format!(
+                "User {} does not exist",
+                address.user_id
+            )));
+        }
+
+        if address.is_active {
+            self.check_active_unique(&address.address, None).await?;
+        }
+
+        let now = self.clock.now();
+        let storage = &address.storage;
+        // The whitelist is bound as a single comma-separated value, like it
+        // is read back (see `split_list`)
+        let whitelist = address.whitelist.join(",");
+        let query = format!(
+            "
+            INSERT INTO {}
+                (address, user_id, is_active, email_quota, max_email_size, storage_quota,
+                 num_received, storage_used, last_renewal_time, storage_backend,
+                 storage_token, storage_refresh_token, storage_token_expiry, storage_path,
+                 is_whitelist_enabled, whitelist, organization, bundle_attachments,
+                 encrypt_files, submission_password, last_update_time, creation_time)
+            VALUES ($1, $2, $3, $4, $5, $6, 0, 0, $7, $8, $9, $10, $11, $12, $13,
+                    string_to_array($14, ','), 'none', false, false, '', $7, $7)
+            RETURNING {}",
+            ADDRESS_TABLE, ADDRESS_INFO_COLUMNS
+        );
+
+        let row = sqlx::query(&query)
+            .bind(&address.address)
+            .bind(address.user_id)
+            .bind(address.is_active)
+            .bind(address.quotas.email_quota)
+            .bind(address.quotas.max_email_size)
+            .bind(address.quotas.storage_quota)
+            .bind(now)
+            .bind(storage.storage_backend.as_deref())
+            .bind(self.seal_token(&storage.storage_token))
+            .bind(
+                storage
+                    .storage_refresh_token
+                    .as_deref()
+                    .map(|token| self.seal_token(token)),
+            )
+            .bind(storage.storage_token_expiry)
+            .bind(&storage.storage_path)
+            .bind(address.is_whitelist_enabled)
+            .bind(whitelist)
+            .fetch_one(self.db)
+            .await?;
+
+        Ok(address_info_from_row(&row))
+    }
+
+    /// Fail if an address with this name is active, other than the one
+    /// with the given ID
+    async fn check_active_unique(&mut self, address: &str, id: Option<i32>) -> Result<(), Error> {
+        let query = format!(
+            "SELECT 1 FROM {} WHERE address = $1 AND is_active = true AND id != $2",
+            ADDRESS_TABLE
+        );
+        let row = sqlx::query(&query)
+            .bind(address)
+            .bind(id.unwrap_or(-1))
+            .fetch_optional(self.db)
+            .await?;
+
+        if row.is_some() {
+            return Err(Error::InvalidRequest(format!(
+                "Address {} is already active",
+                address
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Returns None if the address does not exist
+    pub async fn update_address(
+        &mut self,
+        id: i32,
+        update: &AddressUpdate,
+    ) -> Result<Option<AddressInfo>, Error> {
+        if let Some(user_id) = update.user_id {
+            if self.get_user(user_id).await?.is_none() {
+                return Err(Error::InvalidRequest(format!(
+                    "User {} does not exist",
+                    user_id
+                )));
+            }
+        }
+
+        if update.is_active == Some(true) {
+            if let Some(existing) = self.get_address_info(id).await? {
+                self.check_active_unique(&existing.address, Some(id))
+                    .await?;
+            }
+        }
+
+        let query = format!(
+            "
+            UPDATE {}
+            SET user_id = COALESCE($1, user_id),
+                is_active = COALESCE($2, is_active),
+                is_whitelist_enabled = COALESCE($3, is_whitelist_enabled),
+                filename_template = NULLIF(COALESCE($4, filename_template), ''),
+                bundle_template = NULLIF(COALESCE($5, bundle_template), ''),
+                last_update_time = $6
+            WHERE id = $7
+            RETURNING {}",
+            ADDRESS_TABLE, ADDRESS_INFO_COLUMNS
+        );
+
+        let row = sqlx::query(&query)
+            .bind(update.user_id)
+            .bind(update.is_active)
+            .bind(update.is_whitelist_enabled)
+            .bind(update.filename_template.as_deref())
+            .bind(update.bundle_template.as_deref())
+            .bind(self.clock.now())
+            .bind(id)
+            .fetch_optional(self.db)
+            .await?;
+
+        Ok(row.as_ref().map(address_info_from_row))
+    }
+
+    /// Replace the quotas of an address
+    ///
+    /// Returns None if the address does not exist.
+    pub async fn set_quotas(
+        &mut self,
+        id: i32,
+        quotas: &Quotas,
+    ) -> Result<Option<AddressInfo>, Error> {
+        let query = format!(
+            "
+            UPDATE {}
+            SET email_quota = $1, max_email_size = $2, storage_quota = $3,
+                last_update_time = $4
+            WHERE id = $5
+            RETURNING {}",
+            ADDRESS_TABLE, ADDRESS_INFO_COLUMNS
+        );
+
+        let row = sqlx::query(&query)
+            .bind(quotas.email_quota)
+            .bind(quotas.max_email_size)
+            .bind(quotas.storage_quota)
+            .bind(self.clock.now())
+            .bind(id)
+            .fetch_optional(self.db)
+            .await?;
+
+        Ok(row.as_ref().map(address_info_from_row))
+    }
+
+    /// Replace the storage backend and credentials of an address
+    ///
+    /// Returns None if the address does not exist.
+    pub async fn set_storage(
+        &mut self,
+        id: i32,
+        storage: &Storage,
+    ) -> Result<Option<AddressInfo>, Error> {
+        let query = format!(
+            "
+            UPDATE {}
+            SET storage_backend = $1, storage_token = $2, storage_refresh_token = $3,
+                storage_token_expiry = $4, storage_path = $5, last_update_time = $6
+            WHERE id = $7
+            RETURNING {}",
+            ADDRESS_TABLE, ADDRESS_INFO_COLUMNS
+        );
+
+        let row = sqlx::query(&query)
+            .bind(storage.storage_backend.as_deref())
+            .bind(self.seal_token(&storage.storage_token))
+            .bind(
+                storage
+                    .storage_refresh_token
+                    .as_deref()
+                    .map(|token| self.seal_token(token)),
+            )
+            .bind(storage.storage_token_expiry)
+            .bind(&storage.storage_path)
+            .bind(self.clock.now())
+            .bind(id)
+            .fetch_optional(self.db)
+            .await?;
+
+        Ok(row.as_ref().map(address_info_from_row))
+    }
+
+    /// Delete an address that has no emails, along with its rules, storage
+    /// routes, and webhooks
+    ///
+    /// Addresses with emails should be deactivated instead, so that their
+    /// history is kept. Returns false if the address does not exist.
+    pub async fn delete_address(&mut self, id: i32) -> Result<bool, Error> {
+        let query = format!("SELECT 1 FROM {} WHERE address_id = $1 LIMIT 1", MAIL_TABLE);
+        let row = sqlx::query(&query).bind(id).fetch_optional(self.db).await?;
+
+        if row.is_some() {
+            return Err(Error::InvalidRequest(format!(
+                "Address {} still has emails; deactivate it instead",
+                id
+            )));
+        }
+
+        let mut tx = self.db.begin().await?;
+
+        for table in &[
+            RULE_TABLE,
+            PRIORITY_RULE_TABLE,
+            STORAGE_ROUTE_TABLE,
+            WEBHOOK_TABLE,
+        ] {
+            let query = format!("DELETE FROM {} WHERE address_id = $1", table);
+            sqlx::query(&query).bind(id).execute(&mut tx).await?;
+        }
+
+        let query = format!("DELETE FROM {} WHERE id = $1", ADDRESS_TABLE);
+        let num_rows = sqlx::query(&query).bind(id).execute(&mut tx).await?;
+
+        tx.commit().await?;
+
+        Ok(num_rows > 0)
+    }
+
+    /// Returns None if the address does not exist
+    pub async fn get_whitelist(&mut self, id: i32) -> Result<Option<Vec<String>>, Error> {
+        let query = format!(
+            "SELECT array_to_string(whitelist, ',') AS whitelist FROM {} WHERE id = $1",
+            ADDRESS_TABLE
+        );
+        let row = sqlx::query(&query).bind(id).fetch_optional(self.db).await?;
+
+        Ok(row.map(|r| split_list(r.get("whitelist"))))
+    }
+
+    /// Add a sender to the whitelist of an address, unless it is on it
+    /// already
+    ///
+    /// Returns the new whitelist, or None if the address does not exist.
+    pub async fn add_to_whitelist(
+        &mut self,
+        id: i32,
+        sender: &str,
+    ) -> Result<Option<Vec<String>>, Error> {
+        let query = format!(
+            "
+            UPDATE {}
+            SET whitelist = CASE
+                    WHEN $1 = ANY (whitelist) THEN whitelist
+                    ELSE array_append(whitelist, $1)
+                END,
+                last_update_time = $2
+            WHERE id = $3
+            RETURNING array_to_string(whitelist, ',') AS whitelist",
+            ADDRESS_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(sender)
+            .bind(self.clock.now())
+            .bind(id)
+            .fetch_optional(self.db)
+            .await?;
+
+        Ok(row.map(|r| split_list(r.get("whitelist"))))
+    }
+
+    /// Remove a sender from the whitelist of an address
+    ///
+    /// Returns the new whitelist, or None if the address does not exist.
+    pub async fn remove_from_whitelist(
+        &mut self,
+        id: i32,
+        sender: &str,
+    ) -> Result<Option<Vec<String>>, Error> {
+        let query = format!(
+            "
+            UPDATE {}
+            SET whitelist = array_remove(whitelist, $1), last_update_time = $2
+            WHERE id = $3
+            RETURNING array_to_string(whitelist, ',') AS whitelist",
+            ADDRESS_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(sender)
+            .bind(self.clock.now())
+            .bind(id)
+            .fetch_optional(self.db)
+            .await?;
+
+        Ok(row.map(|r| split_list(r.get("whitelist"))))
+    }
+
+    /// Get the most recent log messages, oldest first
+    ///
+    /// If `after` is set, the messages logged after it are returned instead,
+    /// so that new messages can be followed. Only messages about the emails
+    /// of `address` are returned if it is set; messages logged before an
+    /// email was accepted are not tied to an address.
+    pub async fn get_logs(
+        &mut self,
+        address: Option<&str>,
+        after: Option<i32>,
+        limit: i64,
+    ) -> Result<Vec<LogEntry>, Error> {
+        let order = if after.is_some() { "ASC" } else { "DESC" };
+        let query = format!(
+            "
+            SELECT l.id, l.mail_id, l.log_level, l.msg, l.creation_time
+            FROM {} l
+            LEFT JOIN {} m ON m.id = l.mail_id
+            LEFT JOIN {} a ON a.id = m.address_id
+            WHERE ($1::TEXT IS NULL OR a.address = $1)
+                AND ($2::INTEGER IS NULL OR l.id > $2)
+            ORDER BY l.id {}
+            LIMIT $3",
+            LOG_TABLE, MAIL_TABLE, ADDRESS_TABLE, order
+        );
+
+        let rows = sqlx::query(&query)
+            .bind(address)
+            .bind(after)
+            .bind(limit)
+            .fetch_all(self.db)
+            .await?;
+
+        let mut logs: Vec<LogEntry> = rows
+            .iter()
+            .map(|r| LogEntry {
+                id: r.get("id"),
+                mail_id: r.get("mail_id"),
+                level: LogLevel::from(r.get::<i32, &str>("log_level"))
+                    .as_str()
+                    .to_string(),
+                msg: r.get("msg"),
+                creation_time: r.get("creation_time"),
+            })
+            .collect();
+
+        if after.is_none() {
+            logs.reverse();
+        }
+
+        Ok(logs)
+    }
+
+    /// Get the status of an email and its attachments, including failed
+    /// emails
+    ///
+    /// Returns None if the email does not exist.
+    pub async fn get_email_status(
+        &mut self,
+        mail_id: &uuid::Uuid,
+    ) -> Result<Option<EmailStatus>, Error> {
+        let query = format!(
+            "
+            SELECT m.message_id, m.num_attachments, m.total_size, m.status, m.error_msg,
+                m.last_update_time, m.creation_time, a.address
+            FROM {} m
+            JOIN {} a ON a.id = m.address_id
+            WHERE m.id = $1",
+            MAIL_TABLE, ADDRESS_TABLE
+        );
+
+        let row = sqlx::query(&query)
+            .bind(mail_id)
+            .fetch_optional(self.db)
+            .await?;
+
+        let data = match row {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        let query = format!(
+            "
+            SELECT index, name, size, mime_type, storage_path, is_duplicate, status,
+                error_msg, creation_time
+            FROM {}
+            WHERE mail_id = $1
+            ORDER BY index",
+            ATTACHMENT_TABLE
+        );
+
+        let attachments = sqlx::query(&query)
+            .bind(mail_id)
+            .fetch_all(self.db)
+            .await?
+            .iter()
+            .map(|r| AttachmentStatus {
+                index: r.get("index"),
+                name: r.get("name"),
+                size: r.get("size"),
+                mime_type: r.get("mime_type"),
+                storage_path: r.get("storage_path"),
+                is_duplicate: r.get("is_duplicate"),
+                status: r.get("status"),
+                error_msg: r
+                    .get::<Option<String>, &str>("error_msg")
+                    .filter(|m| !m.is_empty()),
+                creation_time: r.get("creation_time"),
+            })
+            .collect();
+
+        Ok(Some(EmailStatus {
+            id: *mail_id,
+            address: data.get("address"),
+            message_id: data.get("message_id"),
+            num_attachments: data.get("num_attachments"),
+            total_size: data.get("total_size"),
+            status: data.get("status"),
+            error_msg: data
+                .get::<Option<String>, &str>("error_msg")
+                .filter(|m| !m.is_empty()),
+            attachments,
+            last_update_time: data.get("last_update_time"),
+            creation_time: data.get("creation_time"),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::id::IdGenerator;
+
+    /// Recipients that would break out of a naively formatted query
+    const HOSTILE_RECIPIENTS: &[&str] = &[
+        "x' OR '1'='1",
+        "test1@vaulty.net' OR 'a'='a",
+        "'); DROP TABLE vaulty_addresses; --",
+        "test1@vaulty.net'--",
+        "\\' UNION SELECT * FROM vaulty_users --",
+    ];
+
+    /// Requires a DB loaded with `vaulty-db/schema.sql`, e.g.:
+    /// `VAULTY_TEST_DB=postgres://vaulty@127.0.0.1/vaulty`
+    #[tokio::test]
+    #[ignore]
+    async fn get_address_hostile_recipients() {
+        let url = std::env::var("VAULTY_TEST_DB").expect("No test DB found");
+        let mut pool = sqlx::PgPool::new(&url).await.unwrap();
+        let mut client = Client::new(&mut pool);
+        let defaults = Settings::from_config(&Default::default());
+
+        for r in HOSTILE_RECIPIENTS {
+            let address = client.get_address(&[r], &defaults).await.unwrap();
+            assert!(address.is_none(), "{}", r);
+        }
+
+        // The first valid recipient is returned regardless of hostile ones
+        let mut recipients = HOSTILE_RECIPIENTS.to_vec();
+        recipients.extend(&["test2@vaulty.net", "test1@vaulty.net"]);
+
+        let address = client.get_address(&recipients, &defaults).await.unwrap();
+        assert_eq!(address.unwrap().address, "test2@vaulty.net");
+    }
+
+    /// Requires a DB loaded with `vaulty-db/schema.sql`
+    #[tokio::test]
+    #[ignore]
+    async fn accept_email_quota() {
+        let url = std::env::var("VAULTY_TEST_DB").expect("No test DB found");
+        let mut pool = sqlx::PgPool::new(&url).await.unwrap();
+        let mut client = Client::new(&mut pool);
+        let defaults = Settings::from_config(&Default::default());
+
+        let mut address = client
+            .get_address(&["test1@vaulty.net"], &defaults)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Seed with the current time so that reruns do not collide
+        let ids = crate::id::NamespaceIds;
+        let seed = Utc::now().to_rfc3339();
+
+        let email = Email {
+            uuid: ids.generate(format!("{}-accepted", seed).as_bytes()),
+            recipients: vec![address.address.clone()],
+            body: "Hello".to_string(),
+            size: 5,
+            ..Default::default()
+        };
+
+        assert!(client.accept_email(&email, &address).await.unwrap());
+
+        // Accepting the same email again does not count it twice
+        assert!(!client.accept_email(&email, &address).await.unwrap());
+
+        let accepted = client
+            .get_address(&["test1@vaulty.net"], &defaults)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(accepted.num_received, address.num_received + 1);
+        assert_eq!(accepted.storage_used, address.storage_used + 5);
+
+        // Rejected emails are stored as failed and not counted
+        address.settings.email_quota = accepted.num_received;
+        let email = Email {
+            uuid: ids.generate(format!("{}-rejected", seed).as_bytes()),
+            ..email
+        };
+
+        match client.accept_email(&email, &address).await {
+            Err(Error::QuotaExceeded(_)) => (),
+            _ => panic!("Expected quota to be exceeded"),
+        }
+
+        assert!(client.get_email(&email.uuid).await.unwrap().is_none());
+
+        let rejected = client
+            .get_address(&["test1@vaulty.net"], &defaults)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rejected.num_received, accepted.num_received);
+    }
+
+    #[test]
+    fn quota_renewal_due() {
+        let last_renewal_time = "2020-02-09T19:38:12Z".parse::<DateTime<Utc>>().unwrap();
+        let address = Address {
+            address: "test1@vaulty.net".to_string(),
+            user_id: 1,
+            is_active: true,
+            num_received: 0,
+            storage_used: 0,
+            storage_token: "".to_string(),
+            storage_path: "/vaulty".to_string(),
+            last_renewal_time,
+            storage_refresh_token: None,
+            storage_token_expiry: None,
+            dropbox_namespace_id: None,
+            dropbox_team_member_id: None,
+            reply_success_template: None,
+            reply_rejection_template: None,
+            link_archive_pattern: None,
+            pipeline: Vec::new(),
+            filename_template: None,
+            organization: Organization::None,
+            bundle_attachments: false,
+            bundle_template: None,
+            encrypt_files: false,
+            encryption_key: None,
+            indexed_storage_path: None,
+            settings: Settings {
+                email_quota: 1000,
+                storage_quota: 20_000_000_000,
+                max_email_size: 20_000_000,
+                storage_backend: storage::Backend::Dropbox,
+                reply_on_success: false,
+                reply_on_rejection: false,
+                skip_unchanged: false,
+                dedup_attachments: false,
+                transliterate_filenames: false,
+                store_body: false,
+                archive_eml: false,
+                archive_links: false,
+                body_pdf: false,
+                sign_manifests: false,
+                auto_generated_policy: AutoGeneratedPolicy::Store,
+                delivery_mode: DeliveryMode::Sync,
+            },
+            domain_settings: Default::default(),
+            address_settings: Default::default(),
+        };
+
+        let clock = FixedClock::new(last_renewal_time);
+        let period = Duration::days(crate::config::DEFAULT_QUOTA_PERIOD_DAYS);
+        assert!(!address.is_renewal_due(&clock, period));
+
+        clock.advance(period - Duration::seconds(1));
+        assert!(!address.is_renewal_due(&clock, period));
+
+        clock.advance(Duration::seconds(1));
+        assert!(address.is_renewal_due(&clock, period));
+
+        // Tokens without an expiry never expire
+        assert!(!address.is_token_expired(&clock));
+
+        let address = Address {
+            storage_token_expiry: Some(clock.now() + Duration::hours(1)),
+            ..address
+        };
+        assert!(!address.is_token_expired(&clock));
+
+        clock.advance(Duration::minutes(56));
+        assert!(address.is_token_expired(&clock));
+
+        // Size limits
+        let address = Address {
+            storage_used: 19_990_000_000,
+            ..address
+        };
+        assert!(address.check_size(5_000_000).is_none());
+        assert!(address
+            .check_size(20_000_001)
+            .unwrap()
+            .contains("maximum email size"));
+        assert!(address
+            .check_size(15_000_000)
+            .unwrap()
+            .contains("storage quota"));
+    }
 }
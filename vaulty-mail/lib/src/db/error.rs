@@ -0,0 +1,213 @@
+use std::error;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use sqlx::error::DatabaseError;
+
+use crate::error::Cause;
+
+/// Start of the Postgres messages of transactions that were rolled back, and
+/// can be run again as they are (serialization failures and deadlocks)
+///
+/// sqlx does not expose the SQLSTATE of an error, so errors are told apart
+/// by their message.
+const ROLLED_BACK_MESSAGES: &[&str] = &["could not serialize access", "deadlock detected"];
+
+/// Start of the Postgres messages of the server closing the connection, or
+/// not accepting connections while it starts up or shuts down
+const CONNECTION_MESSAGES: &[&str] = &["terminating connection", "the database system is"];
+
+/// Error type for DB calls
+/// Tells a row that does not exist from a DB that cannot be reached, so that
+/// an email can be rejected or deferred accordingly. Errors keep the sqlx
+/// error they were built from as their source.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Error {
+    /// The row that was looked up does not exist
+    NotFound,
+    QueryFailed(Cause),
+    /// The DB could not be reached (e.g., while it restarts), or the
+    /// connection was lost
+    ConnectionFailed(Cause),
+    /// The query violated a constraint (e.g., a unique or foreign key)
+    Constraint(Cause),
+}
+
+impl Error {
+    /// Returns true if the Postgres error is that of the server closing the
+    /// connection, or shutting down
+    fn is_connection_error(e: &dyn DatabaseError) -> bool {
+        CONNECTION_MESSAGES
+            .iter()
+            .any(|m| e.message().starts_with(m))
+    }
+
+    /// Returns true if the Postgres error is that of an integrity constraint
+    /// violation
+    ///
+    /// Not-null violations have no constraint name, but their message says
+    /// that a constraint was violated like the others do.
+    fn is_constraint_error(e: &dyn DatabaseError) -> bool {
+        e.constraint_name().is_some() || e.message().contains(" violates ")
+    }
+
+    fn sqlx_error(&self) -> Option<&sqlx::Error> {
+        error::Error::source(self).and_then(|e| e.downcast_ref::<sqlx::Error>())
+    }
+
+    /// Returns true if no connection could be taken from the pool, so the
+    /// call never reached the DB
+    pub fn is_pool_timeout(&self) -> bool {
+        match self {
+            Error::ConnectionFailed(_) => {
+                matches!(self.sqlx_error(), Some(sqlx::Error::PoolTimedOut { .. }))
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns true if Postgres rolled back the transaction because it failed
+    /// to serialize or deadlocked
+    pub fn is_rolled_back(&self) -> bool {
+        match (self, self.sqlx_error()) {
+            (Error::QueryFailed(_), Some(sqlx::Error::Database(e))) => {
+                Self::is_rolled_back_error(e.as_ref())
+            }
+            _ => false,
+        }
+    }
+
+    fn is_rolled_back_error(e: &dyn DatabaseError) -> bool {
+        ROLLED_BACK_MESSAGES
+            .iter()
+            .any(|m| e.message().starts_with(m))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotFound => f.write_str("Database row not found"),
+            Error::QueryFailed(ref cause) => write!(f, "Database error: {}", cause),
+            Error::ConnectionFailed(ref cause) => write!(f, "Database unavailable: {}", cause),
+            Error::Constraint(ref cause) => {
+                write!(f, "Database constraint violated: {}", cause)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::QueryFailed(cause)
+            | Error::ConnectionFailed(cause)
+            | Error::Constraint(cause) => cause.source(),
+            Error::NotFound => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        let (is_connection, is_constraint) = match &err {
+            sqlx::Error::NotFound => return Self::NotFound,
+            sqlx::Error::Io { .. } | sqlx::Error::PoolTimedOut { .. } | sqlx::Error::PoolClosed => {
+                (true, false)
+            }
+            sqlx::Error::Database(e) => (
+                Self::is_connection_error(e.as_ref()),
+                Self::is_constraint_error(e.as_ref()),
+            ),
+            _ => (false, false),
+        };
+
+        let cause = Cause::with_source("", err);
+
+        if is_connection {
+            Self::ConnectionFailed(cause)
+        } else if is_constraint {
+            Self::Constraint(cause)
+        } else {
+            Self::QueryFailed(cause)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_errors() {
+        assert!(matches!(
+            Error::from(sqlx::Error::NotFound),
+            Error::NotFound
+        ));
+
+        let reset = sqlx::Error::Io(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+        let err = Error::from(reset);
+        assert!(matches!(err, Error::ConnectionFailed(_)));
+        assert!(error::Error::source(&err).is_some());
+
+        assert!(matches!(
+            Error::from(sqlx::Error::Protocol("unexpected message".into())),
+            Error::QueryFailed(_)
+        ));
+
+        let db_error = |message, constraint| {
+            Error::from(sqlx::Error::Database(Box::new(TestDbError {
+                message,
+                constraint,
+            })))
+        };
+
+        let shutdown = db_error("terminating connection due to administrator command", None);
+        assert!(matches!(shutdown, Error::ConnectionFailed(_)));
+
+        let starting = db_error("the database system is starting up", None);
+        assert!(matches!(starting, Error::ConnectionFailed(_)));
+
+        let unique = db_error(
+            "duplicate key value violates unique constraint \"vaulty_addresses_pkey\"",
+            Some("vaulty_addresses_pkey"),
+        );
+        assert!(matches!(unique, Error::Constraint(_)));
+
+        let not_null = db_error(
+            "null value in column \"address\" violates not-null constraint",
+            None,
+        );
+        assert!(matches!(not_null, Error::Constraint(_)));
+
+        let serialization = db_error("could not serialize access due to concurrent update", None);
+        assert!(matches!(serialization, Error::QueryFailed(_)));
+        assert!(serialization.is_rolled_back());
+
+        let deadlock = db_error("deadlock detected", None);
+        assert!(deadlock.is_rolled_back());
+        assert!(!unique.is_rolled_back());
+    }
+
+    #[derive(Debug)]
+    struct TestDbError {
+        message: &'static str,
+        constraint: Option<&'static str>,
+    }
+
+    impl fmt::Display for TestDbError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(self.message)
+        }
+    }
+
+    impl DatabaseError for TestDbError {
+        fn message(&self) -> &str {
+            self.message
+        }
+
+        fn constraint_name(&self) -> Option<&str> {
+            self.constraint
+        }
+    }
+}
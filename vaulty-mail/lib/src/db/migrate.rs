@@ -0,0 +1,276 @@
+//! Schema migrations embedded in the server
+//!
+//! Tables are owned by the Django app under vaulty-web, so they are created
+//! and changed by its migrations. The migrations here only add what the mail
+//! server needs on top of them (e.g., indexes for its own queries), and are
+//! applied once the web schema is at `WEB_SCHEMA_VERSION`.
+
+use sqlx::Row;
+
+use crate::email::content_hash;
+use crate::Error;
+
+/// Latest vaulty-web migration that this build expects to be applied
+///
+/// Bump this along with web migrations that the server depends on.
+pub const WEB_SCHEMA_VERSION: &str = "0039_indexed_file";
+
+/// Migrations applied so far, along with a checksum of each
+const MIGRATION_TABLE: &str = "vaulty_schema_migrations";
+
+/// Key of the advisory lock held while migrating, so that servers that start
+/// at the same time do not apply the same migration twice
+const MIGRATION_LOCK: i64 = 0x7661_756c_7479;
+
+/// A single schema change
+///
+/// Each migration must be a single SQL statement that can run in a
+/// transaction (e.g., no `CREATE INDEX CONCURRENTLY`), and must not be
+/// edited once released: add a new one instead.
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+impl Migration {
+    fn checksum(&self) -> String {
+        content_hash(self.sql.as_bytes())
+    }
+}
+
+/// Channel that changes to addresses and domains are notified on, once
+/// migrated (see `migrations/0002_notify_address_changes.sql`)
+///
+/// The payload is the address, or `@` followed by the domain. Renamed
+/// addresses are notified under their old and new names.
+pub const ADDRESS_CHANNEL: &str = "vaulty_address_changed";
+
+/// All migrations, in the order they are applied
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "mail_address_time_index",
+        sql: include_str!("../../migrations/0001_mail_address_time_index.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "notify_address_changes",
+        sql: include_str!("../../migrations/0002_notify_address_changes.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "notify_address_changes_trigger",
+        sql: include_str!("../../migrations/0003_notify_address_changes_trigger.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "notify_domain_changes_trigger",
+        sql: include_str!("../../migrations/0004_notify_domain_changes_trigger.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "notify_renamed_addresses",
+        sql: include_str!("../../migrations/0005_notify_renamed_addresses.sql"),
+    },
+];
+
+/// A migration recorded in the DB
+struct Applied {
+    version: i32,
+    checksum: String,
+}
+
+/// Migrations that still need to be applied, given those that were
+///
+/// Fails if the DB was migrated by a newer build, or if a migration was
+/// changed since it was applied.
+fn plan<'a>(migrations: &'a [Migration], applied: &[Applied]) -> Result<Vec<&'a Migration>, Error> {
+    for a in applied {
+        match migrations.iter().find(|m| m.version == a.version) {
+            Some(m) if m.checksum() != a.checksum => {
+                return Err(Error::Generic(format!(
+                    "Migration {} ({}) was changed after it was applied",
+                    m.version, m.name
+                )));
+            }
+            Some(_) => (),
+            None => {
+                return Err(Error::Generic(format!(
+                    "DB schema has migration {}, which this build does not know about; \
+                     upgrade the server",
+                    a.version
+                )));
+            }
+        }
+    }
+
+    Ok(migrations
+        .iter()
+        .filter(|m| !applied.iter().any(|a| a.version == m.version))
+        .collect())
+}
+
+fn applied_query() -> String {
+    format!(
+        "SELECT version, checksum FROM {} ORDER BY version",
+        MIGRATION_TABLE
+    )
+}
+
+fn applied_from_rows(rows: &[sqlx::postgres::PgRow]) -> Vec<Applied> {
+    rows.iter()
+        .map(|r| Applied {
+            version: r.get("version"),
+            checksum: r.get("checksum"),
+        })
+        .collect()
+}
+
+/// Check that the web schema is at the version this build expects
+async fn check_web_schema(db: &mut sqlx::PgPool) -> Result<(), Error> {
+    let query = "
+        SELECT EXISTS (
+            SELECT 1 FROM django_migrations WHERE app = 'web' AND name = $1
+        ) AS is_applied";
+
+    let is_applied: bool = sqlx::query(query)
+        .bind(WEB_SCHEMA_VERSION)
+        .fetch_one(db)
+        .await
+        .map(|r| r.get("is_applied"))
+        .unwrap_or(false);
+
+    if !is_applied {
+        return Err(Error::Generic(format!(
+            "The vaulty-web schema is not at {}; run its migrations first \
+             (manage.py migrate)",
+            WEB_SCHEMA_VERSION
+        )));
+    }
+
+    Ok(())
+}
+
+/// Names of the migrations that have not been applied yet
+///
+/// All migrations are pending if the DB was never migrated.
+pub async fn pending(db: &mut sqlx::PgPool) -> Result<Vec<&'static str>, Error> {
+    // The migration table only exists once the DB was migrated
+    let applied = match sqlx::query(&applied_query()).fetch_all(db).await {
+        Ok(rows) => applied_from_rows(&rows),
+        Err(_) => Vec::new(),
+    };
+
+    Ok(plan(MIGRATIONS, &applied)?
+        .into_iter()
+        .map(|m| m.name)
+        .collect())
+}
+
+/// Bring the schema up to date
+///
+/// The web schema must be up to date already. Pending migrations are
+/// applied in a single transaction, so either all or none are. Returns the
+/// names of the migrations that were applied.
+pub async fn migrate(db: &mut sqlx::PgPool) -> Result<Vec<&'static str>, Error> {
+    check_web_schema(db).await?;
+
+    let mut tx = db.begin().await?;
+
+    // Released when the transaction ends
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(MIGRATION_LOCK)
+        .execute(&mut tx)
+        .await?;
+
+    let query = format!(
+        "
+        CREATE TABLE IF NOT EXISTS {} (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            checksum TEXT NOT NULL,
+            applied_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+        )",
+        MIGRATION_TABLE
+    );
+    sqlx::query(&query).execute(&mut tx).await?;
+
+    let rows = sqlx::query(&applied_query()).fetch_all(&mut tx).await?;
+    let applied = applied_from_rows(&rows);
+    let mut names = Vec::new();
+
+    for migration in plan(MIGRATIONS, &applied)? {
+        log::info!(
+            "Applying migration {} ({})",
+            migration.version,
+            migration.name
+        );
+
+        sqlx::query(migration.sql).execute(&mut tx).await?;
+
+        let query = format!(
+            "INSERT INTO {} (version, name, checksum) VALUES ($1, $2, $3)",
+            MIGRATION_TABLE
+        );
+        sqlx::query(&query)
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(migration.checksum())
+            .execute(&mut tx)
+            .await?;
+
+        names.push(migration.name);
+    }
+
+    tx.commit().await?;
+
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            name: "first",
+            sql: "CREATE INDEX a ON b (c)",
+        },
+        Migration {
+            version: 2,
+            name: "second",
+            sql: "CREATE INDEX d ON e (f)",
+        },
+    ];
+
+    fn applied(version: i32, sql: &str) -> Applied {
+        Applied {
+            version,
+            checksum: content_hash(sql.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn plan_migrations() {
+        let names = |planned: Vec<&Migration>| planned.iter().map(|m| m.name).collect::<Vec<_>>();
+
+        let planned = plan(TEST_MIGRATIONS, &[]).unwrap();
+        assert_eq!(names(planned), vec!["first", "second"]);
+
+        let planned = plan(TEST_MIGRATIONS, &[applied(1, TEST_MIGRATIONS[0].sql)]).unwrap();
+        assert_eq!(names(planned), vec!["second"]);
+
+        // Edited after it was applied
+        assert!(plan(TEST_MIGRATIONS, &[applied(1, "CREATE INDEX a ON b (x)")]).is_err());
+
+        // Applied by a newer build
+        assert!(plan(TEST_MIGRATIONS, &[applied(3, "")]).is_err());
+
+        // Released migrations are numbered in order
+        for (i, m) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(m.version, i as i32 + 1);
+        }
+    }
+}
@@ -1,2 +1,8 @@
 pub mod db;
+pub mod error;
+pub mod migrate;
+pub mod retry;
 pub use db::*;
+pub use error::Error;
+pub use migrate::migrate;
+pub use retry::RetryPolicy;
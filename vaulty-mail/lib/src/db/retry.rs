@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::db;
+use crate::storage;
+use crate::Error;
+
+/// Retries DB calls that fail for transient reasons
+///
+/// Calls are retried when the DB cannot be reached (e.g., while Postgres
+/// restarts), or when Postgres rolled back a transaction that can be run
+/// again (serialization failures and deadlocks). Other errors, such as
+/// constraint violations, are returned right away.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+
+    /// Delay before the first retry; doubled for each retry after that
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: crate::config::DEFAULT_DB_MAX_ATTEMPTS,
+            base_delay: Duration::from_millis(crate::config::DEFAULT_DB_RETRY_DELAY_MS),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_attempts: config.db_max_attempts,
+            base_delay: Duration::from_millis(config.db_retry_delay_ms),
+        }
+    }
+
+    /// Delay before the given retry (starting at 1), with the same jitter
+    /// as storage requests
+    pub fn backoff(&self, retry: u32) -> Duration {
+        storage::RetryPolicy {
+            max_attempts: self.max_attempts,
+            base_delay: self.base_delay,
+            ..Default::default()
+        }
+        .backoff(retry)
+    }
+
+    /// Returns the result of an attempt (starting at 1), unless it should be
+    /// retried, in which case this waits for the backoff and returns `None`
+    ///
+    /// Calls that are `idempotent` (e.g., reads) are also retried when the
+    /// connection was lost while they were in flight. See `is_transient`.
+    pub async fn check<T>(
+        &self,
+        result: Result<T, Error>,
+        attempt: &mut u32,
+        idempotent: bool,
+    ) -> Option<Result<T, Error>> {
+        let err = match &result {
+            Err(e) if *attempt < self.max_attempts && is_transient(e, idempotent) => e,
+            _ => return Some(result),
+        };
+
+        let delay = self.backoff(*attempt);
+        log::warn!(
+            "DB call failed: {} (attempt {} of {}); retrying in {:?}",
+            err,
+            attempt,
+            self.max_attempts,
+            delay
+        );
+
+        tokio::time::delay_for(delay).await;
+        *attempt += 1;
+
+        None
+    }
+}
+
+/// Returns true if a DB call that failed with `err` can be run again
+///
+/// Calls that could not get a connection never reached the DB, and
+/// transactions that failed to serialize or deadlocked were rolled back, so
+/// these are always retried. When a connection is lost while a call is in
+/// flight, it is unknown whether a write was applied, so only `idempotent`
+/// calls are retried then.
+pub fn is_transient(err: &Error, idempotent: bool) -> bool {
+    let err = match err {
+        Error::Database(e) => e,
+        _ => return false,
+    };
+
+    match err {
+        db::Error::ConnectionFailed(_) => idempotent || err.is_pool_timeout(),
+        db::Error::QueryFailed(_) => err.is_rolled_back(),
+        db::Error::NotFound | db::Error::Constraint(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transient_errors() {
+        let reset = || {
+            Error::from(sqlx::Error::Io(std::io::Error::from(
+                std::io::ErrorKind::ConnectionReset,
+            )))
+        };
+
+        assert!(is_transient(&reset(), true));
+        assert!(!is_transient(&reset(), false));
+
+        assert!(!is_transient(&Error::from(sqlx::Error::NotFound), true));
+        assert!(!is_transient(
+            &Error::Database(crate::db::Error::QueryFailed("no source".into())),
+            true
+        ));
+        assert!(!is_transient(&Error::NotFound, true));
+
+        // Only the variant matters, not where the error came from
+        let lost = || Error::Database(crate::db::Error::ConnectionFailed("lost".into()));
+        assert!(is_transient(&lost(), true));
+        assert!(!is_transient(&lost(), false));
+        assert!(!is_transient(
+            &Error::Database(crate::db::Error::Constraint("duplicate".into())),
+            true
+        ));
+    }
+
+    #[tokio::test]
+    async fn retry_until_max_attempts() {
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1),
+        };
+        let reset = || {
+            Err::<(), _>(Error::from(sqlx::Error::Io(std::io::Error::from(
+                std::io::ErrorKind::ConnectionReset,
+            ))))
+        };
+
+        let mut attempt = 1;
+        assert!(policy.check(reset(), &mut attempt, true).await.is_none());
+        assert_eq!(attempt, 2);
+        assert!(policy.check(reset(), &mut attempt, true).await.is_some());
+
+        let mut attempt = 1;
+        assert!(policy.check(Ok(()), &mut attempt, true).await.is_some());
+        assert!(policy.check(reset(), &mut attempt, false).await.is_some());
+    }
+}
@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+
+use crate::email::Email;
+
+/// Directives start with this character (e.g., "!folder Receipts")
+pub const PREFIX: char = '!';
+
+/// Per-email control directives, sent by the address owner in the subject or
+/// body of an email
+///
+/// In the subject, a directive is a word starting with `!`, followed by its
+/// argument, if it takes one (e.g., "Invoice #42 !folder Receipts/2024").
+/// In the plaintext body, each directive is on its own line, before any
+/// other text. Words that are not known directives are left as they are.
+///
+/// Supported directives:
+///
+/// * `!folder <path>`: store the email in this subfolder of the address
+///   storage path
+/// * `!notify on|off`: whether to send webhook notifications about the email
+/// * `!zip`: bundle the attachments of the email into a single zip archive,
+///   even if the address does not bundle attachments
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Directives {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notify: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zip: Option<bool>,
+}
+
+/// Directives that take no argument
+const FLAGS: &[&str] = &["zip"];
+
+/// Name of the directive in a word, if it is one
+fn directive_name(word: &str) -> Option<&str> {
+    if word.starts_with(PREFIX) {
+        Some(&word[PREFIX.len_utf8()..])
+    } else {
+        None
+    }
+}
+
+impl Directives {
+    /// Parse the directives of an email
+    ///
+    /// Returns the directives along with the subject and body with the
+    /// directives removed, or `None` if the email has no directives.
+    pub fn parse(email: &Email) -> Option<(Self, Option<String>, String)> {
+        let mut directives = Self::default();
+        let mut found = false;
+
+        let subject = email.subject.as_ref().map(|subject| {
+            let mut words = subject.split_whitespace().peekable();
+            let mut kept = Vec::new();
+
+            while let Some(word) = words.next() {
+                match directive_name(word) {
+                    Some(name) if FLAGS.contains(&name) && directives.apply(name, "") => {
+                        found = true;
+                    }
+                    Some(name) if directives.apply(name, words.peek().copied().unwrap_or("")) => {
+                        // Skip the argument
+                        words.next();
+                        found = true;
+                    }
+                    _ => kept.push(word),
+                }
+            }
+
+            if found {
+                kept.join(" ")
+            } else {
+                subject.clone()
+            }
+        });
+
+        // Directives at the top of the body, one per line
+        let mut lines = email.body.lines().peekable();
+        let mut num_directives = 0;
+
+        while let Some(line) = lines.peek() {
+            let line = line.trim();
+
+            if line.is_empty() && num_directives == 0 {
+                lines.next();
+                continue;
+            }
+
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let name = directive_name(parts.next().unwrap_or(""));
+            let arg = parts.next().unwrap_or("").trim();
+
+            match name {
+                Some(name) if FLAGS.contains(&name) && !arg.is_empty() => break,
+                Some(name) if directives.apply(name, arg) => (),
+                _ => break,
+            }
+
+            lines.next();
+            num_directives += 1;
+        }
+
+        if !found && num_directives == 0 {
+            return None;
+        }
+
+        let body = if num_directives > 0 {
+            lines
+                .collect::<Vec<_>>()
+                .join("\n")
+                .trim_start()
+                .to_string()
+        } else {
+            email.body.clone()
+        };
+
+        Some((directives, subject, body))
+    }
+
+    /// Apply a single directive
+    ///
+    /// Returns false if the directive is not known, or its argument is not
+    /// valid.
+    fn apply(&mut self, name: &str, arg: &str) -> bool {
+        match (name, arg.to_lowercase().as_str()) {
+            ("folder", "") => false,
+            ("folder", _) => {
+                self.folder = Some(arg.to_string());
+                true
+            }
+            ("notify", "on") => {
+                self.notify = Some(true);
+                true
+            }
+            ("notify", "off") => {
+                self.notify = Some(false);
+                true
+            }
+            ("zip", "") => {
+                self.zip = Some(true);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(subject: &str, body: &str) -> Email {
+        Email {
+            subject: Some(subject.to_string()),
+            body: body.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_directives() {
+        assert_eq!(Directives::parse(&email("Invoice #42!", "Hi!")), None);
+
+        let (directives, subject, body) = Directives::parse(&email(
+            "Invoice #42 !folder Receipts/2024 !notify off",
+            "Hi!",
+        ))
+        .unwrap();
+        assert_eq!(directives.folder.as_deref(), Some("Receipts/2024"));
+        assert_eq!(directives.notify, Some(false));
+        assert_eq!(subject.as_deref(), Some("Invoice #42"));
+        assert_eq!(body, "Hi!");
+
+        let (directives, subject, body) = Directives::parse(&email(
+            "Invoice #42",
+            "\n!folder Tax Returns\r\n!notify OFF\n\nHi!\n!notify on",
+        ))
+        .unwrap();
+        assert_eq!(directives.folder.as_deref(), Some("Tax Returns"));
+        assert_eq!(directives.notify, Some(false));
+        assert_eq!(subject.as_deref(), Some("Invoice #42"));
+        assert_eq!(body, "Hi!\n!notify on");
+
+        // Flags take no argument
+        let (directives, subject, body) =
+            Directives::parse(&email("!zip Invoice #42", "!zip\nHi!")).unwrap();
+        assert_eq!(directives.zip, Some(true));
+        assert_eq!(subject.as_deref(), Some("Invoice #42"));
+        assert_eq!(body, "Hi!");
+
+        // Unknown directives and invalid arguments are ignored
+        assert_eq!(
+            Directives::parse(&email("!archive !notify maybe", "!zip it\n!folder\nHi!")),
+            None
+        );
+    }
+}
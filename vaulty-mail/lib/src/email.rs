@@ -1,12 +1,123 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
-// Unique UUID namespace (URL + vaulty.net)
-const UUID_NAMESPACE: &str = "11d00b11-d9d0-5831-a6f7-8f88f86f870a";
+use crate::id::{IdGenerator, NamespaceIds};
+
+/// Max length of a persisted subject, in characters
+pub const MAX_SUBJECT_LEN: usize = 255;
+
+/// Max length of an email address (RFC 5321), in characters
+pub const MAX_ADDRESS_LEN: usize = 254;
+
+/// Header added to all mail sent by Vaulty, to detect mail looping back
+pub const LOOP_HEADER: &str = "X-Vaulty-Loop";
+
+/// Version of the `Email` and `Attachment` wire format, sent by the filter
+/// to the server
+///
+/// The filter and server can run different versions during a rolling
+/// upgrade, so payloads must stay readable in both directions:
+///
+/// * New fields default when missing, and optional ones are left out when
+///   unset
+/// * Unknown fields are ignored, so never use `deny_unknown_fields`
+/// * Renamed fields keep their old name as a serde alias
+///
+/// Bump the version, and add a golden payload under `test/fixtures/email`,
+/// whenever fields are added, renamed, or change meaning.
+///
+/// 0. Unversioned payloads
+/// 1. Adds `version`, `importance`, and `priority`
+/// 2. Adds `is_test`
+/// 3. Adds `directives`
+/// 4. Adds `received_time`
+pub const SCHEMA_VERSION: u32 = 4;
+
+/// Why an email looks auto-generated
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoGenerated {
+    /// Marked as auto-submitted by its sender (e.g., an out of office reply
+    /// or bulk mail)
+    AutoSubmitted,
+    /// Sent by Vaulty itself
+    Loop,
+}
+
+impl AutoGenerated {
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::AutoSubmitted => "auto-submitted",
+            Self::Loop => "sent by Vaulty",
+        }
+    }
+}
+
+/// Importance set by the sender, from the Importance or X-Priority header
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Importance {
+    High,
+    Normal,
+    Low,
+}
+
+impl Importance {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::High => "high",
+            Self::Normal => "normal",
+            Self::Low => "low",
+        }
+    }
+
+    /// Parse an Importance header value (e.g., "high")
+    fn from_importance(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "high" => Some(Self::High),
+            "normal" => Some(Self::Normal),
+            "low" => Some(Self::Low),
+            _ => None,
+        }
+    }
+
+    /// Parse an X-Priority header value (e.g., "1 (Highest)")
+    fn from_x_priority(value: &str) -> Option<Self> {
+        match value.trim().chars().next()? {
+            '1' | '2' => Some(Self::High),
+            '3' => Some(Self::Normal),
+            '4' | '5' => Some(Self::Low),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Importance {
+    fn from(s: &str) -> Self {
+        Self::from_importance(s).unwrap_or_else(|| {
+            log::error!("Unknown importance: {}", s);
+            Self::Normal
+        })
+    }
+}
+
+impl From<String> for Importance {
+    fn from(s: String) -> Self {
+        s.as_str().into()
+    }
+}
 
 /// Represents a single parsed MIME email.
-#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Email {
+    /// Wire format version of the sender of this email (see
+    /// `SCHEMA_VERSION`); 0 if unversioned
+    #[serde(default)]
+    pub version: u32,
+
     /// Email metadata
     pub sender: String,
     pub recipients: Vec<String>,
@@ -39,6 +150,75 @@ pub struct Email {
 
     /// Message-ID for this email, if found
     pub message_id: Option<String>,
+
+    /// Set if the headers mark this email as auto-generated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_generated: Option<AutoGenerated>,
+
+    /// Importance set by the sender, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub importance: Option<Importance>,
+
+    /// Set by the server if the email matched a priority rule of its
+    /// address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<crate::rules::Priority>,
+
+    /// Set for synthetic emails sent to test the storage of an address
+    /// (see `canary::test_email`)
+    ///
+    /// Only trusted for emails the server made itself: it is cleared for
+    /// emails from the filter.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub is_test: bool,
+
+    /// Set by the server if the attachments of the email were bundled into
+    /// a single zip archive, which is then its only attachment (see
+    /// `EmailHandler::bundle`)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub is_bundle: bool,
+
+    /// Set by the server if the sender is allowed to control how the email
+    /// is handled, and the email has directives (see `directive`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directives: Option<crate::directive::Directives>,
+
+    /// Set by the server if the email was submitted over SMTP by a client
+    /// that logged in as this address, rather than received from another
+    /// mail server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub submitted_by: Option<String>,
+
+    /// Set by the server when the email is accepted, so that files stored
+    /// later on are dated by its receipt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub received_time: Option<DateTime<Utc>>,
+}
+
+impl Default for Email {
+    fn default() -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            sender: String::new(),
+            recipients: Vec::new(),
+            subject: None,
+            body: String::new(),
+            body_html: None,
+            size: 0,
+            num_attachments: 0,
+            attachments: None,
+            uuid: Uuid::nil(),
+            message_id: None,
+            auto_generated: None,
+            importance: None,
+            priority: None,
+            is_test: false,
+            is_bundle: false,
+            directives: None,
+            submitted_by: None,
+            received_time: None,
+        }
+    }
 }
 
 /// A single attachment.
@@ -52,6 +232,7 @@ pub enum Attachment {
 
 /// Represents the data for an email attachment.
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct AttachmentData {
     /// MIME type of attachment (e.g., text/plain)
     pub mime: String,
@@ -161,15 +342,70 @@ impl Email {
             if k == "Subject" {
                 self.subject = v;
             } else if k == "Message-ID" {
-                // Extract message ID, if available
-                self.message_id = v.map(|s| s.replace("<", "").replace(">", ""));
+                // Extract message ID, if available. mailparse leaves the CR
+                // of the line ending in the value.
+                self.message_id = v.map(|s| s.trim().replace("<", "").replace(">", ""));
+            }
+        }
+    }
+
+    /// Detect auto-generated email from its headers (RFC 3834)
+    fn parse_auto_generated(&mut self, part: &mailparse::ParsedMail) {
+        for header in part.headers.iter() {
+            let (key, value) = match (header.get_key(), header.get_value()) {
+                (Ok(k), Ok(v)) => (k.to_lowercase(), v.trim().to_lowercase()),
+                _ => continue,
+            };
+
+            if key == LOOP_HEADER.to_lowercase() {
+                // A loop takes precedence over any other marker
+                self.auto_generated = Some(AutoGenerated::Loop);
+                return;
+            }
+
+            let is_auto_submitted = match key.as_str() {
+                "auto-submitted" => value != "no",
+                "x-autoreply" | "x-autorespond" => true,
+                "precedence" => ["bulk", "junk", "auto_reply"].contains(&value.as_str()),
+                _ => false,
+            };
+
+            if is_auto_submitted {
+                self.auto_generated = Some(AutoGenerated::AutoSubmitted);
+            }
+        }
+    }
+
+    /// Detect the importance set by the sender
+    ///
+    /// The Importance header takes precedence over X-Priority.
+    fn parse_importance(&mut self, part: &mailparse::ParsedMail) {
+        let mut x_priority = None;
+
+        for header in part.headers.iter() {
+            let (key, value) = match (header.get_key(), header.get_value()) {
+                (Ok(k), Ok(v)) => (k.to_lowercase(), v),
+                _ => continue,
+            };
+
+            match key.as_str() {
+                "importance" => {
+                    if let Some(importance) = Importance::from_importance(&value) {
+                        self.importance = Some(importance);
+                        return;
+                    }
+                }
+                "x-priority" => x_priority = Importance::from_x_priority(&value),
+                _ => (),
             }
         }
+
+        self.importance = x_priority;
     }
 
-    /// Generates a deterministic UUID for this email based on metadata.
-    /// The idea is that the UUID should be the same for the same email.
-    fn generate_uuid(&self) -> Uuid {
+    /// Generates a UUID for this email based on metadata.
+    /// With the default generator, the UUID is the same for the same email.
+    fn generate_uuid(&self, ids: &dyn IdGenerator) -> Uuid {
         let mut buf = Vec::new();
 
         if let Some(message_id) = &self.message_id {
@@ -186,13 +422,20 @@ impl Email {
             buf.extend(r.as_bytes());
         }
 
-        let uuid = Uuid::parse_str(UUID_NAMESPACE).unwrap();
-
-        Uuid::new_v5(&uuid, &buf)
+        ids.generate(&buf)
     }
 
     /// Convert a raw MIME email into structured format
     pub fn from_mime(mime_content: &[u8]) -> Result<Email, Box<dyn std::error::Error>> {
+        Self::from_mime_with_ids(mime_content, &NamespaceIds)
+    }
+
+    /// Convert a raw MIME email into structured format, using the given
+    /// generator to assign the email UUID
+    pub fn from_mime_with_ids(
+        mime_content: &[u8],
+        ids: &dyn IdGenerator,
+    ) -> Result<Email, Box<dyn std::error::Error>> {
         let parsed = mailparse::parse_mail(mime_content)?;
 
         let mut email = Email::new();
@@ -203,16 +446,39 @@ impl Email {
         // Parse mail headers
         // This will overwrite the UUID above if "Message-ID" is found
         email.parse_headers(&parsed);
+        email.parse_auto_generated(&parsed);
+        email.parse_importance(&parsed);
 
         // Parse body and attachments
         email.parse_recursive(&parsed)?;
 
-        // Assign a deterministic UUID to this email
-        email.uuid = email.generate_uuid();
+        // Assign a UUID to this email
+        email.uuid = email.generate_uuid(ids);
+
+        // Attachments were parsed before the UUID was known
+        if let Some(attachments) = &mut email.attachments {
+            for a in attachments.iter_mut() {
+                a.data_mut().email_id = email.uuid;
+            }
+        }
 
         Ok(email)
     }
 
+    /// Normalize the subject and sender before they are stored or echoed
+    /// back anywhere (DB, logs, responses, storage paths)
+    ///
+    /// Control characters are stripped and the subject is truncated. The
+    /// sender must be a single valid address; any display name is dropped.
+    pub fn normalize(&mut self) -> Result<(), crate::Error> {
+        self.subject = self.subject.as_ref().map(|s| sanitize(s, MAX_SUBJECT_LEN));
+
+        self.sender = parse_address(&self.sender)
+            .ok_or_else(|| crate::Error::InvalidSender(sanitize(&self.sender, MAX_ADDRESS_LEN)))?;
+
+        Ok(())
+    }
+
     pub fn with_sender(self, sender: String) -> Self {
         Self { sender, ..self }
     }
@@ -273,7 +539,19 @@ impl Attachment {
         // Build attachment struct
         d.mime = mimetype.to_string();
         d.charset = Some(charset.to_string());
-        d.name = content_type.params["name"].clone();
+        // Some clients (lettre included) only name the file in the
+        // Content-Disposition header
+        d.name = match content_type.params.get("name") {
+            Some(name) => name.clone(),
+            None => match part.get_content_disposition() {
+                Ok(disposition) => disposition
+                    .params
+                    .get("filename")
+                    .cloned()
+                    .unwrap_or_default(),
+                Err(_) => String::new(),
+            },
+        };
         d.data = match part.get_body_raw() {
             Ok(body) => body,
             Err(_) => {
@@ -398,12 +676,100 @@ impl AttachmentData {
     }
 }
 
+/// Strip control characters and surrounding whitespace, and truncate to
+/// `max_len` characters
+///
+/// Truncated strings end with an ellipsis.
+pub fn sanitize(s: &str, max_len: usize) -> String {
+    let s: String = s.chars().filter(|c| !c.is_control()).collect();
+    let s = s.trim();
+
+    if s.chars().count() <= max_len {
+        return s.to_string();
+    }
+
+    let mut truncated: String = s.chars().take(max_len.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Parse a single email address, dropping any display name
+///
+/// Returns `None` if the input is not exactly one valid address.
+pub fn parse_address(s: &str) -> Option<String> {
+    let s: String = s.chars().filter(|c| !c.is_control()).collect();
+    let addrs = mailparse::addrparse(&s).ok()?;
+
+    let mut addrs = addrs.iter();
+    let addr = match (addrs.next(), addrs.next()) {
+        (Some(mailparse::MailAddr::Single(info)), None) => info.addr.trim().to_string(),
+        _ => return None,
+    };
+
+    let mut parts = addr.rsplitn(2, '@');
+    let valid = match (parts.next(), parts.next()) {
+        (Some(domain), Some(local)) => {
+            !local.is_empty() && !domain.is_empty() && !addr.contains(char::is_whitespace)
+        }
+        _ => false,
+    };
+
+    if valid && addr.chars().count() <= MAX_ADDRESS_LEN {
+        Some(addr)
+    } else {
+        None
+    }
+}
+
+/// Hex-encoded SHA-256 of attachment content
+///
+/// Used to detect attachments that are identical to a previously stored
+/// version.
+pub fn content_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data).as_slice())
+}
+
+/// Computes `content_hash` of attachment content that is streamed in chunks
+#[derive(Clone, Default)]
+pub struct ContentHasher {
+    hasher: Sha256,
+
+    /// Number of bytes hashed so far
+    size: usize,
+}
+
+impl ContentHasher {
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.hasher.input(chunk);
+        self.size += chunk.len();
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn finish(self) -> String {
+        hex::encode(self.hasher.result().as_slice())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use std::fs::File;
     use std::io::Read;
 
+    /// Golden wire payloads, one per schema version
+    const WIRE_V0: &str = include_str!("../test/fixtures/email/v0.json");
+    const WIRE_V1: &str = include_str!("../test/fixtures/email/v1.json");
+    const WIRE_V2: &str = include_str!("../test/fixtures/email/v2.json");
+    const WIRE_V3: &str = include_str!("../test/fixtures/email/v3.json");
+    const WIRE_V4: &str = include_str!("../test/fixtures/email/v4.json");
+    const WIRE_ATTACHMENT_V0: &str = include_str!("../test/fixtures/email/attachment_v0.json");
+
+    /// Payload from a later version, with fields this version does not know
+    const WIRE_FUTURE: &str = include_str!("../test/fixtures/email/future.json");
+
     static SAMPLE_EMAIL_PATHS: &[&str] = &[
         // Content (multipart/alternative), Attachment, Attachment
         concat!(env!("CARGO_MANIFEST_DIR"), "/test", "/sample_email_1.txt"),
@@ -461,4 +827,173 @@ mod test {
 
         assert!(attachments[1].is_inline());
     }
+
+    #[test]
+    fn normalize_subject_and_sender() {
+        let mut mail = Email {
+            sender: "Jane Doe <jane@example.org>".to_string(),
+            subject: Some(format!("  Re:\r\n {}", "a".repeat(300))),
+            ..Default::default()
+        };
+
+        mail.normalize().unwrap();
+
+        let subject = mail.subject.unwrap();
+        assert_eq!(mail.sender, "jane@example.org");
+        assert_eq!(subject.chars().count(), MAX_SUBJECT_LEN);
+        assert!(subject.starts_with("Re: aaa"));
+        assert!(subject.ends_with('…'));
+
+        for sender in &["", "jane", "@example.org", "a@b.com, c@d.com"] {
+            let mut mail = Email {
+                sender: sender.to_string(),
+                ..Default::default()
+            };
+            assert!(mail.normalize().is_err(), "{}", sender);
+        }
+    }
+
+    #[test]
+    fn parse_auto_generated() {
+        let cases = [
+            (
+                "Auto-Submitted: auto-replied\r\n",
+                Some(AutoGenerated::AutoSubmitted),
+            ),
+            ("Auto-Submitted: no\r\n", None),
+            ("X-Autoreply: yes\r\n", Some(AutoGenerated::AutoSubmitted)),
+            ("Precedence: Bulk\r\n", Some(AutoGenerated::AutoSubmitted)),
+            ("Precedence: list\r\n", None),
+            (
+                "Precedence: bulk\r\nX-Vaulty-Loop: 1\r\n",
+                Some(AutoGenerated::Loop),
+            ),
+        ];
+
+        for (headers, expected) in cases.iter() {
+            let mime = format!("Subject: Out of office\r\n{}\r\nBack soon\r\n", headers);
+            let mail = Email::from_mime(mime.as_bytes()).unwrap();
+
+            assert_eq!(mail.auto_generated, *expected, "{}", headers);
+        }
+    }
+
+    #[test]
+    fn parse_importance() {
+        let cases = [
+            ("Importance: High\r\n", Some(Importance::High)),
+            ("X-Priority: 1 (Highest)\r\n", Some(Importance::High)),
+            ("X-Priority: 5\r\n", Some(Importance::Low)),
+            (
+                "X-Priority: 1\r\nImportance: normal\r\n",
+                Some(Importance::Normal),
+            ),
+            ("Importance: urgent\r\n", None),
+            ("", None),
+        ];
+
+        for (headers, expected) in cases.iter() {
+            let mime = format!("Subject: Invoice\r\n{}\r\nPlease pay\r\n", headers);
+            let mail = Email::from_mime(mime.as_bytes()).unwrap();
+
+            assert_eq!(mail.importance, *expected, "{}", headers);
+        }
+    }
+
+    #[test]
+    fn read_older_payloads() {
+        let mail: Email = serde_json::from_str(WIRE_V0).unwrap();
+
+        assert_eq!(mail.version, 0);
+        assert_eq!(mail.sender, "jane@example.org");
+        assert_eq!(mail.num_attachments, 2);
+        assert_eq!(mail.auto_generated, None);
+        assert_eq!(mail.importance, None);
+        assert!(mail.priority.is_none());
+
+        let mail: Email = serde_json::from_str(WIRE_V1).unwrap();
+
+        assert_eq!(mail.version, 1);
+        assert_eq!(mail.importance, Some(Importance::High));
+        assert!(!mail.is_test);
+
+        let mail: Email = serde_json::from_str(WIRE_V2).unwrap();
+
+        assert_eq!(mail.version, 2);
+        assert!(mail.is_test);
+        assert!(mail.directives.is_none());
+
+        let mail: Email = serde_json::from_str(WIRE_V3).unwrap();
+
+        assert_eq!(mail.version, 3);
+        assert!(mail.directives.is_some());
+        assert!(mail.received_time.is_none());
+
+        // Attachments written before `index` and `email_id` were added
+        let attachment: Attachment = serde_json::from_str(WIRE_ATTACHMENT_V0).unwrap();
+        assert!(attachment.is_regular());
+        assert_eq!(attachment.get_name(), "invoice.pdf");
+        assert_eq!(attachment.get_index(), 0);
+        assert!(attachment.get_email_id().is_nil());
+    }
+
+    #[test]
+    fn read_newer_payloads() {
+        let mail: Email = serde_json::from_str(WIRE_FUTURE).unwrap();
+
+        assert!(mail.version > SCHEMA_VERSION);
+        assert_eq!(mail.sender, "jane@example.org");
+        assert_eq!(mail.importance, Some(Importance::High));
+    }
+
+    #[test]
+    fn round_trip_current_payload() {
+        let golden: serde_json::Value = serde_json::from_str(WIRE_V4).unwrap();
+
+        let mail: Email = serde_json::from_value(golden.clone()).unwrap();
+        assert_eq!(mail.version, SCHEMA_VERSION);
+        assert_eq!(serde_json::to_value(&mail).unwrap(), golden);
+
+        // Unset optional fields are left out for older readers
+        let json = serde_json::to_value(Email::new()).unwrap();
+        assert_eq!(json["version"], SCHEMA_VERSION);
+        assert!(json.get("importance").is_none());
+        assert!(json.get("priority").is_none());
+        assert!(json.get("is_test").is_none());
+        assert!(json.get("directives").is_none());
+        assert!(json.get("received_time").is_none());
+    }
+
+    #[test]
+    fn parse_with_sequential_ids() {
+        let mut mail_file = File::open(SAMPLE_EMAIL_PATHS[0]).unwrap();
+        let mut mail_content = String::new();
+        mail_file.read_to_string(&mut mail_content).unwrap();
+
+        let ids = crate::id::SequentialIds::new(1);
+        let mail = Email::from_mime_with_ids(mail_content.as_bytes(), &ids).unwrap();
+
+        assert_eq!(
+            mail.uuid.to_string(),
+            "00000000-0000-0000-0000-000000000001".to_string()
+        );
+        assert_eq!(mail.attachments.unwrap()[0].get_email_id(), &mail.uuid);
+    }
+
+    #[test]
+    fn hash_in_chunks() {
+        let data = b"attachment content, in a few chunks";
+
+        let mut hasher = ContentHasher::default();
+        for chunk in data.chunks(8) {
+            hasher.update(chunk);
+        }
+
+        assert_eq!(hasher.size(), data.len());
+        assert_eq!(hasher.finish(), content_hash(data));
+        assert_eq!(
+            ContentHasher::default().finish(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
 }
@@ -1,35 +1,198 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::Arc;
+
+use super::db;
 use super::storage;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Message of an error, along with the error that caused it, if any
+///
+/// The source is kept so that it can be walked with `source()` when
+/// debugging. Only the message is serialized, as a plain string: errors sent
+/// to clients never carry their source, and errors received from the server
+/// have none.
+#[derive(Clone, Debug)]
+pub struct Cause {
+    message: String,
+    source: Option<Arc<dyn StdError + Send + Sync>>,
+}
+
+impl Cause {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Wrap an error, prefixing its message with some context (e.g.,
+    /// "Failed to fetch https://...: connection refused")
+    pub fn with_source(context: &str, source: impl StdError + Send + Sync + 'static) -> Self {
+        let message = if context.is_empty() {
+            source.to_string()
+        } else {
+            format!("{}: {}", context, source)
+        };
+
+        Self {
+            message,
+            source: Some(Arc::new(source)),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.source
+            .as_deref()
+            .map(|e| e as &(dyn StdError + 'static))
+    }
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<String> for Cause {
+    fn from(message: String) -> Self {
+        Self::new(message)
+    }
+}
+
+impl From<&str> for Cause {
+    fn from(message: &str) -> Self {
+        Self::new(message)
+    }
+}
+
+impl Serialize for Cause {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.message)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cause {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::new)
+    }
+}
 
 /// All possible Vaulty library errors
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Error {
+    /// A failure with no underlying error
     Generic(String),
-    Database(String),
+    /// A failure caused by another error (e.g., an HTTP or parse error)
+    Internal(Cause),
+    Database(db::Error),
     Storage(storage::Error),
     QuotaExceeded(String),
     TokenExpired,
     InvalidRecipient,
-    SenderNotWhitelisted { recipient: String },
+    InvalidSender(String),
+    SenderNotWhitelisted {
+        recipient: String,
+    },
+    /// The email is auto-generated, and the address rejects such email
+    AutoGenerated {
+        recipient: String,
+    },
+    /// The sender has sent too many emails to this address recently
+    RateLimited {
+        sender: String,
+        recipient: String,
+    },
     Unauthorized,
+    /// The request is authenticated, but its signature is invalid
+    Forbidden(String),
     NotFound,
+    /// No email with this ID is being processed
+    EmailNotFound(String),
     MissingHeader(String),
+    /// The request parameters are invalid
+    InvalidRequest(String),
+    /// The request body is larger than the server accepts
+    PayloadTooLarge(String),
+    /// The request has no Content-Length, so its size cannot be checked
+    LengthRequired,
+    /// The request failed but can be retried later
+    Temporary(String),
+    /// Ingest is paused for maintenance; the request can be retried later
+    Maintenance,
+}
+
+impl Error {
+    /// Wrap an error that caused a failure, with some context (e.g.,
+    /// "Failed to send email")
+    pub fn internal(context: &str, source: impl StdError + Send + Sync + 'static) -> Self {
+        Self::Internal(Cause::with_source(context, source))
+    }
+
+    /// Machine-readable code of this error, sent to clients along with the
+    /// message (see `api::ErrorResponse`)
+    ///
+    /// Codes are stable, unlike messages, which may be reworded or localized.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Generic(_) | Error::Internal(_) => "internal_error",
+            Error::Database(db::Error::NotFound) => "not_found",
+            Error::Database(db::Error::ConnectionFailed(_)) => "database_unavailable",
+            Error::Database(db::Error::Constraint(_)) => "conflict",
+            Error::Database(db::Error::QueryFailed(_)) => "database_error",
+            Error::Storage(_) => "storage_error",
+            Error::QuotaExceeded(_) => "quota_exceeded",
+            Error::TokenExpired => "token_expired",
+            Error::InvalidRecipient => "invalid_recipient",
+            Error::InvalidSender(_) => "invalid_sender",
+            Error::SenderNotWhitelisted { .. } => "sender_not_whitelisted",
+            Error::AutoGenerated { .. } => "auto_generated",
+            Error::RateLimited { .. } => "rate_limited",
+            Error::Unauthorized => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::NotFound => "not_found",
+            Error::EmailNotFound(_) => "email_not_found",
+            Error::MissingHeader(_) => "missing_header",
+            Error::InvalidRequest(_) => "invalid_request",
+            Error::PayloadTooLarge(_) => "payload_too_large",
+            Error::LengthRequired => "length_required",
+            Error::Temporary(_) => "temporary",
+            Error::Maintenance => "maintenance",
+        }
+    }
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Generic(ref msg) => write!(f, "{}", msg),
-            Error::Database(ref msg) => write!(f, "{}", msg),
-            Error::Storage(ref e) => write!(f, "Storage error: {}", e.to_string()),
+            Error::Internal(ref cause) => write!(f, "{}", cause),
+            Error::Database(ref e) => write!(f, "{}", e),
+            Error::Storage(ref e) => write!(f, "{}", e),
             Error::QuotaExceeded(ref msg) => write!(f, "{}", msg),
             Error::TokenExpired => write!(f, "The storage account token has expired for this Vaulty address. Please login to Vaulty to refresh the token."),
             Error::InvalidRecipient => write!(f, "None of the recipients of this email are valid Vaulty addresses."),
+            Error::InvalidSender(ref sender) => write!(f, "The sender address of this email is invalid: {}", sender),
             Error::SenderNotWhitelisted { ref recipient } =>
                 write!(f, "The sender of this email is not on the whitelist for address {}.", recipient),
+            Error::AutoGenerated { ref recipient } =>
+                write!(f, "Address {} does not accept auto-generated email.", recipient),
+            Error::RateLimited { ref sender, ref recipient } =>
+                write!(f, "Too many emails have been sent from {} to {} recently. Please try again later.", sender, recipient),
             Error::Unauthorized => write!(f, "Access to this endpoint is not authorized."),
+            Error::Forbidden(ref msg) => write!(f, "{}", msg),
             Error::NotFound => write!(f, "No such endpoint exists."),
+            Error::EmailNotFound(ref id) => write!(f, "No email with ID {} is being processed.", id),
+            Error::Temporary(ref msg) => write!(f, "{}", msg),
+            Error::InvalidRequest(ref msg) => write!(f, "{}", msg),
+            Error::PayloadTooLarge(ref msg) => write!(f, "{}", msg),
+            Error::LengthRequired => write!(f, "The request must have a Content-Length header."),
+            Error::Maintenance => write!(f, "Vaulty is undergoing maintenance. Mail will be accepted again shortly."),
             Error::MissingHeader(ref msg) => {
                 if msg == "Authorization" {
                     write!(f, "This endpoint requires HTTP authorization.")
@@ -41,7 +204,16 @@ impl std::fmt::Display for Error {
     }
 }
 
-impl std::error::Error for Error {}
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Internal(cause) => cause.source(),
+            Error::Database(e) => Some(e),
+            Error::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<storage::Error> for Error {
     fn from(err: storage::Error) -> Self {
@@ -52,8 +224,85 @@ impl From<storage::Error> for Error {
     }
 }
 
+impl From<db::Error> for Error {
+    fn from(err: db::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
 impl From<sqlx::Error> for Error {
     fn from(err: sqlx::Error) -> Self {
-        Self::Database(err.to_string())
+        Self::Database(err.into())
+    }
+}
+
+/// Render an error along with each error in its source chain (e.g.,
+/// "Storage request failed: ... (caused by: ...)"), for logging
+///
+/// Sources whose message is already part of the message of the error they
+/// caused are skipped.
+pub fn report(err: &dyn StdError) -> String {
+    let mut out = err.to_string();
+    let mut source = err.source();
+
+    while let Some(e) = source {
+        let msg = e.to_string();
+        if !out.contains(&msg) {
+            out.push_str(&format!(" (caused by: {})", msg));
+        }
+        source = e.source();
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_chain() {
+        let io = std::io::Error::new(std::io::ErrorKind::Other, "connection reset");
+        let err = Error::internal("Failed to fetch https://example.com", io);
+
+        assert_eq!(
+            err.to_string(),
+            "Failed to fetch https://example.com: connection reset"
+        );
+        assert_eq!(err.source().unwrap().to_string(), "connection reset");
+        assert_eq!(report(&err), err.to_string());
+
+        // Storage errors keep the error they were built from
+        let json = serde_json::from_str::<u32>("nope").unwrap_err();
+        let err = Error::from(storage::Error::from(json));
+        let storage = err.source().unwrap();
+        assert!(storage.source().is_some());
+        assert!(err
+            .to_string()
+            .starts_with("Invalid response from storage: "));
+
+        // Sources are not sent to clients
+        let sent = serde_json::to_string(&Error::internal("Failed", err)).unwrap();
+        let received: Error = serde_json::from_str(&sent).unwrap();
+        assert!(received.to_string().starts_with("Failed: Invalid response"));
+        assert!(received.source().is_none());
+    }
+
+    #[test]
+    fn codes() {
+        assert_eq!(Error::InvalidRecipient.code(), "invalid_recipient");
+        assert_eq!(
+            Error::from(storage::Error::RequestTimeout).code(),
+            "storage_error"
+        );
+        assert_eq!(
+            Error::from(storage::Error::TokenExpired("expired".to_string())).code(),
+            "token_expired"
+        );
+        assert_eq!(
+            Error::from(storage::Error::AccessDenied("denied".to_string())).code(),
+            "storage_error"
+        );
+        assert_eq!(Error::from(sqlx::Error::NotFound).code(), "not_found");
     }
 }
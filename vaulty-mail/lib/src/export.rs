@@ -0,0 +1,414 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::Error;
+
+/// Number of hex characters kept from each pseudonym
+const PSEUDONYM_LEN: usize = 16;
+
+/// Leading characters that make spreadsheets treat a CSV field as a formula
+const CSV_FORMULA_CHARS: &[char] = &['=', '+', '-', '@', '\t', '\r'];
+
+/// Table to export
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Table {
+    /// One row per email
+    Emails,
+    /// One row per attachment
+    Attachments,
+}
+
+impl Table {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Emails => "emails",
+            Self::Attachments => "attachments",
+        }
+    }
+
+    /// Columns exported by default
+    pub fn columns(self) -> &'static [Column] {
+        match self {
+            Self::Emails => &[
+                Column::MailId,
+                Column::Address,
+                Column::MessageId,
+                Column::NumAttachments,
+                Column::TotalSize,
+                Column::Status,
+                Column::ErrorMsg,
+                Column::CreationTime,
+            ],
+            Self::Attachments => &[
+                Column::MailId,
+                Column::Address,
+                Column::Index,
+                Column::Name,
+                Column::Size,
+                Column::IsDuplicate,
+                Column::Status,
+                Column::ErrorMsg,
+                Column::CreationTime,
+            ],
+        }
+    }
+}
+
+/// Output format of an export
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "csv" => Ok(Self::Csv),
+            "jsonl" => Ok(Self::Jsonl),
+            _ => Err(Error::InvalidRequest(format!(
+                "Unsupported export format: {} (expected csv or jsonl)",
+                name
+            ))),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Jsonl => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Jsonl => "jsonl",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Column {
+    MailId,
+    Address,
+    MessageId,
+    NumAttachments,
+    TotalSize,
+    Index,
+    Name,
+    Size,
+    IsDuplicate,
+    Status,
+    ErrorMsg,
+    CreationTime,
+}
+
+impl Column {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::MailId => "mail_id",
+            Self::Address => "address",
+            Self::MessageId => "message_id",
+            Self::NumAttachments => "num_attachments",
+            Self::TotalSize => "total_size",
+            Self::Index => "index",
+            Self::Name => "name",
+            Self::Size => "size",
+            Self::IsDuplicate => "is_duplicate",
+            Self::Status => "status",
+            Self::ErrorMsg => "error_msg",
+            Self::CreationTime => "creation_time",
+        }
+    }
+
+    /// Parse a comma-separated list of columns for a table
+    ///
+    /// An empty list selects the default columns of the table.
+    pub fn parse_list(s: &str, table: Table) -> Result<Vec<Self>, Error> {
+        let names: Vec<&str> = s
+            .split(',')
+            .map(|c| c.trim())
+            .filter(|c| !c.is_empty())
+            .collect();
+
+        if names.is_empty() {
+            return Ok(table.columns().to_vec());
+        }
+
+        names
+            .into_iter()
+            .map(|name| {
+                table
+                    .columns()
+                    .iter()
+                    .copied()
+                    .find(|c| c.name() == name)
+                    .ok_or_else(|| {
+                        Error::InvalidRequest(format!(
+                            "Unknown column for {}: {}",
+                            table.name(),
+                            name
+                        ))
+                    })
+            })
+            .collect()
+    }
+}
+
+/// A single exported email or attachment
+///
+/// Attachment fields are only set for attachment exports, and vice versa.
+#[derive(Clone, Debug)]
+pub struct Row {
+    pub mail_id: Uuid,
+    pub address: String,
+    pub message_id: Option<String>,
+    pub num_attachments: Option<i32>,
+    pub total_size: Option<i32>,
+    pub index: Option<i32>,
+    pub name: Option<String>,
+    pub size: Option<i32>,
+    pub is_duplicate: Option<bool>,
+    pub status: bool,
+    pub error_msg: Option<String>,
+    pub creation_time: DateTime<Utc>,
+}
+
+/// Position of the last exported row, used to fetch the next batch
+#[derive(Clone, Debug)]
+pub struct Cursor {
+    pub creation_time: DateTime<Utc>,
+    pub id: String,
+}
+
+/// Encodes rows of an export, one line at a time
+pub struct Exporter {
+    format: Format,
+    columns: Vec<Column>,
+    anonymization_key: Option<Vec<u8>>,
+}
+
+impl Exporter {
+    pub fn new(format: Format, columns: Vec<Column>) -> Self {
+        Self {
+            format,
+            columns,
+            anonymization_key: None,
+        }
+    }
+
+    /// Replace addresses, Message-IDs, and attachment names with keyed
+    /// pseudonyms, and drop error messages
+    ///
+    /// Pseudonyms are stable for a given key, so rows can still be grouped
+    /// by address. The address domain and attachment extension are kept.
+    pub fn with_anonymization(self, key: &[u8]) -> Self {
+        Self {
+            anonymization_key: Some(key.to_vec()),
+            ..self
+        }
+    }
+
+    /// CSV header line, if the format has one
+    pub fn header(&self) -> Option<String> {
+        match self.format {
+            Format::Csv => {
+                let names: Vec<&str> = self.columns.iter().map(|c| c.name()).collect();
+                Some(names.join(",") + "\n")
+            }
+            Format::Jsonl => None,
+        }
+    }
+
+    /// Encode a single row, including the trailing newline
+    pub fn encode(&self, row: &Row) -> String {
+        let values = self.columns.iter().map(|c| (c, self.value(row, *c)));
+
+        match self.format {
+            Format::Csv => {
+                let fields: Vec<String> = values.map(|(_, v)| csv_field(&v)).collect();
+                fields.join(",") + "\n"
+            }
+            Format::Jsonl => {
+                let object: serde_json::Map<String, serde_json::Value> =
+                    values.map(|(c, v)| (c.name().to_string(), v)).collect();
+                serde_json::Value::Object(object).to_string() + "\n"
+            }
+        }
+    }
+
+    fn value(&self, row: &Row, column: Column) -> serde_json::Value {
+        use serde_json::json;
+
+        match column {
+            Column::MailId => json!(row.mail_id.to_string()),
+            Column::Address => json!(self.anonymize_address(&row.address)),
+            Column::MessageId => json!(row.message_id.as_ref().map(|m| self.anonymize(m))),
+            Column::NumAttachments => json!(row.num_attachments),
+            Column::TotalSize => json!(row.total_size),
+            Column::Index => json!(row.index),
+            Column::Name => json!(row.name.as_ref().map(|n| self.anonymize_name(n))),
+            Column::Size => json!(row.size),
+            Column::IsDuplicate => json!(row.is_duplicate),
+            Column::Status => json!(row.status),
+            Column::ErrorMsg => {
+                // Error messages may contain addresses or names
+                json!(row
+                    .error_msg
+                    .as_ref()
+                    .filter(|_| self.anonymization_key.is_none()))
+            }
+            Column::CreationTime => json!(row.creation_time.to_rfc3339()),
+        }
+    }
+
+    fn anonymize(&self, s: &str) -> String {
+        let key = match &self.anonymization_key {
+            Some(key) => key,
+            None => return s.to_string(),
+        };
+
+        let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any size");
+        mac.input(s.as_bytes());
+
+        let mut pseudonym = hex::encode(mac.result().code().as_slice());
+        pseudonym.truncate(PSEUDONYM_LEN);
+        pseudonym
+    }
+
+    fn anonymize_address(&self, address: &str) -> String {
+        match address.rfind('@') {
+            Some(i) => format!("{}{}", self.anonymize(&address[..i]), &address[i..]),
+            None => self.anonymize(address),
+        }
+    }
+
+    fn anonymize_name(&self, name: &str) -> String {
+        match name.rfind('.').filter(|i| *i > 0) {
+            Some(i) => format!("{}{}", self.anonymize(&name[..i]), &name[i..]),
+            None => self.anonymize(name),
+        }
+    }
+}
+
+/// Format a value as a CSV field, quoting it if needed (RFC 4180)
+///
+/// Strings that spreadsheets would run as a formula (e.g., an attachment
+/// named "=HYPERLINK(...)") are prefixed with a single quote, so that they
+/// are shown as text instead.
+fn csv_field(value: &serde_json::Value) -> String {
+    let s = match value {
+        serde_json::Value::Null => return String::new(),
+        serde_json::Value::String(s) if s.starts_with(CSV_FORMULA_CHARS) => format!("'{}", s),
+        serde_json::Value::String(s) => s.clone(),
+        v => v.to_string(),
+    };
+
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row() -> Row {
+        Row {
+            mail_id: Uuid::parse_str("1a2b3c4d-d9d0-5831-a6f7-8f88f86f870a").unwrap(),
+            address: "invoices@vaulty.net".to_string(),
+            message_id: None,
+            num_attachments: None,
+            total_size: None,
+            index: Some(0),
+            name: Some("Q1, \"final\".pdf".to_string()),
+            size: Some(1024),
+            is_duplicate: Some(false),
+            status: false,
+            error_msg: Some("Storage error for invoices@vaulty.net".to_string()),
+            creation_time: "2020-02-09T19:38:12Z".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn csv() {
+        let columns =
+            Column::parse_list("mail_id, name,size,error_msg", Table::Attachments).unwrap();
+        let exporter = Exporter::new(Format::Csv, columns);
+
+        assert_eq!(exporter.header().unwrap(), "mail_id,name,size,error_msg\n");
+        assert_eq!(
+            exporter.encode(&row()),
+            "1a2b3c4d-d9d0-5831-a6f7-8f88f86f870a,\"Q1, \"\"final\"\".pdf\",1024,\
+             Storage error for invoices@vaulty.net\n"
+        );
+    }
+
+    #[test]
+    fn csv_formulas() {
+        let field = |s: &str| csv_field(&serde_json::Value::from(s));
+
+        assert_eq!(field("=1+2.pdf"), "'=1+2.pdf");
+        assert_eq!(field("+1.pdf"), "'+1.pdf");
+        assert_eq!(field("-1.pdf"), "'-1.pdf");
+        assert_eq!(field("@SUM(A1).pdf"), "'@SUM(A1).pdf");
+        assert_eq!(field("=A1,B1"), "\"'=A1,B1\"");
+        assert_eq!(field("a=1.pdf"), "a=1.pdf");
+
+        // Numbers are not formulas
+        assert_eq!(csv_field(&serde_json::Value::from(-1)), "-1");
+    }
+
+    #[test]
+    fn jsonl() {
+        let exporter = Exporter::new(Format::Jsonl, Table::Emails.columns().to_vec());
+        let line = exporter.encode(&row());
+
+        assert!(exporter.header().is_none());
+        assert!(line.ends_with('\n'));
+
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["address"], "invoices@vaulty.net");
+        assert_eq!(value["status"], false);
+        assert_eq!(value["num_attachments"], serde_json::Value::Null);
+        assert_eq!(value["creation_time"], "2020-02-09T19:38:12+00:00");
+    }
+
+    #[test]
+    fn anonymized() {
+        let columns = Column::parse_list("address,name,error_msg", Table::Attachments).unwrap();
+        let exporter = Exporter::new(Format::Jsonl, columns).with_anonymization(b"secret");
+
+        let first: serde_json::Value = serde_json::from_str(&exporter.encode(&row())).unwrap();
+        let second: serde_json::Value = serde_json::from_str(&exporter.encode(&row())).unwrap();
+
+        let address = first["address"].as_str().unwrap();
+        assert!(address.ends_with("@vaulty.net"));
+        assert!(!address.contains("invoices"));
+        assert_eq!(address.len(), PSEUDONYM_LEN + "@vaulty.net".len());
+
+        assert!(first["name"].as_str().unwrap().ends_with(".pdf"));
+        assert_eq!(first["error_msg"], serde_json::Value::Null);
+
+        // Pseudonyms are stable
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn unknown_column_or_format() {
+        assert!(Column::parse_list("name", Table::Emails).is_err());
+        assert_eq!(
+            Column::parse_list("", Table::Emails).unwrap(),
+            Table::Emails.columns()
+        );
+        assert!(Format::from_name("parquet").is_err());
+    }
+}
@@ -0,0 +1,242 @@
+//! Fault injection for resilience testing
+//!
+//! With the `faults` feature enabled, DB calls, storage uploads, and cache
+//! operations can be made to randomly fail or slow down, so that retries and
+//! failure handling can be exercised in integration tests and staging.
+//! Without the feature, `inject` is a no-op.
+//!
+//! Faults are set per target in the config file, e.g.:
+//!
+//! ```toml
+//! fault_storage = "failure=0.1,delay=0.5,delay_ms=2000"
+//! ```
+
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::Error;
+
+/// Part of the pipeline a fault is injected into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Db,
+    Storage,
+    Cache,
+}
+
+impl Target {
+    pub const ALL: &'static [Target] = &[Target::Db, Target::Storage, Target::Cache];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::Db => "db",
+            Target::Storage => "storage",
+            Target::Cache => "cache",
+        }
+    }
+
+    #[cfg(feature = "faults")]
+    fn error(self) -> Error {
+        match self {
+            Target::Db => {
+                Error::Database(crate::db::Error::QueryFailed("Injected DB fault".into()))
+            }
+            Target::Storage => Error::Storage(crate::storage::Error::Internal(
+                "Injected storage fault".to_string(),
+            )),
+            Target::Cache => Error::Generic("Injected cache fault".to_string()),
+        }
+    }
+}
+
+/// Faults injected into a single target
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Fault {
+    /// Probability (0.0 - 1.0) that a call fails
+    pub failure_rate: f32,
+
+    /// Probability (0.0 - 1.0) that a call is delayed, and by how much
+    pub delay_rate: f32,
+    pub delay: Duration,
+}
+
+impl Fault {
+    /// Parse a fault spec, e.g., "failure=0.1,delay=0.5,delay_ms=2000"
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut fault = Self::default();
+
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let mut kv = part.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim();
+            let value = kv.next().unwrap_or("").trim();
+
+            let invalid = || format!("Invalid fault setting: {}", part);
+
+            match key {
+                "failure" => fault.failure_rate = rate(value).ok_or_else(invalid)?,
+                "delay" => fault.delay_rate = rate(value).ok_or_else(invalid)?,
+                "delay_ms" => {
+                    fault.delay = value
+                        .parse::<u64>()
+                        .map(Duration::from_millis)
+                        .map_err(|_| invalid())?
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(fault)
+    }
+}
+
+fn rate(value: &str) -> Option<f32> {
+    value.parse::<f32>().ok().filter(|r| *r >= 0.0 && *r <= 1.0)
+}
+
+fn spec(config: &Config, target: Target) -> Option<&str> {
+    match target {
+        Target::Db => config.fault_db.as_deref(),
+        Target::Storage => config.fault_storage.as_deref(),
+        Target::Cache => config.fault_cache.as_deref(),
+    }
+}
+
+#[cfg(feature = "faults")]
+mod state {
+    use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+    use std::time::Duration;
+
+    use super::{Fault, Target};
+
+    // One slot per target, indexed by `Target as usize`. Rates are stored as
+    // f32 bits.
+    static FAILURE_RATE: [AtomicU32; 3] = [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+    static DELAY_RATE: [AtomicU32; 3] = [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+    static DELAY_MS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+    pub fn get(target: Target) -> Fault {
+        let i = target as usize;
+
+        Fault {
+            failure_rate: f32::from_bits(FAILURE_RATE[i].load(Ordering::SeqCst)),
+            delay_rate: f32::from_bits(DELAY_RATE[i].load(Ordering::SeqCst)),
+            delay: Duration::from_millis(DELAY_MS[i].load(Ordering::SeqCst)),
+        }
+    }
+
+    pub fn set(target: Target, fault: Fault) {
+        let i = target as usize;
+
+        FAILURE_RATE[i].store(fault.failure_rate.to_bits(), Ordering::SeqCst);
+        DELAY_RATE[i].store(fault.delay_rate.to_bits(), Ordering::SeqCst);
+        DELAY_MS[i].store(fault.delay.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+/// Faults currently injected into a target
+#[cfg(feature = "faults")]
+pub fn get(target: Target) -> Fault {
+    state::get(target)
+}
+
+/// Inject faults into a target, replacing any set before
+#[cfg(feature = "faults")]
+pub fn set(target: Target, fault: Fault) {
+    if fault != Fault::default() {
+        log::warn!("Injecting faults into {}: {:?}", target.name(), fault);
+    }
+
+    state::set(target, fault);
+}
+
+#[cfg(not(feature = "faults"))]
+pub fn set(target: Target, _fault: Fault) {
+    log::error!(
+        "Faults set for {}, but fault injection is not built in",
+        target.name()
+    );
+}
+
+/// Stop injecting faults into all targets
+#[cfg(feature = "faults")]
+pub fn clear() {
+    for &target in Target::ALL {
+        state::set(target, Fault::default());
+    }
+}
+
+/// Set up the faults listed in config
+pub fn init(config: &Config) {
+    for &target in Target::ALL {
+        if let Some(spec) = spec(config, target) {
+            match Fault::parse(spec) {
+                Ok(fault) => set(target, fault),
+                Err(e) => log::error!("{}", e),
+            }
+        }
+    }
+}
+
+/// Randomly delay or fail a call to the given target
+///
+/// Call this before the real call. Delays are applied before failures, so a
+/// call can be both slow and failed.
+#[cfg(feature = "faults")]
+pub async fn inject(target: Target) -> Result<(), Error> {
+    let fault = get(target);
+
+    if fault.delay_rate > 0.0 && rand::random::<f32>() < fault.delay_rate {
+        tokio::time::delay_for(fault.delay).await;
+    }
+
+    if fault.failure_rate > 0.0 && rand::random::<f32>() < fault.failure_rate {
+        log::warn!("Injecting {} fault", target.name());
+        return Err(target.error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "faults"))]
+pub async fn inject(_target: Target) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_specs() {
+        assert_eq!(
+            Fault::parse("failure=0.1, delay=0.5,delay_ms=2000").unwrap(),
+            Fault {
+                failure_rate: 0.1,
+                delay_rate: 0.5,
+                delay: Duration::from_millis(2000),
+            }
+        );
+        assert_eq!(Fault::parse("").unwrap(), Fault::default());
+
+        assert!(Fault::parse("failure=2").is_err());
+        assert!(Fault::parse("delay_ms=-1").is_err());
+        assert!(Fault::parse("timeout=0.1").is_err());
+    }
+
+    #[cfg(feature = "faults")]
+    #[tokio::test]
+    async fn inject_faults() {
+        set(
+            Target::Cache,
+            Fault {
+                failure_rate: 1.0,
+                ..Default::default()
+            },
+        );
+        assert!(inject(Target::Cache).await.is_err());
+        assert!(inject(Target::Db).await.is_ok());
+
+        clear();
+        assert!(inject(Target::Cache).await.is_ok());
+    }
+}
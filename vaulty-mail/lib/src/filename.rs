@@ -0,0 +1,367 @@
+/// Max length of a normalized filename, in characters
+pub const MAX_FILENAME_LEN: usize = 255;
+
+/// Used in place of a filename that has nothing usable left
+const DEFAULT_STEM: &str = "attachment";
+
+/// Used in place of characters that cannot be transliterated
+const REPLACEMENT: char = '_';
+
+/// Normalize an attachment filename before it is stored
+///
+/// Any directory components and control characters are dropped, and the
+/// name is truncated to `MAX_FILENAME_LEN` characters, keeping its
+/// extension. If `transliterate` is set, non-ASCII characters are mapped to
+/// their closest ASCII equivalent (e.g., "отчёт.pdf" becomes "otchyot.pdf").
+/// Characters with no equivalent, such as CJK or emoji, are dropped.
+/// Otherwise, Unicode names are kept as-is.
+pub fn normalize(name: &str, transliterate: bool) -> String {
+    // Only keep the last path component
+    let name = name.rsplit(&['/', '\\'][..]).next().unwrap_or("");
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+
+    let name = if transliterate { to_ascii(&name) } else { name };
+
+    let (stem, ext) = split_extension(&name);
+
+    // Strip whatever was left around dropped characters
+    let trim = |c: char| c == REPLACEMENT || c.is_whitespace();
+    let stem = stem.trim_matches(trim);
+    let ext = ext.map(|e| e.trim_matches(trim)).filter(|e| !e.is_empty());
+
+    // Names made up of dots only (e.g., "..") are not usable either
+    let stem = if stem.chars().all(|c| c == '.') {
+        DEFAULT_STEM
+    } else {
+        stem
+    };
+
+    match ext {
+        Some(ext) => {
+            let ext_len = ext.chars().count() + 1;
+            let max_stem_len = MAX_FILENAME_LEN.saturating_sub(ext_len).max(1);
+            let stem: String = stem.chars().take(max_stem_len).collect();
+            let ext: String = ext.chars().take(MAX_FILENAME_LEN - 1).collect();
+            format!("{}.{}", stem, ext)
+        }
+        None => stem.chars().take(MAX_FILENAME_LEN).collect(),
+    }
+}
+
+/// Split a filename into its stem and extension, if any
+///
+/// Leading dots (e.g., ".bashrc") are part of the stem.
+fn split_extension(name: &str) -> (&str, Option<&str>) {
+    match name.rfind('.') {
+        Some(i) if name[..i].chars().any(|c| c != '.') => (&name[..i], Some(&name[i + 1..])),
+        _ => (name, None),
+    }
+}
+
+/// Transliterate a string to ASCII
+///
+/// Runs of characters with no ASCII equivalent are collapsed into a single
+/// replacement character.
+fn to_ascii(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        if c.is_ascii() {
+            out.push(c);
+            continue;
+        }
+
+        match transliterate_char(c) {
+            Some(t) => out.push_str(&t),
+            None => {
+                if !out.ends_with(REPLACEMENT) {
+                    out.push(REPLACEMENT);
+                }
+            }
+        }
+    }
+
+    out
+}
+
+/// ASCII equivalent of a single non-ASCII character, if there is one
+///
+/// Uppercase characters map to a capitalized equivalent (e.g., "Ж" becomes
+/// "Zh").
+fn transliterate_char(c: char) -> Option<String> {
+    let lower = c.to_lowercase().next()?;
+    let t = transliterate_lower(lower)?;
+
+    if lower == c {
+        return Some(t.to_string());
+    }
+
+    let mut chars = t.chars();
+    Some(match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+    })
+}
+
+fn transliterate_lower(c: char) -> Option<&'static str> {
+    let t = match c {
+        // Latin
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'č' => "c",
+        'ď' | 'đ' | 'ð' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ė' | 'ę' | 'ě' => "e",
+        'ğ' => "g",
+        'ì' | 'í' | 'î' | 'ï' | 'ī' | 'į' | 'ı' => "i",
+        'ł' | 'ľ' => "l",
+        'ñ' | 'ń' | 'ň' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ő' => "o",
+        'œ' => "oe",
+        'ř' => "r",
+        'ś' | 'š' | 'ş' => "s",
+        'ß' => "ss",
+        'ť' | 'ţ' => "t",
+        'þ' => "th",
+        'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' | 'ű' | 'ų' => "u",
+        'ý' | 'ÿ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+
+        // Cyrillic
+        'а' => "a",
+        'б' => "b",
+        'в' => "v",
+        'г' | 'ґ' => "g",
+        'д' => "d",
+        'е' => "e",
+        'ё' => "yo",
+        'є' => "ye",
+        'ж' => "zh",
+        'з' => "z",
+        'и' | 'і' => "i",
+        'ї' => "yi",
+        'й' => "y",
+        'к' => "k",
+        'л' => "l",
+        'м' => "m",
+        'н' => "n",
+        'о' => "o",
+        'п' => "p",
+        'р' => "r",
+        'с' => "s",
+        'т' => "t",
+        'у' => "u",
+        'ф' => "f",
+        'х' => "kh",
+        'ц' => "ts",
+        'ч' => "ch",
+        'ш' => "sh",
+        'щ' => "shch",
+        'ъ' | 'ь' => "",
+        'ы' => "y",
+        'э' => "e",
+        'ю' => "yu",
+        'я' => "ya",
+
+        // Punctuation
+        '‘' | '’' => "'",
+        '“' | '”' | '«' | '»' => "\"",
+        '–' | '—' => "-",
+        '…' => "...",
+        '\u{a0}' => " ",
+
+        _ => return None,
+    };
+
+    Some(t)
+}
+
+/// Variables that attachment and archive name templates can use
+pub const VARIABLES: &[&str] = &["date", "sender", "subject", "name", "email_id"];
+
+/// Template for the names attachments are stored under, relative to the
+/// email folder (e.g., "{date}/{sender}/{subject}-{name}")
+///
+/// Placeholders are replaced with the value of the variable of the same
+/// name; unknown placeholders are kept as-is. Path separators in values are
+/// replaced, so that only the template itself creates subfolders. Each path
+/// component is then normalized like a filename.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template(String);
+
+impl Template {
+    pub fn new(template: &str) -> Self {
+        Self(template.to_string())
+    }
+
+    /// Placeholders of the template that are not in `VARIABLES`, which
+    /// are kept as-is when rendering
+    pub fn unknown_variables(&self) -> Vec<&str> {
+        let mut unknown = Vec::new();
+        let mut rest = self.0.as_str();
+
+        while let Some(start) = rest.find('{') {
+            rest = &rest[start..];
+
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+
+            let key = &rest[1..end];
+            if !VARIABLES.contains(&key) && !unknown.contains(&key) {
+                unknown.push(key);
+            }
+
+            rest = &rest[end + 1..];
+        }
+
+        unknown
+    }
+
+    /// Render the template with the given variables
+    ///
+    /// Returns `None` if nothing usable is left, in which case the original
+    /// name should be used.
+    pub fn render<V: AsRef<str>>(&self, vars: &[(&str, V)]) -> Option<String> {
+        let mut out = String::with_capacity(self.0.len());
+        let mut rest = self.0.as_str();
+
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+
+            let key = &rest[1..end];
+            match vars.iter().find(|(k, _)| *k == key) {
+                Some((_, value)) => out.push_str(&value.as_ref().replace(&['/', '\\'][..], "-")),
+                None => out.push_str(&rest[..=end]),
+            }
+
+            rest = &rest[end + 1..];
+        }
+        out.push_str(rest);
+
+        let name = out
+            .split(&['/', '\\'][..])
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && *name != "." && *name != "..")
+            .map(|name| normalize(name, false))
+            .collect::<Vec<_>>()
+            .join("/");
+
+        Some(name).filter(|n| !n.is_empty())
+    }
+}
+
+/// Add a collision suffix to a name (e.g., "a/invoice.pdf" becomes
+/// "a/invoice (2).pdf")
+pub fn with_suffix(name: &str, n: usize) -> String {
+    let (dir, file) = match name.rfind('/') {
+        Some(i) => name.split_at(i + 1),
+        None => ("", name),
+    };
+
+    match split_extension(file) {
+        (stem, Some(ext)) => format!("{}{} ({}).{}", dir, stem, n, ext),
+        (stem, None) => format!("{}{} ({})", dir, stem, n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_template() {
+        let template = Template::new("{date}/{sender}/{subject}-{name}");
+        let vars = [
+            ("date", "2020-04-01"),
+            ("sender", "a@example.com"),
+            ("subject", "Invoice 1/2: ../April"),
+            ("name", "invoice.pdf"),
+        ];
+
+        assert_eq!(
+            template.render(&vars).unwrap(),
+            "2020-04-01/a@example.com/Invoice 1-2: ..-April-invoice.pdf"
+        );
+
+        // Unknown placeholders and unclosed braces are kept
+        let template = Template::new("{unknown} {name");
+        assert_eq!(template.render(&vars).unwrap(), "{unknown} {name");
+        assert_eq!(template.unknown_variables(), vec!["unknown"]);
+        assert!(Template::new("{date}/{email_id}-{name}")
+            .unknown_variables()
+            .is_empty());
+
+        // Empty and relative components are dropped
+        let template = Template::new("../{missing}//./{name}");
+        assert_eq!(template.render(&vars).unwrap(), "{missing}/invoice.pdf");
+        assert_eq!(Template::new("/./").render(&vars), None);
+
+        assert_eq!(with_suffix("a/invoice.pdf", 2), "a/invoice (2).pdf");
+        assert_eq!(with_suffix("README", 1), "README (1)");
+    }
+
+    #[test]
+    fn transliterate_cyrillic() {
+        assert_eq!(normalize("отчёт.pdf", true), "otchyot.pdf");
+        assert_eq!(normalize("Отчёт за май.docx", true), "Otchyot za may.docx");
+        assert_eq!(normalize("ЖУРНАЛ.txt", true), "ZhURNAL.txt");
+        assert_eq!(normalize("Объявление.png", true), "Obyavlenie.png");
+    }
+
+    #[test]
+    fn transliterate_latin() {
+        assert_eq!(normalize("Café Ürün.txt", true), "Cafe Urun.txt");
+        assert_eq!(
+            normalize("Straße – Übersicht.pdf", true),
+            "Strasse - Ubersicht.pdf"
+        );
+    }
+
+    #[test]
+    fn transliterate_cjk() {
+        assert_eq!(normalize("报告.pdf", true), "attachment.pdf");
+        assert_eq!(normalize("2020年報告.xlsx", true), "2020.xlsx");
+        assert_eq!(normalize("請求書 March.pdf", true), "March.pdf");
+    }
+
+    #[test]
+    fn transliterate_emoji() {
+        assert_eq!(normalize("🎉 party.png", true), "party.png");
+        assert_eq!(normalize("photo 📷.jpg", true), "photo.jpg");
+        assert_eq!(normalize("🎉🎉🎉", true), "attachment");
+        assert_eq!(normalize("a🎉🎉b.txt", true), "a_b.txt");
+    }
+
+    #[test]
+    fn keep_unicode() {
+        for name in &["отчёт.pdf", "报告.pdf", "🎉 party.png", "Café.txt"] {
+            assert_eq!(normalize(name, false), *name);
+        }
+    }
+
+    #[test]
+    fn strip_paths_and_control_chars() {
+        assert_eq!(normalize("../../etc/passwd", false), "passwd");
+        assert_eq!(normalize("C:\\Users\\me\\report.pdf", false), "report.pdf");
+        assert_eq!(normalize("a\u{0}b\r\n.txt", false), "ab.txt");
+        assert_eq!(normalize("", false), "attachment");
+        assert_eq!(normalize("..", false), "attachment");
+        assert_eq!(normalize(".bashrc", false), ".bashrc");
+    }
+
+    #[test]
+    fn truncate_keeps_extension() {
+        let name = format!("{}.pdf", "a".repeat(300));
+        let normalized = normalize(&name, false);
+
+        assert_eq!(normalized.chars().count(), MAX_FILENAME_LEN);
+        assert!(normalized.ends_with("a.pdf"));
+    }
+}
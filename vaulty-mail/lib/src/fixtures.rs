@@ -0,0 +1,157 @@
+//! Sanitized inbound webhook payloads from supported email service providers.
+//!
+//! These are used by Vaulty's own golden-file tests, and are exposed so that
+//! downstream users can exercise their parsing code against the same data.
+//!
+//! Only Mailgun is supported for now; fixtures for other providers should be
+//! added alongside their parsers.
+
+/// Mailgun "store and notify" webhook payloads
+pub mod mailgun {
+    /// Payload posted with `Content-Type: application/json`
+    pub const NOTIFY_JSON: &str = include_str!("../test/fixtures/mailgun/notify.json");
+
+    /// Payload posted with `Content-Type: application/x-www-form-urlencoded`
+    pub const NOTIFY_FORM: &str = include_str!("../test/fixtures/mailgun/notify.form");
+
+    /// Expected `email::Email` and `email::Attachment` structures for the
+    /// payloads above
+    ///
+    /// Regenerate it when fields are added to the structures.
+    pub const NOTIFY_GOLDEN: &str = include_str!("../test/fixtures/mailgun/notify.golden.json");
+
+    /// `store()` notification payloads, which only point to the stored message
+    pub const STORED_JSON: &str = include_str!("../test/fixtures/mailgun/stored.json");
+    pub const STORED_FORM: &str = include_str!("../test/fixtures/mailgun/stored.form");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email;
+
+    /// Compare parsed Mailgun email and attachments against the golden file
+    fn check_mailgun_golden(
+        mail: crate::mailgun::Email,
+        attachments: Vec<crate::mailgun::Attachment>,
+    ) {
+        let golden: serde_json::Value = serde_json::from_str(mailgun::NOTIFY_GOLDEN).unwrap();
+
+        let mail: email::Email = mail.into();
+        assert_eq!(serde_json::to_value(&mail).unwrap(), golden["email"]);
+
+        // Attachment content is fetched separately, so fill in an empty body
+        let attachments: Vec<email::Attachment> = attachments
+            .into_iter()
+            .map(|mut a| {
+                a.content = Some(Vec::new());
+                a.into()
+            })
+            .collect();
+        assert_eq!(
+            serde_json::to_value(&attachments).unwrap(),
+            golden["attachments"]
+        );
+    }
+
+    #[test]
+    fn mailgun_notify_json() {
+        let mail = crate::mailgun::Email::from_json(mailgun::NOTIFY_JSON).unwrap();
+        let attachments = crate::mailgun::Attachment::from_json(mailgun::NOTIFY_JSON).unwrap();
+
+        check_mailgun_golden(mail, attachments);
+
+        let post = crate::mailgun::JsonPost::parse(mailgun::NOTIFY_JSON.as_bytes()).unwrap();
+        assert!(post.stored.is_none());
+        check_mailgun_golden(post.email, post.attachments);
+    }
+
+    #[test]
+    fn mailgun_json_limits() {
+        let attachment = serde_json::json!({
+            "url": "https://se.api.mailgun.net/v3/domains/mg.example.com/messages/A/attachments/0",
+            "content-type": "application/pdf",
+            "name": "a.pdf",
+            "size": 1,
+        });
+        let post = |n| {
+            serde_json::json!({
+                "sender": "jane@example.org",
+                "recipient": "test1@vaulty.net",
+                "attachments": vec![attachment.clone(); n],
+            })
+            .to_string()
+        };
+
+        let max = crate::mailgun::MAX_ATTACHMENTS;
+        let parsed = crate::mailgun::JsonPost::parse(post(max).as_bytes()).unwrap();
+        assert_eq!(parsed.attachments.len(), max);
+
+        let err = crate::mailgun::JsonPost::parse(post(max + 1).as_bytes()).unwrap_err();
+        assert!(matches!(err, crate::Error::InvalidRequest(_)));
+    }
+
+    #[test]
+    fn mailgun_notify_form() {
+        let mail = crate::mailgun::Email::from_form(mailgun::NOTIFY_FORM).unwrap();
+        let attachments = crate::mailgun::Attachment::from_form(mailgun::NOTIFY_FORM).unwrap();
+
+        check_mailgun_golden(mail, attachments);
+    }
+
+    #[test]
+    fn mailgun_notify_multipart() {
+        // Multipart posts have the same text fields as form posts, but each
+        // attachment is sent as a file part instead of a URL
+        let fields: Vec<(String, String)> =
+            url::form_urlencoded::parse(mailgun::NOTIFY_FORM.as_bytes())
+                .into_owned()
+                .filter(|(k, _)| k != "attachments")
+                .collect();
+        assert!(crate::mailgun::StoredMessage::from_fields(fields.clone()).is_err());
+
+        let mail = crate::mailgun::Email::from_fields(fields);
+        let attachments = vec![
+            ("invoice-2020-02.pdf", "application/pdf", 48213),
+            ("logo.png", "image/png", 3265),
+        ]
+        .into_iter()
+        .map(|(name, content_type, size)| {
+            let mut a = crate::mailgun::Attachment::inline(
+                name.to_string(),
+                content_type.to_string(),
+                Vec::new(),
+            );
+            a.size = size;
+            a
+        })
+        .collect();
+
+        check_mailgun_golden(mail, attachments);
+
+        assert!(crate::mailgun::Attachment::is_field("attachment-1"));
+        assert!(!crate::mailgun::Attachment::is_field("attachment-count"));
+    }
+
+    #[test]
+    fn mailgun_stored() {
+        for stored in &[
+            crate::mailgun::StoredMessage::from_json(mailgun::STORED_JSON).unwrap(),
+            crate::mailgun::StoredMessage::from_form(mailgun::STORED_FORM).unwrap(),
+            crate::mailgun::JsonPost::parse(mailgun::STORED_JSON.as_bytes())
+                .unwrap()
+                .stored
+                .unwrap(),
+        ] {
+            assert_eq!(stored.sender, "jane@example.org");
+            assert_eq!(stored.recipient, "test1@vaulty.net");
+            assert_eq!(
+                stored.url,
+                "https://se.api.mailgun.net/v3/domains/mg.example.com/messages/AgEFfzNDcBNPmZVh"
+            );
+        }
+
+        // Inline notifications are not stored notifications
+        assert!(crate::mailgun::StoredMessage::from_form(mailgun::NOTIFY_FORM).is_err());
+    }
+}
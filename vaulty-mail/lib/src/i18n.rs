@@ -0,0 +1,159 @@
+use crate::Error;
+
+/// Messages for user-facing errors in a single language
+pub trait Catalog: Send + Sync {
+    /// Language tag, as sent in `Content-Language` (e.g., "en")
+    fn language(&self) -> &'static str;
+
+    /// Message for an error, or None to fall back to English
+    fn message(&self, error: &Error) -> Option<String>;
+}
+
+/// Error messages as written in `Error`
+pub struct English;
+
+impl Catalog for English {
+    fn language(&self) -> &'static str {
+        "en"
+    }
+
+    fn message(&self, error: &Error) -> Option<String> {
+        Some(error.to_string())
+    }
+}
+
+pub struct French;
+
+impl Catalog for French {
+    fn language(&self) -> &'static str {
+        "fr"
+    }
+
+    fn message(&self, error: &Error) -> Option<String> {
+        let msg = match error {
+            Error::QuotaExceeded(_) => {
+                "Cette adresse Vaulty a atteint son quota pour cette période.".to_string()
+            }
+            Error::TokenExpired => {
+                "Le jeton d'accès au stockage de cette adresse Vaulty a expiré. \
+                 Veuillez vous connecter à Vaulty pour le renouveler."
+                    .to_string()
+            }
+            Error::InvalidRecipient => {
+                "Aucun des destinataires de cet e-mail n'est une adresse Vaulty valide.".to_string()
+            }
+            Error::InvalidSender(sender) => format!(
+                "L'adresse de l'expéditeur de cet e-mail est invalide : {}",
+                sender
+            ),
+            Error::SenderNotWhitelisted { recipient } => format!(
+                "L'expéditeur de cet e-mail n'est pas sur la liste blanche de l'adresse {}.",
+                recipient
+            ),
+            Error::AutoGenerated { recipient } => format!(
+                "L'adresse {} n'accepte pas les e-mails générés automatiquement.",
+                recipient
+            ),
+            Error::RateLimited { sender, recipient } => format!(
+                "Trop d'e-mails ont été envoyés de {} à {} récemment. \
+                 Veuillez réessayer plus tard.",
+                sender, recipient
+            ),
+            Error::Unauthorized => "L'accès à cette ressource n'est pas autorisé.".to_string(),
+            Error::NotFound => "Cette ressource n'existe pas.".to_string(),
+            Error::EmailNotFound(id) => {
+                format!(
+                    "Aucun e-mail avec l'identifiant {} n'est en cours de traitement.",
+                    id
+                )
+            }
+            Error::Maintenance => "Vaulty est en maintenance. Les e-mails seront de nouveau \
+                 acceptés sous peu."
+                .to_string(),
+            // Other messages include details that are only available in English
+            _ => return None,
+        };
+
+        Some(msg)
+    }
+}
+
+/// Supported languages. The first one is the default.
+pub const CATALOGS: &[&dyn Catalog] = &[&English, &French];
+
+/// Pick the catalog that best matches an `Accept-Language` header
+///
+/// Languages are matched on their primary subtag (e.g., "fr-CA" matches
+/// "fr"), in order of preference.
+pub fn negotiate(accept_language: Option<&str>) -> &'static dyn Catalog {
+    let mut ranges: Vec<(String, f32)> = accept_language
+        .unwrap_or("")
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+
+            let q = parts
+                .map(|p| p.trim())
+                .find(|p| p.starts_with("q="))
+                .map(|p| &p[2..])
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if tag.is_empty() || q <= 0.0 {
+                return None;
+            }
+
+            let primary = tag.split('-').next().unwrap_or(tag).to_lowercase();
+
+            Some((primary, q))
+        })
+        .collect();
+
+    // Stable, so ties keep the order of the header
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    ranges
+        .iter()
+        .find_map(|(tag, _)| {
+            if tag == "*" {
+                Some(CATALOGS[0])
+            } else {
+                CATALOGS.iter().copied().find(|c| c.language() == tag)
+            }
+        })
+        .unwrap_or(CATALOGS[0])
+}
+
+/// Message for an error in the given language, falling back to English
+pub fn message(catalog: &dyn Catalog, error: &Error) -> String {
+    catalog.message(error).unwrap_or_else(|| error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(negotiate(None).language(), "en");
+        assert_eq!(negotiate(Some("fr-CA")).language(), "fr");
+        assert_eq!(negotiate(Some("de, fr;q=0.5, en;q=0.8")).language(), "en");
+        assert_eq!(negotiate(Some("de, *;q=0.1")).language(), "en");
+        assert_eq!(negotiate(Some("FR;q=0.9, en;q=0")).language(), "fr");
+        assert_eq!(negotiate(Some("de")).language(), "en");
+    }
+
+    #[test]
+    fn fallback_to_english() {
+        let catalog = negotiate(Some("fr"));
+
+        assert_eq!(
+            message(catalog, &Error::NotFound),
+            "Cette ressource n'existe pas."
+        );
+
+        let error = Error::InvalidRequest("Unknown column for emails: name".to_string());
+        assert_eq!(message(catalog, &error), error.to_string());
+    }
+}
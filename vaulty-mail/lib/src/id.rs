@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use uuid::Uuid;
+
+// Unique UUID namespace (URL + vaulty.net)
+const UUID_NAMESPACE: &str = "11d00b11-d9d0-5831-a6f7-8f88f86f870a";
+
+/// Source of UUIDs for emails and other DB entities.
+///
+/// The seed is whatever uniquely identifies the entity (e.g., email metadata).
+/// Implementations are free to ignore it.
+pub trait IdGenerator: Send + Sync {
+    fn generate(&self, seed: &[u8]) -> Uuid;
+}
+
+/// Generates name-based (v5) UUIDs in the Vaulty namespace
+///
+/// The same seed always maps to the same UUID.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NamespaceIds;
+
+impl IdGenerator for NamespaceIds {
+    fn generate(&self, seed: &[u8]) -> Uuid {
+        let namespace = Uuid::parse_str(UUID_NAMESPACE).unwrap();
+        Uuid::new_v5(&namespace, seed)
+    }
+}
+
+/// Generates predictable UUIDs from a counter, ignoring the seed
+#[derive(Debug, Default)]
+pub struct SequentialIds {
+    next: AtomicU64,
+}
+
+impl SequentialIds {
+    pub fn new(start: u64) -> Self {
+        Self {
+            next: AtomicU64::new(start),
+        }
+    }
+}
+
+impl IdGenerator for SequentialIds {
+    fn generate(&self, _seed: &[u8]) -> Uuid {
+        Uuid::from_u128(self.next.fetch_add(1, Ordering::SeqCst) as u128)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequential_ids() {
+        let ids = SequentialIds::new(1);
+
+        assert_eq!(
+            ids.generate(b"abc").to_string(),
+            "00000000-0000-0000-0000-000000000001"
+        );
+        assert_eq!(
+            ids.generate(b"abc").to_string(),
+            "00000000-0000-0000-0000-000000000002"
+        );
+    }
+
+    #[test]
+    fn namespace_ids_are_deterministic() {
+        assert_eq!(NamespaceIds.generate(b"abc"), NamespaceIds.generate(b"abc"));
+        assert_ne!(NamespaceIds.generate(b"abc"), NamespaceIds.generate(b"def"));
+    }
+}
@@ -1,20 +1,52 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
 use bytes::Bytes;
-use chrono::offset::Utc;
-use futures::stream::Stream;
+use chrono::NaiveDate;
+use futures::stream::{self, Stream};
 
+pub mod admin;
+pub mod anomaly;
 pub mod api;
+pub mod canary;
+pub mod changes;
+pub mod clock;
 pub mod config;
 pub mod constants;
+pub mod crypto;
 pub mod db;
+pub mod directive;
 pub mod email;
+pub mod export;
+pub mod faults;
+pub mod filename;
+pub mod fixtures;
+pub mod i18n;
+pub mod id;
+pub mod links;
 pub mod mailgun;
+pub mod manifest;
+pub mod notify;
+pub mod pdf;
+pub mod pipeline;
+pub mod reply;
+pub mod rules;
+pub mod ses;
+pub mod settings;
+pub mod stats;
 pub mod storage;
+pub mod whitelist;
+pub mod zip;
 
 mod error;
-pub use error::Error;
+pub use error::{report, Cause, Error};
 
+use clock::Clock;
+use settings::Organization;
 use storage::client::Client;
 use storage::dropbox::client::DropboxClient;
+use storage::s3::client::{PresignedUpload, S3Client};
 use storage::Backend;
 
 pub struct EmailHandler<'a> {
@@ -22,6 +54,26 @@ pub struct EmailHandler<'a> {
     storage_token: &'a str,
     storage_backend: &'a storage::Backend,
     storage_path: &'a str,
+    metadata: storage::Metadata,
+    upload_chunk_size: Option<usize>,
+    retry: storage::RetryPolicy,
+    stall_timeout: Option<Duration>,
+    encrypt_files: bool,
+    encryption_key: Option<crypto::Key>,
+    dropbox_namespace_id: Option<&'a str>,
+    dropbox_team_member_id: Option<&'a str>,
+    dropbox_batch: Option<&'a storage::dropbox::batch::Batch>,
+    store_body: bool,
+    folder: Option<String>,
+    pipeline: pipeline::Pipeline,
+    filename_template: Option<filename::Template>,
+    bundle_template: Option<filename::Template>,
+    taken_paths: Vec<String>,
+    organization: Organization,
+    sender_domain: String,
+
+    /// Folders created for organized uploads
+    created_folders: Mutex<HashSet<String>>,
 }
 
 impl<'a> EmailHandler<'a> {
@@ -30,19 +82,314 @@ impl<'a> EmailHandler<'a> {
             storage_token: token,
             storage_backend: backend,
             storage_path: path,
+            metadata: Default::default(),
+            upload_chunk_size: None,
+            retry: Default::default(),
+            stall_timeout: None,
+            encrypt_files: false,
+            encryption_key: None,
+            dropbox_namespace_id: None,
+            dropbox_team_member_id: None,
+            dropbox_batch: None,
+            store_body: false,
+            folder: None,
+            pipeline: Default::default(),
+            filename_template: None,
+            bundle_template: None,
+            taken_paths: Vec::new(),
+            organization: Organization::None,
+            sender_domain: String::new(),
+            created_folders: Default::default(),
 
             // TODO: Figure out user's date from email
             // Will be used for naming scrapbook entries
-            date: Utc::today().format("%F").to_string(),
+            date: clock::SystemClock.now().format("%F").to_string(),
+        }
+    }
+
+    /// Use the given clock to determine the handling date
+    pub fn with_clock(self, clock: &dyn Clock) -> Self {
+        Self {
+            date: clock.now().format("%F").to_string(),
+            ..self
+        }
+    }
+
+    /// Attach custom metadata to every object uploaded by this handler
+    pub fn with_metadata(self, metadata: storage::Metadata) -> Self {
+        Self { metadata, ..self }
+    }
+
+    /// Upload attachments larger than this in chunks, if the backend
+    /// supports it
+    pub fn with_upload_chunk_size(self, upload_chunk_size: usize) -> Self {
+        Self {
+            upload_chunk_size: Some(upload_chunk_size),
+            ..self
+        }
+    }
+
+    /// Retry storage requests that fail for transient reasons using this
+    /// policy
+    pub fn with_retry_policy(self, retry: storage::RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
+    /// Cancel uploads that make no progress for this long (see
+    /// `storage::Watchdog`)
+    pub fn with_stall_timeout(self, stall_timeout: Duration) -> Self {
+        Self {
+            stall_timeout: Some(stall_timeout),
+            ..self
+        }
+    }
+
+    /// Encrypt files with the key of the address before they are uploaded
+    /// (see `crypto`)
+    ///
+    /// If the key is unavailable, uploads fail with a temporary error rather
+    /// than store files in the clear.
+    pub fn with_encryption(self, key: Option<crypto::Key>) -> Self {
+        Self {
+            encrypt_files: true,
+            encryption_key: key,
+            ..self
+        }
+    }
+
+    /// Upload to a Dropbox team space, acting as the given team member
+    pub fn with_dropbox_team(
+        self,
+        namespace_id: Option<&'a str>,
+        team_member_id: Option<&'a str>,
+    ) -> Self {
+        Self {
+            dropbox_namespace_id: namespace_id,
+            dropbox_team_member_id: team_member_id,
+            ..self
+        }
+    }
+
+    /// Defer the commit of small Dropbox uploads to the given batch
+    ///
+    /// Files uploaded this way only show up once the batch is finished with
+    /// `finish_dropbox_batch`. Other backends ignore the batch.
+    pub fn with_dropbox_batch(self, batch: &'a storage::dropbox::batch::Batch) -> Self {
+        Self {
+            dropbox_batch: Some(batch),
+            ..self
+        }
+    }
+
+    /// Store files on the backend of a storage route instead of that of the
+    /// address (see `rules::route`)
+    ///
+    /// Dropbox team settings and batches belong to the address storage, so
+    /// they do not apply to routed files.
+    pub fn with_route(self, route: Option<&'a rules::Route>) -> Self {
+        match route {
+            Some(route) => Self {
+                storage_token: &route.storage_token,
+                storage_backend: &route.storage_backend,
+                storage_path: &route.storage_path,
+                dropbox_namespace_id: None,
+                dropbox_team_member_id: None,
+                dropbox_batch: None,
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    /// Store the email body (text and HTML) when handling an email without
+    /// an attachment
+    pub fn with_store_body(self, store_body: bool) -> Self {
+        Self { store_body, ..self }
+    }
+
+    /// Run attachments through these stages before they are stored
+    pub fn with_pipeline(self, pipeline: pipeline::Pipeline) -> Self {
+        Self { pipeline, ..self }
+    }
+
+    /// Name attachments after this template instead of their original name
+    ///
+    /// The template is rendered before the attachment goes through the
+    /// pipeline. See `filename::Template` for the supported placeholders.
+    pub fn with_filename_template(self, template: &str) -> Self {
+        Self {
+            filename_template: Some(filename::Template::new(template)),
+            ..self
+        }
+    }
+
+    /// Name zip archives of bundled attachments after this template instead
+    /// of the email date and subject (e.g., "{date}-{sender}-{subject}.zip")
+    ///
+    /// The template has the same placeholders as filename templates, where
+    /// `{name}` is the default archive name. See `bundle`.
+    pub fn with_bundle_template(self, template: &str) -> Self {
+        Self {
+            bundle_template: Some(filename::Template::new(template)),
+            ..self
+        }
+    }
+
+    /// Full storage paths that are already taken (e.g., by other
+    /// attachments of the email), which attachments get a collision suffix
+    /// rather than overwrite
+    ///
+    /// Paths are compared case-insensitively, as Dropbox paths are (i.e.,
+    /// by their `path_lower`).
+    pub fn with_taken_paths(self, taken_paths: Vec<String>) -> Self {
+        Self {
+            taken_paths: taken_paths.iter().map(|p| p.to_lowercase()).collect(),
+            ..self
         }
     }
 
+    /// Organize files of the email into subfolders of the storage path,
+    /// by handling date or sender domain
+    ///
+    /// The subfolder comes before any folder set with `with_folder`. Missing
+    /// folders are created before the first upload.
+    pub fn with_organization(self, organization: Organization, email: &email::Email) -> Self {
+        let sender_domain = email
+            .sender
+            .rfind('@')
+            .map(|i| email.sender[i + 1..].trim().to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .map(|domain| filename::normalize(&domain, false))
+            .unwrap_or_default();
+
+        Self {
+            organization,
+            sender_domain,
+            ..self
+        }
+    }
+
+    /// Store files in a subfolder of the storage path
+    ///
+    /// The subfolder may be nested (e.g., "Receipts/2024"). Each folder name
+    /// is normalized like a filename, and relative names are dropped.
+    pub fn with_folder(self, folder: &str) -> Self {
+        let folder = folder
+            .split(&['/', '\\'][..])
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && *name != "." && *name != "..")
+            .map(|name| filename::normalize(name, false))
+            .collect::<Vec<_>>()
+            .join("/");
+
+        Self {
+            folder: Some(folder).filter(|f| !f.is_empty()),
+            ..self
+        }
+    }
+
+    /// Subfolder the organization policy puts files in, if any
+    fn organized_folder(&self) -> Option<String> {
+        match self.organization {
+            Organization::None => None,
+            Organization::Date => Some(self.date.replace('-', "/")),
+            // Senders without a usable domain are stored directly
+            Organization::SenderDomain => {
+                Some(self.sender_domain.clone()).filter(|d| !d.is_empty())
+            }
+        }
+    }
+
+    /// Full storage path of a file
+    pub fn file_path(&self, name: &str) -> String {
+        let mut path = self.storage_path.to_string();
+
+        for folder in self.organized_folder().iter().chain(self.folder.iter()) {
+            path.push('/');
+            path.push_str(folder);
+        }
+
+        format!("{}/{}", path, name)
+    }
+
+    /// Create the folder a file is stored in, if it was not created yet by
+    /// this handler
+    async fn ensure_folder(&self, file_path: &str) -> Result<(), Error> {
+        let folder = match file_path.rfind('/') {
+            Some(i) if i > 0 => &file_path[..i],
+            _ => return Ok(()),
+        };
+
+        if self.created_folders.lock().unwrap().contains(folder) {
+            return Ok(());
+        }
+
+        match self.storage_backend {
+            Backend::Dropbox => self.dropbox_client().ensure_folder(folder).await?,
+            Backend::Gdrive => (),
+            Backend::S3 => self.s3_client()?.ensure_folder(folder).await?,
+        }
+
+        self.created_folders
+            .lock()
+            .unwrap()
+            .insert(folder.to_string());
+
+        Ok(())
+    }
+
+    fn dropbox_client(&self) -> DropboxClient<'a> {
+        let mut client =
+            DropboxClient::from_token(self.storage_token).with_retry_policy(self.retry);
+
+        if let Some(chunk_size) = self.upload_chunk_size {
+            client = client.with_chunk_size(chunk_size);
+        }
+
+        if let Some(namespace_id) = self.dropbox_namespace_id {
+            client = client.with_namespace(namespace_id);
+        }
+
+        if let Some(team_member_id) = self.dropbox_team_member_id {
+            client = client.with_team_member(team_member_id);
+        }
+
+        if let Some(batch) = self.dropbox_batch {
+            client = client.with_batch(batch);
+        }
+
+        client
+    }
+
+    /// Commit all files of a Dropbox batch
+    ///
+    /// Returns the outcome for each file, in the order they were uploaded.
+    pub async fn finish_dropbox_batch(
+        &self,
+        batch: &storage::dropbox::batch::Batch,
+    ) -> Result<Vec<storage::dropbox::batch::Committed>, Error> {
+        let client = self.dropbox_client();
+        let result = client.finish_batch(batch).await;
+
+        result.map_err(|e| e.into())
+    }
+
+    fn s3_client(&self) -> Result<S3Client, storage::Error> {
+        let mut client = S3Client::from_token(self.storage_token)?.with_retry_policy(self.retry);
+
+        if let Some(part_size) = self.upload_chunk_size {
+            client = client.with_part_size(part_size);
+        }
+
+        Ok(client)
+    }
+
     pub async fn handle(
         &self,
         email: &email::Email,
         attachment: Option<impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static>,
         attachment_name: String,
-        _attachment_size: usize,
+        attachment_size: usize,
     ) -> Result<(), Error> {
         log::info!(
             "Handling mail for {} on {}",
@@ -64,31 +411,700 @@ impl<'a> EmailHandler<'a> {
 
         // 4. Write all attachments to folder via Dropbox API
         if let Some(attachment) = attachment {
-            let file_path = format!("{}/{}", self.storage_path, attachment_name);
+            self.handle_attachment(email, attachment, attachment_name, attachment_size)
+                .await
+                .map(|_| ())
+        } else if self.store_body {
+            // Store the email body alongside the attachments
+            self.upload_body(email).await
+        } else {
+            // Just dump the email (scrapbook mode!)
+            Ok(())
+        }
+    }
+
+    /// Run an attachment through the pipeline of the address, then store it
+    ///
+    /// Returns the name the attachment was stored under, as stages may
+    /// rename it, or the stage that dropped it.
+    pub async fn handle_attachment(
+        &self,
+        email: &email::Email,
+        data: impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static,
+        name: String,
+        size: usize,
+    ) -> Result<pipeline::Processed, Error> {
+        // The files of a bundle went through the template and pipeline as
+        // they were added to it
+        if email.is_bundle {
+            let name = self.unique_name(self.bundle_name(email, name));
+            let name = self.upload(&name, data).await?;
+            return Ok(pipeline::Processed::Stored(name));
+        }
 
-            match self.storage_backend {
-                Backend::Dropbox => {
-                    // Build a Dropbox client
-                    let client = DropboxClient::from_token(self.storage_token);
-                    let result = client.upload_stream(&file_path, attachment).await;
+        let name = self.template_name(email, name);
 
-                    result.map_err(|e| e.into())
+        let attachment = pipeline::Attachment {
+            name,
+            size,
+            data: Box::pin(data),
+            derived: Vec::new(),
+        };
+        let ctx = pipeline::Context {
+            email,
+            date: &self.date,
+        };
+
+        match self.pipeline.run(&ctx, attachment).await? {
+            Ok(attachment) => {
+                // Stored under the name the backend picked, so that it can be
+                // found again (e.g., to update its metadata)
+                let name = self.unique_name(attachment.name);
+                let name = self.upload(&name, attachment.data).await?;
+
+                // Derived files are stored once the attachment is, and are
+                // not accounted for separately
+                for derived in attachment.derived {
+                    let derived_name = self.unique_name(derived.name(&name));
+                    self.upload(&derived_name, stream::iter(vec![Ok(derived.data)]))
+                        .await?;
                 }
-                Backend::Gdrive => {
-                    // TODO
-                    Ok(())
+
+                Ok(pipeline::Processed::Stored(name))
+            }
+            Err(stage) => {
+                log::info!(
+                    "Attachment of mail for {} was dropped by stage {}",
+                    email.recipients[0],
+                    stage
+                );
+                Ok(pipeline::Processed::Dropped(stage))
+            }
+        }
+    }
+
+    /// Run the attachments of an email through the template and pipeline,
+    /// then bundle those that are kept into a single zip archive
+    ///
+    /// The archive is named after the handling date and email subject, like
+    /// the email body, unless the handler has a bundle template. It is
+    /// stored with `handle_attachment` once the email is marked as a bundle,
+    /// which is when the template is applied. Files, along with any files
+    /// derived from them, keep the names they would have been stored under,
+    /// with a collision suffix if needed. Returns `None` if all attachments
+    /// were dropped.
+    pub async fn bundle(
+        &self,
+        email: &email::Email,
+        attachments: Vec<pipeline::Attachment>,
+    ) -> Result<Option<pipeline::Attachment>, Error> {
+        let ctx = pipeline::Context {
+            email,
+            date: &self.date,
+        };
+        let mut entries: Vec<zip::Entry> = Vec::new();
+
+        for attachment in attachments {
+            let attachment = pipeline::Attachment {
+                name: self.template_name(email, attachment.name),
+                ..attachment
+            };
+
+            let attachment = match self.pipeline.run(&ctx, attachment).await? {
+                Ok(attachment) => attachment,
+                Err(stage) => {
+                    log::info!(
+                        "Attachment of mail for {} was dropped from its bundle by stage {}",
+                        email.recipients[0],
+                        stage
+                    );
+                    continue;
                 }
-                Backend::S3 => {
-                    // TODO
-                    Ok(())
+            };
+
+            let unique_name = |entries: &[zip::Entry], name: String| {
+                let mut candidate = name.clone();
+                let mut n = 1;
+                while entries.iter().any(|e| e.name == candidate) {
+                    candidate = filename::with_suffix(&name, n);
+                    n += 1;
                 }
+                candidate
+            };
+
+            let name = unique_name(&entries, attachment.name);
+            let derived: Vec<zip::Entry> = attachment
+                .derived
+                .into_iter()
+                .map(|d| zip::Entry {
+                    name: d.name(&name),
+                    size: d.data.len(),
+                    data: Box::pin(stream::iter(vec![Ok(d.data)])),
+                })
+                .collect();
+
+            entries.push(zip::Entry {
+                name,
+                size: attachment.size,
+                data: attachment.data,
+            });
+
+            for entry in derived {
+                let name = unique_name(&entries, entry.name);
+                entries.push(zip::Entry { name, ..entry });
+            }
+        }
+
+        if entries.is_empty() {
+            return Ok(None);
+        }
+
+        let date = NaiveDate::parse_from_str(&self.date, "%F")
+            .map_err(|e| Error::internal("Invalid handling date", e))?;
+        let archive = zip::Archive::new(entries, date)?;
+
+        Ok(Some(pipeline::Attachment {
+            name: filename::normalize(&body_name(email, &self.date, "zip"), false),
+            size: archive.size(),
+            data: Box::pin(archive),
+            derived: Vec::new(),
+        }))
+    }
+
+    /// Name of a bundle after the bundle template, if any
+    ///
+    /// Names always have a .zip extension.
+    fn bundle_name(&self, email: &email::Email, name: String) -> String {
+        let template = match &self.bundle_template {
+            Some(template) => template,
+            None => return name,
+        };
+
+        match template.render(&template_vars(email, &self.date, &name)) {
+            Some(rendered) if rendered.to_lowercase().ends_with(".zip") => rendered,
+            Some(rendered) => format!("{}.zip", rendered),
+            None => name,
+        }
+    }
+
+    /// Name of an attachment after the filename template, if any
+    fn template_name(&self, email: &email::Email, name: String) -> String {
+        match &self.filename_template {
+            Some(template) => template
+                .render(&template_vars(email, &self.date, &name))
+                .unwrap_or(name),
+            None => name,
+        }
+    }
+
+    /// Add a collision suffix to a name if its path is already taken
+    fn unique_name(&self, name: String) -> String {
+        let is_taken = |name: &str| {
+            self.taken_paths
+                .contains(&self.file_path(name).to_lowercase())
+        };
+
+        if !is_taken(&name) {
+            return name;
+        }
+
+        let mut n = 1;
+        loop {
+            let candidate = filename::with_suffix(&name, n);
+            if !is_taken(&candidate) {
+                return candidate;
+            }
+            n += 1;
+        }
+    }
+
+    /// Store the list of links extracted from an HTML email body
+    ///
+    /// The list is named like the email body.
+    pub async fn store_links(&self, email: &email::Email, list: String) -> Result<(), Error> {
+        let name = filename::normalize(&body_name(email, &self.date, "links.txt"), false);
+        let data = stream::iter(vec![Ok(Bytes::from(list))]);
+
+        log::info!(
+            "Storing links of mail for {} as {}",
+            email.recipients[0],
+            name
+        );
+
+        self.upload(&name, data).await.map(|_| ())
+    }
+
+    /// Store the HTML body of an email, rendered to PDF, next to its
+    /// attachments
+    pub async fn store_body_pdf(&self, email: &email::Email, pdf: Vec<u8>) -> Result<(), Error> {
+        let name = filename::normalize(&body_name(email, &self.date, "pdf"), false);
+        let data = stream::iter(vec![Ok(Bytes::from(pdf))]);
+
+        log::info!(
+            "Storing PDF body of mail for {} as {}",
+            email.recipients[0],
+            name
+        );
+
+        self.upload(&name, data).await.map(|_| ())
+    }
+
+    /// Store the signed manifest of an email next to its attachments
+    ///
+    /// The signature is stored in a separate file, so that the manifest is
+    /// kept byte for byte as it was signed.
+    pub async fn store_manifest(
+        &self,
+        manifest: &manifest::Manifest,
+        key: &manifest::SigningKey,
+    ) -> Result<(), Error> {
+        let bytes = manifest.to_bytes();
+        let signature = key.sign(&bytes);
+
+        log::info!(
+            "Storing manifest of mail for {} as {}",
+            manifest.recipient,
+            manifest.name()
+        );
+
+        self.upload(&manifest.name(), stream::iter(vec![Ok(Bytes::from(bytes))]))
+            .await?;
+
+        let signature = Bytes::from(signature.to_bytes());
+        self.upload(
+            &manifest.signature_name(),
+            stream::iter(vec![Ok(signature)]),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Upload the plaintext and HTML bodies of an email, if not empty
+    ///
+    /// Bodies are named after the handling date and email subject.
+    async fn upload_body(&self, email: &email::Email) -> Result<(), Error> {
+        let bodies = vec![
+            ("txt", Some(&email.body)),
+            ("html", email.body_html.as_ref()),
+        ];
+
+        for (ext, body) in bodies {
+            let body = match body {
+                Some(body) if !body.trim().is_empty() => body,
+                _ => continue,
+            };
+
+            let name = filename::normalize(&body_name(email, &self.date, ext), false);
+            let data = stream::iter(vec![Ok(Bytes::from(body.clone()))]);
+
+            self.upload(&name, data).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Archive the raw RFC822 message as a single .eml file
+    ///
+    /// The message is stored exactly as it was received.
+    pub async fn archive(
+        &self,
+        email: &email::Email,
+        raw: impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        let name = filename::normalize(&body_name(email, &self.date, "eml"), false);
+
+        log::info!("Archiving mail for {} as {}", email.recipients[0], name);
+
+        self.upload(&name, raw).await.map(|_| ())
+    }
+
+    /// Upload a file to the storage folder, and return the name it was
+    /// stored under
+    ///
+    /// The name differs from `name` if the backend renamed the file, which
+    /// Dropbox does if a file with the same name already exists.
+    async fn upload(
+        &self,
+        name: &str,
+        data: impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static,
+    ) -> Result<String, Error> {
+        let file_path = self.file_path(name);
+
+        faults::inject(faults::Target::Storage).await?;
+
+        if self.organization != Organization::None {
+            self.ensure_folder(&file_path).await?;
+        }
+
+        let data: pipeline::Data = match (self.encrypt_files, &self.encryption_key) {
+            (false, _) => Box::pin(data),
+            (true, Some(key)) => Box::pin(crypto::encrypt(key, data)),
+            (true, None) => {
+                return Err(Error::Temporary(
+                    "Encryption key of the address is unavailable; retry later.".to_string(),
+                ))
+            }
+        };
+
+        let watchdog = storage::Watchdog::new(self.storage_backend, &file_path, self.stall_timeout);
+        let data = watchdog.track(data);
+
+        let stored_path = match self.storage_backend {
+            Backend::Dropbox => {
+                // Build a Dropbox client
+                let client = self.dropbox_client();
+
+                let upload = client.upload_stream(&file_path, data, &self.metadata);
+
+                watchdog
+                    .run(async { upload.await.map_err(Error::from) })
+                    .await?
+            }
+            Backend::Gdrive => {
+                // TODO
+                file_path.clone()
+            }
+            Backend::S3 => {
+                // S3 settings are stored as JSON in the token
+                let client = self.s3_client()?;
+                let upload = client.upload_stream(&file_path, data, &self.metadata);
+
+                watchdog
+                    .run(async { upload.await.map_err(Error::from) })
+                    .await?
+            }
+        };
+
+        Ok(stored_path.rsplit('/').next().unwrap_or(name).to_string())
+    }
+
+    /// Link to a stored attachment in the storage backend, if there is one
+    pub fn file_url(&self, attachment_name: &str) -> Option<String> {
+        self.path_url(&self.file_path(attachment_name))
+    }
+
+    /// Link to a file stored at `file_path`, if there is one
+    pub fn path_url(&self, file_path: &str) -> Option<String> {
+        match self.storage_backend {
+            Backend::Dropbox => Some(storage::dropbox::api::build_web_url(file_path)),
+            Backend::Gdrive => None,
+            Backend::S3 => S3Client::from_token(self.storage_token)
+                .ok()
+                .map(|client| client.object_url(file_path)),
+        }
+    }
+
+    /// Files that are already in the storage folder (e.g., put there by the
+    /// owner of the address), up to `max_files`
+    ///
+    /// Only Dropbox folders are listed for now.
+    pub async fn list_existing_files(
+        &self,
+        max_files: usize,
+    ) -> Result<Vec<storage::dropbox::client::ListedFile>, Error> {
+        match self.storage_backend {
+            Backend::Dropbox => Ok(self
+                .dropbox_client()
+                .list_files(self.storage_path, max_files)
+                .await?),
+            Backend::Gdrive | Backend::S3 => Ok(Vec::new()),
+        }
+    }
+
+    /// Pre-sign an upload of an attachment straight to the storage backend
+    ///
+    /// Returns `None` if the backend does not support direct uploads, or if
+    /// files are encrypted, as they must go through the server to be.
+    /// `expires` is in seconds.
+    pub fn presign_upload(
+        &self,
+        attachment_name: &str,
+        size: usize,
+        sha256: &str,
+        expires: u64,
+    ) -> Result<Option<PresignedUpload>, Error> {
+        let file_path = self.file_path(attachment_name);
+
+        if self.encrypt_files {
+            return Ok(None);
+        }
+
+        match self.storage_backend {
+            Backend::S3 => {
+                let client = self.s3_client()?;
+                let upload =
+                    client.presign_upload(&file_path, size, sha256, &self.metadata, expires)?;
+
+                Ok(Some(upload))
+            }
+            Backend::Dropbox | Backend::Gdrive => Ok(None),
+        }
+    }
+
+    /// Check that an attachment uploaded through `presign_upload` was
+    /// stored in full
+    pub async fn verify_upload(
+        &self,
+        attachment_name: &str,
+        size: usize,
+        sha256: &str,
+    ) -> Result<(), Error> {
+        let file_path = self.file_path(attachment_name);
+
+        faults::inject(faults::Target::Storage).await?;
+
+        let info = match self.storage_backend {
+            Backend::S3 => self.s3_client()?.head_object(&file_path).await?,
+            Backend::Dropbox | Backend::Gdrive => {
+                return Err(Error::InvalidRequest(format!(
+                    "{} does not support direct uploads",
+                    self.storage_backend
+                )))
+            }
+        };
+
+        if info.size != size || info.sha256.as_deref() != Some(sha256) {
+            return Err(Error::InvalidRequest(format!(
+                "Uploaded attachment {} does not match: expected {} bytes with SHA-256 {}, found {} bytes with SHA-256 {}",
+                attachment_name,
+                size,
+                sha256,
+                info.size,
+                info.sha256.as_deref().unwrap_or("(none)")
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Refresh the metadata of a previously stored attachment instead of
+    /// uploading it again
+    ///
+    /// `file_path` is the path the attachment was stored at, which is not
+    /// always the one `file_path` gives for its name (e.g., if Dropbox
+    /// renamed it, or it was stored in another dated folder).
+    pub async fn update_metadata(&self, file_path: &str) -> Result<(), Error> {
+        faults::inject(faults::Target::Storage).await?;
+
+        match self.storage_backend {
+            Backend::Dropbox => {
+                let client = self.dropbox_client();
+                let result = client.update_metadata(file_path, &self.metadata).await;
+                result.map_err(|e| e.into())
+            }
+            Backend::Gdrive => {
+                // TODO
+                Ok(())
+            }
+            Backend::S3 => {
+                let client = self.s3_client()?;
+                let result = client.update_metadata(file_path, &self.metadata).await;
+                result.map_err(|e| e.into())
             }
-        } else {
-            // Just dump the email (scrapbook mode!)
-            Ok(())
         }
     }
 }
 
+/// Name of a stored email body or archived message, e.g.,
+/// "2020-02-09 Invoice #42 (1a2b3c4d).txt"
+///
+/// The start of the email UUID keeps bodies of emails with the same subject
+/// apart.
+fn body_name(email: &email::Email, date: &str, ext: &str) -> String {
+    let subject = email
+        .subject
+        .as_deref()
+        .filter(|s| !s.trim().is_empty())
+        .unwrap_or("No subject")
+        // Slashes would otherwise be treated as directories
+        .replace(&['/', '\\'][..], "-");
+    let id = email.uuid.to_string();
+
+    format!("{} {} ({}).{}", date, subject, &id[..8], ext)
+}
+
+/// Variables available to filename templates (see `filename::VARIABLES`)
+fn template_vars(email: &email::Email, date: &str, name: &str) -> Vec<(&'static str, String)> {
+    let subject = email
+        .subject
+        .as_deref()
+        .filter(|s| !s.trim().is_empty())
+        .unwrap_or("No subject");
+    let id = email.uuid.to_string();
+
+    vec![
+        ("date", date.to_string()),
+        ("sender", email.sender.clone()),
+        ("subject", subject.to_string()),
+        ("name", name.to_string()),
+        ("email_id", id[..8].to_string()),
+    ]
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    use futures::stream::TryStreamExt;
+
+    #[test]
+    fn template_variables() {
+        let vars = template_vars(&Default::default(), "2020-04-01", "a.pdf");
+        let names: Vec<&str> = vars.iter().map(|(k, _)| *k).collect();
+        assert_eq!(names, filename::VARIABLES);
+    }
+
+    #[test]
+    fn body_names() {
+        let mut email = email::Email {
+            uuid: uuid::Uuid::parse_str("1a2b3c4d-d9d0-5831-a6f7-8f88f86f870a").unwrap(),
+            subject: Some("Invoice #42".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            body_name(&email, "2020-02-09", "txt"),
+            "2020-02-09 Invoice #42 (1a2b3c4d).txt"
+        );
+
+        email.subject = Some("Invoice 1/2".to_string());
+        assert_eq!(
+            body_name(&email, "2020-02-09", "txt"),
+            "2020-02-09 Invoice 1-2 (1a2b3c4d).txt"
+        );
+
+        email.subject = None;
+        assert_eq!(
+            body_name(&email, "2020-02-09", "html"),
+            "2020-02-09 No subject (1a2b3c4d).html"
+        );
+    }
+
+    #[test]
+    fn nested_folders() {
+        let backend = Backend::Dropbox;
+        let handler = |folder| EmailHandler::new("token", &backend, "/vaulty").with_folder(folder);
+
+        assert_eq!(
+            handler("Receipts/2024").file_path("a.pdf"),
+            "/vaulty/Receipts/2024/a.pdf"
+        );
+        assert_eq!(
+            handler("/../Receipts\\.//2024/").file_path("a.pdf"),
+            "/vaulty/Receipts/2024/a.pdf"
+        );
+        assert_eq!(handler("..").file_path("a.pdf"), "/vaulty/a.pdf");
+    }
+
+    #[test]
+    fn organized_folders() {
+        let backend = Backend::Dropbox;
+        let email = email::Email {
+            sender: "Billing@Example.com".to_string(),
+            ..Default::default()
+        };
+        let handler = |organization| {
+            EmailHandler::new("token", &backend, "/vaulty")
+                .with_organization(organization, &email)
+                .with_folder("Invoices")
+        };
+
+        assert_eq!(
+            handler(Organization::SenderDomain).file_path("a.pdf"),
+            "/vaulty/example.com/Invoices/a.pdf"
+        );
+
+        let handler = handler(Organization::Date);
+        assert_eq!(
+            handler.file_path("a.pdf"),
+            format!("/vaulty/{}/Invoices/a.pdf", handler.date.replace('-', "/"))
+        );
+    }
+
+    #[test]
+    fn collision_suffixes() {
+        let backend = Backend::Dropbox;
+        let handler = EmailHandler::new("token", &backend, "/vaulty")
+            .with_folder("Invoices")
+            .with_taken_paths(vec![
+                "/vaulty/Invoices/a.pdf".to_string(),
+                "/vaulty/Invoices/a (1).pdf".to_string(),
+                "/vaulty/invoices/A (2).PDF".to_string(),
+            ]);
+
+        assert_eq!(handler.unique_name("a.pdf".to_string()), "a (3).pdf");
+        assert_eq!(handler.unique_name("b.pdf".to_string()), "b.pdf");
+    }
+
+    #[tokio::test]
+    async fn bundle_attachments() {
+        let backend = Backend::Dropbox;
+        let email = email::Email {
+            uuid: uuid::Uuid::parse_str("1a2b3c4d-d9d0-5831-a6f7-8f88f86f870a").unwrap(),
+            recipients: vec!["test@vaulty.net".to_string()],
+            subject: Some("Invoices".to_string()),
+            ..Default::default()
+        };
+        let handler = EmailHandler::new("token", &backend, "/vaulty")
+            .with_pipeline(pipeline::Pipeline::from_names(&["skip_empty".to_string()]));
+
+        let attachment = |name: &str, data: &'static [u8]| pipeline::Attachment {
+            name: name.to_string(),
+            size: data.len(),
+            data: Box::pin(stream::iter(vec![Ok(Bytes::from_static(data))])),
+            derived: Vec::new(),
+        };
+        let attachments = vec![
+            attachment("a.pdf", b"first"),
+            attachment("a.pdf", b"second"),
+            attachment("empty.pdf", b""),
+        ];
+
+        let bundle = handler.bundle(&email, attachments).await.unwrap().unwrap();
+        assert_eq!(
+            bundle.name,
+            format!("{} Invoices (1a2b3c4d).zip", handler.date)
+        );
+
+        let data: Vec<Bytes> = bundle.data.try_collect().await.unwrap();
+        let data = data.concat();
+        assert_eq!(data.len(), bundle.size);
+
+        // The empty file was dropped, and the second file renamed
+        let contains = |name: &[u8]| data.windows(name.len()).any(|w| w == name);
+        assert!(contains(b"a (1).pdf"));
+        assert!(!contains(b"empty.pdf"));
+
+        let dropped = vec![attachment("empty.pdf", b"")];
+        assert!(handler.bundle(&email, dropped).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn bundle_names() {
+        let backend = Backend::Dropbox;
+        let email = email::Email {
+            sender: "billing@example.com".to_string(),
+            subject: Some("Invoice 1/2".to_string()),
+            ..Default::default()
+        };
+        let handler = |template| {
+            EmailHandler::new("token", &backend, "/vaulty").with_bundle_template(template)
+        };
+        let name = || "bundle.zip".to_string();
+
+        let h = handler("{date}-{sender}-{subject}.zip");
+        assert_eq!(
+            h.bundle_name(&email, name()),
+            format!("{}-billing@example.com-Invoice 1-2.zip", h.date)
+        );
+
+        // The extension is added if missing
+        let h = handler("{date}/{subject}");
+        assert_eq!(
+            h.bundle_name(&email, name()),
+            format!("{}/Invoice 1-2.zip", h.date)
+        );
+
+        assert_eq!(handler("/").bundle_name(&email, name()), "bundle.zip");
+    }
+}
@@ -0,0 +1,598 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::stream::StreamExt;
+use reqwest::header::{CONTENT_DISPOSITION, LOCATION};
+use reqwest::redirect;
+use serde::Serialize;
+use url::Url;
+
+use crate::Error;
+
+/// Max number of redirects followed when fetching a linked file
+const MAX_REDIRECTS: usize = 3;
+
+/// Linked files are only archived if they start with this
+const PDF_MAGIC: &[u8] = b"%PDF-";
+
+/// A hyperlink in an HTML email body
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Link {
+    pub url: String,
+
+    /// Text of the link, with tags and extra whitespace removed
+    pub text: String,
+}
+
+/// A file fetched from a link
+pub struct Fetched {
+    pub name: String,
+    pub data: Bytes,
+}
+
+/// Extract the absolute HTTP(S) links from an HTML document
+///
+/// This is not a full HTML parser: it only looks at anchor tags, which is
+/// enough for the "your invoice is ready" emails that link to a document
+/// instead of attaching it. Links are returned in order, without duplicates.
+pub fn extract(html: &str) -> Vec<Link> {
+    // ASCII lowercasing keeps byte offsets the same
+    let lower = html.to_ascii_lowercase();
+    let mut links: Vec<Link> = Vec::new();
+    let mut pos = 0;
+
+    while let Some(start) = find_anchor(&lower, pos) {
+        let end = match tag_end(&lower, start) {
+            Some(end) => end,
+            None => break,
+        };
+        pos = end + 1;
+
+        // Skip "<a"
+        let href = match attribute(&html[start + 2..end], "href") {
+            Some(href) => href,
+            None => continue,
+        };
+
+        let url = match Url::parse(href.trim()) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url.to_string(),
+            _ => continue,
+        };
+
+        let close = lower[pos..].find("</a").map_or(lower.len(), |i| pos + i);
+        let text = text(&html[pos..close]);
+
+        match links.iter_mut().find(|l| l.url == url) {
+            Some(link) if link.text.is_empty() => link.text = text,
+            Some(_) => (),
+            None => links.push(Link { url, text }),
+        }
+    }
+
+    links
+}
+
+/// Render links as a plain text list, one link per line
+pub fn to_text(links: &[Link]) -> String {
+    links
+        .iter()
+        .map(|l| {
+            if l.text.is_empty() {
+                format!("{}\n", l.url)
+            } else {
+                format!("{} <{}>\n", l.text, l.url)
+            }
+        })
+        .collect()
+}
+
+/// Match a URL against a pattern, where `*` matches any number of
+/// characters (e.g., "https://billing.example.com/*.pdf")
+///
+/// Matching is case-insensitive.
+pub fn matches(pattern: &str, url: &str) -> bool {
+    let pattern = pattern.trim().to_ascii_lowercase();
+    let url = url.to_ascii_lowercase();
+
+    let parts: Vec<&str> = pattern.split('*').collect();
+
+    // No wildcard: exact match
+    if parts.len() == 1 {
+        return url == pattern;
+    }
+
+    let first = parts[0];
+    let last = parts[parts.len() - 1];
+
+    if !url.starts_with(first) || url.len() < first.len() + last.len() {
+        return false;
+    }
+
+    let mut rest = &url[first.len()..url.len() - last.len()];
+
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    url.ends_with(last)
+}
+
+/// Fetch a linked PDF
+///
+/// Links in email come from untrusted senders, so requests are guarded
+/// against server-side request forgery: only HTTP(S) on the default ports is
+/// allowed, and the host must only resolve to public addresses. Redirects
+/// are followed manually so that each hop is checked as well.
+///
+/// The HTTP client resolves the host again, so the address it connected to
+/// is checked once more before the response is read.
+pub async fn fetch(url: &str, max_size: usize, timeout: Duration) -> Result<Fetched, Error> {
+    let client = reqwest::Client::builder()
+        .redirect(redirect::Policy::none())
+        .timeout(timeout)
+        .build()
+        .map_err(|e| Error::internal("Failed to build HTTP client", e))?;
+
+    let mut url = Url::parse(url)
+        .map_err(|e| Error::InvalidRequest(format!("Invalid link {}: {}", url, e)))?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        check_url(&url).await?;
+
+        let resp = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| Error::internal(&format!("Failed to fetch {}", url), e))?;
+
+        match resp.remote_addr() {
+            Some(addr) if is_public(addr.ip()) => (),
+            _ => {
+                return Err(Error::InvalidRequest(format!(
+                    "Link {} does not point to a public address",
+                    url
+                )))
+            }
+        }
+
+        if resp.status().is_redirection() {
+            let location = resp
+                .headers()
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| Error::Generic(format!("Redirect from {} has no location", url)))?;
+
+            url = url
+                .join(location)
+                .map_err(|e| Error::internal(&format!("Invalid redirect from {}", url), e))?;
+
+            continue;
+        }
+
+        if !resp.status().is_success() {
+            return Err(Error::Generic(format!(
+                "Fetching {} failed with {}",
+                url,
+                resp.status()
+            )));
+        }
+
+        let too_large =
+            || Error::Generic(format!("Linked file {} is over {} bytes", url, max_size));
+
+        if resp
+            .content_length()
+            .map_or(false, |len| len > max_size as u64)
+        {
+            return Err(too_large());
+        }
+
+        let name = resp
+            .headers()
+            .get(CONTENT_DISPOSITION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(disposition_name)
+            .unwrap_or_else(|| url_name(&url));
+
+        let mut data = BytesMut::new();
+        let mut body = resp.bytes_stream();
+
+        while let Some(chunk) = body.next().await {
+            let chunk =
+                chunk.map_err(|e| Error::internal(&format!("Failed to fetch {}", url), e))?;
+
+            if data.len() + chunk.len() > max_size {
+                return Err(too_large());
+            }
+
+            data.extend_from_slice(&chunk);
+        }
+
+        if !data.starts_with(PDF_MAGIC) {
+            return Err(Error::InvalidRequest(format!(
+                "Linked file {} is not a PDF",
+                url
+            )));
+        }
+
+        return Ok(Fetched {
+            name: pdf_name(&name),
+            data: data.freeze(),
+        });
+    }
+
+    Err(Error::Generic(format!(
+        "Too many redirects fetching {}",
+        url
+    )))
+}
+
+/// Check that a URL is safe to fetch
+async fn check_url(url: &Url) -> Result<(), Error> {
+    let invalid = |reason: &str| Error::InvalidRequest(format!("Link {} {}", url, reason));
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(invalid("is not HTTP(S)"));
+    }
+
+    // Non-default ports are mostly internal services
+    if url.port().is_some() {
+        return Err(invalid("uses a non-default port"));
+    }
+
+    let host = url.host_str().ok_or_else(|| invalid("has no host"))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    let addrs: Vec<IpAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| Error::internal(&format!("Failed to resolve {}", host), e))?
+        .map(|addr| addr.ip())
+        .collect();
+
+    if addrs.is_empty() || !addrs.iter().all(|&ip| is_public(ip)) {
+        return Err(invalid("does not point to a public address"));
+    }
+
+    Ok(())
+}
+
+/// Returns true if the address is publicly routable
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // "This" network
+        || a == 0
+        // Carrier-grade NAT
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking
+        || (a == 198 && (b & 0xfe) == 18)
+        // Reserved
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+
+    // IPv4-mapped (::ffff:a.b.c.d) and IPv4-compatible (::a.b.c.d)
+    if segments[..5].iter().all(|&s| s == 0) && (segments[5] == 0xffff || segments[5] == 0) {
+        let [_, _, _, _, _, _, hi, lo] = segments;
+        let v4 = Ipv4Addr::new((hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8);
+
+        return !ip.is_loopback() && !ip.is_unspecified() && is_public_v4(v4);
+    }
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link-local
+        || (segments[0] & 0xffc0) == 0xfe80
+        // Documentation
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+}
+
+/// Find the next anchor tag at or after `from`
+fn find_anchor(lower: &str, from: usize) -> Option<usize> {
+    let mut from = from;
+
+    loop {
+        let start = from + lower[from..].find("<a")?;
+        let next = lower[start + 2..].chars().next();
+
+        // Skip other tags starting with "a" (e.g., <abbr>)
+        match next {
+            Some(c) if c.is_ascii_whitespace() || c == '>' || c == '/' => return Some(start),
+            None => return None,
+            _ => from = start + 2,
+        }
+    }
+}
+
+/// Find the end of the tag starting at `start`, skipping quoted values
+fn tag_end(s: &str, start: usize) -> Option<usize> {
+    let mut quote = None;
+
+    for (i, c) in s[start..].char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(start + i),
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// Value of an attribute in the body of a tag, with entities decoded
+fn attribute(attrs: &str, name: &str) -> Option<String> {
+    let mut rest = attrs;
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+
+        if rest.is_empty() {
+            return None;
+        }
+
+        let name_end = rest
+            .find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        let attr_name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+
+        let value = if rest.starts_with('=') {
+            rest = rest[1..].trim_start();
+
+            match rest.chars().next() {
+                Some(q) if q == '"' || q == '\'' => {
+                    let end = rest[1..].find(q).map_or(rest.len(), |i| i + 1);
+                    let value = &rest[1..end];
+                    rest = rest.get(end + 1..).unwrap_or("");
+                    value
+                }
+                _ => {
+                    let end = rest
+                        .find(|c: char| c.is_ascii_whitespace())
+                        .unwrap_or(rest.len());
+                    let value = &rest[..end];
+                    rest = &rest[end..];
+                    value
+                }
+            }
+        } else {
+            ""
+        };
+
+        if attr_name.eq_ignore_ascii_case(name) {
+            return Some(decode_entities(value));
+        }
+    }
+}
+
+/// Text content of an HTML fragment
+fn text(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if !in_tag => text.push(c),
+            _ => (),
+        }
+    }
+
+    decode_entities(&text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Decode the common named entities and all numeric entities
+fn decode_entities(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        let end = match rest.find(';') {
+            // Entities are short; anything longer is a literal ampersand
+            Some(end) if end <= 10 => end,
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                u32::from_str_radix(&entity[2..], 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+            }
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None,
+        };
+
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// File name from a Content-Disposition header, if any
+fn disposition_name(header: &str) -> Option<String> {
+    header
+        .split(';')
+        .map(|p| p.trim())
+        .find(|p| p.to_ascii_lowercase().starts_with("filename="))
+        .map(|p| p["filename=".len()..].trim_matches('"').to_string())
+        .filter(|name| !name.trim().is_empty())
+}
+
+/// File name from the last segment of a URL path
+fn url_name(url: &Url) -> String {
+    url.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|s| !s.is_empty())
+        .unwrap_or("document")
+        .to_string()
+}
+
+fn pdf_name(name: &str) -> String {
+    if name.to_ascii_lowercase().ends_with(".pdf") {
+        name.to_string()
+    } else {
+        format!("{}.pdf", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_links() {
+        let html = r#"
+            <p>Your invoice is ready.</p>
+            <A class="btn" HREF="https://billing.example.com/invoice?id=1&amp;format=pdf">
+                <b>Download</b>&nbsp;invoice
+            </a>
+            <abbr title="x">abbr</abbr>
+            <a href='https://example.com/help' title="a > b">Help</a>
+            <a href=https://example.com/unquoted>Unquoted</a>
+            <a href="mailto:billing@example.com">Email us</a>
+            <a href="/relative">Relative</a>
+            <a name="anchor">No link</a>
+            <a href="https://example.com/help"><img src="logo.png"></a>
+        "#;
+
+        let links = extract(html);
+
+        assert_eq!(
+            links,
+            vec![
+                Link {
+                    url: "https://billing.example.com/invoice?id=1&format=pdf".to_string(),
+                    text: "Download invoice".to_string(),
+                },
+                Link {
+                    url: "https://example.com/help".to_string(),
+                    text: "Help".to_string(),
+                },
+                Link {
+                    url: "https://example.com/unquoted".to_string(),
+                    text: "Unquoted".to_string(),
+                },
+            ]
+        );
+
+        assert_eq!(
+            to_text(&links[1..2]),
+            "Help <https://example.com/help>\n".to_string()
+        );
+        assert!(extract("<a href=\"https://example.com\"").is_empty());
+    }
+
+    #[test]
+    fn match_patterns() {
+        assert!(matches("*.pdf", "https://example.com/invoice.PDF"));
+        assert!(matches(
+            "https://billing.example.com/*/download*",
+            "https://billing.example.com/invoices/42/download?format=pdf"
+        ));
+        assert!(!matches(
+            "https://billing.example.com/*",
+            "https://evil.com/?https://billing.example.com/"
+        ));
+        assert!(matches("https://example.com/a", "https://example.com/a"));
+        assert!(!matches("https://example.com/a", "https://example.com/ab"));
+        assert!(!matches("*a*a*", "https://b"));
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in &["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(
+            disposition_name("attachment; filename=\"Invoice 42.pdf\""),
+            Some("Invoice 42.pdf".to_string())
+        );
+        assert_eq!(disposition_name("inline"), None);
+
+        let url = Url::parse("https://example.com/invoices/42").unwrap();
+        assert_eq!(pdf_name(&url_name(&url)), "42.pdf");
+
+        let url = Url::parse("https://example.com/").unwrap();
+        assert_eq!(pdf_name(&url_name(&url)), "document.pdf");
+    }
+}
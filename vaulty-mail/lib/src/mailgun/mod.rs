@@ -1,2 +1,4 @@
+mod signature;
 mod types;
+pub use signature::*;
 pub use types::*;
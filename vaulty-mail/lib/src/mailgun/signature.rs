@@ -0,0 +1,156 @@
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+/// Signature that Mailgun attaches to each webhook post
+///
+/// The signature is the hex HMAC-SHA256 of the timestamp followed by the
+/// token, keyed with the webhook signing key of the Mailgun account.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Signature {
+    pub timestamp: String,
+    pub token: String,
+    pub signature: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignatureError {
+    /// The signature does not match the timestamp and token
+    Invalid,
+    /// The timestamp is too far from the current time
+    Expired,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Invalid => f.write_str("Invalid Mailgun webhook signature."),
+            Self::Expired => f.write_str("Mailgun webhook signature has expired."),
+        }
+    }
+}
+
+impl Signature {
+    /// Read the signature from the fields of a form
+    ///
+    /// Returns `None` if any part of the signature is missing.
+    pub fn from_fields(fields: impl IntoIterator<Item = (String, String)>) -> Option<Self> {
+        let mut signature = Self::default();
+
+        for (k, v) in fields {
+            if k == "timestamp" {
+                signature.timestamp = v;
+            } else if k == "token" {
+                signature.token = v;
+            } else if k == "signature" {
+                signature.signature = v;
+            }
+        }
+
+        Some(signature).filter(Self::is_complete)
+    }
+
+    pub fn from_form(body: &str) -> Option<Self> {
+        Self::from_fields(url::form_urlencoded::parse(body.as_bytes()).into_owned())
+    }
+
+    /// Read the signature from a JSON post
+    ///
+    /// Inbound routes post the signature fields at the top level, while
+    /// event webhooks nest them in a `signature` object.
+    pub fn from_json(body: &str) -> Option<Self> {
+        super::JsonPost::parse(body.as_bytes()).ok()?.signature
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        !self.timestamp.is_empty() && !self.token.is_empty() && !self.signature.is_empty()
+    }
+
+    /// Verify the signature against the signing key
+    ///
+    /// `now` is the current Unix time. Signatures whose timestamp is more
+    /// than `max_age` seconds away from it are rejected, so that captured
+    /// posts can only be replayed for a short while.
+    pub fn verify(&self, signing_key: &str, now: i64, max_age: i64) -> Result<(), SignatureError> {
+        let code = hex::decode(&self.signature).map_err(|_| SignatureError::Invalid)?;
+
+        let mut mac = Hmac::<Sha256>::new_varkey(signing_key.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.input(self.timestamp.as_bytes());
+        mac.input(self.token.as_bytes());
+
+        // Compared in constant time
+        mac.verify(&code).map_err(|_| SignatureError::Invalid)?;
+
+        let timestamp = self
+            .timestamp
+            .parse::<i64>()
+            .map_err(|_| SignatureError::Invalid)?;
+
+        if (now - timestamp).abs() > max_age {
+            return Err(SignatureError::Expired);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "key-3ax6xnjp29jd6fds4gc373sgvjxteol0";
+    const TIMESTAMP: i64 = 1581295092;
+
+    #[test]
+    fn verify_signature() {
+        let form = format!(
+            "timestamp={}&token={}&signature={}",
+            TIMESTAMP,
+            "0123456789abcdef0123456789abcdef0123456789abcdef01",
+            "944734cf1a1fde69b955bc3cb4a92a1e3c1bdb927bdb3764a1da3f8d57298e97"
+        );
+        let signature = Signature::from_form(&form).unwrap();
+
+        assert_eq!(signature.verify(KEY, TIMESTAMP + 10, 300), Ok(()));
+        assert_eq!(
+            signature.verify("key-other", TIMESTAMP, 300),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            signature.verify(KEY, TIMESTAMP + 301, 300),
+            Err(SignatureError::Expired)
+        );
+
+        // The token is covered by the signature
+        let forged = Signature {
+            token: "f".repeat(50),
+            ..signature.clone()
+        };
+        assert_eq!(
+            forged.verify(KEY, TIMESTAMP, 300),
+            Err(SignatureError::Invalid)
+        );
+
+        // Event webhooks nest the signature
+        let json = serde_json::json!({
+            "signature": {
+                "timestamp": signature.timestamp,
+                "token": signature.token,
+                "signature": signature.signature,
+            },
+            "event-data": {},
+        })
+        .to_string();
+        assert_eq!(
+            Signature::from_json(&json)
+                .unwrap()
+                .verify(KEY, TIMESTAMP, 300),
+            Ok(())
+        );
+
+        assert!(Signature::from_form("timestamp=1&token=abc").is_none());
+    }
+}
@@ -2,7 +2,16 @@ use std::collections::HashMap;
 use std::convert::From;
 use std::default::Default;
 
-use serde::Deserialize;
+use bytes::Bytes;
+use futures::future::{self, Either};
+use futures::stream::{self, Stream, TryStreamExt};
+use serde::de::{self, IgnoredAny, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use super::Signature;
+
+/// Most attachments read from a JSON post
+pub const MAX_ATTACHMENTS: usize = 100;
 
 // TODO: Move this out into a trait and implement a
 // basic version for MG, SES, and Postfix (?)
@@ -17,6 +26,24 @@ pub struct Email {
     body_html: String,
 }
 
+/// Notification sent by Mailgun's `store()` route action
+///
+/// Only a URL to the stored message is sent. The full MIME message must be
+/// fetched from the Mailgun API.
+#[derive(Deserialize, Debug, Default)]
+pub struct StoredMessage {
+    pub sender: String,
+    pub recipient: String,
+    #[serde(rename = "message-url")]
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct StoredMime {
+    #[serde(rename = "body-mime")]
+    body_mime: String,
+}
+
 #[derive(Deserialize, Debug, Default)]
 struct AttachmentJson {
     attachments: Vec<Attachment>,
@@ -31,7 +58,126 @@ pub struct Attachment {
     #[serde(rename = "content-type")]
     content_type: String,
     pub name: String,
-    size: usize,
+    pub size: usize,
+}
+
+/// `signature` field of a JSON post
+///
+/// Inbound routes post the signature fields at the top level, while event
+/// webhooks nest them in a `signature` object.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SignatureField {
+    Hex(String),
+    Nested(Signature),
+    Other(IgnoredAny),
+}
+
+/// Fields of a JSON post that Vaulty reads
+///
+/// Other fields (e.g., the message headers) are skipped as the body is
+/// parsed, rather than kept in memory.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct RawJsonPost {
+    sender: String,
+    recipient: String,
+    subject: String,
+    #[serde(rename = "body-plain")]
+    body: String,
+    #[serde(rename = "body-html")]
+    body_html: String,
+    #[serde(rename = "message-url")]
+    url: String,
+    timestamp: String,
+    token: String,
+    signature: Option<SignatureField>,
+    #[serde(deserialize_with = "bounded_attachments")]
+    attachments: Vec<Attachment>,
+}
+
+/// Read the attachments of a post, failing as soon as there are more than
+/// `MAX_ATTACHMENTS`
+fn bounded_attachments<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Attachment>, D::Error> {
+    struct Bounded;
+
+    impl<'de> Visitor<'de> for Bounded {
+        type Value = Vec<Attachment>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "at most {} attachments", MAX_ATTACHMENTS)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut attachments = Vec::new();
+
+            while let Some(attachment) = seq.next_element()? {
+                if attachments.len() == MAX_ATTACHMENTS {
+                    return Err(de::Error::invalid_length(MAX_ATTACHMENTS + 1, &self));
+                }
+
+                attachments.push(attachment);
+            }
+
+            Ok(attachments)
+        }
+    }
+
+    d.deserialize_seq(Bounded)
+}
+
+/// A JSON post from Mailgun, parsed in a single pass
+///
+/// Posts either carry an email and its attachments, or point to a message
+/// stored by Mailgun (see `StoredMessage`).
+#[derive(Debug, Default)]
+pub struct JsonPost {
+    /// Not set if any part of the signature is missing
+    pub signature: Option<Signature>,
+    /// Only set for `store()` notifications
+    pub stored: Option<StoredMessage>,
+    pub email: Email,
+    pub attachments: Vec<Attachment>,
+}
+
+impl JsonPost {
+    pub fn parse(body: &[u8]) -> Result<Self, crate::Error> {
+        let post: RawJsonPost = serde_json::from_slice(body)
+            .map_err(|e| crate::Error::InvalidRequest(format!("Invalid Mailgun post: {}", e)))?;
+
+        let signature = match post.signature {
+            Some(SignatureField::Hex(signature)) => Some(Signature {
+                timestamp: post.timestamp,
+                token: post.token,
+                signature,
+            }),
+            Some(SignatureField::Nested(signature)) => Some(signature),
+            Some(SignatureField::Other(_)) | None => None,
+        };
+
+        let stored = if post.url.is_empty() {
+            None
+        } else {
+            Some(StoredMessage {
+                sender: post.sender.clone(),
+                recipient: post.recipient.clone(),
+                url: post.url,
+            })
+        };
+
+        Ok(Self {
+            signature: signature.filter(Signature::is_complete),
+            stored,
+            email: Email {
+                sender: post.sender,
+                recipient: post.recipient,
+                subject: post.subject,
+                body: post.body,
+                body_html: post.body_html,
+            },
+            attachments: post.attachments,
+        })
+    }
 }
 
 /// Represents a single email as provided by Mailgun
@@ -41,10 +187,19 @@ impl Email {
     }
 
     pub fn from_form(body: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut mail = Self::new();
         let parsed = url::form_urlencoded::parse(body.as_bytes()).into_owned();
 
-        for (k, v) in parsed {
+        Ok(Self::from_fields(parsed))
+    }
+
+    /// Build an email from the text fields of a form, such as those of a
+    /// `multipart/form-data` post
+    ///
+    /// Unknown fields are ignored.
+    pub fn from_fields(fields: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut mail = Self::new();
+
+        for (k, v) in fields {
             if k == "sender" {
                 mail.sender = v;
             } else if k == "recipient" {
@@ -58,7 +213,7 @@ impl Email {
             }
         }
 
-        Ok(mail)
+        mail
     }
 
     pub fn from_json(body: &str) -> Result<Self, Box<dyn std::error::Error>> {
@@ -83,8 +238,100 @@ impl From<Email> for crate::email::Email {
     }
 }
 
+impl StoredMessage {
+    pub fn from_form(body: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let parsed = url::form_urlencoded::parse(body.as_bytes()).into_owned();
+
+        Self::from_fields(parsed)
+    }
+
+    /// Build a notification from the text fields of a form
+    ///
+    /// Fails if there is no message URL, i.e., the email was not stored.
+    pub fn from_fields(
+        fields: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut stored = Self::default();
+
+        for (k, v) in fields {
+            if k == "sender" {
+                stored.sender = v;
+            } else if k == "recipient" {
+                stored.recipient = v;
+            } else if k == "message-url" {
+                stored.url = v;
+            }
+        }
+
+        if stored.url.is_empty() {
+            return Err("No message-url found in Mailgun notification".into());
+        }
+
+        Ok(stored)
+    }
+
+    pub fn from_json(body: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        serde_json::from_str::<Self>(body).map_err(|e| e.into())
+    }
+
+    /// Fetch the full MIME content of the stored message
+    pub async fn fetch(
+        &self,
+        api_key: Option<&String>,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let client = reqwest::Client::new();
+
+        let resp = client
+            .get(reqwest::Url::parse(&self.url)?)
+            .basic_auth("api", api_key)
+            .header(reqwest::header::ACCEPT, "message/rfc2822")
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let stored: StoredMime = serde_json::from_slice(&resp.bytes().await?)?;
+
+        Ok(stored.body_mime.into_bytes())
+    }
+
+    /// Delete the stored message from Mailgun once it has been processed
+    pub async fn delete(&self, api_key: Option<&String>) -> Result<(), Box<dyn std::error::Error>> {
+        let client = reqwest::Client::new();
+
+        client
+            .delete(reqwest::Url::parse(&self.url)?)
+            .basic_auth("api", api_key)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
 /// Represents a single email attachment
 impl Attachment {
+    /// Attachment posted inline, such as a file part (`attachment-N`) of a
+    /// `multipart/form-data` post
+    pub fn inline(name: String, content_type: String, content: Vec<u8>) -> Self {
+        Self {
+            size: content.len(),
+            content: Some(content),
+            url: String::new(),
+            content_type,
+            name,
+        }
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    /// Whether a form field holds an attachment (e.g., "attachment-1")
+    pub fn is_field(name: &str) -> bool {
+        name.starts_with("attachment-") && name["attachment-".len()..].parse::<usize>().is_ok()
+    }
+
     /// Create a Vec of attachments from a Mailgun form response
     pub fn from_form(body: &str) -> Result<Vec<Attachment>, Box<dyn std::error::Error>> {
         let parsed: HashMap<String, String> = url::form_urlencoded::parse(body.as_bytes())
@@ -101,14 +348,21 @@ impl Attachment {
             .map(|json| json.attachments)
     }
 
-    /// If the attachment has a URL but no content, grab the attachment
-    /// content. Data is filled into the current struct.
+    /// Stream the attachment content
+    ///
+    /// Inline content is yielded as a single chunk. Otherwise, the content is
+    /// streamed from the attachment URL as it is downloaded, so that large
+    /// attachments never fully reside in memory.
     pub async fn fetch(
-        mut self,
+        self,
         api_key: Option<&String>,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        if self.content.is_some() {
-            return Ok(self);
+    ) -> Result<
+        impl Stream<Item = Result<Bytes, crate::Error>> + Send + Sync + 'static,
+        Box<dyn std::error::Error>,
+    > {
+        if let Some(content) = self.content {
+            let data = stream::once(future::ready(Ok(Bytes::from(content))));
+            return Ok(Either::Left(data));
         }
 
         let client = reqwest::Client::new();
@@ -120,11 +374,11 @@ impl Attachment {
             .await?
             .error_for_status()?;
 
-        let buf = &resp.bytes().await?;
-
-        self.content = Some(buf.to_vec());
+        let data = resp
+            .bytes_stream()
+            .map_err(|e| crate::Error::internal("Failed to download attachment", e));
 
-        Ok(self)
+        Ok(Either::Right(data))
     }
 }
 
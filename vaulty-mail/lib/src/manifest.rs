@@ -0,0 +1,268 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer};
+use serde::{Deserialize, Serialize};
+
+use crate::email::Email;
+use crate::Error;
+
+/// Version of the manifest format
+pub const VERSION: u32 = 1;
+
+/// Manifests are stored next to the attachments of an email, named after
+/// the email ID (e.g., "<uuid>.manifest.json"), with their signature in a
+/// file of the same name with this suffix instead
+pub const MANIFEST_SUFFIX: &str = ".manifest.json";
+pub const SIGNATURE_SUFFIX: &str = ".manifest.sig";
+
+pub const ALGORITHM: &str = "ed25519";
+
+/// A single file stored for an email
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct File {
+    /// Path of the file, relative to the storage path of the address
+    pub path: String,
+    pub mime_type: String,
+
+    /// Size, in bytes
+    pub size: u64,
+
+    /// Hex SHA-256 of the content (see `email::content_hash`)
+    pub sha256: String,
+}
+
+/// List of the files stored for an email, along with the email metadata
+///
+/// The manifest is signed with the server key, so that anyone with the
+/// public key can check that the files were stored by Vaulty for this email,
+/// and have not been changed since.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub mail_id: uuid::Uuid,
+    pub message_id: Option<String>,
+    pub sender: String,
+    pub recipient: String,
+    pub subject: Option<String>,
+    pub signed_at: DateTime<Utc>,
+    pub files: Vec<File>,
+}
+
+impl Manifest {
+    /// Build the manifest of an email
+    ///
+    /// `files` have their full storage path, which is made relative to the
+    /// storage path of the address (`storage_root`).
+    pub fn new(email: &Email, storage_root: &str, files: Vec<File>, now: DateTime<Utc>) -> Self {
+        let root = format!("{}/", storage_root.trim_end_matches('/'));
+
+        let files = files
+            .into_iter()
+            .map(|f| File {
+                path: f.path.strip_prefix(&root).unwrap_or(&f.path).to_string(),
+                ..f
+            })
+            .collect();
+
+        Self {
+            version: VERSION,
+            mail_id: email.uuid,
+            message_id: email.message_id.clone(),
+            sender: email.sender.clone(),
+            recipient: email.recipients.first().cloned().unwrap_or_default(),
+            subject: email.subject.clone(),
+            signed_at: now,
+            files,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        format!("{}{}", self.mail_id, MANIFEST_SUFFIX)
+    }
+
+    pub fn signature_name(&self) -> String {
+        format!("{}{}", self.mail_id, SIGNATURE_SUFFIX)
+    }
+
+    /// The manifest as stored, which is what is signed
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).expect("manifest is always serializable")
+    }
+}
+
+/// Detached signature of a stored manifest
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ManifestSignature {
+    pub algorithm: String,
+
+    /// Hex public key of the server that signed the manifest
+    pub public_key: String,
+
+    /// Hex signature of the manifest file content
+    pub signature: String,
+}
+
+impl ManifestSignature {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).expect("signature is always serializable")
+    }
+}
+
+/// Server key that manifests are signed with
+pub struct SigningKey(Keypair);
+
+impl SigningKey {
+    /// Load the key from its hex 32-byte seed (see `manifest_signing_key`)
+    pub fn from_hex(seed: &str) -> Result<Self, Error> {
+        let invalid = |e: String| Error::Generic(format!("Invalid manifest signing key: {}", e));
+
+        let seed = hex::decode(seed.trim()).map_err(|e| invalid(e.to_string()))?;
+        let secret = SecretKey::from_bytes(&seed).map_err(|e| invalid(e.to_string()))?;
+        let public = PublicKey::from(&secret);
+
+        Ok(Self(Keypair { secret, public }))
+    }
+
+    /// Hex public key, which is what manifests are verified against
+    pub fn public_key(&self) -> String {
+        hex::encode(self.0.public.as_bytes())
+    }
+
+    pub fn sign(&self, manifest: &[u8]) -> ManifestSignature {
+        let signature: Signature = self.0.sign(manifest);
+
+        ManifestSignature {
+            algorithm: ALGORITHM.to_string(),
+            public_key: self.public_key(),
+            signature: hex::encode(&signature.to_bytes()[..]),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyError {
+    /// The trusted key, or the key or signature in the signature file, is
+    /// malformed
+    Malformed(String),
+    /// The manifest was signed with another key
+    UntrustedKey(String),
+    /// The signature does not match the manifest
+    InvalidSignature,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Malformed(msg) => write!(f, "Malformed signature: {}", msg),
+            Self::UntrustedKey(key) => write!(f, "Signed with untrusted key {}", key),
+            Self::InvalidSignature => f.write_str("Signature does not match the manifest"),
+        }
+    }
+}
+
+/// Check the signature of a stored manifest against the trusted public key
+/// of the server
+///
+/// Returns the parsed manifest if the signature is valid. The files listed
+/// in it still have to be checked against their hashes.
+pub fn verify(
+    manifest: &[u8],
+    signature: &ManifestSignature,
+    trusted_key: &str,
+) -> Result<Manifest, VerifyError> {
+    if signature.algorithm != ALGORITHM {
+        return Err(VerifyError::Malformed(format!(
+            "unsupported algorithm {}",
+            signature.algorithm
+        )));
+    }
+
+    if !signature
+        .public_key
+        .eq_ignore_ascii_case(trusted_key.trim())
+    {
+        return Err(VerifyError::UntrustedKey(signature.public_key.clone()));
+    }
+
+    let key = hex::decode(trusted_key.trim()).map_err(|e| VerifyError::Malformed(e.to_string()))?;
+    let key = PublicKey::from_bytes(&key).map_err(|e| VerifyError::Malformed(e.to_string()))?;
+
+    let bytes =
+        hex::decode(&signature.signature).map_err(|e| VerifyError::Malformed(e.to_string()))?;
+    let sig = Signature::try_from(&bytes[..]).map_err(|e| VerifyError::Malformed(e.to_string()))?;
+
+    key.verify_strict(manifest, &sig)
+        .map_err(|_| VerifyError::InvalidSignature)?;
+
+    serde_json::from_slice(manifest).map_err(|e| VerifyError::Malformed(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+    fn manifest() -> Manifest {
+        let email = Email {
+            sender: "a@example.com".to_string(),
+            recipients: vec!["b@vaulty.net".to_string()],
+            subject: Some("Contract".to_string()),
+            uuid: uuid::Uuid::nil(),
+            ..Default::default()
+        };
+
+        let files = vec![File {
+            path: "/vaulty/Contracts/contract.pdf".to_string(),
+            mime_type: "application/pdf".to_string(),
+            size: 3,
+            sha256: crate::email::content_hash(b"pdf"),
+        }];
+
+        Manifest::new(&email, "/vaulty/", files, Utc::now())
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let key = SigningKey::from_hex(SEED).unwrap();
+        let public_key = key.public_key();
+
+        // RFC 8032 test vector 1
+        assert_eq!(
+            public_key,
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"
+        );
+
+        let manifest = manifest();
+        assert_eq!(manifest.files[0].path, "Contracts/contract.pdf");
+        assert_eq!(
+            manifest.name(),
+            "00000000-0000-0000-0000-000000000000.manifest.json"
+        );
+
+        let bytes = manifest.to_bytes();
+        let signature = key.sign(&bytes);
+
+        assert_eq!(verify(&bytes, &signature, &public_key), Ok(manifest));
+
+        // Any change to the manifest is caught
+        let mut tampered = bytes.clone();
+        let i = tampered.len() - 10;
+        tampered[i] ^= 1;
+        assert_eq!(
+            verify(&tampered, &signature, &public_key),
+            Err(VerifyError::InvalidSignature)
+        );
+
+        // Signatures by other keys are not trusted
+        let other = SigningKey::from_hex(&"1".repeat(64)).unwrap();
+        assert!(matches!(
+            verify(&bytes, &other.sign(&bytes), &public_key),
+            Err(VerifyError::UntrustedKey(_))
+        ));
+
+        assert!(SigningKey::from_hex("abcd").is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use super::{truncate, Category, Notifier};
+
+/// Max length of a Discord message, in characters
+const MAX_MESSAGE_LEN: usize = 2000;
+
+/// Posts messages to a Discord webhook
+///
+/// Messages use Discord's Markdown formatting. Mentions (e.g., `@everyone`)
+/// in emails are never resolved.
+pub struct Discord;
+
+impl Notifier for Discord {
+    fn template(&self, category: Category) -> &'static str {
+        match category {
+            Category::Received => {
+                ":incoming_envelope: Email from {sender} to {recipient} received: **{subject}**"
+            }
+            Category::Success => {
+                ":white_check_mark: Stored {num_files} file(s) from {sender} to {recipient}: \
+                 **{subject}**\n{files}"
+            }
+            Category::Rejection => {
+                ":x: Email from {sender} to {recipient} was rejected ({reason}): \
+                 **{subject}**\n{message}"
+            }
+            Category::AttachmentStored => {
+                ":paperclip: Stored {files} from {sender} to {recipient}: **{subject}**"
+            }
+            Category::AttachmentReceived => {
+                ":inbox_tray: Received {files} from {sender} to {recipient}: **{subject}**"
+            }
+            Category::Alert => ":warning: Unusual activity on {recipient}: {message}",
+        }
+    }
+
+    /// Escape Markdown formatting characters
+    fn escape(&self, s: &str) -> String {
+        let mut escaped = String::with_capacity(s.len());
+
+        for c in s.chars() {
+            if "\\*_~`|>[]()".contains(c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+
+        escaped
+    }
+
+    fn link(&self, name: &str, url: &str) -> String {
+        format!("[{}](<{}>)", name, url)
+    }
+
+    fn body(&self, message: String) -> serde_json::Value {
+        serde_json::json!({
+            "content": truncate(message, MAX_MESSAGE_LEN),
+            "allowed_mentions": { "parse": [] },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{clock, email};
+    use super::super::{Format, Notification, StoredFile};
+
+    #[test]
+    fn success_with_links() {
+        let mut mail = email();
+        mail.subject = Some("*Q1* invoices".to_string());
+
+        let n =
+            Notification::success(&mail, "".to_string(), &clock()).with_files(vec![StoredFile {
+                name: "invoice_1.pdf".to_string(),
+                url: Some("https://www.dropbox.com/home/vaulty/invoice_1.pdf".to_string()),
+            }]);
+
+        let payload = n.payload(Format::Discord, None);
+
+        assert_eq!(
+            payload["content"],
+            ":white_check_mark: Stored 1 file(s) from billing@example.com to invoices@vaulty.net: \
+             **\\*Q1\\* invoices**\n\
+             [invoice\\_1.pdf](<https://www.dropbox.com/home/vaulty/invoice_1.pdf>)"
+        );
+        assert_eq!(payload["allowed_mentions"]["parse"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn long_messages_are_truncated() {
+        let n = Notification::received(&email(), "".to_string(), &clock());
+        let template = "a".repeat(3000);
+        let payload = n.payload(Format::Discord, Some(template.as_str()));
+
+        assert_eq!(
+            payload["content"].as_str().unwrap().chars().count(),
+            super::MAX_MESSAGE_LEN
+        );
+    }
+}
@@ -0,0 +1,747 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::clock::Clock;
+use crate::email::{AutoGenerated, Email};
+use crate::Error;
+
+pub mod discord;
+pub mod slack;
+
+/// Webhook request timeout, in seconds
+const WEBHOOK_TIMEOUT: u64 = 10;
+
+/// Max number of attempts per webhook call
+const WEBHOOK_MAX_ATTEMPTS: u32 = 4;
+
+/// Delay before the first retry of a webhook call, in milliseconds. The delay
+/// doubles after each attempt.
+const WEBHOOK_RETRY_DELAY_MS: u64 = 1000;
+
+/// Header containing the HMAC-SHA256 signature of a webhook payload
+pub const SIGNATURE_HEADER: &str = "X-Vaulty-Signature";
+
+/// Header containing the Unix timestamp that a webhook payload was signed at
+pub const TIMESTAMP_HEADER: &str = "X-Vaulty-Timestamp";
+
+/// Kinds of notifications a webhook can subscribe to
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    /// An email was accepted and its attachments are being stored
+    Received,
+    /// An email was stored
+    Success,
+    /// An email, or one of its attachments, was not stored
+    Rejection,
+    /// A single attachment was stored
+    AttachmentStored,
+    /// An attachment was fully read, and is about to be stored
+    AttachmentReceived,
+    /// Unusual activity on an address, such as a spike in email volume
+    Alert,
+}
+
+/// Event reported by a notification, included in JSON payloads
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    EmailReceived,
+    EmailStored,
+    AttachmentStored,
+    AttachmentReceived,
+    EmailFailed,
+    QuotaExceeded,
+    AnomalyDetected,
+}
+
+/// Why an email or attachment was not stored
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    QuotaExceeded,
+    SenderNotWhitelisted,
+    AutoGenerated,
+    AttachmentDropped,
+    StorageError,
+    Expired,
+}
+
+impl Reason {
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::QuotaExceeded => "quota exceeded",
+            Self::SenderNotWhitelisted => "sender not whitelisted",
+            Self::AutoGenerated => "auto-generated email",
+            Self::AttachmentDropped => "attachment dropped by filtering rules",
+            Self::StorageError => "storage error",
+            Self::Expired => "attachments never arrived",
+        }
+    }
+}
+
+/// Payload format expected by a webhook
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// The notification, as is
+    Json,
+    /// Slack incoming webhook
+    Slack,
+    /// Discord webhook
+    Discord,
+}
+
+impl Format {
+    /// Notifier used to render human-readable messages, if any
+    pub fn notifier(self) -> Option<&'static dyn Notifier> {
+        match self {
+            Self::Json => None,
+            Self::Slack => Some(&slack::Slack),
+            Self::Discord => Some(&discord::Discord),
+        }
+    }
+}
+
+impl From<&str> for Format {
+    fn from(s: &str) -> Self {
+        if s == "json" {
+            Self::Json
+        } else if s == "slack" {
+            Self::Slack
+        } else if s == "discord" {
+            Self::Discord
+        } else {
+            // Default to JSON
+            log::error!("Unknown webhook format: {}", s);
+            Self::Json
+        }
+    }
+}
+
+impl From<String> for Format {
+    fn from(s: String) -> Self {
+        s.as_str().into()
+    }
+}
+
+/// A webhook configured for an address
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Webhook {
+    pub url: String,
+    pub format: Format,
+    pub on_received: bool,
+    pub on_success: bool,
+    pub on_rejection: bool,
+    pub on_attachment: bool,
+    #[serde(default)]
+    pub on_attachment_received: bool,
+    pub on_alert: bool,
+
+    /// Key used to sign payloads, if any. See `sign`.
+    pub secret: Option<String>,
+
+    /// Message template used instead of the notifier default, for all
+    /// categories. Ignored for JSON webhooks. See `Notification::render`.
+    pub template: Option<String>,
+}
+
+impl Webhook {
+    pub fn wants(&self, category: Category) -> bool {
+        match category {
+            Category::Received => self.on_received,
+            Category::Success => self.on_success,
+            Category::Rejection => self.on_rejection,
+            Category::AttachmentStored => self.on_attachment,
+            Category::AttachmentReceived => self.on_attachment_received,
+            Category::Alert => self.on_alert,
+        }
+    }
+
+    /// Whether a notification should be sent to this webhook
+    ///
+    /// High priority emails are pushed as soon as they are received, even to
+    /// webhooks that only subscribe to stored emails.
+    pub fn accepts(&self, notification: &Notification) -> bool {
+        self.wants(notification.category)
+            || (notification.high_priority
+                && notification.category == Category::Received
+                && self.on_success)
+    }
+}
+
+/// A file stored for an email
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StoredFile {
+    pub name: String,
+    /// Link to the file in the storage backend, if known
+    pub url: Option<String>,
+}
+
+/// An attachment that was received, but not yet stored
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReceivedAttachment {
+    pub name: String,
+    /// In bytes
+    pub size: usize,
+    /// Hex-encoded SHA-256 of the content
+    pub sha256: String,
+}
+
+/// A single notification about an email sent to a Vaulty address
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Notification {
+    pub event: Event,
+    pub category: Category,
+    /// Only set for rejections
+    pub reason: Option<Reason>,
+    pub mail_id: Uuid,
+    pub message_id: Option<String>,
+    pub recipient: String,
+    pub sender: String,
+    pub subject: Option<String>,
+    /// Set if the email looks auto-generated
+    pub auto_generated: Option<AutoGenerated>,
+    /// Set if the email matched a priority rule that flags notifications
+    #[serde(default)]
+    pub high_priority: bool,
+    /// Set if the owner turned off notifications for the email with a
+    /// directive; muted notifications are never sent
+    #[serde(skip)]
+    pub muted: bool,
+    /// Details, as logged by Vaulty
+    pub message: String,
+    /// Only set on success, or when an attachment is stored
+    pub files: Vec<StoredFile>,
+    /// Only set when an attachment is received
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<ReceivedAttachment>,
+    pub time: DateTime<Utc>,
+}
+
+impl Notification {
+    fn new(email: &Email, category: Category, message: String, clock: &dyn Clock) -> Self {
+        let event = match category {
+            Category::Received => Event::EmailReceived,
+            Category::Success => Event::EmailStored,
+            Category::Rejection => Event::EmailFailed,
+            Category::AttachmentStored => Event::AttachmentStored,
+            Category::AttachmentReceived => Event::AttachmentReceived,
+            Category::Alert => Event::AnomalyDetected,
+        };
+
+        Self {
+            event,
+            category,
+            reason: None,
+            mail_id: email.uuid,
+            message_id: email.message_id.clone(),
+            recipient: email.recipients.first().cloned().unwrap_or_default(),
+            sender: email.sender.clone(),
+            subject: email.subject.clone(),
+            auto_generated: email.auto_generated,
+            high_priority: email.priority.as_ref().map_or(false, |p| p.notify),
+            muted: email.directives.as_ref().and_then(|d| d.notify) == Some(false),
+            message,
+            files: Vec::new(),
+            attachment: None,
+            time: clock.now(),
+        }
+    }
+
+    pub fn received(email: &Email, message: String, clock: &dyn Clock) -> Self {
+        Self::new(email, Category::Received, message, clock)
+    }
+
+    pub fn success(email: &Email, message: String, clock: &dyn Clock) -> Self {
+        Self::new(email, Category::Success, message, clock)
+    }
+
+    pub fn rejection(email: &Email, reason: Reason, message: String, clock: &dyn Clock) -> Self {
+        let event = match reason {
+            Reason::QuotaExceeded => Event::QuotaExceeded,
+            _ => Event::EmailFailed,
+        };
+
+        Self {
+            event,
+            reason: Some(reason),
+            ..Self::new(email, Category::Rejection, message, clock)
+        }
+    }
+
+    pub fn attachment_stored(
+        email: &Email,
+        file: StoredFile,
+        message: String,
+        clock: &dyn Clock,
+    ) -> Self {
+        Self::new(email, Category::AttachmentStored, message, clock).with_files(vec![file])
+    }
+
+    /// An attachment was read in full, before it is uploaded
+    ///
+    /// Sent as soon as the attachment matches its declared size, so that
+    /// receivers can act on it without waiting for the storage backend.
+    pub fn attachment_received(
+        email: &Email,
+        attachment: ReceivedAttachment,
+        message: String,
+        clock: &dyn Clock,
+    ) -> Self {
+        let file = StoredFile {
+            name: attachment.name.clone(),
+            url: None,
+        };
+
+        Self {
+            attachment: Some(attachment),
+            ..Self::new(email, Category::AttachmentReceived, message, clock)
+        }
+        .with_files(vec![file])
+    }
+
+    /// Alert about an address, rather than a single email
+    ///
+    /// The mail ID is nil and the sender is empty.
+    pub fn alert(address: &str, message: String, clock: &dyn Clock) -> Self {
+        let email = Email {
+            recipients: vec![address.to_string()],
+            ..Default::default()
+        };
+
+        Self::new(&email, Category::Alert, message, clock)
+    }
+
+    pub fn with_files(self, files: Vec<StoredFile>) -> Self {
+        Self { files, ..self }
+    }
+
+    /// Render a message template for this notification
+    ///
+    /// Supported placeholders: `{sender}`, `{recipient}`, `{subject}`,
+    /// `{reason}`, `{message}`, `{email_id}`, `{num_files}`, and `{files}`
+    /// (one link per line). Values are escaped for the given notifier.
+    ///
+    /// The template is rendered in a single pass, so that placeholders in
+    /// values (e.g., a subject of "{files}") are kept as is.
+    pub fn render<N: Notifier + ?Sized>(&self, template: &str, notifier: &N) -> String {
+        let sender = if self.sender.is_empty() {
+            "unknown sender"
+        } else {
+            &self.sender
+        };
+
+        let files = self
+            .files
+            .iter()
+            .map(|f| match &f.url {
+                Some(url) => notifier.link(&notifier.escape(&f.name), url),
+                None => notifier.escape(&f.name),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+
+            match &rest[1..end] {
+                "sender" => out.push_str(&notifier.escape(sender)),
+                "recipient" => out.push_str(&notifier.escape(&self.recipient)),
+                "subject" => out
+                    .push_str(&notifier.escape(self.subject.as_deref().unwrap_or("(no subject)"))),
+                "reason" => out.push_str(self.reason.map(Reason::description).unwrap_or("")),
+                "message" => out.push_str(&notifier.escape(&self.message)),
+                "email_id" => out.push_str(&self.mail_id.to_string()),
+                "num_files" => out.push_str(&self.files.len().to_string()),
+                "files" => out.push_str(&files),
+                _ => out.push_str(&rest[..=end]),
+            }
+
+            rest = &rest[end + 1..];
+        }
+        out.push_str(rest);
+
+        out
+    }
+
+    /// Webhook request body for the given format
+    pub fn payload(&self, format: Format, template: Option<&str>) -> serde_json::Value {
+        match format.notifier() {
+            Some(notifier) => notifier.payload(self, template),
+            None => serde_json::to_value(self).unwrap_or_default(),
+        }
+    }
+}
+
+/// Renders human-readable notifications for a chat service
+pub trait Notifier: Send + Sync {
+    /// Default message template for a category
+    fn template(&self, category: Category) -> &'static str;
+
+    /// Escape a value before it is inserted into a message
+    fn escape(&self, s: &str) -> String;
+
+    /// Format a link to a stored file
+    fn link(&self, name: &str, url: &str) -> String;
+
+    /// Request body for a rendered message
+    fn body(&self, message: String) -> serde_json::Value;
+
+    fn payload(&self, notification: &Notification, template: Option<&str>) -> serde_json::Value {
+        let template = template.unwrap_or_else(|| self.template(notification.category));
+        let message = notification.render(template, self);
+
+        if notification.high_priority {
+            self.body(format!("[High priority] {}", message))
+        } else {
+            self.body(message)
+        }
+    }
+}
+
+/// Truncate a message to `max_len` characters, ending with an ellipsis
+fn truncate(s: String, max_len: usize) -> String {
+    if s.chars().count() <= max_len {
+        return s;
+    }
+
+    let mut truncated: String = s.chars().take(max_len.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Signature of a webhook payload, as sent in the signature header
+///
+/// The signature is an HMAC-SHA256 of `{timestamp}.{body}`, so receivers can
+/// reject replayed payloads based on the timestamp header.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.input(format!("{}.{}", timestamp, body).as_bytes());
+
+    format!("sha256={}", hex::encode(mac.result().code().as_slice()))
+}
+
+/// Send a notification to a single webhook
+///
+/// Failures that may succeed on retry (e.g., timeouts, 5xx responses) are
+/// returned as `Error::Temporary`.
+pub async fn send(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    notification: &Notification,
+) -> Result<(), Error> {
+    if !webhook.url.starts_with("https://") {
+        return Err(Error::Generic(format!(
+            "Webhook {} does not use HTTPS",
+            webhook.url
+        )));
+    }
+
+    let body = notification
+        .payload(webhook.format, webhook.template.as_deref())
+        .to_string();
+
+    let mut req = client
+        .post(&webhook.url)
+        .header(CONTENT_TYPE, "application/json");
+
+    if let Some(secret) = &webhook.secret {
+        let timestamp = notification.time.timestamp();
+
+        req = req
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(SIGNATURE_HEADER, sign(secret, timestamp, &body));
+    }
+
+    req.body(body)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|e| {
+            let msg = format!("Webhook {} failed: {}", webhook.url, e);
+            let is_retryable = e.is_timeout()
+                || e.status().map_or(true, |s| {
+                    s.is_server_error() || s == StatusCode::TOO_MANY_REQUESTS
+                });
+
+            if is_retryable {
+                Error::Temporary(msg)
+            } else {
+                Error::Generic(msg)
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Send a notification to a single webhook, retrying temporary failures
+/// with exponential backoff
+pub async fn send_with_retry(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    notification: &Notification,
+) -> Result<(), Error> {
+    let mut delay = Duration::from_millis(WEBHOOK_RETRY_DELAY_MS);
+    let mut attempt = 1;
+
+    loop {
+        match send(client, webhook, notification).await {
+            Err(Error::Temporary(msg)) if attempt < WEBHOOK_MAX_ATTEMPTS => {
+                log::info!(
+                    "{} (attempt {} of {}); retrying in {:?}",
+                    msg,
+                    attempt,
+                    WEBHOOK_MAX_ATTEMPTS,
+                    delay
+                );
+
+                tokio::time::delay_for(delay).await;
+
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Send a notification to every webhook that accepts it
+///
+/// Returns the errors of any webhooks that failed.
+pub async fn send_all(webhooks: &[Webhook], notification: &Notification) -> Vec<Error> {
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(WEBHOOK_TIMEOUT))
+        .build()
+        .unwrap_or_default();
+
+    let mut errors = Vec::new();
+
+    for webhook in webhooks.iter().filter(|w| w.accepts(notification)) {
+        if let Err(e) = send_with_retry(&client, webhook, notification).await {
+            errors.push(e);
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::email::content_hash;
+
+    pub fn email() -> Email {
+        Email {
+            sender: "billing@example.com".to_string(),
+            recipients: vec!["invoices@vaulty.net".to_string()],
+            subject: Some("Invoice #42".to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn clock() -> FixedClock {
+        FixedClock::new("2020-02-09T19:38:12Z".parse().unwrap())
+    }
+
+    #[test]
+    fn json_payload() {
+        let n = Notification::rejection(
+            &email(),
+            Reason::QuotaExceeded,
+            "Address invoices@vaulty.net has hit its quota of 100 emails for this period."
+                .to_string(),
+            &clock(),
+        );
+
+        let json = n.payload(Format::Json, Some("ignored"));
+        assert_eq!(json["category"], "rejection");
+        assert_eq!(json["reason"], "quota_exceeded");
+        assert_eq!(json["sender"], "billing@example.com");
+    }
+
+    #[test]
+    fn custom_template() {
+        let n = Notification::rejection(
+            &email(),
+            Reason::SenderNotWhitelisted,
+            "".to_string(),
+            &clock(),
+        );
+
+        let payload = n.payload(
+            Format::Slack,
+            Some("{recipient} rejected {sender}: {reason}"),
+        );
+        assert_eq!(
+            payload["text"],
+            "invoices@vaulty.net rejected billing@example.com: sender not whitelisted"
+        );
+
+        // Placeholders in values are not expanded
+        let mut email = email();
+        email.sender = "{recipient}".to_string();
+        email.subject = Some("{message} {unknown}".to_string());
+        let n = Notification::rejection(
+            &email,
+            Reason::SenderNotWhitelisted,
+            "secret".to_string(),
+            &clock(),
+        );
+
+        let payload = n.payload(Format::Slack, Some("{sender}: {subject} {"));
+        assert_eq!(payload["text"], "{recipient}: {message} {unknown} {");
+    }
+
+    #[test]
+    fn webhook_categories() {
+        let webhook = Webhook {
+            url: "https://hooks.slack.com/services/T0/B0/X".to_string(),
+            format: Format::from("slack"),
+            on_received: false,
+            on_success: false,
+            on_rejection: true,
+            on_attachment: false,
+            on_attachment_received: false,
+            on_alert: false,
+            secret: None,
+            template: None,
+        };
+
+        assert_eq!(webhook.format, Format::Slack);
+        assert!(!webhook.wants(Category::Received));
+        assert!(!webhook.wants(Category::Success));
+        assert!(webhook.wants(Category::Rejection));
+        assert!(!webhook.wants(Category::AttachmentStored));
+        assert!(!webhook.wants(Category::Alert));
+    }
+
+    #[test]
+    fn attachment_received() {
+        let attachment = ReceivedAttachment {
+            name: "invoice.pdf".to_string(),
+            size: 1024,
+            sha256: content_hash(b"%PDF-"),
+        };
+        let n = Notification::attachment_received(&email(), attachment, "".to_string(), &clock());
+
+        let json = n.payload(Format::Json, None);
+        assert_eq!(json["event"], "attachment_received");
+        assert_eq!(json["attachment"]["size"], 1024);
+        assert_eq!(json["attachment"]["sha256"], content_hash(b"%PDF-"));
+
+        // Only included when an attachment is received
+        let json =
+            Notification::received(&email(), "".to_string(), &clock()).payload(Format::Json, None);
+        assert!(json.get("attachment").is_none());
+
+        let payload = n.payload(Format::Slack, None);
+        assert_eq!(
+            payload["text"],
+            ":inbox_tray: Received invoice.pdf from billing@example.com to invoices@vaulty.net: *Invoice #42*"
+        );
+    }
+
+    #[test]
+    fn high_priority() {
+        let webhook = Webhook {
+            url: "https://example.com/hook".to_string(),
+            format: Format::Slack,
+            on_received: false,
+            on_success: true,
+            on_rejection: false,
+            on_attachment: false,
+            on_attachment_received: false,
+            on_alert: false,
+            secret: None,
+            template: None,
+        };
+
+        let received = Notification::received(&email(), "".to_string(), &clock());
+        assert!(!received.high_priority);
+        assert!(!webhook.accepts(&received));
+
+        let email = Email {
+            priority: Some(crate::rules::Priority {
+                folder: None,
+                notify: true,
+            }),
+            ..email()
+        };
+        let received = Notification::received(&email, "".to_string(), &clock());
+        assert!(webhook.accepts(&received));
+
+        let payload = received.payload(Format::Slack, Some("{subject}"));
+        assert_eq!(payload["text"], "[High priority] Invoice #42");
+
+        let json = received.payload(Format::Json, None);
+        assert_eq!(json["high_priority"], true);
+    }
+
+    #[test]
+    fn events() {
+        let rejection =
+            |reason| Notification::rejection(&email(), reason, "".to_string(), &clock());
+
+        assert_eq!(rejection(Reason::QuotaExceeded).event, Event::QuotaExceeded);
+        assert_eq!(rejection(Reason::StorageError).event, Event::EmailFailed);
+
+        let file = StoredFile {
+            name: "invoice.pdf".to_string(),
+            url: None,
+        };
+        let n = Notification::attachment_stored(&email(), file, "".to_string(), &clock());
+
+        let json = n.payload(Format::Json, None);
+        assert_eq!(json["event"], "attachment_stored");
+        assert_eq!(json["files"][0]["name"], "invoice.pdf");
+
+        let n = Notification::alert(
+            "invoices@vaulty.net",
+            "500 emails received today, against a daily average of 10.0".to_string(),
+            &clock(),
+        );
+
+        let json = n.payload(Format::Json, None);
+        assert_eq!(json["event"], "anomaly_detected");
+        assert_eq!(json["category"], "alert");
+        assert_eq!(json["recipient"], "invoices@vaulty.net");
+        assert!(n.mail_id.is_nil());
+    }
+
+    #[test]
+    fn signature() {
+        let body = r#"{"event":"email_stored"}"#;
+        let signature = sign("secret", 1581277092, body);
+
+        assert!(signature.starts_with("sha256="));
+        assert_eq!(signature.len(), "sha256=".len() + 64);
+        assert_eq!(signature, sign("secret", 1581277092, body));
+        assert_ne!(signature, sign("secret", 1581277093, body));
+        assert_ne!(signature, sign("other", 1581277092, body));
+    }
+
+    #[test]
+    fn truncate_message() {
+        assert_eq!(truncate("abc".to_string(), 3), "abc");
+        assert_eq!(truncate("abcd".to_string(), 3), "ab…");
+    }
+}
@@ -0,0 +1,98 @@
+use super::{truncate, Category, Notifier};
+
+/// Slack recommends keeping messages under this many characters
+const MAX_MESSAGE_LEN: usize = 4000;
+
+/// Posts messages to a Slack incoming webhook
+///
+/// Messages use Slack's `mrkdwn` formatting.
+pub struct Slack;
+
+impl Notifier for Slack {
+    fn template(&self, category: Category) -> &'static str {
+        match category {
+            Category::Received => {
+                ":incoming_envelope: Email from {sender} to {recipient} received: *{subject}*"
+            }
+            Category::Success => {
+                ":white_check_mark: Stored {num_files} file(s) from {sender} to {recipient}: \
+                 *{subject}*\n{files}"
+            }
+            Category::Rejection => {
+                ":x: Email from {sender} to {recipient} was rejected ({reason}): \
+                 *{subject}*\n{message}"
+            }
+            Category::AttachmentStored => {
+                ":paperclip: Stored {files} from {sender} to {recipient}: *{subject}*"
+            }
+            Category::AttachmentReceived => {
+                ":inbox_tray: Received {files} from {sender} to {recipient}: *{subject}*"
+            }
+            Category::Alert => ":warning: Unusual activity on {recipient}: {message}",
+        }
+    }
+
+    /// Only `&`, `<`, and `>` need to be escaped
+    fn escape(&self, s: &str) -> String {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+
+    fn link(&self, name: &str, url: &str) -> String {
+        format!("<{}|{}>", url, name)
+    }
+
+    fn body(&self, message: String) -> serde_json::Value {
+        serde_json::json!({ "text": truncate(message, MAX_MESSAGE_LEN) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{clock, email};
+    use super::super::{Format, Notification, Reason, StoredFile};
+
+    #[test]
+    fn rejection() {
+        let n = Notification::rejection(
+            &email(),
+            Reason::QuotaExceeded,
+            "Address invoices@vaulty.net has hit its quota of 100 emails for this period."
+                .to_string(),
+            &clock(),
+        );
+
+        assert_eq!(
+            n.payload(Format::Slack, None)["text"],
+            ":x: Email from billing@example.com to invoices@vaulty.net was rejected \
+             (quota exceeded): *Invoice #42*\n\
+             Address invoices@vaulty.net has hit its quota of 100 emails for this period."
+        );
+    }
+
+    #[test]
+    fn success_with_links() {
+        let mut mail = email();
+        mail.subject = Some("<Q1 & Q2>".to_string());
+
+        let n = Notification::success(&mail, "".to_string(), &clock()).with_files(vec![
+            StoredFile {
+                name: "invoice.pdf".to_string(),
+                url: Some("https://www.dropbox.com/home/vaulty/invoice.pdf".to_string()),
+            },
+            StoredFile {
+                name: "notes.txt".to_string(),
+                url: None,
+            },
+        ]);
+
+        assert_eq!(
+            n.payload(Format::Slack, None)["text"],
+            ":white_check_mark: Stored 2 file(s) from billing@example.com to invoices@vaulty.net: \
+             *&lt;Q1 &amp; Q2&gt;*\n\
+             <https://www.dropbox.com/home/vaulty/invoice.pdf|invoice.pdf>\n\
+             notes.txt"
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::Error;
+
+pub type RenderFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>, Error>> + Send + 'a>>;
+
+/// PDF files start with this
+const PDF_MAGIC: &[u8] = b"%PDF-";
+
+/// Something that turns an HTML document into a PDF
+///
+/// HTML bodies come from untrusted senders, so renderers must not run
+/// scripts, read local files, or fetch remote resources.
+pub trait Renderer: Send + Sync {
+    fn render<'a>(&'a self, html: &'a str) -> RenderFuture<'a>;
+}
+
+/// Render with a `wkhtmltopdf` subprocess
+pub struct Wkhtmltopdf {
+    path: String,
+    timeout: Duration,
+}
+
+impl Wkhtmltopdf {
+    pub fn new(path: &str, timeout: Duration) -> Self {
+        Self {
+            path: path.to_string(),
+            timeout,
+        }
+    }
+
+    fn args() -> &'static [&'static str] {
+        &[
+            "--quiet",
+            "--disable-javascript",
+            "--disable-local-file-access",
+            // Remote images and stylesheets (e.g., tracking pixels) are not
+            // fetched: all requests go to a closed port
+            "--proxy",
+            "http://127.0.0.1:1",
+            "--load-error-handling",
+            "ignore",
+            "--load-media-error-handling",
+            "ignore",
+            // Read from stdin, write to stdout
+            "-",
+            "-",
+        ]
+    }
+
+    async fn run(&self, html: &str) -> Result<Vec<u8>, Error> {
+        let mut child = Command::new(&self.path)
+            .args(Self::args())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| Error::internal(&format!("Failed to run {}", self.path), e))?;
+
+        // The body is written while the output is read, so that neither
+        // side blocks on a full pipe. Write errors only happen if the
+        // renderer exits early, which its output already tells.
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let input = html.as_bytes().to_vec();
+        let write = async move {
+            let _ = stdin.write_all(&input).await;
+        };
+
+        let (_, output) = futures::join!(write, child.wait_with_output());
+        let output =
+            output.map_err(|e| Error::internal(&format!("Failed to run {}", self.path), e))?;
+
+        // wkhtmltopdf exits with an error if some resources failed to load,
+        // even if the PDF was rendered
+        if output.stdout.starts_with(PDF_MAGIC) {
+            return Ok(output.stdout);
+        }
+
+        Err(Error::Generic(format!(
+            "Renderer did not produce a PDF ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+impl Renderer for Wkhtmltopdf {
+    fn render<'a>(&'a self, html: &'a str) -> RenderFuture<'a> {
+        Box::pin(async move {
+            // The process is killed when the timeout drops it
+            tokio::time::timeout(self.timeout, self.run(html))
+                .await
+                .map_err(|_| {
+                    Error::Temporary(format!(
+                        "Rendering took longer than {} seconds",
+                        self.timeout.as_secs()
+                    ))
+                })?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn render_failures() {
+        let timeout = Duration::from_secs(5);
+
+        let missing = Wkhtmltopdf::new("/nonexistent/wkhtmltopdf", timeout);
+        let err = missing.render("<p>Hi</p>").await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Failed to run /nonexistent/wkhtmltopdf: "));
+
+        // Anything that does not look like a PDF is rejected
+        let echo = Wkhtmltopdf::new("echo", timeout);
+        let err = echo.render("<p>Hi</p>").await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Renderer did not produce a PDF"));
+    }
+}
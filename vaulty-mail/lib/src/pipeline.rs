@@ -0,0 +1,500 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::stream::{self, Stream, TryStreamExt};
+use image::GenericImageView;
+
+use crate::email::Email;
+use crate::filename;
+use crate::Error;
+
+/// Content of an attachment, streamed through the pipeline
+pub type Data = Pin<Box<dyn Stream<Item = Result<Bytes, Error>> + Send + Sync>>;
+
+pub type StageFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Option<Attachment>, Error>> + Send + 'a>>;
+
+/// An attachment on its way to storage
+pub struct Attachment {
+    /// Name the attachment is stored under, relative to the email folder
+    pub name: String,
+
+    /// Size as received, in bytes
+    pub size: usize,
+
+    pub data: Data,
+
+    /// Files made from the attachment by stages (e.g., thumbnails), stored
+    /// next to it
+    pub derived: Vec<Derived>,
+}
+
+/// A file made from an attachment, such as a downscaled copy of an image
+pub struct Derived {
+    /// What the file is (e.g., "thumbnail"), added to the name of the
+    /// attachment
+    pub label: String,
+
+    /// Extension of the file, which may differ from that of the attachment
+    pub extension: String,
+
+    pub data: Bytes,
+}
+
+impl Derived {
+    /// Name of the file, given the name the attachment is stored under
+    /// (e.g., "a/photo.png" becomes "a/photo (thumbnail).jpg")
+    pub fn name(&self, attachment_name: &str) -> String {
+        let (dir, file) = match attachment_name.rfind('/') {
+            Some(i) => attachment_name.split_at(i + 1),
+            None => ("", attachment_name),
+        };
+        let stem = match file.rfind('.') {
+            Some(i) if i > 0 => &file[..i],
+            _ => file,
+        };
+
+        format!("{}{} ({}).{}", dir, stem, self.label, self.extension)
+    }
+}
+
+/// What a stage knows about the email an attachment belongs to
+pub struct Context<'a> {
+    pub email: &'a Email,
+
+    /// Handling date (e.g., "2020-04-01")
+    pub date: &'a str,
+}
+
+/// A single processing step that attachments go through before they are
+/// stored (e.g., renaming, compression, or OCR)
+///
+/// A stage returns the attachment to pass on to the next stage, or `None`
+/// to drop it. The content hash recorded for an attachment is that of the
+/// attachment as received, so stages that change the content of attachments
+/// should also change their name.
+pub trait Stage: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn process<'a>(&'a self, ctx: &'a Context<'a>, attachment: Attachment) -> StageFuture<'a>;
+}
+
+/// Result of running an attachment through the pipeline
+#[derive(Clone, Debug, PartialEq)]
+pub enum Processed {
+    /// Stored under this name
+    Stored(String),
+    /// Dropped by this stage
+    Dropped(String),
+}
+
+/// Ordered list of stages, configured per address
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    stages: Vec<Arc<dyn Stage>>,
+}
+
+impl Pipeline {
+    /// Build a pipeline from the names of built-in stages
+    ///
+    /// Unknown stages are skipped, so that an address is never left unable
+    /// to store email.
+    pub fn from_names(names: &[String]) -> Self {
+        let stages = names
+            .iter()
+            .filter_map(|name| {
+                let stage = builtin(name.trim());
+                if stage.is_none() {
+                    log::error!("Unknown pipeline stage: {}", name);
+                }
+                stage
+            })
+            .collect();
+
+        Self { stages }
+    }
+
+    /// Append a stage, which runs after all existing ones
+    pub fn with_stage(mut self, stage: Arc<dyn Stage>) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Run an attachment through each stage in order
+    ///
+    /// Returns the attachment to store, or the name of the stage that
+    /// dropped it.
+    pub async fn run(
+        &self,
+        ctx: &Context<'_>,
+        mut attachment: Attachment,
+    ) -> Result<Result<Attachment, String>, Error> {
+        for stage in &self.stages {
+            attachment = match stage.process(ctx, attachment).await? {
+                Some(attachment) => attachment,
+                None => return Ok(Err(stage.name().to_string())),
+            };
+        }
+
+        Ok(Ok(attachment))
+    }
+}
+
+/// Built-in stage with the given name, if any
+pub fn builtin(name: &str) -> Option<Arc<dyn Stage>> {
+    match name {
+        DatePrefix::NAME => Some(Arc::new(DatePrefix)),
+        SenderFolder::NAME => Some(Arc::new(SenderFolder)),
+        SkipEmpty::NAME => Some(Arc::new(SkipEmpty)),
+        Resize::THUMBNAIL => Some(Arc::new(Resize::thumbnail())),
+        Resize::DOWNSCALE => Some(Arc::new(Resize::downscale())),
+        _ => None,
+    }
+}
+
+/// Prefix attachment names with the handling date (e.g., "2020-04-01
+/// invoice.pdf"), so that they sort by date
+pub struct DatePrefix;
+
+impl DatePrefix {
+    pub const NAME: &'static str = "date_prefix";
+}
+
+impl Stage for DatePrefix {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn process<'a>(&'a self, ctx: &'a Context<'a>, attachment: Attachment) -> StageFuture<'a> {
+        Box::pin(async move {
+            // Only the file name is prefixed, if an earlier stage put the
+            // attachment in a subfolder
+            let name = match attachment.name.rfind('/') {
+                Some(i) => format!(
+                    "{}{} {}",
+                    &attachment.name[..=i],
+                    ctx.date,
+                    &attachment.name[i + 1..]
+                ),
+                None => format!("{} {}", ctx.date, attachment.name),
+            };
+
+            Ok(Some(Attachment { name, ..attachment }))
+        })
+    }
+}
+
+/// Store attachments in a subfolder named after the sender of the email
+pub struct SenderFolder;
+
+impl SenderFolder {
+    pub const NAME: &'static str = "sender_folder";
+}
+
+impl Stage for SenderFolder {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn process<'a>(&'a self, ctx: &'a Context<'a>, attachment: Attachment) -> StageFuture<'a> {
+        Box::pin(async move {
+            let folder = filename::normalize(&ctx.email.sender, false);
+
+            Ok(Some(Attachment {
+                name: format!("{}/{}", folder, attachment.name),
+                ..attachment
+            }))
+        })
+    }
+}
+
+/// Drop empty attachments
+pub struct SkipEmpty;
+
+impl SkipEmpty {
+    pub const NAME: &'static str = "skip_empty";
+}
+
+impl Stage for SkipEmpty {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn process<'a>(&'a self, _ctx: &'a Context<'a>, attachment: Attachment) -> StageFuture<'a> {
+        Box::pin(async move { Ok(Some(attachment).filter(|a| a.size > 0)) })
+    }
+}
+
+/// Store a downscaled copy of JPEG and PNG images next to the original
+///
+/// Copies fit in a square of the given size and are JPEG encoded. Images
+/// that already fit are not copied. Other attachments, and images that
+/// cannot be decoded (e.g., HEIC, which has no pure Rust decoder), are
+/// passed on unchanged. Images are buffered in memory to be decoded, so
+/// images of more than `MAX_PIXELS` pixels are not resized.
+pub struct Resize {
+    name: &'static str,
+    max_size: u32,
+}
+
+impl Resize {
+    pub const THUMBNAIL: &'static str = "thumbnail";
+    pub const DOWNSCALE: &'static str = "downscale";
+
+    /// Extensions of the images that are resized
+    const EXTENSIONS: &'static [&'static str] = &["jpg", "jpeg", "png"];
+
+    /// Quality of the JPEG copies, from 1 to 100
+    const QUALITY: u8 = 85;
+
+    /// Largest image that is decoded, in pixels (i.e., 50 megapixels, or
+    /// 200 MB decoded)
+    const MAX_PIXELS: u64 = 50_000_000;
+
+    /// Small preview of images (e.g., "photo (thumbnail).jpg")
+    pub fn thumbnail() -> Self {
+        Self {
+            name: Self::THUMBNAIL,
+            max_size: 256,
+        }
+    }
+
+    /// Copy of images small enough to view on a phone (e.g., "photo
+    /// (downscale).jpg")
+    pub fn downscale() -> Self {
+        Self {
+            name: Self::DOWNSCALE,
+            max_size: 2048,
+        }
+    }
+
+    fn is_image(name: &str) -> bool {
+        match name.rsplit('.').next() {
+            Some(ext) if ext.len() < name.len() => {
+                Self::EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(ext))
+            }
+            _ => false,
+        }
+    }
+
+    /// Downscaled JPEG copy of an image, if it is larger than `max_size`
+    ///
+    /// The dimensions are read from the header first, so that images of
+    /// more than `max_pixels` pixels are never decoded.
+    fn resize(
+        data: &[u8],
+        max_size: u32,
+        max_pixels: u64,
+    ) -> Result<Option<Vec<u8>>, image::ImageError> {
+        let (width, height) = image::io::Reader::new(std::io::Cursor::new(data))
+            .with_guessed_format()?
+            .into_dimensions()?;
+
+        if width as u64 * height as u64 > max_pixels {
+            return Err(image::ImageError::Limits(
+                image::error::LimitError::from_kind(image::error::LimitErrorKind::DimensionError),
+            ));
+        }
+
+        let image = image::load_from_memory(data)?;
+
+        if image.width() <= max_size && image.height() <= max_size {
+            return Ok(None);
+        }
+
+        // JPEG has no alpha channel
+        let resized = image::DynamicImage::ImageRgb8(image.thumbnail(max_size, max_size).to_rgb8());
+
+        let mut out = Vec::new();
+        resized.write_to(&mut out, image::ImageOutputFormat::Jpeg(Self::QUALITY))?;
+
+        Ok(Some(out))
+    }
+}
+
+impl Stage for Resize {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn process<'a>(&'a self, ctx: &'a Context<'a>, attachment: Attachment) -> StageFuture<'a> {
+        Box::pin(async move {
+            if !Self::is_image(&attachment.name) {
+                return Ok(Some(attachment));
+            }
+
+            let data = attachment
+                .data
+                .try_fold(Vec::new(), |mut buf, chunk| async move {
+                    buf.extend_from_slice(&chunk);
+                    Ok(buf)
+                })
+                .await?;
+            let data = Bytes::from(data);
+
+            // Decoding is CPU-bound, so keep it off the async workers
+            let image = data.clone();
+            let max_size = self.max_size;
+            let resized = tokio::task::spawn_blocking(move || {
+                Self::resize(&image, max_size, Self::MAX_PIXELS)
+            })
+            .await
+            .map_err(|e| Error::Generic(format!("Failed to resize image: {}", e)))?;
+
+            let mut derived = attachment.derived;
+            match resized {
+                Ok(Some(resized)) => derived.push(Derived {
+                    label: self.name.to_string(),
+                    extension: "jpg".to_string(),
+                    data: Bytes::from(resized),
+                }),
+                Ok(None) => (),
+                Err(e) => log::warn!(
+                    "Not resizing attachment {} of mail for {}: {}",
+                    attachment.name,
+                    ctx.email.recipients.first().map_or("", |r| r.as_str()),
+                    e
+                ),
+            }
+
+            Ok(Some(Attachment {
+                name: attachment.name,
+                size: attachment.size,
+                data: Box::pin(stream::iter(vec![Ok(data)])),
+                derived,
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attachment(name: &str, size: usize) -> Attachment {
+        Attachment {
+            name: name.to_string(),
+            size,
+            data: Box::pin(stream::iter(vec![Ok(Bytes::from(vec![0; size]))])),
+            derived: Vec::new(),
+        }
+    }
+
+    /// Uppercases attachment names, as an example of a custom stage
+    struct Uppercase;
+
+    impl Stage for Uppercase {
+        fn name(&self) -> &str {
+            "uppercase"
+        }
+
+        fn process<'a>(&'a self, _ctx: &'a Context<'a>, attachment: Attachment) -> StageFuture<'a> {
+            Box::pin(async move {
+                Ok(Some(Attachment {
+                    name: attachment.name.to_uppercase(),
+                    ..attachment
+                }))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn run_stages() {
+        let email = Email {
+            sender: "a@example.com".to_string(),
+            ..Default::default()
+        };
+        let ctx = Context {
+            email: &email,
+            date: "2020-04-01",
+        };
+
+        let names = vec![
+            "sender_folder".to_string(),
+            "unknown".to_string(),
+            "skip_empty".to_string(),
+            "date_prefix".to_string(),
+        ];
+        let pipeline = Pipeline::from_names(&names).with_stage(Arc::new(Uppercase));
+
+        let stored = pipeline.run(&ctx, attachment("invoice.pdf", 10)).await;
+        let stored = stored.unwrap().ok().unwrap();
+        assert_eq!(
+            stored.name,
+            format!(
+                "{}/2020-04-01 INVOICE.PDF",
+                filename::normalize("a@example.com", false).to_uppercase()
+            )
+        );
+
+        let dropped = pipeline.run(&ctx, attachment("empty.txt", 0)).await;
+        assert_eq!(dropped.unwrap().err(), Some("skip_empty".to_string()));
+
+        assert!(Pipeline::default().is_empty());
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = image::DynamicImage::new_rgba8(width, height);
+        let mut out = Vec::new();
+        image
+            .write_to(&mut out, image::ImageOutputFormat::Png)
+            .unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn resize_images() {
+        let email = Email::default();
+        let ctx = Context {
+            email: &email,
+            date: "2020-04-01",
+        };
+        let stage = Resize::thumbnail();
+
+        let file = |name: &str, data: Vec<u8>| Attachment {
+            name: name.to_string(),
+            size: data.len(),
+            data: Box::pin(stream::iter(vec![Ok(Bytes::from(data))])),
+            derived: Vec::new(),
+        };
+
+        let original = png(600, 300);
+        let resized = stage
+            .process(&ctx, file("a/photo.PNG", original.clone()))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The original is passed on as is
+        let data: Vec<Bytes> = resized.data.try_collect().await.unwrap();
+        assert_eq!(data.concat(), original);
+
+        assert_eq!(resized.derived.len(), 1);
+        let thumbnail = &resized.derived[0];
+        assert_eq!(thumbnail.name("a/photo.PNG"), "a/photo (thumbnail).jpg");
+
+        let thumbnail = image::load_from_memory(&thumbnail.data).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (256, 128));
+
+        // Small images, other files, and images that cannot be decoded are
+        // not resized
+        for (name, data) in [
+            ("small.png", png(100, 100)),
+            ("notes.txt", b"notes".to_vec()),
+            ("broken.jpg", b"not an image".to_vec()),
+        ] {
+            let processed = stage.process(&ctx, file(name, data)).await.unwrap();
+            assert!(processed.unwrap().derived.is_empty());
+        }
+
+        // Images with too many pixels are not decoded
+        assert!(Resize::resize(&png(600, 300), 256, 600 * 300).is_ok());
+        assert!(Resize::resize(&png(600, 300), 256, 600 * 300 - 1).is_err());
+    }
+}
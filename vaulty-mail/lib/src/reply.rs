@@ -0,0 +1,242 @@
+use lettre::smtp::extension::ClientId;
+use lettre::smtp::ClientSecurity;
+use lettre::{SendableEmail, SmtpClient, Transport};
+use lettre_email::Email;
+
+use crate::config::Config;
+use crate::email::LOOP_HEADER;
+use crate::notify::{Category, Notification, Notifier, Reason};
+use crate::settings::Settings;
+use crate::Error;
+
+/// Renders replies to senders as plain text
+///
+/// Files are listed by name only, as links point to the recipient's storage.
+pub struct PlainText;
+
+impl Notifier for PlainText {
+    fn template(&self, category: Category) -> &'static str {
+        match category {
+            Category::Received
+            | Category::Success
+            | Category::AttachmentStored
+            | Category::AttachmentReceived => {
+                "Your email \"{subject}\" to {recipient} was received.\n\n\
+                 Files stored: {num_files}\n{files}\n"
+            }
+            Category::Rejection => {
+                "Your email \"{subject}\" to {recipient} was not stored: {reason}.\n"
+            }
+            Category::Alert => "{message}\n",
+        }
+    }
+
+    fn escape(&self, s: &str) -> String {
+        s.to_string()
+    }
+
+    fn link(&self, name: &str, _url: &str) -> String {
+        name.to_string()
+    }
+
+    fn body(&self, message: String) -> serde_json::Value {
+        serde_json::Value::String(message)
+    }
+}
+
+/// Returns true if the sender of an email should get a reply for this
+/// notification
+///
+/// Only rejections that the sender can act on are replied to.
+pub fn wants_reply(settings: &Settings, notification: &Notification) -> bool {
+    // Never reply to bounces or auto-generated email, as that could start a
+    // mail loop
+    if notification.sender.is_empty() || notification.auto_generated.is_some() {
+        return false;
+    }
+
+    match notification.category {
+        Category::Received
+        | Category::AttachmentStored
+        | Category::AttachmentReceived
+        | Category::Alert => false,
+        Category::Success => settings.reply_on_success,
+        Category::Rejection => {
+            settings.reply_on_rejection
+                && matches!(
+                    notification.reason,
+                    Some(Reason::QuotaExceeded) | Some(Reason::SenderNotWhitelisted)
+                )
+        }
+    }
+}
+
+/// Sends replies to senders over SMTP
+pub struct Mailer {
+    host: String,
+    port: u16,
+    from: String,
+}
+
+impl Mailer {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            host: config.smtp_host.clone(),
+            port: config.smtp_port,
+            from: config.reply_from.clone(),
+        }
+    }
+
+    /// Build the reply to the email a notification is about
+    ///
+    /// The template supports the same placeholders as webhook templates. See
+    /// `Notification::render`.
+    pub fn build(
+        &self,
+        notification: &Notification,
+        template: Option<&str>,
+    ) -> Result<SendableEmail, Error> {
+        let template = template.unwrap_or_else(|| PlainText.template(notification.category));
+        let body = notification.render(template, &PlainText);
+
+        // Mail clients thread replies on the Subject and In-Reply-To
+        let subject = match &notification.subject {
+            Some(subject) => format!("Re: {}", subject),
+            None => format!("Your email to {}", notification.recipient),
+        };
+
+        let mut builder = Email::builder()
+            .to(notification.sender.clone())
+            .from(self.from.as_str())
+            .subject(subject)
+            // Tell auto-responders not to reply to us (RFC 3834)
+            .header(("Auto-Submitted", "auto-replied"))
+            .header((LOOP_HEADER, notification.mail_id.to_string()))
+            .text(body);
+
+        if let Some(message_id) = &notification.message_id {
+            let message_id = format!("<{}>", message_id);
+            builder = builder
+                .in_reply_to(message_id.clone())
+                .references(message_id);
+        }
+
+        let email = builder
+            .build()
+            .map_err(|e| Error::Generic(format!("Failed to build reply: {}", e)))?;
+
+        Ok(email.into())
+    }
+
+    /// Send a reply to the sender of the email a notification is about
+    ///
+    /// This blocks until the SMTP server accepts the reply.
+    pub fn send(&self, notification: &Notification, template: Option<&str>) -> Result<(), Error> {
+        let email = self.build(notification, template)?;
+
+        self.deliver(email)?;
+
+        log::info!(
+            "Sent {:?} reply to {} for email {}",
+            notification.category,
+            notification.sender,
+            notification.mail_id
+        );
+
+        Ok(())
+    }
+
+    /// Send an email via the configured SMTP server
+    ///
+    /// This blocks until the SMTP server accepts the email.
+    pub fn deliver(&self, email: SendableEmail) -> Result<(), Error> {
+        let mut transport = SmtpClient::new((self.host.as_str(), self.port), ClientSecurity::None)
+            .map_err(|e| Error::Generic(format!("Failed to connect to SMTP server: {}", e)))?
+            .hello_name(ClientId::hostname())
+            .transport();
+
+        transport
+            .send(email)
+            .map_err(|e| Error::Generic(format!("Failed to send email: {}", e)))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::AutoGenerated;
+    use crate::notify::tests::{clock, email};
+    use crate::notify::StoredFile;
+
+    fn settings() -> Settings {
+        Settings {
+            reply_on_success: true,
+            reply_on_rejection: true,
+            ..Settings::from_config(&Config::default())
+        }
+    }
+
+    #[test]
+    fn default_templates() {
+        let n = Notification::success(&email(), "".to_string(), &clock()).with_files(vec![
+            StoredFile {
+                name: "invoice.pdf".to_string(),
+                url: Some("https://www.dropbox.com/home/invoice.pdf".to_string()),
+            },
+        ]);
+
+        assert_eq!(
+            n.render(PlainText.template(n.category), &PlainText),
+            "Your email \"Invoice #42\" to invoices@vaulty.net was received.\n\n\
+             Files stored: 1\ninvoice.pdf\n"
+        );
+
+        let n = Notification::rejection(&email(), Reason::QuotaExceeded, "".to_string(), &clock());
+
+        assert_eq!(
+            n.render(PlainText.template(n.category), &PlainText),
+            "Your email \"Invoice #42\" to invoices@vaulty.net was not stored: quota exceeded.\n"
+        );
+    }
+
+    #[test]
+    fn reply_categories() {
+        let settings = settings();
+        let rejection =
+            |reason| Notification::rejection(&email(), reason, "".to_string(), &clock());
+
+        assert!(wants_reply(&settings, &rejection(Reason::QuotaExceeded)));
+        assert!(wants_reply(
+            &settings,
+            &rejection(Reason::SenderNotWhitelisted)
+        ));
+        assert!(!wants_reply(&settings, &rejection(Reason::StorageError)));
+        assert!(!wants_reply(
+            &settings,
+            &Notification::received(&email(), "".to_string(), &clock())
+        ));
+
+        let settings = Settings {
+            reply_on_rejection: false,
+            ..settings
+        };
+        assert!(!wants_reply(&settings, &rejection(Reason::QuotaExceeded)));
+
+        // Bounces have no sender
+        let mut bounce = email();
+        bounce.sender = String::new();
+        assert!(!wants_reply(
+            &settings,
+            &Notification::success(&bounce, "".to_string(), &clock())
+        ));
+
+        let mut auto_reply = email();
+        auto_reply.auto_generated = Some(AutoGenerated::AutoSubmitted);
+        assert!(!wants_reply(
+            &settings,
+            &Notification::success(&auto_reply, "".to_string(), &clock())
+        ));
+    }
+}
@@ -0,0 +1,660 @@
+use serde::{Deserialize, Serialize};
+
+use crate::directive::Directives;
+use crate::email::{Email, Importance};
+use crate::storage::Backend;
+
+/// What to do with an attachment that matches a rule
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+impl From<&str> for Action {
+    fn from(s: &str) -> Self {
+        if s == "allow" {
+            Self::Allow
+        } else if s == "deny" {
+            Self::Deny
+        } else {
+            // Unknown rules should never let attachments through
+            log::error!("Unknown attachment rule action: {}", s);
+            Self::Deny
+        }
+    }
+}
+
+impl From<String> for Action {
+    fn from(s: String) -> Self {
+        s.as_str().into()
+    }
+}
+
+/// A single attachment filtering rule for an address
+///
+/// A rule matches an attachment if all of its criteria match. A rule with
+/// no criteria matches every attachment.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Rule {
+    pub action: Action,
+
+    /// File extension, without the dot (e.g., "pdf")
+    pub extension: Option<String>,
+
+    /// MIME type, either exact (e.g., "image/png") or a wildcard subtype
+    /// (e.g., "image/*")
+    pub mime_type: Option<String>,
+
+    /// Only match attachments larger than this, in bytes
+    pub larger_than: Option<i64>,
+}
+
+impl Rule {
+    pub fn matches(&self, name: &str, mime_type: &str, size: usize) -> bool {
+        criteria_match(
+            self.extension.as_deref(),
+            self.mime_type.as_deref(),
+            self.larger_than,
+            name,
+            mime_type,
+            size,
+        )
+    }
+
+    fn describe(&self) -> String {
+        let mut criteria = Vec::new();
+
+        if let Some(ext) = &self.extension {
+            criteria.push(format!("extension {}", ext));
+        }
+
+        if let Some(mime_type) = &self.mime_type {
+            criteria.push(format!("type {}", mime_type));
+        }
+
+        if let Some(larger_than) = self.larger_than {
+            criteria.push(format!("larger than {} bytes", larger_than));
+        }
+
+        if criteria.is_empty() {
+            "all attachments".to_string()
+        } else {
+            criteria.join(", ")
+        }
+    }
+}
+
+/// Check an attachment against the filtering rules for its address
+///
+/// Deny rules take precedence. If there are any allow rules, the attachment
+/// must match at least one of them. Returns the reason the attachment should
+/// be dropped, if any.
+pub fn check(rules: &[Rule], name: &str, mime_type: &str, size: usize) -> Option<String> {
+    let denied = rules
+        .iter()
+        .filter(|r| r.action == Action::Deny)
+        .find(|r| r.matches(name, mime_type, size));
+
+    if let Some(rule) = denied {
+        return Some(format!("denied by rule ({})", rule.describe()));
+    }
+
+    let mut allow_rules = rules
+        .iter()
+        .filter(|r| r.action == Action::Allow)
+        .peekable();
+
+    if allow_rules.peek().is_some() && !allow_rules.any(|r| r.matches(name, mime_type, size)) {
+        return Some("not allowed by any rule".to_string());
+    }
+
+    None
+}
+
+/// A rule that stores matching files of an address on another storage
+/// backend than the address (e.g., images on S3, PDFs on Dropbox)
+///
+/// A route matches a file if all of its criteria match, like attachment
+/// rules. A route with no criteria matches every file.
+#[derive(Clone, Debug, Serialize)]
+pub struct Route {
+    /// File extension, without the dot (e.g., "pdf")
+    pub extension: Option<String>,
+
+    /// MIME type, either exact (e.g., "image/png") or a wildcard subtype
+    /// (e.g., "image/*")
+    pub mime_type: Option<String>,
+
+    /// Only match files larger than this, in bytes
+    pub larger_than: Option<i64>,
+
+    /// Where matching files are stored, as for addresses
+    pub storage_backend: Backend,
+    #[serde(skip_serializing)]
+    pub storage_token: String,
+    pub storage_path: String,
+}
+
+impl Route {
+    pub fn matches(&self, name: &str, mime_type: &str, size: usize) -> bool {
+        criteria_match(
+            self.extension.as_deref(),
+            self.mime_type.as_deref(),
+            self.larger_than,
+            name,
+            mime_type,
+            size,
+        )
+    }
+}
+
+/// Name that stored email bodies are matched on by routes, as text/plain
+pub const BODY_NAME: &str = "body.txt";
+
+/// Find where a file of an address is stored
+///
+/// Routes are checked in order, and the first one that matches wins.
+/// Returns None if the file is stored on the address backend.
+pub fn route<'r>(
+    routes: &'r [Route],
+    name: &str,
+    mime_type: &str,
+    size: usize,
+) -> Option<&'r Route> {
+    routes.iter().find(|r| r.matches(name, mime_type, size))
+}
+
+/// How an email that matched a priority rule is handled
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Priority {
+    /// Subfolder of the address storage path to store the email in
+    pub folder: Option<String>,
+
+    /// Flag notifications about the email as high priority, and send them to
+    /// webhooks as soon as the email is received
+    pub notify: bool,
+}
+
+/// A rule that maps the importance or subject of emails sent to an address
+/// to a storage behavior
+///
+/// A rule matches an email if all of its criteria match. A rule with no
+/// criteria matches every email.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PriorityRule {
+    /// Importance set by the sender (see `Email::importance`)
+    pub importance: Option<Importance>,
+
+    /// Tag that the subject must contain, ignoring case (e.g., "[urgent]")
+    pub subject_tag: Option<String>,
+
+    pub priority: Priority,
+}
+
+impl PriorityRule {
+    pub fn matches(&self, email: &Email) -> bool {
+        let importance_matches = self
+            .importance
+            .map_or(true, |importance| email.importance == Some(importance));
+
+        let subject_matches = self.subject_tag.as_ref().map_or(true, |tag| {
+            email
+                .subject
+                .as_ref()
+                .map_or(false, |s| s.to_lowercase().contains(&tag.to_lowercase()))
+        });
+
+        importance_matches && subject_matches
+    }
+}
+
+/// Check an email against the priority rules for its address
+///
+/// Rules are checked in order, and the first one that matches wins.
+pub fn check_priority(rules: &[PriorityRule], email: &Email) -> Option<Priority> {
+    rules
+        .iter()
+        .find(|r| r.matches(email))
+        .map(|r| r.priority.clone())
+}
+
+/// Attachment of a synthetic email, used to test the rules of an address
+#[derive(Clone, Debug, Deserialize)]
+pub struct TestAttachment {
+    pub name: String,
+
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+
+    /// Size, in bytes
+    #[serde(default)]
+    pub size: usize,
+}
+
+fn default_content_type() -> String {
+    "application/octet-stream".to_string()
+}
+
+/// Description of a synthetic email, used to test the rules of an address
+/// without sending anything
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TestEmail {
+    pub sender: String,
+    pub subject: Option<String>,
+    pub body: String,
+    pub importance: Option<Importance>,
+    pub attachments: Vec<TestAttachment>,
+}
+
+impl TestEmail {
+    /// Build the email as it would be received by the server
+    pub fn to_email(&self, recipient: &str) -> Email {
+        Email {
+            sender: self.sender.clone(),
+            recipients: vec![recipient.to_string()],
+            subject: self.subject.clone(),
+            body: self.body.clone(),
+            importance: self.importance,
+            num_attachments: self.attachments.len() as u16,
+            ..Default::default()
+        }
+    }
+}
+
+/// How the rules of an address apply to a single test attachment
+#[derive(Clone, Debug, Serialize)]
+pub struct AttachmentOutcome {
+    pub name: String,
+
+    /// Attachment rules that match, in order
+    pub matched_rules: Vec<Rule>,
+
+    /// Reason the attachment would be dropped, if any (see `check`)
+    pub drop_reason: Option<String>,
+
+    /// Route the attachment would be stored with, if not stored on the
+    /// address backend (see `route`)
+    pub route: Option<Route>,
+}
+
+/// How the rules of an address apply to a test email
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TestOutcome {
+    /// False if the sender is not on the address whitelist, in which case
+    /// the email would be rejected and no other rules apply
+    pub sender_allowed: bool,
+
+    /// First priority rule that matches, if any
+    pub priority_rule: Option<PriorityRule>,
+
+    /// Directives that would apply, if the sender is an owner of the address
+    pub directives: Option<Directives>,
+
+    /// True if the email has directives, but the sender is not allowed to
+    /// use them
+    pub directives_ignored: bool,
+
+    /// Subfolder of the address storage path the email would be stored in
+    pub folder: Option<String>,
+
+    /// Whether notifications would be flagged as high priority, or muted
+    pub high_priority: bool,
+    pub muted: bool,
+
+    pub attachments: Vec<AttachmentOutcome>,
+}
+
+/// Check a test email against the rules of its address, in the order the
+/// server applies them to received email
+///
+/// `sender_allowed` and `is_owner` are the result of the whitelist checks
+/// for the sender (see `Address::validate_sender` and
+/// `Address::is_whitelisted`). Nothing is stored or sent.
+pub fn evaluate(
+    test: &TestEmail,
+    recipient: &str,
+    sender_allowed: bool,
+    is_owner: bool,
+    priority_rules: &[PriorityRule],
+    attachment_rules: &[Rule],
+    routes: &[Route],
+) -> TestOutcome {
+    if !sender_allowed {
+        return TestOutcome::default();
+    }
+
+    let mut email = test.to_email(recipient);
+    let priority_rule = priority_rules.iter().find(|r| r.matches(&email)).cloned();
+    let mut directives_ignored = false;
+
+    if let Some((directives, subject, body)) = Directives::parse(&email) {
+        if is_owner {
+            email.directives = Some(directives);
+            email.subject = subject;
+            email.body = body;
+        } else {
+            directives_ignored = true;
+        }
+    }
+
+    let priority = priority_rule.as_ref().map(|r| &r.priority);
+    let directive_folder = email.directives.as_ref().and_then(|d| d.folder.clone());
+    let priority_folder = priority.and_then(|p| p.folder.clone());
+
+    let attachments = test
+        .attachments
+        .iter()
+        .map(|a| AttachmentOutcome {
+            name: a.name.clone(),
+            matched_rules: attachment_rules
+                .iter()
+                .filter(|r| r.matches(&a.name, &a.content_type, a.size))
+                .cloned()
+                .collect(),
+            drop_reason: check(attachment_rules, &a.name, &a.content_type, a.size),
+            route: route(routes, &a.name, &a.content_type, a.size).cloned(),
+        })
+        .collect();
+
+    TestOutcome {
+        sender_allowed,
+        folder: directive_folder.or(priority_folder),
+        high_priority: priority.map_or(false, |p| p.notify),
+        muted: email.directives.as_ref().and_then(|d| d.notify) == Some(false),
+        priority_rule,
+        directives: email.directives,
+        directives_ignored,
+        attachments,
+    }
+}
+
+/// Whether a file matches all of the criteria that are set
+fn criteria_match(
+    extension_pattern: Option<&str>,
+    mime_type_pattern: Option<&str>,
+    larger_than: Option<i64>,
+    name: &str,
+    mime_type: &str,
+    size: usize,
+) -> bool {
+    let extension_matches = extension_pattern.map_or(true, |ext| {
+        extension(name).map_or(false, |e| {
+            e.eq_ignore_ascii_case(ext.trim_start_matches('.'))
+        })
+    });
+
+    let mime_type_matches =
+        mime_type_pattern.map_or(true, |pattern| mime_type_matches(pattern, mime_type));
+
+    let size_matches = larger_than.map_or(true, |larger_than| size as i64 > larger_than);
+
+    extension_matches && mime_type_matches && size_matches
+}
+
+fn extension(name: &str) -> Option<&str> {
+    let i = name.rfind('.')?;
+    Some(&name[i + 1..]).filter(|e| i > 0 && !e.is_empty())
+}
+
+fn mime_type_matches(pattern: &str, mime_type: &str) -> bool {
+    // Ignore any parameters (e.g., "text/plain; charset=utf-8")
+    let mime_type = mime_type.split(';').next().unwrap_or("").trim();
+
+    match pattern.strip_suffix("/*") {
+        Some(top_level) => mime_type
+            .split('/')
+            .next()
+            .map_or(false, |t| t.eq_ignore_ascii_case(top_level)),
+        None => pattern.eq_ignore_ascii_case(mime_type),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: Action, extension: Option<&str>, mime_type: Option<&str>) -> Rule {
+        Rule {
+            action,
+            extension: extension.map(String::from),
+            mime_type: mime_type.map(String::from),
+            larger_than: None,
+        }
+    }
+
+    #[test]
+    fn no_rules() {
+        assert_eq!(
+            check(&[], "setup.exe", "application/octet-stream", 100),
+            None
+        );
+    }
+
+    #[test]
+    fn allow_list() {
+        let rules = vec![
+            rule(Action::Allow, Some("pdf"), None),
+            rule(Action::Allow, None, Some("image/*")),
+        ];
+
+        assert_eq!(check(&rules, "report.PDF", "application/pdf", 100), None);
+        assert_eq!(check(&rules, "logo.gif", "image/gif", 100), None);
+        assert!(check(&rules, "notes.txt", "text/plain; charset=utf-8", 100).is_some());
+        assert!(check(&rules, "pdf", "application/octet-stream", 100).is_some());
+    }
+
+    #[test]
+    fn deny_list() {
+        let mut large = rule(Action::Deny, None, None);
+        large.larger_than = Some(1000);
+
+        let rules = vec![
+            rule(Action::Deny, Some("exe"), None),
+            rule(Action::Deny, None, Some("application/x-msdownload")),
+            large,
+        ];
+
+        assert!(check(&rules, "setup.exe", "application/octet-stream", 100).is_some());
+        assert!(check(&rules, "setup", "application/x-msdownload", 100).is_some());
+        assert!(check(&rules, "movie.mp4", "video/mp4", 1001).is_some());
+        assert_eq!(check(&rules, "movie.mp4", "video/mp4", 1000), None);
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let rules = vec![
+            rule(Action::Allow, None, Some("image/*")),
+            rule(Action::Deny, Some("svg"), None),
+        ];
+
+        assert_eq!(check(&rules, "logo.png", "image/png", 100), None);
+        assert!(check(&rules, "logo.svg", "image/svg+xml", 100).is_some());
+    }
+
+    #[test]
+    fn storage_routes() {
+        let to = |backend: Backend, path: &str| Route {
+            extension: None,
+            mime_type: None,
+            larger_than: None,
+            storage_backend: backend,
+            storage_token: "token".to_string(),
+            storage_path: path.to_string(),
+        };
+
+        let routes = vec![
+            Route {
+                mime_type: Some("image/*".to_string()),
+                ..to(Backend::S3, "/images")
+            },
+            Route {
+                extension: Some("pdf".to_string()),
+                ..to(Backend::Dropbox, "/Documents")
+            },
+            Route {
+                larger_than: Some(1000),
+                ..to(Backend::S3, "/large")
+            },
+        ];
+
+        let path = |name, mime_type, size| {
+            route(&routes, name, mime_type, size).map(|r| r.storage_path.as_str())
+        };
+
+        assert_eq!(path("scan.jpg", "image/jpeg", 5000), Some("/images"));
+        assert_eq!(
+            path("Invoice.PDF", "application/pdf", 5000),
+            Some("/Documents")
+        );
+        assert_eq!(path("movie.mp4", "video/mp4", 5000), Some("/large"));
+        assert_eq!(path("notes.txt", "text/plain", 100), None);
+        assert!(route(&[], "scan.jpg", "image/jpeg", 100).is_none());
+    }
+
+    #[test]
+    fn priority_rules() {
+        let urgent = Priority {
+            folder: Some("Urgent".to_string()),
+            notify: true,
+        };
+        let important = Priority {
+            folder: None,
+            notify: true,
+        };
+
+        let rules = vec![
+            PriorityRule {
+                importance: None,
+                subject_tag: Some("[urgent]".to_string()),
+                priority: urgent.clone(),
+            },
+            PriorityRule {
+                importance: Some(Importance::High),
+                subject_tag: None,
+                priority: important.clone(),
+            },
+        ];
+
+        let email = |subject: &str, importance| Email {
+            subject: Some(subject.to_string()),
+            importance,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            check_priority(&rules, &email("[URGENT] Invoice", Some(Importance::High))),
+            Some(urgent)
+        );
+        assert_eq!(
+            check_priority(&rules, &email("Invoice", Some(Importance::High))),
+            Some(important)
+        );
+        assert_eq!(
+            check_priority(&rules, &email("Invoice", Some(Importance::Low))),
+            None
+        );
+        assert_eq!(check_priority(&[], &email("[urgent]", None)), None);
+    }
+
+    #[test]
+    fn evaluate_test_email() {
+        let priority_rules = vec![PriorityRule {
+            importance: Some(Importance::High),
+            subject_tag: None,
+            priority: Priority {
+                folder: Some("Important".to_string()),
+                notify: true,
+            },
+        }];
+        let attachment_rules = vec![
+            rule(Action::Allow, None, Some("image/*")),
+            rule(Action::Deny, Some("svg"), None),
+        ];
+        let routes = vec![Route {
+            extension: None,
+            mime_type: Some("image/png".to_string()),
+            larger_than: None,
+            storage_backend: Backend::S3,
+            storage_token: "token".to_string(),
+            storage_path: "/images".to_string(),
+        }];
+
+        let test = TestEmail {
+            sender: "owner@example.com".to_string(),
+            subject: Some("Receipts !folder Receipts !notify off".to_string()),
+            importance: Some(Importance::High),
+            attachments: vec![
+                TestAttachment {
+                    name: "logo.png".to_string(),
+                    content_type: "image/png".to_string(),
+                    size: 100,
+                },
+                TestAttachment {
+                    name: "logo.svg".to_string(),
+                    content_type: "image/svg+xml".to_string(),
+                    size: 100,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let outcome = evaluate(
+            &test,
+            "a@vaulty.net",
+            true,
+            true,
+            &priority_rules,
+            &attachment_rules,
+            &routes,
+        );
+
+        assert!(outcome.priority_rule.is_some());
+        assert!(outcome.high_priority);
+        assert!(outcome.muted);
+        assert!(!outcome.directives_ignored);
+
+        // Directives take precedence over the priority rule
+        assert_eq!(outcome.folder.as_deref(), Some("Receipts"));
+
+        assert_eq!(outcome.attachments[0].matched_rules.len(), 1);
+        assert_eq!(outcome.attachments[0].drop_reason, None);
+        assert_eq!(outcome.attachments[1].matched_rules.len(), 2);
+        assert!(outcome.attachments[1].drop_reason.is_some());
+        assert_eq!(
+            outcome.attachments[0]
+                .route
+                .as_ref()
+                .map(|r| r.storage_path.as_str()),
+            Some("/images")
+        );
+
+        // Directives are only applied for owners
+        let outcome = evaluate(
+            &test,
+            "a@vaulty.net",
+            true,
+            false,
+            &priority_rules,
+            &attachment_rules,
+            &routes,
+        );
+        assert!(outcome.directives_ignored);
+        assert!(!outcome.muted);
+        assert_eq!(outcome.folder.as_deref(), Some("Important"));
+
+        let outcome = evaluate(
+            &test,
+            "a@vaulty.net",
+            false,
+            false,
+            &priority_rules,
+            &attachment_rules,
+            &routes,
+        );
+        assert!(!outcome.sender_allowed);
+        assert!(outcome.attachments.is_empty());
+    }
+}
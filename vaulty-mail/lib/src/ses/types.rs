@@ -0,0 +1,217 @@
+use serde::Deserialize;
+
+/// Type of an SNS message that asks the endpoint to confirm a subscription
+pub const SUBSCRIPTION_CONFIRMATION: &str = "SubscriptionConfirmation";
+
+/// Type of an SNS message that carries a notification
+pub const NOTIFICATION: &str = "Notification";
+
+/// Type of an SES notification for a received email
+pub const RECEIVED: &str = "Received";
+
+/// Message sent by SNS to an HTTP(S) subscription
+///
+/// The signature of the message is not verified. Messages must be sent with
+/// the basic auth credentials of the subscription, and only messages from
+/// configured topics are accepted. Subscriptions are only confirmed through
+/// AWS URLs.
+#[derive(Deserialize, Debug)]
+pub struct SnsMessage {
+    #[serde(rename = "Type")]
+    pub kind: String,
+    #[serde(rename = "MessageId")]
+    pub id: String,
+    #[serde(rename = "TopicArn")]
+    pub topic_arn: String,
+    #[serde(rename = "Message")]
+    pub message: String,
+    #[serde(rename = "SubscribeURL")]
+    pub subscribe_url: Option<String>,
+}
+
+/// Notification sent by SES when an email is received
+///
+/// The email is either included in the notification (SNS action), or
+/// stored in an S3 bucket (S3 action).
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+    pub notification_type: String,
+    pub mail: Mail,
+    pub receipt: Receipt,
+    content: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Mail {
+    /// Envelope sender
+    pub source: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Receipt {
+    /// Envelope recipients that matched the receipt rule
+    pub recipients: Vec<String>,
+    action: Action,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+enum Action {
+    #[serde(rename = "SNS")]
+    Sns { encoding: Option<String> },
+    #[serde(rename = "S3", rename_all = "camelCase")]
+    S3 {
+        bucket_name: String,
+        object_key: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Where the raw MIME message of a received email can be found
+#[derive(Debug, PartialEq)]
+pub enum Content {
+    Inline(Vec<u8>),
+    S3 { bucket: String, key: String },
+}
+
+impl SnsMessage {
+    pub fn from_json(body: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        serde_json::from_slice::<Self>(body).map_err(|e| e.into())
+    }
+
+    /// Region of the topic, taken from its ARN
+    /// (e.g., "arn:aws:sns:us-east-1:123456789012:vaulty")
+    pub fn region(&self) -> Option<&str> {
+        self.topic_arn.split(':').nth(3).filter(|r| !r.is_empty())
+    }
+
+    /// Parse the SES notification carried by this message
+    pub fn notification(&self) -> Result<Notification, Box<dyn std::error::Error>> {
+        serde_json::from_str::<Notification>(&self.message).map_err(|e| e.into())
+    }
+
+    /// Confirm a subscription by visiting the URL sent by SNS
+    pub async fn confirm(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let url = self
+            .subscribe_url
+            .as_ref()
+            .ok_or("No SubscribeURL found in SNS message")?;
+        let url = reqwest::Url::parse(url)?;
+
+        // Never follow a URL that does not point to AWS
+        let is_aws = url
+            .host_str()
+            .map_or(false, |host| host.ends_with(".amazonaws.com"));
+
+        if url.scheme() != "https" || !is_aws {
+            return Err(format!("Invalid SubscribeURL: {}", url).into());
+        }
+
+        reqwest::Client::new()
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+impl Notification {
+    /// Locate the raw MIME message of the received email
+    pub fn content(&self) -> Result<Content, Box<dyn std::error::Error>> {
+        match &self.receipt.action {
+            Action::Sns { encoding } => {
+                let content = self
+                    .content
+                    .as_ref()
+                    .ok_or("No content found in SES notification")?;
+
+                match encoding.as_deref() {
+                    Some("BASE64") => Ok(Content::Inline(base64::decode(content)?)),
+                    _ => Ok(Content::Inline(content.clone().into_bytes())),
+                }
+            }
+            Action::S3 {
+                bucket_name,
+                object_key,
+            } => Ok(Content::S3 {
+                bucket: bucket_name.clone(),
+                key: object_key.clone(),
+            }),
+            Action::Other => Err("Unsupported SES receipt action".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sns_message(notification: serde_json::Value) -> Vec<u8> {
+        serde_json::json!({
+            "Type": "Notification",
+            "MessageId": "a1b2c3",
+            "TopicArn": "arn:aws:sns:eu-west-1:123456789012:vaulty-inbound",
+            "Message": notification.to_string(),
+            "Timestamp": "2020-02-09T12:00:00.000Z",
+            "SignatureVersion": "1",
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn received_content() {
+        let body = sns_message(serde_json::json!({
+            "notificationType": "Received",
+            "mail": {"source": "sender@example.com", "destination": ["a@vaulty.net"]},
+            "receipt": {
+                "recipients": ["a@vaulty.net"],
+                "action": {"type": "SNS", "topicArn": "arn", "encoding": "BASE64"},
+            },
+            "content": base64::encode("Subject: Hi\r\n\r\nHello!"),
+        }));
+
+        let message = SnsMessage::from_json(&body).unwrap();
+        assert_eq!(message.kind, NOTIFICATION);
+        assert_eq!(message.region(), Some("eu-west-1"));
+
+        let notification = message.notification().unwrap();
+        assert_eq!(notification.notification_type, RECEIVED);
+        assert_eq!(notification.mail.source, "sender@example.com");
+        assert_eq!(notification.receipt.recipients, vec!["a@vaulty.net"]);
+        assert_eq!(
+            notification.content().unwrap(),
+            Content::Inline(b"Subject: Hi\r\n\r\nHello!".to_vec())
+        );
+
+        let body = sns_message(serde_json::json!({
+            "notificationType": "Received",
+            "mail": {"source": "sender@example.com"},
+            "receipt": {
+                "recipients": ["a@vaulty.net"],
+                "action": {
+                    "type": "S3",
+                    "topicArn": "arn",
+                    "bucketName": "vaulty-inbound",
+                    "objectKey": "emails/a1b2c3",
+                },
+            },
+        }));
+
+        let notification = SnsMessage::from_json(&body)
+            .unwrap()
+            .notification()
+            .unwrap();
+        assert_eq!(
+            notification.content().unwrap(),
+            Content::S3 {
+                bucket: "vaulty-inbound".to_string(),
+                key: "emails/a1b2c3".to_string(),
+            }
+        );
+    }
+}
@@ -0,0 +1,329 @@
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::storage::Backend;
+
+/// What to do with auto-generated email (e.g., auto-replies, bulk mail, or
+/// mail looping back from Vaulty)
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoGeneratedPolicy {
+    /// Store it like any other email
+    Store,
+    /// Accept it, but do not store it
+    Ignore,
+    /// Reject it
+    Reject,
+}
+
+impl From<&str> for AutoGeneratedPolicy {
+    fn from(s: &str) -> Self {
+        if s == "store" {
+            Self::Store
+        } else if s == "ignore" {
+            Self::Ignore
+        } else if s == "reject" {
+            Self::Reject
+        } else {
+            // Default to storing, as before
+            log::error!("Unknown auto-generated email policy: {}", s);
+            Self::Store
+        }
+    }
+}
+
+impl From<String> for AutoGeneratedPolicy {
+    fn from(s: String) -> Self {
+        s.as_str().into()
+    }
+}
+
+/// When the client is told that an attachment was handled
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMode {
+    /// Store, then acknowledge: the client only gets a success once the
+    /// attachment is in storage, and retries it otherwise
+    Sync,
+    /// Acknowledge, then store: the attachment is saved to the spool once
+    /// received, and stored in the background and retried by the server,
+    /// including after a restart
+    Async,
+}
+
+impl From<&str> for DeliveryMode {
+    fn from(s: &str) -> Self {
+        if s == "sync" {
+            Self::Sync
+        } else if s == "async" {
+            Self::Async
+        } else {
+            // Default to the stronger guarantee
+            log::error!("Unknown delivery mode: {}", s);
+            Self::Sync
+        }
+    }
+}
+
+impl From<String> for DeliveryMode {
+    fn from(s: String) -> Self {
+        s.as_str().into()
+    }
+}
+
+/// How uploads of an address are organized into subfolders of its storage
+/// path
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Organization {
+    /// Store uploads directly under the storage path
+    None,
+    /// Store uploads under the handling date (e.g., "2020/04/01")
+    Date,
+    /// Store uploads under the domain of the sender (e.g., "example.com")
+    SenderDomain,
+}
+
+impl Default for Organization {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl From<&str> for Organization {
+    fn from(s: &str) -> Self {
+        if s == "none" {
+            Self::None
+        } else if s == "date" {
+            Self::Date
+        } else if s == "sender_domain" {
+            Self::SenderDomain
+        } else {
+            log::error!("Unknown organization policy: {}", s);
+            Self::None
+        }
+    }
+}
+
+impl From<String> for Organization {
+    fn from(s: String) -> Self {
+        s.as_str().into()
+    }
+}
+
+/// Effective settings for a single address.
+///
+/// Settings are resolved in the following order, with later layers taking
+/// precedence:
+///
+/// 1. Deployment defaults (config file)
+/// 2. Domain defaults (`vaulty_domains` table)
+/// 3. Address overrides (`vaulty_addresses` table)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Settings {
+    /// Max number of emails per quota period
+    pub email_quota: i32,
+
+    /// Max storage used per quota period, in bytes
+    pub storage_quota: i64,
+
+    /// Max size of a single email, in bytes
+    pub max_email_size: i32,
+
+    pub storage_backend: Backend,
+
+    /// Reply to the sender when an email is processed successfully
+    pub reply_on_success: bool,
+
+    /// Reply to the sender when an email is rejected (e.g., quota exceeded)
+    pub reply_on_rejection: bool,
+
+    /// Skip uploading attachments that are identical to the last stored
+    /// attachment with the same name
+    pub skip_unchanged: bool,
+
+    /// Skip uploading attachments whose content was already stored for the
+    /// address, under any name
+    pub dedup_attachments: bool,
+
+    /// Transliterate non-ASCII attachment filenames to ASCII
+    pub transliterate_filenames: bool,
+
+    /// Store the email body (text and HTML) alongside its attachments
+    pub store_body: bool,
+
+    /// Archive the raw message as a single .eml file
+    pub archive_eml: bool,
+
+    /// Store the links of HTML emails without attachments, and fetch the
+    /// linked files that match the address link pattern
+    pub archive_links: bool,
+
+    /// Render the HTML body to PDF and store it alongside the attachments
+    pub body_pdf: bool,
+
+    /// Store a manifest of the files stored for each email, signed with the
+    /// server key (see `manifest`)
+    pub sign_manifests: bool,
+
+    /// What to do with auto-generated email
+    pub auto_generated_policy: AutoGeneratedPolicy,
+
+    /// When attachments sent by the filter are acknowledged
+    pub delivery_mode: DeliveryMode,
+}
+
+/// A single layer of settings. Unset fields fall through to the layer below.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SettingsLayer {
+    pub email_quota: Option<i32>,
+    pub storage_quota: Option<i64>,
+    pub max_email_size: Option<i32>,
+    pub storage_backend: Option<Backend>,
+    pub reply_on_success: Option<bool>,
+    pub reply_on_rejection: Option<bool>,
+    pub skip_unchanged: Option<bool>,
+    pub dedup_attachments: Option<bool>,
+    pub transliterate_filenames: Option<bool>,
+    pub store_body: Option<bool>,
+    pub archive_eml: Option<bool>,
+    pub archive_links: Option<bool>,
+    pub body_pdf: Option<bool>,
+    pub sign_manifests: Option<bool>,
+    pub auto_generated_policy: Option<AutoGeneratedPolicy>,
+    pub delivery_mode: Option<DeliveryMode>,
+}
+
+impl Settings {
+    /// Deployment-wide defaults
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            email_quota: config.default_email_quota,
+            storage_quota: config.default_storage_quota,
+            max_email_size: i32::try_from(config.max_email_size).unwrap_or(i32::MAX),
+            storage_backend: Backend::Dropbox,
+            reply_on_success: false,
+            reply_on_rejection: false,
+            skip_unchanged: false,
+            dedup_attachments: false,
+            transliterate_filenames: false,
+            store_body: false,
+            archive_eml: false,
+            archive_links: false,
+            body_pdf: false,
+            sign_manifests: false,
+            auto_generated_policy: AutoGeneratedPolicy::Store,
+            delivery_mode: DeliveryMode::from(config.default_delivery_mode.as_str()),
+        }
+    }
+
+    /// Apply a layer on top of these settings
+    pub fn merge(self, layer: &SettingsLayer) -> Self {
+        Self {
+            email_quota: layer.email_quota.unwrap_or(self.email_quota),
+            storage_quota: layer.storage_quota.unwrap_or(self.storage_quota),
+            max_email_size: layer.max_email_size.unwrap_or(self.max_email_size),
+            storage_backend: layer
+                .storage_backend
+                .clone()
+                .unwrap_or(self.storage_backend),
+            reply_on_success: layer.reply_on_success.unwrap_or(self.reply_on_success),
+            reply_on_rejection: layer.reply_on_rejection.unwrap_or(self.reply_on_rejection),
+            skip_unchanged: layer.skip_unchanged.unwrap_or(self.skip_unchanged),
+            dedup_attachments: layer.dedup_attachments.unwrap_or(self.dedup_attachments),
+            transliterate_filenames: layer
+                .transliterate_filenames
+                .unwrap_or(self.transliterate_filenames),
+            store_body: layer.store_body.unwrap_or(self.store_body),
+            archive_eml: layer.archive_eml.unwrap_or(self.archive_eml),
+            archive_links: layer.archive_links.unwrap_or(self.archive_links),
+            body_pdf: layer.body_pdf.unwrap_or(self.body_pdf),
+            sign_manifests: layer.sign_manifests.unwrap_or(self.sign_manifests),
+            auto_generated_policy: layer
+                .auto_generated_policy
+                .unwrap_or(self.auto_generated_policy),
+            delivery_mode: layer.delivery_mode.unwrap_or(self.delivery_mode),
+        }
+    }
+
+    /// Resolve the effective settings given a list of layers, ordered from
+    /// lowest to highest precedence
+    pub fn resolve(defaults: &Settings, layers: &[&SettingsLayer]) -> Self {
+        layers
+            .iter()
+            .fold(defaults.clone(), |settings, layer| settings.merge(layer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_layers() {
+        let defaults = Settings {
+            email_quota: 100,
+            storage_quota: 1000,
+            max_email_size: 10,
+            storage_backend: Backend::Dropbox,
+            reply_on_success: false,
+            reply_on_rejection: false,
+            skip_unchanged: false,
+            dedup_attachments: false,
+            transliterate_filenames: false,
+            store_body: false,
+            archive_eml: false,
+            archive_links: false,
+            body_pdf: false,
+            sign_manifests: false,
+            auto_generated_policy: AutoGeneratedPolicy::Store,
+            delivery_mode: DeliveryMode::Sync,
+        };
+
+        let domain = SettingsLayer {
+            email_quota: Some(200),
+            storage_backend: Some(Backend::S3),
+            skip_unchanged: Some(true),
+            dedup_attachments: Some(false),
+            reply_on_rejection: Some(true),
+            store_body: Some(true),
+            archive_links: Some(true),
+            auto_generated_policy: Some(AutoGeneratedPolicy::Reject),
+            delivery_mode: Some(DeliveryMode::Async),
+            ..Default::default()
+        };
+
+        let address = SettingsLayer {
+            email_quota: Some(300),
+            reply_on_success: Some(true),
+            transliterate_filenames: Some(true),
+            archive_eml: Some(true),
+            body_pdf: Some(true),
+            sign_manifests: Some(true),
+            dedup_attachments: Some(true),
+            auto_generated_policy: Some(AutoGeneratedPolicy::Ignore),
+            ..Default::default()
+        };
+
+        let settings = Settings::resolve(&defaults, &[&domain, &address]);
+
+        assert_eq!(settings.email_quota, 300);
+        assert_eq!(settings.storage_quota, 1000);
+        assert_eq!(settings.max_email_size, 10);
+        assert!(matches!(settings.storage_backend, Backend::S3));
+        assert!(settings.reply_on_success);
+        assert!(settings.reply_on_rejection);
+        assert!(settings.skip_unchanged);
+        assert!(settings.dedup_attachments);
+        assert!(settings.transliterate_filenames);
+        assert!(settings.store_body);
+        assert!(settings.archive_eml);
+        assert!(settings.archive_links);
+        assert!(settings.body_pdf);
+        assert!(settings.sign_manifests);
+        assert_eq!(settings.auto_generated_policy, AutoGeneratedPolicy::Ignore);
+        assert_eq!(settings.delivery_mode, DeliveryMode::Async);
+    }
+}
@@ -0,0 +1,345 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+const KB: i64 = 1024;
+const MB: i64 = 1024 * KB;
+
+/// Lower bounds of the email and attachment size buckets, in bytes
+pub const SIZE_BUCKETS: &[i64] = &[0, 10 * KB, 100 * KB, MB, 5 * MB, 10 * MB, 25 * MB, 50 * MB];
+
+/// Lower bounds of the attachment count buckets
+pub const ATTACHMENT_COUNT_BUCKETS: &[i64] = &[0, 1, 2, 3, 5, 10, 20];
+
+/// Number of most common content types broken out
+pub const MAX_CONTENT_TYPES: usize = 20;
+
+/// Default and max number of days of email the stats are computed over
+pub const DEFAULT_DAYS: i64 = 30;
+pub const MAX_DAYS: i64 = 365;
+
+/// Number of most recent days of the quota period that the usage rate of an
+/// address is projected from
+pub const RECENT_DAYS: i64 = 7;
+
+/// Values in `[min, max)`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Bucket {
+    pub min: i64,
+
+    /// Not set for the last bucket
+    pub max: Option<i64>,
+
+    pub count: i64,
+}
+
+/// Distribution of a single value (e.g., email size)
+#[derive(Clone, Debug, Serialize)]
+pub struct Distribution {
+    pub count: i64,
+
+    /// Percentiles and max, if there are any values
+    pub p50: Option<i64>,
+    pub p90: Option<i64>,
+    pub p99: Option<i64>,
+    pub max: Option<i64>,
+
+    pub buckets: Vec<Bucket>,
+}
+
+impl Distribution {
+    pub fn empty(bounds: &[i64]) -> Self {
+        Self {
+            count: 0,
+            p50: None,
+            p90: None,
+            p99: None,
+            max: None,
+            buckets: buckets(bounds, &[]),
+        }
+    }
+}
+
+/// Build histogram buckets from per-bucket counts
+///
+/// Counts are keyed by bucket number as returned by Postgres' `width_bucket`
+/// for the same bounds: bucket `i` holds values in `[bounds[i - 1],
+/// bounds[i])`.
+pub fn buckets(bounds: &[i64], counts: &[(i32, i64)]) -> Vec<Bucket> {
+    bounds
+        .iter()
+        .enumerate()
+        .map(|(i, &min)| Bucket {
+            min,
+            max: bounds.get(i + 1).copied(),
+            count: counts
+                .iter()
+                .filter(|(bucket, _)| *bucket as usize == i + 1)
+                .map(|(_, count)| count)
+                .sum(),
+        })
+        .collect()
+}
+
+/// Attachment size distribution for a single content type
+#[derive(Clone, Debug, Serialize)]
+pub struct ContentType {
+    pub content_type: String,
+
+    #[serde(flatten)]
+    pub size: Distribution,
+}
+
+/// Shape of inbound email over a period
+///
+/// Meant to help pick size limits and quotas.
+#[derive(Clone, Debug, Serialize)]
+pub struct Stats {
+    /// Start of the period, up to now
+    pub since: DateTime<Utc>,
+
+    pub email_size: Distribution,
+    pub attachment_count: Distribution,
+    pub attachment_size: Distribution,
+
+    /// Most common attachment content types, most common first
+    pub content_types: Vec<ContentType>,
+
+    pub computed_at: DateTime<Utc>,
+}
+
+/// Email and storage used by an address on a single day
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DailyUsage {
+    /// Start of the day, in UTC
+    pub day: DateTime<Utc>,
+    pub emails: i64,
+    pub bytes: i64,
+}
+
+/// Use of a single quota over the current period
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Usage {
+    pub used: i64,
+    pub quota: i64,
+    pub remaining: i64,
+
+    /// Average use per day over the last `RECENT_DAYS` days of the period
+    pub rate_per_day: f64,
+
+    /// When the quota runs out at the recent rate, if before the end of the
+    /// period
+    pub projected_exhaustion: Option<DateTime<Utc>>,
+}
+
+impl Usage {
+    fn new(
+        used: i64,
+        quota: i64,
+        rate_per_day: f64,
+        now: DateTime<Utc>,
+        period_end: DateTime<Utc>,
+    ) -> Self {
+        let remaining = (quota - used).max(0);
+
+        let projected_exhaustion = if remaining == 0 {
+            Some(now)
+        } else if rate_per_day > 0.0 {
+            // Checked before converting, as the rate can be tiny
+            let secs = remaining as f64 / rate_per_day * 86_400.0;
+            if secs < (period_end - now).num_seconds() as f64 {
+                Some(now + Duration::seconds(secs as i64))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        Self {
+            used,
+            quota,
+            remaining,
+            rate_per_day,
+            projected_exhaustion,
+        }
+    }
+}
+
+/// Quota burn-down of an address over its current quota period
+#[derive(Clone, Debug, Serialize)]
+pub struct Burndown {
+    pub address: String,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+
+    pub emails: Usage,
+    pub storage: Usage,
+
+    /// Usage on each day of the period so far, oldest first
+    ///
+    /// Emails are counted at their full size, which is close to, but not
+    /// exactly, what is counted against the storage quota.
+    pub daily: Vec<DailyUsage>,
+
+    pub computed_at: DateTime<Utc>,
+}
+
+impl Burndown {
+    /// Build the burn-down of an address from its current quota use and
+    /// its usage on the days of the period that had any email
+    pub fn new(
+        address: &str,
+        period_start: DateTime<Utc>,
+        period_end: DateTime<Utc>,
+        (emails_used, email_quota): (i64, i64),
+        (bytes_used, storage_quota): (i64, i64),
+        days: &[DailyUsage],
+        now: DateTime<Utc>,
+    ) -> Self {
+        let start = period_start.date().and_hms(0, 0, 0);
+
+        // Fill in the days with no email
+        let mut daily = Vec::new();
+        let mut day = start;
+        while day <= now {
+            daily.push(
+                days.iter()
+                    .find(|d| d.day == day)
+                    .cloned()
+                    .unwrap_or(DailyUsage {
+                        day,
+                        emails: 0,
+                        bytes: 0,
+                    }),
+            );
+            day += Duration::days(1);
+        }
+
+        // The rate is taken over whole days, up to now
+        let window_start = (now - Duration::days(RECENT_DAYS)).max(period_start);
+        let window_days = ((now - window_start).num_seconds() as f64 / 86_400.0).max(1.0);
+        let recent = daily
+            .iter()
+            .filter(|d| d.day + Duration::days(1) > window_start);
+        let recent_emails: i64 = recent.clone().map(|d| d.emails).sum();
+        let recent_bytes: i64 = recent.map(|d| d.bytes).sum();
+
+        Self {
+            address: address.to_string(),
+            period_start,
+            period_end,
+            emails: Usage::new(
+                emails_used,
+                email_quota,
+                recent_emails as f64 / window_days,
+                now,
+                period_end,
+            ),
+            storage: Usage::new(
+                bytes_used,
+                storage_quota,
+                recent_bytes as f64 / window_days,
+                now,
+                period_end,
+            ),
+            daily,
+            computed_at: now,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_buckets() {
+        let buckets = buckets(ATTACHMENT_COUNT_BUCKETS, &[(1, 10), (2, 4), (7, 1)]);
+
+        assert_eq!(buckets.len(), ATTACHMENT_COUNT_BUCKETS.len());
+        assert_eq!(
+            buckets[0],
+            Bucket {
+                min: 0,
+                max: Some(1),
+                count: 10
+            }
+        );
+        assert_eq!(buckets[1].count, 4);
+        assert_eq!(buckets[2].count, 0);
+        assert_eq!(
+            buckets[6],
+            Bucket {
+                min: 20,
+                max: None,
+                count: 1
+            }
+        );
+
+        let empty = Distribution::empty(SIZE_BUCKETS);
+        assert_eq!(empty.buckets.len(), SIZE_BUCKETS.len());
+        assert!(empty.buckets.iter().all(|b| b.count == 0));
+    }
+
+    #[test]
+    fn project_burndown() {
+        let start = "2020-04-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let end = start + Duration::days(30);
+        let now = start + Duration::days(10);
+        let day = |n| start.date().and_hms(0, 0, 0) + Duration::days(n);
+
+        let days = vec![
+            DailyUsage {
+                day: day(0),
+                emails: 50,
+                bytes: 5000,
+            },
+            DailyUsage {
+                day: day(8),
+                emails: 14,
+                bytes: 7000,
+            },
+        ];
+
+        let burndown = Burndown::new(
+            "a@vaulty.net",
+            start,
+            end,
+            (64, 100),
+            (12000, 12000),
+            &days,
+            now,
+        );
+
+        // Every day of the period so far is listed
+        assert_eq!(burndown.daily.len(), 11);
+        assert_eq!(burndown.daily[0], days[0]);
+        assert_eq!(burndown.daily[1].emails, 0);
+        assert_eq!(burndown.daily[8], days[1]);
+
+        // 14 emails over the last 7 days: the remaining 36 last 18 days
+        assert_eq!(burndown.emails.remaining, 36);
+        assert_eq!(burndown.emails.rate_per_day, 2.0);
+        assert_eq!(
+            burndown.emails.projected_exhaustion,
+            Some(now + Duration::days(18))
+        );
+
+        // Storage is already used up
+        assert_eq!(burndown.storage.remaining, 0);
+        assert_eq!(burndown.storage.projected_exhaustion, Some(now));
+
+        // Quotas that last until the end of the period are not projected
+        let burndown = Burndown::new(
+            "a@vaulty.net",
+            start,
+            end,
+            (64, 1000),
+            (0, 1 << 40),
+            &days,
+            now,
+        );
+        assert_eq!(burndown.emails.projected_exhaustion, None);
+        assert_eq!(burndown.storage.projected_exhaustion, None);
+    }
+}
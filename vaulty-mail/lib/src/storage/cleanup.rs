@@ -0,0 +1,134 @@
+use std::fmt;
+use std::future::Future;
+
+/// Outcome of cleaning up the partial state of a failed or cancelled upload
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cleanup {
+    /// The partial state was removed from the backend
+    Removed,
+
+    /// The backend has no way to remove the partial state, but discards it
+    /// by itself once it expires
+    Expires,
+
+    /// The partial state could not be removed, and may still use storage
+    Failed(String),
+}
+
+impl fmt::Display for Cleanup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Removed => f.write_str("removed"),
+            Self::Expires => f.write_str("left to expire"),
+            Self::Failed(msg) => write!(f, "cleanup failed: {}", msg),
+        }
+    }
+}
+
+/// Record the outcome of cleaning up a partial upload
+fn record(path: &str, cleanup: &Cleanup) {
+    match cleanup {
+        Cleanup::Failed(_) => log::warn!("Partial upload of {}: {}", path, cleanup),
+        _ => log::info!("Partial upload of {}: {}", path, cleanup),
+    }
+}
+
+/// Cleans up the partial state of an upload unless it is disarmed
+///
+/// The guard is created once an upload has left state on the backend (e.g.,
+/// an S3 multipart upload was started). If the upload fails, the cleanup is
+/// run in place. If the upload is cancelled instead (e.g., the session is
+/// aborted or a deadline passes), the upload future is dropped midway, so
+/// the cleanup is spawned as a task when the guard is dropped.
+pub(crate) struct CleanupGuard<F>
+where
+    F: Future<Output = Cleanup> + Send + 'static,
+{
+    path: String,
+    cleanup: Option<F>,
+}
+
+impl<F> CleanupGuard<F>
+where
+    F: Future<Output = Cleanup> + Send + 'static,
+{
+    pub fn new(path: &str, cleanup: F) -> Self {
+        Self {
+            path: path.to_string(),
+            cleanup: Some(cleanup),
+        }
+    }
+
+    /// The upload completed, so there is nothing to clean up
+    pub fn disarm(mut self) {
+        self.cleanup = None;
+    }
+
+    /// Clean up after a failed upload
+    pub async fn run(mut self) -> Cleanup {
+        // Always set, as the guard is consumed by `run` and `disarm`
+        let cleanup = self.cleanup.take().unwrap().await;
+        record(&self.path, &cleanup);
+        cleanup
+    }
+}
+
+impl<F> Drop for CleanupGuard<F>
+where
+    F: Future<Output = Cleanup> + Send + 'static,
+{
+    fn drop(&mut self) {
+        if let Some(cleanup) = self.cleanup.take() {
+            let path = std::mem::take(&mut self.path);
+
+            log::info!("Upload of {} was cancelled; cleaning up", path);
+
+            tokio::spawn(async move {
+                let cleanup = cleanup.await;
+                record(&path, &cleanup);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn cleanup(ran: Arc<AtomicBool>) -> impl Future<Output = Cleanup> + Send + 'static {
+        async move {
+            ran.store(true, Ordering::SeqCst);
+            Cleanup::Removed
+        }
+    }
+
+    #[tokio::test]
+    async fn cleanup_on_failure_and_cancellation() {
+        // Completed uploads are not cleaned up
+        let ran = Arc::new(AtomicBool::new(false));
+        CleanupGuard::new("/a.pdf", cleanup(ran.clone())).disarm();
+        tokio::task::yield_now().await;
+        assert!(!ran.load(Ordering::SeqCst));
+
+        // Failed uploads are cleaned up in place
+        let guard = CleanupGuard::new("/a.pdf", cleanup(ran.clone()));
+        assert_eq!(guard.run().await, Cleanup::Removed);
+        assert!(ran.load(Ordering::SeqCst));
+
+        // Cancelled uploads are cleaned up in the background
+        let ran = Arc::new(AtomicBool::new(false));
+        let upload = async {
+            let _guard = CleanupGuard::new("/a.pdf", cleanup(ran.clone()));
+            futures::future::pending::<()>().await;
+        };
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), upload).await;
+
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(ran.load(Ordering::SeqCst));
+    }
+}
@@ -4,15 +4,26 @@ use std::pin::Pin;
 use bytes::Bytes;
 use futures::stream::Stream;
 
-use crate::storage::Error;
+use crate::storage::{Error, Metadata};
 
 // Definition of future types for async use
 pub type ClientFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, Error>> + Send + 'a>>;
 
 pub trait Client {
+    /// Upload a file, and return the path it was stored at
+    ///
+    /// The path differs from `path` if the backend renamed the file (e.g.,
+    /// because a file with the same name already exists).
     fn upload_stream(
         &self,
         path: &str,
         data: impl Stream<Item = Result<Bytes, crate::Error>> + Send + Sync + 'static,
-    ) -> ClientFuture<'_, ()>;
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, String>;
+
+    /// Replace the custom metadata of an existing object
+    fn update_metadata(&self, path: &str, metadata: &Metadata) -> ClientFuture<'_, ()>;
+
+    /// Create a folder, along with any missing parents, if it does not exist
+    fn ensure_folder(&self, path: &str) -> ClientFuture<'_, ()>;
 }
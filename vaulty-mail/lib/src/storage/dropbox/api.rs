@@ -5,12 +5,33 @@ use reqwest::StatusCode;
 use serde::Deserialize;
 
 pub const DROPBOX_ARG_HEADER: &str = "Dropbox-API-Arg";
+pub const DROPBOX_PATH_ROOT_HEADER: &str = "Dropbox-API-Path-Root";
+pub const DROPBOX_SELECT_USER_HEADER: &str = "Dropbox-API-Select-User";
 pub const DROPBOX_BASE_API: &str = "https://api.dropboxapi.com/2/";
 pub const DROPBOX_BASE_CONTENT: &str = "https://content.dropboxapi.com/2/";
+pub const DROPBOX_OAUTH_TOKEN: &str = "https://api.dropbox.com/oauth2/token";
+pub const DROPBOX_WEB_HOME: &str = "https://www.dropbox.com/home";
 
 // Request timeout, in seconds
 pub(crate) const DROPBOX_REQUEST_TIMEOUT: u64 = 30;
 
+/// Default size of each chunk in an upload session, in bytes
+///
+/// Streams smaller than this are uploaded in a single request. Dropbox
+/// recommends chunks that are a multiple of 4 MB.
+pub const DROPBOX_UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Maximum number of upload sessions committed in a single batch
+pub(crate) const DROPBOX_FINISH_BATCH_MAX_ENTRIES: usize = 1000;
+
+// Delay before the status of a batch commit is first checked, and the
+// maximum delay between checks, in milliseconds
+pub(crate) const DROPBOX_FINISH_BATCH_POLL_DELAY: u64 = 250;
+pub(crate) const DROPBOX_FINISH_BATCH_MAX_POLL_DELAY: u64 = 4000;
+
+// Maximum number of checks of a batch commit before giving up
+pub(crate) const DROPBOX_FINISH_BATCH_MAX_POLLS: usize = 30;
+
 /// Map possible Dropbox API errors to generic storage backend error
 pub fn map_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
     let err = resp.error_for_status_ref();
@@ -21,7 +42,7 @@ pub fn map_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
 
         match status {
             StatusCode::BAD_REQUEST => Err(Error::BadInput(msg)),
-            StatusCode::FORBIDDEN => Err(Error::TokenExpired(msg)),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(Error::TokenExpired(msg)),
             StatusCode::CONFLICT => Err(Error::BadEndpoint(msg)),
             StatusCode::TOO_MANY_REQUESTS => Err(Error::RateLimited(msg)),
             _ => Err(Error::Internal(msg)),
@@ -33,8 +54,15 @@ pub fn map_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
 
 pub enum Endpoint {
     ListFolder,
+    ListFolderContinue,
     CreateFolder,
     FileUpload,
+    UploadSessionStart,
+    UploadSessionAppend,
+    UploadSessionFinish,
+    UploadSessionFinishBatch,
+    UploadSessionFinishBatchCheck,
+    PropertiesOverwrite,
     Search,
 }
 
@@ -74,6 +102,9 @@ pub struct SearchResult {
 #[derive(Deserialize, Debug)]
 pub struct ListFolderResult {
     pub entries: Vec<SearchResultEntry>,
+
+    /// Pass to `files/list_folder/continue` to get more entries
+    pub cursor: String,
     pub has_more: bool,
 }
 
@@ -82,6 +113,18 @@ pub struct CreateFolderResult {
     pub name: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct RefreshTokenResult {
+    pub access_token: String,
+    /// Lifetime of the access token, in seconds
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UploadSessionStartResult {
+    pub session_id: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct FileUploadResult {
     name: String,
@@ -89,16 +132,194 @@ pub struct FileUploadResult {
     size: usize,
     server_modified: String,
     path_lower: String,
-    path_display: String,
+    /// Differs from the path uploaded to if the file was renamed
+    pub path_display: String,
     content_hash: String,
 }
 
+/// Status of a batch of upload session commits
+///
+/// Starting a batch returns either a job ID to check, or the final result
+/// if the batch completed right away.
+#[derive(Deserialize, Debug)]
+#[serde(tag = ".tag", rename_all = "snake_case")]
+pub enum FinishBatchStatus {
+    AsyncJobId {
+        async_job_id: String,
+    },
+    InProgress,
+    Complete {
+        entries: Vec<FinishBatchEntry>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Result of a single commit in a batch
+#[derive(Deserialize, Debug)]
+#[serde(tag = ".tag", rename_all = "snake_case")]
+pub enum FinishBatchEntry {
+    Success { path_display: String },
+    Failure { failure: serde_json::Value },
+}
+
+impl FinishBatchEntry {
+    /// Path the file was committed to, or the reason it was not
+    pub fn into_result(self) -> Result<String, Error> {
+        match self {
+            Self::Success { path_display } => Ok(path_display),
+            Self::Failure { failure } => {
+                let reason = failure_reason(&failure);
+
+                // Too many commits to the same namespace at once
+                if reason.contains("too_many_write_operations") {
+                    Err(Error::RateLimited(reason))
+                } else {
+                    Err(Error::BadEndpoint(reason))
+                }
+            }
+        }
+    }
+}
+
+/// Describe a Dropbox error union by its nested tags (e.g., "path/conflict")
+fn failure_reason(failure: &serde_json::Value) -> String {
+    let mut tags = Vec::new();
+    let mut value = failure;
+
+    while let Some(tag) = value.get(".tag").and_then(|t| t.as_str()) {
+        tags.push(tag);
+
+        match value.get(tag) {
+            Some(inner) => value = inner,
+            None => break,
+        }
+    }
+
+    if tags.is_empty() {
+        failure.to_string()
+    } else {
+        tags.join("/")
+    }
+}
+
 #[inline]
 pub fn build_endpoint_url(endpoint: Endpoint) -> String {
     match endpoint {
         Endpoint::ListFolder => format!("{}{}", DROPBOX_BASE_API, "files/list_folder"),
+        Endpoint::ListFolderContinue => {
+            format!("{}{}", DROPBOX_BASE_API, "files/list_folder/continue")
+        }
         Endpoint::CreateFolder => format!("{}{}", DROPBOX_BASE_API, "files/create_folder_v2"),
         Endpoint::FileUpload => format!("{}{}", DROPBOX_BASE_CONTENT, "files/upload"),
+        Endpoint::UploadSessionStart => {
+            format!("{}{}", DROPBOX_BASE_CONTENT, "files/upload_session/start")
+        }
+        Endpoint::UploadSessionAppend => {
+            format!(
+                "{}{}",
+                DROPBOX_BASE_CONTENT, "files/upload_session/append_v2"
+            )
+        }
+        Endpoint::UploadSessionFinish => {
+            format!("{}{}", DROPBOX_BASE_CONTENT, "files/upload_session/finish")
+        }
+        Endpoint::UploadSessionFinishBatch => {
+            format!(
+                "{}{}",
+                DROPBOX_BASE_API, "files/upload_session/finish_batch"
+            )
+        }
+        Endpoint::UploadSessionFinishBatchCheck => format!(
+            "{}{}",
+            DROPBOX_BASE_API, "files/upload_session/finish_batch/check"
+        ),
+        Endpoint::PropertiesOverwrite => format!(
+            "{}{}",
+            DROPBOX_BASE_API, "file_properties/properties/overwrite"
+        ),
         Endpoint::Search => format!("{}{}", DROPBOX_BASE_API, "files/search"),
     }
 }
+
+/// Link to a file in the Dropbox web UI
+pub fn build_web_url(path: &str) -> String {
+    let mut url = url::Url::parse(DROPBOX_WEB_HOME).unwrap();
+
+    url.path_segments_mut()
+        .unwrap()
+        .extend(path.split('/').filter(|s| !s.is_empty()));
+
+    url.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn web_url() {
+        assert_eq!(
+            build_web_url("/vaulty/Q1 report #2.pdf"),
+            "https://www.dropbox.com/home/vaulty/Q1%20report%20%232.pdf"
+        );
+    }
+
+    #[test]
+    fn finish_batch_status() {
+        let status: FinishBatchStatus = serde_json::from_str(
+            r#"{".tag": "async_job_id", "async_job_id": "34g93hh34h04y384084"}"#,
+        )
+        .unwrap();
+        assert!(
+            matches!(status, FinishBatchStatus::AsyncJobId { async_job_id } if async_job_id == "34g93hh34h04y384084")
+        );
+
+        let status: FinishBatchStatus = serde_json::from_str(r#"{".tag": "in_progress"}"#).unwrap();
+        assert!(matches!(status, FinishBatchStatus::InProgress));
+
+        let status: FinishBatchStatus = serde_json::from_str(
+            r#"{
+                ".tag": "complete",
+                "entries": [
+                    {
+                        ".tag": "success",
+                        "name": "a.pdf",
+                        "id": "id:a4ayc_80_OEAAAAAAAAAXw",
+                        "path_lower": "/vaulty/a.pdf",
+                        "path_display": "/vaulty/a.pdf",
+                        "size": 7212
+                    },
+                    {
+                        ".tag": "failure",
+                        "failure": {".tag": "path", "path": {".tag": "insufficient_space"}}
+                    },
+                    {
+                        ".tag": "failure",
+                        "failure": {".tag": "too_many_write_operations"}
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let results: Vec<_> = match status {
+            FinishBatchStatus::Complete { entries } => {
+                entries.into_iter().map(|e| e.into_result()).collect()
+            }
+            _ => panic!("Batch is not complete"),
+        };
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_deref().ok(), Some("/vaulty/a.pdf"));
+        assert!(
+            matches!(&results[1], Err(Error::BadEndpoint(r)) if r == "path/insufficient_space")
+        );
+        assert!(
+            matches!(&results[2], Err(Error::RateLimited(r)) if r == "too_many_write_operations")
+        );
+
+        let status: FinishBatchStatus = serde_json::from_str(r#"{".tag": "other"}"#).unwrap();
+        assert!(matches!(status, FinishBatchStatus::Other));
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::Mutex;
+
+use crate::storage::Error;
+
+/// Upload session ready to be committed as part of a batch
+#[derive(Debug)]
+pub(super) struct Entry {
+    pub path: String,
+    pub session_id: String,
+    pub offset: usize,
+    pub commit: serde_json::Value,
+}
+
+/// Outcome of committing a single file of a batch
+#[derive(Debug)]
+pub struct Committed {
+    /// Path the file was stored at, if it was committed, or else the path it
+    /// was uploaded to
+    pub path: String,
+    pub result: Result<(), Error>,
+}
+
+/// Small uploads whose commits are deferred, so that they can all be
+/// committed in a single request
+///
+/// Each commit takes a lock on the user's Dropbox, so committing many small
+/// files one at a time is slow and counts against the rate limit. Files in
+/// a batch are uploaded right away, but only show up in Dropbox once the
+/// batch is finished with `DropboxClient::finish_batch`.
+#[derive(Debug, Default)]
+pub struct Batch {
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Number of files waiting to be committed
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(super) fn push(&self, entry: Entry) {
+        self.entries.lock().unwrap().push(entry);
+    }
+
+    /// Remove all files from the batch, in the order they were added
+    pub(super) fn take(&self) -> Vec<Entry> {
+        std::mem::take(&mut *self.entries.lock().unwrap())
+    }
+}
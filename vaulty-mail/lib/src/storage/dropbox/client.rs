@@ -1,17 +1,45 @@
 use std::time::Duration;
 
 use bytes::Bytes;
-use futures::stream::Stream;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use futures::future;
+use futures::stream::{Stream, StreamExt};
 use reqwest::header::CONTENT_TYPE;
 
 use super::api;
+use super::batch::{self, Batch, Committed};
 
+use crate::clock::Clock;
 use crate::storage::client::{Client, ClientFuture};
-use crate::storage::Error;
+use crate::storage::{Cleanup, CleanupGuard, Error, Metadata, RetryPolicy};
+
+/// A file found in a Dropbox folder
+#[derive(Clone, Debug)]
+pub struct ListedFile {
+    /// Full path, as displayed by Dropbox
+    pub path: String,
+    pub name: String,
+    pub size: usize,
+
+    /// See `hash::content_hash`
+    pub content_hash: String,
+}
 
 pub struct DropboxClient<'a> {
     token: &'a str,
     client: reqwest::Client,
+    chunk_size: usize,
+    retry: RetryPolicy,
+
+    /// Namespace that paths are relative to (e.g., a team space), if not
+    /// the token owner's personal space
+    namespace_id: Option<&'a str>,
+
+    /// Team member to act as, if the token is a team token
+    team_member_id: Option<&'a str>,
+
+    /// Batch that commits of single-request uploads are deferred to
+    batch: Option<&'a Batch>,
 }
 
 impl<'a> DropboxClient<'a> {
@@ -23,6 +51,56 @@ impl<'a> DropboxClient<'a> {
         Self {
             token: token,
             client: client,
+            chunk_size: api::DROPBOX_UPLOAD_CHUNK_SIZE,
+            retry: Default::default(),
+            namespace_id: None,
+            team_member_id: None,
+            batch: None,
+        }
+    }
+
+    /// Streams larger than `chunk_size` are uploaded in chunks using an
+    /// upload session
+    ///
+    /// A chunk size of 0 is ignored, since no upload would ever make progress.
+    pub fn with_chunk_size(self, chunk_size: usize) -> Self {
+        if chunk_size == 0 {
+            return self;
+        }
+
+        Self { chunk_size, ..self }
+    }
+
+    /// Retry requests that fail for transient reasons using this policy
+    pub fn with_retry_policy(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
+    /// Resolve all paths relative to the given namespace (e.g., a Dropbox
+    /// Business team space) instead of the token owner's personal space
+    pub fn with_namespace(self, namespace_id: &'a str) -> Self {
+        Self {
+            namespace_id: Some(namespace_id),
+            ..self
+        }
+    }
+
+    /// Act as the given team member when using a team token
+    pub fn with_team_member(self, team_member_id: &'a str) -> Self {
+        Self {
+            team_member_id: Some(team_member_id),
+            ..self
+        }
+    }
+
+    /// Defer the commit of uploads smaller than `chunk_size` to the given
+    /// batch
+    ///
+    /// Such files only show up in Dropbox once the batch is finished.
+    pub fn with_batch(self, batch: &'a Batch) -> Self {
+        Self {
+            batch: Some(batch),
+            ..self
         }
     }
 
@@ -30,25 +108,41 @@ impl<'a> DropboxClient<'a> {
     async fn request(
         &self,
         endpoint: api::Endpoint,
-        body: reqwest::Body,
+        body: Bytes,
         args: Option<&str>,
         content_type: Option<&str>,
     ) -> Result<bytes::Bytes, Error> {
-        let url = api::build_endpoint_url(endpoint);
+        let url = reqwest::Url::parse(&api::build_endpoint_url(endpoint))?;
+        let root = self.namespace_id.map(|namespace_id| {
+            serde_json::json!({".tag": "namespace_id", "namespace_id": namespace_id}).to_string()
+        });
 
-        let mut req = self
-            .client
-            .post(reqwest::Url::parse(&url)?)
-            .bearer_auth(&self.token)
-            .header(CONTENT_TYPE, content_type.unwrap_or("application/json"))
-            .body(body);
+        // The request is rebuilt for each attempt
+        let build = || {
+            let mut req = self
+                .client
+                .post(url.clone())
+                .bearer_auth(self.token)
+                .header(CONTENT_TYPE, content_type.unwrap_or("application/json"))
+                .body(body.clone());
 
-        if let Some(v) = args {
-            req = req.header(api::DROPBOX_ARG_HEADER, v);
-        }
+            if let Some(v) = args {
+                req = req.header(api::DROPBOX_ARG_HEADER, v);
+            }
+
+            if let Some(root) = &root {
+                req = req.header(api::DROPBOX_PATH_ROOT_HEADER, root.as_str());
+            }
+
+            if let Some(team_member_id) = self.team_member_id {
+                req = req.header(api::DROPBOX_SELECT_USER_HEADER, team_member_id);
+            }
+
+            req
+        };
 
         // Map response into an error if applicable
-        let resp = api::map_status(req.send().await?);
+        let resp = api::map_status(self.retry.send(build).await?);
 
         Ok(resp?.bytes().await?)
     }
@@ -61,6 +155,67 @@ impl<'a> DropboxClient<'a> {
         serde_json::from_slice(&resp).map_err(|e| e.into())
     }
 
+    pub async fn list_folder_continue(&self, cursor: &str) -> Result<api::ListFolderResult, Error> {
+        let body = serde_json::json!({ "cursor": cursor }).to_string();
+        let resp = self
+            .request(api::Endpoint::ListFolderContinue, body.into(), None, None)
+            .await?;
+        serde_json::from_slice(&resp).map_err(|e| e.into())
+    }
+
+    /// List the files in a folder and all of its subfolders, up to
+    /// `max_files`
+    ///
+    /// Files past `max_files` are not listed. A folder that does not exist
+    /// has no files.
+    pub async fn list_files(&self, path: &str, max_files: usize) -> Result<Vec<ListedFile>, Error> {
+        // The root folder is the empty path
+        let path = path.trim_end_matches('/');
+        let body = serde_json::json!({ "path": path, "recursive": true }).to_string();
+
+        let mut result: api::ListFolderResult = match self
+            .request(api::Endpoint::ListFolder, body.into(), None, None)
+            .await
+        {
+            Ok(resp) => serde_json::from_slice(&resp)?,
+            Err(Error::BadEndpoint(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut files = Vec::new();
+
+        loop {
+            for entry in result.entries {
+                if let api::SearchResultEntry::File {
+                    name,
+                    size,
+                    path_display,
+                    content_hash,
+                    ..
+                } = entry
+                {
+                    files.push(ListedFile {
+                        path: path_display,
+                        name,
+                        size,
+                        content_hash,
+                    });
+                }
+            }
+
+            if files.len() >= max_files {
+                files.truncate(max_files);
+                return Ok(files);
+            }
+
+            if !result.has_more {
+                return Ok(files);
+            }
+
+            result = self.list_folder_continue(&result.cursor).await?;
+        }
+    }
+
     /// Create a folder in user's Dropbox
     /// This function does not return any API metadata
     pub async fn create_folder(&self, path: &str) -> Result<(), Error> {
@@ -87,6 +242,286 @@ impl<'a> DropboxClient<'a> {
         Ok(())
     }
 
+    /// Build the property groups used to store object metadata
+    ///
+    /// Returns `None` if there is no metadata, or no property template has
+    /// been registered for the app.
+    fn property_groups(metadata: &Metadata) -> Option<serde_json::Value> {
+        let template_id = metadata.template_id.as_ref()?;
+
+        if metadata.is_empty() {
+            return None;
+        }
+
+        let fields: Vec<_> = metadata
+            .iter()
+            .map(|(k, v)| serde_json::json!({"name": k, "value": v}))
+            .collect();
+
+        Some(serde_json::json!([{"template_id": template_id, "fields": fields}]))
+    }
+
+    /// Build the commit info for a file upload
+    ///
+    /// Metadata is attached as a property group, which requires a property
+    /// template to have been registered for the app.
+    fn upload_commit(path: &str, metadata: &Metadata) -> serde_json::Value {
+        // Auto-rename the attachment if it exists
+        let mut args = serde_json::json!({"path": path, "autorename": true});
+
+        if let Some(groups) = Self::property_groups(metadata) {
+            args["property_groups"] = groups;
+        }
+
+        args
+    }
+
+    /// Upload a single chunk as part of an upload session
+    ///
+    /// Starts a new session if `cursor` is `None`. Returns the session ID and
+    /// offset to use for the next chunk.
+    async fn upload_chunk(
+        &self,
+        cursor: Option<(String, usize)>,
+        data: Vec<u8>,
+    ) -> Result<(String, usize), Error> {
+        let len = data.len();
+
+        match cursor {
+            None => {
+                let args = serde_json::json!({"close": false}).to_string();
+                let resp = self
+                    .request(
+                        api::Endpoint::UploadSessionStart,
+                        data.into(),
+                        Some(&args),
+                        Some("application/octet-stream"),
+                    )
+                    .await?;
+                let result: api::UploadSessionStartResult = serde_json::from_slice(&resp)?;
+
+                Ok((result.session_id, len))
+            }
+            Some((session_id, offset)) => {
+                let args = serde_json::json!({
+                    "cursor": {"session_id": session_id, "offset": offset},
+                    "close": false,
+                })
+                .to_string();
+                let _resp = self
+                    .request(
+                        api::Endpoint::UploadSessionAppend,
+                        data.into(),
+                        Some(&args),
+                        Some("application/octet-stream"),
+                    )
+                    .await?;
+
+                Ok((session_id, offset + len))
+            }
+        }
+    }
+
+    /// Upload a stream, in chunks if it is larger than `chunk_size`
+    ///
+    /// `guard` is set once an upload session has been started. Returns the
+    /// path the file was stored at, as it is renamed if the path is taken.
+    /// Files committed as part of a batch keep their path until then.
+    async fn upload_chunks(
+        &self,
+        path: &str,
+        data: impl Stream<Item = Result<Bytes, crate::Error>>,
+        commit: serde_json::Value,
+        guard: &mut Option<CleanupGuard<future::Ready<Cleanup>>>,
+    ) -> Result<String, Error> {
+        let mut data = Box::pin(data);
+
+        // At most one chunk (plus the latest piece of the stream) is held
+        // in memory at any time
+        let mut buf = Vec::new();
+        let mut cursor = None;
+
+        while let Some(bytes) = data.next().await {
+            let bytes = bytes.map_err(|e| Error::Internal(e.to_string()))?;
+            buf.extend_from_slice(&bytes);
+
+            while buf.len() >= self.chunk_size {
+                let rest = buf.split_off(self.chunk_size);
+                let chunk = std::mem::replace(&mut buf, rest);
+                cursor = Some(self.upload_chunk(cursor, chunk).await?);
+
+                guard.get_or_insert_with(|| {
+                    CleanupGuard::new(path, future::ready(Cleanup::Expires))
+                });
+            }
+        }
+
+        let resp = match (cursor, self.batch) {
+            // Small enough for a single request, but committed later on
+            (None, Some(batch)) => {
+                let offset = buf.len();
+                let args = serde_json::json!({"close": true}).to_string();
+                let resp = self
+                    .request(
+                        api::Endpoint::UploadSessionStart,
+                        buf.into(),
+                        Some(&args),
+                        Some("application/octet-stream"),
+                    )
+                    .await?;
+                let result: api::UploadSessionStartResult = serde_json::from_slice(&resp)?;
+
+                batch.push(batch::Entry {
+                    path: path.to_string(),
+                    session_id: result.session_id,
+                    offset,
+                    commit,
+                });
+
+                return Ok(path.to_string());
+            }
+            // Small enough for a single request
+            (None, None) => {
+                self.request(
+                    api::Endpoint::FileUpload,
+                    buf.into(),
+                    Some(&commit.to_string()),
+                    Some("application/octet-stream"),
+                )
+                .await?
+            }
+            // Upload the remainder (if any) and commit the file
+            (Some((session_id, offset)), _) => {
+                let args = serde_json::json!({
+                    "cursor": {"session_id": session_id, "offset": offset},
+                    "commit": commit,
+                })
+                .to_string();
+
+                self.request(
+                    api::Endpoint::UploadSessionFinish,
+                    buf.into(),
+                    Some(&args),
+                    Some("application/octet-stream"),
+                )
+                .await?
+            }
+        };
+
+        let result: api::FileUploadResult = serde_json::from_slice(&resp)?;
+
+        Ok(result.path_display)
+    }
+
+    /// Commit all files of a batch
+    ///
+    /// Dropbox commits a batch in the background, so its status is checked
+    /// until every file is committed. Results are in the order the files
+    /// were added to the batch. An error is only returned if the batch as a
+    /// whole failed, in which case none of its files were committed.
+    pub async fn finish_batch(&self, batch: &Batch) -> Result<Vec<Committed>, Error> {
+        let entries = batch.take();
+        let mut committed = Vec::with_capacity(entries.len());
+
+        for entries in entries.chunks(api::DROPBOX_FINISH_BATCH_MAX_ENTRIES) {
+            let body = serde_json::json!({
+                "entries": entries
+                    .iter()
+                    .map(|e| serde_json::json!({
+                        "cursor": {"session_id": e.session_id, "offset": e.offset},
+                        "commit": e.commit,
+                    }))
+                    .collect::<Vec<_>>(),
+            })
+            .to_string();
+
+            let resp = self
+                .request(
+                    api::Endpoint::UploadSessionFinishBatch,
+                    body.into(),
+                    None,
+                    None,
+                )
+                .await?;
+            let results = self.wait_for_batch(serde_json::from_slice(&resp)?).await?;
+
+            if results.len() != entries.len() {
+                return Err(Error::Internal(format!(
+                    "Batch returned {} results for {} files",
+                    results.len(),
+                    entries.len()
+                )));
+            }
+
+            committed.extend(entries.iter().zip(results).map(|(entry, result)| {
+                match result.into_result() {
+                    // Dropbox renames files that already exist
+                    Ok(path) => Committed {
+                        path,
+                        result: Ok(()),
+                    },
+                    Err(e) => Committed {
+                        path: entry.path.clone(),
+                        result: Err(e),
+                    },
+                }
+            }));
+        }
+
+        Ok(committed)
+    }
+
+    /// Wait for a batch commit to complete, and return the result of each
+    /// commit
+    async fn wait_for_batch(
+        &self,
+        status: api::FinishBatchStatus,
+    ) -> Result<Vec<api::FinishBatchEntry>, Error> {
+        let async_job_id = match status {
+            api::FinishBatchStatus::Complete { entries } => return Ok(entries),
+            api::FinishBatchStatus::AsyncJobId { async_job_id } => async_job_id,
+            status => {
+                return Err(Error::Internal(format!(
+                    "Unexpected batch status: {:?}",
+                    status
+                )))
+            }
+        };
+
+        let body = serde_json::json!({ "async_job_id": async_job_id }).to_string();
+        let mut delay = Duration::from_millis(api::DROPBOX_FINISH_BATCH_POLL_DELAY);
+        let max_delay = Duration::from_millis(api::DROPBOX_FINISH_BATCH_MAX_POLL_DELAY);
+
+        for _ in 0..api::DROPBOX_FINISH_BATCH_MAX_POLLS {
+            tokio::time::delay_for(delay).await;
+            delay = std::cmp::min(delay * 2, max_delay);
+
+            let resp = self
+                .request(
+                    api::Endpoint::UploadSessionFinishBatchCheck,
+                    body.clone().into(),
+                    None,
+                    None,
+                )
+                .await?;
+
+            let status: api::FinishBatchStatus = serde_json::from_slice(&resp)?;
+
+            match status {
+                api::FinishBatchStatus::Complete { entries } => return Ok(entries),
+                api::FinishBatchStatus::InProgress => (),
+                status => {
+                    return Err(Error::Internal(format!(
+                        "Unexpected batch status: {:?}",
+                        status
+                    )))
+                }
+            }
+        }
+
+        Err(Error::RequestTimeout)
+    }
+
     pub async fn search(&self, path: &str, query: &str) -> Result<api::SearchResult, Error> {
         let data = serde_json::json!({"path": path, "query": query}).to_string();
         let resp = self
@@ -96,34 +531,102 @@ impl<'a> DropboxClient<'a> {
     }
 }
 
+/// Exchange an OAuth2 refresh token for a new short-lived access token
+///
+/// Returns the new access token along with its expiry time.
+pub async fn refresh_access_token(
+    refresh_token: &str,
+    app_key: &str,
+    app_secret: &str,
+    clock: &dyn Clock,
+) -> Result<(String, DateTime<Utc>), Error> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(api::DROPBOX_REQUEST_TIMEOUT))
+        .build()
+        .unwrap();
+
+    let params = [
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+    ];
+
+    let req = client
+        .post(reqwest::Url::parse(api::DROPBOX_OAUTH_TOKEN)?)
+        .basic_auth(app_key, Some(app_secret))
+        .form(&params);
+
+    // Map response into an error if applicable
+    let resp = api::map_status(req.send().await?)?.bytes().await?;
+    let result: api::RefreshTokenResult = serde_json::from_slice(&resp)?;
+
+    let expiry = clock.now() + ChronoDuration::seconds(result.expires_in);
+
+    Ok((result.access_token, expiry))
+}
+
 impl<'a> Client for DropboxClient<'a> {
     /// Upload a file to a user's Dropbox
-    /// This function does not return any API metadata
+    ///
+    /// Dropbox has no way to discard an upload session, so the chunks of a
+    /// failed or cancelled upload are left to expire. They are never visible
+    /// in the user's Dropbox.
     fn upload_stream(
         &self,
         path: &str,
         data: impl Stream<Item = Result<Bytes, crate::Error>> + Send + Sync + 'static,
-    ) -> ClientFuture<'_, ()> {
-        // Auto-rename the attachment if it exists
-        let args = serde_json::json!({"path": path, "autorename": true}).to_string();
-        let url = api::build_endpoint_url(api::Endpoint::FileUpload);
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, String> {
+        let commit = Self::upload_commit(path, metadata);
+        let path = path.to_string();
 
         Box::pin(async move {
-            let mut req = self
-                .client
-                .post(reqwest::Url::parse(&url)?)
-                .bearer_auth(&self.token)
-                .header(CONTENT_TYPE, "application/octet-stream")
-                .body(reqwest::Body::wrap_stream(data));
+            // Set once an upload session has been started
+            let mut guard = None;
+            let result = self.upload_chunks(&path, data, commit, &mut guard).await;
+
+            match guard {
+                Some(guard) if result.is_err() => {
+                    guard.run().await;
+                }
+                Some(guard) => guard.disarm(),
+                None => (),
+            }
+
+            result
+        })
+    }
 
-            req = req.header(api::DROPBOX_ARG_HEADER, args);
+    /// Overwrite the property group of an existing file
+    ///
+    /// This is a no-op if there is no metadata to store.
+    fn update_metadata(&self, path: &str, metadata: &Metadata) -> ClientFuture<'_, ()> {
+        let groups = Self::property_groups(metadata);
+        let path = path.to_string();
 
-            // Map response into an error if applicable
-            let _resp = api::map_status(req.send().await?)?.bytes().await?;
+        Box::pin(async move {
+            if let Some(groups) = groups {
+                let body = serde_json::json!({"path": path, "property_groups": groups}).to_string();
+                let _resp = self
+                    .request(api::Endpoint::PropertiesOverwrite, body.into(), None, None)
+                    .await?;
+            }
 
             Ok(())
         })
     }
+
+    /// Dropbox creates missing parents along with the folder, and answers
+    /// with a conflict if the folder already exists
+    fn ensure_folder(&self, path: &str) -> ClientFuture<'_, ()> {
+        let path = path.to_string();
+
+        Box::pin(async move {
+            match self.create_folder(&path).await {
+                Ok(()) | Err(Error::BadEndpoint(_)) => Ok(()),
+                Err(e) => Err(e),
+            }
+        })
+    }
 }
 
 #[cfg(test)]
@@ -163,6 +666,41 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_chunked_upload_stream() {
+        let token = std::env::var("DROPBOX_TOKEN").expect("No Dropbox token found");
+        let client = DropboxClient::from_token(&token).with_chunk_size(4);
+        let data =
+            futures::stream::iter(vec![Ok(Bytes::from("Hello ")), Ok(Bytes::from("there!"))]);
+
+        let result = client
+            .upload_stream("/vaulty_test_chunked.txt", data, &Default::default())
+            .await;
+
+        println!("{:?}", result);
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_batch_upload() {
+        let token = std::env::var("DROPBOX_TOKEN").expect("No Dropbox token found");
+        let batch = Batch::new();
+        let client = DropboxClient::from_token(&token).with_batch(&batch);
+
+        for path in &["/vaulty_test_batch_1.txt", "/vaulty_test_batch_2.txt"] {
+            let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+            let result = client.upload_stream(path, data, &Default::default()).await;
+            assert!(result.is_ok());
+        }
+        assert_eq!(batch.len(), 2);
+
+        let result = client.finish_batch(&batch).await;
+
+        println!("{:?}", result);
+        assert!(result.unwrap().iter().all(|c| c.result.is_ok()));
+        assert!(batch.is_empty());
+    }
+
     #[tokio::test]
     /// /vaulty/search1 -> "test/", "test123/"
     async fn test_search_folders() {
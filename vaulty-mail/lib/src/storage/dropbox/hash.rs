@@ -0,0 +1,40 @@
+use sha2::{Digest, Sha256};
+
+/// Size of the blocks that Dropbox hashes separately
+const BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Dropbox content hash of a file: the SHA-256 of the SHA-256 of each 4 MB
+/// block, hex-encoded
+///
+/// This is the `content_hash` of files listed by Dropbox, which differs from
+/// the SHA-256 that attachments are hashed with (see `email::content_hash`).
+pub fn content_hash(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+
+    for block in data.chunks(BLOCK_SIZE) {
+        hasher.input(Sha256::digest(block).as_slice());
+    }
+
+    hex::encode(hasher.result().as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_blocks() {
+        // No blocks at all
+        assert_eq!(
+            content_hash(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let data = vec![7u8; BLOCK_SIZE + 10];
+        let mut blocks = Sha256::digest(&data[..BLOCK_SIZE]).to_vec();
+        blocks.extend(Sha256::digest(&data[BLOCK_SIZE..]));
+
+        assert_eq!(content_hash(&data), hex::encode(Sha256::digest(&blocks)));
+        assert_ne!(content_hash(b"a"), crate::email::content_hash(b"a"));
+    }
+}
@@ -1,2 +1,4 @@
-mod api;
+pub(crate) mod api;
+pub mod batch;
 pub mod client;
+pub mod hash;
@@ -3,17 +3,23 @@ use std::fmt;
 
 use serde::{Deserialize, Serialize};
 
+use crate::error::Cause;
+
 /// Error type for storage backends.
-/// Each type can store a message for logging purposes.
+/// Each type can store a message for logging purposes. Errors built from
+/// another error (e.g., a failed HTTP request) keep it as their source.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Error {
-    UrlParseError(String),
+    UrlParseError(Cause),
     RequestTimeout,
-    RequestError(String),
-    JsonParseError(String),
+    RequestError(Cause),
+    JsonParseError(Cause),
     BadInput(String),
     BadEndpoint(String),
     TokenExpired(String),
+    /// The credentials are valid, but not allowed to access the path (e.g.,
+    /// an S3 bucket policy)
+    AccessDenied(String),
     RateLimited(String),
     Internal(String),
 }
@@ -21,24 +27,48 @@ pub enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Error::UrlParseError(_) => f.write_str("UrlParseError"),
-            Error::RequestTimeout => f.write_str("RequestTimeout"),
-            Error::RequestError(ref msg) => f.write_str(&format!("RequestError: {}", msg)),
-            Error::JsonParseError(ref msg) => f.write_str(&format!("JsonParseError: {}", msg)),
-            Error::BadInput(_) => f.write_str("BadInput"),
-            Error::BadEndpoint(_) => f.write_str("BadEndpoint"),
-            Error::TokenExpired(_) => f.write_str("TokenExpired"),
-            Error::RateLimited(_) => f.write_str("RateLimited"),
-            Error::Internal(_) => f.write_str("Internal Error"),
+            Error::UrlParseError(ref cause) => write!(f, "Invalid storage URL: {}", cause),
+            Error::RequestTimeout => {
+                f.write_str("Request to storage timed out; the upload will be retried")
+            }
+            Error::RequestError(ref cause) => write!(f, "Request to storage failed: {}", cause),
+            Error::JsonParseError(ref cause) => {
+                write!(f, "Invalid response from storage: {}", cause)
+            }
+            Error::BadInput(ref msg) => write!(f, "Storage rejected the request: {}", msg),
+            Error::BadEndpoint(ref msg) => write!(f, "Storage path conflict: {}", msg),
+            Error::TokenExpired(ref msg) => write!(
+                f,
+                "Storage token expired or lacks access; reconnect the storage account: {}",
+                msg
+            ),
+            Error::AccessDenied(ref msg) => write!(
+                f,
+                "Storage denied access; check the permissions of the storage credentials: {}",
+                msg
+            ),
+            Error::RateLimited(ref msg) => {
+                write!(f, "Storage is rate limiting requests; retry later: {}", msg)
+            }
+            Error::Internal(ref msg) => write!(f, "Storage error: {}", msg),
         }
     }
 }
 
-impl error::Error for Error {}
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::UrlParseError(cause)
+            | Error::RequestError(cause)
+            | Error::JsonParseError(cause) => cause.source(),
+            _ => None,
+        }
+    }
+}
 
 impl From<url::ParseError> for Error {
     fn from(err: url::ParseError) -> Self {
-        Self::UrlParseError(err.to_string())
+        Self::UrlParseError(Cause::with_source("", err))
     }
 }
 
@@ -47,13 +77,13 @@ impl From<reqwest::Error> for Error {
         if err.is_timeout() {
             Self::RequestTimeout
         } else {
-            Self::RequestError(err.to_string())
+            Self::RequestError(Cause::with_source("", err))
         }
     }
 }
 
 impl From<serde_json::error::Error> for Error {
     fn from(err: serde_json::error::Error) -> Self {
-        Self::JsonParseError(err.to_string())
+        Self::JsonParseError(Cause::with_source("", err))
     }
 }
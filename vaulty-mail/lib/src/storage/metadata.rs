@@ -0,0 +1,156 @@
+use crate::clock::Clock;
+use crate::email::Email;
+
+/// Custom metadata attached to each uploaded object.
+///
+/// Backends that support object metadata (e.g., Dropbox property groups)
+/// forward these fields so that storage-side tooling can filter and search
+/// without touching the Vaulty DB.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    /// Backend-specific template ID, if the backend requires one
+    /// (e.g., Dropbox property template)
+    pub template_id: Option<String>,
+
+    fields: Vec<(String, String)>,
+}
+
+impl Metadata {
+    /// Render metadata for an email using the given template.
+    ///
+    /// The template is a comma-separated list of `key={placeholder}` entries.
+    /// Supported placeholders: `{sender}`, `{email_id}`, `{message_id}`, and
+    /// `{date}` (time of receipt in RFC 3339 format).
+    ///
+    /// For example: `vaulty-sender={sender},vaulty-email-id={email_id}`
+    ///
+    /// Emails that were not accepted by the server yet are dated now.
+    pub fn from_template(template: &str, email: &Email, clock: &dyn Clock) -> Self {
+        let date = email.received_time.unwrap_or_else(|| clock.now());
+        Self::render(template, email, &date.to_rfc3339())
+    }
+
+    fn render(template: &str, email: &Email, date: &str) -> Self {
+        let email_id = email.uuid.to_string();
+        let message_id = email.message_id.as_deref().unwrap_or("");
+
+        let fields = template
+            .split(',')
+            .filter_map(|entry| {
+                let mut kv = entry.splitn(2, '=');
+                let key = kv.next()?.trim();
+                let value = kv.next()?.trim();
+
+                if key.is_empty() {
+                    return None;
+                }
+
+                // Rendered in a single pass, so that placeholders in values
+                // (e.g., a sender of "{date}") are kept as is
+                let mut out = String::with_capacity(value.len());
+                let mut rest = value;
+
+                while let Some(start) = rest.find('{') {
+                    out.push_str(&rest[..start]);
+                    rest = &rest[start..];
+
+                    let end = match rest.find('}') {
+                        Some(end) => end,
+                        None => break,
+                    };
+
+                    match &rest[1..end] {
+                        "sender" => out.push_str(&email.sender),
+                        "email_id" => out.push_str(&email_id),
+                        "message_id" => out.push_str(message_id),
+                        "date" => out.push_str(date),
+                        _ => out.push_str(&rest[..=end]),
+                    }
+
+                    rest = &rest[end + 1..];
+                }
+                out.push_str(rest);
+
+                Some((key.to_string(), out))
+            })
+            .collect();
+
+        Self {
+            template_id: None,
+            fields,
+        }
+    }
+
+    pub fn with_template_id(self, template_id: Option<String>) -> Self {
+        Self {
+            template_id,
+            ..self
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_template() {
+        let email = Email {
+            sender: "abc@abc.com".to_string(),
+            message_id: Some("1234@mail.abc.com".to_string()),
+            ..Default::default()
+        };
+
+        let template =
+            "vaulty-sender={sender}, vaulty-message-id={message_id},vaulty-date={date},bad";
+        let metadata = Metadata::render(template, &email, "2020-02-09T19:38:12+00:00");
+        let fields: Vec<_> = metadata.iter().collect();
+
+        assert_eq!(
+            fields,
+            vec![
+                ("vaulty-sender", "abc@abc.com"),
+                ("vaulty-message-id", "1234@mail.abc.com"),
+                ("vaulty-date", "2020-02-09T19:38:12+00:00"),
+            ]
+        );
+
+        // Placeholders in values are not expanded
+        let email = Email {
+            sender: "{message_id}".to_string(),
+            message_id: Some("{date}".to_string()),
+            ..Default::default()
+        };
+
+        let metadata = Metadata::render("a={sender},b={message_id} {unknown} {", &email, "now");
+        let fields: Vec<_> = metadata.iter().collect();
+
+        assert_eq!(
+            fields,
+            vec![("a", "{message_id}"), ("b", "{date} {unknown} {")]
+        );
+    }
+
+    #[test]
+    fn date_is_time_of_receipt() {
+        let received = "2020-02-09T19:38:12Z".parse().unwrap();
+        let email = Email {
+            received_time: Some(received),
+            ..Default::default()
+        };
+        let clock = crate::clock::FixedClock::new(received + chrono::Duration::hours(1));
+
+        let metadata = Metadata::from_template("vaulty-date={date}", &email, &clock);
+        let fields: Vec<_> = metadata.iter().collect();
+
+        assert_eq!(fields, vec![("vaulty-date", "2020-02-09T19:38:12+00:00")]);
+    }
+}
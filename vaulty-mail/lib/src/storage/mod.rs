@@ -1,7 +1,17 @@
 mod backends;
+mod cleanup;
 pub mod client;
 pub mod dropbox;
 mod error;
+mod metadata;
+mod retry;
+pub mod s3;
+mod watchdog;
 
 pub use backends::Backend;
+pub use cleanup::Cleanup;
+pub(crate) use cleanup::CleanupGuard;
 pub use error::Error;
+pub use metadata::Metadata;
+pub use retry::RetryPolicy;
+pub use watchdog::{stalled_uploads, Watchdog};
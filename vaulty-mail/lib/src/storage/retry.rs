@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+use crate::config::Config;
+
+/// Longest delay between two attempts
+///
+/// Requests that the backend asks us to retry later than this are not
+/// retried at all.
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Retries storage requests that fail for transient reasons
+///
+/// Timeouts, connection failures, rate limiting (429) and 5xx responses are
+/// retried with exponential backoff and jitter. If the backend sends a
+/// `Retry-After` header, it is used as the delay instead.
+///
+/// Retries happen per request (e.g., per upload chunk), as an upload stream
+/// can only be read once.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+
+    /// Delay before the first retry; doubled for each retry after that
+    pub base_delay: Duration,
+
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: crate::config::DEFAULT_UPLOAD_MAX_ATTEMPTS,
+            base_delay: Duration::from_millis(crate::config::DEFAULT_UPLOAD_RETRY_DELAY_MS),
+            max_delay: MAX_RETRY_DELAY,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_attempts: config.upload_max_attempts,
+            base_delay: Duration::from_millis(config.upload_retry_delay_ms),
+            ..Default::default()
+        }
+    }
+
+    fn is_retryable(status: StatusCode) -> bool {
+        status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+    }
+
+    /// Delay before the given retry (starting at 1)
+    ///
+    /// The delay is picked at random between half and all of the backoff, so
+    /// that concurrent uploads that failed together do not retry together.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32 << retry.saturating_sub(1).min(16);
+        let delay = self
+            .base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        let half = delay.as_millis() as u64 / 2;
+        let jitter = rand::thread_rng().gen_range(0, half + 1);
+
+        Duration::from_millis(half + jitter)
+    }
+
+    /// Delay requested by the backend, if any
+    ///
+    /// Only the delay-seconds form of the header is supported.
+    fn retry_after(resp: &Response) -> Option<Duration> {
+        resp.headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs)
+    }
+
+    /// Send the request built by `build`, retrying transient failures
+    ///
+    /// A new request is built for each attempt. The response of the last
+    /// attempt is returned as is; status codes are left to the caller.
+    pub async fn send(
+        &self,
+        build: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, reqwest::Error> {
+        let mut attempt = 1;
+
+        loop {
+            let result = build().send().await;
+
+            let delay = match &result {
+                Ok(resp) if Self::is_retryable(resp.status()) => match Self::retry_after(resp) {
+                    Some(delay) if delay > self.max_delay => return result,
+                    Some(delay) => delay,
+                    None => self.backoff(attempt),
+                },
+                // Errors without a response are timeouts or connection
+                // failures, unless the request could not be built at all
+                Err(e) if !e.is_builder() => self.backoff(attempt),
+                _ => return result,
+            };
+
+            if attempt >= self.max_attempts {
+                return result;
+            }
+
+            match &result {
+                Ok(resp) => log::warn!(
+                    "Storage request to {} failed with {} (attempt {} of {}); retrying in {:?}",
+                    resp.url(),
+                    resp.status(),
+                    attempt,
+                    self.max_attempts,
+                    delay
+                ),
+                Err(e) => log::warn!(
+                    "Storage request failed: {} (attempt {} of {}); retrying in {:?}",
+                    e,
+                    attempt,
+                    self.max_attempts,
+                    delay
+                ),
+            }
+
+            tokio::time::delay_for(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(1000),
+            max_delay: Duration::from_millis(3000),
+        };
+
+        for _ in 0..20 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_millis(500) && first <= Duration::from_millis(1000));
+
+            let second = policy.backoff(2);
+            assert!(second >= Duration::from_millis(1000) && second <= Duration::from_millis(2000));
+
+            // Capped at the max delay
+            let fourth = policy.backoff(4);
+            assert!(fourth >= Duration::from_millis(1500) && fourth <= Duration::from_millis(3000));
+        }
+
+        // Large retry counts do not overflow
+        assert!(policy.backoff(100) <= policy.max_delay);
+    }
+}
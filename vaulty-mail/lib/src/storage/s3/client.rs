@@ -0,0 +1,525 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::stream::{Stream, TryStreamExt};
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, ETAG};
+use reqwest::Method;
+
+use super::api;
+
+use crate::clock::{Clock, SystemClock};
+use crate::storage::client::{Client, ClientFuture};
+use crate::storage::{Cleanup, CleanupGuard, Error, Metadata, RetryPolicy};
+
+/// Pre-signed upload of a single object
+#[derive(Clone, Debug)]
+pub struct PresignedUpload {
+    pub url: String,
+
+    /// Headers that must be sent as is with the PUT request
+    pub headers: Vec<(String, String)>,
+}
+
+/// Size and hash of a stored object
+#[derive(Clone, Debug)]
+pub struct ObjectInfo {
+    pub size: usize,
+
+    /// Hex SHA-256 of the object, if it was uploaded through a pre-signed URL
+    pub sha256: Option<String>,
+}
+
+/// Client for S3 and S3-compatible object stores (e.g., MinIO, Wasabi)
+///
+/// Requests are path-style (`{endpoint}/{bucket}/{key}`), which is supported
+/// by all S3-compatible services.
+#[derive(Clone)]
+pub struct S3Client {
+    config: api::Config,
+    client: reqwest::Client,
+    part_size: usize,
+    retry: RetryPolicy,
+}
+
+impl S3Client {
+    /// Build a client from the JSON settings stored in an address
+    /// `storage_token`
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        let config: api::Config = serde_json::from_str(token)
+            .map_err(|e| Error::BadInput(format!("Invalid S3 settings: {}", e)))?;
+
+        Ok(Self::new(config))
+    }
+
+    pub fn new(config: api::Config) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(api::S3_REQUEST_TIMEOUT))
+            .build()
+            .unwrap();
+
+        Self {
+            config,
+            client,
+            part_size: api::S3_UPLOAD_PART_SIZE,
+            retry: Default::default(),
+        }
+    }
+
+    /// Streams larger than `part_size` are uploaded in parts using a
+    /// multipart upload
+    ///
+    /// S3 rejects parts smaller than 5 MB, so smaller sizes are rounded up.
+    pub fn with_part_size(self, part_size: usize) -> Self {
+        Self {
+            part_size: part_size.max(api::S3_MIN_PART_SIZE),
+            ..self
+        }
+    }
+
+    /// Retry requests that fail for transient reasons using this policy
+    pub fn with_retry_policy(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
+    /// URL of the object at the given path
+    ///
+    /// The object is only accessible with the bucket credentials, unless the
+    /// bucket is public.
+    pub fn object_url(&self, path: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            self.config.endpoint(),
+            api::uri_encode(&self.config.bucket, true),
+            api::uri_encode(path.trim_start_matches('/'), false)
+        )
+    }
+
+    /// Custom metadata is stored as user-defined object metadata
+    fn metadata_headers(metadata: &Metadata) -> Vec<(String, String)> {
+        metadata
+            .iter()
+            .map(|(k, v)| (format!("x-amz-meta-{}", k.to_lowercase()), v.to_string()))
+            .collect()
+    }
+
+    /// Canonical URI, full URL, and host of the object with the given key
+    ///
+    /// `key` must already be URI-encoded.
+    fn object_request(&self, key: &str) -> Result<(String, reqwest::Url, String), Error> {
+        let canonical_uri = format!("/{}/{}", api::uri_encode(&self.config.bucket, true), key);
+        let url = reqwest::Url::parse(&format!("{}{}", self.config.endpoint(), canonical_uri))?;
+
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(Error::BadInput(format!("Invalid S3 endpoint: {}", url))),
+        };
+
+        Ok((canonical_uri, url, host))
+    }
+
+    /// Pre-sign an upload of an object, so that it can be uploaded without
+    /// the bucket credentials
+    ///
+    /// The object size and SHA-256 are signed, so any other content is
+    /// rejected by S3. The hash is also stored with the object, so that the
+    /// upload can be verified with `head_object`.
+    pub fn presign_upload(
+        &self,
+        path: &str,
+        size: usize,
+        sha256: &str,
+        metadata: &Metadata,
+        expires: u64,
+    ) -> Result<PresignedUpload, Error> {
+        let key = api::uri_encode(path.trim_start_matches('/'), false);
+        let (canonical_uri, mut url, host) = self.object_request(&key)?;
+
+        let mut headers = vec![
+            ("host".to_string(), host),
+            ("content-length".to_string(), size.to_string()),
+            ("x-amz-content-sha256".to_string(), sha256.to_string()),
+            (api::S3_SHA256_METADATA.to_string(), sha256.to_string()),
+        ];
+        headers.extend(Self::metadata_headers(metadata));
+        headers.sort();
+
+        let query = api::presign(
+            "PUT",
+            &canonical_uri,
+            &headers,
+            sha256,
+            expires,
+            &self.config,
+            SystemClock.now(),
+        );
+        url.set_query(Some(&query));
+
+        Ok(PresignedUpload {
+            url: url.to_string(),
+            // Host is set by the client from the URL
+            headers: headers.into_iter().filter(|(k, _)| k != "host").collect(),
+        })
+    }
+
+    /// Look up the size and hash of an object
+    pub async fn head_object(&self, path: &str) -> Result<ObjectInfo, Error> {
+        let key = api::uri_encode(path.trim_start_matches('/'), false);
+        let (canonical_uri, url, host) = self.object_request(&key)?;
+
+        let now = SystemClock.now();
+        let payload_hash = api::hex_sha256(b"");
+
+        let headers = vec![
+            ("host".to_string(), host),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            (
+                "x-amz-date".to_string(),
+                now.format("%Y%m%dT%H%M%SZ").to_string(),
+            ),
+        ];
+
+        let auth = api::sign(
+            "HEAD",
+            &canonical_uri,
+            &headers,
+            &payload_hash,
+            &self.config,
+            now,
+        );
+
+        let build = || {
+            let mut req = self
+                .client
+                .head(url.clone())
+                .header(AUTHORIZATION, auth.as_str());
+
+            for (k, v) in headers.iter().filter(|(k, _)| k != "host") {
+                req = req.header(k.as_str(), v.as_str());
+            }
+
+            req
+        };
+
+        let resp = api::map_status(self.retry.send(build).await?)?;
+
+        // The body of a HEAD response is always empty, so the length is read
+        // from the header
+        let header = |name: &str| {
+            resp.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+
+        let size = header(CONTENT_LENGTH.as_str())
+            .and_then(|v| v.parse::<usize>().ok())
+            .ok_or_else(|| Error::Internal(format!("No object size returned for {}", path)))?;
+
+        Ok(ObjectInfo {
+            size,
+            sha256: header(api::S3_SHA256_METADATA),
+        })
+    }
+
+    /// Download an object in full
+    pub async fn get_object(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let key = api::uri_encode(path.trim_start_matches('/'), false);
+        let resp = self
+            .send(Method::GET, &key, "", Vec::new(), Vec::new())
+            .await?;
+
+        Ok(resp.bytes().await?.to_vec())
+    }
+
+    /// Sign and send a request for the object with the given key
+    ///
+    /// `key` must already be URI-encoded, and `query` must be canonical (see
+    /// `api::sign_with_query`). `extra_headers` must have lowercase names
+    /// (e.g., `x-amz-meta-*`).
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &str,
+        data: Vec<u8>,
+        extra_headers: Vec<(String, String)>,
+    ) -> Result<reqwest::Response, Error> {
+        let (canonical_uri, mut url, host) = self.object_request(key)?;
+
+        if !query.is_empty() {
+            url.set_query(Some(query));
+        }
+
+        let now = SystemClock.now();
+        let payload_hash = api::hex_sha256(&data);
+
+        let mut headers = vec![
+            ("host".to_string(), host),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            (
+                "x-amz-date".to_string(),
+                now.format("%Y%m%dT%H%M%SZ").to_string(),
+            ),
+        ];
+        headers.extend(extra_headers);
+        headers.sort();
+
+        let auth = api::sign_with_query(
+            method.as_str(),
+            &canonical_uri,
+            query,
+            &headers,
+            &payload_hash,
+            &self.config,
+            now,
+        );
+
+        let data = Bytes::from(data);
+
+        // The request is rebuilt for each attempt. The signature is valid
+        // for several minutes, which covers all retries.
+        let build = || {
+            let mut req = self
+                .client
+                .request(method.clone(), url.clone())
+                .header(AUTHORIZATION, auth.as_str())
+                .header(CONTENT_TYPE, "application/octet-stream");
+
+            // Host is set by reqwest from the URL
+            for (k, v) in headers.iter().filter(|(k, _)| k != "host") {
+                req = req.header(k.as_str(), v.as_str());
+            }
+
+            req.body(data.clone())
+        };
+
+        // Map response into an error if applicable
+        api::map_status(self.retry.send(build).await?)
+    }
+
+    /// Upload an object to the configured bucket
+    ///
+    /// `key` must already be URI-encoded. `extra_headers` must have lowercase
+    /// names (e.g., `x-amz-meta-*`).
+    async fn put_object(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        extra_headers: Vec<(String, String)>,
+    ) -> Result<(), Error> {
+        let _resp = self.send(Method::PUT, key, "", data, extra_headers).await?;
+
+        Ok(())
+    }
+
+    /// Start a multipart upload of an object
+    ///
+    /// Returns the upload ID. Object metadata is set when the upload starts.
+    async fn create_multipart_upload(
+        &self,
+        key: &str,
+        extra_headers: Vec<(String, String)>,
+    ) -> Result<String, Error> {
+        let resp = self
+            .send(Method::POST, key, "uploads=", Vec::new(), extra_headers)
+            .await?;
+        let body = resp.text().await?;
+
+        api::xml_element(&body, "UploadId")
+            .map(String::from)
+            .ok_or_else(|| Error::Internal(format!("No upload ID returned: {}", body)))
+    }
+
+    /// Upload a single part of a multipart upload
+    ///
+    /// Part numbers start at 1. Returns the ETag of the part, which is
+    /// needed to complete the upload.
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: usize,
+        data: Vec<u8>,
+    ) -> Result<String, Error> {
+        let query = format!(
+            "partNumber={}&uploadId={}",
+            part_number,
+            api::uri_encode(upload_id, true)
+        );
+        let resp = self
+            .send(Method::PUT, key, &query, data, Vec::new())
+            .await?;
+
+        resp.headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+            .ok_or_else(|| Error::Internal(format!("No ETag returned for part {}", part_number)))
+    }
+
+    /// Upload the remaining parts of a multipart upload and complete it
+    ///
+    /// `buf` holds the data read from the stream so far.
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        mut buf: Vec<u8>,
+        data: impl Stream<Item = Result<Bytes, Error>>,
+    ) -> Result<(), Error> {
+        let mut data = Box::pin(data);
+        let mut etags = Vec::new();
+        let mut done = false;
+
+        // At most one part (plus the latest piece of the stream) is held in
+        // memory at any time. The last part may be smaller than the others.
+        while !done {
+            while buf.len() < self.part_size && !done {
+                match data.try_next().await? {
+                    Some(chunk) => buf.extend_from_slice(&chunk),
+                    None => done = true,
+                }
+            }
+
+            if buf.is_empty() {
+                break;
+            }
+
+            let rest = buf.split_off(buf.len().min(self.part_size));
+            let part = std::mem::replace(&mut buf, rest);
+            let etag = self
+                .upload_part(key, upload_id, etags.len() + 1, part)
+                .await?;
+            etags.push(etag);
+        }
+
+        let query = format!("uploadId={}", api::uri_encode(upload_id, true));
+        let body = api::complete_multipart_body(&etags).into_bytes();
+        let resp = self
+            .send(Method::POST, key, &query, body, Vec::new())
+            .await?;
+
+        // Completing an upload can fail after a 200 OK has been sent, in
+        // which case the error is in the body
+        let body = resp.text().await?;
+
+        match api::xml_element(&body, "Code") {
+            Some(code) => Err(Error::Internal(format!(
+                "Failed to complete multipart upload: {}",
+                code
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Abort a multipart upload, which deletes all parts uploaded so far
+    ///
+    /// Parts of an upload that is neither completed nor aborted are kept
+    /// (and billed) indefinitely, unless the bucket has a lifecycle rule
+    /// for them.
+    async fn abort_multipart_upload(&self, key: &str, upload_id: &str) -> Cleanup {
+        let query = format!("uploadId={}", api::uri_encode(upload_id, true));
+        let result = self
+            .send(Method::DELETE, key, &query, Vec::new(), Vec::new())
+            .await;
+
+        match result {
+            Ok(_) => Cleanup::Removed,
+            Err(e) => Cleanup::Failed(format!("{:?}", e)),
+        }
+    }
+}
+
+impl Client for S3Client {
+    /// Upload a file to the configured bucket
+    ///
+    /// S3 does not accept chunked uploads without a known length, so streams
+    /// smaller than the part size are buffered in memory and uploaded in a
+    /// single request. Larger streams are uploaded part by part.
+    ///
+    /// If a multipart upload fails or is cancelled, it is aborted so that
+    /// the parts uploaded so far do not linger in the bucket.
+    fn upload_stream(
+        &self,
+        path: &str,
+        data: impl Stream<Item = Result<Bytes, crate::Error>> + Send + Sync + 'static,
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, String> {
+        let path = path.to_string();
+        let key = api::uri_encode(path.trim_start_matches('/'), false);
+        let metadata_headers = Self::metadata_headers(metadata);
+
+        Box::pin(async move {
+            let mut data = Box::pin(data.map_err(|e| Error::Internal(e.to_string())));
+            let mut buf = Vec::new();
+
+            while buf.len() < self.part_size {
+                match data.try_next().await? {
+                    Some(chunk) => buf.extend_from_slice(&chunk),
+                    // Small enough for a single request
+                    None => {
+                        self.put_object(&key, buf, metadata_headers).await?;
+                        return Ok(path);
+                    }
+                }
+            }
+
+            let upload_id = self.create_multipart_upload(&key, metadata_headers).await?;
+
+            // The abort request needs its own copy of the client, as it may
+            // outlive this upload
+            let guard = {
+                let client = self.clone();
+                let (key, upload_id) = (key.clone(), upload_id.clone());
+
+                CleanupGuard::new(&path, async move {
+                    client.abort_multipart_upload(&key, &upload_id).await
+                })
+            };
+
+            match self.upload_parts(&key, &upload_id, buf, data).await {
+                Ok(()) => {
+                    guard.disarm();
+                    Ok(path)
+                }
+                Err(e) => {
+                    guard.run().await;
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    /// Replace the user-defined metadata of an existing object
+    ///
+    /// S3 metadata cannot be edited in place, so the object is copied onto
+    /// itself with the new metadata.
+    fn update_metadata(&self, path: &str, metadata: &Metadata) -> ClientFuture<'_, ()> {
+        let key = api::uri_encode(path.trim_start_matches('/'), false);
+        let is_empty = metadata.is_empty();
+
+        let mut headers = Self::metadata_headers(metadata);
+        headers.push((
+            "x-amz-copy-source".to_string(),
+            format!("/{}/{}", api::uri_encode(&self.config.bucket, true), key),
+        ));
+        headers.push((
+            "x-amz-metadata-directive".to_string(),
+            "REPLACE".to_string(),
+        ));
+
+        Box::pin(async move {
+            if is_empty {
+                return Ok(());
+            }
+
+            self.put_object(&key, Vec::new(), headers).await
+        })
+    }
+
+    /// S3 has no folders: keys with a common prefix show up as one
+    fn ensure_folder(&self, _path: &str) -> ClientFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
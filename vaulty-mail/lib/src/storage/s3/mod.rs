@@ -0,0 +1,4 @@
+mod api;
+pub mod client;
+
+pub use api::Config;
@@ -0,0 +1,194 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::stream::Stream;
+use tokio::time::{self, Instant};
+
+use super::Backend;
+use crate::pipeline::Data;
+use crate::Error;
+
+/// Largest piece of data handed to a storage client at once
+///
+/// Large chunks are split up so that progress is seen as they are sent,
+/// rather than once per chunk.
+const MAX_PIECE: usize = 64 * 1024;
+
+/// Number of stalled uploads since startup, indexed by `index`
+static STALLED: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+fn index(backend: &Backend) -> usize {
+    match backend {
+        Backend::Dropbox => 0,
+        Backend::Gdrive => 1,
+        Backend::S3 => 2,
+    }
+}
+
+/// Number of uploads to the backend that were cancelled for making no
+/// progress, since startup
+pub fn stalled_uploads(backend: &Backend) -> u64 {
+    STALLED[index(backend)].load(Ordering::Relaxed)
+}
+
+/// Cancels an upload that stops reading its data
+///
+/// The HTTP client only times out connections it was told to, so an upload
+/// over a hung connection (e.g., a TLS session the peer stopped answering)
+/// would otherwise wait forever, holding on to its concurrency permit.
+///
+/// Progress is measured as the storage client reads the upload data. The
+/// wait for a response after the last byte counts as no progress as well,
+/// so the timeout should leave room for the backend to commit large files.
+pub struct Watchdog<'a> {
+    backend: &'a Backend,
+    path: &'a str,
+
+    /// No watchdog if not set
+    timeout: Option<Duration>,
+
+    /// When data was last read
+    last: Arc<Mutex<Instant>>,
+}
+
+impl<'a> Watchdog<'a> {
+    pub fn new(backend: &'a Backend, path: &'a str, timeout: Option<Duration>) -> Self {
+        Self {
+            backend,
+            path,
+            timeout,
+            last: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Wrap the data of the upload, so that reads count as progress
+    pub fn track(
+        &self,
+        data: impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static,
+    ) -> Progress {
+        Progress {
+            inner: Box::pin(data),
+            pending: Bytes::new(),
+            last: self.last.clone(),
+        }
+    }
+
+    /// Run the upload, cancelling it if it makes no progress for the timeout
+    ///
+    /// A cancelled upload fails with a temporary error, so that it is
+    /// retried.
+    pub async fn run<T>(self, upload: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return upload.await,
+        };
+
+        futures::pin_mut!(upload);
+
+        loop {
+            let deadline = *self.last.lock().unwrap() + timeout;
+
+            if let Ok(result) = time::timeout_at(deadline, upload.as_mut()).await {
+                return result;
+            }
+
+            // Data was read while waiting
+            if *self.last.lock().unwrap() + timeout > Instant::now() {
+                continue;
+            }
+
+            STALLED[index(self.backend)].fetch_add(1, Ordering::Relaxed);
+
+            log::warn!(
+                "Upload of {} to {} made no progress for {} seconds; cancelling it",
+                self.path,
+                self.backend,
+                timeout.as_secs()
+            );
+
+            return Err(Error::Temporary(format!(
+                "Upload to {} made no progress for {} seconds; retry later.",
+                self.backend,
+                timeout.as_secs()
+            )));
+        }
+    }
+}
+
+/// Data of a watched upload
+pub struct Progress {
+    inner: Data,
+
+    /// Rest of the chunk being read
+    pending: Bytes,
+
+    last: Arc<Mutex<Instant>>,
+}
+
+impl Stream for Progress {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.pending.is_empty() {
+            match this.inner.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => this.pending = chunk,
+                other => return other,
+            }
+        }
+
+        *this.last.lock().unwrap() = Instant::now();
+
+        let n = this.pending.len().min(MAX_PIECE);
+        Poll::Ready(Some(Ok(this.pending.split_to(n))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::stream::{self, StreamExt, TryStreamExt};
+
+    /// Read the data like a storage client, taking `delay` per piece
+    async fn read(data: Progress, delay: Duration) -> Result<usize, Error> {
+        data.try_fold(0, move |n, chunk| async move {
+            time::delay_for(delay).await;
+            Ok(n + chunk.len())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn cancel_stalled_uploads() {
+        let backend = Backend::S3;
+        let timeout = Some(Duration::from_millis(100));
+
+        // Slow, but steady: large chunks are read in pieces, each of which
+        // counts as progress
+        let chunk = Bytes::from(vec![0u8; 2 * MAX_PIECE]);
+        let slow = stream::iter(vec![Ok(chunk.clone()), Ok(chunk)]);
+        let watchdog = Watchdog::new(&backend, "a.pdf", timeout);
+        let data = watchdog.track(slow);
+        let delay = Duration::from_millis(40);
+        assert_eq!(
+            watchdog.run(read(data, delay)).await.unwrap(),
+            4 * MAX_PIECE
+        );
+        assert_eq!(stalled_uploads(&backend), 0);
+
+        // Stops after the first chunk
+        let hung = stream::iter(vec![Ok(Bytes::from_static(b"a"))]).chain(stream::pending());
+        let watchdog = Watchdog::new(&backend, "a.pdf", timeout);
+        let data = watchdog.track(hung);
+        let err = watchdog.run(read(data, delay)).await.unwrap_err();
+        assert!(matches!(err, Error::Temporary(_)));
+        assert_eq!(stalled_uploads(&backend), 1);
+    }
+}
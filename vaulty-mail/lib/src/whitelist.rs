@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use regex::{Regex, RegexBuilder};
+
+use crate::Error;
+
+/// Longest whitelist entry, which is also the longest address
+const MAX_ENTRY_LEN: usize = 512;
+
+/// Largest compiled regex entry, in bytes
+const MAX_REGEX_SIZE: usize = 1 << 16;
+
+/// Most compiled regex entries kept in memory; the cache is cleared once it
+/// holds more
+const MAX_CACHED_REGEXES: usize = 1024;
+
+/// Regex entries compiled so far, by source
+///
+/// Whitelists are loaded from the DB for every email, so the same entries
+/// are compiled over and over otherwise.
+static REGEXES: Lazy<Mutex<HashMap<String, Arc<Regex>>>> = Lazy::new(Default::default);
+
+/// A single entry of an address whitelist
+///
+/// Entries are stored as they were entered:
+///
+/// * `*` allows all senders
+/// * Entries with a `*` match any characters in its place (e.g.,
+///   `*@mycompany.com` allows the whole domain)
+/// * Entries between slashes are regexes that must match the whole sender
+///   (e.g., `/(alice|bob)@mycompany\.com/`)
+/// * All other entries must match the sender exactly
+///
+/// Matching is case-insensitive.
+#[derive(Clone, Debug)]
+pub enum Entry {
+    Exact(String),
+    Wildcard(String),
+    Regex(Arc<Regex>),
+}
+
+impl Entry {
+    pub fn parse(entry: &str) -> Result<Self, Error> {
+        let entry = entry.trim();
+
+        if entry.is_empty() || entry.len() > MAX_ENTRY_LEN {
+            return Err(invalid(entry, "must be 1 to 512 characters long"));
+        }
+
+        // Whitelists are read back from the DB as comma-separated lists
+        if entry.contains(',') {
+            return Err(invalid(entry, "must not contain commas"));
+        }
+
+        if entry.len() > 1 && entry.starts_with('/') && entry.ends_with('/') {
+            return compile(&entry[1..entry.len() - 1]).map(Self::Regex);
+        }
+
+        if entry.contains(char::is_whitespace) {
+            return Err(invalid(entry, "must not contain spaces"));
+        }
+
+        if entry.contains('*') {
+            Ok(Self::Wildcard(entry.to_ascii_lowercase()))
+        } else {
+            crate::admin::validate_address(entry)?;
+            Ok(Self::Exact(entry.to_ascii_lowercase()))
+        }
+    }
+
+    pub fn matches(&self, sender: &str) -> bool {
+        match self {
+            Self::Exact(address) => sender.eq_ignore_ascii_case(address),
+            Self::Wildcard(pattern) => crate::links::matches(pattern, sender),
+            Self::Regex(regex) => regex.is_match(sender),
+        }
+    }
+}
+
+/// Whitelist of an address, parsed from its entries
+#[derive(Clone, Debug, Default)]
+pub struct Whitelist {
+    entries: Vec<Entry>,
+}
+
+impl Whitelist {
+    /// Parse the stored entries of a whitelist
+    ///
+    /// Invalid entries (e.g., added before they were validated) never match,
+    /// so that they do not reject all email for the address.
+    pub fn new(entries: &[String]) -> Self {
+        let entries = entries
+            .iter()
+            .filter_map(|entry| match Entry::parse(entry) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    log::warn!("Ignoring whitelist entry: {}", e);
+                    None
+                }
+            })
+            .collect();
+
+        Self { entries }
+    }
+
+    /// Returns true if any entry matches the sender
+    pub fn allows(&self, sender: &str) -> bool {
+        self.entries.iter().any(|entry| entry.matches(sender))
+    }
+
+    /// Returns true if the sender is listed on its own, i.e., it matches an
+    /// exact entry
+    ///
+    /// Wildcard and regex entries let senders through, but do not make them
+    /// owners of the address (see `directive`).
+    pub fn lists(&self, sender: &str) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| matches!(entry, Entry::Exact(_)) && entry.matches(sender))
+    }
+}
+
+fn invalid(entry: &str, reason: &str) -> Error {
+    Error::InvalidRequest(format!(
+        "{} is not a valid whitelist entry: {}",
+        entry, reason
+    ))
+}
+
+/// Compile a regex entry, or get it from the cache
+fn compile(source: &str) -> Result<Arc<Regex>, Error> {
+    if let Some(regex) = REGEXES.lock().unwrap().get(source) {
+        return Ok(regex.clone());
+    }
+
+    // Entries must match the whole sender, not just part of it
+    let regex = RegexBuilder::new(&format!("^(?:{})$", source))
+        .case_insensitive(true)
+        .size_limit(MAX_REGEX_SIZE)
+        .build()
+        .map_err(|e| invalid(source, &e.to_string()))?;
+    let regex = Arc::new(regex);
+
+    let mut regexes = REGEXES.lock().unwrap();
+    if regexes.len() >= MAX_CACHED_REGEXES {
+        regexes.clear();
+    }
+    regexes.insert(source.to_string(), regex.clone());
+
+    Ok(regex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn whitelist(entries: &[&str]) -> Whitelist {
+        let entries: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+        Whitelist::new(&entries)
+    }
+
+    #[test]
+    fn entries() {
+        let list = whitelist(&[
+            "alice@example.com",
+            "*@mycompany.com",
+            r"/(bob|carol)\+.*@example\.org/",
+        ]);
+
+        assert!(list.allows("Alice@Example.com"));
+        assert!(!list.allows("alice@example.com.evil.com"));
+
+        assert!(list.allows("dave@mycompany.com"));
+        assert!(!list.allows("dave@sub.mycompany.com"));
+        assert!(!list.allows("dave@mycompany.com.evil.com"));
+
+        assert!(list.allows("bob+bills@example.org"));
+        assert!(!list.allows("bob@example.org"));
+        assert!(!list.allows("xbob+bills@example.org.evil.com"));
+
+        assert!(!whitelist(&[]).allows("alice@example.com"));
+        assert!(whitelist(&["*"]).allows("anyone@anywhere.com"));
+    }
+
+    #[test]
+    fn owners() {
+        let list = whitelist(&["alice@example.com", "*", "/.*/"]);
+
+        assert!(list.lists("alice@example.com"));
+        assert!(list.allows("bob@example.com"));
+        assert!(!list.lists("bob@example.com"));
+    }
+
+    #[test]
+    fn invalid_entries() {
+        assert!(Entry::parse("not an address").is_err());
+        assert!(Entry::parse("alice").is_err());
+        assert!(Entry::parse("/(unclosed/").is_err());
+        assert!(Entry::parse("/a{1,2}@x.com/").is_err());
+        assert!(Entry::parse("").is_err());
+
+        // Invalid entries are skipped
+        let list = whitelist(&["/(unclosed/", "alice@example.com"]);
+        assert!(list.allows("alice@example.com"));
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use chrono::{Datelike, NaiveDate};
+use futures::stream::Stream;
+
+use crate::pipeline::Data;
+use crate::Error;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_SIGNATURE: u32 = 0x0605_4b50;
+
+const LOCAL_HEADER_LEN: usize = 30;
+const DATA_DESCRIPTOR_LEN: usize = 16;
+const CENTRAL_HEADER_LEN: usize = 46;
+const END_LEN: usize = 22;
+
+/// Zip 2.0, the first version with folders and data descriptors
+const VERSION: u16 = 20;
+
+/// MS-DOS time of all files
+const MIDNIGHT: u16 = 0;
+
+/// Sizes and CRC follow the data, and names are UTF-8
+const FLAGS: u16 = (1 << 3) | (1 << 11);
+
+/// A file to add to an archive
+pub struct Entry {
+    /// Path of the file in the archive (e.g., "invoices/march.pdf")
+    pub name: String,
+
+    /// Size of the file, in bytes
+    pub size: usize,
+
+    pub data: Data,
+}
+
+/// Entry whose data is being written
+struct Current {
+    name: String,
+    data: Data,
+    crc: u32,
+    size: u64,
+    /// Size the entry claimed to be, which the archive size was computed
+    /// from
+    expected: u64,
+    offset: u64,
+}
+
+/// Zip archive of a set of files, streamed as it is written
+///
+/// Files are stored without compression, as most attachments (PDFs, images,
+/// office documents) are compressed already. This also means the size of the
+/// archive is known before it is written. Each file is read only once its
+/// turn comes, and its sizes and CRC are written after its data, so that
+/// files are never buffered.
+///
+/// Archives are limited to 4 GB, as ZIP64 is not supported.
+pub struct Archive {
+    entries: VecDeque<Entry>,
+    current: Option<Current>,
+    central_directory: Vec<u8>,
+    num_entries: u16,
+    offset: u64,
+    date: u16,
+    crc_table: Vec<u32>,
+    size: usize,
+    done: bool,
+}
+
+impl Archive {
+    /// Build an archive of these files, all dated `modified` at midnight
+    ///
+    /// Files are added in order.
+    pub fn new(entries: Vec<Entry>, modified: NaiveDate) -> Result<Self, Error> {
+        let size = entries
+            .iter()
+            .map(|e| {
+                LOCAL_HEADER_LEN
+                    + e.size
+                    + DATA_DESCRIPTOR_LEN
+                    + CENTRAL_HEADER_LEN
+                    + 2 * e.name.len()
+            })
+            .sum::<usize>()
+            + END_LEN;
+
+        if entries.len() > u16::MAX as usize
+            || size as u64 > u32::MAX as u64
+            || entries.iter().any(|e| e.name.len() > u16::MAX as usize)
+        {
+            return Err(too_large());
+        }
+
+        // MS-DOS dates start in 1980
+        let year = (modified.year().max(1980) - 1980) as u16;
+        let date = (year << 9) | ((modified.month() as u16) << 5) | modified.day() as u16;
+
+        Ok(Self {
+            entries: entries.into(),
+            current: None,
+            central_directory: Vec::new(),
+            num_entries: 0,
+            offset: 0,
+            date,
+            crc_table: crc_table(),
+            size,
+            done: false,
+        })
+    }
+
+    /// Size of the archive, in bytes
+    ///
+    /// The archive fails with an error if a file is not the size it claims
+    /// to be, so that it is never stored with a different size.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn local_header(&self, name: &str) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(LOCAL_HEADER_LEN + name.len());
+
+        put_u32(&mut buf, LOCAL_HEADER_SIGNATURE);
+        put_u16(&mut buf, VERSION);
+        put_u16(&mut buf, FLAGS);
+        // Stored
+        put_u16(&mut buf, 0);
+        put_u16(&mut buf, MIDNIGHT);
+        put_u16(&mut buf, self.date);
+        // CRC and sizes are in the data descriptor
+        put_u32(&mut buf, 0);
+        put_u32(&mut buf, 0);
+        put_u32(&mut buf, 0);
+        put_u16(&mut buf, name.len() as u16);
+        put_u16(&mut buf, 0);
+        buf.extend_from_slice(name.as_bytes());
+
+        buf
+    }
+
+    /// Write the data descriptor of a file, and add it to the central
+    /// directory
+    fn finish_entry(&mut self, entry: Current) -> Vec<u8> {
+        let crc = !entry.crc;
+        let size = entry.size as u32;
+
+        let mut buf = Vec::with_capacity(DATA_DESCRIPTOR_LEN);
+        put_u32(&mut buf, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut buf, crc);
+        put_u32(&mut buf, size);
+        put_u32(&mut buf, size);
+
+        let cd = &mut self.central_directory;
+        put_u32(cd, CENTRAL_HEADER_SIGNATURE);
+        put_u16(cd, VERSION);
+        put_u16(cd, VERSION);
+        put_u16(cd, FLAGS);
+        put_u16(cd, 0);
+        put_u16(cd, MIDNIGHT);
+        put_u16(cd, self.date);
+        put_u32(cd, crc);
+        put_u32(cd, size);
+        put_u32(cd, size);
+        put_u16(cd, entry.name.len() as u16);
+        // Extra field, comment, disk number, and attributes
+        put_u16(cd, 0);
+        put_u16(cd, 0);
+        put_u16(cd, 0);
+        put_u16(cd, 0);
+        put_u32(cd, 0);
+        put_u32(cd, entry.offset as u32);
+        cd.extend_from_slice(entry.name.as_bytes());
+
+        self.num_entries += 1;
+
+        buf
+    }
+
+    /// Write the central directory, which ends the archive
+    fn finish(&mut self) -> Vec<u8> {
+        let mut buf = std::mem::take(&mut self.central_directory);
+        let size = buf.len() as u32;
+
+        put_u32(&mut buf, END_SIGNATURE);
+        // Disk numbers
+        put_u16(&mut buf, 0);
+        put_u16(&mut buf, 0);
+        put_u16(&mut buf, self.num_entries);
+        put_u16(&mut buf, self.num_entries);
+        put_u32(&mut buf, size);
+        put_u32(&mut buf, self.offset as u32);
+        // Comment
+        put_u16(&mut buf, 0);
+
+        buf
+    }
+
+    /// Account for a chunk written to the archive
+    fn write(&mut self, buf: Bytes) -> Poll<Option<Result<Bytes, Error>>> {
+        self.offset += buf.len() as u64;
+
+        if self.offset > u32::MAX as u64 {
+            self.done = true;
+            return Poll::Ready(Some(Err(too_large())));
+        }
+
+        Poll::Ready(Some(Ok(buf)))
+    }
+}
+
+impl Stream for Archive {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        if let Some(current) = this.current.as_mut() {
+            return match current.data.as_mut().poll_next(cx) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(Some(Ok(chunk))) => {
+                    current.crc = crc_update(&this.crc_table, current.crc, &chunk);
+                    current.size += chunk.len() as u64;
+
+                    if current.size > current.expected {
+                        this.done = true;
+                        return Poll::Ready(Some(Err(size_mismatch(current))));
+                    }
+
+                    this.write(chunk)
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    this.done = true;
+                    Poll::Ready(Some(Err(e)))
+                }
+                Poll::Ready(None) => {
+                    let current = this.current.take().unwrap();
+
+                    if current.size != current.expected {
+                        this.done = true;
+                        return Poll::Ready(Some(Err(size_mismatch(&current))));
+                    }
+
+                    let buf = this.finish_entry(current);
+                    this.write(buf.into())
+                }
+            };
+        }
+
+        match this.entries.pop_front() {
+            Some(entry) => {
+                let buf = this.local_header(&entry.name);
+                this.current = Some(Current {
+                    name: entry.name,
+                    data: entry.data,
+                    crc: !0,
+                    size: 0,
+                    expected: entry.size as u64,
+                    offset: this.offset,
+                });
+                this.write(buf.into())
+            }
+            None => {
+                let buf = this.finish();
+                let chunk = this.write(buf.into());
+                this.done = true;
+
+                // Only possible if the size computation is off
+                if this.offset != this.size as u64 {
+                    return Poll::Ready(Some(Err(Error::Generic(format!(
+                        "Zip archive is {} bytes instead of {}",
+                        this.offset, this.size
+                    )))));
+                }
+
+                chunk
+            }
+        }
+    }
+}
+
+fn too_large() -> Error {
+    Error::Generic("Attachments are too large to bundle into a zip archive (over 4 GB)".into())
+}
+
+fn size_mismatch(entry: &Current) -> Error {
+    Error::Generic(format!(
+        "{} is not the size it claims to be ({} bytes); not bundling it",
+        entry.name, entry.expected
+    ))
+}
+
+fn put_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+/// Lookup table for CRC-32 (IEEE), as used by zip
+fn crc_table() -> Vec<u32> {
+    (0..256u32)
+        .map(|mut c| {
+            for _ in 0..8 {
+                c = if c & 1 == 1 {
+                    0xedb8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+            }
+            c
+        })
+        .collect()
+}
+
+fn crc_update(table: &[u32], mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc = table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::stream::{self, StreamExt, TryStreamExt};
+
+    fn entry(name: &str, chunks: &[&'static [u8]]) -> Entry {
+        let data: Vec<Result<Bytes, Error>> =
+            chunks.iter().map(|c| Ok(Bytes::from_static(c))).collect();
+
+        Entry {
+            name: name.to_string(),
+            size: chunks.iter().map(|c| c.len()).sum(),
+            data: Box::pin(stream::iter(data)),
+        }
+    }
+
+    fn u16_at(buf: &[u8], i: usize) -> u16 {
+        u16::from_le_bytes([buf[i], buf[i + 1]])
+    }
+
+    fn u32_at(buf: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]])
+    }
+
+    #[tokio::test]
+    async fn write_archive() {
+        let entries = vec![
+            entry(
+                "invoice.pdf",
+                &[b"The quick brown fox ", b"jumps over the lazy dog"],
+            ),
+            entry("scans/receipt.png", &[]),
+        ];
+        let date = NaiveDate::from_ymd(2020, 2, 9);
+
+        let archive = Archive::new(entries, date).unwrap();
+        let size = archive.size();
+        let chunks: Vec<Bytes> = archive.try_collect().await.unwrap();
+        let buf = chunks.concat();
+
+        assert_eq!(buf.len(), size);
+
+        // The end record points to the central directory
+        let end = &buf[buf.len() - END_LEN..];
+        assert_eq!(u32_at(end, 0), END_SIGNATURE);
+        assert_eq!(u16_at(end, 10), 2);
+        let cd_size = u32_at(end, 12) as usize;
+        let cd_offset = u32_at(end, 16) as usize;
+        assert_eq!(cd_offset + cd_size + END_LEN, buf.len());
+
+        // Each file has its name, CRC, and size in the central directory
+        let mut i = cd_offset;
+        let mut files = Vec::new();
+        while i < cd_offset + cd_size {
+            assert_eq!(u32_at(&buf, i), CENTRAL_HEADER_SIGNATURE);
+            assert_eq!(u16_at(&buf, i + 14), (40 << 9) | (2 << 5) | 9);
+
+            let name_len = u16_at(&buf, i + 28) as usize;
+            let name = &buf[i + CENTRAL_HEADER_LEN..i + CENTRAL_HEADER_LEN + name_len];
+            let offset = u32_at(&buf, i + 42) as usize;
+            assert_eq!(u32_at(&buf, offset), LOCAL_HEADER_SIGNATURE);
+
+            files.push((
+                String::from_utf8(name.to_vec()).unwrap(),
+                u32_at(&buf, i + 16),
+                u32_at(&buf, i + 24),
+            ));
+            i += CENTRAL_HEADER_LEN + name_len;
+        }
+
+        assert_eq!(
+            files,
+            vec![
+                ("invoice.pdf".to_string(), 0x414f_a339, 43),
+                ("scans/receipt.png".to_string(), 0, 0),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stop_on_error() {
+        let failing = Entry {
+            name: "broken.pdf".to_string(),
+            size: 10,
+            data: Box::pin(stream::iter(vec![Err(Error::Generic("Boom".into()))])),
+        };
+        let entries = vec![failing, entry("invoice.pdf", &[b"data"])];
+
+        let archive = Archive::new(entries, NaiveDate::from_ymd(2020, 2, 9)).unwrap();
+        let chunks: Vec<Result<Bytes, Error>> = archive.collect().await;
+
+        // The local header of the first file, then the error
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[1].is_err());
+    }
+
+    #[tokio::test]
+    async fn check_sizes() {
+        for size in &[3, 5] {
+            let mut wrong = entry("invoice.pdf", &[b"data"]);
+            wrong.size = *size;
+
+            let archive = Archive::new(vec![wrong], NaiveDate::from_ymd(2020, 2, 9)).unwrap();
+            let chunks: Vec<Result<Bytes, Error>> = archive.collect().await;
+
+            assert!(chunks.last().unwrap().is_err());
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use vaulty::config::Config;
+
+use super::lru::{Kind, Lru};
+
+/// How long verified credentials are cached, in seconds
+const CACHE_TTL: u64 = 60;
+
+/// Prefix of the Argon2 hashes stored by the web app
+///
+/// The rest of the hash is a standard PHC string (`$argon2id$v=19$...`).
+const DJANGO_ARGON2_PREFIX: &str = "argon2";
+
+/// Checks HTTP Basic Authentication credentials
+///
+/// Each API client (e.g., a Postfix relay) has its own credentials, stored
+/// in the DB with an Argon2 hash. The user and pass set in the config file
+/// are always accepted as well; the server does not start with the default
+/// pass (see `http::run`).
+///
+/// Argon2 is slow by design, so credentials are cached for a short while
+/// once verified. Only a hash of the credentials is kept.
+pub struct Authenticator {
+    db: sqlx::PgPool,
+    config: Arc<Config>,
+
+    /// User that each verified Authorization header belongs to, and when it
+    /// was verified, keyed by the SHA-256 of the header
+    cache: Mutex<Lru<Vec<u8>, (String, Instant)>>,
+}
+
+impl Authenticator {
+    pub fn new(db: sqlx::PgPool, config: Arc<Config>) -> Self {
+        let cache = Lru::new(Kind::Auth, config.auth_cache_max_entries);
+
+        Self {
+            db,
+            config,
+            cache: Mutex::new(cache),
+        }
+    }
+
+    /// Verify the value of an Authorization header
+    ///
+    /// Returns the name of the authenticated user.
+    pub async fn authenticate(&self, header: &str) -> Result<String, vaulty::Error> {
+        let key = Sha256::digest(header.as_bytes()).to_vec();
+        let ttl = Duration::from_secs(CACHE_TTL);
+
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|(_, verified)| verified.elapsed() < ttl)
+            .map(|(user, _)| user.clone());
+
+        if let Some(user) = cached {
+            return Ok(user);
+        }
+
+        let (user, pass) = parse(header).ok_or(vaulty::Error::Unauthorized)?;
+
+        let is_valid = if user == self.config.auth_user {
+            constant_time_eq(pass.as_bytes(), self.config.auth_pass.as_bytes())
+        } else {
+            self.verify(&user, pass).await?
+        };
+
+        if !is_valid {
+            log::warn!("Failed authentication for API user {}", user);
+            return Err(vaulty::Error::Unauthorized);
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.remove_where(|_, (_, verified)| verified.elapsed() >= ttl);
+        cache.insert(key, (user.clone(), Instant::now()));
+
+        Ok(user)
+    }
+
+    /// Check a password against the hash stored for an API user
+    async fn verify(&self, user: &str, pass: String) -> Result<bool, vaulty::Error> {
+        let mut db = self.db.clone();
+        let mut db_client = vaulty::db::Client::new(&mut db);
+
+        let hash = match db_client.get_api_user_password(user).await? {
+            Some(hash) => hash,
+            None => return Ok(false),
+        };
+
+        verify_password(&hash, pass).await.map_err(|e| {
+            log::error!("Invalid password hash for API user {}: {}", user, e);
+            vaulty::Error::Unauthorized
+        })
+    }
+}
+
+/// Check a password against an Argon2 hash stored by the web app
+///
+/// Fails if the hash is invalid.
+pub async fn verify_password(hash: &str, pass: String) -> Result<bool, vaulty::Error> {
+    let encoded = hash.trim_start_matches(DJANGO_ARGON2_PREFIX).to_string();
+
+    // Hashing is CPU-bound, so keep it off the async workers
+    let result =
+        tokio::task::spawn_blocking(move || argon2::verify_encoded(&encoded, pass.as_bytes()))
+            .await
+            .map_err(|e| vaulty::Error::Generic(e.to_string()))?;
+
+    result.map_err(|e| vaulty::Error::Generic(e.to_string()))
+}
+
+/// Verifies the signatures of Mailgun webhook posts
+///
+/// Each token is accepted once, so that a captured post cannot be replayed
+/// while its signature is still recent enough.
+pub struct MailgunVerifier {
+    signing_key: Option<String>,
+    max_age: i64,
+
+    /// Timestamp of each token seen recently
+    tokens: Mutex<Lru<String, i64>>,
+}
+
+impl MailgunVerifier {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            signing_key: config.mailgun_signing_key.clone(),
+            max_age: config.mailgun_signature_max_age,
+            tokens: Mutex::new(Lru::new(
+                Kind::MailgunTokens,
+                config.mailgun_token_cache_max_entries,
+            )),
+        }
+    }
+
+    /// Verify the signature of a post, if it has one
+    pub fn verify(
+        &self,
+        signature: Option<&vaulty::mailgun::Signature>,
+    ) -> Result<(), vaulty::Error> {
+        self.verify_at(signature, chrono::Utc::now().timestamp())
+    }
+
+    fn verify_at(
+        &self,
+        signature: Option<&vaulty::mailgun::Signature>,
+        now: i64,
+    ) -> Result<(), vaulty::Error> {
+        let forbidden = |msg: &str| {
+            log::warn!("Rejected Mailgun post: {}", msg);
+            vaulty::Error::Forbidden(msg.to_string())
+        };
+
+        let signing_key = self
+            .signing_key
+            .as_ref()
+            .ok_or_else(|| forbidden("Mailgun webhooks are not enabled."))?;
+        let signature = signature.ok_or_else(|| forbidden("Missing Mailgun webhook signature."))?;
+
+        signature
+            .verify(signing_key, now, self.max_age)
+            .map_err(|e| forbidden(&e.to_string()))?;
+
+        let mut tokens = self.tokens.lock().unwrap();
+
+        // Older tokens are rejected by their timestamp anyway
+        tokens.remove_where(|_, timestamp| (now - timestamp).abs() > self.max_age);
+
+        if tokens.get(&signature.token).is_some() {
+            return Err(forbidden("Mailgun webhook token was already used."));
+        }
+
+        // The timestamp was parsed to verify the signature
+        let timestamp = signature.timestamp.parse().unwrap_or(now);
+        tokens.insert(signature.token.clone(), timestamp);
+
+        Ok(())
+    }
+}
+
+/// Compare two secrets in time that only depends on their length
+///
+/// Both are hashed first, so that the time does not depend on the length of
+/// the expected secret either.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0, |diff, (x, y)| diff | (x ^ y))
+        == 0
+}
+
+/// Extract the user and pass from a Basic Authorization header
+fn parse(header: &str) -> Option<(String, String)> {
+    let mut parts = header.trim().splitn(2, ' ');
+
+    let scheme = parts.next()?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = base64::decode(parts.next()?.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+
+    let mut credentials = decoded.splitn(2, ':');
+    let user = credentials.next()?.to_string();
+    let pass = credentials.next()?.to_string();
+
+    Some((user, pass))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_header() {
+        let header = format!("Basic {}", base64::encode("relay-1:p4ss:word"));
+        assert_eq!(
+            parse(&header),
+            Some(("relay-1".to_string(), "p4ss:word".to_string()))
+        );
+
+        assert_eq!(parse(&format!("Bearer {}", base64::encode("a:b"))), None);
+        assert_eq!(parse("Basic not-base64!"), None);
+        assert_eq!(parse(&format!("Basic {}", base64::encode("no-pass"))), None);
+    }
+
+    #[test]
+    fn compare_secrets() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cres"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cret!"));
+        assert!(!constant_time_eq(b"", b"s3cret"));
+    }
+
+    #[test]
+    fn mailgun_replay() {
+        let config = Config {
+            mailgun_signing_key: Some("key-3ax6xnjp29jd6fds4gc373sgvjxteol0".to_string()),
+            mailgun_signature_max_age: 300,
+            mailgun_token_cache_max_entries: 10,
+            ..Default::default()
+        };
+        let verifier = MailgunVerifier::from_config(&config);
+
+        let signature = vaulty::mailgun::Signature {
+            timestamp: "1581295092".to_string(),
+            token: "0123456789abcdef0123456789abcdef0123456789abcdef01".to_string(),
+            signature: "944734cf1a1fde69b955bc3cb4a92a1e3c1bdb927bdb3764a1da3f8d57298e97"
+                .to_string(),
+        };
+        let now = 1581295092;
+
+        assert!(verifier.verify_at(Some(&signature), now).is_ok());
+        assert!(matches!(
+            verifier.verify_at(Some(&signature), now + 1),
+            Err(vaulty::Error::Forbidden(_))
+        ));
+        assert!(verifier.verify_at(None, now).is_err());
+
+        // Posts are rejected if no signing key is configured
+        let verifier = MailgunVerifier::from_config(&Default::default());
+        assert!(verifier.verify_at(Some(&signature), now).is_err());
+    }
+
+    #[test]
+    fn django_argon2_hash() {
+        let config = argon2::Config::default();
+        let hash = argon2::hash_encoded(b"secret", b"saltsaltsalt", &config).unwrap();
+        let stored = format!("{}{}", DJANGO_ARGON2_PREFIX, hash);
+
+        let encoded = stored.trim_start_matches(DJANGO_ARGON2_PREFIX);
+        assert!(argon2::verify_encoded(encoded, b"secret").unwrap());
+        assert!(!argon2::verify_encoded(encoded, b"wrong").unwrap());
+    }
+}
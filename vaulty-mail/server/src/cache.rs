@@ -1,11 +1,17 @@
-use std::collections::HashMap;
-
 use chrono::prelude::*;
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
 
 use vaulty::email::Email;
 
+use super::lru::{Kind, Lru};
+
+/// Emails waiting for their attachments, keyed by email ID
+///
+/// The cache is bounded. Attachments of an evicted email still get
+/// processed, as its entry is rebuilt from the DB.
 pub struct Cache {
-    cache: HashMap<String, CacheEntry>,
+    cache: Lru<String, CacheEntry>,
 
     /// Total number of entries processed
     pub num_processed: u64,
@@ -15,7 +21,8 @@ pub struct Cache {
 }
 
 // Cache entry is cloneable to reduce read lock hold time
-#[derive(Clone)]
+// It is serializable so that it can be shared between server instances
+#[derive(Clone, Deserialize, Serialize)]
 pub struct CacheEntry {
     pub email: Email,
     pub address: vaulty::db::Address,
@@ -26,12 +33,36 @@ pub struct CacheEntry {
 
     pub insertion_time: Option<DateTime<Local>>,
     pub last_updated: Option<DateTime<Local>>,
+
+    /// When `address` was read from the DB. See `session::FreshStore`.
+    #[serde(default)]
+    pub address_time: Option<DateTime<Local>>,
+}
+
+impl CacheEntry {
+    /// Approximate size of this entry in memory, in bytes
+    pub fn size(&self) -> usize {
+        let email = &self.email;
+        let len = |s: &Option<String>| s.as_ref().map_or(0, |s| s.len());
+
+        std::mem::size_of::<Self>()
+            + email.sender.len()
+            + email.recipients.iter().map(|r| r.len()).sum::<usize>()
+            + len(&email.subject)
+            + email.body.len()
+            + len(&email.body_html)
+            + len(&email.message_id)
+            + self.address.address.len()
+            + self.address.storage_token.len()
+            + self.address.storage_path.len()
+            + self.attachments_processed.len() * std::mem::size_of::<u16>()
+    }
 }
 
 impl Cache {
-    pub fn new() -> Self {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
         Self {
-            cache: HashMap::new(),
+            cache: Lru::new(Kind::Mail, max_entries).with_max_bytes(max_bytes, CacheEntry::size),
             num_processed: 0,
             avg_processing_time: 0.0,
         }
@@ -39,10 +70,13 @@ impl Cache {
 
     pub fn insert(&mut self, key: String, mut entry: CacheEntry) {
         entry.insertion_time = Some(Local::now());
-        self.cache.insert(key, entry);
+
+        for (key, _) in self.cache.insert(key, entry) {
+            log::warn!("Evicted email {} from the mail cache", key);
+        }
     }
 
-    pub fn get(&self, key: &str) -> Option<&CacheEntry> {
+    pub fn get(&mut self, key: &str) -> Option<&CacheEntry> {
         self.cache.get(key)
     }
 
@@ -56,27 +90,38 @@ impl Cache {
         })
     }
 
-    pub fn contains(&self, key: &str) -> bool {
-        self.cache.contains_key(key)
+    /// Remove all entries that have not been updated within `ttl`
+    ///
+    /// Expired entries are not counted as processed.
+    pub fn remove_expired(&mut self, ttl: Duration) -> Vec<CacheEntry> {
+        let now = Local::now();
+
+        self.cache.remove_where(|_, e| {
+            e.last_updated
+                .or(e.insertion_time)
+                .map(|t| now.signed_duration_since(t) > ttl)
+                .unwrap_or(false)
+        })
     }
 
-    pub fn remove(&mut self, key: &str) {
-        assert!(self.contains(key));
+    /// Remove an entry and account for its processing time
+    ///
+    /// Returns the removed entry, if it was in the cache.
+    pub fn remove(&mut self, key: &str) -> Option<CacheEntry> {
+        let entry = self.cache.remove(key)?;
 
         self.num_processed += 1;
 
-        let entry = self.get_mut(key).unwrap();
-
         // Get the total number of microseconds this entry spent in the cache
-        let processing_time = Local::now()
-            .signed_duration_since(entry.insertion_time.unwrap())
-            .num_microseconds()
-            .unwrap();
+        let processing_time = entry
+            .insertion_time
+            .and_then(|t| Local::now().signed_duration_since(t).num_microseconds())
+            .unwrap_or(0);
 
         // Update the overall average processing time for the cache
         // Note that this an approximation
         self.avg_processing_time += processing_time as f32 / self.num_processed as f32;
 
-        self.cache.remove(key);
+        Some(entry)
     }
 }
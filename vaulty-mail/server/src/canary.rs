@@ -0,0 +1,142 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use vaulty::config::Config;
+use vaulty::db::LogLevel;
+use vaulty::reply::Mailer;
+
+/// How often the DB is checked for the canary email, in seconds
+const POLL_INTERVAL: u64 = 10;
+
+/// Outcome of the canary self-test
+#[derive(Clone, Debug, Serialize)]
+pub struct CanaryState {
+    pub address: String,
+
+    /// False if the last canary email was not stored within the deadline
+    pub healthy: bool,
+
+    pub last_sent: Option<DateTime<Utc>>,
+    pub last_stored: Option<DateTime<Utc>>,
+
+    /// Number of canary emails in a row that were not stored in time
+    pub failures: u32,
+    pub last_error: Option<String>,
+}
+
+/// End-to-end self-test
+///
+/// A synthetic email is periodically sent to the canary address through the
+/// SMTP server, and is expected to make it through the filter and server
+/// into storage within a deadline. This catches breakage that the server
+/// cannot see on its own, such as an expired storage token or a broken
+/// Postfix filter.
+///
+/// The canary address must exist and be active, and should be whitelisted
+/// to receive mail from itself.
+pub struct Canary {
+    address: String,
+    interval: Duration,
+    deadline: Duration,
+    state: Mutex<CanaryState>,
+}
+
+impl Canary {
+    /// Returns None if no canary address is configured
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let address = config.canary_address.clone()?;
+
+        Some(Self {
+            address: address.clone(),
+            interval: Duration::from_secs(config.canary_interval),
+            deadline: Duration::from_secs(config.canary_deadline),
+            state: Mutex::new(CanaryState {
+                address,
+                healthy: true,
+                last_sent: None,
+                last_stored: None,
+                failures: 0,
+                last_error: None,
+            }),
+        })
+    }
+
+    pub fn state(&self) -> CanaryState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Periodically send a canary email and wait for it to be stored
+    pub async fn run(self: Arc<Self>, mut db: sqlx::PgPool, config: Arc<Config>) {
+        let mut interval = tokio::time::interval(self.interval);
+
+        loop {
+            interval.tick().await;
+
+            let mut db_client = vaulty::db::Client::new(&mut db);
+            let id = uuid::Uuid::new_v4();
+
+            self.state.lock().unwrap().last_sent = Some(db_client.clock().now());
+
+            match self.check(&mut db_client, &id, &config).await {
+                Ok(()) => {
+                    log::info!("Canary {} stored for {}", id, self.address);
+
+                    let mut state = self.state.lock().unwrap();
+                    state.healthy = true;
+                    state.last_stored = Some(db_client.clock().now());
+                    state.failures = 0;
+                    state.last_error = None;
+                }
+                Err(e) => {
+                    let msg = format!("Canary {} failed for {}: {}", id, self.address, e);
+
+                    log::error!("{}", msg);
+                    db_client.log(&msg, None, LogLevel::Error).await;
+
+                    let mut state = self.state.lock().unwrap();
+                    state.healthy = false;
+                    state.failures += 1;
+                    state.last_error = Some(e);
+                }
+            }
+        }
+    }
+
+    /// Send a single canary email and poll the DB until it is stored or the
+    /// deadline passes
+    async fn check(
+        &self,
+        db_client: &mut vaulty::db::Client<'_>,
+        id: &uuid::Uuid,
+        config: &Config,
+    ) -> Result<(), String> {
+        let email = vaulty::canary::build(&self.address, id).map_err(|e| e.to_string())?;
+        let mailer = Mailer::from_config(config);
+
+        // The SMTP client blocks
+        tokio::task::spawn_blocking(move || mailer.deliver(email))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+
+        let message_id = vaulty::canary::message_id(id);
+        let poll = async {
+            loop {
+                tokio::time::delay_for(Duration::from_secs(POLL_INTERVAL)).await;
+
+                match db_client.is_stored(&message_id).await {
+                    Ok(true) => break,
+                    Ok(false) => (),
+                    Err(e) => log::warn!("Failed to look up canary {}: {}", id, e),
+                }
+            }
+        };
+
+        tokio::time::timeout(self.deadline, poll)
+            .await
+            .map_err(|_| format!("not stored within {}s", self.deadline.as_secs()))
+    }
+}
@@ -1,29 +1,243 @@
 use bytes::{buf::Buf, Bytes};
-use futures::stream::{self, FuturesUnordered, Stream, StreamExt, TryStreamExt};
-use lazy_static::lazy_static;
-use serde::Serialize;
-use tokio::sync::RwLock;
+use futures::future::{self, Either};
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
 use warp::{self, reply::Reply, Rejection};
 
-use vaulty::{db::LogLevel, email, mailgun};
+use vaulty::{
+    clock::Clock,
+    config::Config,
+    db::{Address, LogLevel},
+    email, mailgun,
+    notify::{Category, Notification, Reason, ReceivedAttachment, StoredFile},
+    pipeline::Processed,
+    reply::Mailer,
+    settings::{AutoGeneratedPolicy, DeliveryMode, Settings},
+    storage,
+};
 
-use super::cache::{Cache, CacheEntry};
-use super::error::Error;
+use super::auth::MailgunVerifier;
+use super::cache::CacheEntry;
+use super::canary::Canary;
+use super::error::{EmailError, Error};
+use super::filters;
+use super::flags::{self, Stage};
+use super::limiter::UploadLimits;
+use super::lru;
+use super::ratelimit::RateLimiter;
+use super::retries;
+use super::session::SessionStore;
+use super::spool;
+use super::stats::StatsCache;
 
-lazy_static! {
-    /// Global mail cache
-    static ref MAIL_CACHE: RwLock<Cache> = RwLock::new(Cache::new());
+/// Reject an email, so that its UUID is sent back along with the error
+fn reject_email(uuid: uuid::Uuid, err: impl Into<Error>) -> Rejection {
+    warp::reject::custom(EmailError(uuid, err.into().0))
 }
 
 pub mod postfix {
     use super::*;
 
+    /// Store a sample of a rejected email for abuse review, if enabled
+    ///
+    /// Only rejections that point to abuse (e.g., spam sent to a catch-all
+    /// domain) are sampled, not quota rejections.
+    async fn sample_rejected(
+        email: &email::Email,
+        reason: &str,
+        config: &Config,
+        db_client: &mut vaulty::db::Client<'_>,
+    ) {
+        if !flags::is_enabled(Stage::Sampling)
+            || config.sample_rate <= 0.0
+            || rand::random::<f32>() >= config.sample_rate
+        {
+            return;
+        }
+
+        db_client
+            .insert_sample(email, reason, config.sample_size)
+            .await;
+        db_client.prune_samples(config.sample_retention_days).await;
+    }
+
+    /// Log an attachment that was not the size declared for it, and flag its
+    /// sender for abuse review once it keeps happening
+    ///
+    /// `actual` is not known if the attachment was aborted for being too
+    /// large.
+    async fn size_mismatch(
+        email: &email::Email,
+        name: &str,
+        declared: usize,
+        actual: Option<usize>,
+        limits: &UploadLimits,
+        config: &Config,
+        db_client: &mut vaulty::db::Client<'_>,
+    ) {
+        let msg = match actual {
+            Some(actual) => format!(
+                "Attachment {} of email {} is {} bytes, but was declared as {} bytes",
+                name, email.uuid, actual, declared
+            ),
+            None => format!(
+                "Aborted attachment {} of email {}: more than {} bytes over its declared size of {} bytes",
+                name, email.uuid, config.attachment_size_margin, declared
+            ),
+        };
+
+        log::warn!("{}", msg);
+        db_client
+            .log(&msg, Some(&email.uuid), LogLevel::Warning)
+            .await;
+
+        if !limits.record_size_mismatch(&email.sender) {
+            return;
+        }
+
+        let msg = format!(
+            "Sender {} keeps sending attachments that do not match their declared size",
+            email.sender
+        );
+
+        log::warn!("{}", msg);
+        db_client
+            .log(&msg, Some(&email.uuid), LogLevel::Warning)
+            .await;
+
+        if flags::is_enabled(Stage::Sampling) {
+            db_client
+                .insert_sample(email, "size_mismatch", config.sample_size)
+                .await;
+        }
+    }
+
     pub async fn email(
         mut email: email::Email,
-        mut db: sqlx::PgPool,
+        db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        limits: Arc<UploadLimits>,
+        rate_limiter: Arc<RateLimiter>,
+        config: Arc<Config>,
     ) -> Result<impl Reply, Rejection> {
-        let mut db_client = vaulty::db::Client::new(&mut db);
+        // Test emails skip the whitelist, so only the server may send them
+        // (see `send_test`); clients cannot mark an email as one. Directives
+        // are parsed from the email by the server too.
+        email.is_test = false;
+        email.directives = None;
+
+        // Attachments of addresses that bundle them are zipped together, so
+        // the whole message is needed (see `message`)
+        if email.num_attachments > 0
+            && bundles_attachments(&email, db.clone(), &config)
+                .await
+                .map_err(|e| reject_email(email.uuid, e))?
+        {
+            log::info!(
+                "Asking for the whole message of {} to bundle it",
+                email.uuid
+            );
+
+            let result = vaulty::api::ServerResult {
+                success: true,
+                mail_id: Some(email.uuid.to_string()),
+                send_message: Some(true),
+                ..Default::default()
+            };
+
+            return Ok(warp::reply::json(&result));
+        }
+
+        receive(email, db, sessions, limits, rate_limiter, config)
+            .await
+            .map(|result| warp::reply::json(&result))
+    }
+
+    /// Deliver the whole message of an email from the filter, for addresses
+    /// that bundle attachments
+    ///
+    /// The message is parsed again and run through `deliver`, so that its
+    /// attachments are bundled like those of mail from the SMTP server.
+    pub async fn message(
+        raw: Bytes,
+        mail_id: String,
+        sender: String,
+        recipients: String,
+        db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        limits: Arc<UploadLimits>,
+        rate_limiter: Arc<RateLimiter>,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        let invalid = |msg| warp::reject::custom(Error(vaulty::Error::InvalidRequest(msg)));
+
+        // The filter already assigned the email its ID
+        let uuid = uuid::Uuid::parse_str(&mail_id)
+            .map_err(|_| invalid(format!("Invalid email ID: {}", mail_id)))?;
+
+        let recipients = recipients
+            .split(',')
+            .map(|r| r.trim().to_string())
+            .filter(|r| !r.is_empty())
+            .collect();
+
+        let mut email = email::Email::from_mime(&raw)
+            .map_err(|e| invalid(format!("Failed to parse email {}: {}", mail_id, e)))?
+            .with_sender(sender)
+            .with_recipients(recipients);
+        email.uuid = uuid;
+
+        deliver(
+            email,
+            Some(&raw),
+            db,
+            sessions,
+            limits,
+            rate_limiter,
+            config,
+        )
+        .await
+        .map(|result| warp::reply::json(&result))
+        .map_err(|e| reject_email(uuid, e))
+    }
+
+    /// Accept an email and create a cache entry to track its attachments
+    pub async fn receive(
+        mut email: email::Email,
+        mut db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        limits: Arc<UploadLimits>,
+        rate_limiter: Arc<RateLimiter>,
+        config: Arc<Config>,
+    ) -> Result<vaulty::api::ServerResult, Rejection> {
+        let mut db_client = new_db_client(&mut db, &config);
         let uuid = email.uuid.to_string();
+        let email_id = email.uuid;
+
+        // Fields added after this version are ignored
+        if email.version > email::SCHEMA_VERSION {
+            log::warn!(
+                "Email {} uses a newer schema version ({} > {})",
+                uuid,
+                email.version,
+                email::SCHEMA_VERSION
+            );
+        }
+
+        // Normalize user-controlled fields before they are stored or logged
+        if let Err(e) = email.normalize() {
+            log::warn!("Rejecting email {}: {}", uuid, e);
+            return Err(reject_email(email_id, e));
+        }
+
+        // Mail from Vaulty's own reply address is looping back, even if the
+        // loop header was stripped along the way
+        if email.sender.eq_ignore_ascii_case(&config.reply_from) {
+            email.auto_generated = Some(email::AutoGenerated::Loop);
+        }
 
         // Result is successful by default
         let mut result = vaulty::api::ServerResult {
@@ -34,24 +248,31 @@ pub mod postfix {
         // Check if this email is already in the cache
         // This can occur in the case of the client retrying after a temporary
         // failure (e.g., server timeout).
-        if MAIL_CACHE.read().await.contains(&uuid) {
+        let cached = sessions
+            .get(&uuid)
+            .await
+            .map_err(|e| reject_email(email_id, e))?;
+
+        if cached.is_some() {
             let msg = format!("Email {} has already been processed.", uuid);
 
             log::info!("{}", msg);
 
             result.message = Some(msg);
-            return Ok(warp::reply::json(&result));
+            return Ok(result);
         }
 
         // Get address information for the relevant recipient address
         // Use this to verify that user still has enough quota remaining
-        let recipients = &email.recipients.iter().map(|r| r.as_str()).collect();
-        let address = match db_client.get_address(recipients).await {
+        let recipients: Vec<&str> = email.recipients.iter().map(|r| r.as_str()).collect();
+        let defaults = Settings::from_config(&config);
+        let address_time = chrono::Local::now();
+        let address = match db_client.get_address(&recipients, &defaults).await {
             Ok(a) => a,
             Err(e) => {
                 let msg = e.to_string();
                 log::error!("{}", msg);
-                return Err(warp::reject::custom(Error::from(e)));
+                return Err(reject_email(email_id, e));
             }
         };
 
@@ -59,7 +280,7 @@ pub mod postfix {
         // with a unique status code.
         // NOTE: This case should never be hit as Postfix is looking at the
         // same DB.
-        let address = match address {
+        let mut address = match address {
             None => {
                 // We do not use internal UUID here b/c there really is no
                 // history maintained for this email.  Using Message-ID will
@@ -68,7 +289,7 @@ pub mod postfix {
                 let msg = format!(
                     "Rejecting email message_id: {}, \
                                     from: {}, to: {}",
-                    &email.message_id.unwrap_or("N/A".to_string()),
+                    email.message_id.as_deref().unwrap_or("N/A"),
                     &email.sender,
                     &email.recipients.join(", ")
                 );
@@ -76,23 +297,51 @@ pub mod postfix {
                 log::warn!("{}", msg);
                 db_client.log(&msg, None, LogLevel::Warning).await;
 
+                sample_rejected(&email, "invalid_recipient", &config, &mut db_client).await;
+
                 let err = Error(vaulty::Error::InvalidRecipient);
-                return Err(warp::reject::custom(err));
+                return Err(reject_email(email_id, err));
             }
             Some(a) => a,
         };
 
+        // Files of an address that encrypts them are only stored once its
+        // key can be used, so the email is deferred until then. The key is
+        // created on first use.
+        if address.encrypt_files {
+            if let Err(e) = ensure_encryption_key(&mut address, &config, &mut db_client).await {
+                let msg = format!(
+                    "Deferring email {} for {}: cannot encrypt files: {}",
+                    uuid, address.address, e
+                );
+
+                log::error!("{}", msg);
+                db_client.log(&msg, None, LogLevel::Error).await;
+
+                let err = vaulty::Error::Temporary(format!(
+                    "Encryption is unavailable for {}; retry later.",
+                    address.address
+                ));
+                return Err(reject_email(email_id, err));
+            }
+        }
+
         // Update the email to just have the valid recipient address
         // found above
         let recipient = &address.address;
         email.recipients.retain(|r| r == recipient);
 
         // Ensure that sender address is whitelisted
-        let valid = address.validate_sender(&email, &mut db_client).await;
+        // Test emails are sent by the address to itself
+        let valid = if email.is_test {
+            Ok(true)
+        } else {
+            address.validate_sender(&email, &mut db_client).await
+        };
         if let Err(e) = valid {
             let msg = e.to_string();
             log::error!("{}", msg);
-            return Err(warp::reject::custom(Error::from(e)));
+            return Err(reject_email(email_id, e));
         }
 
         if !valid.unwrap() {
@@ -103,70 +352,190 @@ pub mod postfix {
                 email.message_id
             );
 
-            let err = Error(vaulty::Error::SenderNotWhitelisted {
+            sample_rejected(&email, "sender_not_whitelisted", &config, &mut db_client).await;
+
+            let err = vaulty::Error::SenderNotWhitelisted {
                 recipient: recipient.to_string(),
-            });
-            return Err(warp::reject::custom(err));
+            };
+
+            let notification = Notification::rejection(
+                &email,
+                Reason::SenderNotWhitelisted,
+                err.to_string(),
+                db_client.clock(),
+            );
+            reply(&address, &notification, &config);
+            notify(db_client.db, notification);
+
+            return Err(reject_email(email_id, err));
         }
 
-        // Insert this email into DB
-        if let Err(e) = db_client.insert_email(&email).await {
-            let msg = e.to_string();
-            log::error!("{}", msg);
-            return Err(warp::reject::custom(Error::from(e)));
+        // Apply the address policy to auto-generated email
+        if let Some(kind) = email.auto_generated {
+            match address.settings.auto_generated_policy {
+                AutoGeneratedPolicy::Store => {
+                    log::info!("Storing {} email {}", kind.description(), uuid);
+                }
+                AutoGeneratedPolicy::Ignore => {
+                    let msg = format!(
+                        "Ignoring email {} for {}: {}",
+                        uuid,
+                        recipient,
+                        kind.description()
+                    );
+
+                    log::info!("{}", msg);
+                    db_client.log(&msg, None, LogLevel::Info).await;
+
+                    // The client sends no attachments when none are expected
+                    result.message = Some(msg);
+                    result.num_attachments = Some(0);
+
+                    return Ok(result);
+                }
+                AutoGeneratedPolicy::Reject => {
+                    log::warn!(
+                        "Rejecting email {:?}: {}",
+                        email.message_id,
+                        kind.description()
+                    );
+
+                    let err = vaulty::Error::AutoGenerated {
+                        recipient: recipient.to_string(),
+                    };
+
+                    let notification = Notification::rejection(
+                        &email,
+                        Reason::AutoGenerated,
+                        err.to_string(),
+                        db_client.clock(),
+                    );
+                    notify(db_client.db, notification);
+
+                    return Err(reject_email(email_id, err));
+                }
+            }
         }
 
-        // Verify that address quota is not exceeded with this email
-        // Quota is checked again on every attachment
-        let max_email_size = address.max_email_size;
-        let is_email_size_exceeded = email.size as i32 > max_email_size;
-        let is_storage_quota_exceeded =
-            (address.storage_used + email.size as i64) > address.storage_quota;
-        let is_email_quota_exceeded = (address.num_received + 1) > address.email_quota;
-        let reject = is_email_size_exceeded || is_storage_quota_exceeded || is_email_quota_exceeded;
+        // Postfix may retry delivery of an email that was already accepted
+        // (e.g., if the reply to the filter was lost). Retries are matched on
+        // the email UUID or Message-ID, and get the original email back
+        // instead of being accepted, and counted, again.
+        let original = db_client
+            .find_accepted_email(&email.uuid, email.message_id.as_deref(), recipient)
+            .await
+            .map_err(|e| reject_email(email_id, e))?;
 
-        if reject {
-            let msg = if is_email_size_exceeded {
-                format!(
-                    "This email is larger than allowed for {}: the maximum email size is {} MB.",
-                    recipient,
-                    (max_email_size / 1_000_000),
-                )
-            } else if is_storage_quota_exceeded {
-                format!(
-                    "Address {} has hit its storage quota of {} MB for this period.",
-                    recipient,
-                    (address.storage_quota / 1_000_000)
-                )
-            } else {
-                format!(
-                    "Address {} has hit its quota of {} emails for this period.",
-                    recipient, address.email_quota,
-                )
+        if let Some(original) = original {
+            return repeated_email(
+                original,
+                result,
+                &address,
+                sessions.as_ref(),
+                &config,
+                &mut db_client,
+            )
+            .await;
+        }
+
+        // Defer email from a sender that has sent too much to this address
+        // recently, before it counts against the address quota. Postfix
+        // retries it later.
+        if !rate_limiter.check(&email.sender, recipient) {
+            let err = vaulty::Error::RateLimited {
+                sender: email.sender.clone(),
+                recipient: recipient.to_string(),
             };
 
-            log::warn!("{}", msg);
+            let msg = format!("Deferring email {}: {}", uuid, err);
 
-            db_client
-                .log(&msg, Some(&email.uuid), LogLevel::Warning)
-                .await;
+            log::warn!("{}", msg);
+            db_client.log(&msg, None, LogLevel::Warning).await;
 
-            db_client.update_email(&email, false, Some(&msg)).await;
+            sample_rejected(&email, "rate_limited", &config, &mut db_client).await;
 
-            let err = Error(vaulty::Error::QuotaExceeded(msg));
-            return Err(warp::reject::custom(err));
+            return Err(reject_email(email_id, err));
         }
 
-        // Increment received storage for the email body
-        // If this fails, do not proceed with processing this email
-        // TODO: Can we do this in a single transaction (merge with above)?
-        if let Err(e) = address
-            .update_storage_used(email.body.len(), true, &mut db_client)
+        // Map the importance and subject of the email to how it is stored and
+        // notified, as configured for the address. The result is recorded on
+        // the email, so it also applies to its attachments.
+        let rules = db_client
+            .get_priority_rules(recipient)
             .await
-        {
-            let msg = e.to_string();
-            log::error!("{}", msg);
-            return Err(warp::reject::custom(Error::from(e)));
+            .map_err(|e| reject_email(email_id, e))?;
+        email.priority = vaulty::rules::check_priority(&rules, &email);
+
+        if let Some(priority) = &email.priority {
+            log::info!("Email {} matched a priority rule: {:?}", uuid, priority);
+        }
+
+        // Senders on the address whitelist (i.e., its owners) can control how
+        // the email is handled with directives in the email itself. The
+        // directives are removed before the email is stored.
+        if let Some((directives, subject, body)) = vaulty::directive::Directives::parse(&email) {
+            let is_owner = address
+                .is_whitelisted(&email, &mut db_client)
+                .await
+                .map_err(|e| reject_email(email_id, e))?;
+
+            if is_owner {
+                log::info!("Email {} has directives: {:?}", uuid, directives);
+
+                email.directives = Some(directives);
+                email.subject = subject;
+                email.body = body;
+            } else {
+                log::info!(
+                    "Ignoring directives in email {}: {} is not on the {} whitelist",
+                    uuid,
+                    email.sender,
+                    recipient
+                );
+            }
+        }
+
+        // Insert this email into DB, verify that the address quota is not
+        // exceeded, and count it against the address, all in one transaction
+        email.received_time = Some(db_client.clock().now());
+        match db_client.accept_email(&email, &address).await {
+            Ok(true) => (),
+            Ok(false) => {
+                // Accepted concurrently by another request
+                return repeated_email(
+                    email.uuid,
+                    result,
+                    &address,
+                    sessions.as_ref(),
+                    &config,
+                    &mut db_client,
+                )
+                .await;
+            }
+            Err(vaulty::Error::QuotaExceeded(msg)) => {
+                log::warn!("{}", msg);
+
+                db_client
+                    .log(&msg, Some(&email.uuid), LogLevel::Warning)
+                    .await;
+
+                let notification = Notification::rejection(
+                    &email,
+                    Reason::QuotaExceeded,
+                    msg.clone(),
+                    db_client.clock(),
+                );
+                reply(&address, &notification, &config);
+                notify(db_client.db, notification);
+
+                let err = Error(vaulty::Error::QuotaExceeded(msg));
+                return Err(reject_email(email_id, err));
+            }
+            Err(e) => {
+                let msg = e.to_string();
+                log::error!("{}", msg);
+                return Err(reject_email(email_id, e));
+            }
         }
 
         let msg = format!("Got email for recipient {}", recipient);
@@ -174,14 +543,116 @@ pub mod postfix {
         log::info!("{}", msg);
         db_client.log(&msg, Some(&email.uuid), LogLevel::Info).await;
 
+        // Files already in the Dropbox folder of the address are indexed
+        // once it is first used, so that later attachments do not
+        // overwrite them
+        if config.index_storage_folders && address.needs_index() {
+            tokio::spawn(index_storage_folder(
+                address.clone(),
+                email.clone(),
+                db_client.db.clone(),
+                limits.clone(),
+                config.clone(),
+            ));
+        }
+
         log::info!("{}, {}", email.sender, uuid);
 
+        // Store the email body alongside the attachments, if enabled. A
+        // failure here does not affect the attachments, so the email is
+        // still accepted.
+        if address.settings.store_body {
+            let routes = db_client.get_storage_routes(&address.address).await;
+
+            let h = match &routes {
+                Ok(routes) => {
+                    let size = email.body.len();
+                    let route =
+                        vaulty::rules::route(routes, vaulty::rules::BODY_NAME, "text/plain", size);
+                    let handler = email_handler(&address, &email, &config, db_client.clock())
+                        .with_route(route);
+                    let no_attachment: Option<stream::Empty<Result<Bytes, vaulty::Error>>> = None;
+
+                    let permit = limits.get(backend(&address, route)).acquire().await;
+                    let h = handler
+                        .handle(&email, no_attachment, String::new(), 0)
+                        .await;
+                    permit.record(&h, size);
+
+                    h
+                }
+                Err(e) => Err(e.clone()),
+            };
+
+            if let Err(e) = h {
+                let msg = format!("Failed to store body of email {}: {}", uuid, e);
+
+                log::error!("{}", msg);
+                db_client
+                    .log(&msg, Some(&email.uuid), LogLevel::Error)
+                    .await;
+
+                let notification =
+                    Notification::rejection(&email, Reason::StorageError, msg, db_client.clock());
+                notify(db_client.db, notification);
+            }
+        }
+
+        // Links are archived in the background, as linked files can take a
+        // while to fetch
+        if address.settings.archive_links
+            && email.num_attachments == 0
+            && flags::is_enabled(Stage::Links)
+        {
+            tokio::spawn(archive_links(
+                address.clone(),
+                email.clone(),
+                db_client.db.clone(),
+                limits.clone(),
+                config.clone(),
+            ));
+        }
+
+        // Rendering runs in a subprocess and can be slow, so it is done in
+        // the background as well
+        if address.settings.body_pdf
+            && email.body_html.is_some()
+            && config.pdf_renderer.is_some()
+            && flags::is_enabled(Stage::BodyPdf)
+        {
+            tokio::spawn(render_body_pdf(
+                address.clone(),
+                email.clone(),
+                db_client.db.clone(),
+                limits.clone(),
+                config.clone(),
+            ));
+        }
+
         // Send back a JSON result to the client containing all info
-        result.storage_backend = Some(address.storage_backend.clone());
+        result.mail_id = Some(uuid.clone());
+        result.storage_backend = Some(address.settings.storage_backend.clone());
         result.num_attachments = Some(email.num_attachments as i32);
+        result.archive_eml = Some(address.settings.archive_eml);
+        result.direct_upload_min_size = config
+            .direct_upload_min_size
+            .filter(|_| matches!(address.settings.storage_backend, storage::Backend::S3))
+            .filter(|_| !address.encrypt_files);
+
+        let notification = if email.num_attachments == 0 {
+            db_client
+                .insert_change(vaulty::changes::Kind::EmailStored, &email.uuid, None, None)
+                .await;
+            Notification::success(&email, msg, db_client.clock())
+        } else {
+            Notification::received(&email, msg, db_client.clock())
+        };
+        reply(&address, &notification, &config);
+        notify(db_client.db, notification);
 
-        // Create a cache entry if email has attachments
-        if email.num_attachments > 0 {
+        // Create a cache entry if email has attachments, or if the raw
+        // message is expected for archival
+        if email.num_attachments > 0 || address.settings.archive_eml {
             log::info!("Creating cache entry for {}", email.uuid);
 
             let entry = CacheEntry {
@@ -190,206 +661,3622 @@ pub mod postfix {
                 attachments_processed: Vec::new(),
                 insertion_time: None,
                 last_updated: None,
+                address_time: Some(address_time),
             };
 
-            MAIL_CACHE.write().await.insert(uuid.clone(), entry);
+            if let Err(e) = sessions.insert(&uuid, entry).await {
+                log::error!("{}", e);
+                return Err(reject_email(email_id, e));
+            }
         }
 
-        Ok(warp::reply::json(&result))
+        Ok(result)
     }
 
-    pub async fn attachment(
-        size: usize,
-        _content_type: String,
-        mail_id: String,
-        name: String,
-        index: u16,
-        body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + Sync + 'static,
-        mut db: sqlx::PgPool,
-    ) -> Result<impl Reply, Rejection> {
-        let mut result = vaulty::api::ServerResult {
-            success: true,
-            ..Default::default()
-        };
+    /// Reply to an email that was already accepted as `original`, instead of
+    /// accepting it again
+    ///
+    /// If some of its attachments were not processed yet, the cache entry of
+    /// the original is restored so that the client can send them again.
+    /// Attachments that were already processed are skipped by the server.
+    async fn repeated_email(
+        original: uuid::Uuid,
+        mut result: vaulty::api::ServerResult,
+        address: &vaulty::db::Address,
+        sessions: &dyn SessionStore,
+        config: &Config,
+        db_client: &mut vaulty::db::Client<'_>,
+    ) -> Result<vaulty::api::ServerResult, Rejection> {
+        let mail_id = original.to_string();
 
-        let mut db_client = vaulty::db::Client::new(&mut db);
+        let (email, processed) = db_client
+            .get_email(&original)
+            .await
+            .map_err(|e| reject_email(original, e))?
+            .ok_or_else(|| {
+                let err = vaulty::Error::EmailNotFound(mail_id.clone());
+                reject_email(original, err)
+            })?;
 
-        // Acquire cache read lock and clone email
-        // This minimizes read lock time
-        let entry = {
-            let lock = MAIL_CACHE.read().await;
+        let msg = format!(
+            "Email {} was already accepted for recipient {}",
+            mail_id, address.address
+        );
 
-            if let Some(entry) = lock.get(&mail_id) {
-                // Figure out if we've already processed this attachment by
-                // checking the attachment index against the number of processed
-                // attachments. If we've processed it, silently terminate here.
-                if entry.attachments_processed.contains(&index) {
-                    let msg = format!(
-                        "Attachment {} has already been processed for email {}",
-                        index, mail_id
-                    );
+        log::info!("{}", msg);
+        db_client.log(&msg, Some(&original), LogLevel::Info).await;
 
-                    log::info!("{}", msg);
-                    result.message = Some(msg);
+        let is_pending = processed.len() < email.num_attachments as usize;
+        if is_pending {
+            get_entry(&mail_id, sessions, config, db_client)
+                .await
+                .map_err(|e| reject_email(original, e))?;
+        }
 
-                    return Ok(warp::reply::json(&result));
-                }
+        result.message = Some(msg);
+        result.mail_id = Some(mail_id);
+        result.storage_backend = Some(address.settings.storage_backend.clone());
+        result.num_attachments = Some(if is_pending {
+            email.num_attachments as i32
+        } else {
+            0
+        });
 
-                Some(entry.clone())
-            } else {
-                None
+        // The raw message was archived along with the original
+        result.archive_eml = Some(false);
+        result.direct_upload_min_size = config
+            .direct_upload_min_size
+            .filter(|_| matches!(address.settings.storage_backend, storage::Backend::S3))
+            .filter(|_| !address.encrypt_files);
+
+        Ok(result)
+    }
+
+    /// Check the size of an email before it is sent, so that emails that
+    /// would be rejected are never parsed or transferred
+    ///
+    /// The size is checked again when the email is accepted.
+    pub async fn size(
+        estimate: vaulty::api::SizeEstimate,
+        mut db: sqlx::PgPool,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        let mut db_client = new_db_client(&mut db, &config);
+
+        let recipients: Vec<&str> = estimate.recipients.iter().map(|r| r.as_str()).collect();
+        let defaults = Settings::from_config(&config);
+        let address = match db_client.get_address(&recipients, &defaults).await {
+            Ok(Some(a)) => a,
+            Ok(None) => {
+                let err = Error(vaulty::Error::InvalidRecipient);
+                return Err(warp::reject::custom(err));
+            }
+            Err(e) => {
+                log::error!("{}", e);
+                return Err(warp::reject::custom(Error::from(e)));
             }
         };
 
-        // We did not find an entry for this attachment...
-        if entry.is_none() {
+        if let Some(msg) = address.check_size(estimate.size) {
             let msg = format!(
-                "No entry found for one of the attachments (mail_id: {})",
-                mail_id
+                "Rejecting email of {} bytes up front: {}",
+                estimate.size, msg
             );
-            let err = Error(vaulty::Error::Generic(msg));
+
+            log::warn!("{}", msg);
+            db_client.log(&msg, None, LogLevel::Warning).await;
+
+            let err = Error(vaulty::Error::QuotaExceeded(msg));
             return Err(warp::reject::custom(err));
         }
 
-        let entry = entry.unwrap();
+        let result = vaulty::api::ServerResult {
+            success: true,
+            ..Default::default()
+        };
 
-        let email = &entry.email;
-        let address = &entry.address;
+        Ok(warp::reply::json(&result))
+    }
 
-        let recipient = &email.recipients[0];
-        let msg = format!("Got attachment for recipient {}", recipient);
-        db_client.log(&msg, Some(&email.uuid), LogLevel::Info).await;
+    /// Store the links of an HTML email without attachments, and the linked
+    /// files that match the address link pattern
+    ///
+    /// Failures are logged, but do not affect the email, which has already
+    /// been accepted.
+    async fn archive_links(
+        address: Address,
+        email: email::Email,
+        mut db: sqlx::PgPool,
+        limits: Arc<UploadLimits>,
+        config: Arc<Config>,
+    ) {
+        let mut db_client = new_db_client(&mut db, &config);
 
-        log::info!(
-            "Attachment name: {}, Recipient: {}, Size: {}, UUID: {}",
-            name,
-            recipient,
-            size,
-            mail_id
-        );
+        let links = match email.body_html.as_deref() {
+            Some(html) => vaulty::links::extract(html),
+            None => return,
+        };
 
-        // Check if processing this attachment will result in the user exceeding
-        // their quota. We need to check again here because another email may have been
-        // processed in between (e.g., this email has been retried).
-        let is_quota_exceeded = (address.storage_used + size as i64) > address.storage_quota;
-        if is_quota_exceeded {
-            let msg = format!(
-                "Address {} has hit its quota of {} MB for this period.",
-                recipient,
-                (address.storage_quota / 1_000_000)
-            );
+        if links.is_empty() {
+            return;
+        }
 
-            log::warn!("{}", msg);
+        let handler = email_handler(&address, &email, &config, db_client.clock());
+        let limit = limits.get(&address.settings.storage_backend);
+
+        let list = vaulty::links::to_text(&links);
+        let size = list.len();
 
+        let permit = limit.acquire().await;
+        let h = handler.store_links(&email, list).await;
+        permit.record(&h, size);
+
+        if let Err(e) = h {
+            let msg = format!("Failed to store links of email {}: {}", email.uuid, e);
+
+            log::error!("{}", msg);
             db_client
-                .log(&msg, Some(&email.uuid), LogLevel::Warning)
+                .log(&msg, Some(&email.uuid), LogLevel::Error)
                 .await;
 
-            db_client.update_email(&email, false, Some(&msg)).await;
-
-            let err = Error(vaulty::Error::QuotaExceeded(msg));
-            return Err(warp::reject::custom(err));
+            return;
         }
 
-        let handler = vaulty::EmailHandler::new(
+        let pattern = match &address.link_archive_pattern {
+            Some(pattern) => pattern,
+            None => return,
+        };
+
+        let timeout = std::time::Duration::from_secs(config.link_fetch_timeout);
+        let matching = links
+            .iter()
+            .filter(|l| vaulty::links::matches(pattern, &l.url))
+            .take(config.max_link_fetches);
+
+        // Tracked locally, as `address` is borrowed by the handler
+        let mut storage_used = address.storage_used;
+
+        for link in matching {
+            // Linked files count against the storage quota, like attachments
+            let remaining = address.settings.storage_quota - storage_used;
+            if remaining <= 0 {
+                let msg = format!(
+                    "Not fetching more links of email {}: address {} has hit its storage quota",
+                    email.uuid, address.address
+                );
+
+                log::warn!("{}", msg);
+                db_client
+                    .log(&msg, Some(&email.uuid), LogLevel::Warning)
+                    .await;
+
+                break;
+            }
+
+            let max_size = (config.max_attachment_size as i64)
+                .min(address.settings.max_email_size as i64)
+                .min(remaining);
+
+            let fetched = match vaulty::links::fetch(&link.url, max_size as usize, timeout).await {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    let msg = format!("Failed to fetch link of email {}: {}", email.uuid, e);
+
+                    log::warn!("{}", msg);
+                    db_client
+                        .log(&msg, Some(&email.uuid), LogLevel::Warning)
+                        .await;
+
+                    continue;
+                }
+            };
+
+            let name = vaulty::filename::normalize(
+                &fetched.name,
+                address.settings.transliterate_filenames,
+            );
+            let size = fetched.data.len();
+
+            if let Some(msg) = address.check_size(size) {
+                let msg = format!("Not storing linked file {}: {}", name, msg);
+
+                log::warn!("{}", msg);
+                db_client
+                    .log(&msg, Some(&email.uuid), LogLevel::Warning)
+                    .await;
+
+                continue;
+            }
+
+            let data = stream::iter(vec![Ok::<_, vaulty::Error>(fetched.data)]);
+
+            let permit = limit.acquire().await;
+            let h = handler.handle(&email, Some(data), name.clone(), size).await;
+            permit.record(&h, size);
+
+            let h = match h {
+                Ok(()) => {
+                    address
+                        .update_storage_used(size, false, &mut db_client)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+
+            match h {
+                Ok(()) => {
+                    storage_used += size as i64;
+
+                    let msg = format!("Stored linked file {} of email {}", name, email.uuid);
+
+                    log::info!("{}", msg);
+                    db_client.log(&msg, Some(&email.uuid), LogLevel::Info).await;
+                }
+                Err(e) => {
+                    let msg = format!(
+                        "Failed to store linked file {} of email {}: {}",
+                        name, email.uuid, e
+                    );
+
+                    log::error!("{}", msg);
+                    db_client
+                        .log(&msg, Some(&email.uuid), LogLevel::Error)
+                        .await;
+                }
+            }
+        }
+    }
+
+    /// Render the HTML body of an email to PDF and store it
+    async fn render_body_pdf(
+        address: Address,
+        email: email::Email,
+        mut db: sqlx::PgPool,
+        limits: Arc<UploadLimits>,
+        config: Arc<Config>,
+    ) {
+        use vaulty::pdf::Renderer;
+
+        let mut db_client = new_db_client(&mut db, &config);
+
+        let (html, path) = match (email.body_html.as_deref(), config.pdf_renderer.as_deref()) {
+            (Some(html), Some(path)) => (html, path),
+            _ => return,
+        };
+
+        let timeout = std::time::Duration::from_secs(config.pdf_render_timeout);
+        let renderer = vaulty::pdf::Wkhtmltopdf::new(path, timeout);
+
+        let h = match renderer.render(html).await {
+            Ok(pdf) => {
+                let handler = email_handler(&address, &email, &config, db_client.clock());
+                let size = pdf.len();
+
+                let permit = limits
+                    .get(&address.settings.storage_backend)
+                    .acquire()
+                    .await;
+                let h = handler.store_body_pdf(&email, pdf).await;
+                permit.record(&h, size);
+
+                match h {
+                    Ok(()) => {
+                        address
+                            .update_storage_used(size, false, &mut db_client)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = h {
+            let msg = format!("Failed to store PDF body of email {}: {}", email.uuid, e);
+
+            log::error!("{}", msg);
+            db_client
+                .log(&msg, Some(&email.uuid), LogLevel::Error)
+                .await;
+        }
+    }
+
+    /// Backend a file is stored on, given its storage route
+    fn backend<'a>(
+        address: &'a Address,
+        route: Option<&'a vaulty::rules::Route>,
+    ) -> &'a storage::Backend {
+        route.map_or(&address.settings.storage_backend, |r| &r.storage_backend)
+    }
+
+    /// Build a handler that stores files for an email in the address storage
+    /// backend
+    ///
+    /// Files of the address are stored elsewhere if they match a storage
+    /// route (see `EmailHandler::with_route`).
+    pub(super) fn email_handler<'a>(
+        address: &'a vaulty::db::Address,
+        email: &email::Email,
+        config: &Config,
+        clock: &dyn Clock,
+    ) -> vaulty::EmailHandler<'a> {
+        let mut handler = vaulty::EmailHandler::new(
             &address.storage_token,
-            &address.storage_backend,
+            &address.settings.storage_backend,
             &address.storage_path,
+        )
+        .with_retry_policy(storage::RetryPolicy::from_config(config))
+        .with_stall_timeout(std::time::Duration::from_secs(config.upload_stall_timeout));
+
+        if let Some(chunk_size) = config.upload_chunk_size {
+            handler = handler.with_upload_chunk_size(chunk_size);
+        }
+
+        handler = handler
+            .with_dropbox_team(
+                address.dropbox_namespace_id.as_deref(),
+                address.dropbox_team_member_id.as_deref(),
+            )
+            .with_store_body(address.settings.store_body);
+
+        if address.encrypt_files {
+            let key = match encryption_key(address, config) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    log::error!("Cannot encrypt files of {}: {}", address.address, e);
+                    None
+                }
+            };
+
+            handler = handler.with_encryption(key);
+        }
+
+        // Test emails are kept apart from real email. Otherwise, the owner
+        // may pick a subfolder with a directive, or the email may go to the
+        // subfolder of the priority rule it matched.
+        let directive_folder = email.directives.as_ref().and_then(|d| d.folder.as_deref());
+        let priority_folder = email.priority.as_ref().and_then(|p| p.folder.as_deref());
+
+        let folder = if email.is_test {
+            Some(vaulty::canary::TEST_FOLDER)
+        } else {
+            directive_folder.or(priority_folder)
+        };
+        if let Some(folder) = folder {
+            handler = handler.with_folder(folder);
+        }
+
+        // Test emails are stored as sent, so that the stored path is known
+        if !email.is_test && !address.pipeline.is_empty() {
+            handler =
+                handler.with_pipeline(vaulty::pipeline::Pipeline::from_names(&address.pipeline));
+        }
+        if !email.is_test {
+            handler = handler.with_organization(address.organization, email);
+        }
+        if let Some(template) = address
+            .filename_template
+            .as_deref()
+            .filter(|_| !email.is_test)
+        {
+            handler = handler.with_filename_template(template);
+        }
+        if let Some(template) = address.bundle_template.as_deref() {
+            handler = handler.with_bundle_template(template);
+        }
+
+        // Attach custom metadata to the uploaded object, if configured
+        let template = config
+            .metadata_template
+            .as_ref()
+            .filter(|_| flags::is_enabled(Stage::Metadata));
+        if let Some(template) = template {
+            let metadata = storage::Metadata::from_template(template, email, clock)
+                .with_template_id(config.dropbox_property_template.clone());
+            handler = handler.with_metadata(metadata);
+        }
+
+        handler
+    }
+
+    /// Decrypt the key that the files of an address are encrypted with
+    fn encryption_key(
+        address: &Address,
+        config: &Config,
+    ) -> Result<vaulty::crypto::Key, vaulty::Error> {
+        let wrapped = address.encryption_key.as_deref().ok_or_else(|| {
+            vaulty::Error::Generic(format!("Address {} has no encryption key", address.address))
+        })?;
+
+        vaulty::crypto::Key::unwrap(&master_key(config)?, wrapped)
+    }
+
+    fn master_key(config: &Config) -> Result<vaulty::crypto::Key, vaulty::Error> {
+        let key = config.encryption_master_key.as_deref().ok_or_else(|| {
+            vaulty::Error::Generic("encryption_master_key is not set".to_string())
+        })?;
+
+        vaulty::crypto::Key::from_hex(key)
+    }
+
+    /// Create the encryption key of an address if it has none yet, and
+    /// check that it can be decrypted
+    async fn ensure_encryption_key(
+        address: &mut Address,
+        config: &Config,
+        db_client: &mut vaulty::db::Client<'_>,
+    ) -> Result<(), vaulty::Error> {
+        if address.encryption_key.is_none() {
+            let wrapped = vaulty::crypto::Key::generate().wrap(&master_key(config)?);
+            let key = address.set_encryption_key(&wrapped, db_client).await?;
+
+            log::info!("Created encryption key for {}", address.address);
+
+            address.encryption_key = Some(key);
+        }
+
+        encryption_key(address, config).map(|_| ())
+    }
+
+    /// Index the files already in the storage folder of an address
+    ///
+    /// Listing a large folder takes a while, so the folder is indexed in the
+    /// background, once at a time per address; attachments stored before it
+    /// is done are not checked against it. Indexing is best-effort: if the
+    /// folder cannot be listed (e.g., the storage token expired), it is
+    /// indexed with the next email.
+    async fn index_storage_folder(
+        address: Address,
+        email: email::Email,
+        mut db: sqlx::PgPool,
+        limits: Arc<UploadLimits>,
+        config: Arc<Config>,
+    ) {
+        if !limits.begin_index(&address.address) {
+            return;
+        }
+
+        let mut db_client = new_db_client(&mut db, &config);
+
+        let max_files = config.storage_index_max_files;
+        let files = email_handler(&address, &email, &config, db_client.clock())
+            .list_existing_files(max_files)
+            .await;
+
+        let result = match files {
+            Ok(files) => db_client
+                .index_files(&address, &files)
+                .await
+                .map(|_| files.len()),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(num_files) => {
+                if num_files >= max_files {
+                    log::warn!(
+                        "Only indexed the first {} files in {} for {}",
+                        max_files,
+                        address.storage_path,
+                        address.address
+                    );
+                }
+
+                log::info!(
+                    "Indexed {} existing files in {} for {}",
+                    num_files,
+                    address.storage_path,
+                    address.address
+                );
+            }
+            Err(e) => log::error!(
+                "Failed to index files in {} for {}: {}",
+                address.storage_path,
+                address.address,
+                e
+            ),
+        }
+
+        limits.end_index(&address.address);
+    }
+
+    /// Refresh the storage access token for an address and persist it
+    ///
+    /// The cache entry for the email is updated so that any remaining
+    /// attachments use the new token.
+    async fn refresh_storage_token(
+        address: &vaulty::db::Address,
+        mail_id: &str,
+        sessions: &dyn SessionStore,
+        config: &Config,
+        db_client: &mut vaulty::db::Client<'_>,
+    ) -> Result<vaulty::db::Address, vaulty::Error> {
+        // Only Dropbox uses refresh tokens for now
+        let refresh_token = match (
+            &address.settings.storage_backend,
+            &address.storage_refresh_token,
+        ) {
+            (storage::Backend::Dropbox, Some(token)) => token,
+            _ => return Err(vaulty::Error::TokenExpired),
+        };
+
+        let (app_key, app_secret) = match (&config.dropbox_app_key, &config.dropbox_app_secret) {
+            (Some(key), Some(secret)) => (key, secret),
+            _ => {
+                log::warn!("Dropbox app credentials are not set; cannot refresh token");
+                return Err(vaulty::Error::TokenExpired);
+            }
+        };
+
+        let (token, expiry) = storage::dropbox::client::refresh_access_token(
+            refresh_token,
+            app_key,
+            app_secret,
+            db_client.clock(),
+        )
+        .await?;
+
+        db_client
+            .update_storage_token(&address.address, &token, expiry)
+            .await?;
+
+        log::info!("Refreshed storage token for {}", address.address);
+
+        let mut address = address.clone();
+        address.storage_token = token;
+        address.storage_token_expiry = Some(expiry);
+
+        if let Some(mut entry) = sessions.get(mail_id).await? {
+            entry.address = address.clone();
+            sessions.update(mail_id, entry).await?;
+        }
+
+        Ok(address)
+    }
+
+    /// Rebuild the cache entry for an email from the DB
+    ///
+    /// The rebuilt entry is inserted into the cache for the remaining
+    /// attachments of this email.
+    async fn load_entry(
+        mail_id: &str,
+        sessions: &dyn SessionStore,
+        config: &Config,
+        db_client: &mut vaulty::db::Client<'_>,
+    ) -> Result<CacheEntry, vaulty::Error> {
+        let not_found = || vaulty::Error::EmailNotFound(mail_id.to_string());
+
+        let uuid = uuid::Uuid::parse_str(mail_id).map_err(|_| not_found())?;
+
+        let (email, attachments_processed) =
+            db_client.get_email(&uuid).await?.ok_or_else(not_found)?;
+
+        let defaults = Settings::from_config(config);
+        let recipients: Vec<&str> = email.recipients.iter().map(|r| r.as_str()).collect();
+        let address_time = chrono::Local::now();
+        let address = db_client
+            .get_address(&recipients, &defaults)
+            .await?
+            .ok_or_else(not_found)?;
+
+        log::info!("Restored cache entry for {} from DB", mail_id);
+
+        let entry = CacheEntry {
+            email,
+            address,
+            attachments_processed,
+            insertion_time: None,
+            last_updated: None,
+            address_time: Some(address_time),
+        };
+
+        sessions.insert(mail_id, entry.clone()).await?;
+
+        Ok(entry)
+    }
+
+    /// Check that a string is a hex SHA-256 digest
+    fn is_sha256(s: &str) -> bool {
+        s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
+    }
+
+    /// Get the cache entry for an email
+    ///
+    /// The entry may be missing if the server restarted between the email
+    /// and its attachments, so fall back to the DB.
+    async fn get_entry(
+        mail_id: &str,
+        sessions: &dyn SessionStore,
+        config: &Config,
+        db_client: &mut vaulty::db::Client<'_>,
+    ) -> Result<CacheEntry, vaulty::Error> {
+        match sessions.get(mail_id).await? {
+            Some(entry) => Ok(entry),
+            None => load_entry(mail_id, sessions, config, db_client).await,
+        }
+    }
+
+    /// Record that an attachment of an email was processed
+    ///
+    /// Once all attachments have been processed, the cache entry is removed
+    /// and the email is reported as stored, with the final outcome in
+    /// `result`.
+    async fn finish_attachment(
+        entry: &CacheEntry,
+        mail_id: &str,
+        index: u16,
+        result: &mut vaulty::api::ServerResult,
+        sessions: &dyn SessionStore,
+        config: &Config,
+        db_client: &mut vaulty::db::Client<'_>,
+    ) {
+        let email = &entry.email;
+        let address = &entry.address;
+
+        // Retries are only counted once, so that an email is not reported
+        // as stored while some of its attachments are still missing
+        let mut updated = entry.clone();
+        if !updated.attachments_processed.contains(&index) {
+            updated.attachments_processed.push(index);
+        }
+
+        if updated.attachments_processed.len() < email.num_attachments as usize {
+            // Update the cache entry
+            // It may have been removed in the meantime (e.g., expired), in
+            // which case the DB is the source of truth on the next attachment
+
+            if let Err(e) = sessions.update(mail_id, updated).await {
+                log::error!("Failed to update cache entry for {}: {}", mail_id, e);
+            }
+
+            return;
+        }
+
+        // If this is the last attachment for this email, cleanup the cache
+        // entry.
+        log::info!("Removing {} from cache", mail_id);
+        if let Err(e) = sessions.remove(mail_id).await {
+            log::error!("Failed to remove cache entry for {}: {}", mail_id, e);
+        }
+
+        // Send back a JSON result to the client containing all info
+        result.storage_backend = Some(address.settings.storage_backend.clone());
+        result.num_attachments = Some(email.num_attachments as i32);
+
+        // Link to every attachment stored for this email, including those
+        // stored by earlier requests
+        let handler = email_handler(address, email, config, db_client.clock());
+        let files = match db_client.get_stored_attachments(&email.uuid).await {
+            Ok(names) => names
+                .into_iter()
+                .map(|name| StoredFile {
+                    url: handler.file_url(&name),
+                    name,
+                })
+                .collect(),
+            Err(e) => {
+                log::error!("Failed to get stored attachments for {}: {}", mail_id, e);
+                Vec::new()
+            }
+        };
+
+        if address.settings.sign_manifests {
+            store_manifest(&handler, email, address, config, db_client).await;
+        }
+
+        let msg = format!(
+            "Processed {} attachment(s) for recipient {}",
+            email.num_attachments, &email.recipients[0]
         );
 
-        let attachment = body
-            .map_ok(|mut b| b.to_bytes())
-            .map_err(|e| vaulty::Error::Generic(e.to_string()));
+        db_client
+            .insert_change(vaulty::changes::Kind::EmailStored, &email.uuid, None, None)
+            .await;
+        let notification = Notification::success(email, msg, db_client.clock()).with_files(files);
+        reply(address, &notification, config);
+        notify(db_client.db, notification);
+    }
+
+    /// Sign and store the manifest of the files stored for an email
+    ///
+    /// Failures are logged, but do not fail the email, as its files are
+    /// already stored.
+    async fn store_manifest(
+        handler: &vaulty::EmailHandler<'_>,
+        email: &email::Email,
+        address: &Address,
+        config: &Config,
+        db_client: &mut vaulty::db::Client<'_>,
+    ) {
+        let key = match config.manifest_signing_key.as_deref() {
+            Some(seed) => vaulty::manifest::SigningKey::from_hex(seed),
+            None => {
+                log::warn!(
+                    "Not signing manifest of {}: manifest_signing_key is not set",
+                    email.uuid
+                );
+                return;
+            }
+        };
+
+        let result = match (key, db_client.get_manifest_files(&email.uuid).await) {
+            (Ok(key), Ok(files)) => {
+                let manifest = vaulty::manifest::Manifest::new(
+                    email,
+                    &address.storage_path,
+                    files,
+                    db_client.clock().now(),
+                );
+                handler.store_manifest(&manifest, &key).await
+            }
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        };
+
+        if let Err(e) = result {
+            let msg = format!("Failed to store manifest of email {}: {}", email.uuid, e);
+
+            log::error!("{}", msg);
+            db_client
+                .log(&msg, Some(&email.uuid), LogLevel::Error)
+                .await;
+        }
+    }
+
+    pub async fn attachment(
+        size: usize,
+        content_type: String,
+        mail_id: String,
+        name: String,
+        index: u16,
+        sha256: Option<String>,
+        body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + Sync + 'static,
+        db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        limits: Arc<UploadLimits>,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        // Errors are reported when the attachment is handled
+        let entry = {
+            let mut db = db.clone();
+            let mut db_client = new_db_client(&mut db, &config);
+            get_entry(&mail_id, sessions.as_ref(), &config, &mut db_client).await
+        };
+
+        let delivery_mode = entry.as_ref().map_or(DeliveryMode::Sync, |entry| {
+            entry.address.settings.delivery_mode
+        });
+
+        // Attachments are stored right away while the spool is full
+        let is_spooled = delivery_mode == DeliveryMode::Async && limits.spool().reserve(size);
+        if delivery_mode == DeliveryMode::Async && !is_spooled {
+            log::warn!(
+                "Spool is full; storing attachment {} of {} synchronously",
+                index,
+                mail_id
+            );
+        }
+
+        let result = if is_spooled {
+            let meta = spool::Meta {
+                size,
+                content_type,
+                mail_id,
+                name,
+                index,
+                sha256,
+            };
+
+            queue_attachment(meta, body, db, sessions, limits, config).await?
+        } else {
+            receive_attachment_for(
+                entry,
+                size,
+                content_type,
+                mail_id,
+                name,
+                index,
+                sha256,
+                body,
+                db,
+                sessions,
+                limits,
+                config,
+                None,
+            )
+            .await?
+        };
+
+        Ok(warp::reply::json(&result))
+    }
+
+    /// Save an attachment to the spool and acknowledge it, then store it in
+    /// the background
+    ///
+    /// Room must already be reserved for the attachment in the spool. See
+    /// `store_spooled`.
+    async fn queue_attachment(
+        meta: spool::Meta,
+        body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + Sync + 'static,
+        db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        limits: Arc<UploadLimits>,
+        config: Arc<Config>,
+    ) -> Result<vaulty::api::ServerResult, Rejection> {
+        if let Err(e) = limits.spool().save(&meta, body).await {
+            log::error!(
+                "Failed to save attachment {} of {}: {}",
+                meta.index,
+                meta.mail_id,
+                e
+            );
+
+            // The client sends the attachment again later
+            let err = match e {
+                vaulty::Error::InvalidRequest(_) => e,
+                _ => vaulty::Error::Temporary(e.to_string()),
+            };
+            return Err(warp::reject::custom(Error(err)));
+        }
+
+        let msg = format!(
+            "Attachment {} of {} was received and is being stored",
+            meta.index, meta.mail_id
+        );
+        log::info!("{}", msg);
+
+        tokio::spawn(store_spooled(meta, db, sessions, limits, config));
+
+        Ok(vaulty::api::ServerResult {
+            success: true,
+            message: Some(msg),
+            queued: Some(true),
+            ..Default::default()
+        })
+    }
+
+    /// Store an attachment saved to the spool, then remove it from the spool
+    ///
+    /// Attachments that fail for a temporary reason (e.g., a storage token
+    /// refresh) are retried with backoff, up to `upload_max_attempts` times,
+    /// and are kept in the spool to be retried again on the next startup
+    /// after that. Other failures are recorded against the email as if the
+    /// attachment was stored synchronously.
+    pub async fn store_spooled(
+        meta: spool::Meta,
+        db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        limits: Arc<UploadLimits>,
+        config: Arc<Config>,
+    ) {
+        let retry = storage::RetryPolicy::from_config(&config);
+        let mut attempt = 1;
+
+        let e = loop {
+            let received = {
+                // Only a few saved attachments are read into memory at once
+                let _worker = limits.spool().worker().await;
+
+                match limits.spool().load(&meta).await {
+                    Ok(data) => {
+                        let body = stream::iter(vec![Ok::<_, warp::Error>(data)]);
+                        receive_attachment(
+                            meta.size,
+                            meta.content_type.clone(),
+                            meta.mail_id.clone(),
+                            meta.name.clone(),
+                            meta.index,
+                            meta.sha256.clone(),
+                            body,
+                            db.clone(),
+                            sessions.clone(),
+                            limits.clone(),
+                            config.clone(),
+                            None,
+                        )
+                        .await
+                        .map_err(rejection_error)
+                    }
+                    Err(e) => Err(e),
+                }
+            };
+
+            let e = match received {
+                Ok(_) => {
+                    limits.spool().remove(&meta).await;
+                    return;
+                }
+                Err(e) => e,
+            };
+
+            if !matches!(e, vaulty::Error::Temporary(_)) || attempt >= retry.max_attempts {
+                break e;
+            }
+
+            log::warn!(
+                "Retrying attachment {} of {} in the background: {}",
+                meta.index,
+                meta.mail_id,
+                e
+            );
+            tokio::time::delay_for(retry.backoff(attempt)).await;
+            attempt += 1;
+        };
+
+        let msg = format!(
+            "Failed to store attachment {} of {} in the background: {}",
+            meta.index, meta.mail_id, e
+        );
+        log::error!("{}", msg);
+
+        let mut db = db.clone();
+        let mut db_client = vaulty::db::Client::new(&mut db);
+        let mail_id = uuid::Uuid::parse_str(&meta.mail_id).ok();
+        db_client.log(&msg, mail_id.as_ref(), LogLevel::Error).await;
+
+        // Temporary failures are retried once the server restarts
+        if !matches!(e, vaulty::Error::Temporary(_)) {
+            limits.spool().remove(&meta).await;
+        }
+    }
+
+    /// Store a single attachment of an email, and update its cache entry
+    ///
+    /// If a batch is given, the commit of a small Dropbox upload is deferred
+    /// to it. Such an attachment is only accounted for and reported as
+    /// stored once the batch is committed (see `commit_batch`).
+    pub async fn receive_attachment(
+        size: usize,
+        content_type: String,
+        mail_id: String,
+        name: String,
+        index: u16,
+        sha256: Option<String>,
+        body: impl Stream<Item = Result<impl Buf, impl std::error::Error + Send + Sync + 'static>>
+            + Send
+            + Sync
+            + 'static,
+        mut db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        limits: Arc<UploadLimits>,
+        config: Arc<Config>,
+        batch: Option<&storage::dropbox::batch::Batch>,
+    ) -> Result<vaulty::api::ServerResult, Rejection> {
+        let entry = {
+            let mut db_client = new_db_client(&mut db, &config);
+            get_entry(&mail_id, sessions.as_ref(), &config, &mut db_client).await
+        };
+
+        receive_attachment_for(
+            entry,
+            size,
+            content_type,
+            mail_id,
+            name,
+            index,
+            sha256,
+            body,
+            db,
+            sessions,
+            limits,
+            config,
+            batch,
+        )
+        .await
+    }
+
+    /// Store a single attachment of an email whose cache entry was already
+    /// looked up, as for `receive_attachment`
+    async fn receive_attachment_for(
+        entry: Result<CacheEntry, vaulty::Error>,
+        size: usize,
+        content_type: String,
+        mail_id: String,
+        name: String,
+        index: u16,
+        sha256: Option<String>,
+        body: impl Stream<Item = Result<impl Buf, impl std::error::Error + Send + Sync + 'static>>
+            + Send
+            + Sync
+            + 'static,
+        mut db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        limits: Arc<UploadLimits>,
+        config: Arc<Config>,
+        batch: Option<&storage::dropbox::batch::Batch>,
+    ) -> Result<vaulty::api::ServerResult, Rejection> {
+        let mut result = vaulty::api::ServerResult {
+            success: true,
+            ..Default::default()
+        };
+
+        let mut db_client = new_db_client(&mut db, &config);
+
+        retries::record_submission();
+
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!(
+                    "No entry found for attachment {} of {}: {}",
+                    index,
+                    mail_id,
+                    e
+                );
+                return Err(warp::reject::custom(Error::from(e)));
+            }
+        };
+
+        // Figure out if we've already processed this attachment by
+        // checking the attachment index against the number of processed
+        // attachments. If we've processed it, silently terminate here.
+        if entry.attachments_processed.contains(&index) {
+            let msg = format!(
+                "Attachment {} has already been processed for email {}",
+                index, mail_id
+            );
+
+            log::info!("{}", msg);
+            result.message = Some(msg);
+            retries::record(retries::Kind::Processed, &mail_id, index);
+
+            return Ok(result);
+        }
+
+        // A retry of an attachment that is still being handled would upload
+        // and account for it twice, so ask the client to try again later
+        let _claim = match limits.claim(&mail_id, index) {
+            Some(claim) => claim,
+            None => {
+                retries::record(retries::Kind::InFlight, &mail_id, index);
+
+                let msg = format!(
+                    "Attachment {} of {} is still being handled; retry later.",
+                    index, mail_id
+                );
+                let err = Error(vaulty::Error::Temporary(msg));
+                return Err(warp::reject::custom(err));
+            }
+        };
+
+        // The cache entry may not reflect earlier submissions (e.g., if it
+        // could not be updated), so check the DB as well
+        let status = db_client
+            .get_attachment_status(&entry.email.uuid, index)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+        match status {
+            Some(true) => {
+                let msg = format!(
+                    "Attachment {} has already been stored for email {}",
+                    index, mail_id
+                );
+
+                log::info!("{}", msg);
+                result.message = Some(msg);
+                retries::record(retries::Kind::Processed, &mail_id, index);
+
+                finish_attachment(
+                    &entry,
+                    &mail_id,
+                    index,
+                    &mut result,
+                    sessions.as_ref(),
+                    &config,
+                    &mut db_client,
+                )
+                .await;
+
+                return Ok(result);
+            }
+            Some(false) => retries::record(retries::Kind::Failed, &mail_id, index),
+            None => (),
+        }
+
+        // Refresh the storage token ahead of time if it has expired
+        if flags::is_enabled(Stage::TokenRefresh)
+            && entry.address.is_token_expired(db_client.clock())
+        {
+            match refresh_storage_token(
+                &entry.address,
+                &mail_id,
+                sessions.as_ref(),
+                &config,
+                &mut db_client,
+            )
+            .await
+            {
+                Ok(address) => entry.address = address,
+                Err(e) => {
+                    log::error!("Failed to refresh storage token: {}", e);
+                    return Err(warp::reject::custom(Error::from(e)));
+                }
+            }
+        }
+
+        let email = &entry.email;
+        let address = &entry.address;
+
+        let name = vaulty::filename::normalize(&name, address.settings.transliterate_filenames);
+
+        let recipient = &email.recipients[0];
+        let msg = format!("Got attachment for recipient {}", recipient);
+        db_client.log(&msg, Some(&email.uuid), LogLevel::Info).await;
+
+        // The same attachment may be sent again under another index (e.g.,
+        // if the email was sent again in a different order)
+        if let Some(sha256) = sha256.as_deref().filter(|s| is_sha256(s)) {
+            let is_stored = db_client
+                .is_attachment_stored(&email.uuid, &name, sha256)
+                .await
+                .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+            if is_stored {
+                let msg = format!(
+                    "Attachment {} has already been stored for email {}",
+                    name, mail_id
+                );
+
+                log::info!("{}", msg);
+                db_client.log(&msg, Some(&email.uuid), LogLevel::Info).await;
+                result.message = Some(msg);
+                retries::record(retries::Kind::Duplicate, &mail_id, index);
+
+                db_client
+                    .insert_attachment(
+                        email,
+                        index,
+                        &name,
+                        size,
+                        &content_type,
+                        None,
+                        Some(sha256),
+                        true,
+                        true,
+                        None,
+                    )
+                    .await;
+
+                finish_attachment(
+                    &entry,
+                    &mail_id,
+                    index,
+                    &mut result,
+                    sessions.as_ref(),
+                    &config,
+                    &mut db_client,
+                )
+                .await;
+
+                return Ok(result);
+            }
+        }
+
+        log::info!(
+            "Attachment name: {}, Recipient: {}, Size: {}, UUID: {}",
+            name,
+            recipient,
+            size,
+            mail_id
+        );
+
+        // Drop attachments that the address filtering rules do not accept.
+        // The rest of the email is still processed. The files of a bundle
+        // were checked as they were added to it.
+        let mut drop_reason = if email.is_bundle {
+            None
+        } else {
+            let rules = db_client
+                .get_attachment_rules(recipient)
+                .await
+                .map_err(|e| warp::reject::custom(Error::from(e)))?;
+            vaulty::rules::check(&rules, &name, &content_type, size)
+        };
+
+        if let Some(reason) = &drop_reason {
+            let msg = format!("Dropped attachment {} for {}: {}", name, recipient, reason);
+
+            log::warn!("{}", msg);
+            db_client
+                .log(&msg, Some(&email.uuid), LogLevel::Warning)
+                .await;
+
+            let notification = Notification::rejection(
+                email,
+                Reason::AttachmentDropped,
+                msg.clone(),
+                db_client.clock(),
+            );
+            notify(db_client.db, notification);
+
+            result.message = Some(msg);
+        }
+
+        // Check if processing this attachment will result in the user exceeding
+        // their quota. We need to check again here because another email may have been
+        // processed in between (e.g., this email has been retried).
+        let is_quota_exceeded =
+            (address.storage_used + size as i64) > address.settings.storage_quota;
+        if drop_reason.is_none() && is_quota_exceeded {
+            let msg = format!(
+                "Address {} has hit its quota of {} MB for this period.",
+                recipient,
+                (address.settings.storage_quota / 1_000_000)
+            );
+
+            log::warn!("{}", msg);
+
+            db_client
+                .log(&msg, Some(&email.uuid), LogLevel::Warning)
+                .await;
+
+            db_client.update_email(&email, false, Some(&msg)).await;
+
+            let notification = Notification::rejection(
+                email,
+                Reason::QuotaExceeded,
+                msg.clone(),
+                db_client.clock(),
+            );
+            reply(address, &notification, &config);
+            notify(db_client.db, notification);
+
+            let err = Error(vaulty::Error::QuotaExceeded(msg));
+            return Err(warp::reject::custom(err));
+        }
+
+        // Files may be stored on another backend than that of the address
+        let routes = db_client
+            .get_storage_routes(recipient)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+        let route = vaulty::rules::route(&routes, &name, &content_type, size);
+
+        let handler = email_handler(address, email, &config, db_client.clock());
+        let handler = match batch {
+            Some(batch) => handler.with_dropbox_batch(batch),
+            None => handler,
+        };
+        let handler = handler.with_route(route);
+        let num_queued = batch.map_or(0, |b| b.len());
+
+        // Templates may give attachments of the email the same name, so they
+        // get a collision suffix rather than overwrite each other. So do
+        // attachments named like files that were already in the folder of
+        // the address.
+        let mut taken = Vec::new();
+
+        if address.filename_template.is_some() && !email.is_test {
+            let files = db_client
+                .get_manifest_files(&email.uuid)
+                .await
+                .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+            taken.extend(files.into_iter().map(|f| f.path));
+        }
+
+        if route.is_none() && address.indexed_storage_path.is_some() {
+            let paths = db_client
+                .get_indexed_paths(recipient)
+                .await
+                .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+            taken.extend(paths);
+        }
+
+        let handler = handler.with_taken_paths(taken);
+
+        let attachment = body
+            .map_ok(|mut b| b.to_bytes())
+            .map_err(|e| vaulty::Error::internal("Failed to read attachment body", e));
+
+        // The declared size is only trusted up to a margin, so that a larger
+        // attachment cannot get past the size checks above
+        let overrun = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let attachment = limit_size(
+            attachment,
+            size,
+            config.attachment_size_margin,
+            overrun.clone(),
+        );
+
+        // Size of the attachment as read, once known
+        let mut actual_size = None;
+
+        // Attachments are hashed as they are uploaded. If duplicates may be
+        // skipped, the attachment is instead buffered and hashed so that it
+        // can be compared against stored attachments before it is uploaded.
+        // Attachment size is already capped by the route.
+        let mut content_hash = None;
+
+        // Path of the stored file this attachment is identical to
+        let mut duplicate_of = None;
+        let mut is_unchanged = false;
+
+        let hasher = Arc::new(std::sync::Mutex::new(email::ContentHasher::default()));
+
+        // Webhooks are told about the attachment once it has been read in
+        // full, without waiting for the upload. The hash is filled in then.
+        let received = if drop_reason.is_none() {
+            let attachment = ReceivedAttachment {
+                name: name.clone(),
+                size,
+                sha256: String::new(),
+            };
+            let msg = format!("Received attachment {} for {}", name, recipient);
+
+            Some(Notification::attachment_received(
+                email,
+                attachment,
+                msg,
+                db_client.clock(),
+            ))
+        } else {
+            None
+        };
+
+        let attachment = if drop_reason.is_none()
+            && (address.settings.skip_unchanged || address.settings.dedup_attachments)
+            && flags::is_enabled(Stage::Dedup)
+        {
+            let data = attachment
+                .try_fold(Vec::new(), |mut buf, chunk| async move {
+                    buf.extend_from_slice(&chunk);
+                    Ok(buf)
+                })
+                .await;
+
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    if overrun.load(Ordering::SeqCst) {
+                        size_mismatch(email, &name, size, None, &limits, &config, &mut db_client)
+                            .await;
+                    }
+
+                    return Err(warp::reject::custom(Error::from(e)));
+                }
+            };
+
+            let hash = email::content_hash(&data);
+            actual_size = Some(data.len());
+
+            if let Some(notification) = received {
+                notify_received(db_client.db, notification, data.len(), hash.clone());
+            }
+
+            if address.settings.skip_unchanged {
+                let last_hash = db_client
+                    .get_last_attachment_hash(recipient, &name)
+                    .await
+                    .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+                is_unchanged = last_hash.as_ref() == Some(&hash);
+            }
+
+            // The stored file is looked up by its path, as the storage
+            // backend may have renamed it
+            if is_unchanged || address.settings.dedup_attachments {
+                duplicate_of = db_client
+                    .find_attachment_by_hash(recipient, &hash)
+                    .await
+                    .map_err(|e| warp::reject::custom(Error::from(e)))?;
+            }
+
+            content_hash = Some(hash);
+
+            Either::Left(stream::iter(vec![Ok(Bytes::from(data))]))
+        } else {
+            let pool = db_client.db.clone();
+            let done = hasher.clone();
+            let end = stream::once(async move {
+                if let Some(notification) = received {
+                    let hasher = done.lock().unwrap().clone();
+                    notify_received(&pool, notification, hasher.size(), hasher.finish());
+                }
+
+                None
+            })
+            .filter_map(future::ready);
+
+            let hasher = hasher.clone();
+            Either::Right(
+                attachment
+                    .inspect_ok(move |chunk| hasher.lock().unwrap().update(chunk))
+                    .chain(end),
+            )
+        };
+
+        let is_duplicate = duplicate_of.is_some();
+
+        // Name the attachment was stored under, if the address pipeline or
+        // storage backend renamed it
+        let mut stored_as = None;
+
+        let mut h = if drop_reason.is_some() {
+            Ok(())
+        } else if let Some(stored_path) = &duplicate_of {
+            let msg = if is_unchanged {
+                format!(
+                    "Attachment {} for {} is unchanged since it was last stored; skipping upload",
+                    name, recipient
+                )
+            } else {
+                format!(
+                    "Attachment {} for {} is identical to stored attachment {}; skipping upload",
+                    name, recipient, stored_path
+                )
+            };
+
+            log::info!("{}", msg);
+            db_client.log(&msg, Some(&email.uuid), LogLevel::Info).await;
+            result.message = Some(msg);
+
+            // Metadata is still refreshed so that it reflects the latest email
+            handler.update_metadata(stored_path).await
+        } else {
+            let permit = limits.get(backend(address, route)).acquire().await;
+            let processed = handler
+                .handle_attachment(email, attachment, name.clone(), size)
+                .await;
+            let h = processed.as_ref().map(|_| ()).map_err(|e| e.clone());
+            permit.record(&h, size);
+
+            match processed {
+                Ok(Processed::Stored(stored)) => {
+                    let hasher = std::mem::take(&mut *hasher.lock().unwrap());
+                    actual_size = Some(hasher.size());
+                    content_hash = Some(hasher.finish());
+                    stored_as = Some(stored);
+                }
+                Ok(Processed::Dropped(stage)) => {
+                    let msg = format!("Dropped attachment {} for {}", name, recipient);
+
+                    log::info!("{}: dropped by stage {}", msg, stage);
+                    db_client.log(&msg, Some(&email.uuid), LogLevel::Info).await;
+
+                    drop_reason = Some(format!("dropped by pipeline stage {}", stage));
+                }
+                Err(_) => (),
+            }
+
+            h
+        };
+
+        // If the token was rejected, refresh it so that a retry of this
+        // attachment succeeds. The attachment body has been consumed, so ask
+        // the client to retry instead of failing the email. Tokens of routes
+        // are not refreshed.
+        if let Err(vaulty::Error::TokenExpired) = h {
+            if route.is_none()
+                && flags::is_enabled(Stage::TokenRefresh)
+                && refresh_storage_token(
+                    address,
+                    &mail_id,
+                    sessions.as_ref(),
+                    &config,
+                    &mut db_client,
+                )
+                .await
+                .is_ok()
+            {
+                h = Err(vaulty::Error::Temporary(
+                    "Storage token was refreshed; retry this attachment.".to_string(),
+                ));
+            }
+        }
+
+        // The size that was read is accounted for, rather than the size
+        // declared for the attachment
+        if overrun.load(Ordering::SeqCst) {
+            size_mismatch(email, &name, size, None, &limits, &config, &mut db_client).await;
+        }
+
+        let size = match actual_size {
+            Some(actual) if actual != size => {
+                size_mismatch(
+                    email,
+                    &name,
+                    size,
+                    Some(actual),
+                    &limits,
+                    &config,
+                    &mut db_client,
+                )
+                .await;
+
+                actual
+            }
+            _ => size,
+        };
+
+        // Whether the commit of the upload was deferred to the batch
+        let is_queued = batch.map_or(false, |b| b.len() > num_queued);
+
+        // Duplicates point to the stored copy
+        let file_path = match &duplicate_of {
+            Some(path) => path.clone(),
+            None => handler.file_path(stored_as.as_ref().unwrap_or(&name)),
+        };
+        let stored_name = file_path.rsplit('/').next().unwrap_or(&name).to_string();
+
+        // If an error occurred while processing this attachment,
+        // mark the email as failed. Temporary errors are retried by the
+        // client, which must still find the email then.
+        if let Err(e) = h.as_ref() {
+            let msg = e.to_string();
+
+            if let vaulty::Error::Temporary(_) = e {
+                log::warn!(
+                    "Deferring attachment {} of email {}: {}",
+                    index,
+                    email.uuid,
+                    msg
+                );
+            } else {
+                // Insert failed attachment
+                db_client
+                    .insert_attachment(
+                        email,
+                        index,
+                        &name,
+                        size,
+                        &content_type,
+                        Some(&file_path),
+                        content_hash.as_deref(),
+                        is_duplicate,
+                        false,
+                        Some(&msg),
+                    )
+                    .await;
+
+                db_client.update_email(email, false, Some(&msg)).await;
+
+                let notification =
+                    Notification::rejection(email, Reason::StorageError, msg, db_client.clock());
+                notify(db_client.db, notification);
+            }
+        }
+
+        // Bail out early if we failed
+        if let Err(e) = h {
+            return Err(warp::reject::custom(Error::from(e)));
+        }
+
+        // Insert successful (or dropped) attachment into DB
+        db_client
+            .insert_attachment(
+                email,
+                index,
+                &name,
+                size,
+                &content_type,
+                Some(file_path.as_str()).filter(|_| drop_reason.is_none()),
+                content_hash.as_deref(),
+                is_duplicate,
+                drop_reason.is_none(),
+                drop_reason.as_deref(),
+            )
+            .await;
+
+        // Update used storage for this attachment on success
+        // Skipped and dropped attachments do not use any additional storage
+        if !is_duplicate && !is_queued && drop_reason.is_none() {
+            if let Err(e) = address
+                .update_storage_used(size, false, &mut db_client)
+                .await
+            {
+                let msg = e.to_string();
+                log::error!("{}", msg);
+
+                // The client retries the attachment
+                db_client
+                    .update_attachment_status(&email.uuid, index, false, Some(&msg))
+                    .await;
+
+                return Err(warp::reject::custom(Error::from(e)));
+            }
+        }
+
+        if drop_reason.is_none() && !is_queued {
+            let msg = format!("Stored attachment {} for recipient {}", name, recipient);
+
+            let file = StoredFile {
+                url: handler.path_url(&file_path),
+                name: stored_name,
+            };
+
+            let notification = Notification::attachment_stored(email, file, msg, db_client.clock());
+            notify(db_client.db, notification);
+        }
+
+        // Finally, update the cache
+        finish_attachment(
+            &entry,
+            &mail_id,
+            index,
+            &mut result,
+            sessions.as_ref(),
+            &config,
+            &mut db_client,
+        )
+        .await;
+
+        Ok(result)
+    }
+
+    /// Run an email through the same steps as the filter: the email itself,
+    /// its raw message if the address archives it, then each attachment
+    ///
+    /// Used for email that does not come from the filter (e.g., the SMTP
+    /// server, or test emails). Returns the result of the last step.
+    pub async fn deliver(
+        mut email: email::Email,
+        raw_message: Option<&[u8]>,
+        db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        limits: Arc<UploadLimits>,
+        rate_limiter: Arc<RateLimiter>,
+        config: Arc<Config>,
+    ) -> Result<vaulty::api::ServerResult, vaulty::Error> {
+        let attachments = email.attachments.take().unwrap_or_default();
+        let uuid = email.uuid.to_string();
+
+        // The address may store the attachments as a single zip archive,
+        // which is then the only attachment of the email. Test emails are
+        // stored as sent.
+        if !attachments.is_empty()
+            && !email.is_test
+            && bundles_attachments(&email, db.clone(), &config).await?
+        {
+            email.is_bundle = true;
+            email.num_attachments = 1;
+        }
+        let is_bundle = email.is_bundle;
+
+        let mut result = receive(
+            email,
+            db.clone(),
+            sessions.clone(),
+            limits.clone(),
+            rate_limiter,
+            config.clone(),
+        )
+        .await
+        .map_err(rejection_error)?;
+
+        // An email that was already accepted keeps its original ID
+        let mail_id = result.mail_id.clone().unwrap_or(uuid);
+
+        if let (Some(true), Some(raw_message)) = (result.archive_eml, raw_message) {
+            let body = stream::iter(vec![Ok::<_, warp::Error>(Bytes::from(
+                raw_message.to_vec(),
+            ))]);
+
+            raw(
+                raw_message.len(),
+                mail_id.clone(),
+                body,
+                db.clone(),
+                sessions.clone(),
+                limits.clone(),
+                config.clone(),
+            )
+            .await
+            .map_err(rejection_error)?;
+        }
+
+        // No attachments are expected if the email was ignored
+        if result.num_attachments == Some(0) {
+            return Ok(result);
+        }
+
+        if is_bundle {
+            return deliver_bundle(attachments, mail_id, result, db, sessions, limits, config)
+                .await;
+        }
+
+        // Small attachments stored in Dropbox are committed together, as
+        // each commit is slow and counts against the rate limit. The last
+        // attachment is stored on its own once the others are committed, so
+        // that the email is only reported as stored once all of them are.
+        let batch = storage::dropbox::batch::Batch::new();
+        let use_batch = attachments.len() > 2 && flags::is_enabled(Stage::BatchCommits);
+        let last = attachments.len().saturating_sub(1);
+
+        // Index and size of each attachment in the batch, in batch order
+        let mut queued = Vec::new();
+
+        for (i, a) in attachments.into_iter().enumerate() {
+            if i == last && !batch.is_empty() {
+                commit_batch(&batch, &queued, &mail_id, db.clone(), &sessions, &config).await?;
+            }
+
+            let body = stream::iter(vec![Ok::<_, warp::Error>(Bytes::from(
+                a.get_data().clone(),
+            ))]);
+            let num_queued = batch.len();
+
+            let received = receive_attachment(
+                a.get_size(),
+                a.get_mime().to_string(),
+                mail_id.clone(),
+                a.get_name().to_string(),
+                a.get_index(),
+                Some(email::content_hash(a.get_data())),
+                body,
+                db.clone(),
+                sessions.clone(),
+                limits.clone(),
+                config.clone(),
+                Some(&batch).filter(|_| use_batch && i < last),
+            )
+            .await;
+
+            if batch.len() > num_queued {
+                queued.push((a.get_index(), a.get_size()));
+            }
+
+            // Attachments already in the batch are recorded as processed,
+            // so they are committed even if this one failed
+            if received.is_err() && !batch.is_empty() {
+                if let Err(e) =
+                    commit_batch(&batch, &queued, &mail_id, db.clone(), &sessions, &config).await
+                {
+                    log::error!("Failed to commit batch for {}: {}", mail_id, e);
+                }
+            }
+
+            result = received.map_err(rejection_error)?;
+        }
+
+        Ok(result)
+    }
+
+    /// Whether the attachments of an email are bundled, either because its
+    /// address bundles those of each email, or because an owner of the
+    /// address asked for it with `!zip` (see `directive`)
+    async fn bundles_attachments(
+        email: &email::Email,
+        mut db: sqlx::PgPool,
+        config: &Config,
+    ) -> Result<bool, vaulty::Error> {
+        let mut db_client = new_db_client(&mut db, config);
+
+        let recipients: Vec<&str> = email.recipients.iter().map(|r| r.as_str()).collect();
+        let defaults = Settings::from_config(config);
+        let address = match db_client.get_address(&recipients, &defaults).await? {
+            Some(address) => address,
+            None => return Ok(false),
+        };
+
+        if address.bundle_attachments {
+            return Ok(true);
+        }
+
+        match vaulty::directive::Directives::parse(email) {
+            Some((directives, _, _)) if directives.zip == Some(true) => {
+                Ok(address.is_whitelisted(email, &mut db_client).await?)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Bundle the attachments of an email into a single zip archive, and
+    /// store it as the only attachment of the email
+    ///
+    /// Attachments that the address filtering rules do not accept are left
+    /// out of the archive. If no attachment is left, the email is reported as
+    /// stored without one.
+    async fn deliver_bundle(
+        attachments: Vec<email::Attachment>,
+        mail_id: String,
+        mut result: vaulty::api::ServerResult,
+        mut db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        limits: Arc<UploadLimits>,
+        config: Arc<Config>,
+    ) -> Result<vaulty::api::ServerResult, vaulty::Error> {
+        let mut db_client = new_db_client(&mut db, &config);
+        let entry = get_entry(&mail_id, sessions.as_ref(), &config, &mut db_client).await?;
+
+        let email = &entry.email;
+        let address = &entry.address;
+        let recipient = &address.address;
+
+        let rules = db_client.get_attachment_rules(recipient).await?;
+        let mut kept = Vec::new();
+
+        for a in attachments {
+            let name =
+                vaulty::filename::normalize(a.get_name(), address.settings.transliterate_filenames);
+
+            if let Some(reason) = vaulty::rules::check(&rules, &name, a.get_mime(), a.get_size()) {
+                let msg = format!("Dropped attachment {} for {}: {}", name, recipient, reason);
+
+                log::warn!("{}", msg);
+                db_client
+                    .log(&msg, Some(&email.uuid), LogLevel::Warning)
+                    .await;
+
+                let notification = Notification::rejection(
+                    email,
+                    Reason::AttachmentDropped,
+                    msg,
+                    db_client.clock(),
+                );
+                notify(db_client.db, notification);
+
+                continue;
+            }
+
+            kept.push(vaulty::pipeline::Attachment {
+                name,
+                size: a.get_size(),
+                data: Box::pin(stream::iter(vec![Ok(Bytes::from(a.get_data_owned()))])),
+                derived: Vec::new(),
+            });
+        }
+
+        let handler = email_handler(address, email, &config, db_client.clock());
+
+        let bundle = match handler.bundle(email, kept).await? {
+            Some(bundle) => bundle,
+            None => {
+                let msg = format!(
+                    "All attachments of {} were dropped; nothing to bundle",
+                    mail_id
+                );
+
+                log::info!("{}", msg);
+                db_client.log(&msg, Some(&email.uuid), LogLevel::Info).await;
+                result.message = Some(msg);
+
+                finish_attachment(
+                    &entry,
+                    &mail_id,
+                    0,
+                    &mut result,
+                    sessions.as_ref(),
+                    &config,
+                    &mut db_client,
+                )
+                .await;
+
+                return Ok(result);
+            }
+        };
+
+        receive_attachment(
+            bundle.size,
+            "application/zip".to_string(),
+            mail_id,
+            bundle.name,
+            0,
+            None,
+            bundle.data,
+            db,
+            sessions,
+            limits,
+            config,
+            None,
+        )
+        .await
+        .map_err(rejection_error)
+    }
+
+    /// Commit the attachments of an email that were uploaded to a Dropbox
+    /// batch
+    ///
+    /// `queued` has the index and size of each attachment in the batch, in
+    /// batch order. Committed attachments are accounted for and reported as
+    /// stored. The rest are marked as failed, and are no longer recorded as
+    /// processed so that they are stored again if the email is retried.
+    async fn commit_batch(
+        batch: &storage::dropbox::batch::Batch,
+        queued: &[(u16, usize)],
+        mail_id: &str,
+        mut db: sqlx::PgPool,
+        sessions: &Arc<dyn SessionStore>,
+        config: &Config,
+    ) -> Result<(), vaulty::Error> {
+        let mut db_client = new_db_client(&mut db, config);
+        let mut entry = get_entry(mail_id, sessions.as_ref(), config, &mut db_client).await?;
+
+        let email = &entry.email;
+        let address = &entry.address;
+        let recipient = &email.recipients[0];
+
+        let handler = email_handler(address, email, config, db_client.clock());
+
+        // Path of each committed attachment
+        let results: Vec<Result<String, vaulty::Error>> =
+            match handler.finish_dropbox_batch(batch).await {
+                Ok(committed) => committed
+                    .into_iter()
+                    .map(|c| {
+                        let storage::dropbox::batch::Committed { result, path } = c;
+                        result.map(|_| path).map_err(|e| e.into())
+                    })
+                    .collect(),
+                // None of the attachments were committed
+                Err(e) => vec![Err(e); queued.len()],
+            };
+
+        let msg = format!(
+            "Committed batch of {} attachment(s) for recipient {}",
+            queued.len(),
+            recipient
+        );
+        log::info!("{}", msg);
+        db_client.log(&msg, Some(&email.uuid), LogLevel::Info).await;
+
+        let mut failed = Vec::new();
+
+        for (&(index, size), result) in queued.iter().zip(results) {
+            let path = match result {
+                Ok(path) => path,
+                Err(e) => {
+                    let msg = e.to_string();
+
+                    log::error!(
+                        "Failed to commit attachment {} of {}: {}",
+                        index,
+                        mail_id,
+                        msg
+                    );
+                    db_client
+                        .update_attachment_status(&email.uuid, index, false, Some(&msg))
+                        .await;
+
+                    failed.push((index, e));
+                    continue;
+                }
+            };
+
+            if let Err(e) = address
+                .update_storage_used(size, false, &mut db_client)
+                .await
+            {
+                log::error!("{}", e);
+            }
+
+            // Dropbox renames files that already exist when they are committed
+            db_client
+                .update_attachment_path(&email.uuid, index, &path)
+                .await;
+
+            let name = path.rsplit('/').next().unwrap_or(&path).to_string();
+            let msg = format!("Stored attachment {} for recipient {}", name, recipient);
+
+            let file = StoredFile {
+                url: handler.file_url(&name),
+                name,
+            };
+
+            let notification = Notification::attachment_stored(email, file, msg, db_client.clock());
+            notify(db_client.db, notification);
+        }
+
+        let err = match failed.first() {
+            Some((_, e)) => e.clone(),
+            None => return Ok(()),
+        };
+
+        let msg = err.to_string();
+        db_client.update_email(email, false, Some(&msg)).await;
+
+        // Temporary errors are retried by the client
+        if !matches!(err, vaulty::Error::Temporary(_)) {
+            let notification =
+                Notification::rejection(email, Reason::StorageError, msg, db_client.clock());
+            notify(db_client.db, notification);
+        }
+
+        entry
+            .attachments_processed
+            .retain(|index| !failed.iter().any(|(i, _)| i == index));
+
+        if let Err(e) = sessions.update(mail_id, entry).await {
+            log::error!("Failed to update cache entry for {}: {}", mail_id, e);
+        }
+
+        Err(err)
+    }
+
+    /// Recover the error a controller was rejected with
+    fn rejection_error(rejection: Rejection) -> vaulty::Error {
+        if let Some(e) = rejection.find::<EmailError>() {
+            return e.1.clone();
+        }
+
+        match rejection.find::<Error>() {
+            Some(e) => e.0.clone(),
+            None => vaulty::Error::Generic(format!("{:?}", rejection)),
+        }
+    }
+
+    /// Pre-sign an upload of a large attachment straight to the storage
+    /// backend, so that it does not go through the server
+    ///
+    /// No URL is returned if the attachment has to be sent to the server as
+    /// usual instead (e.g., it is dropped by a rule, would exceed the address
+    /// quota, or is unchanged), which then handles it as for any attachment.
+    pub async fn upload_url(
+        upload: vaulty::api::DirectUpload,
+        mut db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        let mut result = vaulty::api::UploadUrl::default();
+
+        if !is_sha256(&upload.sha256) {
+            let msg = format!("Invalid attachment SHA-256: {}", upload.sha256);
+            return Err(warp::reject::custom(Error(vaulty::Error::InvalidRequest(
+                msg,
+            ))));
+        }
+
+        let is_large = config
+            .direct_upload_min_size
+            .map_or(false, |min_size| upload.size >= min_size);
+        if !is_large {
+            return Ok(warp::reply::json(&result));
+        }
+
+        let mut db_client = new_db_client(&mut db, &config);
+
+        let entry = get_entry(&upload.mail_id, sessions.as_ref(), &config, &mut db_client)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+        // The client will send the attachment, and the server will tell it
+        // that it was already processed
+        if entry.attachments_processed.contains(&upload.index) {
+            return Ok(warp::reply::json(&result));
+        }
+
+        let email = &entry.email;
+        let address = &entry.address;
+        let recipient = &email.recipients[0];
+
+        let name =
+            vaulty::filename::normalize(&upload.name, address.settings.transliterate_filenames);
+
+        let rules = db_client
+            .get_attachment_rules(recipient)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+        let is_dropped =
+            vaulty::rules::check(&rules, &name, &upload.content_type, upload.size).is_some();
+        let is_quota_exceeded =
+            (address.storage_used + upload.size as i64) > address.settings.storage_quota;
+
+        let is_unchanged = if address.settings.skip_unchanged && flags::is_enabled(Stage::Dedup) {
+            let last_hash = db_client
+                .get_last_attachment_hash(recipient, &name)
+                .await
+                .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+            last_hash.as_ref() == Some(&upload.sha256)
+        } else {
+            false
+        };
+
+        let is_duplicate = if address.settings.dedup_attachments && flags::is_enabled(Stage::Dedup)
+        {
+            db_client
+                .find_attachment_by_hash(recipient, &upload.sha256)
+                .await
+                .map_err(|e| warp::reject::custom(Error::from(e)))?
+                .is_some()
+        } else {
+            false
+        };
+
+        if is_dropped || is_quota_exceeded || is_unchanged || is_duplicate {
+            return Ok(warp::reply::json(&result));
+        }
+
+        let routes = db_client
+            .get_storage_routes(recipient)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+        let route = vaulty::rules::route(&routes, &name, &upload.content_type, upload.size);
+
+        let handler = email_handler(address, email, &config, db_client.clock()).with_route(route);
+
+        match handler.presign_upload(
+            &name,
+            upload.size,
+            &upload.sha256,
+            config.direct_upload_expiry,
+        ) {
+            Ok(Some(presigned)) => {
+                let msg = format!(
+                    "Issued direct upload URL for attachment {} of {}",
+                    name, upload.mail_id
+                );
+
+                log::info!("{}", msg);
+                db_client.log(&msg, Some(&email.uuid), LogLevel::Info).await;
+
+                result.url = Some(presigned.url);
+                result.headers = presigned.headers;
+            }
+            Ok(None) => (),
+            Err(e) => {
+                // The attachment can still be sent through the server
+                log::error!(
+                    "Failed to pre-sign upload of attachment {} of {}: {}",
+                    name,
+                    upload.mail_id,
+                    e
+                );
+            }
+        }
+
+        Ok(warp::reply::json(&result))
+    }
+
+    /// Confirm that an attachment was uploaded straight to the storage
+    /// backend
+    ///
+    /// The stored object is checked against the size and hash that the
+    /// upload URL was issued for, and then accounted for like any other
+    /// attachment.
+    pub async fn confirm_upload(
+        upload: vaulty::api::DirectUpload,
+        mut db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        let mut result = vaulty::api::ServerResult {
+            success: true,
+            ..Default::default()
+        };
+
+        let mail_id = &upload.mail_id;
+        let index = upload.index;
+
+        let mut db_client = new_db_client(&mut db, &config);
+
+        let entry = get_entry(mail_id, sessions.as_ref(), &config, &mut db_client)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+        if entry.attachments_processed.contains(&index) {
+            let msg = format!(
+                "Attachment {} has already been processed for email {}",
+                index, mail_id
+            );
+
+            log::info!("{}", msg);
+            result.message = Some(msg);
+
+            return Ok(warp::reply::json(&result));
+        }
+
+        let email = &entry.email;
+        let address = &entry.address;
+        let recipient = &email.recipients[0];
+
+        let name =
+            vaulty::filename::normalize(&upload.name, address.settings.transliterate_filenames);
+
+        // The upload was pre-signed for the route of the attachment
+        let routes = db_client
+            .get_storage_routes(recipient)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+        let route = vaulty::rules::route(&routes, &name, &upload.content_type, upload.size);
+
+        let handler = email_handler(address, email, &config, db_client.clock()).with_route(route);
+
+        let h = handler
+            .verify_upload(&name, upload.size, &upload.sha256)
+            .await;
+        let file_path = handler.file_path(&name);
+
+        if let Err(e) = h {
+            let msg = e.to_string();
+
+            log::error!("{}", msg);
+
+            // Temporary errors are retried by the client, which must still
+            // find the email then
+            if !matches!(e, vaulty::Error::Temporary(_)) {
+                db_client
+                    .insert_attachment(
+                        email,
+                        index,
+                        &name,
+                        upload.size,
+                        &upload.content_type,
+                        Some(&file_path),
+                        Some(&upload.sha256),
+                        false,
+                        false,
+                        Some(&msg),
+                    )
+                    .await;
+
+                db_client.update_email(email, false, Some(&msg)).await;
+
+                let notification =
+                    Notification::rejection(email, Reason::StorageError, msg, db_client.clock());
+                notify(db_client.db, notification);
+            }
+
+            return Err(warp::reject::custom(Error::from(e)));
+        }
+
+        db_client
+            .insert_attachment(
+                email,
+                index,
+                &name,
+                upload.size,
+                &upload.content_type,
+                Some(&file_path),
+                Some(&upload.sha256),
+                false,
+                true,
+                None,
+            )
+            .await;
+
+        if let Err(e) = address
+            .update_storage_used(upload.size, false, &mut db_client)
+            .await
+        {
+            let msg = e.to_string();
+            log::error!("{}", msg);
+
+            db_client
+                .update_attachment_status(&email.uuid, index, false, Some(&msg))
+                .await;
+
+            return Err(warp::reject::custom(Error::from(e)));
+        }
+
+        let msg = format!(
+            "Stored directly uploaded attachment {} for recipient {}",
+            name, recipient
+        );
+        db_client.log(&msg, Some(&email.uuid), LogLevel::Info).await;
+
+        let file = StoredFile {
+            url: handler.file_url(&name),
+            name: name.clone(),
+        };
+        let notification = Notification::attachment_stored(email, file, msg, db_client.clock());
+        notify(db_client.db, notification);
+
+        finish_attachment(
+            &entry,
+            mail_id,
+            index,
+            &mut result,
+            sessions.as_ref(),
+            &config,
+            &mut db_client,
+        )
+        .await;
+
+        Ok(warp::reply::json(&result))
+    }
+
+    /// Archive the raw message of an email as a single .eml file
+    ///
+    /// The client sends the raw message after the email, and before any of
+    /// its attachments.
+    pub async fn raw(
+        size: usize,
+        mail_id: String,
+        body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + Sync + 'static,
+        mut db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        limits: Arc<UploadLimits>,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        let mut result = vaulty::api::ServerResult {
+            success: true,
+            ..Default::default()
+        };
+
+        let mut db_client = new_db_client(&mut db, &config);
+
+        let entry = sessions
+            .get(&mail_id)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+        let mut entry = match entry {
+            Some(entry) => entry,
+            None => match load_entry(&mail_id, sessions.as_ref(), &config, &mut db_client).await {
+                Ok(entry) => entry,
+                Err(e) => {
+                    log::warn!("No entry found for raw message of {}: {}", mail_id, e);
+                    return Err(warp::reject::custom(Error::from(e)));
+                }
+            },
+        };
+
+        if !entry.address.settings.archive_eml {
+            let msg = format!(
+                "Address {} does not archive raw messages; ignoring raw message of {}",
+                entry.address.address, mail_id
+            );
+
+            log::warn!("{}", msg);
+            result.message = Some(msg);
+
+            return Ok(warp::reply::json(&result));
+        }
+
+        // Refresh the storage token ahead of time if it has expired
+        if flags::is_enabled(Stage::TokenRefresh)
+            && entry.address.is_token_expired(db_client.clock())
+        {
+            match refresh_storage_token(
+                &entry.address,
+                &mail_id,
+                sessions.as_ref(),
+                &config,
+                &mut db_client,
+            )
+            .await
+            {
+                Ok(address) => entry.address = address,
+                Err(e) => {
+                    log::error!("Failed to refresh storage token: {}", e);
+                    return Err(reject_email(entry.email.uuid, e));
+                }
+            }
+        }
+
+        let email = &entry.email;
+        let address = &entry.address;
+        let recipient = &address.address;
+
+        let is_quota_exceeded =
+            (address.storage_used + size as i64) > address.settings.storage_quota;
+        if is_quota_exceeded {
+            let msg = format!(
+                "Address {} has hit its quota of {} MB for this period.",
+                recipient,
+                (address.settings.storage_quota / 1_000_000)
+            );
+
+            log::warn!("{}", msg);
+
+            db_client
+                .log(&msg, Some(&email.uuid), LogLevel::Warning)
+                .await;
+
+            db_client.update_email(email, false, Some(&msg)).await;
+
+            let notification = Notification::rejection(
+                email,
+                Reason::QuotaExceeded,
+                msg.clone(),
+                db_client.clock(),
+            );
+            reply(address, &notification, &config);
+            notify(db_client.db, notification);
+
+            let err = Error(vaulty::Error::QuotaExceeded(msg));
+            return Err(reject_email(email.uuid, err));
+        }
+
+        let handler = email_handler(address, email, &config, db_client.clock());
+
+        let raw = body
+            .map_ok(|mut b| b.to_bytes())
+            .map_err(|e| vaulty::Error::internal("Failed to read email body", e));
+
+        let permit = limits
+            .get(&address.settings.storage_backend)
+            .acquire()
+            .await;
+        let mut h = handler.archive(email, raw).await;
+        permit.record(&h, size);
+
+        // The raw message has been consumed, so ask the client to retry
+        // once the token is refreshed
+        if let Err(vaulty::Error::TokenExpired) = h {
+            if flags::is_enabled(Stage::TokenRefresh)
+                && refresh_storage_token(
+                    address,
+                    &mail_id,
+                    sessions.as_ref(),
+                    &config,
+                    &mut db_client,
+                )
+                .await
+                .is_ok()
+            {
+                h = Err(vaulty::Error::Temporary(
+                    "Storage token was refreshed; retry this message.".to_string(),
+                ));
+            }
+        }
+
+        if let Err(e) = h {
+            let msg = format!("Failed to archive email {}: {}", mail_id, e);
+
+            log::error!("{}", msg);
+            db_client
+                .log(&msg, Some(&email.uuid), LogLevel::Error)
+                .await;
+
+            // Temporary errors are retried by the client
+            if !matches!(e, vaulty::Error::Temporary(_)) {
+                db_client.update_email(email, false, Some(&msg)).await;
+
+                let notification =
+                    Notification::rejection(email, Reason::StorageError, msg, db_client.clock());
+                notify(db_client.db, notification);
+            }
+
+            return Err(reject_email(email.uuid, e));
+        }
+
+        if let Err(e) = address
+            .update_storage_used(size, false, &mut db_client)
+            .await
+        {
+            let msg = e.to_string();
+            log::error!("{}", msg);
+            return Err(reject_email(email.uuid, e));
+        }
+
+        let msg = format!("Archived raw message for recipient {}", recipient);
+
+        log::info!("{}", msg);
+        db_client.log(&msg, Some(&email.uuid), LogLevel::Info).await;
+
+        // Attachments, if any, still need the cache entry
+        if email.num_attachments == 0 {
+            log::info!("Removing {} from cache", mail_id);
+            if let Err(e) = sessions.remove(&mail_id).await {
+                log::error!("Failed to remove cache entry for {}: {}", mail_id, e);
+            }
+        }
+
+        result.message = Some(msg);
+
+        Ok(warp::reply::json(&result))
+    }
+}
+
+/// Create a DB client that decrypts and encrypts storage tokens with the
+/// configured key
+///
+/// The key is checked at startup, so an invalid key is never seen here.
+pub(crate) fn new_db_client<'a>(
+    db: &'a mut sqlx::PgPool,
+    config: &Config,
+) -> vaulty::db::Client<'a> {
+    let token_key = config
+        .storage_token_key
+        .as_deref()
+        .and_then(|key| vaulty::crypto::Key::from_hex(key).ok());
+
+    vaulty::db::Client::new(db)
+        .with_token_key(token_key)
+        .with_retry(vaulty::db::RetryPolicy::from_config(config))
+}
+
+/// Send a notification to the webhooks configured for its address
+///
+/// Webhooks are called in the background so that a slow or failing webhook
+/// never holds up mail processing.
+/// Send an `attachment_received` notification, once the attachment was read
+///
+/// Nothing is sent if the attachment is not the size it was announced with,
+/// as it was then cut short or padded on the way.
+/// Fail an attachment body once more than `margin` bytes over its declared
+/// size have been read, and flag it in `overrun`
+fn limit_size(
+    body: impl Stream<Item = Result<Bytes, vaulty::Error>>,
+    declared: usize,
+    margin: usize,
+    overrun: Arc<std::sync::atomic::AtomicBool>,
+) -> impl Stream<Item = Result<Bytes, vaulty::Error>> {
+    let max = declared.saturating_add(margin);
+    let mut read = 0;
+
+    body.and_then(move |chunk| {
+        read += chunk.len();
+
+        let result = if read > max {
+            overrun.store(true, Ordering::SeqCst);
+            Err(vaulty::Error::PayloadTooLarge(format!(
+                "Attachment is larger than its declared size of {} bytes",
+                declared
+            )))
+        } else {
+            Ok(chunk)
+        };
+
+        future::ready(result)
+    })
+}
+
+fn notify_received(db: &sqlx::PgPool, mut notification: Notification, size: usize, sha256: String) {
+    if let Some(attachment) = notification.attachment.as_mut() {
+        if attachment.size != size {
+            log::warn!(
+                "Attachment {} of {} is {} bytes, but {} were expected",
+                attachment.name,
+                notification.mail_id,
+                size,
+                attachment.size
+            );
+            return;
+        }
+
+        attachment.sha256 = sha256;
+    }
+
+    notify(db, notification);
+}
+
+fn notify(db: &sqlx::PgPool, notification: Notification) {
+    if !flags::is_enabled(Stage::Webhooks) || notification.muted {
+        return;
+    }
+
+    let mut db = db.clone();
+
+    tokio::spawn(async move {
+        let mut db_client = vaulty::db::Client::new(&mut db);
+
+        let webhooks = match db_client.get_webhooks(&notification.recipient).await {
+            Ok(webhooks) => webhooks,
+            Err(e) => {
+                log::error!(
+                    "Failed to get webhooks for {}: {}",
+                    notification.recipient,
+                    e
+                );
+                return;
+            }
+        };
+
+        for e in vaulty::notify::send_all(&webhooks, &notification).await {
+            let msg = e.to_string();
+
+            log::warn!("{}", msg);
+            db_client
+                .log(&msg, Some(&notification.mail_id), LogLevel::Warning)
+                .await;
+        }
+    });
+}
+
+/// Reply to the sender of an email, if its address wants replies for this
+/// notification
+///
+/// Replies are sent in the background, like webhooks.
+fn reply(address: &Address, notification: &Notification, config: &Config) {
+    if !flags::is_enabled(Stage::Replies)
+        || !vaulty::reply::wants_reply(&address.settings, notification)
+    {
+        return;
+    }
+
+    let template = match notification.category {
+        Category::Rejection => address.reply_rejection_template.clone(),
+        _ => address.reply_success_template.clone(),
+    };
+    let mailer = Mailer::from_config(config);
+    let notification = notification.clone();
+
+    // The SMTP client blocks
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = mailer.send(&notification, template.as_deref()) {
+            log::warn!(
+                "Failed to reply to {} for email {}: {}",
+                notification.sender,
+                notification.mail_id,
+                e
+            );
+        }
+    });
+}
+
+/// How often the mail cache is checked for expired entries, in seconds
+const CACHE_EXPIRY_INTERVAL: u64 = 60;
+
+/// Periodically expire mail cache entries whose attachments never arrived
+/// (e.g., Postfix crashed or dropped the email)
+///
+/// Each expired email is logged and marked as failed in the DB.
+pub async fn expire_cache(
+    mut db: sqlx::PgPool,
+    sessions: Arc<dyn SessionStore>,
+    ttl: chrono::Duration,
+) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(CACHE_EXPIRY_INTERVAL));
+
+    loop {
+        interval.tick().await;
+
+        let expired = match sessions.remove_expired(ttl).await {
+            Ok(expired) if !expired.is_empty() => expired,
+            Ok(_) => continue,
+            Err(e) => {
+                log::error!("Failed to expire cache entries: {}", e);
+                continue;
+            }
+        };
+
+        let mut db_client = vaulty::db::Client::new(&mut db);
+
+        for entry in expired {
+            let email = &entry.email;
+            let msg = format!(
+                "Expired email {} after {} seconds: received {} of {} attachments",
+                email.uuid,
+                ttl.num_seconds(),
+                entry.attachments_processed.len(),
+                email.num_attachments
+            );
+
+            log::warn!("{}", msg);
+
+            db_client
+                .log(&msg, Some(&email.uuid), LogLevel::Warning)
+                .await;
+            db_client.update_email(email, false, Some(&msg)).await;
+
+            let notification =
+                Notification::rejection(email, Reason::Expired, msg, db_client.clock());
+            notify(db_client.db, notification);
+        }
+    }
+}
+
+/// Interval between address quota renewal runs, in seconds
+const QUOTA_RENEWAL_INTERVAL: u64 = 10 * 60;
+
+/// Periodically reset the quotas of addresses whose quota period has
+/// elapsed
+pub async fn renew_quotas(mut db: sqlx::PgPool, period: chrono::Duration) {
+    let mut interval =
+        tokio::time::interval(std::time::Duration::from_secs(QUOTA_RENEWAL_INTERVAL));
+
+    loop {
+        interval.tick().await;
+
+        let mut db_client = vaulty::db::Client::new(&mut db);
+
+        let renewed = match db_client.renew_quotas(period).await {
+            Ok(renewed) => renewed,
+            Err(e) => {
+                log::error!("Failed to renew quotas: {}", e);
+                continue;
+            }
+        };
+
+        for address in renewed {
+            let msg = format!("Renewed quota for address {}", address);
+
+            log::info!("{}", msg);
+            db_client.log(&msg, None, LogLevel::Info).await;
+        }
+    }
+}
+
+/// Periodically check every address for unusual spikes in email volume or
+/// bytes stored, and alert its webhooks
+///
+/// Each address is alerted on at most once per day for each metric.
+pub async fn detect_anomalies(mut db: sqlx::PgPool, config: Arc<Config>) {
+    let thresholds = vaulty::anomaly::Thresholds::from_config(&config);
+    let mut interval =
+        tokio::time::interval(std::time::Duration::from_secs(config.anomaly_interval));
+
+    // Day each address was last alerted on, per metric
+    let mut alerted: std::collections::HashMap<
+        (String, vaulty::anomaly::Metric),
+        chrono::NaiveDate,
+    > = std::collections::HashMap::new();
+
+    loop {
+        interval.tick().await;
+
+        let mut db_client = vaulty::db::Client::new(&mut db);
+        let today = db_client.clock().now().date().naive_utc();
+
+        let volumes = match db_client.get_daily_volumes(thresholds.window_days).await {
+            Ok(volumes) => volumes,
+            Err(e) => {
+                log::error!("Failed to get daily volumes: {}", e);
+                continue;
+            }
+        };
+
+        alerted.retain(|_, day| *day == today);
+
+        for (address, daily) in volumes {
+            let (current, history) = match daily.split_last() {
+                Some(split) => split,
+                None => continue,
+            };
+
+            for anomaly in vaulty::anomaly::detect(history, current, &thresholds) {
+                let key = (address.clone(), anomaly.metric);
+                if alerted.contains_key(&key) {
+                    continue;
+                }
+
+                let msg = format!("Unusual activity on {}: {}", address, anomaly);
+
+                log::warn!("{}", msg);
+                db_client.log(&msg, None, LogLevel::Warning).await;
+
+                // The notification already names the address
+                let notification =
+                    Notification::alert(&address, anomaly.to_string(), db_client.clock());
+                notify(db_client.db, notification);
+
+                alerted.insert(key, today);
+            }
+        }
+    }
+}
+
+/// JSON endpoints used to monitor server state
+pub mod monitor {
+    use super::*;
+
+    /// Returns a snapshot of mail cache state
+    pub async fn cache(
+        mut _db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+    ) -> Result<impl Reply, Rejection> {
+        let state = sessions
+            .stats()
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+        Ok(warp::reply::json(&state))
+    }
+
+    /// Returns the enabled state of each pipeline stage
+    pub async fn flags() -> Result<impl Reply, Rejection> {
+        Ok(warp::reply::json(&flags::snapshot()))
+    }
+
+    /// Returns the size, hit rate, and evictions of each in-memory cache
+    pub async fn caches() -> Result<impl Reply, Rejection> {
+        Ok(warp::reply::json(&lru::snapshot()))
+    }
+
+    /// Returns the number of retried attachment submissions since startup
+    pub async fn retries() -> Result<impl Reply, Rejection> {
+        Ok(warp::reply::json(&retries::snapshot()))
+    }
+
+    /// Returns the current upload concurrency limit of each storage backend
+    pub async fn uploads(limits: Arc<UploadLimits>) -> Result<impl Reply, Rejection> {
+        Ok(warp::reply::json(&limits.snapshot()))
+    }
+
+    /// Returns the outcome of the canary self-test
+    ///
+    /// Responds with a 503 if the last canary email was not stored in time,
+    /// so that external monitoring can alert on it.
+    pub async fn canary(canary: Option<Arc<Canary>>) -> Result<impl Reply, Rejection> {
+        let state = match canary {
+            Some(canary) => canary.state(),
+            None => return Err(warp::reject::not_found()),
+        };
+
+        let status = if state.healthy {
+            warp::http::StatusCode::OK
+        } else {
+            warp::http::StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&state), status))
+    }
+}
+
+/// JSON endpoints used to administer Vaulty
+pub mod admin {
+    use super::*;
+
+    /// Returns the effective settings for an address, along with the domain
+    /// and address layers they were resolved from
+    pub async fn settings(
+        address: String,
+        mut db: sqlx::PgPool,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        #[derive(Serialize)]
+        struct AddressSettings {
+            address: String,
+            defaults: Settings,
+            domain: vaulty::settings::SettingsLayer,
+            overrides: vaulty::settings::SettingsLayer,
+            effective: Settings,
+        }
+
+        let mut db_client = new_db_client(&mut db, &config);
+        let defaults = Settings::from_config(&config);
+
+        let address = db_client
+            .get_address(&[address.as_str()], &defaults)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?
+            .ok_or_else(|| warp::reject::custom(Error(vaulty::Error::InvalidRecipient)))?;
+
+        let resp = AddressSettings {
+            address: address.address,
+            defaults,
+            domain: address.domain_settings,
+            overrides: address.address_settings,
+            effective: address.settings,
+        };
+
+        Ok(warp::reply::json(&resp))
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct MaintenanceState {
+        pub enabled: bool,
+    }
+
+    /// Returns the maintenance state, switching it first if a new state is
+    /// given
+    pub async fn maintenance(state: Option<MaintenanceState>) -> Result<impl Reply, Rejection> {
+        if let Some(state) = state {
+            let prev = filters::MAINTENANCE_MODE.swap(state.enabled, Ordering::SeqCst);
+
+            if prev != state.enabled {
+                log::warn!(
+                    "Maintenance mode {}",
+                    if state.enabled { "enabled" } else { "disabled" }
+                );
+            }
+        }
+
+        let state = MaintenanceState {
+            enabled: filters::MAINTENANCE_MODE.load(Ordering::SeqCst),
+        };
+
+        Ok(warp::reply::json(&state))
+    }
+
+    /// Returns the enabled state of each pipeline stage, switching the given
+    /// stages first
+    pub async fn flags(
+        updates: Option<std::collections::BTreeMap<Stage, bool>>,
+    ) -> Result<impl Reply, Rejection> {
+        for (stage, enabled) in updates.unwrap_or_default() {
+            flags::set_enabled(stage, enabled);
+        }
+
+        Ok(warp::reply::json(&flags::snapshot()))
+    }
+
+    /// Number of rows fetched from the DB at a time during an export
+    const EXPORT_BATCH_SIZE: usize = 1000;
+
+    #[derive(Debug, Deserialize)]
+    pub struct ExportQuery {
+        /// First day to export (UTC), inclusive
+        pub start: chrono::NaiveDate,
+        /// Last day to export (UTC), exclusive
+        pub end: chrono::NaiveDate,
+        pub table: Option<vaulty::export::Table>,
+        /// "csv" (default) or "jsonl"
+        pub format: Option<String>,
+        /// Comma-separated list of columns; all columns by default
+        pub columns: Option<String>,
+        #[serde(default)]
+        pub anonymize: bool,
+    }
+
+    /// Export email or attachment metadata for a date range
+    ///
+    /// Rows are fetched and encoded in batches while the response is
+    /// streamed, so large ranges are never held in memory.
+    pub async fn export(
+        query: ExportQuery,
+        db: sqlx::PgPool,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        use vaulty::export::{Column, Cursor, Exporter, Format, Table};
+
+        if query.end <= query.start {
+            let msg = "Export end date must be after its start date".to_string();
+            return Err(warp::reject::custom(Error(vaulty::Error::InvalidRequest(
+                msg,
+            ))));
+        }
+
+        let table = query.table.unwrap_or(Table::Emails);
+        let format = Format::from_name(query.format.as_deref().unwrap_or("csv"))
+            .map_err(|e| warp::reject::custom(Error(e)))?;
+        let columns = Column::parse_list(query.columns.as_deref().unwrap_or(""), table)
+            .map_err(|e| warp::reject::custom(Error(e)))?;
+
+        let mut exporter = Exporter::new(format, columns);
+
+        if query.anonymize {
+            // Without a configured key, pseudonyms are only consistent
+            // within a single export
+            let key = match &config.export_key {
+                Some(key) => key.as_bytes().to_vec(),
+                None => rand::random::<[u8; 32]>().to_vec(),
+            };
+
+            exporter = exporter.with_anonymization(&key);
+        }
+
+        let start =
+            chrono::DateTime::<chrono::Utc>::from_utc(query.start.and_hms(0, 0, 0), chrono::Utc);
+        let end =
+            chrono::DateTime::<chrono::Utc>::from_utc(query.end.and_hms(0, 0, 0), chrono::Utc);
+
+        log::info!(
+            "Exporting {} from {} to {} as {:?} (anonymized: {})",
+            table.name(),
+            query.start,
+            query.end,
+            format,
+            query.anonymize
+        );
+
+        let header = exporter.header().map(|h| Ok(Bytes::from(h)));
+        let exporter = Arc::new(exporter);
+
+        // The state is the cursor to fetch the next batch after, and is
+        // None once all batches have been fetched
+        let batches = stream::unfold(Some(None::<Cursor>), move |state| {
+            let mut db = db.clone();
+            let exporter = exporter.clone();
+
+            async move {
+                let after = match state {
+                    Some(after) => after,
+                    None => return None,
+                };
+                let mut db_client = vaulty::db::Client::new(&mut db);
+
+                let rows = db_client
+                    .get_export_rows(table, start, end, after.as_ref(), EXPORT_BATCH_SIZE)
+                    .await;
+
+                match rows {
+                    Ok(rows) => {
+                        let next = if rows.len() < EXPORT_BATCH_SIZE {
+                            None
+                        } else {
+                            rows.last().map(|(_, cursor)| Some(cursor.clone()))
+                        };
+
+                        let chunk: String =
+                            rows.iter().map(|(row, _)| exporter.encode(row)).collect();
+
+                        Some((Ok(Bytes::from(chunk)), next))
+                    }
+                    Err(e) => {
+                        log::error!("Export of {} failed: {}", table.name(), e);
+                        Some((Err(e), None))
+                    }
+                }
+            }
+        });
+
+        let body = stream::iter(header).chain(batches);
+
+        let filename = format!(
+            "vaulty-{}-{}-{}.{}",
+            table.name(),
+            query.start,
+            query.end,
+            format.extension()
+        );
+
+        warp::http::Response::builder()
+            .header(warp::http::header::CONTENT_TYPE, format.content_type())
+            .header(
+                warp::http::header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            )
+            .body(hyper::Body::wrap_stream(body))
+            .map_err(|e| {
+                warp::reject::custom(Error(vaulty::Error::internal(
+                    "Failed to build download response",
+                    e,
+                )))
+            })
+    }
+
+    fn reject(e: vaulty::Error) -> Rejection {
+        warp::reject::custom(Error(e))
+    }
+
+    /// Reply with the user or address, or a 404 if it does not exist
+    fn found<T: Serialize>(value: Option<T>) -> Result<warp::reply::Json, Rejection> {
+        match value {
+            Some(value) => Ok(warp::reply::json(&value)),
+            None => Err(warp::reject::not_found()),
+        }
+    }
+
+    fn created<T: Serialize>(value: &T) -> impl Reply {
+        warp::reply::with_status(warp::reply::json(value), warp::http::StatusCode::CREATED)
+    }
+
+    fn no_content(deleted: bool) -> Result<impl Reply, Rejection> {
+        if deleted {
+            Ok(warp::http::StatusCode::NO_CONTENT)
+        } else {
+            Err(warp::reject::not_found())
+        }
+    }
+
+    pub async fn list_users(mut db: sqlx::PgPool) -> Result<impl Reply, Rejection> {
+        let mut db_client = vaulty::db::Client::new(&mut db);
+        let users = db_client.list_users().await.map_err(reject)?;
+
+        Ok(warp::reply::json(&users))
+    }
+
+    pub async fn get_user(id: i32, mut db: sqlx::PgPool) -> Result<impl Reply, Rejection> {
+        let mut db_client = vaulty::db::Client::new(&mut db);
+
+        found(db_client.get_user(id).await.map_err(reject)?)
+    }
+
+    pub async fn create_user(
+        user: vaulty::admin::NewUser,
+        mut db: sqlx::PgPool,
+    ) -> Result<impl Reply, Rejection> {
+        user.validate().map_err(reject)?;
+
+        let mut db_client = vaulty::db::Client::new(&mut db);
+        let user = db_client.create_user(&user).await.map_err(reject)?;
+
+        log::info!("Created user {} ({})", user.username, user.id);
+
+        Ok(created(&user))
+    }
+
+    pub async fn update_user(
+        id: i32,
+        update: vaulty::admin::UserUpdate,
+        mut db: sqlx::PgPool,
+    ) -> Result<impl Reply, Rejection> {
+        update.validate().map_err(reject)?;
+
+        let mut db_client = vaulty::db::Client::new(&mut db);
+
+        found(db_client.update_user(id, &update).await.map_err(reject)?)
+    }
+
+    pub async fn delete_user(id: i32, mut db: sqlx::PgPool) -> Result<impl Reply, Rejection> {
+        let mut db_client = vaulty::db::Client::new(&mut db);
+        let deleted = db_client.delete_user(id).await.map_err(reject)?;
+
+        if deleted {
+            log::info!("Deleted user {}", id);
+        }
+
+        no_content(deleted)
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct AddressesQuery {
+        /// Only list the addresses of this user
+        pub user: Option<i32>,
+    }
+
+    pub async fn list_addresses(
+        query: AddressesQuery,
+        mut db: sqlx::PgPool,
+    ) -> Result<impl Reply, Rejection> {
+        let mut db_client = vaulty::db::Client::new(&mut db);
+        let addresses = db_client.list_addresses(query.user).await.map_err(reject)?;
+
+        Ok(warp::reply::json(&addresses))
+    }
+
+    pub async fn get_address(id: i32, mut db: sqlx::PgPool) -> Result<impl Reply, Rejection> {
+        let mut db_client = vaulty::db::Client::new(&mut db);
+
+        found(db_client.get_address_info(id).await.map_err(reject)?)
+    }
+
+    pub async fn create_address(
+        address: vaulty::admin::NewAddress,
+        mut db: sqlx::PgPool,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        address.validate().map_err(reject)?;
+
+        let mut db_client = new_db_client(&mut db, &config);
+        let address = db_client.create_address(&address).await.map_err(reject)?;
+
+        log::info!("Created address {} ({})", address.address, address.id);
+
+        Ok(created(&address))
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct DryRunQuery {
+        /// Preview the change instead of making it
+        #[serde(default)]
+        pub dry_run: bool,
+    }
+
+    /// Preview a change to an address, without making it
+    ///
+    /// See `vaulty::admin::preview`.
+    async fn preview(
+        id: i32,
+        apply: impl FnOnce(&mut vaulty::admin::AddressInfo),
+        storage: Option<&vaulty::admin::Storage>,
+        db: &mut sqlx::PgPool,
+        config: &Config,
+    ) -> Result<warp::reply::Json, Rejection> {
+        let mut db_client = new_db_client(db, config);
+        let defaults = Settings::from_config(config);
+
+        let before = db_client.get_address_info(id).await.map_err(reject)?;
+        let address = db_client
+            .get_address_by_id(id, &defaults)
+            .await
+            .map_err(reject)?;
+
+        let (before, address) = match (before, address) {
+            (Some(before), Some(address)) => (before, address),
+            _ => return Err(warp::reject::not_found()),
+        };
+
+        let mut after = before.clone();
+        apply(&mut after);
+
+        let now = db_client.clock().now();
+        let dry_run = vaulty::admin::preview(&before, &after, &address, &defaults, storage, now);
+
+        Ok(warp::reply::json(&dry_run))
+    }
+
+    pub async fn update_address(
+        id: i32,
+        query: DryRunQuery,
+        update: vaulty::admin::AddressUpdate,
+        mut db: sqlx::PgPool,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        if query.dry_run {
+            return preview(id, |address| update.apply(address), None, &mut db, &config).await;
+        }
+
+        let mut db_client = vaulty::db::Client::new(&mut db);
+
+        found(
+            db_client
+                .update_address(id, &update)
+                .await
+                .map_err(reject)?,
+        )
+    }
+
+    pub async fn set_quotas(
+        id: i32,
+        query: DryRunQuery,
+        quotas: vaulty::admin::Quotas,
+        mut db: sqlx::PgPool,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        quotas.validate().map_err(reject)?;
+
+        if query.dry_run {
+            let apply = |address: &mut vaulty::admin::AddressInfo| address.quotas = quotas.clone();
+            return preview(id, apply, None, &mut db, &config).await;
+        }
+
+        let mut db_client = vaulty::db::Client::new(&mut db);
+
+        found(db_client.set_quotas(id, &quotas).await.map_err(reject)?)
+    }
+
+    /// Replace the storage credentials of an address
+    ///
+    /// Tokens are encrypted with the storage token key, if configured.
+    pub async fn set_storage(
+        id: i32,
+        query: DryRunQuery,
+        storage: vaulty::admin::Storage,
+        mut db: sqlx::PgPool,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        storage.validate().map_err(reject)?;
+
+        if query.dry_run {
+            let apply = |address: &mut vaulty::admin::AddressInfo| storage.apply(address);
+            return preview(id, apply, Some(&storage), &mut db, &config).await;
+        }
+
+        let mut db_client = new_db_client(&mut db, &config);
+        let address = db_client.set_storage(id, &storage).await.map_err(reject)?;
+
+        if let Some(address) = &address {
+            log::info!("Updated storage of {} ({})", address.address, id);
+        }
+
+        found(address)
+    }
+
+    pub async fn delete_address(id: i32, mut db: sqlx::PgPool) -> Result<impl Reply, Rejection> {
+        let mut db_client = vaulty::db::Client::new(&mut db);
+        let deleted = db_client.delete_address(id).await.map_err(reject)?;
+
+        if deleted {
+            log::info!("Deleted address {}", id);
+        }
+
+        no_content(deleted)
+    }
+
+    pub async fn get_whitelist(id: i32, mut db: sqlx::PgPool) -> Result<impl Reply, Rejection> {
+        let mut db_client = vaulty::db::Client::new(&mut db);
+
+        found(db_client.get_whitelist(id).await.map_err(reject)?)
+    }
 
-        let h = handler.handle(email, Some(attachment), name, size).await;
+    pub async fn add_to_whitelist(
+        id: i32,
+        entry: vaulty::admin::WhitelistEntry,
+        mut db: sqlx::PgPool,
+    ) -> Result<impl Reply, Rejection> {
+        vaulty::admin::validate_whitelist_entry(&entry.sender).map_err(reject)?;
 
-        // If an error occurred while processing this attachment,
-        // mark the email as failed
-        if let Err(e) = h.as_ref() {
-            let msg = e.to_string();
+        let mut db_client = vaulty::db::Client::new(&mut db);
 
-            // Insert failed attachment
+        found(
             db_client
-                .insert_attachment(&email, index, size, false, Some(&msg))
-                .await;
+                .add_to_whitelist(id, &entry.sender)
+                .await
+                .map_err(reject)?,
+        )
+    }
 
-            db_client.update_email(&email, false, Some(&msg)).await;
-        }
+    pub async fn remove_from_whitelist(
+        id: i32,
+        sender: String,
+        mut db: sqlx::PgPool,
+    ) -> Result<impl Reply, Rejection> {
+        let mut db_client = vaulty::db::Client::new(&mut db);
+        let sender = vaulty::admin::decode_path_segment(&sender);
 
-        let resp = h
-            .map(|_| warp::reply::json(&result))
-            .map_err(|e| warp::reject::custom(Error::from(e)));
+        found(
+            db_client
+                .remove_from_whitelist(id, &sender)
+                .await
+                .map_err(reject)?,
+        )
+    }
 
-        // Bail out early if we failed
-        if resp.is_err() {
-            return resp;
-        }
+    #[derive(Debug, Deserialize)]
+    pub struct LogsQuery {
+        /// Only show messages about the emails of this address
+        pub address: Option<String>,
+        /// ID of the last message seen; only newer messages are returned
+        pub after: Option<i32>,
+        pub limit: Option<i64>,
+    }
 
-        // Insert successful attachment into DB
-        db_client
-            .insert_attachment(&email, index, size, true, None)
-            .await;
+    pub async fn logs(query: LogsQuery, mut db: sqlx::PgPool) -> Result<impl Reply, Rejection> {
+        let mut db_client = vaulty::db::Client::new(&mut db);
+        let limit = vaulty::admin::log_limit(query.limit);
 
-        // Update used storage for this attachment on success
-        if let Err(e) = address
-            .update_storage_used(size, false, &mut db_client)
+        let logs = db_client
+            .get_logs(query.address.as_deref(), query.after, limit)
             .await
-        {
-            let msg = e.to_string();
-            log::error!("{}", msg);
-            return Err(warp::reject::custom(Error::from(e)));
-        }
+            .map_err(reject)?;
 
-        // Finally, update the cache
-        if entry.attachments_processed.len() + 1 < email.num_attachments as usize {
-            // Update the cache entry
-            let mut lock = MAIL_CACHE.write().await;
-            let entry = lock.get_mut(&mail_id).unwrap();
-            entry.attachments_processed.push(index);
-        } else {
-            // If this is the last attachment for this email, cleanup the cache
-            // entry.
-            log::info!("Removing {} from cache", mail_id);
-            MAIL_CACHE.write().await.remove(&mail_id);
+        Ok(warp::reply::json(&logs))
+    }
 
-            // Send back a JSON result to the client containing all info
-            result.storage_backend = Some(address.storage_backend.clone());
-            result.num_attachments = Some(email.num_attachments as i32);
-        }
+    pub async fn email_status(id: String, mut db: sqlx::PgPool) -> Result<impl Reply, Rejection> {
+        let id = uuid::Uuid::parse_str(&id).map_err(|_| warp::reject::not_found())?;
+        let mut db_client = vaulty::db::Client::new(&mut db);
 
-        resp
+        found(db_client.get_email_status(&id).await.map_err(reject)?)
     }
 }
 
-/// JSON endpoints used to monitor server state
-pub mod monitor {
+/// JSON endpoints used by external systems to sync Vaulty state
+pub mod api {
     use super::*;
 
-    /// Returns a snapshot of mail cache state
-    pub async fn cache(mut _db: sqlx::PgPool) -> Result<impl Reply, Rejection> {
-        #[derive(Serialize)]
-        struct CacheState {
-            num_processed: u64,
-            avg_processing_time: f32,
+    use vaulty::changes::{self, Cursor, Page};
+
+    #[derive(Debug, Deserialize)]
+    pub struct ChangesQuery {
+        /// Cursor returned with the last page; starts from the beginning if
+        /// not set
+        pub since: Option<String>,
+        /// Only return changes for this address
+        pub address: Option<String>,
+        pub limit: Option<usize>,
+    }
+
+    /// Returns the email and attachment state transitions after a cursor,
+    /// oldest first
+    ///
+    /// Clients poll with the cursor of the last page they got. The cursor
+    /// only moves forward when there are new changes.
+    pub async fn changes(
+        query: ChangesQuery,
+        mut db: sqlx::PgPool,
+    ) -> Result<impl Reply, Rejection> {
+        let since = match &query.since {
+            Some(since) => Cursor::decode(since).map_err(|e| warp::reject::custom(Error(e)))?,
+            None => Cursor::default(),
+        };
+        let limit = query
+            .limit
+            .unwrap_or(changes::DEFAULT_PAGE_SIZE)
+            .max(1)
+            .min(changes::MAX_PAGE_SIZE);
+
+        let mut db_client = vaulty::db::Client::new(&mut db);
+
+        // Fetch one extra change to know if there are more
+        let changes = db_client
+            .get_changes(since, query.address.as_deref(), limit + 1)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+        Ok(warp::reply::json(&Page::new(changes, since, limit)))
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct StatsQuery {
+        /// Number of days of email to compute stats over, up to now
+        pub days: Option<i64>,
+    }
+
+    /// Returns the distribution of email sizes, attachment counts, and
+    /// attachment sizes (overall and per content type)
+    ///
+    /// Stats are computed from the DB and cached for a while.
+    pub async fn stats(
+        query: StatsQuery,
+        mut db: sqlx::PgPool,
+        cache: Arc<StatsCache>,
+    ) -> Result<impl Reply, Rejection> {
+        let days = query
+            .days
+            .unwrap_or(vaulty::stats::DEFAULT_DAYS)
+            .max(1)
+            .min(vaulty::stats::MAX_DAYS);
+
+        if let Some(stats) = cache.get(days) {
+            return Ok(warp::reply::json(stats.as_ref()));
         }
 
-        let state = {
-            let cache = MAIL_CACHE.read().await;
+        let mut db_client = vaulty::db::Client::new(&mut db);
+        let since = db_client.clock().now() - chrono::Duration::days(days);
+
+        let stats = db_client
+            .get_stats(since)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+        let stats = Arc::new(stats);
+        cache.insert(days, stats.clone());
 
-            CacheState {
-                num_processed: cache.num_processed,
-                avg_processing_time: cache.avg_processing_time,
-            }
+        Ok(warp::reply::json(stats.as_ref()))
+    }
+
+    /// Returns the email and storage quota use of an address over its
+    /// current quota period, day by day, along with when each quota is
+    /// projected to run out at the recent rate
+    pub async fn burndown(
+        address: String,
+        mut db: sqlx::PgPool,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        let mut db_client = new_db_client(&mut db, &config);
+        let defaults = Settings::from_config(&config);
+
+        let found = db_client
+            .get_address(&[address.as_str()], &defaults)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?
+            .ok_or_else(|| warp::reject::custom(Error(vaulty::Error::InvalidRecipient)))?;
+
+        let days = db_client
+            .get_daily_usage(&found.address, found.last_renewal_time)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+        let period = chrono::Duration::days(config.quota_period_days);
+
+        let burndown = vaulty::stats::Burndown::new(
+            &found.address,
+            found.last_renewal_time,
+            found.quota_period_end(period),
+            (found.num_received as i64, found.settings.email_quota as i64),
+            (found.storage_used, found.settings.storage_quota),
+            &days,
+            db_client.clock().now(),
+        );
+
+        Ok(warp::reply::json(&burndown))
+    }
+
+    /// Outcome of a test email sent to an address
+    #[derive(Debug, Serialize)]
+    pub struct TestReport {
+        pub mail_id: String,
+        pub address: String,
+        pub storage_backend: storage::Backend,
+
+        /// Where the test attachment was stored, on success
+        pub storage_path: Option<String>,
+
+        pub success: bool,
+        pub error: Option<vaulty::Error>,
+    }
+
+    /// Sends a test email with a small attachment through the full pipeline
+    /// to an address, so that its storage connection can be checked right
+    /// after setup
+    ///
+    /// The email and attachment are handled exactly as if they came from
+    /// the filter, except that the email is marked as a test in the DB and
+    /// stored in its own folder. It counts against the address quota.
+    pub async fn send_test(
+        address: String,
+        mut db: sqlx::PgPool,
+        sessions: Arc<dyn SessionStore>,
+        limits: Arc<UploadLimits>,
+        rate_limiter: Arc<RateLimiter>,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        let id = uuid::Uuid::new_v4();
+        let email = vaulty::canary::test_email(&address, &id)
+            .map_err(|e| warp::reject::custom(Error(e)))?;
+
+        let (mut report, file_paths) = {
+            let mut db_client = new_db_client(&mut db, &config);
+            let defaults = Settings::from_config(&config);
+
+            let found = db_client
+                .get_address(&[address.as_str()], &defaults)
+                .await
+                .map_err(|e| warp::reject::custom(Error::from(e)))?
+                .ok_or_else(|| warp::reject::custom(Error(vaulty::Error::InvalidRecipient)))?;
+
+            let handler = postfix::email_handler(&found, &email, &config, db_client.clock());
+            let file_paths: Vec<String> = email
+                .attachments
+                .iter()
+                .flatten()
+                .map(|a| handler.file_path(a.get_name()))
+                .collect();
+
+            let report = TestReport {
+                mail_id: email.uuid.to_string(),
+                address: found.address.clone(),
+                storage_backend: found.settings.storage_backend.clone(),
+                storage_path: None,
+                success: false,
+                error: None,
+            };
+
+            (report, file_paths)
         };
 
-        Ok(warp::reply::json(&state))
+        log::info!("Sending test email {} to {}", report.mail_id, address);
+
+        let outcome =
+            postfix::deliver(email, None, db, sessions, limits, rate_limiter, config).await;
+
+        match outcome {
+            Ok(_) => {
+                log::info!("Test email {} stored for {}", report.mail_id, address);
+
+                report.success = true;
+                report.storage_path = file_paths.into_iter().next();
+            }
+            Err(err) => {
+                log::warn!(
+                    "Test email {} failed for {}: {}",
+                    report.mail_id,
+                    address,
+                    err
+                );
+
+                report.error = Some(err);
+            }
+        }
+
+        Ok(warp::reply::json(&report))
+    }
+
+    /// Checks a synthetic email against the whitelist, priority rules,
+    /// directives, and attachment rules of an address, and returns which
+    /// rules match and what would happen to the email
+    ///
+    /// Nothing is stored, logged, or notified. Rate limits and quotas are not
+    /// checked, as they depend on the email traffic at the time.
+    pub async fn test_rules(
+        address: String,
+        test: vaulty::rules::TestEmail,
+        mut db: sqlx::PgPool,
+        config: Arc<Config>,
+    ) -> Result<impl Reply, Rejection> {
+        let mut db_client = new_db_client(&mut db, &config);
+        let defaults = Settings::from_config(&config);
+
+        let found = db_client
+            .get_address(&[address.as_str()], &defaults)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?
+            .ok_or_else(|| warp::reject::custom(Error(vaulty::Error::InvalidRecipient)))?;
+
+        let email = test.to_email(&found.address);
+
+        let sender_allowed = found
+            .allows_sender(&email.sender, &mut db_client)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+        let is_owner = found
+            .is_whitelisted(&email, &mut db_client)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+        let priority_rules = db_client
+            .get_priority_rules(&found.address)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+        let attachment_rules = db_client
+            .get_attachment_rules(&found.address)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+        let routes = db_client
+            .get_storage_routes(&found.address)
+            .await
+            .map_err(|e| warp::reject::custom(Error::from(e)))?;
+
+        let outcome = vaulty::rules::evaluate(
+            &test,
+            &found.address,
+            sender_allowed,
+            is_owner,
+            &priority_rules,
+            &attachment_rules,
+            &routes,
+        );
+
+        Ok(warp::reply::json(&outcome))
     }
 }
 
 pub async fn mailgun(
     content_type: Option<String>,
-    body: String,
-    api_key: Option<String>,
+    body: Bytes,
+    verifier: Arc<MailgunVerifier>,
+    db: sqlx::PgPool,
+    sessions: Arc<dyn SessionStore>,
+    limits: Arc<UploadLimits>,
+    rate_limiter: Arc<RateLimiter>,
+    config: Arc<Config>,
 ) -> Result<impl Reply, Rejection> {
     if let None = content_type {
         return Err(warp::reject::not_found());
@@ -397,11 +4284,61 @@ pub async fn mailgun(
 
     let content_type = content_type.unwrap();
 
+    // JSON posts are parsed once, keeping only the fields that are used
+    if content_type == "application/json" {
+        let post = mailgun::JsonPost::parse(&body).map_err(|e| {
+            log::warn!("Rejecting Mailgun post: {}", e);
+            warp::reject::custom(Error(e))
+        })?;
+        drop(body);
+
+        verifier
+            .verify(post.signature.as_ref())
+            .map_err(|e| warp::reject::custom(Error(e)))?;
+
+        // Messages stored by Mailgun only include a URL to the full message
+        if let Some(stored) = post.stored {
+            mailgun_stored(stored, db, sessions, limits, rate_limiter, config).await?;
+        } else {
+            mailgun_handle(
+                post.email,
+                post.attachments,
+                db,
+                sessions,
+                limits,
+                rate_limiter,
+                config,
+            )
+            .await?;
+        }
+
+        return Ok(warp::reply());
+    }
+
+    let body = std::str::from_utf8(&body).map_err(|_e| warp::reject::not_found())?;
+
+    let signature = mailgun::Signature::from_form(body);
+    verifier
+        .verify(signature.as_ref())
+        .map_err(|e| warp::reject::custom(Error(e)))?;
+
+    // Messages stored by Mailgun only include a URL to the full message
+    let stored = if content_type == "application/x-www-form-urlencoded" {
+        mailgun::StoredMessage::from_form(body).ok()
+    } else {
+        None
+    };
+
+    if let Some(stored) = stored {
+        mailgun_stored(stored, db, sessions, limits, rate_limiter, config).await?;
+        return Ok(warp::reply());
+    }
+
     let mail;
     let attachments;
 
-    if content_type == "application/json" {
-        mail = match mailgun::Email::from_json(&body) {
+    if content_type == "application/x-www-form-urlencoded" {
+        mail = match mailgun::Email::from_form(body) {
             Ok(m) => m,
             Err(e) => {
                 log::error!("{:?}", e);
@@ -409,72 +4346,513 @@ pub async fn mailgun(
             }
         };
 
-        attachments = match mailgun::Attachment::from_json(&body) {
+        attachments = match mailgun::Attachment::from_form(body) {
             Ok(m) => m,
             Err(e) => {
                 log::error!("{:?}", e);
                 return Err(warp::reject::not_found());
             }
         };
-    } else if content_type == "application/x-www-form-urlencoded" {
-        mail = match mailgun::Email::from_form(&body) {
-            Ok(m) => m,
+    } else {
+        return Err(warp::reject::not_found());
+    }
+
+    mailgun_handle(
+        mail,
+        attachments,
+        db,
+        sessions,
+        limits,
+        rate_limiter,
+        config,
+    )
+    .await?;
+
+    Ok(warp::reply())
+}
+
+/// Handles a Mailgun post sent as `multipart/form-data`
+///
+/// Mailgun forwards emails this way, with each attachment as a file part
+/// (`attachment-1`, `attachment-2`, ...) instead of a URL to fetch it from.
+pub async fn mailgun_multipart(
+    form: warp::multipart::FormData,
+    verifier: Arc<MailgunVerifier>,
+    db: sqlx::PgPool,
+    sessions: Arc<dyn SessionStore>,
+    limits: Arc<UploadLimits>,
+    rate_limiter: Arc<RateLimiter>,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let parts: Vec<warp::multipart::Part> = form.try_collect().await.map_err(|e| {
+        log::error!("Failed to read multipart form: {}", e);
+        warp::reject::not_found()
+    })?;
+
+    let mut fields = Vec::new();
+    let mut attachments = Vec::new();
+
+    for mut part in parts {
+        let name = part.name().to_string();
+        let filename = part.filename().map(String::from);
+        let content_type = part
+            .content_type()
+            .unwrap_or("application/octet-stream")
+            .to_string();
+
+        let mut data = Vec::new();
+        while let Some(chunk) = part.data().await {
+            let mut chunk = chunk.map_err(|e| {
+                log::error!("Failed to read multipart field {}: {}", name, e);
+                warp::reject::not_found()
+            })?;
+            data.extend_from_slice(&chunk.to_bytes());
+        }
+
+        match filename {
+            Some(filename) if mailgun::Attachment::is_field(&name) => {
+                attachments.push(mailgun::Attachment::inline(filename, content_type, data));
+            }
+            _ => fields.push((name, String::from_utf8_lossy(&data).into_owned())),
+        }
+    }
+
+    let signature = mailgun::Signature::from_fields(fields.clone());
+    verifier
+        .verify(signature.as_ref())
+        .map_err(|e| warp::reject::custom(Error(e)))?;
+
+    // Stored messages may also be posted as multipart forms
+    // The parse error is not `Send`, so it is dropped before awaiting
+    if attachments.is_empty() {
+        let stored = mailgun::StoredMessage::from_fields(fields.clone()).ok();
+        if let Some(stored) = stored {
+            mailgun_stored(stored, db, sessions, limits, rate_limiter, config).await?;
+            return Ok(warp::reply());
+        }
+    }
+
+    let mail = mailgun::Email::from_fields(fields);
+
+    mailgun_handle(
+        mail,
+        attachments,
+        db,
+        sessions,
+        limits,
+        rate_limiter,
+        config,
+    )
+    .await?;
+
+    Ok(warp::reply())
+}
+
+/// Run an email posted by Mailgun through the pipeline
+///
+/// The attachments are fetched from Mailgun first, unless they were posted
+/// inline.
+async fn mailgun_handle(
+    mail: mailgun::Email,
+    attachments: Vec<mailgun::Attachment>,
+    db: sqlx::PgPool,
+    sessions: Arc<dyn SessionStore>,
+    limits: Arc<UploadLimits>,
+    rate_limiter: Arc<RateLimiter>,
+    config: Arc<Config>,
+) -> Result<(), Rejection> {
+    let mut mail: email::Email = mail.into();
+    mail.uuid = uuid::Uuid::new_v4();
+
+    let api_key = config.mailgun_key.as_ref();
+    let max_size = config.max_attachment_size.min(config.max_email_size) as usize;
+
+    // The attachments are held in memory, as for Postfix mail, since they
+    // are hashed and possibly bundled before they are stored. Downloads are
+    // cut short once they exceed the size limits instead.
+    //
+    // Attachments are fetched concurrently, but kept in order so that their
+    // indices match the email
+    let fetched: Vec<Result<(String, String, Vec<u8>), vaulty::Error>> =
+        stream::iter(attachments.into_iter().map(|a| async move {
+            let name = a.name.clone();
+            let content_type = a.content_type().to_string();
+            let too_large = || {
+                vaulty::Error::PayloadTooLarge(format!(
+                    "Attachment {} is larger than {} bytes",
+                    name, max_size
+                ))
+            };
+
+            // Mailgun reports the size, so most are not even fetched
+            if a.size > max_size {
+                return Err(too_large());
+            }
+
+            let mut data = Vec::with_capacity(a.size);
+            let chunks = a
+                .fetch(api_key)
+                .await
+                .map_err(|e| vaulty::Error::Temporary(e.to_string()))?;
+            futures::pin_mut!(chunks);
+
+            while let Some(chunk) = chunks
+                .try_next()
+                .await
+                .map_err(|e| vaulty::Error::Temporary(e.to_string()))?
+            {
+                if data.len() + chunk.len() > max_size {
+                    return Err(too_large());
+                }
+                data.extend_from_slice(&chunk);
+            }
+
+            Ok((name, content_type, data))
+        }))
+        .buffered(config.upload_concurrency_per_email)
+        .collect()
+        .await;
+
+    let mut size = mail.body.len() + mail.body_html.as_ref().map_or(0, |b| b.len());
+    let mut stored = Vec::new();
+
+    for (index, result) in fetched.into_iter().enumerate() {
+        let (name, mime, data) = match result {
+            Ok(fetched) => fetched,
+            Err(vaulty::Error::Temporary(e)) => {
+                let msg = format!("Failed to fetch attachment of {}: {}", mail.uuid, e);
+                log::error!("{}", msg);
+                return Err(reject_email(mail.uuid, vaulty::Error::Temporary(msg)));
+            }
+            // Mailgun would only retry, so the email is dropped like any
+            // other that is rejected
             Err(e) => {
-                log::error!("{:?}", e);
-                return Err(warp::reject::not_found());
+                log::warn!("Rejecting email {} received from Mailgun: {}", mail.uuid, e);
+                return Ok(());
             }
         };
 
-        attachments = match mailgun::Attachment::from_form(&body) {
-            Ok(m) => m,
+        size += data.len();
+
+        stored.push(email::Attachment::Regular(email::AttachmentData {
+            mime,
+            name,
+            size: data.len(),
+            data,
+            index: index as u16,
+            email_id: mail.uuid,
+            ..Default::default()
+        }));
+    }
+
+    mail.size = size;
+    mail.num_attachments = stored.len() as u16;
+    mail.attachments = Some(stored);
+
+    mailgun_deliver(mail, None, db, sessions, limits, rate_limiter, config).await
+}
+
+/// Number of attempts made to fetch a stored message from Mailgun
+const MAILGUN_FETCH_ATTEMPTS: u32 = 3;
+
+/// Handles a Mailgun `store()` notification
+///
+/// The full MIME message is fetched from Mailgun and run through the same
+/// pipeline as Postfix mail. The stored copy is deleted once the email has
+/// been handled.
+async fn mailgun_stored(
+    stored: mailgun::StoredMessage,
+    db: sqlx::PgPool,
+    sessions: Arc<dyn SessionStore>,
+    limits: Arc<UploadLimits>,
+    rate_limiter: Arc<RateLimiter>,
+    config: Arc<Config>,
+) -> Result<(), Rejection> {
+    let api_key = config.mailgun_key.clone();
+    let mut attempt = 1;
+
+    let mime = loop {
+        let result = stored
+            .fetch(api_key.as_ref())
+            .await
+            .map_err(|e| e.to_string());
+
+        match result {
+            Ok(mime) => break mime,
+            Err(e) if attempt < MAILGUN_FETCH_ATTEMPTS => {
+                log::warn!(
+                    "Failed to fetch stored message {} (attempt {}): {}",
+                    stored.url,
+                    attempt,
+                    e
+                );
+
+                // Back off exponentially: 1s, 2s, ...
+                let delay = 1 << (attempt - 1);
+                tokio::time::delay_for(std::time::Duration::from_secs(delay)).await;
+
+                attempt += 1;
+            }
             Err(e) => {
-                log::error!("{:?}", e);
+                log::error!("Failed to fetch stored message {}: {}", stored.url, e);
                 return Err(warp::reject::not_found());
             }
-        };
-    } else {
-        return Err(warp::reject::not_found());
-    }
+        }
+    };
 
-    let mail: email::Email = mail.into();
-    let storage_backend: vaulty::storage::Backend = "dropbox".into();
+    let mail = match email::Email::from_mime(&mime) {
+        Ok(m) => m,
+        Err(e) => {
+            log::error!("Failed to parse stored message {}: {}", stored.url, e);
+            return Err(warp::reject::not_found());
+        }
+    };
 
-    let handler = vaulty::EmailHandler::new("test123", &storage_backend, "/vaulty");
+    let mail = mail
+        .with_sender(stored.sender.clone())
+        .with_recipients(vec![stored.recipient.clone()]);
 
-    let attachment_tasks = attachments
-        .into_iter()
-        .map(|a| a.fetch(api_key.as_ref()))
-        .collect::<FuturesUnordered<_>>()
-        .map_ok(|a| email::Attachment::from(a))
-        .map_err(|e| vaulty::Error::Generic(e.to_string()))
-        .and_then(|a| {
-            let name = a.get_name().clone();
-            let size = a.get_size();
-            let data = vec![Ok(Bytes::from(a.get_data_owned()))];
-            let data = stream::iter(data);
-            handler.handle(&mail, Some(data), name, size)
-        })
-        .map_err(|_| warp::reject::not_found());
+    mailgun_deliver(
+        mail,
+        Some(&mime),
+        db,
+        sessions,
+        limits,
+        rate_limiter,
+        config,
+    )
+    .await?;
 
-    // TODO: Consider making handle_email and handle_attachment
-    // Compiler complains about "unknown" type for the Option
-    // let email_task = handler.handle(&mail, None, "", 0).await;
-    // if let Err(_) = email_task {
-    //     return Err(warp::reject::not_found());
-    // }
+    log::info!("Stored message {} handled", stored.url);
 
-    for r in attachment_tasks
-        .collect::<Vec<Result<(), warp::reject::Rejection>>>()
+    // Acknowledge the message by removing it from Mailgun storage
+    // Mailgun expires stored messages regardless, so this is best-effort
+    let deleted = stored
+        .delete(api_key.as_ref())
         .await
-    {
-        if let Err(_) = r {
+        .map_err(|e| e.to_string());
+
+    if let Err(e) = deleted {
+        log::warn!("Failed to delete stored message {}: {}", stored.url, e);
+    }
+
+    Ok(())
+}
+
+/// Run an email received from Mailgun through the same steps as the filter
+///
+/// Mailgun retries a post until it succeeds, so only temporary errors are
+/// returned. Rejected emails are reported to their sender and owner by the
+/// pipeline, and are only logged here.
+async fn mailgun_deliver(
+    mail: email::Email,
+    raw_message: Option<&[u8]>,
+    db: sqlx::PgPool,
+    sessions: Arc<dyn SessionStore>,
+    limits: Arc<UploadLimits>,
+    rate_limiter: Arc<RateLimiter>,
+    config: Arc<Config>,
+) -> Result<(), Rejection> {
+    let uuid = mail.uuid;
+    let result = postfix::deliver(
+        mail,
+        raw_message,
+        db,
+        sessions,
+        limits,
+        rate_limiter,
+        config,
+    )
+    .await;
+
+    match result {
+        Ok(_) => log::info!("Email {} received from Mailgun", uuid),
+        Err(vaulty::Error::Temporary(msg)) => {
+            log::warn!("Failed to handle email {} from Mailgun: {}", uuid, msg);
+            return Err(reject_email(uuid, vaulty::Error::Temporary(msg)));
+        }
+        Err(e) => log::warn!("Rejecting email {} received from Mailgun: {}", uuid, e),
+    }
+
+    Ok(())
+}
+
+/// Handles a message published by SNS for AWS SES inbound email
+///
+/// Subscriptions to configured topics are confirmed. Received emails are
+/// either included in the notification, or fetched from the S3 bucket that
+/// SES stored them in, and then run through the same pipeline as mail
+/// received over SMTP.
+pub async fn ses(
+    body: bytes::Bytes,
+    db: sqlx::PgPool,
+    sessions: Arc<dyn SessionStore>,
+    limits: Arc<UploadLimits>,
+    rate_limiter: Arc<RateLimiter>,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let message = match vaulty::ses::SnsMessage::from_json(&body) {
+        Ok(m) => m,
+        Err(e) => {
+            log::warn!("Invalid SNS message: {}", e);
             return Err(warp::reject::not_found());
         }
+    };
+
+    if !config.ses_topic_arns.contains(&message.topic_arn) {
+        log::warn!(
+            "Rejecting SNS message {} from unknown topic {}",
+            message.id,
+            message.topic_arn
+        );
+        return Err(warp::reject::not_found());
     }
 
-    log::info!("Fetched all attachments successfully!");
+    match message.kind.as_str() {
+        vaulty::ses::SUBSCRIPTION_CONFIRMATION => {
+            if let Err(e) = message.confirm().await {
+                let msg = format!(
+                    "Failed to confirm subscription to {}: {}",
+                    message.topic_arn, e
+                );
+                log::error!("{}", msg);
+                return Err(warp::reject::custom(Error(vaulty::Error::Temporary(msg))));
+            }
 
-    log::info!("Mail handling completed");
+            log::info!("Confirmed subscription to {}", message.topic_arn);
+        }
+        vaulty::ses::NOTIFICATION => {
+            ses_received(message, db, sessions, limits, rate_limiter, config).await?
+        }
+        kind => log::info!("Ignoring SNS {} for {}", kind, message.topic_arn),
+    }
 
     Ok(warp::reply())
 }
+
+/// Handles an SES notification for a received email
+///
+/// SNS retries delivery if this fails, so only temporary errors are
+/// returned. Rejected emails cannot be bounced back to the sender, and are
+/// only logged.
+async fn ses_received(
+    message: vaulty::ses::SnsMessage,
+    db: sqlx::PgPool,
+    sessions: Arc<dyn SessionStore>,
+    limits: Arc<UploadLimits>,
+    rate_limiter: Arc<RateLimiter>,
+    config: Arc<Config>,
+) -> Result<(), Rejection> {
+    let notification = match message.notification() {
+        Ok(n) => n,
+        Err(e) => {
+            log::error!("Invalid SES notification {}: {}", message.id, e);
+            return Err(warp::reject::not_found());
+        }
+    };
+
+    if notification.notification_type != vaulty::ses::RECEIVED {
+        log::info!(
+            "Ignoring SES {} notification {}",
+            notification.notification_type,
+            message.id
+        );
+        return Ok(());
+    }
+
+    // Bounces are ignored, as they are by the filter
+    if notification.mail.source.is_empty() {
+        log::warn!("Received a bounced email notification... ignoring");
+        return Ok(());
+    }
+
+    let content = match notification.content() {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("No email found in SES notification {}: {}", message.id, e);
+            return Err(warp::reject::not_found());
+        }
+    };
+
+    let raw = match content {
+        vaulty::ses::Content::Inline(raw) => raw,
+        vaulty::ses::Content::S3 { bucket, key } => {
+            let result = ses_fetch(&message, &bucket, &key, &config).await;
+
+            result.map_err(|e| {
+                let msg = format!("Failed to fetch email s3://{}/{}: {}", bucket, key, e);
+                log::error!("{}", msg);
+                warp::reject::custom(Error(vaulty::Error::Temporary(msg)))
+            })?
+        }
+    };
+
+    let email = match email::Email::from_mime(&raw) {
+        Ok(email) => email
+            .with_sender(notification.mail.source.clone())
+            .with_recipients(notification.receipt.recipients.clone()),
+        Err(e) => {
+            log::error!("Failed to parse email from SES {}: {}", message.id, e);
+            return Ok(());
+        }
+    };
+
+    let uuid = email.uuid;
+    let result = postfix::deliver(
+        email,
+        Some(&raw),
+        db,
+        sessions,
+        limits,
+        rate_limiter,
+        config,
+    )
+    .await;
+
+    match result {
+        Ok(_) => log::info!("Email {} received from SES", uuid),
+        Err(vaulty::Error::Temporary(msg)) => {
+            log::warn!("Failed to handle email {} from SES: {}", uuid, msg);
+            return Err(reject_email(uuid, vaulty::Error::Temporary(msg)));
+        }
+        Err(e) => log::warn!("Rejecting email {} received from SES: {}", uuid, e),
+    }
+
+    Ok(())
+}
+
+/// Fetch an email that SES stored in S3
+///
+/// The bucket must be in the same region as the SNS topic, which SES
+/// requires anyway.
+async fn ses_fetch(
+    message: &vaulty::ses::SnsMessage,
+    bucket: &str,
+    key: &str,
+    config: &Config,
+) -> Result<Vec<u8>, vaulty::Error> {
+    let (access_key_id, secret_access_key) =
+        match (&config.ses_access_key_id, &config.ses_secret_access_key) {
+            (Some(id), Some(secret)) => (id.clone(), secret.clone()),
+            _ => {
+                return Err(vaulty::Error::Generic(
+                    "ses_access_key_id and ses_secret_access_key are not set".to_string(),
+                ))
+            }
+        };
+
+    let region = message
+        .region()
+        .ok_or_else(|| vaulty::Error::Generic(format!("Invalid topic {}", message.topic_arn)))?;
+
+    let client = storage::s3::client::S3Client::new(storage::s3::Config {
+        endpoint: None,
+        region: region.to_string(),
+        bucket: bucket.to_string(),
+        access_key_id,
+        secret_access_key,
+    })
+    .with_retry_policy(storage::RetryPolicy::from_config(config));
+
+    Ok(client.get_object(key).await?)
+}
@@ -1,6 +1,12 @@
 use std::convert::Infallible;
 
-use warp::{http::StatusCode, Rejection, Reply};
+use warp::{
+    http::{header, HeaderValue, StatusCode},
+    Rejection, Reply,
+};
+
+/// Sent along with 401 responses, so that clients know to send credentials
+const AUTH_CHALLENGE: &str = "Basic realm=\"vaulty\"";
 
 /// Wrap the shared Vaulty error type so Reject can be impl'd
 #[derive(Debug)]
@@ -8,51 +14,81 @@ pub struct Error(pub vaulty::Error);
 
 impl warp::reject::Reject for Error {}
 
+/// An error for a specific email, so that the UUID the server assigned to it
+/// is sent back along with the error
+#[derive(Debug)]
+pub struct EmailError(pub uuid::Uuid, pub vaulty::Error);
+
+impl warp::reject::Reject for EmailError {}
+
+/// UUID of the email a rejection was for, attached to the response
+#[derive(Clone, Copy, Debug)]
+pub struct EmailId(pub uuid::Uuid);
+
+fn status_of(error: &vaulty::Error) -> StatusCode {
+    match error {
+        vaulty::Error::Generic(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        vaulty::Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        // A DB that is down is retried later, like a storage token being
+        // refreshed
+        vaulty::Error::Database(e) => match e {
+            vaulty::db::Error::NotFound => StatusCode::NOT_FOUND,
+            vaulty::db::Error::QueryFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            vaulty::db::Error::ConnectionFailed(_) => StatusCode::SERVICE_UNAVAILABLE,
+            vaulty::db::Error::Constraint(_) => StatusCode::CONFLICT,
+        },
+        vaulty::Error::Storage(_) => StatusCode::BAD_GATEWAY,
+        vaulty::Error::QuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+        vaulty::Error::TokenExpired => StatusCode::UNPROCESSABLE_ENTITY,
+        vaulty::Error::InvalidRecipient => StatusCode::UNPROCESSABLE_ENTITY,
+        vaulty::Error::InvalidSender(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        vaulty::Error::SenderNotWhitelisted { .. } => StatusCode::FORBIDDEN,
+        vaulty::Error::AutoGenerated { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        vaulty::Error::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+        vaulty::Error::Unauthorized => StatusCode::UNAUTHORIZED,
+        vaulty::Error::Forbidden(_) => StatusCode::FORBIDDEN,
+        vaulty::Error::EmailNotFound(_) => StatusCode::NOT_FOUND,
+        vaulty::Error::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        vaulty::Error::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        vaulty::Error::LengthRequired => StatusCode::LENGTH_REQUIRED,
+        vaulty::Error::Temporary(_) => StatusCode::SERVICE_UNAVAILABLE,
+        vaulty::Error::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+        // All other error variants are not expected here
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 /// Maps internal server errors to HTTP return codes.
 ///
-/// All HTTP responses with error code `UNPROCESSABLE_ENTITY` are visible to
-/// users of Vaulty. These messages are displayed to the user verbatim as part
-/// of an email reply.
+/// Rejections of an email get distinct codes, so that `vaulty-filter` can
+/// tell Postfix whether to bounce or defer it:
+///
+/// * `UNPROCESSABLE_ENTITY`: the email is invalid (e.g., unknown recipient)
+/// * `FORBIDDEN`: the sender is not allowed to mail this address
+/// * `TOO_MANY_REQUESTS`: the address is over its quota or rate limit
+/// * `BAD_GATEWAY`: the storage backend failed, so the email is retried later
+///
+/// The messages of these responses are visible to users of Vaulty, and are
+/// displayed to the user verbatim as part of an email reply.
+///
+/// The body is a `vaulty::api::ErrorResponse`. The error is also attached to
+/// the response, so that its message can be localized, and the email it was
+/// for added. See `filters::localize`.
 pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     let status_code;
     let error;
+    let mut email_id = None;
 
     if err.is_not_found() {
         status_code = StatusCode::NOT_FOUND;
         error = vaulty::Error::NotFound;
     } else if let Some(e) = err.find::<Error>() {
         error = e.0.clone();
-
-        match error {
-            vaulty::Error::Generic(_) => {
-                status_code = StatusCode::INTERNAL_SERVER_ERROR;
-            }
-            vaulty::Error::Database(_) => {
-                status_code = StatusCode::INTERNAL_SERVER_ERROR;
-            }
-            vaulty::Error::Storage(_) => {
-                status_code = StatusCode::INTERNAL_SERVER_ERROR;
-            }
-            vaulty::Error::QuotaExceeded(_) => {
-                status_code = StatusCode::UNPROCESSABLE_ENTITY;
-            }
-            vaulty::Error::TokenExpired => {
-                status_code = StatusCode::UNPROCESSABLE_ENTITY;
-            }
-            vaulty::Error::InvalidRecipient => {
-                status_code = StatusCode::UNPROCESSABLE_ENTITY;
-            }
-            vaulty::Error::SenderNotWhitelisted { .. } => {
-                status_code = StatusCode::UNPROCESSABLE_ENTITY;
-            }
-            vaulty::Error::Unauthorized => {
-                status_code = StatusCode::UNAUTHORIZED;
-            }
-            _ => {
-                // All other error variants are not expected here
-                status_code = StatusCode::INTERNAL_SERVER_ERROR;
-            }
-        }
+        status_code = status_of(&error);
+    } else if let Some(e) = err.find::<EmailError>() {
+        error = e.1.clone();
+        email_id = Some(EmailId(e.0));
+        status_code = status_of(&error);
     } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
         status_code = StatusCode::UNAUTHORIZED;
         error = vaulty::Error::MissingHeader(e.name().to_string());
@@ -61,16 +97,28 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
         error = vaulty::Error::Generic("Internal server error".to_string());
     }
 
-    let resp = vaulty::api::ServerResult {
-        success: false,
-        error: Some(error),
-        ..Default::default()
-    };
+    // The client only sees the message, so keep the full chain in the logs
+    if status_code.is_server_error() {
+        log::error!("{}", vaulty::report(&error));
+    }
+
+    let resp = vaulty::api::ErrorResponse::new(&error);
+
+    let mut resp = warp::reply::with_status(warp::reply::json(&resp), status_code).into_response();
+    resp.extensions_mut().insert(error);
+    if let Some(email_id) = email_id {
+        resp.extensions_mut().insert(email_id);
+    }
 
-    Ok(warp::reply::with_status(
-        warp::reply::json(&resp),
-        status_code,
-    ))
+    // Clients such as SNS only send credentials once they are challenged
+    if status_code == StatusCode::UNAUTHORIZED {
+        resp.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static(AUTH_CHALLENGE),
+        );
+    }
+
+    Ok(resp)
 }
 
 impl From<vaulty::Error> for Error {
@@ -79,8 +127,63 @@ impl From<vaulty::Error> for Error {
     }
 }
 
+impl From<vaulty::db::Error> for Error {
+    fn from(err: vaulty::db::Error) -> Self {
+        Self(err.into())
+    }
+}
+
 impl From<sqlx::Error> for Error {
     fn from(err: sqlx::Error) -> Self {
         Self(err.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn rejection_status(err: vaulty::Error) -> StatusCode {
+        let resp = handle_rejection(warp::reject::custom(Error(err)))
+            .await
+            .unwrap();
+        resp.into_response().status()
+    }
+
+    #[tokio::test]
+    async fn rejection_statuses() {
+        assert_eq!(
+            rejection_status(vaulty::Error::InvalidRecipient).await,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            rejection_status(vaulty::Error::QuotaExceeded("Over quota".to_string())).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            rejection_status(vaulty::Error::SenderNotWhitelisted {
+                recipient: "test@vaulty.net".to_string(),
+            })
+            .await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            rejection_status(vaulty::Error::Storage(
+                vaulty::storage::Error::RequestTimeout
+            ))
+            .await,
+            StatusCode::BAD_GATEWAY
+        );
+    }
+
+    #[tokio::test]
+    async fn unauthorized_challenge() {
+        let resp = handle_rejection(warp::reject::custom(Error(vaulty::Error::Unauthorized)))
+            .await
+            .unwrap()
+            .into_response();
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()[header::WWW_AUTHENTICATE], AUTH_CHALLENGE);
+    }
+}
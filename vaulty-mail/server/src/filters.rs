@@ -1,25 +1,56 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
-use super::error::Error;
+use chrono::Local;
+use hyper::body::HttpBody;
+use warp::{
+    filters::{path::FullPath, BoxedFilter},
+    http::{header, HeaderMap, HeaderValue, Method},
+    reply::{Reply, Response},
+    Filter,
+};
 
-use vaulty::config::Config;
+use super::auth::Authenticator;
+use super::error::{EmailId, Error};
 
-use warp::{filters::BoxedFilter, Filter};
+use vaulty::config::Config;
 
-/// Simple filter for HTTP Basic Authentication
+/// Filter for HTTP Basic Authentication
 ///
-/// User and pass checked against those set in config file
-pub fn basic_auth(config: Arc<Config>) -> BoxedFilter<()> {
+/// See `Authenticator` for how credentials are checked.
+pub fn basic_auth(auth: Arc<Authenticator>) -> BoxedFilter<()> {
     warp::header::<String>("Authorization")
-        .and(warp::any().map(move || config.clone()))
-        .and_then(|auth: String, config: Arc<Config>| async move {
-            let user = &config.auth_user;
-            let pass = &config.auth_pass;
+        .and(warp::any().map(move || auth.clone()))
+        .and_then(|header: String, auth: Arc<Authenticator>| async move {
+            match auth.authenticate(&header).await {
+                Ok(user) => {
+                    log::debug!("Authenticated API user {}", user);
+                    Ok(())
+                }
+                Err(e) => Err(warp::reject::custom(Error(e))),
+            }
+        })
+        .untuple_one()
+        .boxed()
+}
 
-            let full = format!("{}:{}", user, pass);
+/// Global maintenance switch
+///
+/// Initialized from config on startup and toggled via the admin API.
+pub static MAINTENANCE_MODE: AtomicBool = AtomicBool::new(false);
 
-            if !auth.contains(&base64::encode(&full)) {
-                let err = Error(vaulty::Error::Unauthorized);
+/// Rejects requests while the server is in maintenance mode
+///
+/// Only applied to ingest routes so that admin and monitoring endpoints keep
+/// working. Clients get a 503 and are expected to retry later.
+pub fn maintenance() -> BoxedFilter<()> {
+    warp::any()
+        .and_then(|| async move {
+            if MAINTENANCE_MODE.load(Ordering::SeqCst) {
+                let err = Error(vaulty::Error::Maintenance);
                 Err(warp::reject::custom(err))
             } else {
                 Ok(())
@@ -28,3 +59,278 @@ pub fn basic_auth(config: Arc<Config>) -> BoxedFilter<()> {
         .untuple_one()
         .boxed()
 }
+
+/// Rejects requests whose body is larger than `max_size` bytes with a 413,
+/// before the body is read
+///
+/// Unlike `warp::body::content_length_limit`, the client is told what the
+/// limit is. Requests with no Content-Length (e.g., chunked) are rejected
+/// with a 411, as their size is only known once they are read.
+pub fn payload_limit(max_size: u64) -> BoxedFilter<()> {
+    warp::header::optional::<u64>("content-length")
+        .and_then(move |size: Option<u64>| async move {
+            match size {
+                Some(size) if size > max_size => {
+                    let err = Error(vaulty::Error::PayloadTooLarge(format!(
+                        "Request body of {} bytes is larger than the limit of {} bytes.",
+                        size, max_size
+                    )));
+                    Err(warp::reject::custom(err))
+                }
+                Some(_) => Ok(()),
+                None => Err(warp::reject::custom(Error(vaulty::Error::LengthRequired))),
+            }
+        })
+        .untuple_one()
+        .boxed()
+}
+
+/// Log target used for HTTP access logs
+///
+/// This is kept separate from application logs so that it can be filtered
+/// independently (e.g., `RUST_LOG=vaulty::access=info`).
+pub const ACCESS_LOG_TARGET: &str = "vaulty::access";
+
+/// Supported access log formats
+#[derive(Clone, Copy, Debug)]
+pub enum AccessLogFormat {
+    /// Apache/NCSA combined log format, with bytes in, duration (us), and
+    /// email UUID appended
+    Combined,
+    Json,
+}
+
+impl AccessLogFormat {
+    /// Access logging is disabled unless a format is set in config
+    pub fn from_config(config: &Config) -> Option<Self> {
+        match config.access_log.as_deref() {
+            Some("combined") => Some(Self::Combined),
+            Some("json") => Some(Self::Json),
+            Some(other) => {
+                log::error!("Unknown access log format: {}", other);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// Localizes error messages based on the `Accept-Language` header
+///
+/// The UUID of the email the request was for is added to the error as well.
+/// This is the UUID the server assigned to the email if it was rejected with
+/// an `EmailError`, or else the one sent in the `Vaulty-Email-ID` header. The wrapped filter must already be
+/// recovered, as the error is read from the response built by
+/// `error::handle_rejection`.
+pub fn localize<F, T>(filter: F) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (T,), Error = Infallible> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    warp::header::headers_cloned()
+        .and(filter)
+        .map(|headers: HeaderMap, reply: T| {
+            let resp = reply.into_response();
+
+            let error = match resp.extensions().get::<vaulty::Error>() {
+                Some(error) => error.clone(),
+                None => return resp,
+            };
+
+            let header_value = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+            let accept_language = header_value(header::ACCEPT_LANGUAGE.as_str());
+            let email_id = header_value(vaulty::constants::VAULTY_EMAIL_ID);
+
+            // The UUID the server assigned takes precedence over the one
+            // sent by the client, which is ignored if it is not valid
+            let email_id = resp
+                .extensions()
+                .get::<EmailId>()
+                .map(|id| id.0)
+                .or_else(|| email_id.and_then(|id| uuid::Uuid::parse_str(id).ok()));
+
+            let catalog = vaulty::i18n::negotiate(accept_language);
+            let result = vaulty::api::ErrorResponse::new(&error)
+                .with_message(vaulty::i18n::message(catalog, &error))
+                .with_email_uuid(email_id.map(|id| id.to_string()));
+
+            let mut localized =
+                warp::reply::with_status(warp::reply::json(&result), resp.status()).into_response();
+            localized.headers_mut().insert(
+                header::CONTENT_LANGUAGE,
+                HeaderValue::from_static(catalog.language()),
+            );
+            if let Some(challenge) = resp.headers().get(header::WWW_AUTHENTICATE) {
+                localized
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, challenge.clone());
+            }
+
+            localized
+        })
+}
+
+/// Wraps a filter with HTTP access logging.
+///
+/// Logs method, path, status, bytes in/out, duration, remote IP, and the
+/// email UUID (if sent in the `Vaulty-Email-ID` header) for every request.
+/// The wrapped filter must already be recovered so that error responses are
+/// logged too.
+pub fn access_log<F, T>(
+    filter: F,
+    format: AccessLogFormat,
+) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (T,), Error = Infallible> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    warp::any()
+        .map(Instant::now)
+        .and(warp::addr::remote())
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(filter)
+        .map(
+            move |start: Instant,
+                  remote: Option<SocketAddr>,
+                  method: Method,
+                  path: FullPath,
+                  headers: HeaderMap,
+                  reply: T| {
+                let resp = reply.into_response();
+
+                let header_value = |name: &str| {
+                    headers
+                        .get(name)
+                        .and_then(|v| v.to_str().ok())
+                        .map(String::from)
+                };
+
+                let remote_ip = remote
+                    .map(|r| r.ip().to_string())
+                    .unwrap_or_else(|| "-".to_string());
+                let bytes_in = header_value(header::CONTENT_LENGTH.as_str())
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or(0);
+                let bytes_out = resp.body().size_hint().exact();
+                let duration = start.elapsed().as_micros();
+                let email_id = header_value(vaulty::constants::VAULTY_EMAIL_ID);
+                let status = resp.status().as_u16();
+
+                match format {
+                    AccessLogFormat::Combined => {
+                        log::info!(
+                            target: ACCESS_LOG_TARGET,
+                            "{} - - [{}] \"{} {} HTTP/1.1\" {} {} \"{}\" \"{}\" {} {} {}",
+                            remote_ip,
+                            Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+                            method,
+                            path.as_str(),
+                            status,
+                            bytes_out
+                                .map(|b| b.to_string())
+                                .unwrap_or_else(|| "-".to_string()),
+                            header_value(header::REFERER.as_str())
+                                .unwrap_or_else(|| "-".to_string()),
+                            header_value(header::USER_AGENT.as_str())
+                                .unwrap_or_else(|| "-".to_string()),
+                            bytes_in,
+                            duration,
+                            email_id.unwrap_or_else(|| "-".to_string()),
+                        );
+                    }
+                    AccessLogFormat::Json => {
+                        let entry = serde_json::json!({
+                            "time": Local::now().to_rfc3339(),
+                            "remote_ip": remote_ip,
+                            "method": method.as_str(),
+                            "path": path.as_str(),
+                            "status": status,
+                            "bytes_in": bytes_in,
+                            "bytes_out": bytes_out,
+                            "duration_us": duration as u64,
+                            "email_id": email_id,
+                            "user_agent": header_value(header::USER_AGENT.as_str()),
+                        });
+
+                        log::info!(target: ACCESS_LOG_TARGET, "{}", entry);
+                    }
+                }
+
+                resp
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{handle_rejection, EmailError};
+
+    #[tokio::test]
+    async fn payload_limit_needs_length() {
+        let limit = payload_limit(10);
+
+        let ok = warp::test::request()
+            .header("content-length", "10")
+            .filter(&limit)
+            .await;
+        assert!(ok.is_ok());
+
+        let too_large = warp::test::request()
+            .header("content-length", "11")
+            .filter(&limit)
+            .await;
+        assert!(too_large.is_err());
+
+        let chunked = warp::test::request()
+            .header("transfer-encoding", "chunked")
+            .filter(&limit)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            chunked.find::<Error>(),
+            Some(Error(vaulty::Error::LengthRequired))
+        ));
+    }
+
+    #[tokio::test]
+    async fn localize_email_id() {
+        const ASSIGNED: &str = "6d2b1c3e-9a4f-4e0b-8c1d-2f3a4b5c6d7e";
+        let sent = uuid::Uuid::new_v4();
+
+        async fn email_uuid(err: fn() -> warp::Rejection, header: &str) -> Option<String> {
+            let filter = localize(
+                warp::any()
+                    .and_then(move || async move { Err::<String, _>(err()) })
+                    .recover(handle_rejection),
+            );
+            let resp = warp::test::request()
+                .header(vaulty::constants::VAULTY_EMAIL_ID, header)
+                .reply(&filter)
+                .await;
+
+            serde_json::from_slice::<vaulty::api::ErrorResponse>(resp.body())
+                .unwrap()
+                .email_uuid
+        }
+
+        // The UUID assigned by the server is preferred
+        let email_err = || {
+            let id = uuid::Uuid::parse_str(ASSIGNED).unwrap();
+            warp::reject::custom(EmailError(id, vaulty::Error::InvalidRecipient))
+        };
+        assert_eq!(
+            email_uuid(email_err, &sent.to_string()).await,
+            Some(ASSIGNED.to_string())
+        );
+
+        let err = || warp::reject::custom(Error(vaulty::Error::InvalidRecipient));
+        assert_eq!(
+            email_uuid(err, &sent.to_string()).await,
+            Some(sent.to_string())
+        );
+        assert_eq!(email_uuid(err, "../../etc/passwd").await, None);
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use vaulty::config::Config;
+
+/// Optional pipeline stages that can be switched off at runtime
+///
+/// Stages are enabled by default. A stage that misbehaves in production can
+/// be disabled instantly via the admin API, without a restart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Skip uploads of unchanged attachments
+    Dedup,
+    /// Abuse sampling of rejected emails
+    Sampling,
+    /// Custom metadata on uploaded objects
+    Metadata,
+    /// Storage access token refresh
+    TokenRefresh,
+    /// Success and rejection webhooks
+    Webhooks,
+    /// Success and rejection replies to senders
+    Replies,
+    /// Link archiving for HTML emails without attachments
+    Links,
+    /// Batched Dropbox commits of small attachments
+    BatchCommits,
+    /// PDF rendering of HTML bodies
+    BodyPdf,
+}
+
+impl Stage {
+    pub const ALL: &'static [Stage] = &[
+        Stage::Dedup,
+        Stage::Sampling,
+        Stage::Metadata,
+        Stage::TokenRefresh,
+        Stage::Webhooks,
+        Stage::Replies,
+        Stage::Links,
+        Stage::BatchCommits,
+        Stage::BodyPdf,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Dedup => "dedup",
+            Stage::Sampling => "sampling",
+            Stage::Metadata => "metadata",
+            Stage::TokenRefresh => "token_refresh",
+            Stage::Webhooks => "webhooks",
+            Stage::Replies => "replies",
+            Stage::Links => "links",
+            Stage::BatchCommits => "batch_commits",
+            Stage::BodyPdf => "body_pdf",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|s| s.name() == name)
+    }
+}
+
+/// One disabled switch per stage, indexed by `Stage as usize`
+static DISABLED: [AtomicBool; 9] = [
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+];
+
+pub fn is_enabled(stage: Stage) -> bool {
+    !DISABLED[stage as usize].load(Ordering::SeqCst)
+}
+
+pub fn set_enabled(stage: Stage, enabled: bool) {
+    let was_enabled = !DISABLED[stage as usize].swap(!enabled, Ordering::SeqCst);
+
+    if was_enabled != enabled {
+        log::warn!(
+            "Stage {} {}",
+            stage.name(),
+            if enabled { "enabled" } else { "disabled" }
+        );
+    }
+}
+
+/// Current state of all stages
+pub fn snapshot() -> BTreeMap<Stage, bool> {
+    Stage::ALL.iter().map(|&s| (s, is_enabled(s))).collect()
+}
+
+/// Disable the stages listed in config
+pub fn init(config: &Config) {
+    for name in &config.disabled_stages {
+        match Stage::from_name(name) {
+            Some(stage) => set_enabled(stage, false),
+            None => log::error!("Unknown stage: {}", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stage_names() {
+        for &stage in Stage::ALL {
+            assert_eq!(Stage::from_name(stage.name()), Some(stage));
+            assert_eq!(
+                serde_json::to_value(stage).unwrap(),
+                serde_json::json!(stage.name())
+            );
+        }
+
+        assert_eq!(Stage::from_name("scanning"), None);
+    }
+}
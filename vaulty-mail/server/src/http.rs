@@ -1,49 +1,310 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 
 use warp::{self, Filter};
 
+use super::auth::Authenticator;
+use super::canary::Canary;
+use super::controllers;
 use super::error;
+use super::filters;
+use super::flags;
+use super::limiter::UploadLimits;
+use super::listen::{self, AddressChanges};
+use super::ratelimit::{self, RateLimiter};
 use super::routes;
+use super::session;
+use super::smtp;
+use super::stats::StatsCache;
 
 use vaulty::config::Config;
 
 pub async fn get_db_pool(config: &Config) -> sqlx::PgPool {
-    let db_host = &config.db_host;
-    let db_name = &config.db_name;
-    let db_user = &config.db_user;
-
-    let db_path = if config.db_password.is_some() {
-        let db_password = config.db_password.as_ref().unwrap();
-        format!(
-            "postgres://{}:{}@{}/{}",
-            db_user, db_password, db_host, db_name
-        )
-    } else {
-        format!("postgres://{}@{}/{}", db_user, db_host, db_name)
-    };
+    check_database_url(config);
+
+    // Idle connections and old connections are kept if set to 0
+    let seconds = |secs: u64| Some(Duration::from_secs(secs)).filter(|_| secs > 0);
+
+    sqlx::PgPool::builder()
+        .max_size(config.db_pool_max_size)
+        .min_size(config.db_pool_min_size)
+        .connect_timeout(Duration::from_secs(config.db_connect_timeout))
+        .idle_timeout(seconds(config.db_idle_timeout))
+        .max_lifetime(seconds(config.db_max_lifetime))
+        .build(&config.database_url())
+        .await
+        .unwrap()
+}
+
+/// Check that the DB URL points to Postgres
+///
+/// The schema created by vaulty-web is Postgres-only (e.g., whitelists are
+/// arrays), and queries rely on Postgres features (e.g., `ANY`, `RETURNING`,
+/// and LISTEN/NOTIFY), so other DBs such as SQLite or MySQL are rejected up
+/// front instead of failing on the first query.
+fn check_database_url(config: &Config) {
+    let url = config.database_url();
+    let scheme = url.split("://").next().unwrap_or_default();
 
-    sqlx::PgPool::new(&db_path).await.unwrap()
+    if scheme != "postgres" && scheme != "postgresql" {
+        log::error!(
+            "Unsupported DB URL scheme \"{}\": only Postgres is supported",
+            scheme
+        );
+        std::process::exit(1);
+    }
+}
+
+/// Check that the config credential is not the well-known default, which
+/// would let anyone call the API
+fn check_auth_pass(config: &Config) {
+    if config.auth_pass == vaulty::config::DEFAULT_VAULTY_PASS {
+        log::error!("auth_pass is the default password; set a strong one in the config file");
+        std::process::exit(1);
+    }
+}
+
+/// Check the key storage tokens are encrypted with, if any
+fn check_storage_token_key(config: &Config) {
+    if let Some(key) = &config.storage_token_key {
+        vaulty::crypto::Key::from_hex(key).expect("Invalid storage_token_key");
+    }
+}
+
+/// Encrypt the storage tokens that are still stored in plaintext, and exit
+pub async fn encrypt_storage_tokens(arg: Config) {
+    check_storage_token_key(&arg);
+
+    let mut pool = get_db_pool(&arg).await;
+    let mut db_client = controllers::new_db_client(&mut pool, &arg);
+
+    match db_client.encrypt_storage_tokens().await {
+        Ok(n) => log::info!("Encrypted {} storage tokens", n),
+        Err(e) => {
+            log::error!("Failed to encrypt storage tokens: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Apply pending DB migrations, and exit
+pub async fn migrate(arg: Config) {
+    let mut pool = get_db_pool(&arg).await;
+
+    match vaulty::db::migrate(&mut pool).await {
+        Ok(applied) if applied.is_empty() => log::info!("DB schema is up to date"),
+        Ok(applied) => log::info!("Applied DB migrations: {}", applied.join(", ")),
+        Err(e) => {
+            log::error!("Failed to migrate DB: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 pub async fn run(arg: Config) {
-    let pool = get_db_pool(&arg).await;
+    check_auth_pass(&arg);
+    check_storage_token_key(&arg);
+
+    let mut pool = get_db_pool(&arg).await;
     log::info!("Connected to Postgres DB: {}/{}", arg.db_host, arg.db_name);
 
+    match vaulty::db::migrate::pending(&mut pool).await {
+        Ok(pending) if !pending.is_empty() => log::warn!(
+            "DB migrations are pending ({}); run vaulty_server --migrate",
+            pending.join(", ")
+        ),
+        Ok(_) => (),
+        Err(e) => log::error!("DB schema is not supported: {}", e),
+    }
+
+    if arg.maintenance {
+        log::warn!("Starting in maintenance mode; ingest is paused");
+        filters::MAINTENANCE_MODE.store(true, Ordering::SeqCst);
+    }
+
+    flags::init(&arg);
+    vaulty::faults::init(&arg);
+
     // Use Arc to share config across threads on server
     let config = Arc::new(arg);
 
-    let mailgun = routes::mailgun(config.clone());
-    let postfix = routes::postfix(pool.clone(), config.clone());
-    let monitor = routes::monitor(pool.clone(), config.clone());
+    let sessions = session::from_config(&config).await;
+
+    // Emails waiting on their attachments pick up changes to their address
+    let sessions: Arc<dyn session::SessionStore> = if config.listen_address_changes {
+        let changes = Arc::new(AddressChanges::new(chrono::Duration::seconds(
+            config.cache_ttl,
+        )));
+        tokio::spawn(listen::run(config.clone(), changes.clone()));
+
+        Arc::new(session::FreshStore::new(
+            sessions,
+            changes,
+            pool.clone(),
+            config.clone(),
+        ))
+    } else {
+        sessions
+    };
+    let limits = Arc::new(UploadLimits::from_config(&config));
+    let rate_limiter = Arc::new(RateLimiter::from_config(&config));
+
+    // Attachments that were acknowledged before the server stopped are
+    // stored now
+    let spooled = limits.spool().saved();
+    if !spooled.is_empty() {
+        log::info!("Storing {} spooled attachments", spooled.len());
+    }
+    for meta in spooled {
+        tokio::spawn(controllers::postfix::store_spooled(
+            meta,
+            pool.clone(),
+            sessions.clone(),
+            limits.clone(),
+            config.clone(),
+        ));
+    }
+
+    tokio::spawn(controllers::expire_cache(
+        pool.clone(),
+        sessions.clone(),
+        chrono::Duration::seconds(config.cache_ttl),
+    ));
+
+    tokio::spawn(controllers::renew_quotas(
+        pool.clone(),
+        chrono::Duration::days(config.quota_period_days),
+    ));
+
+    tokio::spawn(ratelimit::prune(rate_limiter.clone()));
+
+    tokio::spawn(controllers::detect_anomalies(pool.clone(), config.clone()));
+
+    let canary = Canary::from_config(&config).map(Arc::new);
+
+    if let Some(canary) = &canary {
+        tokio::spawn(canary.clone().run(pool.clone(), config.clone()));
+    }
+
+    // Mail may also be received directly over SMTP, instead of through
+    // Postfix and the filter, or submitted by clients that log in as an
+    // address
+    if !config.smtp_listen_ports.is_empty() || !config.smtp_submission_ports.is_empty() {
+        if !config.smtp_listen_ports.is_empty() && config.smtp_domains.is_empty() {
+            log::warn!("No smtp_domains configured; the SMTP server rejects all recipients");
+        }
+
+        let tls = match (&config.smtp_tls_cert, &config.smtp_tls_key) {
+            (Some(cert), Some(key)) => {
+                Some(smtp::tls_acceptor(cert, key).expect("Invalid smtp_tls_cert or smtp_tls_key"))
+            }
+            _ => None,
+        };
+
+        let pipeline = smtp::Pipeline {
+            db: pool.clone(),
+            sessions: sessions.clone(),
+            limits: limits.clone(),
+            rate_limiter: rate_limiter.clone(),
+            config: config.clone(),
+            tls,
+        };
+
+        for port in &config.smtp_listen_ports {
+            tokio::spawn(smtp::run(*port, smtp::Mode::Relay, pipeline.clone()));
+        }
+
+        // Passwords are never accepted in the clear
+        if pipeline.tls.is_none() && !config.smtp_submission_ports.is_empty() {
+            log::error!(
+                "No smtp_tls_cert and smtp_tls_key configured; SMTP submission is disabled"
+            );
+        } else {
+            for port in &config.smtp_submission_ports {
+                tokio::spawn(smtp::run(*port, smtp::Mode::Submission, pipeline.clone()));
+            }
+        }
+    }
+
+    if let Some(seed) = &config.manifest_signing_key {
+        let key =
+            vaulty::manifest::SigningKey::from_hex(seed).expect("Invalid manifest_signing_key");
+        log::info!("Signing manifests with public key {}", key.public_key());
+    }
+
+    let auth = Arc::new(Authenticator::new(pool.clone(), config.clone()));
+
+    let mailgun = routes::mailgun(
+        pool.clone(),
+        sessions.clone(),
+        limits.clone(),
+        rate_limiter.clone(),
+        config.clone(),
+    );
+    let ses = routes::ses(
+        pool.clone(),
+        sessions.clone(),
+        limits.clone(),
+        rate_limiter.clone(),
+        auth.clone(),
+        config.clone(),
+    );
+    let postfix = routes::postfix(
+        pool.clone(),
+        sessions.clone(),
+        limits.clone(),
+        rate_limiter.clone(),
+        auth.clone(),
+        config.clone(),
+    );
+    let monitor = routes::monitor(
+        pool.clone(),
+        sessions.clone(),
+        limits.clone(),
+        canary.clone(),
+        config.clone(),
+    );
+    let admin = routes::admin(pool.clone(), auth.clone(), config.clone());
+    let stats_cache = Arc::new(StatsCache::from_config(&config));
+    let api = routes::api(
+        pool.clone(),
+        sessions.clone(),
+        limits.clone(),
+        rate_limiter,
+        auth.clone(),
+        stats_cache,
+        config.clone(),
+    );
     let index = routes::index();
 
     let get = warp::get().and(index.or(monitor));
-    let post = warp::post().and(mailgun.or(postfix));
+    let post = warp::post().and(mailgun.or(postfix).or(ses));
 
-    let router = get.or(post).recover(error::handle_rejection);
+    // Admin and API routes handle their own methods
+    let router = get
+        .or(post)
+        .or(admin)
+        .or(api)
+        .recover(error::handle_rejection);
+    let router = filters::localize(router);
 
-    let port = config.port;
+    let addr = SocketAddr::new(
+        config
+            .listen_address
+            .parse::<IpAddr>()
+            .expect("Invalid listen_address"),
+        config.port,
+    );
 
-    log::info!("Starting HTTP server at 0.0.0.0:{}...", port);
-    warp::serve(router).run(([0, 0, 0, 0], port)).await;
+    log::info!("Starting HTTP server at {}...", addr);
+
+    if let Some(format) = filters::AccessLogFormat::from_config(&config) {
+        log::info!("Access logging enabled ({:?})", format);
+        let router = filters::access_log(router, format);
+        warp::serve(router).run(addr).await;
+    } else {
+        warp::serve(router).run(addr).await;
+    }
 }
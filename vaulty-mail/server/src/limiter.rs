@@ -0,0 +1,370 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Serialize;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use vaulty::config::Config;
+use vaulty::storage::{self, Backend};
+
+use super::retries::{Claim, InFlight};
+use super::sizes::SizeMismatches;
+use super::spool::Spool;
+
+/// Number of uploads to observe before slow uploads are treated as a sign of
+/// congestion
+const WARMUP_SAMPLES: u32 = 5;
+
+/// An upload is slow if its latency per MB is this many times the average
+const SLOW_FACTOR: f64 = 2.0;
+
+/// Weight of the latest upload in the average latency
+const LATENCY_WEIGHT: f64 = 0.1;
+
+/// Concurrent upload limits, one per storage backend
+pub struct UploadLimits {
+    dropbox: Limiter,
+    gdrive: Limiter,
+    s3: Limiter,
+
+    /// Attachments being uploaded, each of which is uploaded once at a time
+    in_flight: InFlight,
+
+    /// Senders of attachments that were not the size declared for them
+    mismatches: SizeMismatches,
+
+    /// Attachments acknowledged before they were stored
+    spool: Spool,
+
+    /// Addresses whose storage folder is being indexed
+    indexing: Mutex<HashSet<String>>,
+}
+
+impl UploadLimits {
+    pub fn from_config(config: &Config) -> Self {
+        let (min, max) = (config.upload_concurrency_min, config.upload_concurrency_max);
+
+        Self {
+            dropbox: Limiter::new(Backend::Dropbox, min, max),
+            gdrive: Limiter::new(Backend::Gdrive, min, max),
+            s3: Limiter::new(Backend::S3, min, max),
+            in_flight: Default::default(),
+            mismatches: SizeMismatches::from_config(config),
+            spool: Spool::from_config(config),
+            indexing: Default::default(),
+        }
+    }
+
+    pub fn get(&self, backend: &Backend) -> &Limiter {
+        match backend {
+            Backend::Dropbox => &self.dropbox,
+            Backend::Gdrive => &self.gdrive,
+            Backend::S3 => &self.s3,
+        }
+    }
+
+    /// Claim an attachment of an email while it is being handled
+    ///
+    /// Returns `None` if the attachment is already being handled.
+    pub fn claim(&self, mail_id: &str, index: u16) -> Option<Claim<'_>> {
+        self.in_flight.claim(mail_id, index)
+    }
+
+    /// Count an attachment from `sender` that was not the size declared for
+    /// it
+    ///
+    /// Returns true if the sender should be flagged for abuse review.
+    pub fn record_size_mismatch(&self, sender: &str) -> bool {
+        self.mismatches.record(sender)
+    }
+
+    pub fn spool(&self) -> &Spool {
+        &self.spool
+    }
+
+    /// Start indexing the storage folder of an address
+    ///
+    /// Returns false if it is already being indexed.
+    pub fn begin_index(&self, address: &str) -> bool {
+        self.indexing.lock().unwrap().insert(address.to_string())
+    }
+
+    pub fn end_index(&self, address: &str) {
+        self.indexing.lock().unwrap().remove(address);
+    }
+
+    /// Current limit of each backend, along with the number of uploads to
+    /// it that stalled
+    pub fn snapshot(&self) -> Vec<LimitState> {
+        vec![self.dropbox.state(), self.gdrive.state(), self.s3.state()]
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LimitState {
+    pub backend: String,
+    pub limit: usize,
+    pub min: usize,
+    pub max: usize,
+    /// Average upload latency, in seconds per MB
+    pub avg_latency: f64,
+    /// Uploads cancelled for making no progress since startup (see
+    /// `storage::Watchdog`)
+    pub stalled: u64,
+}
+
+struct State {
+    limit: usize,
+
+    /// Permits to drop as they are released, after the limit was lowered
+    debt: usize,
+
+    /// Bumped each time the limit is lowered. Uploads started before then
+    /// cannot lower it again, so a burst of 429s halves the limit once.
+    generation: u64,
+
+    /// Average upload latency, in seconds per MB
+    avg_latency: f64,
+    samples: u32,
+}
+
+/// Limits concurrent uploads to a single storage backend
+///
+/// The limit is tuned AIMD style, within the configured bounds: it grows by
+/// one after each upload that completes in the usual time, and is halved
+/// when the backend rate limits us or an upload is much slower than usual.
+pub struct Limiter {
+    backend: Backend,
+    min: usize,
+    max: usize,
+    semaphore: Semaphore,
+    state: Mutex<State>,
+}
+
+impl Limiter {
+    fn new(backend: Backend, min: usize, max: usize) -> Self {
+        let limit = (min + max) / 2;
+
+        Self {
+            backend,
+            min,
+            max,
+            semaphore: Semaphore::new(limit),
+            state: Mutex::new(State {
+                limit,
+                debt: 0,
+                generation: 0,
+                avg_latency: 0.0,
+                samples: 0,
+            }),
+        }
+    }
+
+    /// Wait until an upload can start
+    pub async fn acquire(&self) -> Permit<'_> {
+        let permit = self.semaphore.acquire().await;
+        let generation = self.state.lock().unwrap().generation;
+
+        Permit {
+            limiter: self,
+            permit: Some(permit),
+            generation,
+            start: Instant::now(),
+        }
+    }
+
+    fn state(&self) -> LimitState {
+        let state = self.state.lock().unwrap();
+
+        LimitState {
+            backend: self.backend.to_string(),
+            limit: state.limit,
+            min: self.min,
+            max: self.max,
+            avg_latency: state.avg_latency,
+            stalled: storage::stalled_uploads(&self.backend),
+        }
+    }
+
+    fn increase(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.limit >= self.max {
+            return;
+        }
+
+        state.limit += 1;
+
+        if state.debt > 0 {
+            state.debt -= 1;
+        } else {
+            self.semaphore.add_permits(1);
+        }
+    }
+
+    fn decrease(&self, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        if generation != state.generation || state.limit <= self.min {
+            return;
+        }
+
+        let limit = (state.limit / 2).max(self.min);
+
+        log::warn!(
+            "Lowering {} upload concurrency from {} to {}",
+            self.backend,
+            state.limit,
+            limit
+        );
+
+        state.debt += state.limit - limit;
+        state.limit = limit;
+        state.generation += 1;
+
+        // Drop any idle permits right away; the rest are dropped as
+        // in-flight uploads complete
+        while state.debt > 0 {
+            match self.semaphore.try_acquire() {
+                Ok(permit) => {
+                    permit.forget();
+                    state.debt -= 1;
+                }
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Record the latency of a successful upload, in seconds per MB
+    ///
+    /// Returns true if the upload was much slower than usual.
+    fn observe_latency(&self, latency: f64) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        let is_slow = state.samples >= WARMUP_SAMPLES && latency > SLOW_FACTOR * state.avg_latency;
+
+        state.avg_latency = if state.samples == 0 {
+            latency
+        } else {
+            LATENCY_WEIGHT * latency + (1.0 - LATENCY_WEIGHT) * state.avg_latency
+        };
+        state.samples = state.samples.saturating_add(1);
+
+        is_slow
+    }
+
+    fn release(&self, permit: SemaphorePermit<'_>) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.debt > 0 {
+            state.debt -= 1;
+            permit.forget();
+        }
+    }
+}
+
+/// A single in-flight upload
+pub struct Permit<'a> {
+    limiter: &'a Limiter,
+    permit: Option<SemaphorePermit<'a>>,
+    generation: u64,
+    start: Instant,
+}
+
+impl<'a> Permit<'a> {
+    /// Tune the limit based on the result of this upload
+    pub fn record(self, result: &Result<(), vaulty::Error>, size: usize) {
+        match result {
+            Ok(()) => {
+                // Normalize by size so that large attachments are not
+                // mistaken for a slow backend
+                let mb = (size as f64 / 1_000_000.0).max(1.0);
+                let latency = self.start.elapsed().as_secs_f64() / mb;
+
+                if self.limiter.observe_latency(latency) {
+                    self.limiter.decrease(self.generation);
+                } else {
+                    self.limiter.increase();
+                }
+            }
+            Err(vaulty::Error::Storage(storage::Error::RateLimited(_))) => {
+                self.limiter.decrease(self.generation);
+            }
+            Err(_) => {
+                // Other failures say nothing about backend load
+            }
+        }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            self.limiter.release(permit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limited() -> Result<(), vaulty::Error> {
+        Err(vaulty::Error::Storage(storage::Error::RateLimited(
+            "429".to_string(),
+        )))
+    }
+
+    #[tokio::test]
+    async fn additive_increase() {
+        let limiter = Limiter::new(Backend::Dropbox, 1, 4);
+        assert_eq!(limiter.state().limit, 2);
+
+        for _ in 0..5 {
+            limiter.acquire().await.record(&Ok(()), 100);
+        }
+
+        assert_eq!(limiter.state().limit, 4);
+        assert_eq!(limiter.semaphore.available_permits(), 4);
+    }
+
+    #[tokio::test]
+    async fn multiplicative_decrease() {
+        let limiter = Limiter::new(Backend::Dropbox, 1, 8);
+        assert_eq!(limiter.state().limit, 4);
+
+        // Both uploads started before the first 429, so the limit is only
+        // halved once
+        let first = limiter.acquire().await;
+        let second = limiter.acquire().await;
+
+        first.record(&rate_limited(), 100);
+        assert_eq!(limiter.state().limit, 2);
+        assert_eq!(limiter.semaphore.available_permits(), 1);
+
+        second.record(&rate_limited(), 100);
+        assert_eq!(limiter.state().limit, 2);
+        assert_eq!(limiter.semaphore.available_permits(), 2);
+
+        limiter.acquire().await.record(&rate_limited(), 100);
+        assert_eq!(limiter.state().limit, 1);
+
+        // Never below the minimum
+        limiter.acquire().await.record(&rate_limited(), 100);
+        assert_eq!(limiter.state().limit, 1);
+        assert_eq!(limiter.semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    fn slow_uploads() {
+        let limiter = Limiter::new(Backend::S3, 1, 8);
+
+        for _ in 0..WARMUP_SAMPLES {
+            assert!(!limiter.observe_latency(1.0));
+        }
+
+        assert!(!limiter.observe_latency(1.5));
+        assert!(limiter.observe_latency(5.0));
+    }
+}
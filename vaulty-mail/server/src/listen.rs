@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Local};
+use futures::channel::mpsc;
+use futures::stream::{self, StreamExt};
+use tokio_postgres::{AsyncMessage, NoTls};
+
+use vaulty::config::Config;
+use vaulty::db::migrate::ADDRESS_CHANNEL;
+
+/// Delay before listening again once the connection is lost, in seconds
+const RECONNECT_INTERVAL: u64 = 5;
+
+/// Changes to addresses and domains notified by the DB
+///
+/// Copies of an address read before it changed are stale. Changes are only
+/// kept for `ttl`, as copies are not kept for longer (see `cache_ttl`).
+pub struct AddressChanges {
+    ttl: Duration,
+
+    /// When each address, or `@` followed by a domain, last changed
+    changed: Mutex<HashMap<String, DateTime<Local>>>,
+
+    /// Changes may have been missed before this time (e.g., while
+    /// reconnecting), so all copies read before it are stale
+    reset: Mutex<Option<DateTime<Local>>>,
+}
+
+impl AddressChanges {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            changed: Mutex::new(HashMap::new()),
+            reset: Mutex::new(None),
+        }
+    }
+
+    /// Record a change notified on `ADDRESS_CHANNEL`
+    pub fn record(&self, key: &str) {
+        self.record_at(key, Local::now());
+    }
+
+    fn record_at(&self, key: &str, now: DateTime<Local>) {
+        let mut changed = self.changed.lock().unwrap();
+
+        changed.retain(|_, time| now - *time < self.ttl);
+        changed.insert(key.to_lowercase(), now);
+    }
+
+    /// Consider all copies read until now stale
+    pub fn reset(&self) {
+        *self.reset.lock().unwrap() = Some(Local::now());
+    }
+
+    /// Returns true if `address` or its domain changed since a copy of it
+    /// was read at `since`
+    ///
+    /// Copies read at an unknown time are stale once any change to them is
+    /// seen.
+    pub fn changed_since(&self, address: &str, since: Option<DateTime<Local>>) -> bool {
+        let is_after = |time: DateTime<Local>| since.map_or(true, |since| time >= since);
+
+        if self.reset.lock().unwrap().map_or(false, is_after) {
+            return true;
+        }
+
+        let address = address.to_lowercase();
+        let domain = address.rsplit('@').next().map(|d| format!("@{}", d));
+        let changed = self.changed.lock().unwrap();
+
+        std::iter::once(&address)
+            .chain(domain.as_ref())
+            .filter_map(|key| changed.get(key))
+            .any(|time| is_after(*time))
+    }
+}
+
+/// Listen for changes to addresses and domains, for as long as the server
+/// runs
+///
+/// Notifications are only sent by a DB that was migrated (see
+/// `vaulty::db::migrate`).
+pub async fn run(config: Arc<Config>, changes: Arc<AddressChanges>) {
+    loop {
+        match listen(&config, &changes).await {
+            Ok(()) => log::warn!("Connection for address changes was closed; reconnecting"),
+            Err(e) => log::error!("Failed to listen for address changes: {}", e),
+        }
+
+        tokio::time::delay_for(std::time::Duration::from_secs(RECONNECT_INTERVAL)).await;
+    }
+}
+
+/// Record notified changes until the connection is closed
+async fn listen(config: &Config, changes: &AddressChanges) -> Result<(), tokio_postgres::Error> {
+    let (client, mut connection) = tokio_postgres::connect(&config.database_url(), NoTls).await?;
+
+    // The connection only makes progress while its messages are polled,
+    // including the response to LISTEN
+    let (tx, mut messages) = mpsc::unbounded();
+    let mut stream = stream::poll_fn(move |cx| connection.poll_message(cx));
+    tokio::spawn(async move {
+        while let Some(message) = stream.next().await {
+            if tx.unbounded_send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    client
+        .batch_execute(&format!("LISTEN {}", ADDRESS_CHANNEL))
+        .await?;
+
+    // Changes made while not listening were missed
+    changes.reset();
+    log::info!("Listening for address changes");
+
+    while let Some(message) = messages.next().await {
+        if let AsyncMessage::Notification(n) = message? {
+            log::debug!("Address {} changed", n.payload());
+            changes.record(n.payload());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_changes() {
+        let changes = AddressChanges::new(Duration::minutes(10));
+        let start = Local::now();
+
+        assert!(!changes.changed_since("a@b.com", Some(start)));
+        assert!(!changes.changed_since("a@b.com", None));
+
+        changes.record_at("A@b.com", start + Duration::minutes(1));
+        assert!(changes.changed_since("a@b.com", Some(start)));
+        assert!(changes.changed_since("a@b.com", None));
+        assert!(!changes.changed_since("a@b.com", Some(start + Duration::minutes(2))));
+        assert!(!changes.changed_since("c@b.com", Some(start)));
+
+        // Domain defaults apply to all of its addresses
+        changes.record_at("@b.com", start + Duration::minutes(3));
+        assert!(changes.changed_since("c@b.com", Some(start + Duration::minutes(2))));
+        assert!(!changes.changed_since("c@d.com", Some(start)));
+
+        // Old changes are forgotten
+        changes.record_at("@d.com", start + Duration::minutes(14));
+        assert!(!changes.changed_since("a@b.com", None));
+        assert!(changes.changed_since("c@d.com", Some(start)));
+
+        changes.reset();
+        assert!(changes.changed_since("e@f.com", Some(start)));
+    }
+}
@@ -0,0 +1,389 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use serde::Serialize;
+
+/// Internal in-memory caches
+///
+/// Every cache is bounded, so that memory use stays flat on long-running
+/// instances, even under attack or heavy load.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// Emails waiting for their attachments (in-memory session store)
+    Mail,
+    /// Verified API credentials
+    Auth,
+    /// Inbound email stats
+    Stats,
+    /// Recent emails of each sender, for rate limiting
+    RateLimit,
+    /// Tokens of recent Mailgun webhook posts, to reject replays
+    MailgunTokens,
+    /// Recent attachment size mismatches of each sender
+    SizeMismatch,
+}
+
+impl Kind {
+    pub const ALL: &'static [Kind] = &[
+        Kind::Mail,
+        Kind::Auth,
+        Kind::Stats,
+        Kind::RateLimit,
+        Kind::MailgunTokens,
+        Kind::SizeMismatch,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Mail => "mail",
+            Kind::Auth => "auth",
+            Kind::Stats => "stats",
+            Kind::RateLimit => "rate_limit",
+            Kind::MailgunTokens => "mailgun_tokens",
+            Kind::SizeMismatch => "size_mismatch",
+        }
+    }
+}
+
+/// Counters of a single cache
+struct Metrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    entries: AtomicUsize,
+    bytes: AtomicUsize,
+    max_entries: AtomicUsize,
+    max_bytes: AtomicUsize,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            entries: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            max_entries: AtomicUsize::new(0),
+            max_bytes: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// One set of counters per cache, indexed by `Kind as usize`
+static METRICS: [Metrics; 6] = [
+    Metrics::new(),
+    Metrics::new(),
+    Metrics::new(),
+    Metrics::new(),
+    Metrics::new(),
+    Metrics::new(),
+];
+
+/// Snapshot of the state of a cache, used for monitoring
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub cache: Kind,
+    pub entries: usize,
+    pub max_entries: usize,
+
+    /// Approximate size of all entries, if the cache is bounded by size
+    pub bytes: Option<usize>,
+    pub max_bytes: Option<usize>,
+
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+/// Current state of all caches
+pub fn snapshot() -> Vec<CacheStats> {
+    Kind::ALL
+        .iter()
+        .map(|&kind| {
+            let m = &METRICS[kind as usize];
+            let max_bytes = Some(m.max_bytes.load(Ordering::Relaxed)).filter(|b| *b > 0);
+
+            CacheStats {
+                cache: kind,
+                entries: m.entries.load(Ordering::Relaxed),
+                max_entries: m.max_entries.load(Ordering::Relaxed),
+                bytes: max_bytes.map(|_| m.bytes.load(Ordering::Relaxed)),
+                max_bytes,
+                hits: m.hits.load(Ordering::Relaxed),
+                misses: m.misses.load(Ordering::Relaxed),
+                evictions: m.evictions.load(Ordering::Relaxed),
+            }
+        })
+        .collect()
+}
+
+struct Slot<V> {
+    value: V,
+    weight: usize,
+
+    /// Key of this entry in the use order
+    used: u64,
+}
+
+/// Cache that evicts its least recently used entries once it holds more
+/// than `max_entries`, or more than `max_bytes` if set
+///
+/// The newest entry is never evicted, even if it is larger than
+/// `max_bytes` on its own. Callers lock the cache themselves.
+pub struct Lru<K, V> {
+    kind: Kind,
+    max_entries: usize,
+    max_bytes: Option<usize>,
+
+    /// Approximate size of an entry, in bytes
+    weigh: fn(&V) -> usize,
+
+    entries: HashMap<K, Slot<V>>,
+
+    /// Keys from least to most recently used
+    order: BTreeMap<u64, K>,
+    clock: u64,
+    bytes: usize,
+}
+
+impl<K: Clone + Eq + Hash, V> Lru<K, V> {
+    pub fn new(kind: Kind, max_entries: usize) -> Self {
+        let max_entries = max_entries.max(1);
+        METRICS[kind as usize]
+            .max_entries
+            .store(max_entries, Ordering::Relaxed);
+
+        Self {
+            kind,
+            max_entries,
+            max_bytes: None,
+            weigh: |_| 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Also bound the total size of entries, as measured by `weigh`
+    pub fn with_max_bytes(self, max_bytes: usize, weigh: fn(&V) -> usize) -> Self {
+        METRICS[self.kind as usize]
+            .max_bytes
+            .store(max_bytes, Ordering::Relaxed);
+
+        Self {
+            max_bytes: Some(max_bytes),
+            weigh,
+            ..self
+        }
+    }
+
+    fn metrics(&self) -> &'static Metrics {
+        &METRICS[self.kind as usize]
+    }
+
+    /// Mark an entry as the most recently used
+    fn touch<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.clock += 1;
+        let clock = self.clock;
+
+        if let Some(slot) = self.entries.get_mut(key) {
+            if let Some(key) = self.order.remove(&slot.used) {
+                self.order.insert(clock, key);
+            }
+
+            slot.used = clock;
+        }
+    }
+
+    fn record(&self, hit: bool) {
+        let counter = if hit {
+            &self.metrics().hits
+        } else {
+            &self.metrics().misses
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn update_gauges(&self) {
+        let m = self.metrics();
+        m.entries.store(self.entries.len(), Ordering::Relaxed);
+        m.bytes.store(self.bytes, Ordering::Relaxed);
+    }
+
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_mut(key).map(|v| &*v)
+    }
+
+    /// Get an entry to modify in place
+    ///
+    /// Its size is not measured again, so use `insert` for changes that
+    /// affect the size of the entry.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hit = self.entries.contains_key(key);
+        self.record(hit);
+
+        if !hit {
+            return None;
+        }
+
+        self.touch(key);
+        self.entries.get_mut(key).map(|slot| &mut slot.value)
+    }
+
+    /// Get an entry, inserting it first if it is missing
+    pub fn get_or_insert_with(&mut self, key: K, f: impl FnOnce() -> V) -> &mut V {
+        if self.get_mut(&key).is_none() {
+            self.insert(key.clone(), f());
+        }
+
+        &mut self.entries.get_mut(&key).unwrap().value
+    }
+
+    /// Insert or replace an entry, making it the most recently used
+    ///
+    /// Returns the entries evicted to make room for it.
+    pub fn insert(&mut self, key: K, value: V) -> Vec<(K, V)> {
+        self.remove(&key);
+
+        let weight = (self.weigh)(&value);
+        self.clock += 1;
+
+        self.order.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            Slot {
+                value,
+                weight,
+                used: self.clock,
+            },
+        );
+        self.bytes += weight;
+
+        let mut evicted = Vec::new();
+
+        while self.entries.len() > 1 && self.is_over_limit() {
+            let oldest = match self.order.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+
+            if let Some(key) = self.order.remove(&oldest) {
+                if let Some(slot) = self.entries.remove(&key) {
+                    self.bytes -= slot.weight;
+                    evicted.push((key, slot.value));
+                }
+            }
+        }
+
+        if !evicted.is_empty() {
+            log::debug!(
+                "Evicted {} entries from the {} cache",
+                evicted.len(),
+                self.kind.name()
+            );
+            self.metrics()
+                .evictions
+                .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        }
+
+        self.update_gauges();
+
+        evicted
+    }
+
+    fn is_over_limit(&self) -> bool {
+        self.entries.len() > self.max_entries
+            || self.max_bytes.map_or(false, |max| self.bytes > max)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let slot = self.entries.remove(key)?;
+
+        self.order.remove(&slot.used);
+        self.bytes -= slot.weight;
+        self.update_gauges();
+
+        Some(slot.value)
+    }
+
+    /// Remove all entries that match `f`
+    pub fn remove_where(&mut self, f: impl Fn(&K, &V) -> bool) -> Vec<V> {
+        let keys: Vec<K> = self
+            .entries
+            .iter()
+            .filter(|(k, slot)| f(k, &slot.value))
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        keys.iter().filter_map(|k| self.remove(k)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut cache = Lru::new(Kind::Stats, 2);
+
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        assert_eq!(cache.get(&1), Some(&"a"));
+
+        // 2 is the least recently used
+        assert_eq!(cache.insert(3, "c"), vec![(2, "b")]);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.entries.len(), 2);
+
+        // Replacing an entry does not evict anything
+        assert!(cache.insert(1, "A").is_empty());
+        assert_eq!(cache.get(&1), Some(&"A"));
+
+        assert_eq!(cache.remove_where(|k, _| *k == 3), vec!["c"]);
+        assert_eq!(cache.entries.len(), 1);
+    }
+
+    #[test]
+    fn evict_by_size() {
+        let mut cache = Lru::new(Kind::Mail, 10).with_max_bytes(10, |v: &String| v.len());
+
+        cache.insert("a", "12345".to_string());
+        cache.insert("b", "12345".to_string());
+        assert_eq!(cache.entries.len(), 2);
+
+        let evicted = cache.insert("c", "1".to_string());
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].0, "a");
+
+        // The newest entry is kept even if it is too large on its own
+        let evicted = cache.insert("d", "12345678901".to_string());
+        assert_eq!(evicted.len(), 2);
+        assert_eq!(cache.get(&"d").map(|v| v.len()), Some(11));
+
+        *cache.get_or_insert_with("e", String::new) += "x";
+        assert_eq!(cache.get(&"e").map(|v| v.as_str()), Some("x"));
+    }
+}
@@ -1,9 +1,22 @@
+mod auth;
 mod cache;
+mod canary;
 mod controllers;
 mod error;
 mod filters;
+mod flags;
 mod http;
+mod limiter;
+mod listen;
+mod lru;
+mod ratelimit;
+mod retries;
 mod routes;
+mod session;
+mod sizes;
+mod smtp;
+mod spool;
+mod stats;
 
 use clap::{App, Arg};
 
@@ -27,6 +40,16 @@ async fn main() {
                 .default_value(vaulty::config::DEFAULT_CONFIG_PATH)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("encrypt_storage_tokens")
+                .long("encrypt-storage-tokens")
+                .help("Encrypt the storage tokens stored in plaintext with storage_token_key, and exit"),
+        )
+        .arg(
+            Arg::with_name("migrate")
+                .long("migrate")
+                .help("Apply pending DB migrations, and exit"),
+        )
         .get_matches();
 
     // Load config
@@ -34,6 +57,16 @@ async fn main() {
     let arg = config::Config::load(config_path);
     log::info!("Loaded config from {:?}", config_path);
 
+    if matches.is_present("migrate") {
+        http::migrate(arg).await;
+        return;
+    }
+
+    if matches.is_present("encrypt_storage_tokens") {
+        http::encrypt_storage_tokens(arg).await;
+        return;
+    }
+
     log::info!("Starting vaulty_server...");
 
     http::run(arg).await;
@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use vaulty::config::Config;
+
+use super::lru::{Kind, Lru};
+
+/// Limits the number of emails a single sender can send to an address
+///
+/// Each (sender, recipient) pair can send up to `limit` emails within any
+/// sliding window of `window`, so that one abusive sender cannot use up the
+/// quota of an address in one go.
+///
+/// Counts are kept in memory, so each server instance enforces the limit on
+/// its own. Only the most recently seen pairs are tracked.
+pub struct RateLimiter {
+    /// No limit if not set
+    limit: Option<usize>,
+    window: Duration,
+
+    /// Time of each email accepted within the window, oldest first
+    hits: Mutex<Lru<(String, String), VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Option<usize>, window: Duration, max_entries: usize) -> Self {
+        Self {
+            limit,
+            window,
+            hits: Mutex::new(Lru::new(Kind::RateLimit, max_entries)),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            config.sender_rate_limit,
+            Duration::from_secs(config.sender_rate_window),
+            config.rate_limit_max_entries,
+        )
+    }
+
+    /// Count an email from `sender` to `recipient` against their limit
+    ///
+    /// Returns false if the limit has been hit, in which case the email is
+    /// not counted.
+    pub fn check(&self, sender: &str, recipient: &str) -> bool {
+        self.check_at(sender, recipient, Instant::now())
+    }
+
+    fn check_at(&self, sender: &str, recipient: &str, now: Instant) -> bool {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let key = (sender.to_lowercase(), recipient.to_lowercase());
+
+        let mut hits = self.hits.lock().unwrap();
+        let times = hits.get_or_insert_with(key, VecDeque::new);
+
+        while times
+            .front()
+            .map_or(false, |t| now.saturating_duration_since(*t) >= self.window)
+        {
+            times.pop_front();
+        }
+
+        if times.len() >= limit {
+            return false;
+        }
+
+        times.push_back(now);
+
+        true
+    }
+
+    /// Forget pairs that have not sent any email within the window
+    fn prune(&self) {
+        let now = Instant::now();
+        let window = self.window;
+
+        self.hits.lock().unwrap().remove_where(|_, times| {
+            times
+                .back()
+                .map_or(true, |t| now.saturating_duration_since(*t) >= window)
+        });
+    }
+}
+
+/// Periodically forget senders that are no longer rate limited, so that
+/// memory use does not grow with the number of distinct senders
+pub async fn prune(limiter: Arc<RateLimiter>) {
+    if limiter.limit.is_none() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(limiter.window);
+
+    loop {
+        interval.tick().await;
+        limiter.prune();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_window() {
+        let limiter = RateLimiter::new(Some(2), Duration::from_secs(60), 10);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(limiter.check_at("a@x.com", "me@vaulty.net", at(0)));
+        assert!(limiter.check_at("A@X.com", "me@vaulty.net", at(10)));
+        assert!(!limiter.check_at("a@x.com", "me@vaulty.net", at(20)));
+
+        // Other senders and recipients have their own limit
+        assert!(limiter.check_at("b@x.com", "me@vaulty.net", at(20)));
+        assert!(limiter.check_at("a@x.com", "you@vaulty.net", at(20)));
+
+        // The first email falls out of the window; rejected emails do not
+        // count
+        assert!(limiter.check_at("a@x.com", "me@vaulty.net", at(60)));
+        assert!(!limiter.check_at("a@x.com", "me@vaulty.net", at(65)));
+        assert!(limiter.check_at("a@x.com", "me@vaulty.net", at(70)));
+
+        let unlimited = RateLimiter::new(None, Duration::from_secs(60), 10);
+        assert!((0..100).all(|_| unlimited.check_at("a@x.com", "me@vaulty.net", at(0))));
+    }
+}
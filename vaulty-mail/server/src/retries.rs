@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// Why an attachment was submitted again for the same email
+///
+/// Postfix retries an attachment whenever the filter does not get a response
+/// in time, even if the first submission is still being handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// The attachment was already processed; it is not stored again
+    Processed,
+    /// The same content was already stored for the email under another
+    /// index; it is not stored again
+    Duplicate,
+    /// An earlier submission is still being handled; the retry is asked to
+    /// try again later
+    InFlight,
+    /// An earlier submission failed (or the attachment was dropped), so the
+    /// attachment is handled again
+    Failed,
+}
+
+impl Kind {
+    pub const ALL: &'static [Kind] = &[
+        Kind::Processed,
+        Kind::Duplicate,
+        Kind::InFlight,
+        Kind::Failed,
+    ];
+}
+
+/// Number of attachment submissions, including retries
+static SUBMISSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of retries of each kind, indexed by `Kind as usize`
+static RETRIES: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Count a submission of an attachment
+pub fn record_submission() {
+    SUBMISSIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a retried submission of an attachment
+pub fn record(kind: Kind, mail_id: &str, index: u16) {
+    RETRIES[kind as usize].fetch_add(1, Ordering::Relaxed);
+
+    log::info!(
+        "Attachment {} of {} was submitted again ({:?})",
+        index,
+        mail_id,
+        kind
+    );
+}
+
+/// Snapshot of attachment retries since startup, used for monitoring
+#[derive(Debug, Serialize)]
+pub struct RetryStats {
+    pub submissions: u64,
+    pub retries: u64,
+
+    /// Share of submissions that were retries
+    pub retry_rate: f64,
+
+    pub by_kind: BTreeMap<Kind, u64>,
+}
+
+/// Current retry counts
+pub fn snapshot() -> RetryStats {
+    let by_kind: BTreeMap<Kind, u64> = Kind::ALL
+        .iter()
+        .map(|&kind| (kind, RETRIES[kind as usize].load(Ordering::Relaxed)))
+        .collect();
+
+    let submissions = SUBMISSIONS.load(Ordering::Relaxed);
+    let retries: u64 = by_kind.values().sum();
+
+    let retry_rate = if submissions > 0 {
+        retries as f64 / submissions as f64
+    } else {
+        0.0
+    };
+
+    RetryStats {
+        submissions,
+        retries,
+        retry_rate,
+        by_kind,
+    }
+}
+
+/// Attachments that are being handled, by email ID and index
+///
+/// A retry of an attachment that is still being handled is turned away, so
+/// that the attachment is not uploaded or accounted for twice.
+#[derive(Default)]
+pub struct InFlight {
+    attachments: Mutex<HashSet<(String, u16)>>,
+}
+
+impl InFlight {
+    /// Claim an attachment for the lifetime of the returned guard
+    ///
+    /// Returns `None` if the attachment is already claimed.
+    pub fn claim(&self, mail_id: &str, index: u16) -> Option<Claim<'_>> {
+        let key = (mail_id.to_string(), index);
+
+        if !self.attachments.lock().unwrap().insert(key.clone()) {
+            return None;
+        }
+
+        Some(Claim {
+            in_flight: self,
+            key,
+        })
+    }
+}
+
+/// Releases a claimed attachment when dropped
+pub struct Claim<'a> {
+    in_flight: &'a InFlight,
+    key: (String, u16),
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.in_flight.attachments.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claim_attachments() {
+        let in_flight = InFlight::default();
+
+        let claim = in_flight.claim("a", 0);
+        assert!(claim.is_some());
+        assert!(in_flight.claim("a", 0).is_none());
+        assert!(in_flight.claim("a", 1).is_some());
+        assert!(in_flight.claim("b", 0).is_some());
+
+        drop(claim);
+        assert!(in_flight.claim("a", 0).is_some());
+    }
+}
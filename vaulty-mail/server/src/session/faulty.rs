@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use chrono::Duration;
+use vaulty::faults::{self, Target};
+
+use super::{SessionStats, SessionStore, StoreFuture};
+use crate::cache::CacheEntry;
+
+/// Session store that injects cache faults in front of another store
+///
+/// See `vaulty::faults`.
+pub struct FaultyStore {
+    inner: Arc<dyn SessionStore>,
+}
+
+impl FaultyStore {
+    pub fn new(inner: Arc<dyn SessionStore>) -> Self {
+        Self { inner }
+    }
+}
+
+impl SessionStore for FaultyStore {
+    fn get(&self, key: &str) -> StoreFuture<'_, Option<CacheEntry>> {
+        let key = key.to_string();
+        Box::pin(async move {
+            faults::inject(Target::Cache).await?;
+            self.inner.get(&key).await
+        })
+    }
+
+    fn insert(&self, key: &str, entry: CacheEntry) -> StoreFuture<'_, ()> {
+        let key = key.to_string();
+        Box::pin(async move {
+            faults::inject(Target::Cache).await?;
+            self.inner.insert(&key, entry).await
+        })
+    }
+
+    fn update(&self, key: &str, entry: CacheEntry) -> StoreFuture<'_, ()> {
+        let key = key.to_string();
+        Box::pin(async move {
+            faults::inject(Target::Cache).await?;
+            self.inner.update(&key, entry).await
+        })
+    }
+
+    fn remove(&self, key: &str) -> StoreFuture<'_, Option<CacheEntry>> {
+        let key = key.to_string();
+        Box::pin(async move {
+            faults::inject(Target::Cache).await?;
+            self.inner.remove(&key).await
+        })
+    }
+
+    fn remove_expired(&self, ttl: Duration) -> StoreFuture<'_, Vec<CacheEntry>> {
+        Box::pin(async move {
+            faults::inject(Target::Cache).await?;
+            self.inner.remove_expired(ttl).await
+        })
+    }
+
+    // Monitoring is left alone
+    fn stats(&self) -> StoreFuture<'_, SessionStats> {
+        self.inner.stats()
+    }
+}
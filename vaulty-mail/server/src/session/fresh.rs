@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use chrono::{Duration, Local};
+use vaulty::config::Config;
+use vaulty::settings::Settings;
+
+use super::{SessionStats, SessionStore, StoreFuture};
+use crate::cache::CacheEntry;
+use crate::controllers::new_db_client;
+use crate::listen::AddressChanges;
+
+/// Session store that reloads the address of an entry once it changed in
+/// the DB, in front of another store
+///
+/// Entries keep a copy of their address for the remaining attachments, which
+/// would otherwise be used until the email expires (e.g., an old storage
+/// token or path). See `listen::AddressChanges`.
+pub struct FreshStore {
+    inner: Arc<dyn SessionStore>,
+    changes: Arc<AddressChanges>,
+    db: sqlx::PgPool,
+    config: Arc<Config>,
+}
+
+impl FreshStore {
+    pub fn new(
+        inner: Arc<dyn SessionStore>,
+        changes: Arc<AddressChanges>,
+        db: sqlx::PgPool,
+        config: Arc<Config>,
+    ) -> Self {
+        Self {
+            inner,
+            changes,
+            db,
+            config,
+        }
+    }
+
+    /// Reload the address of an entry, and store it for later requests
+    ///
+    /// The entry is removed if its address was removed or deactivated
+    /// since, so that its remaining attachments are rejected.
+    async fn reload(&self, key: &str, mut entry: CacheEntry) -> Result<CacheEntry, vaulty::Error> {
+        let mut db = self.db.clone();
+        let mut db_client = new_db_client(&mut db, &self.config);
+        let defaults = Settings::from_config(&self.config);
+        let address_time = Local::now();
+
+        let address = db_client
+            .get_address(&[entry.address.address.as_str()], &defaults)
+            .await?
+            .filter(|a| a.is_active);
+
+        match address {
+            Some(address) => {
+                log::info!(
+                    "Reloaded address {} of email {} after it changed",
+                    address.address,
+                    key
+                );
+
+                entry.address = address;
+                entry.address_time = Some(address_time);
+                self.inner.update(key, entry.clone()).await?;
+            }
+            None => {
+                log::warn!(
+                    "Address {} of email {} was removed or deactivated",
+                    entry.address.address,
+                    key
+                );
+
+                self.inner.remove(key).await?;
+                return Err(vaulty::Error::InvalidRecipient);
+            }
+        }
+
+        Ok(entry)
+    }
+}
+
+impl SessionStore for FreshStore {
+    fn get(&self, key: &str) -> StoreFuture<'_, Option<CacheEntry>> {
+        let key = key.to_string();
+        Box::pin(async move {
+            let entry = match self.inner.get(&key).await? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+
+            let since = entry.address_time.or(entry.insertion_time);
+            if !self.changes.changed_since(&entry.address.address, since) {
+                return Ok(Some(entry));
+            }
+
+            self.reload(&key, entry).await.map(Some)
+        })
+    }
+
+    fn insert(&self, key: &str, entry: CacheEntry) -> StoreFuture<'_, ()> {
+        self.inner.insert(key, entry)
+    }
+
+    fn update(&self, key: &str, entry: CacheEntry) -> StoreFuture<'_, ()> {
+        self.inner.update(key, entry)
+    }
+
+    fn remove(&self, key: &str) -> StoreFuture<'_, Option<CacheEntry>> {
+        self.inner.remove(key)
+    }
+
+    fn remove_expired(&self, ttl: Duration) -> StoreFuture<'_, Vec<CacheEntry>> {
+        self.inner.remove_expired(ttl)
+    }
+
+    fn stats(&self) -> StoreFuture<'_, SessionStats> {
+        self.inner.stats()
+    }
+}
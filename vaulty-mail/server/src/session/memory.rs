@@ -0,0 +1,71 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use tokio::sync::RwLock;
+
+use super::{SessionStats, SessionStore, StoreFuture};
+use crate::cache::{Cache, CacheEntry};
+
+/// Process-local session store
+pub struct MemoryStore {
+    cache: RwLock<Cache>,
+}
+
+impl MemoryStore {
+    pub fn from_config(config: &vaulty::config::Config) -> Self {
+        Self {
+            cache: RwLock::new(Cache::new(
+                config.mail_cache_max_entries,
+                config.mail_cache_max_bytes,
+            )),
+        }
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn get(&self, key: &str) -> StoreFuture<'_, Option<CacheEntry>> {
+        let key = key.to_string();
+        Box::pin(async move { Ok(self.cache.write().await.get(&key).cloned()) })
+    }
+
+    fn insert(&self, key: &str, entry: CacheEntry) -> StoreFuture<'_, ()> {
+        let key = key.to_string();
+        Box::pin(async move {
+            self.cache.write().await.insert(key, entry);
+            Ok(())
+        })
+    }
+
+    fn update(&self, key: &str, entry: CacheEntry) -> StoreFuture<'_, ()> {
+        let key = key.to_string();
+        Box::pin(async move {
+            if let Some(e) = self.cache.write().await.get_mut(&key) {
+                *e = CacheEntry {
+                    insertion_time: e.insertion_time,
+                    last_updated: Some(Local::now()),
+                    ..entry
+                };
+            }
+            Ok(())
+        })
+    }
+
+    fn remove(&self, key: &str) -> StoreFuture<'_, Option<CacheEntry>> {
+        let key = key.to_string();
+        Box::pin(async move { Ok(self.cache.write().await.remove(&key)) })
+    }
+
+    fn remove_expired(&self, ttl: Duration) -> StoreFuture<'_, Vec<CacheEntry>> {
+        Box::pin(async move { Ok(self.cache.write().await.remove_expired(ttl)) })
+    }
+
+    fn stats(&self) -> StoreFuture<'_, SessionStats> {
+        Box::pin(async move {
+            let cache = self.cache.read().await;
+
+            Ok(SessionStats {
+                num_processed: cache.num_processed,
+                avg_processing_time: cache.avg_processing_time,
+            })
+        })
+    }
+}
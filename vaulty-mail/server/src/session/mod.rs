@@ -0,0 +1,86 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use chrono::Duration;
+use serde::Serialize;
+
+use super::cache::CacheEntry;
+
+mod faulty;
+mod fresh;
+mod memory;
+mod redis_store;
+
+pub use faulty::FaultyStore;
+pub use fresh::FreshStore;
+pub use memory::MemoryStore;
+pub use redis_store::RedisStore;
+
+// Definition of future types for async use
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, vaulty::Error>> + Send + 'a>>;
+
+/// Snapshot of session store state, used for monitoring
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SessionStats {
+    /// Total number of entries processed
+    pub num_processed: u64,
+
+    /// Average processing time for entries, in microseconds
+    pub avg_processing_time: f32,
+}
+
+/// Tracks emails between the email request and their attachment requests
+///
+/// The in-memory store only works with a single server instance. Use the
+/// Redis store to run several instances behind a load balancer, so that an
+/// email and its attachments can be handled by different instances.
+pub trait SessionStore: Send + Sync {
+    fn get(&self, key: &str) -> StoreFuture<'_, Option<CacheEntry>>;
+
+    /// Insert a new entry, setting its insertion time
+    fn insert(&self, key: &str, entry: CacheEntry) -> StoreFuture<'_, ()>;
+
+    /// Replace an existing entry, setting its last updated time
+    ///
+    /// This is a no-op if the entry has been removed in the meantime.
+    fn update(&self, key: &str, entry: CacheEntry) -> StoreFuture<'_, ()>;
+
+    /// Remove a fully processed entry
+    fn remove(&self, key: &str) -> StoreFuture<'_, Option<CacheEntry>>;
+
+    /// Remove all entries that have not been updated within `ttl`
+    ///
+    /// Each expired entry is only returned to a single caller.
+    fn remove_expired(&self, ttl: Duration) -> StoreFuture<'_, Vec<CacheEntry>>;
+
+    fn stats(&self) -> StoreFuture<'_, SessionStats>;
+}
+
+/// Build the session store selected in config
+///
+/// Exits if the Redis store cannot be reached, rather than falling back to
+/// memory: other servers would not see the emails this one accepts.
+pub async fn from_config(config: &vaulty::config::Config) -> Arc<dyn SessionStore> {
+    let store: Arc<dyn SessionStore> = match &config.session_store {
+        Some(url) => {
+            let store = match RedisStore::connect(url).await {
+                Ok(store) => store,
+                Err(e) => {
+                    log::error!("Failed to connect to the Redis session store: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            log::info!("Using Redis session store at {}", url);
+            Arc::new(store)
+        }
+        None => Arc::new(MemoryStore::from_config(config)),
+    };
+
+    // Cache faults are only injected in builds meant for resilience testing
+    if cfg!(feature = "faults") {
+        Arc::new(FaultyStore::new(store))
+    } else {
+        store
+    }
+}
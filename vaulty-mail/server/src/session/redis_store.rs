@@ -0,0 +1,223 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use redis::aio::MultiplexedConnection;
+
+use super::{SessionStats, SessionStore, StoreFuture};
+use crate::cache::CacheEntry;
+
+/// All keys are namespaced so that the Redis instance can be shared
+const KEY_PREFIX: &str = "vaulty:session:";
+const NUM_PROCESSED_KEY: &str = "vaulty:stats:num_processed";
+const PROCESSING_TIME_KEY: &str = "vaulty:stats:processing_time";
+
+/// Number of keys fetched per SCAN call
+const SCAN_COUNT: usize = 100;
+
+/// Session store shared by all server instances
+///
+/// Entries are stored as JSON. Redis errors are reported as temporary so
+/// that the client retries the request.
+pub struct RedisStore {
+    conn: MultiplexedConnection,
+}
+
+fn map_err(err: redis::RedisError) -> vaulty::Error {
+    vaulty::Error::Temporary(format!("Session store error: {}", err))
+}
+
+fn session_key(key: &str) -> String {
+    format!("{}{}", KEY_PREFIX, key)
+}
+
+impl RedisStore {
+    /// Connect to Redis at the given URL (e.g., `redis://127.0.0.1/`)
+    pub async fn connect(url: &str) -> Result<Self, vaulty::Error> {
+        let client = redis::Client::open(url).map_err(map_err)?;
+        let conn = client
+            .get_multiplexed_tokio_connection()
+            .await
+            .map_err(map_err)?;
+
+        Ok(Self { conn })
+    }
+
+    async fn get_raw(&self, key: &str) -> Result<Option<CacheEntry>, vaulty::Error> {
+        let value: Option<String> = redis::cmd("GET")
+            .arg(key)
+            .query_async(&mut self.conn.clone())
+            .await
+            .map_err(map_err)?;
+
+        match value {
+            Some(v) => serde_json::from_str(&v)
+                .map(Some)
+                .map_err(|e| vaulty::Error::internal("Invalid session cache entry", e)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_raw(
+        &self,
+        key: &str,
+        entry: &CacheEntry,
+        only_existing: bool,
+    ) -> Result<(), vaulty::Error> {
+        let value = serde_json::to_string(entry)
+            .map_err(|e| vaulty::Error::internal("Failed to serialize session cache entry", e))?;
+
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(key).arg(value);
+
+        if only_existing {
+            cmd.arg("XX");
+        }
+
+        let _: Option<String> = cmd
+            .query_async(&mut self.conn.clone())
+            .await
+            .map_err(map_err)?;
+
+        Ok(())
+    }
+
+    /// Delete a key, returning true if this caller removed it
+    ///
+    /// This ensures that only one instance handles a removed entry.
+    async fn delete_raw(&self, key: &str) -> Result<bool, vaulty::Error> {
+        let num_deleted: i64 = redis::cmd("DEL")
+            .arg(key)
+            .query_async(&mut self.conn.clone())
+            .await
+            .map_err(map_err)?;
+
+        Ok(num_deleted > 0)
+    }
+}
+
+impl SessionStore for RedisStore {
+    fn get(&self, key: &str) -> StoreFuture<'_, Option<CacheEntry>> {
+        let key = session_key(key);
+        Box::pin(async move { self.get_raw(&key).await })
+    }
+
+    fn insert(&self, key: &str, mut entry: CacheEntry) -> StoreFuture<'_, ()> {
+        let key = session_key(key);
+        Box::pin(async move {
+            entry.insertion_time = Some(Local::now());
+            self.set_raw(&key, &entry, false).await
+        })
+    }
+
+    fn update(&self, key: &str, mut entry: CacheEntry) -> StoreFuture<'_, ()> {
+        let key = session_key(key);
+        Box::pin(async move {
+            entry.last_updated = Some(Local::now());
+            self.set_raw(&key, &entry, true).await
+        })
+    }
+
+    fn remove(&self, key: &str) -> StoreFuture<'_, Option<CacheEntry>> {
+        let key = session_key(key);
+        Box::pin(async move {
+            let entry = match self.get_raw(&key).await? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+
+            if !self.delete_raw(&key).await? {
+                return Ok(None);
+            }
+
+            // Get the total number of microseconds this entry spent in the store
+            let processing_time = entry
+                .insertion_time
+                .and_then(|t| Local::now().signed_duration_since(t).num_microseconds())
+                .unwrap_or(0);
+
+            let _: () = redis::pipe()
+                .cmd("INCR")
+                .arg(NUM_PROCESSED_KEY)
+                .ignore()
+                .cmd("INCRBY")
+                .arg(PROCESSING_TIME_KEY)
+                .arg(processing_time)
+                .ignore()
+                .query_async(&mut self.conn.clone())
+                .await
+                .map_err(map_err)?;
+
+            Ok(Some(entry))
+        })
+    }
+
+    fn remove_expired(&self, ttl: Duration) -> StoreFuture<'_, Vec<CacheEntry>> {
+        Box::pin(async move {
+            let now = Local::now();
+            let pattern = format!("{}*", KEY_PREFIX);
+
+            let mut expired = Vec::new();
+            let mut cursor: u64 = 0;
+
+            loop {
+                let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(&pattern)
+                    .arg("COUNT")
+                    .arg(SCAN_COUNT)
+                    .query_async(&mut self.conn.clone())
+                    .await
+                    .map_err(map_err)?;
+
+                for key in keys {
+                    let entry = match self.get_raw(&key).await? {
+                        Some(entry) => entry,
+                        None => continue,
+                    };
+
+                    let is_expired = entry
+                        .last_updated
+                        .or(entry.insertion_time)
+                        .map(|t| now.signed_duration_since(t) > ttl)
+                        .unwrap_or(false);
+
+                    // Another instance may have expired this entry first
+                    if is_expired && self.delete_raw(&key).await? {
+                        expired.push(entry);
+                    }
+                }
+
+                if next == 0 {
+                    break;
+                }
+
+                cursor = next;
+            }
+
+            Ok(expired)
+        })
+    }
+
+    fn stats(&self) -> StoreFuture<'_, SessionStats> {
+        Box::pin(async move {
+            let (num_processed, processing_time): (Option<u64>, Option<u64>) = redis::cmd("MGET")
+                .arg(NUM_PROCESSED_KEY)
+                .arg(PROCESSING_TIME_KEY)
+                .query_async(&mut self.conn.clone())
+                .await
+                .map_err(map_err)?;
+
+            let num_processed = num_processed.unwrap_or(0);
+            let avg_processing_time = if num_processed > 0 {
+                processing_time.unwrap_or(0) as f32 / num_processed as f32
+            } else {
+                0.0
+            };
+
+            Ok(SessionStats {
+                num_processed,
+                avg_processing_time,
+            })
+        })
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use vaulty::config::Config;
+
+use super::lru::{Kind, Lru};
+
+/// Counts attachments whose size did not match the size declared for them,
+/// by sender
+///
+/// A single mismatch may be a client bug, but a sender whose attachments
+/// keep mismatching may be trying to get past size checks and quotas, so it
+/// is flagged for abuse review. Counts are kept in memory, per server
+/// instance.
+pub struct SizeMismatches {
+    threshold: usize,
+    window: Duration,
+
+    /// Time of each mismatch within the window, oldest first
+    hits: Mutex<Lru<String, VecDeque<Instant>>>,
+}
+
+impl SizeMismatches {
+    pub fn new(threshold: usize, window: Duration, max_entries: usize) -> Self {
+        Self {
+            threshold,
+            window,
+            hits: Mutex::new(Lru::new(Kind::SizeMismatch, max_entries)),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            config.size_mismatch_threshold,
+            Duration::from_secs(config.size_mismatch_window),
+            config.rate_limit_max_entries,
+        )
+    }
+
+    /// Count a mismatch of an attachment from `sender`
+    ///
+    /// Returns true if the sender just reached the threshold, so that it is
+    /// only flagged once while it keeps mismatching.
+    pub fn record(&self, sender: &str) -> bool {
+        self.record_at(sender, Instant::now())
+    }
+
+    fn record_at(&self, sender: &str, now: Instant) -> bool {
+        let mut hits = self.hits.lock().unwrap();
+        let times = hits.get_or_insert_with(sender.to_lowercase(), VecDeque::new);
+
+        while times
+            .front()
+            .map_or(false, |t| now.saturating_duration_since(*t) >= self.window)
+        {
+            times.pop_front();
+        }
+
+        times.push_back(now);
+
+        times.len() == self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag_persistent_mismatches() {
+        let mismatches = SizeMismatches::new(2, Duration::from_secs(60), 10);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(!mismatches.record_at("a@x.com", at(0)));
+        assert!(!mismatches.record_at("b@x.com", at(10)));
+        assert!(mismatches.record_at("A@x.com", at(20)));
+
+        // Flagged once
+        assert!(!mismatches.record_at("a@x.com", at(30)));
+
+        // Mismatches fall out of the window
+        assert!(!mismatches.record_at("b@x.com", at(80)));
+        assert!(mismatches.record_at("b@x.com", at(90)));
+    }
+}
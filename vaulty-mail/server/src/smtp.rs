@@ -0,0 +1,847 @@
+use std::fs::File;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use vaulty::config::Config;
+
+use super::auth;
+use super::controllers;
+use super::filters;
+use super::limiter::UploadLimits;
+use super::ratelimit::RateLimiter;
+use super::session::SessionStore;
+
+/// Time allowed for the client to send a command or a line of data, in
+/// seconds
+const READ_TIMEOUT: u64 = 5 * 60;
+
+/// Max length of a command, or of a single line of data, in bytes
+/// Longer lines of data are read in pieces.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+
+/// Max number of recipients of a single email
+const MAX_RECIPIENTS: usize = 100;
+
+/// Max number of failed logins in a single session; the connection is
+/// closed after that
+const MAX_AUTH_FAILURES: usize = 3;
+
+/// Time before replying to a failed login, in seconds, to slow down
+/// password guessing
+const AUTH_FAILURE_DELAY: u64 = 2;
+
+/// State shared by every SMTP session, used to run received email through
+/// the pipeline
+#[derive(Clone)]
+pub struct Pipeline {
+    pub db: sqlx::PgPool,
+    pub sessions: Arc<dyn SessionStore>,
+    pub limits: Arc<UploadLimits>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub config: Arc<Config>,
+
+    /// Used to STARTTLS, if a certificate is configured
+    pub tls: Option<TlsAcceptor>,
+}
+
+/// What a port of the SMTP server is for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Mail from other mail servers, for the configured domains
+    Relay,
+
+    /// Mail from scripts and devices that log in as an address, for that
+    /// address only
+    Submission,
+}
+
+/// A command sent by the client
+#[derive(Debug, PartialEq)]
+enum Command {
+    Helo(String),
+    Ehlo(String),
+    Mail(String),
+    Rcpt(String),
+    Data,
+    Rset,
+    Noop,
+    Quit,
+    StartTls,
+    /// Mechanism, in upper case, and initial response, if any
+    Auth(String, Option<String>),
+    /// A known command with invalid arguments
+    Invalid,
+    Unknown,
+}
+
+impl Command {
+    fn parse(line: &str) -> Self {
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        let (verb, arg) = match line.find(' ') {
+            Some(i) => (&line[..i], line[i + 1..].trim()),
+            None => (line, ""),
+        };
+
+        match verb.to_ascii_uppercase().as_str() {
+            "HELO" if !arg.is_empty() => Self::Helo(arg.to_string()),
+            "EHLO" if !arg.is_empty() => Self::Ehlo(arg.to_string()),
+            "MAIL" => parse_path(arg, "FROM:").map_or(Self::Invalid, Self::Mail),
+            "RCPT" => parse_path(arg, "TO:").map_or(Self::Invalid, Self::Rcpt),
+            "DATA" => Self::Data,
+            "RSET" => Self::Rset,
+            "NOOP" => Self::Noop,
+            "QUIT" => Self::Quit,
+            "STARTTLS" if arg.is_empty() => Self::StartTls,
+            "AUTH" if !arg.is_empty() => {
+                let mut parts = arg.split_whitespace();
+                let mechanism = parts.next().unwrap_or_default().to_ascii_uppercase();
+                let initial = parts.next().map(String::from);
+
+                Self::Auth(mechanism, initial)
+            }
+            "HELO" | "EHLO" | "STARTTLS" | "AUTH" => Self::Invalid,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Parse the address out of `FROM:<address>` or `TO:<address>`, ignoring
+/// any parameters (e.g., `SIZE=1024`) and source routes
+///
+/// The null sender (`<>`) is an empty address.
+fn parse_path(arg: &str, prefix: &str) -> Option<String> {
+    let has_prefix = arg
+        .get(..prefix.len())
+        .map_or(false, |p| p.eq_ignore_ascii_case(prefix));
+    if !has_prefix {
+        return None;
+    }
+
+    let path = arg[prefix.len()..].trim_start();
+    if !path.starts_with('<') {
+        return None;
+    }
+
+    let end = path.find('>')?;
+    let address = &path[1..end];
+
+    let address = match address.rfind(':') {
+        Some(i) if address.starts_with('@') => &address[i + 1..],
+        _ => address,
+    };
+
+    Some(address.to_string())
+}
+
+/// Whether mail for an address is accepted
+fn accepts(domains: &[String], address: &str) -> bool {
+    match address.rfind('@') {
+        Some(i) => domains
+            .iter()
+            .any(|d| d.eq_ignore_ascii_case(&address[i + 1..])),
+        None => false,
+    }
+}
+
+/// Whether mail for an address may be submitted by a client logged in as
+/// `user`
+fn is_user(user: Option<&str>, address: &str) -> bool {
+    user.map_or(false, |u| u.eq_ignore_ascii_case(address))
+}
+
+/// Decode a base64 response to an AUTH challenge
+///
+/// Returns None if the client cancelled the exchange (`*`).
+fn decode_response(response: &str) -> Option<String> {
+    match response.trim() {
+        "*" => None,
+        // Empty initial response
+        "=" => Some(String::new()),
+        response => {
+            let decoded = base64::decode(response).ok()?;
+            String::from_utf8(decoded).ok()
+        }
+    }
+}
+
+/// Decode the user and pass out of an AUTH PLAIN response
+///
+/// Clients may only log in as themselves: an authorization identity other
+/// than the user is rejected.
+fn decode_plain(response: &str) -> Option<(String, String)> {
+    let decoded = decode_response(response)?;
+    let mut parts = decoded.split('\0');
+
+    let authzid = parts.next()?;
+    let user = parts.next()?;
+    let pass = parts.next()?;
+
+    if parts.next().is_some() || (!authzid.is_empty() && authzid != user) {
+        return None;
+    }
+
+    Some((user.to_string(), pass.to_string()))
+}
+
+/// Lines of a multiline reply, all with the same status code
+fn multiline(code: u16, lines: &[String]) -> String {
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let sep = if i + 1 == lines.len() { ' ' } else { '-' };
+            format!("{}{}{}", code, sep, line)
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// Load the certificate chain and private key offered for STARTTLS
+pub fn tls_acceptor(cert: &str, key: &str) -> Result<TlsAcceptor, String> {
+    let certs = read_pem(cert, pemfile::certs)?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", cert));
+    }
+
+    let mut keys = read_pem(key, pemfile::pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_pem(key, pemfile::rsa_private_keys)?;
+    }
+
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("No private key found in {}", key))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read_pem<T>(
+    path: &str,
+    parse: fn(&mut dyn io::BufRead) -> Result<Vec<T>, ()>,
+) -> Result<Vec<T>, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    parse(&mut io::BufReader::new(file)).map_err(|_| format!("Invalid PEM file {}", path))
+}
+
+/// SMTP reply for an email that failed in the pipeline
+///
+/// Status codes match those the filter returns to Postfix.
+fn error_reply(err: &vaulty::Error) -> String {
+    let code = match err {
+        vaulty::Error::InvalidRecipient => "550 5.1.1",
+        vaulty::Error::QuotaExceeded(_) => "552 5.2.3",
+        vaulty::Error::InvalidSender(_) => "553 5.1.7",
+        vaulty::Error::SenderNotWhitelisted { .. } => "550 5.7.1",
+        vaulty::Error::AutoGenerated { .. } => "550 5.7.1",
+        vaulty::Error::RateLimited { .. } => "450 4.7.1",
+        vaulty::Error::TokenExpired | vaulty::Error::Unauthorized => "554 5.7.8",
+        vaulty::Error::Temporary(_) | vaulty::Error::Maintenance => "451 4.3.0",
+        // The address was removed while the email was handled
+        vaulty::Error::Database(vaulty::db::Error::NotFound) => {
+            return error_reply(&vaulty::Error::InvalidRecipient);
+        }
+        vaulty::Error::Database(_) | vaulty::Error::Internal(_) => {
+            // Internal details are not sent back to the client
+            return "451 4.3.0 Temporary failure, try again later".to_string();
+        }
+        _ => "554 5.2.0",
+    };
+
+    // Replies are a single line
+    let msg = err.to_string().replace(&['\r', '\n'][..], " ");
+
+    format!("{} {}", code, msg)
+}
+
+/// Listen for SMTP connections on a port, and run each email received
+/// through the same pipeline as email from the filter
+pub async fn run(port: u16, mode: Mode, pipeline: Pipeline) {
+    let addr = SocketAddr::new(
+        pipeline
+            .config
+            .listen_address
+            .parse::<IpAddr>()
+            .expect("Invalid listen_address"),
+        port,
+    );
+
+    let mut listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed to start SMTP server at {}: {}", addr, e);
+            return;
+        }
+    };
+
+    log::info!("Starting SMTP server at {} ({:?})...", addr, mode);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::warn!("Failed to accept SMTP connection: {}", e);
+                continue;
+            }
+        };
+
+        let pipeline = pipeline.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = connection(stream, mode, &pipeline).await {
+                log::warn!("SMTP session with {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Handle a single SMTP connection, until the client quits
+///
+/// If the client asks to STARTTLS, the session starts over once the TLS
+/// handshake is done.
+async fn connection(stream: TcpStream, mode: Mode, pipeline: &Pipeline) -> io::Result<()> {
+    let stream = match session(stream, mode, false, pipeline).await? {
+        Some(stream) => stream,
+        None => return Ok(()),
+    };
+
+    let acceptor = pipeline
+        .tls
+        .as_ref()
+        .expect("STARTTLS is only offered with a certificate");
+
+    let stream = tokio::time::timeout(Duration::from_secs(READ_TIMEOUT), acceptor.accept(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+
+    session(stream, mode, true, pipeline).await.map(|_| ())
+}
+
+/// Handle SMTP commands until the client quits
+///
+/// Returns the stream if the client asked to STARTTLS.
+async fn session<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    mode: Mode,
+    is_tls: bool,
+    pipeline: &Pipeline,
+) -> io::Result<Option<S>> {
+    let config = &pipeline.config;
+    let hostname = &config.smtp_hostname;
+
+    let mut stream = BufReader::new(stream);
+    let mut line = Vec::new();
+
+    // Envelope of the email being received
+    let mut helo = None;
+    let mut sender: Option<String> = None;
+    let mut recipients: Vec<String> = Vec::new();
+
+    // Address the client logged in as, if any
+    let mut user: Option<String> = None;
+    let mut auth_failures = 0;
+
+    let can_starttls = mode == Mode::Submission && !is_tls && pipeline.tls.is_some();
+    let can_auth = mode == Mode::Submission && is_tls;
+
+    // Clients greet the server again after STARTTLS, without a banner
+    if !is_tls {
+        reply(&mut stream, &format!("220 {} ESMTP Vaulty", hostname)).await?;
+    }
+
+    loop {
+        if read_line(&mut stream, &mut line).await? == 0 {
+            return Ok(None);
+        }
+
+        let response = match Command::parse(&String::from_utf8_lossy(&line)) {
+            Command::Helo(domain) => {
+                helo = Some(domain);
+                sender = None;
+                recipients.clear();
+
+                format!("250 {}", hostname)
+            }
+            Command::Ehlo(domain) => {
+                helo = Some(domain);
+                sender = None;
+                recipients.clear();
+
+                let mut extensions = vec![
+                    hostname.to_string(),
+                    format!("SIZE {}", config.max_email_size),
+                    "8BITMIME".to_string(),
+                    "PIPELINING".to_string(),
+                ];
+                if can_starttls {
+                    extensions.push("STARTTLS".to_string());
+                }
+                if can_auth && user.is_none() {
+                    extensions.push("AUTH PLAIN LOGIN".to_string());
+                }
+
+                multiline(250, &extensions)
+            }
+            Command::StartTls if !can_starttls => "502 5.5.1 STARTTLS not available".to_string(),
+            Command::StartTls if helo.is_none() => "503 5.5.1 Send EHLO first".to_string(),
+            Command::StartTls => {
+                // Commands sent along with STARTTLS would be taken as sent
+                // over TLS
+                if !stream.buffer().is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "commands pipelined after STARTTLS",
+                    ));
+                }
+
+                reply(&mut stream, "220 2.0.0 Ready to start TLS").await?;
+                return Ok(Some(stream.into_inner()));
+            }
+            Command::Auth(..) if mode != Mode::Submission => {
+                "502 5.5.1 AUTH not available".to_string()
+            }
+            Command::Auth(..) if !can_auth => {
+                "530 5.7.0 Must issue a STARTTLS command first".to_string()
+            }
+            Command::Auth(..) if helo.is_none() => "503 5.5.1 Send EHLO first".to_string(),
+            Command::Auth(..) if user.is_some() => "503 5.5.1 Already authenticated".to_string(),
+            Command::Auth(..) if sender.is_some() => {
+                "503 5.5.1 Mail transaction in progress".to_string()
+            }
+            Command::Auth(mechanism, initial) => {
+                let (address, pass) =
+                    match read_credentials(&mut stream, &mechanism, initial).await? {
+                        Ok(Some(credentials)) => credentials,
+                        Ok(None) => {
+                            reply(&mut stream, "501 5.5.2 Invalid authentication response").await?;
+                            continue;
+                        }
+                        Err(response) => {
+                            reply(&mut stream, response).await?;
+                            continue;
+                        }
+                    };
+
+                match authenticate(&address, pass, pipeline).await {
+                    Ok(true) => {
+                        log::info!("SMTP client logged in as {}", address);
+                        user = Some(address);
+
+                        "235 2.7.0 Authentication successful".to_string()
+                    }
+                    Ok(false) => {
+                        log::warn!("Failed SMTP login as {}", address);
+                        auth_failures += 1;
+
+                        tokio::time::delay_for(Duration::from_secs(AUTH_FAILURE_DELAY)).await;
+
+                        if auth_failures >= MAX_AUTH_FAILURES {
+                            reply(&mut stream, "421 4.7.0 Too many failed logins").await?;
+                            return Ok(None);
+                        }
+
+                        "535 5.7.8 Authentication credentials invalid".to_string()
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to check SMTP login as {}: {}", address, e);
+                        "454 4.7.0 Temporary authentication failure".to_string()
+                    }
+                }
+            }
+            Command::Mail(_) if helo.is_none() => "503 5.5.1 Send HELO or EHLO first".to_string(),
+            Command::Mail(_) if mode == Mode::Submission && user.is_none() => {
+                "530 5.7.0 Authentication required".to_string()
+            }
+            Command::Mail(_) if sender.is_some() => "503 5.5.1 Sender already given".to_string(),
+            Command::Mail(_) if filters::MAINTENANCE_MODE.load(Ordering::SeqCst) => {
+                "451 4.3.2 Service paused, try again later".to_string()
+            }
+            Command::Mail(address) => {
+                sender = Some(address);
+                recipients.clear();
+
+                "250 2.1.0 OK".to_string()
+            }
+            Command::Rcpt(_) if sender.is_none() => "503 5.5.1 Send MAIL first".to_string(),
+            Command::Rcpt(_) if recipients.len() >= MAX_RECIPIENTS => {
+                "452 4.5.3 Too many recipients".to_string()
+            }
+            Command::Rcpt(address)
+                if mode == Mode::Relay && !accepts(&config.smtp_domains, &address) =>
+            {
+                "550 5.7.1 Relaying denied".to_string()
+            }
+            Command::Rcpt(address)
+                if mode == Mode::Submission && !is_user(user.as_deref(), &address) =>
+            {
+                "550 5.7.1 Mail may only be submitted to the address logged in as".to_string()
+            }
+            Command::Rcpt(address) => {
+                recipients.push(address);
+
+                "250 2.1.5 OK".to_string()
+            }
+            Command::Data if recipients.is_empty() => "503 5.5.1 Send RCPT first".to_string(),
+            Command::Data => {
+                reply(&mut stream, "354 End data with <CR><LF>.<CR><LF>").await?;
+
+                let data = read_data(&mut stream, config.max_email_size).await?;
+                let sender = sender.take().unwrap_or_default();
+                let recipients = std::mem::take(&mut recipients);
+
+                match data {
+                    Some(data) => deliver(sender, recipients, data, user.clone(), pipeline).await,
+                    None => "552 5.3.4 Message size exceeds fixed limit".to_string(),
+                }
+            }
+            Command::Rset => {
+                sender = None;
+                recipients.clear();
+
+                "250 2.0.0 OK".to_string()
+            }
+            Command::Noop => "250 2.0.0 OK".to_string(),
+            Command::Quit => {
+                reply(&mut stream, "221 2.0.0 Bye").await?;
+                return Ok(None);
+            }
+            Command::Invalid => "501 5.5.4 Syntax error in parameters".to_string(),
+            Command::Unknown => "500 5.5.2 Command not recognized".to_string(),
+        };
+
+        reply(&mut stream, &response).await?;
+    }
+}
+
+/// Read the user and pass of an AUTH exchange
+///
+/// Returns None if the client cancelled the exchange or sent a response
+/// that cannot be decoded, and the reply to send if the mechanism is not
+/// supported.
+async fn read_credentials<S: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    mechanism: &str,
+    initial: Option<String>,
+) -> io::Result<Result<Option<(String, String)>, &'static str>> {
+    match mechanism {
+        "PLAIN" => {
+            let response = match initial {
+                Some(response) => response,
+                None => challenge(stream, "").await?,
+            };
+
+            Ok(Ok(decode_plain(&response)))
+        }
+        "LOGIN" => {
+            let user = match initial {
+                Some(response) => response,
+                None => challenge(stream, "Username:").await?,
+            };
+            let user = match decode_response(&user) {
+                Some(user) => user,
+                None => return Ok(Ok(None)),
+            };
+
+            let pass = challenge(stream, "Password:").await?;
+
+            Ok(Ok(decode_response(&pass).map(|pass| (user, pass))))
+        }
+        _ => Ok(Err("504 5.5.4 Unrecognized authentication type")),
+    }
+}
+
+/// Send an AUTH challenge, and read the response
+async fn challenge<S: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    prompt: &str,
+) -> io::Result<String> {
+    reply(stream, &format!("334 {}", base64::encode(prompt))).await?;
+
+    let mut line = Vec::new();
+    if read_line(stream, &mut line).await? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed during AUTH",
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&line).trim().to_string())
+}
+
+/// Check the submission password of an address
+async fn authenticate(
+    address: &str,
+    pass: String,
+    pipeline: &Pipeline,
+) -> Result<bool, vaulty::Error> {
+    let mut db = pipeline.db.clone();
+    let mut db_client = vaulty::db::Client::new(&mut db);
+
+    match db_client.get_submission_password(address).await? {
+        Some(hash) => auth::verify_password(&hash, pass).await,
+        None => Ok(false),
+    }
+}
+
+/// Run a received email through the pipeline, and reply with the outcome
+///
+/// `submitted_by` is the address the client logged in as, if any.
+async fn deliver(
+    sender: String,
+    recipients: Vec<String>,
+    data: Vec<u8>,
+    submitted_by: Option<String>,
+    pipeline: &Pipeline,
+) -> String {
+    // Bounces are ignored, as they are by the filter
+    if sender.is_empty() {
+        log::warn!("Received a bounced email notification... ignoring");
+        return "250 2.0.0 OK".to_string();
+    }
+
+    let mut email = match vaulty::email::Email::from_mime(&data) {
+        Ok(email) => email.with_sender(sender).with_recipients(recipients),
+        Err(e) => {
+            log::warn!("Failed to parse email received over SMTP: {}", e);
+            return "554 5.6.0 Failed to parse mail body".to_string();
+        }
+    };
+
+    email.submitted_by = submitted_by;
+
+    let uuid = email.uuid;
+    let submitted_by = email.submitted_by.clone();
+    let pipeline = pipeline.clone();
+
+    let result = controllers::postfix::deliver(
+        email,
+        Some(&data),
+        pipeline.db,
+        pipeline.sessions,
+        pipeline.limits,
+        pipeline.rate_limiter,
+        pipeline.config,
+    )
+    .await;
+
+    match result {
+        Ok(_) => {
+            match submitted_by {
+                Some(user) => log::info!("Email {} submitted over SMTP by {}", uuid, user),
+                None => log::info!("Email {} received over SMTP", uuid),
+            }
+            format!("250 2.0.0 OK: queued as {}", uuid)
+        }
+        Err(e) => {
+            log::warn!("Rejecting email {} received over SMTP: {}", uuid, e);
+
+            // The ID lets the sender refer to the email when asking about it
+            format!("{} (email {})", error_reply(&e), uuid)
+        }
+    }
+}
+
+async fn reply<W: AsyncWrite + Unpin>(writer: &mut W, response: &str) -> io::Result<()> {
+    writer.write_all(response.as_bytes()).await?;
+    writer.write_all(b"\r\n").await?;
+    writer.flush().await
+}
+
+/// Read a single line, including its line ending
+///
+/// Returns 0 if the client closed the connection.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+) -> io::Result<usize> {
+    line.clear();
+
+    let mut limited = (&mut *reader).take(MAX_LINE_LEN);
+    let read = limited.read_until(b'\n', line);
+
+    tokio::time::timeout(Duration::from_secs(READ_TIMEOUT), read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "client timed out"))?
+}
+
+/// Read message data up to the line with a single `.`, undoing
+/// dot-stuffing
+///
+/// Returns None if the message is larger than `max_size`. The rest of the
+/// message is still read, so that the session can go on.
+async fn read_data<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_size: u64,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    let mut line = Vec::new();
+    let mut too_big = false;
+
+    loop {
+        if read_line(reader, &mut line).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed during DATA",
+            ));
+        }
+
+        if line.as_slice() == b".\r\n" || line.as_slice() == b".\n" {
+            break;
+        }
+
+        let content = if line.starts_with(b".") {
+            &line[1..]
+        } else {
+            &line[..]
+        };
+
+        if too_big || (data.len() + content.len()) as u64 > max_size {
+            too_big = true;
+            data.clear();
+            continue;
+        }
+
+        data.extend_from_slice(content);
+    }
+
+    Ok(if too_big { None } else { Some(data) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            Command::parse("EHLO mail.example.org\r\n"),
+            Command::Ehlo("mail.example.org".to_string())
+        );
+        assert_eq!(Command::parse("HELO\r\n"), Command::Invalid);
+        assert_eq!(
+            Command::parse("MAIL FROM:<jane@example.org> SIZE=1024\r\n"),
+            Command::Mail("jane@example.org".to_string())
+        );
+        assert_eq!(
+            Command::parse("mail from: <>\r\n"),
+            Command::Mail("".to_string())
+        );
+        assert_eq!(
+            Command::parse("RCPT TO:<@relay.example.org:test1@vaulty.net>\r\n"),
+            Command::Rcpt("test1@vaulty.net".to_string())
+        );
+        assert_eq!(
+            Command::parse("RCPT TO:test1@vaulty.net\r\n"),
+            Command::Invalid
+        );
+        assert_eq!(Command::parse("data\r\n"), Command::Data);
+        assert_eq!(Command::parse("VRFY test1\r\n"), Command::Unknown);
+        assert_eq!(Command::parse("STARTTLS\r\n"), Command::StartTls);
+        assert_eq!(Command::parse("STARTTLS now\r\n"), Command::Invalid);
+        assert_eq!(
+            Command::parse("auth plain AHRlc3QxQHZhdWx0eS5uZXQAc2VjcmV0\r\n"),
+            Command::Auth(
+                "PLAIN".to_string(),
+                Some("AHRlc3QxQHZhdWx0eS5uZXQAc2VjcmV0".to_string())
+            )
+        );
+        assert_eq!(
+            Command::parse("AUTH LOGIN\r\n"),
+            Command::Auth("LOGIN".to_string(), None)
+        );
+        assert_eq!(Command::parse("AUTH\r\n"), Command::Invalid);
+
+        let domains = vec!["vaulty.net".to_string()];
+        assert!(accepts(&domains, "test1@Vaulty.net"));
+        assert!(!accepts(&domains, "test1@example.org"));
+        assert!(!accepts(&domains, "vaulty.net"));
+
+        assert!(is_user(Some("scanner@vaulty.net"), "Scanner@vaulty.net"));
+        assert!(!is_user(Some("scanner@vaulty.net"), "test1@vaulty.net"));
+        assert!(!is_user(None, "test1@vaulty.net"));
+    }
+
+    #[test]
+    fn decode_credentials() {
+        let plain = |s: &str| base64::encode(s);
+
+        assert_eq!(
+            decode_plain(&plain("\0scanner@vaulty.net\0p4ss")),
+            Some(("scanner@vaulty.net".to_string(), "p4ss".to_string()))
+        );
+        assert_eq!(
+            decode_plain(&plain("scanner@vaulty.net\0scanner@vaulty.net\0p4ss")),
+            Some(("scanner@vaulty.net".to_string(), "p4ss".to_string()))
+        );
+
+        // Logging in on behalf of another address
+        assert_eq!(
+            decode_plain(&plain("test1@vaulty.net\0scanner@vaulty.net\0p4ss")),
+            None
+        );
+        assert_eq!(decode_plain(&plain("scanner@vaulty.net")), None);
+        assert_eq!(decode_plain("not-base64!"), None);
+        assert_eq!(decode_plain("*"), None);
+
+        assert_eq!(decode_response(&plain("p4ss")), Some("p4ss".to_string()));
+        assert_eq!(decode_response("="), Some("".to_string()));
+        assert_eq!(decode_response("*"), None);
+    }
+
+    #[test]
+    fn multiline_replies() {
+        let lines = vec!["vaulty.net".to_string(), "STARTTLS".to_string()];
+        assert_eq!(multiline(250, &lines), "250-vaulty.net\r\n250 STARTTLS");
+        assert_eq!(multiline(250, &lines[..1]), "250 vaulty.net");
+    }
+
+    #[test]
+    fn db_error_replies() {
+        let not_found = vaulty::Error::Database(vaulty::db::Error::NotFound);
+        assert!(error_reply(&not_found).starts_with("550 5.1.1 "));
+
+        let down = vaulty::Error::Database(vaulty::db::Error::ConnectionFailed(
+            "connection refused".into(),
+        ));
+        assert_eq!(
+            error_reply(&down),
+            "451 4.3.0 Temporary failure, try again later"
+        );
+    }
+
+    #[tokio::test]
+    async fn read_message_data() {
+        let mut input: &[u8] = b"Subject: Test\r\n\r\n..hidden dot\r\n.\r\nQUIT\r\n";
+
+        let data = read_data(&mut input, 1024).await.unwrap();
+        assert_eq!(
+            data.unwrap(),
+            b"Subject: Test\r\n\r\n.hidden dot\r\n".to_vec()
+        );
+
+        // The rest of the session is left to read
+        assert_eq!(input, b"QUIT\r\n");
+
+        let mut input: &[u8] = b"Subject: Test\r\n\r\nToo long\r\n.\r\n";
+        assert!(read_data(&mut input, 16).await.unwrap().is_none());
+
+        let mut input: &[u8] = b"Subject: Test\r\n";
+        assert!(read_data(&mut input, 1024).await.is_err());
+    }
+}
@@ -0,0 +1,339 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::{Buf, Bytes};
+use futures::stream::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use vaulty::config::Config;
+
+/// Extensions of the body of a saved attachment, and of its request
+const DATA_EXT: &str = "data";
+const META_EXT: &str = "json";
+
+/// Request of an attachment saved to the spool
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Meta {
+    pub size: usize,
+    pub content_type: String,
+    pub mail_id: String,
+    pub name: String,
+    pub index: u16,
+    pub sha256: Option<String>,
+}
+
+impl Meta {
+    /// Name the files of this attachment are saved under
+    ///
+    /// The email ID is sent by the client, so it must be a UUID to be used
+    /// in a path.
+    fn key(&self) -> Result<String, vaulty::Error> {
+        let id = uuid::Uuid::parse_str(&self.mail_id).map_err(|_| {
+            vaulty::Error::InvalidRequest(format!("Invalid email ID: {}", self.mail_id))
+        })?;
+
+        Ok(format!("{}-{}", id, self.index))
+    }
+}
+
+/// Attachments of addresses in async delivery mode, saved to disk until they
+/// are stored
+///
+/// An attachment is only acknowledged once it and its request are saved,
+/// so that it is stored even if the server stops first: saved attachments
+/// are stored again on startup (see `saved`). The spool holds at most
+/// `spool_max_size` bytes; attachments are stored synchronously instead
+/// while it is full.
+pub struct Spool {
+    dir: PathBuf,
+    max_size: u64,
+    used: AtomicU64,
+
+    /// Attachments being stored in the background, each of which is read
+    /// into memory while it is
+    workers: Semaphore,
+}
+
+impl Spool {
+    pub fn new(dir: PathBuf, max_size: u64, max_workers: usize) -> Self {
+        Self {
+            dir,
+            max_size,
+            used: AtomicU64::new(0),
+            workers: Semaphore::new(max_workers),
+        }
+    }
+
+    /// Open the spool directory, creating it if needed
+    ///
+    /// The spool is disabled (i.e., always full) if the directory cannot be
+    /// used.
+    pub fn from_config(config: &Config) -> Self {
+        let mut max_size = config.spool_max_size;
+
+        if max_size > 0 {
+            if let Err(e) = std::fs::create_dir_all(&config.spool_dir) {
+                log::error!(
+                    "Cannot use spool_dir {}, storing all attachments synchronously: {}",
+                    config.spool_dir,
+                    e
+                );
+                max_size = 0;
+            }
+        }
+
+        Self::new(
+            PathBuf::from(&config.spool_dir),
+            max_size,
+            config.upload_concurrency_max,
+        )
+    }
+
+    fn path(&self, key: &str, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", key, ext))
+    }
+
+    /// Reserve room for an attachment of `size` bytes
+    ///
+    /// Returns false if the spool is full.
+    pub fn reserve(&self, size: usize) -> bool {
+        let size = size as u64;
+        let used = self.used.fetch_add(size, Ordering::SeqCst);
+
+        if used + size > self.max_size {
+            self.release(size as usize);
+            return false;
+        }
+
+        true
+    }
+
+    fn release(&self, size: usize) {
+        self.used.fetch_sub(size as u64, Ordering::SeqCst);
+    }
+
+    /// Save an attachment that room was reserved for
+    ///
+    /// The request is written last, once the body is on disk, so that only
+    /// complete attachments are stored again on startup. The reservation is
+    /// released if saving fails.
+    pub async fn save(
+        &self,
+        meta: &Meta,
+        body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + Sync,
+    ) -> Result<(), vaulty::Error> {
+        let result = self.write(meta, body).await;
+
+        if result.is_err() {
+            self.release(meta.size);
+
+            if let Ok(key) = meta.key() {
+                let _ = tokio::fs::remove_file(self.path(&key, DATA_EXT)).await;
+            }
+        }
+
+        result
+    }
+
+    async fn write(
+        &self,
+        meta: &Meta,
+        body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + Sync,
+    ) -> Result<(), vaulty::Error> {
+        let key = meta.key()?;
+        let io = |e: std::io::Error| vaulty::Error::internal("Failed to save attachment", e);
+
+        let mut file = tokio::fs::File::create(self.path(&key, DATA_EXT))
+            .await
+            .map_err(io)?;
+        let mut written = 0;
+
+        futures::pin_mut!(body);
+        while let Some(mut chunk) = body
+            .try_next()
+            .await
+            .map_err(|e| vaulty::Error::internal("Failed to read request body", e))?
+        {
+            let chunk = chunk.to_bytes();
+            written += chunk.len();
+
+            // The reservation is for the declared size
+            if written > meta.size {
+                return Err(vaulty::Error::InvalidRequest(format!(
+                    "Attachment {} of {} is larger than its declared size of {} bytes",
+                    meta.index, meta.mail_id, meta.size
+                )));
+            }
+
+            file.write_all(&chunk).await.map_err(io)?;
+        }
+
+        file.sync_all().await.map_err(io)?;
+
+        let json = serde_json::to_vec(meta)
+            .map_err(|e| vaulty::Error::internal("Failed to save attachment", e))?;
+        let tmp = self.path(&key, &format!("{}.tmp", META_EXT));
+
+        tokio::fs::write(&tmp, json).await.map_err(io)?;
+        tokio::fs::rename(&tmp, self.path(&key, META_EXT))
+            .await
+            .map_err(io)?;
+
+        Ok(())
+    }
+
+    /// Read the body of a saved attachment
+    pub async fn load(&self, meta: &Meta) -> Result<Bytes, vaulty::Error> {
+        let data = tokio::fs::read(self.path(&meta.key()?, DATA_EXT))
+            .await
+            .map_err(|e| vaulty::Error::internal("Failed to read saved attachment", e))?;
+
+        Ok(Bytes::from(data))
+    }
+
+    /// Remove an attachment once it was handled, and free its room
+    pub async fn remove(&self, meta: &Meta) {
+        let key = match meta.key() {
+            Ok(key) => key,
+            Err(_) => return,
+        };
+
+        for ext in &[META_EXT, DATA_EXT] {
+            let path = self.path(&key, ext);
+
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                log::error!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+
+        self.release(meta.size);
+    }
+
+    /// Wait for a turn to store a saved attachment
+    pub async fn worker(&self) -> SemaphorePermit<'_> {
+        self.workers.acquire().await
+    }
+
+    /// Attachments saved before the server last stopped, which are counted
+    /// against the spool size
+    ///
+    /// Bodies that were not completely saved (i.e., with no request) are
+    /// removed. Only called on startup.
+    pub fn saved(&self) -> Vec<Meta> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!("Failed to read spool_dir {}: {}", self.dir.display(), e);
+                return vec![];
+            }
+        };
+
+        let mut saved = vec![];
+
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let ext = path.extension().and_then(|e| e.to_str());
+
+            let is_partial = match ext {
+                Some(DATA_EXT) => !path.with_extension(META_EXT).exists(),
+                Some("tmp") => true,
+                _ => false,
+            };
+
+            if is_partial {
+                log::warn!("Removing partially saved attachment {}", path.display());
+                let _ = std::fs::remove_file(&path);
+            }
+
+            if ext != Some(META_EXT) {
+                continue;
+            }
+
+            match read_meta(&path) {
+                Some(meta) => {
+                    self.used.fetch_add(meta.size as u64, Ordering::SeqCst);
+                    saved.push(meta);
+                }
+                None => log::error!("Ignoring invalid spooled attachment {}", path.display()),
+            }
+        }
+
+        saved
+    }
+}
+
+fn read_meta(path: &Path) -> Option<Meta> {
+    let json = std::fs::read(path).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::stream;
+
+    fn meta(size: usize) -> Meta {
+        Meta {
+            size,
+            content_type: "text/plain".to_string(),
+            mail_id: uuid::Uuid::new_v4().to_string(),
+            name: "a.txt".to_string(),
+            index: 0,
+            sha256: None,
+        }
+    }
+
+    fn body(data: &'static [u8]) -> impl Stream<Item = Result<Bytes, warp::Error>> + Send + Sync {
+        stream::iter(vec![Ok(Bytes::from_static(data))])
+    }
+
+    #[tokio::test]
+    async fn save_and_replay() {
+        let dir = std::env::temp_dir().join(format!("vaulty-spool-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let spool = Spool::new(dir.clone(), 10, 1);
+        let meta = meta(5);
+
+        assert!(spool.reserve(meta.size));
+        assert!(!spool.reserve(6));
+        spool.save(&meta, body(b"hello")).await.unwrap();
+
+        // Saved attachments are found again after a restart
+        let restarted = Spool::new(dir.clone(), 10, 1);
+        let saved = restarted.saved();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(&restarted.load(&saved[0]).await.unwrap()[..], b"hello");
+        assert!(!restarted.reserve(6));
+
+        restarted.remove(&saved[0]).await;
+        assert!(restarted.reserve(10));
+        assert!(restarted.saved().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_and_invalid() {
+        let dir = std::env::temp_dir().join(format!("vaulty-spool-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let spool = Spool::new(dir.clone(), 10, 1);
+
+        let meta = meta(2);
+        assert!(spool.reserve(meta.size));
+        assert!(spool.save(&meta, body(b"hello")).await.is_err());
+        assert!(spool.reserve(10));
+        spool.release(10);
+
+        let mut meta = meta;
+        meta.mail_id = "../../etc/passwd".to_string();
+        assert!(spool.reserve(meta.size));
+        assert!(spool.save(&meta, body(b"hi")).await.is_err());
+
+        assert!(spool.saved().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use vaulty::config::Config;
+use vaulty::stats::Stats;
+
+use super::lru::{Kind, Lru};
+
+/// Number of period lengths cached at once
+const MAX_ENTRIES: usize = 16;
+
+/// Caches inbound email stats, which scan every email and attachment in the
+/// period they cover
+///
+/// Stats are cached per period length (in days).
+pub struct StatsCache {
+    ttl: Duration,
+    entries: Mutex<Lru<i64, (Arc<Stats>, Instant)>>,
+}
+
+impl StatsCache {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.stats_cache_ttl),
+            entries: Mutex::new(Lru::new(Kind::Stats, MAX_ENTRIES)),
+        }
+    }
+
+    pub fn get(&self, days: i64) -> Option<Arc<Stats>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&days)
+            .filter(|(_, computed)| computed.elapsed() < self.ttl)
+            .map(|(stats, _)| stats.clone())
+    }
+
+    pub fn insert(&self, days: i64, stats: Arc<Stats>) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(days, (stats, Instant::now()));
+    }
+}
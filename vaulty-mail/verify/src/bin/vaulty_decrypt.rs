@@ -0,0 +1,82 @@
+use std::fs;
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use vaulty::crypto::{self, Key};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "vaulty-decrypt",
+    about = "Decrypt a file stored by Vaulty for an address that encrypts its files."
+)]
+struct Opt {
+    /// Hex key of the address
+    #[structopt(short, long, required_unless = "wrapped-key")]
+    key: Option<String>,
+
+    /// Key of the address as stored in the DB (`encryption_key` of
+    /// vaulty_addresses), to be decrypted with the master key
+    #[structopt(long, requires = "master-key-file")]
+    wrapped_key: Option<String>,
+
+    /// File holding the hex `encryption_master_key` of the server
+    #[structopt(long, parse(from_os_str))]
+    master_key_file: Option<PathBuf>,
+
+    /// Encrypted file, as downloaded from storage
+    input: PathBuf,
+
+    /// Where to write the decrypted file
+    output: PathBuf,
+}
+
+/// Key of the address, either given as is, or decrypted with the master key
+///
+/// Keys are only ever decrypted offline, by whoever holds the master key.
+fn address_key(opt: &Opt) -> Result<Key, String> {
+    let (wrapped, path) = match (&opt.key, &opt.wrapped_key, &opt.master_key_file) {
+        (Some(key), _, _) => return Key::from_hex(key).map_err(|e| e.to_string()),
+        (None, Some(wrapped), Some(path)) => (wrapped, path),
+        _ => return Err("Either --key or --wrapped-key is required".to_string()),
+    };
+
+    let master = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let master = Key::from_hex(master.trim()).map_err(|e| e.to_string())?;
+
+    Key::unwrap(&master, wrapped).map_err(|e| e.to_string())
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    let key = match address_key(&opt) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let data = match fs::read(&opt.input) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", opt.input.display(), e);
+            std::process::exit(2);
+        }
+    };
+
+    let decrypted = match crypto::decrypt(&key, &data) {
+        Ok(decrypted) => decrypted,
+        Err(e) => {
+            eprintln!("Failed to decrypt {}: {}", opt.input.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = fs::write(&opt.output, decrypted) {
+        eprintln!("Failed to write {}: {}", opt.output.display(), e);
+        std::process::exit(2);
+    }
+}
@@ -0,0 +1,206 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use structopt::StructOpt;
+
+use vaulty::crypto::{self, Key};
+use vaulty::email::ContentHasher;
+use vaulty::manifest::{self, Manifest, ManifestSignature};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "vaulty-verify",
+    about = "Check the files in a Vaulty storage folder against their signed manifests."
+)]
+struct Opt {
+    /// Hex public key of the Vaulty server, as logged at startup
+    #[structopt(short, long)]
+    key: String,
+
+    /// Hex encryption key of the address, if it encrypts its files (see
+    /// vaulty-decrypt). Files are checked as they were before they were
+    /// encrypted.
+    #[structopt(long)]
+    encryption_key: Option<String>,
+
+    /// Local copy of the storage path of the address
+    folder: PathBuf,
+}
+
+/// Problem with a single file listed in a manifest
+enum FileError {
+    Missing,
+    Modified,
+    Unreadable(io::Error),
+}
+
+/// Find all manifests under a folder
+fn find_manifests(dir: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            find_manifests(&path, found)?;
+        } else if path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map_or(false, |n| n.ends_with(manifest::MANIFEST_SUFFIX))
+        {
+            found.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn hash_file(path: &Path, key: Option<&Key>) -> io::Result<(u64, String)> {
+    if let Some(key) = key {
+        let mut data = fs::read(path)?;
+        if crypto::is_encrypted(&data) {
+            data = crypto::decrypt(key, &data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        }
+
+        let mut hasher = ContentHasher::default();
+        hasher.update(&data);
+
+        return Ok((data.len() as u64, hasher.finish()));
+    }
+
+    let mut file = fs::File::open(path)?;
+    let mut hasher = ContentHasher::default();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+
+    Ok((size, hasher.finish()))
+}
+
+/// Check a single file listed in a manifest
+///
+/// Paths are relative to the storage path of the address. If the folder is
+/// a subfolder of it instead, the file is looked up next to the manifest.
+fn check_file(
+    root: &Path,
+    manifest_path: &Path,
+    file: &manifest::File,
+    key: Option<&Key>,
+) -> Result<(), FileError> {
+    let mut path = root.join(&file.path);
+
+    if !path.is_file() {
+        let name = Path::new(&file.path).file_name().unwrap_or_default();
+        path = manifest_path.with_file_name(name);
+    }
+
+    if !path.is_file() {
+        return Err(FileError::Missing);
+    }
+
+    let (size, sha256) = hash_file(&path, key).map_err(FileError::Unreadable)?;
+
+    if size != file.size || sha256 != file.sha256 {
+        return Err(FileError::Modified);
+    }
+
+    Ok(())
+}
+
+/// Check the signature of a manifest
+fn check_manifest(path: &Path, key: &str) -> Result<Manifest, String> {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let signature_path =
+        path.with_file_name(name.replace(manifest::MANIFEST_SUFFIX, manifest::SIGNATURE_SUFFIX));
+
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let signature =
+        fs::read(&signature_path).map_err(|e| format!("Failed to read signature: {}", e))?;
+    let signature: ManifestSignature =
+        serde_json::from_slice(&signature).map_err(|e| format!("Invalid signature: {}", e))?;
+
+    manifest::verify(&bytes, &signature, key).map_err(|e| e.to_string())
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    let encryption_key = match opt.encryption_key.as_deref().map(Key::from_hex) {
+        Some(Ok(key)) => Some(key),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        None => None,
+    };
+
+    let mut manifests = Vec::new();
+    if let Err(e) = find_manifests(&opt.folder, &mut manifests) {
+        eprintln!("Failed to read {}: {}", opt.folder.display(), e);
+        std::process::exit(2);
+    }
+    manifests.sort();
+
+    let mut num_failed = 0;
+
+    for path in &manifests {
+        let manifest = match check_manifest(path, &opt.key) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                println!("FAILED {}: {}", path.display(), e);
+                num_failed += 1;
+                continue;
+            }
+        };
+
+        let mut errors = Vec::new();
+
+        for file in &manifest.files {
+            match check_file(&opt.folder, path, file, encryption_key.as_ref()) {
+                Ok(()) => (),
+                Err(FileError::Missing) => errors.push(format!("missing {}", file.path)),
+                Err(FileError::Modified) => errors.push(format!("modified {}", file.path)),
+                Err(FileError::Unreadable(e)) => {
+                    errors.push(format!("unreadable {}: {}", file.path, e))
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            println!(
+                "OK {}: {} file(s) from {} to {}, signed at {}",
+                path.display(),
+                manifest.files.len(),
+                manifest.sender,
+                manifest.recipient,
+                manifest.signed_at
+            );
+        } else {
+            println!("FAILED {}:", path.display());
+            for error in errors {
+                println!("  {}", error);
+            }
+            num_failed += 1;
+        }
+    }
+
+    println!(
+        "Checked {} manifest(s): {} OK, {} failed",
+        manifests.len(),
+        manifests.len() - num_failed,
+        num_failed
+    );
+
+    if num_failed > 0 {
+        std::process::exit(1);
+    }
+}
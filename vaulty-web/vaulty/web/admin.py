@@ -1,7 +1,10 @@
 from django.contrib import admin
 from django.contrib.auth.admin import UserAdmin
 
-from .models import Address, Alias, Attachment, Mail, User, LaunchMailingList
+from .models import (
+    Address, Alias, ApiUser, Attachment, AttachmentRule, Domain, IndexedFile,
+    Mail, PriorityRule, Sample, StorageRoute, User, LaunchMailingList, Webhook,
+)
 
 
 class AddressAdmin(admin.ModelAdmin):
@@ -13,20 +16,38 @@ class AddressAdmin(admin.ModelAdmin):
     )
     list_filter = ("is_active", "is_whitelist_enabled")
 
+    def save_model(self, request, obj, form, change):
+        # The submission password is entered in plain text; hash it before
+        # saving
+        if "submission_password" in form.changed_data:
+            obj.set_submission_password(form.cleaned_data["submission_password"])
+        super().save_model(request, obj, form, change)
+
+
+class DomainAdmin(admin.ModelAdmin):
+    list_display = (
+        "domain", "email_quota", "storage_quota", "max_email_size",
+        "storage_backend", "reply_on_success", "reply_on_rejection",
+        "skip_unchanged", "dedup_attachments", "transliterate_filenames",
+        "store_body", "archive_eml", "archive_links", "body_pdf",
+        "sign_manifests", "auto_generated_policy", "delivery_mode",
+    )
+
 
 class MailAdmin(admin.ModelAdmin):
     list_display = (
         "user", "address", "message_id", "num_attachments",
-        "total_size", "status", "creation_time",
+        "total_size", "importance", "is_priority", "is_test", "status", "creation_time",
     )
-    list_filter = ("status", )
+    list_filter = ("status", "is_priority", "is_test", "is_bundle")
 
 
 class AttachmentAdmin(admin.ModelAdmin):
     list_display = (
-        "mail", "size", "index", "status", "error_msg", "creation_time",
+        "mail", "name", "size", "index", "storage_path", "status", "is_duplicate",
+        "error_msg", "creation_time",
     )
-    list_filter = ("status", )
+    list_filter = ("status", "is_duplicate")
 
 
 class AliasAdmin(admin.ModelAdmin):
@@ -39,6 +60,53 @@ class AliasAdmin(admin.ModelAdmin):
     list_filter = ("is_active", )
 
 
+class IndexedFileAdmin(admin.ModelAdmin):
+    list_display = ("address", "path", "size", "content_hash", "creation_time")
+
+
+class AttachmentRuleAdmin(admin.ModelAdmin):
+    list_display = ("address", "action", "extension", "mime_type", "larger_than")
+    list_filter = ("action", )
+
+
+class PriorityRuleAdmin(admin.ModelAdmin):
+    list_display = ("address", "importance", "subject_tag", "folder", "notify")
+    list_filter = ("importance", "notify")
+
+
+class StorageRouteAdmin(admin.ModelAdmin):
+    list_display = (
+        "address", "extension", "mime_type", "larger_than",
+        "storage_backend", "storage_path",
+    )
+    list_filter = ("storage_backend", )
+
+
+class WebhookAdmin(admin.ModelAdmin):
+    list_display = (
+        "address", "url", "format", "on_received", "on_success", "on_rejection",
+        "on_attachment", "on_attachment_received", "on_alert",
+    )
+    list_filter = ("format", )
+
+
+class SampleAdmin(admin.ModelAdmin):
+    date_hierarchy = "creation_time"
+    list_display = ("content_hash", "sender", "recipient", "reason", "creation_time")
+    list_filter = ("reason", )
+
+
+class ApiUserAdmin(admin.ModelAdmin):
+    list_display = ("username", "description", "is_active", "creation_time")
+    list_filter = ("is_active", )
+
+    def save_model(self, request, obj, form, change):
+        # The password is entered in plain text; hash it before saving
+        if "password" in form.changed_data:
+            obj.set_password(form.cleaned_data["password"])
+        super().save_model(request, obj, form, change)
+
+
 class LaunchMailingListAdmin(admin.ModelAdmin):
     date_hierarchy = "creation_time"
 
@@ -46,7 +114,15 @@ class LaunchMailingListAdmin(admin.ModelAdmin):
 # Register models in admin
 admin.site.register(User, UserAdmin)
 admin.site.register(Address, AddressAdmin)
+admin.site.register(Domain, DomainAdmin)
 admin.site.register(Mail, MailAdmin)
 admin.site.register(Attachment, AttachmentAdmin)
+admin.site.register(IndexedFile, IndexedFileAdmin)
+admin.site.register(AttachmentRule, AttachmentRuleAdmin)
+admin.site.register(PriorityRule, PriorityRuleAdmin)
+admin.site.register(StorageRoute, StorageRouteAdmin)
+admin.site.register(Webhook, WebhookAdmin)
 admin.site.register(Alias, AliasAdmin)
+admin.site.register(Sample, SampleAdmin)
+admin.site.register(ApiUser, ApiUserAdmin)
 admin.site.register(LaunchMailingList, LaunchMailingListAdmin)
@@ -0,0 +1,53 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0002_create_superuser'),
+    ]
+
+    operations = [
+        migrations.CreateModel(
+            name='Domain',
+            fields=[
+                ('id', models.AutoField(auto_created=True, primary_key=True, serialize=False, verbose_name='ID')),
+                ('domain', models.CharField(max_length=255, unique=True)),
+                ('email_quota', models.IntegerField(blank=True, null=True)),
+                ('max_email_size', models.IntegerField(blank=True, null=True)),
+                ('storage_quota', models.BigIntegerField(blank=True, null=True)),
+                ('storage_backend', models.CharField(blank=True, choices=[('dropbox', 'Dropbox'), ('gdrive', 'Gdrive'), ('s3', 'S3')], max_length=30, null=True)),
+                ('reply_on_success', models.BooleanField(blank=True, null=True)),
+                ('last_update_time', models.DateTimeField(auto_now=True)),
+                ('creation_time', models.DateTimeField(auto_now_add=True)),
+            ],
+            options={
+                'db_table': 'vaulty_domains',
+            },
+        ),
+        migrations.AlterField(
+            model_name='address',
+            name='email_quota',
+            field=models.IntegerField(blank=True, null=True),
+        ),
+        migrations.AlterField(
+            model_name='address',
+            name='max_email_size',
+            field=models.IntegerField(blank=True, null=True),
+        ),
+        migrations.AlterField(
+            model_name='address',
+            name='storage_quota',
+            field=models.BigIntegerField(blank=True, null=True),
+        ),
+        migrations.AlterField(
+            model_name='address',
+            name='storage_backend',
+            field=models.CharField(blank=True, choices=[('dropbox', 'Dropbox'), ('gdrive', 'Gdrive'), ('s3', 'S3')], max_length=30, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='reply_on_success',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+    ]
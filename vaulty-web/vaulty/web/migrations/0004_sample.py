@@ -0,0 +1,27 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0003_domain_settings'),
+    ]
+
+    operations = [
+        migrations.CreateModel(
+            name='Sample',
+            fields=[
+                ('id', models.AutoField(auto_created=True, primary_key=True, serialize=False, verbose_name='ID')),
+                ('content_hash', models.CharField(db_index=True, max_length=64)),
+                ('sample', models.TextField()),
+                ('sender', models.CharField(max_length=512)),
+                ('recipient', models.TextField()),
+                ('subject', models.TextField(null=True)),
+                ('reason', models.CharField(max_length=64)),
+                ('creation_time', models.DateTimeField(auto_now_add=True)),
+            ],
+            options={
+                'db_table': 'vaulty_samples',
+            },
+        ),
+    ]
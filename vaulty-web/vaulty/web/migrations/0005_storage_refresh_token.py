@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0004_sample'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='address',
+            name='storage_refresh_token',
+            field=models.CharField(blank=True, max_length=1000, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='storage_token_expiry',
+            field=models.DateTimeField(blank=True, null=True),
+        ),
+    ]
@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0006_skip_unchanged'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='address',
+            name='dropbox_namespace_id',
+            field=models.CharField(blank=True, max_length=255, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='dropbox_team_member_id',
+            field=models.CharField(blank=True, max_length=255, null=True),
+        ),
+    ]
@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0007_dropbox_team'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='domain',
+            name='transliterate_filenames',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='transliterate_filenames',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+    ]
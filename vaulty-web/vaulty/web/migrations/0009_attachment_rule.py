@@ -0,0 +1,27 @@
+from django.db import migrations, models
+import django.db.models.deletion
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0008_transliterate_filenames'),
+    ]
+
+    operations = [
+        migrations.CreateModel(
+            name='AttachmentRule',
+            fields=[
+                ('id', models.AutoField(auto_created=True, primary_key=True, serialize=False, verbose_name='ID')),
+                ('action', models.CharField(choices=[('allow', 'Allow'), ('deny', 'Deny')], max_length=10)),
+                ('extension', models.CharField(blank=True, max_length=255, null=True)),
+                ('mime_type', models.CharField(blank=True, max_length=255, null=True)),
+                ('larger_than', models.BigIntegerField(blank=True, null=True)),
+                ('creation_time', models.DateTimeField(auto_now_add=True)),
+                ('address', models.ForeignKey(on_delete=django.db.models.deletion.CASCADE, to='web.Address')),
+            ],
+            options={
+                'db_table': 'vaulty_attachment_rules',
+            },
+        ),
+    ]
@@ -0,0 +1,27 @@
+from django.db import migrations, models
+import django.db.models.deletion
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0009_attachment_rule'),
+    ]
+
+    operations = [
+        migrations.CreateModel(
+            name='Webhook',
+            fields=[
+                ('id', models.AutoField(auto_created=True, primary_key=True, serialize=False, verbose_name='ID')),
+                ('url', models.CharField(max_length=1000)),
+                ('format', models.CharField(choices=[('json', 'Json'), ('slack', 'Slack'), ('discord', 'Discord')], default='json', max_length=30)),
+                ('on_success', models.BooleanField(default=False)),
+                ('on_rejection', models.BooleanField(default=True)),
+                ('creation_time', models.DateTimeField(auto_now_add=True)),
+                ('address', models.ForeignKey(on_delete=django.db.models.deletion.CASCADE, to='web.Address')),
+            ],
+            options={
+                'db_table': 'vaulty_webhooks',
+            },
+        ),
+    ]
@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0010_webhook'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='webhook',
+            name='on_received',
+            field=models.BooleanField(default=False),
+        ),
+        migrations.AddField(
+            model_name='webhook',
+            name='template',
+            field=models.TextField(blank=True, null=True),
+        ),
+    ]
@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0011_webhook_template'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='domain',
+            name='store_body',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='store_body',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+    ]
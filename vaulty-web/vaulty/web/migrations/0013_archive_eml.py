@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0012_store_body'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='domain',
+            name='archive_eml',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='archive_eml',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+    ]
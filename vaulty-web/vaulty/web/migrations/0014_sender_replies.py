@@ -0,0 +1,31 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0013_archive_eml'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='domain',
+            name='reply_on_rejection',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='reply_on_rejection',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='reply_success_template',
+            field=models.TextField(blank=True, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='reply_rejection_template',
+            field=models.TextField(blank=True, null=True),
+        ),
+    ]
@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0014_sender_replies'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='domain',
+            name='auto_generated_policy',
+            field=models.CharField(blank=True, choices=[('store', 'Store'), ('ignore', 'Ignore'), ('reject', 'Reject')], max_length=30, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='auto_generated_policy',
+            field=models.CharField(blank=True, choices=[('store', 'Store'), ('ignore', 'Ignore'), ('reject', 'Reject')], max_length=30, null=True),
+        ),
+    ]
@@ -0,0 +1,27 @@
+import django.core.validators
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0015_auto_generated_policy'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='webhook',
+            name='on_attachment',
+            field=models.BooleanField(default=False),
+        ),
+        migrations.AddField(
+            model_name='webhook',
+            name='secret',
+            field=models.CharField(blank=True, max_length=255, null=True),
+        ),
+        migrations.AlterField(
+            model_name='webhook',
+            name='url',
+            field=models.CharField(max_length=1000, validators=[django.core.validators.URLValidator(schemes=['https'])]),
+        ),
+    ]
@@ -0,0 +1,26 @@
+import django.db.models.deletion
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0016_webhook_events'),
+    ]
+
+    operations = [
+        migrations.CreateModel(
+            name='Change',
+            fields=[
+                ('id', models.BigAutoField(primary_key=True, serialize=False)),
+                ('kind', models.CharField(max_length=32)),
+                ('index', models.IntegerField(null=True)),
+                ('error_msg', models.TextField(null=True)),
+                ('creation_time', models.DateTimeField(auto_now_add=True, db_index=True)),
+                ('mail', models.ForeignKey(on_delete=django.db.models.deletion.CASCADE, to='web.Mail')),
+            ],
+            options={
+                'db_table': 'vaulty_changes',
+            },
+        ),
+    ]
@@ -0,0 +1,25 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0017_change'),
+    ]
+
+    operations = [
+        migrations.CreateModel(
+            name='ApiUser',
+            fields=[
+                ('id', models.AutoField(auto_created=True, primary_key=True, serialize=False, verbose_name='ID')),
+                ('username', models.CharField(max_length=150, unique=True)),
+                ('password', models.CharField(max_length=256)),
+                ('description', models.TextField(blank=True, default='')),
+                ('is_active', models.BooleanField(default=True)),
+                ('creation_time', models.DateTimeField(auto_now_add=True)),
+            ],
+            options={
+                'db_table': 'vaulty_api_users',
+            },
+        ),
+    ]
@@ -0,0 +1,26 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0018_api_user'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='domain',
+            name='archive_links',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='archive_links',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='link_archive_pattern',
+            field=models.TextField(blank=True, null=True),
+        ),
+    ]
@@ -0,0 +1,16 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0019_archive_links'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='attachment',
+            name='mime_type',
+            field=models.CharField(max_length=255, null=True),
+        ),
+    ]
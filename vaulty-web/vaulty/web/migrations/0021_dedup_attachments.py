@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0020_attachment_mime_type'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='domain',
+            name='dedup_attachments',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='dedup_attachments',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+    ]
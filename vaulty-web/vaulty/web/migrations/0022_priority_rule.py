@@ -0,0 +1,42 @@
+from django.db import migrations, models
+import django.db.models.deletion
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0021_dedup_attachments'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='mail',
+            name='importance',
+            field=models.CharField(max_length=10, null=True),
+        ),
+        migrations.AddField(
+            model_name='mail',
+            name='is_priority',
+            field=models.BooleanField(default=False),
+        ),
+        migrations.AddField(
+            model_name='mail',
+            name='priority_folder',
+            field=models.CharField(max_length=255, null=True),
+        ),
+        migrations.CreateModel(
+            name='PriorityRule',
+            fields=[
+                ('id', models.AutoField(auto_created=True, primary_key=True, serialize=False, verbose_name='ID')),
+                ('importance', models.CharField(blank=True, choices=[('high', 'High'), ('normal', 'Normal'), ('low', 'Low')], max_length=10, null=True)),
+                ('subject_tag', models.CharField(blank=True, max_length=255, null=True)),
+                ('folder', models.CharField(blank=True, max_length=255, null=True)),
+                ('notify', models.BooleanField(default=False)),
+                ('creation_time', models.DateTimeField(auto_now_add=True)),
+                ('address', models.ForeignKey(on_delete=django.db.models.deletion.CASCADE, to='web.Address')),
+            ],
+            options={
+                'db_table': 'vaulty_priority_rules',
+            },
+        ),
+    ]
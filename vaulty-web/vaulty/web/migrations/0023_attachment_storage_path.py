@@ -0,0 +1,29 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0022_priority_rule'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='attachment',
+            name='storage_path',
+            field=models.CharField(max_length=2000, null=True),
+        ),
+        # Retried attachments used to get a row per attempt; keep the latest
+        migrations.RunSQL(
+            """
+            DELETE FROM vaulty_attachments a
+            USING vaulty_attachments b
+            WHERE a.mail_id = b.mail_id AND a.index = b.index AND a.id < b.id
+            """,
+            migrations.RunSQL.noop,
+        ),
+        migrations.AlterUniqueTogether(
+            name='attachment',
+            unique_together={('mail', 'index')},
+        ),
+    ]
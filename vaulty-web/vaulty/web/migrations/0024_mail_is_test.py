@@ -0,0 +1,16 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0023_attachment_storage_path'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='mail',
+            name='is_test',
+            field=models.BooleanField(default=False),
+        ),
+    ]
@@ -0,0 +1,16 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0024_mail_is_test'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='webhook',
+            name='on_alert',
+            field=models.BooleanField(default=True),
+        ),
+    ]
@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0025_webhook_on_alert'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='mail',
+            name='directive_folder',
+            field=models.CharField(max_length=255, null=True),
+        ),
+        migrations.AddField(
+            model_name='mail',
+            name='directive_notify',
+            field=models.BooleanField(null=True),
+        ),
+    ]
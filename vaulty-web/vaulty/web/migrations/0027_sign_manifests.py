@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0026_mail_directives'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='domain',
+            name='sign_manifests',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='sign_manifests',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+    ]
@@ -0,0 +1,17 @@
+import django.contrib.postgres.fields
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0027_sign_manifests'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='address',
+            name='pipeline_stages',
+            field=django.contrib.postgres.fields.ArrayField(base_field=models.CharField(max_length=100), blank=True, null=True, size=None),
+        ),
+    ]
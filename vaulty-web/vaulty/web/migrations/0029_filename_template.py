@@ -0,0 +1,16 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0028_pipeline_stages'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='address',
+            name='filename_template',
+            field=models.TextField(blank=True, null=True),
+        ),
+    ]
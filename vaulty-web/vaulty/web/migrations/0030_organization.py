@@ -0,0 +1,16 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0029_filename_template'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='address',
+            name='organization',
+            field=models.CharField(choices=[('none', 'None'), ('date', 'Date'), ('sender_domain', 'Sender Domain')], default='none', max_length=30),
+        ),
+    ]
@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0030_organization'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='domain',
+            name='delivery_mode',
+            field=models.CharField(blank=True, choices=[('sync', 'Sync'), ('async', 'Async')], max_length=30, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='delivery_mode',
+            field=models.CharField(blank=True, choices=[('sync', 'Sync'), ('async', 'Async')], max_length=30, null=True),
+        ),
+    ]
@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0031_delivery_mode'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='address',
+            name='bundle_attachments',
+            field=models.BooleanField(default=False),
+        ),
+        migrations.AddField(
+            model_name='mail',
+            name='is_bundle',
+            field=models.BooleanField(default=False),
+        ),
+    ]
@@ -0,0 +1,16 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0032_bundle_attachments'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='address',
+            name='bundle_template',
+            field=models.TextField(blank=True, null=True),
+        ),
+    ]
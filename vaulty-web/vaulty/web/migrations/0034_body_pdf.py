@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0033_bundle_template'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='domain',
+            name='body_pdf',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='body_pdf',
+            field=models.BooleanField(blank=True, null=True),
+        ),
+    ]
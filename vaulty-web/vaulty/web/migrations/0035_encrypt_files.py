@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0034_body_pdf'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='address',
+            name='encrypt_files',
+            field=models.BooleanField(default=False),
+        ),
+        migrations.AddField(
+            model_name='address',
+            name='encryption_key',
+            field=models.TextField(blank=True, editable=False, null=True),
+        ),
+    ]
@@ -0,0 +1,21 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0035_encrypt_files'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='address',
+            name='submission_password',
+            field=models.CharField(blank=True, default='', max_length=256),
+        ),
+        migrations.AddField(
+            model_name='mail',
+            name='submitted_by',
+            field=models.CharField(max_length=512, null=True),
+        ),
+    ]
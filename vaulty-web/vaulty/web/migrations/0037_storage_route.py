@@ -0,0 +1,29 @@
+from django.db import migrations, models
+import django.db.models.deletion
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0036_smtp_submission'),
+    ]
+
+    operations = [
+        migrations.CreateModel(
+            name='StorageRoute',
+            fields=[
+                ('id', models.AutoField(auto_created=True, primary_key=True, serialize=False, verbose_name='ID')),
+                ('extension', models.CharField(blank=True, max_length=255, null=True)),
+                ('mime_type', models.CharField(blank=True, max_length=255, null=True)),
+                ('larger_than', models.BigIntegerField(blank=True, null=True)),
+                ('storage_backend', models.CharField(choices=[('dropbox', 'Dropbox'), ('gdrive', 'Gdrive'), ('s3', 'S3')], max_length=30)),
+                ('storage_token', models.CharField(max_length=1000)),
+                ('storage_path', models.CharField(max_length=1000)),
+                ('creation_time', models.DateTimeField(auto_now_add=True)),
+                ('address', models.ForeignKey(on_delete=django.db.models.deletion.CASCADE, to='web.Address')),
+            ],
+            options={
+                'db_table': 'vaulty_storage_routes',
+            },
+        ),
+    ]
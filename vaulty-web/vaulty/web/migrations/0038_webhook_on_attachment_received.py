@@ -0,0 +1,16 @@
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0037_storage_route'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='webhook',
+            name='on_attachment_received',
+            field=models.BooleanField(default=False),
+        ),
+    ]
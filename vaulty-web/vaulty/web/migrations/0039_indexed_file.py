@@ -0,0 +1,32 @@
+from django.db import migrations, models
+import django.db.models.deletion
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0038_webhook_on_attachment_received'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='address',
+            name='indexed_storage_path',
+            field=models.CharField(blank=True, editable=False, max_length=1000, null=True),
+        ),
+        migrations.CreateModel(
+            name='IndexedFile',
+            fields=[
+                ('id', models.AutoField(auto_created=True, primary_key=True, serialize=False, verbose_name='ID')),
+                ('path', models.CharField(max_length=2000)),
+                ('name', models.CharField(max_length=1000)),
+                ('size', models.BigIntegerField()),
+                ('content_hash', models.CharField(db_index=True, max_length=64)),
+                ('creation_time', models.DateTimeField(auto_now_add=True)),
+                ('address', models.ForeignKey(on_delete=django.db.models.deletion.CASCADE, to='web.Address')),
+            ],
+            options={
+                'db_table': 'vaulty_indexed_files',
+            },
+        ),
+    ]
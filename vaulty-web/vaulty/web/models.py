@@ -1,5 +1,7 @@
+from django.contrib.auth.hashers import make_password
 from django.contrib.auth.models import AbstractUser
 from django.contrib.postgres.fields import ArrayField
+from django.core.validators import URLValidator
 from django.db import models
 
 
@@ -12,6 +14,61 @@ class User(AbstractUser):
     last_update_time = models.DateTimeField(auto_now=True)
 
 
+class StorageBackend(models.TextChoices):
+    DROPBOX = 'dropbox'
+    GDRIVE = 'gdrive'
+    S3 = 's3'
+
+
+class AutoGeneratedPolicy(models.TextChoices):
+    STORE = 'store'
+    IGNORE = 'ignore'
+    REJECT = 'reject'
+
+
+class DeliveryMode(models.TextChoices):
+    SYNC = 'sync'
+    ASYNC = 'async'
+
+
+class Organization(models.TextChoices):
+    NONE = 'none'
+    DATE = 'date'
+    SENDER_DOMAIN = 'sender_domain'
+
+
+class Domain(models.Model):
+    """Default settings for all addresses on a domain.
+
+    Unset (NULL) settings fall back to the deployment defaults in the
+    vaulty-mail config file.
+    """
+    class Meta:
+        db_table = "vaulty_domains"
+
+    domain = models.CharField(max_length=255, unique=True)
+
+    email_quota = models.IntegerField(null=True, blank=True)
+    max_email_size = models.IntegerField(null=True, blank=True)
+    storage_quota = models.BigIntegerField(null=True, blank=True)
+    storage_backend = models.CharField(max_length=30, choices=StorageBackend.choices, null=True, blank=True)
+    reply_on_success = models.BooleanField(null=True, blank=True)
+    reply_on_rejection = models.BooleanField(null=True, blank=True)
+    skip_unchanged = models.BooleanField(null=True, blank=True)
+    dedup_attachments = models.BooleanField(null=True, blank=True)
+    transliterate_filenames = models.BooleanField(null=True, blank=True)
+    store_body = models.BooleanField(null=True, blank=True)
+    archive_eml = models.BooleanField(null=True, blank=True)
+    archive_links = models.BooleanField(null=True, blank=True)
+    body_pdf = models.BooleanField(null=True, blank=True)
+    sign_manifests = models.BooleanField(null=True, blank=True)
+    auto_generated_policy = models.CharField(max_length=30, choices=AutoGeneratedPolicy.choices, null=True, blank=True)
+    delivery_mode = models.CharField(max_length=30, choices=DeliveryMode.choices, null=True, blank=True)
+
+    last_update_time = models.DateTimeField(auto_now=True)
+    creation_time = models.DateTimeField(auto_now_add=True)
+
+
 class Address(models.Model):
     class Meta:
         db_table = "vaulty_addresses"
@@ -24,44 +81,149 @@ class Address(models.Model):
         ),
     ]
 
-    class StorageBackend(models.TextChoices):
-        DROPBOX = 'dropbox'
-        GDRIVE = 'gdrive'
-        S3 = 's3'
-
     # TODO: Do we want this to cascade instead?
     user = models.ForeignKey(User, models.SET_NULL, null=True)
     address = models.CharField(max_length=512)
     is_active = models.BooleanField()
 
+    # Settings below can be left unset (NULL) to inherit the domain or
+    # deployment defaults
+
     # Max number of emails this address can receive
-    email_quota = models.IntegerField()
+    email_quota = models.IntegerField(null=True, blank=True)
 
     # Number of emails this address has received in this renewal period
     num_received = models.IntegerField(default=0)
 
     # Max email size for this address
-    max_email_size = models.IntegerField()
+    max_email_size = models.IntegerField(null=True, blank=True)
 
     # Max storage quota in renewal period, in bytes
-    storage_quota = models.BigIntegerField()
+    storage_quota = models.BigIntegerField(null=True, blank=True)
 
     # Storage used in renewal period, in bytes
     storage_used = models.BigIntegerField(default=0)
     last_renewal_time = models.DateTimeField()
-    storage_backend = models.CharField(max_length=30, choices=StorageBackend.choices)
+    storage_backend = models.CharField(max_length=30, choices=StorageBackend.choices, null=True, blank=True)
     storage_token = models.CharField(max_length=1000)
 
+    # Used to refresh short-lived access tokens (Dropbox)
+    storage_refresh_token = models.CharField(max_length=1000, null=True, blank=True)
+    storage_token_expiry = models.DateTimeField(null=True, blank=True)
+
     # Path to store data (in valid backend format)
     storage_path = models.CharField(max_length=1000)
 
-    # Sender whitelisting
+    # Dropbox folder whose existing files were last indexed by the mail
+    # server (see IndexedFile); the folder is indexed again once
+    # storage_path is changed
+    indexed_storage_path = models.CharField(max_length=1000, null=True, blank=True, editable=False)
+
+    # Dropbox Business: namespace (e.g., team space) that storage_path is
+    # relative to, and the team member to upload as when using a team token
+    dropbox_namespace_id = models.CharField(max_length=255, null=True, blank=True)
+    dropbox_team_member_id = models.CharField(max_length=255, null=True, blank=True)
+
+    # Sender whitelisting. Entries are senders, wildcards (e.g.,
+    # "*@example.com", or "*" for all senders), or regexes between slashes.
+    # See vaulty::whitelist::Entry.
     is_whitelist_enabled = models.BooleanField()
     whitelist = ArrayField(models.CharField(max_length=512))
 
+    # Reply to the sender when an email is processed successfully, or when
+    # it is rejected (e.g., quota exceeded, sender not whitelisted)
+    reply_on_success = models.BooleanField(null=True, blank=True)
+    reply_on_rejection = models.BooleanField(null=True, blank=True)
+
+    # Custom reply templates. Supports the same placeholders as webhook
+    # templates (e.g., {subject}, {reason}).
+    reply_success_template = models.TextField(null=True, blank=True)
+    reply_rejection_template = models.TextField(null=True, blank=True)
+
+    # Skip uploading attachments identical to the last stored attachment
+    # with the same name (e.g., recurring reports)
+    skip_unchanged = models.BooleanField(null=True, blank=True)
+
+    # Skip uploading attachments whose content was already stored for this
+    # address, under any name
+    dedup_attachments = models.BooleanField(null=True, blank=True)
+
+    # Transliterate non-ASCII attachment filenames to ASCII, for sync clients
+    # that do not handle Unicode names
+    transliterate_filenames = models.BooleanField(null=True, blank=True)
+
+    # Store the email body (text and HTML) alongside its attachments
+    store_body = models.BooleanField(null=True, blank=True)
+
+    # Archive the raw message, exactly as received, as a single .eml file
+    archive_eml = models.BooleanField(null=True, blank=True)
+
+    # Store the links of HTML emails without attachments (e.g., "your
+    # invoice is ready" emails), and fetch and store the linked PDFs whose
+    # URL matches the pattern, where * matches anything
+    archive_links = models.BooleanField(null=True, blank=True)
+    link_archive_pattern = models.TextField(null=True, blank=True)
+
+    # Render the HTML body of each email to PDF (e.g., receipts and
+    # confirmations) and store it alongside the attachments
+    body_pdf = models.BooleanField(null=True, blank=True)
+
+    # Store a manifest of the files stored for each email (hashes and email
+    # metadata), signed with the server key, so that the files can later be
+    # checked offline with vaulty-verify
+    sign_manifests = models.BooleanField(null=True, blank=True)
+
+    # Stages that attachments go through before they are stored, in order
+    # (e.g., ["sender_folder", "date_prefix"]). See vaulty::pipeline.
+    pipeline_stages = ArrayField(models.CharField(max_length=100), null=True, blank=True)
+
+    # Template for the names attachments are stored under, relative to the
+    # email folder (e.g., "{date}/{sender}/{subject}-{name}"). See
+    # vaulty::filename::Template.
+    filename_template = models.TextField(null=True, blank=True)
+
+    # Subfolders of the storage path that uploads are organized into: none,
+    # by date (YYYY/MM/DD), or by sender domain
+    organization = models.CharField(max_length=30, choices=Organization.choices, default=Organization.NONE)
+
+    # Store the attachments of each email as a single zip archive, named
+    # after the email date and subject. Only applies to email received in
+    # one piece (by the SMTP server, Mailgun, or SES). Attachments sent
+    # separately by the Postfix filter, and those of test emails, are stored
+    # as files.
+    bundle_attachments = models.BooleanField(default=False)
+
+    # Template for the names of zip archives, if not the email date and
+    # subject (e.g., "{date}-{sender}-{subject}.zip"). Takes the same
+    # placeholders as filename_template, where {name} is the default name.
+    bundle_template = models.TextField(null=True, blank=True)
+
+    # Encrypt files before they are uploaded, with a key of the address
+    # (AES-256-GCM). The key is created by the server when the first email
+    # arrives, and stored encrypted with the server master key. Files are
+    # only decrypted offline, with vaulty-decrypt and the master key.
+    encrypt_files = models.BooleanField(default=False)
+    encryption_key = models.TextField(null=True, blank=True, editable=False)
+
+    # Argon2 hash of the password that scripts and devices (e.g., scanners)
+    # log in with, as the address, to submit email over SMTP; submission is
+    # disabled for the address if empty
+    submission_password = models.CharField(max_length=256, blank=True, default="")
+
+    # What to do with auto-generated email (auto-replies, bulk mail, or mail
+    # looping back from Vaulty): store, ignore, or reject it
+    auto_generated_policy = models.CharField(max_length=30, choices=AutoGeneratedPolicy.choices, null=True, blank=True)
+
+    # When attachments are acknowledged: once stored (sync), or once
+    # received and stored in the background (async)
+    delivery_mode = models.CharField(max_length=30, choices=DeliveryMode.choices, null=True, blank=True)
+
     last_update_time = models.DateTimeField(auto_now=True)
     creation_time = models.DateTimeField(auto_now_add=True)
 
+    def set_submission_password(self, raw_password):
+        self.submission_password = make_password(raw_password, hasher="argon2") if raw_password else ""
+
 
 class Mail(models.Model):
     class Meta:
@@ -74,6 +236,30 @@ class Mail(models.Model):
     num_attachments = models.IntegerField()
     total_size = models.IntegerField()
 
+    # Importance set by the sender (high, normal, or low), if any
+    importance = models.CharField(max_length=10, null=True)
+
+    # Matched a priority rule that flags notifications, and the subfolder it
+    # was stored in, if any
+    is_priority = models.BooleanField(default=False)
+    priority_folder = models.CharField(max_length=255, null=True)
+
+    # Synthetic email sent from the API to test the storage of the address
+    is_test = models.BooleanField(default=False)
+
+    # Attachments were stored as a single zip archive, the only attachment
+    # recorded for the email
+    is_bundle = models.BooleanField(default=False)
+
+    # Directives sent by the owner in the email itself: the subfolder to
+    # store it in, and whether to notify webhooks about it, if set
+    directive_folder = models.CharField(max_length=255, null=True)
+    directive_notify = models.BooleanField(null=True)
+
+    # Address that logged in to submit the email over SMTP, if it was not
+    # received from another mail server
+    submitted_by = models.CharField(max_length=512, null=True)
+
     # Email processed successfully by default
     status = models.BooleanField(default=True)
     error_msg = models.TextField(null=True)
@@ -85,14 +271,72 @@ class Attachment(models.Model):
     class Meta:
         db_table = "vaulty_attachments"
 
+        # A retried attachment replaces the row of its previous attempt
+        unique_together = (("mail", "index"), )
+
     mail = models.ForeignKey(Mail, models.CASCADE)
     index = models.IntegerField()
+    name = models.CharField(max_length=1000, null=True)
     size = models.IntegerField()
+    mime_type = models.CharField(max_length=255, null=True)
+
+    # Where the attachment is (or was to be) stored, unless it was dropped
+    storage_path = models.CharField(max_length=2000, null=True)
+
+    # SHA-256 of the content, unless it was dropped or failed to upload
+    content_hash = models.CharField(max_length=64, null=True, db_index=True)
+
+    # Identical to the last stored version, so the upload was skipped
+    is_duplicate = models.BooleanField(default=False)
+
     status = models.BooleanField(default=True)
     error_msg = models.TextField(null=True)
     creation_time = models.DateTimeField(auto_now_add=True)
 
 
+class IndexedFile(models.Model):
+    """File that was already in the Dropbox folder of an address when the
+    address was pointed at it, as indexed by the mail server.
+
+    Indexed files are never overwritten by attachments (paths are compared
+    case-insensitively, as in Dropbox). They are not used to skip duplicates,
+    as the owner may have removed them since.
+    """
+    class Meta:
+        db_table = "vaulty_indexed_files"
+
+    address = models.ForeignKey(Address, models.CASCADE)
+
+    # Full path, as displayed by Dropbox, and file name
+    path = models.CharField(max_length=2000)
+    name = models.CharField(max_length=1000)
+    size = models.BigIntegerField()
+
+    # Dropbox content hash (SHA-256 of the SHA-256 of each 4 MB block), not
+    # the SHA-256 of attachments
+    content_hash = models.CharField(max_length=64, db_index=True)
+    creation_time = models.DateTimeField(auto_now_add=True)
+
+
+class Change(models.Model):
+    """Email or attachment state transition, served by the change feed."""
+    class Meta:
+        db_table = "vaulty_changes"
+
+    # Change feed cursors are derived from this
+    id = models.BigAutoField(primary_key=True)
+    mail = models.ForeignKey(Mail, models.CASCADE)
+
+    # email_created, email_stored, email_failed, attachment_stored, or
+    # attachment_failed
+    kind = models.CharField(max_length=32)
+
+    # Attachment index, for attachment changes
+    index = models.IntegerField(null=True)
+    error_msg = models.TextField(null=True)
+    creation_time = models.DateTimeField(auto_now_add=True, db_index=True)
+
+
 class Log(models.Model):
     class Meta:
         db_table = "vaulty_logs"
@@ -103,6 +347,141 @@ class Log(models.Model):
     creation_time = models.DateTimeField(auto_now_add=True)
 
 
+class AttachmentRule(models.Model):
+    """Filtering rule applied to attachments sent to an address.
+
+    A rule matches an attachment if all of its set criteria match. Deny rules
+    take precedence; if an address has any allow rules, attachments must
+    match one of them. Attachments that are not accepted are dropped.
+    """
+    class Meta:
+        db_table = "vaulty_attachment_rules"
+
+    class Action(models.TextChoices):
+        ALLOW = 'allow'
+        DENY = 'deny'
+
+    address = models.ForeignKey(Address, models.CASCADE)
+    action = models.CharField(max_length=10, choices=Action.choices)
+
+    # File extension, without the dot (e.g., "pdf")
+    extension = models.CharField(max_length=255, null=True, blank=True)
+
+    # Exact MIME type (e.g., "image/png") or wildcard subtype (e.g., "image/*")
+    mime_type = models.CharField(max_length=255, null=True, blank=True)
+
+    # Only match attachments larger than this, in bytes
+    larger_than = models.BigIntegerField(null=True, blank=True)
+
+    creation_time = models.DateTimeField(auto_now_add=True)
+
+
+class PriorityRule(models.Model):
+    """Rule that maps the importance or subject of emails sent to an address
+    to a storage behavior.
+
+    A rule matches an email if all of its set criteria match. Rules are
+    checked in order of creation, and the first one that matches applies.
+    """
+    class Meta:
+        db_table = "vaulty_priority_rules"
+
+    class Importance(models.TextChoices):
+        HIGH = 'high'
+        NORMAL = 'normal'
+        LOW = 'low'
+
+    address = models.ForeignKey(Address, models.CASCADE)
+
+    # Importance set by the sender, from the Importance or X-Priority header
+    importance = models.CharField(max_length=10, choices=Importance.choices, null=True, blank=True)
+
+    # Tag that the subject must contain, ignoring case (e.g., "[urgent]")
+    subject_tag = models.CharField(max_length=255, null=True, blank=True)
+
+    # Subfolder of the address storage path to store matching emails in
+    folder = models.CharField(max_length=255, null=True, blank=True)
+
+    # Flag webhook notifications as high priority, and send them as soon as
+    # the email is received
+    notify = models.BooleanField(default=False)
+
+    creation_time = models.DateTimeField(auto_now_add=True)
+
+
+class StorageRoute(models.Model):
+    """Rule that stores some files of an address on another storage backend
+    than the address (e.g., images on S3, PDFs on Dropbox).
+
+    A route matches a file if all of its set criteria match, like attachment
+    rules. Attachments are matched on their name, MIME type, and size, and
+    stored bodies as text/plain. Routes are checked in order of creation, and
+    the first one that matches applies; other files are stored on the address
+    backend.
+    """
+    class Meta:
+        db_table = "vaulty_storage_routes"
+
+    address = models.ForeignKey(Address, models.CASCADE)
+
+    # File extension, without the dot (e.g., "pdf")
+    extension = models.CharField(max_length=255, null=True, blank=True)
+
+    # Exact MIME type (e.g., "image/png") or wildcard subtype (e.g., "image/*")
+    mime_type = models.CharField(max_length=255, null=True, blank=True)
+
+    # Only match files larger than this, in bytes
+    larger_than = models.BigIntegerField(null=True, blank=True)
+
+    # Where matching files are stored, as for addresses
+    storage_backend = models.CharField(max_length=30, choices=StorageBackend.choices)
+    storage_token = models.CharField(max_length=1000)
+    storage_path = models.CharField(max_length=1000)
+
+    creation_time = models.DateTimeField(auto_now_add=True)
+
+
+class Webhook(models.Model):
+    """Webhook notified when an email sent to an address is stored or
+    rejected.
+    """
+    class Meta:
+        db_table = "vaulty_webhooks"
+
+    class Format(models.TextChoices):
+        JSON = 'json'
+        SLACK = 'slack'
+        DISCORD = 'discord'
+
+    address = models.ForeignKey(Address, models.CASCADE)
+    url = models.CharField(max_length=1000, validators=[URLValidator(schemes=["https"])])
+    format = models.CharField(max_length=30, choices=Format.choices, default=Format.JSON)
+
+    # Notification categories to send
+    on_received = models.BooleanField(default=False)
+    on_success = models.BooleanField(default=False)
+    on_rejection = models.BooleanField(default=True)
+    on_attachment = models.BooleanField(default=False)
+
+    # An attachment was fully received (with its size and SHA-256 hash),
+    # sent before it is uploaded so that integrations can start processing
+    # it while it is stored
+    on_attachment_received = models.BooleanField(default=False)
+
+    # Unusual activity on the address, such as a spike in email volume
+    on_alert = models.BooleanField(default=True)
+
+    # If set, payloads are signed with HMAC-SHA256 (X-Vaulty-Signature)
+    secret = models.CharField(max_length=255, null=True, blank=True)
+
+    # Message template used instead of the default for Slack and Discord.
+    # Placeholders: {sender}, {recipient}, {subject}, {reason}, {message},
+    # {email_id}, {num_files}, {files}
+    template = models.TextField(null=True, blank=True)
+
+    creation_time = models.DateTimeField(auto_now_add=True)
+
+
 class Alias(models.Model):
     class Meta:
         db_table = "vaulty_aliases"
@@ -116,7 +495,42 @@ class Alias(models.Model):
     is_active = models.BooleanField()
 
 
+class ApiUser(models.Model):
+    """Credentials for a client of the Vaulty server API (e.g., a Postfix relay)."""
+    class Meta:
+        db_table = "vaulty_api_users"
+
+    username = models.CharField(max_length=150, unique=True)
+
+    # Argon2 hash in Django's format; the server only supports Argon2
+    password = models.CharField(max_length=256)
+    description = models.TextField(blank=True, default="")
+    is_active = models.BooleanField(default=True)
+    creation_time = models.DateTimeField(auto_now_add=True)
+
+    def set_password(self, raw_password):
+        self.password = make_password(raw_password, hasher="argon2")
+
+    def __str__(self):
+        return self.username
+
+
 class LaunchMailingList(models.Model):
     """Tracks users who signed up for launch mailing list."""
     email_address = models.CharField(max_length=512, unique=True)
     creation_time = models.DateTimeField(auto_now_add=True)
+
+
+class Sample(models.Model):
+    """Truncated sample of a rejected email, kept for abuse review."""
+    class Meta:
+        db_table = "vaulty_samples"
+
+    # SHA-256 of the full body, used to group identical content
+    content_hash = models.CharField(max_length=64, db_index=True)
+    sample = models.TextField()
+    sender = models.CharField(max_length=512)
+    recipient = models.TextField()
+    subject = models.TextField(null=True)
+    reason = models.CharField(max_length=64)
+    creation_time = models.DateTimeField(auto_now_add=True)